@@ -35,7 +35,7 @@ use lightning::chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdateErr,
 use lightning::chain::transaction::OutPoint;
 use lightning::chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator};
 use lightning::chain::keysinterface::{KeysInterface, InMemoryChannelKeys};
-use lightning::ln::channelmanager::{ChannelManager, PaymentHash, PaymentPreimage, PaymentSecret, PaymentSendFailure, ChannelManagerReadArgs};
+use lightning::ln::channelmanager::{ChannelManager, PaymentHash, PaymentId, PaymentPreimage, PaymentSecret, PaymentSendFailure, ChannelManagerReadArgs};
 use lightning::ln::features::{ChannelFeatures, InitFeatures, NodeFeatures};
 use lightning::ln::msgs::{CommitmentUpdate, ChannelMessageHandler, ErrorAction, UpdateAddHTLC, Init};
 use lightning::util::enforcing_trait_impls::EnforcingChannelKeys;
@@ -224,6 +224,9 @@ fn check_payment_err(send_err: PaymentSendFailure) {
 		PaymentSendFailure::PartialFailure(per_path_results) => {
 			for res in per_path_results { if let Err(api_err) = res { check_api_err(api_err); } }
 		},
+		PaymentSendFailure::DuplicatePayment => {
+			// Our payment_ids wrap, so we may end up re-using one
+		},
 	}
 }
 
@@ -242,7 +245,7 @@ fn send_payment(source: &ChanMan, dest: &ChanMan, dest_chan_id: u64, amt: u64, p
 			fee_msat: amt,
 			cltv_expiry_delta: 200,
 		}]],
-	}, PaymentHash(payment_hash.into_inner()), &None) {
+	}, PaymentHash(payment_hash.into_inner()), &None, PaymentId(payment_hash.into_inner())) {
 		check_payment_err(err);
 		false
 	} else { true }
@@ -267,7 +270,7 @@ fn send_hop_payment(source: &ChanMan, middle: &ChanMan, middle_chan_id: u64, des
 			fee_msat: amt,
 			cltv_expiry_delta: 200,
 		}]],
-	}, PaymentHash(payment_hash.into_inner()), &None) {
+	}, PaymentHash(payment_hash.into_inner()), &None, PaymentId(payment_hash.into_inner())) {
 		check_payment_err(err);
 		false
 	} else { true }
@@ -527,7 +530,7 @@ pub fn do_test<Out: test_logger::Output>(data: &[u8], out: Out) {
 						fee_msat: 10_000_000,
 						cltv_expiry_delta: 200,
 					}]],
-				}, PaymentHash(payment_hash.into_inner()), &Some(PaymentSecret(payment_secret.into_inner()))) {
+				}, PaymentHash(payment_hash.into_inner()), &Some(PaymentSecret(payment_secret.into_inner())), PaymentId(payment_hash.into_inner())) {
 					check_payment_err(err);
 				}
 			} }
@@ -684,7 +687,7 @@ pub fn do_test<Out: test_logger::Output>(data: &[u8], out: Out) {
 				let had_events = !events.is_empty();
 				for event in events.drain(..) {
 					match event {
-						events::Event::PaymentReceived { payment_hash, payment_secret, amt } => {
+						events::Event::PaymentReceived { payment_hash, payment_secret, amt, .. } => {
 							if claim_set.insert(payment_hash.0) {
 								if $fail {
 									assert!(nodes[$node].fail_htlc_backwards(&payment_hash, &payment_secret));
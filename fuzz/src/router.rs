@@ -9,9 +9,12 @@
 
 use bitcoin::blockdata::script::Builder;
 use bitcoin::blockdata::transaction::TxOut;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::network::constants::Network;
 use bitcoin::hash_types::BlockHash;
 
 use lightning::chain;
+use lightning::chain::transaction::OutPoint;
 use lightning::ln::channelmanager::ChannelDetails;
 use lightning::ln::features::InitFeatures;
 use lightning::ln::msgs;
@@ -80,11 +83,12 @@ struct FuzzChainSource {
 	input: Arc<InputData>,
 }
 impl chain::Access for FuzzChainSource {
-	fn get_utxo(&self, _genesis_hash: &BlockHash, _short_channel_id: u64) -> Result<TxOut, chain::AccessError> {
+	fn get_utxo(&self, _genesis_hash: &BlockHash, short_channel_id: u64) -> Result<(OutPoint, TxOut), chain::AccessError> {
 		match self.input.get_slice(2) {
 			Some(&[0, _]) => Err(chain::AccessError::UnknownChain),
 			Some(&[1, _]) => Err(chain::AccessError::UnknownTx),
-			Some(&[_, x]) => Ok(TxOut { value: 0, script_pubkey: Builder::new().push_int(x as i64).into_script().to_v0_p2wsh() }),
+			Some(&[_, x]) => Ok((OutPoint { txid: Default::default(), index: short_channel_id as u16 },
+				TxOut { value: 0, script_pubkey: Builder::new().push_int(x as i64).into_script().to_v0_p2wsh() })),
 			None => Err(chain::AccessError::UnknownTx),
 			_ => unreachable!(),
 		}
@@ -159,7 +163,7 @@ pub fn do_test<Out: test_logger::Output>(data: &[u8], out: Out) {
 	};
 
 	let our_pubkey = get_pubkey!();
-	let net_graph_msg_handler = NetGraphMsgHandler::new(chain_source, Arc::clone(&logger));
+	let net_graph_msg_handler = NetGraphMsgHandler::new(genesis_block(Network::Bitcoin).header.block_hash(), chain_source, Arc::clone(&logger));
 
 	loop {
 		match get_slice!(1)[0] {
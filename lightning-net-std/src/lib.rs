@@ -0,0 +1,494 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! A socket handling library for those who wish to use rust-lightning with native, blocking,
+//! std::net TcpStreams, without pulling in an async runtime.
+//!
+//! Each connection is handled by two threads, one reading from the socket and handing the bytes
+//! over to the PeerManager, and one writing the bytes the PeerManager gives us to the socket.
+//! Beyond that, usage is the same as lightning-net-tokio's: hand over a TcpStream and a reference
+//! to a PeerManager and the rest is handled, except for the
+//! [Event](../lightning/util/events/enum.Event.html) handling mechanism, see below.
+//!
+//! The PeerHandler, due to the fire-and-forget nature of this logic, must be an Arc, and must use
+//! the SocketDescriptor provided here as the PeerHandler's SocketDescriptor.
+//!
+//! Three methods are exposed to register a new connection for handling, see their individual docs
+//! for more. All three take a [mpsc::SyncSender<()>](https://doc.rust-lang.org/std/sync/mpsc/struct.SyncSender.html)
+//! which is sent into every time something occurs which may result in lightning
+//! [Events](../lightning/util/events/enum.Event.html). The call site should, thus, look something
+//! like this:
+//! ```
+//! extern crate bitcoin;
+//! extern crate lightning;
+//! extern crate lightning_net_std;
+//!
+//! use std::sync::mpsc;
+//! use std::net::{SocketAddr, TcpStream};
+//! use bitcoin::secp256k1::key::PublicKey;
+//! use lightning::util::events::EventsProvider;
+//! use std::sync::Arc;
+//!
+//! // Define concrete types for our high-level objects:
+//! type TxBroadcaster = dyn lightning::chain::chaininterface::BroadcasterInterface;
+//! type FeeEstimator = dyn lightning::chain::chaininterface::FeeEstimator;
+//! type Logger = dyn lightning::util::logger::Logger;
+//! type ChainAccess = dyn lightning::chain::Access;
+//! type ChainFilter = dyn lightning::chain::Filter;
+//! type DataPersister = dyn lightning::chain::channelmonitor::Persist<lightning::chain::keysinterface::InMemoryChannelKeys>;
+//! type ChainMonitor = lightning::chain::chainmonitor::ChainMonitor<lightning::chain::keysinterface::InMemoryChannelKeys, Arc<ChainFilter>, Arc<TxBroadcaster>, Arc<FeeEstimator>, Arc<Logger>, Arc<DataPersister>>;
+//! type ChannelManager = lightning::ln::channelmanager::SimpleArcChannelManager<ChainMonitor, TxBroadcaster, FeeEstimator, Logger>;
+//! type PeerManager = lightning::ln::peer_handler::SimpleArcPeerManager<lightning_net_std::SocketDescriptor, ChainMonitor, TxBroadcaster, FeeEstimator, ChainAccess, Logger>;
+//!
+//! // Connect to node with pubkey their_node_id at addr:
+//! fn connect_to_node(peer_manager: PeerManager, chain_monitor: Arc<ChainMonitor>, channel_manager: ChannelManager, their_node_id: PublicKey, addr: SocketAddr) {
+//!     let (sender, receiver) = mpsc::sync_channel(1);
+//!     lightning_net_std::connect_outbound(peer_manager, sender, their_node_id, addr);
+//!     // Once the connection is closed, all senders are dropped and recv() fails.
+//!     while let Ok(()) = receiver.recv() {
+//!         for _event in channel_manager.get_and_clear_pending_events().drain(..) {
+//!             // Handle the event!
+//!         }
+//!         for _event in chain_monitor.get_and_clear_pending_events().drain(..) {
+//!             // Handle the event!
+//!         }
+//!     }
+//! }
+//!
+//! // Begin reading from a newly accepted socket and talk to the peer:
+//! fn accept_socket(peer_manager: PeerManager, chain_monitor: Arc<ChainMonitor>, channel_manager: ChannelManager, socket: TcpStream) {
+//!     let (sender, receiver) = mpsc::sync_channel(1);
+//!     lightning_net_std::setup_inbound(peer_manager, sender, socket);
+//!     while let Ok(()) = receiver.recv() {
+//!         for _event in channel_manager.get_and_clear_pending_events().drain(..) {
+//!             // Handle the event!
+//!         }
+//!         for _event in chain_monitor.get_and_clear_pending_events().drain(..) {
+//!             // Handle the event!
+//!         }
+//!     }
+//! }
+//! ```
+
+extern crate bitcoin;
+extern crate lightning;
+
+use bitcoin::secp256k1::key::PublicKey;
+
+use lightning::ln::peer_handler;
+use lightning::ln::msgs::{ChannelMessageHandler, NetAddress, RoutingMessageHandler};
+use lightning::util::logger::Logger;
+
+use std::{cmp, io, mem, thread};
+use std::hash::Hash;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+static ID_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The most bytes we accept in send_data which the writer thread has yet to write to the socket.
+/// Past that, we only accept more once the writer thread made room, at which point it calls
+/// PeerManager::write_buffer_space_avail (and we pause reads in the meantime).
+const SEND_BUFFER_LIMIT: usize = 64 * 1024;
+
+/// How long we give the writer thread to write what's left in its buffer (eg an error message
+/// explaining why we are disconnecting) when the connection is closed.
+const DISCONNECT_FLUSH_TIMEOUT_SECS: u64 = 1;
+
+/// How long we wait for outbound TCP connections to be accepted in connect_outbound.
+const CONNECT_TIMEOUT_SECS: u64 = 10;
+
+/// Connection contains all our internal state for a connection - we hold a reference to it (in an
+/// Arc) in each SocketDescriptor we create as well as in the reader and writer threads.
+struct Connection {
+	event_notify: mpsc::SyncSender<()>,
+	// Bytes we accepted in send_data which the writer thread has yet to write to the socket.
+	write_buffer: Vec<u8>,
+	// Set when send_data couldn't take all the data it was given, in which case the writer thread
+	// calls write_buffer_space_avail once it has made room.
+	write_blocked: bool,
+	// When we are told by rust-lightning to pause read (because we have writes backing up), we do
+	// so by setting read_paused, after which the reader thread waits for it to be unset before
+	// reading from the socket again.
+	read_paused: bool,
+	// When we are told by rust-lightning to disconnect, we can't return to rust-lightning until we
+	// are sure we won't call any more read/write PeerManager functions with the same connection.
+	// This counts the read_event and write_buffer_space_avail calls which are in progress, which
+	// disconnect_socket waits on to get to 0.
+	rl_calls_in_progress: usize,
+	rl_requested_disconnect: bool,
+	// Set by the reader thread once it is done, telling the writer thread to flush what's left in
+	// write_buffer and exit.
+	closed: bool,
+	writer_done: bool,
+	id: u64,
+}
+
+/// The state shared between the reader and writer threads and the SocketDescriptors.
+struct ConnectionState {
+	conn: Mutex<Connection>,
+	// Notified every time conn changes in a way someone may be waiting on.
+	cond: Condvar,
+	// Used to shut the socket down, waking up threads blocked reading from or writing to it.
+	stream: TcpStream,
+}
+
+impl ConnectionState {
+	fn new(event_notify: mpsc::SyncSender<()>, stream: TcpStream) -> Result<(Arc<Self>, TcpStream, TcpStream), io::Error> {
+		let reader = stream.try_clone()?;
+		let writer = stream.try_clone()?;
+		Ok((Arc::new(Self {
+			conn: Mutex::new(Connection {
+				event_notify, write_buffer: Vec::new(), write_blocked: false, read_paused: false,
+				rl_calls_in_progress: 0, rl_requested_disconnect: false, closed: false,
+				writer_done: false, id: ID_COUNTER.fetch_add(1, Ordering::AcqRel) as u64,
+			}),
+			cond: Condvar::new(),
+			stream,
+		}), reader, writer))
+	}
+
+	fn event_trigger(us: &mut MutexGuard<Connection>) {
+		// Ignore full errors as we just need the user to poll after this point, so if they haven't
+		// received the last send yet, it doesn't matter. If the user is no longer listening,
+		// there's nobody to notify anyway.
+		let _ = us.event_notify.try_send(());
+	}
+
+	/// Spawns the writer thread, then reads from the socket until it is closed, either by
+	/// rust-lightning or by the peer, returning once both threads are done.
+	fn run<CMH, RMH, L>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<RMH>, Arc<L>>>, us: Arc<Self>, mut reader: TcpStream, writer: TcpStream) where
+			CMH: ChannelMessageHandler + 'static,
+			RMH: RoutingMessageHandler + 'static,
+			L: Logger + 'static + ?Sized {
+		let writer_thread = {
+			let peer_manager = Arc::clone(&peer_manager);
+			let us = Arc::clone(&us);
+			thread::spawn(move || Self::schedule_write(peer_manager, us, writer))
+		};
+
+		// 8KB is nice and big but also should never cause any issues with stack overflowing.
+		let mut buf = [0; 8192];
+
+		let mut our_descriptor = SocketDescriptor::new(Arc::clone(&us));
+		// An enum describing why we did/are disconnecting:
+		enum Disconnect {
+			// Rust-Lightning told us to disconnect, either by returning an Err or by calling
+			// SocketDescriptor::disconnect_socket.
+			// In this case, we do not call peer_manager.socket_disconnected() as Rust-Lightning
+			// already knows we're disconnected.
+			CloseConnection,
+			// The connection was disconnected for some other reason, ie because the socket was
+			// closed.
+			// In this case, we do need to call peer_manager.socket_disconnected() to inform
+			// Rust-Lightning that the socket is gone.
+			PeerDisconnected
+		}
+		let disconnect_type = loop {
+			{
+				let mut us_lock = us.conn.lock().unwrap();
+				while us_lock.read_paused && !us_lock.rl_requested_disconnect {
+					us_lock = us.cond.wait(us_lock).unwrap();
+				}
+				if us_lock.rl_requested_disconnect { break Disconnect::CloseConnection; }
+			}
+
+			let read = reader.read(&mut buf);
+			let mut us_lock = us.conn.lock().unwrap();
+			// disconnect_socket shuts the socket down to wake us up, so check for it before
+			// looking at what we read.
+			if us_lock.rl_requested_disconnect { break Disconnect::CloseConnection; }
+			let len = match read {
+				Ok(0) => break Disconnect::PeerDisconnected,
+				Ok(len) => len,
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(_) => break Disconnect::PeerDisconnected,
+			};
+			us_lock.rl_calls_in_progress += 1;
+			mem::drop(us_lock);
+
+			let read_res = peer_manager.read_event(&mut our_descriptor, &buf[0..len]);
+
+			let mut us_lock = us.conn.lock().unwrap();
+			us_lock.rl_calls_in_progress -= 1;
+			us.cond.notify_all();
+			match read_res {
+				Ok(pause_read) => {
+					if pause_read {
+						us_lock.read_paused = true;
+					}
+					Self::event_trigger(&mut us_lock);
+				},
+				Err(_) => break Disconnect::CloseConnection,
+			}
+		};
+
+		{
+			let mut us_lock = us.conn.lock().unwrap();
+			us_lock.closed = true;
+			us.cond.notify_all();
+			// The writer thread may be calling write_buffer_space_avail, which must complete
+			// before we can call socket_disconnected.
+			while us_lock.rl_calls_in_progress != 0 {
+				us_lock = us.cond.wait(us_lock).unwrap();
+			}
+			let flush_deadline = Instant::now() + Duration::from_secs(DISCONNECT_FLUSH_TIMEOUT_SECS);
+			while !us_lock.writer_done {
+				let now = Instant::now();
+				if now >= flush_deadline { break; }
+				us_lock = us.cond.wait_timeout(us_lock, flush_deadline - now).unwrap().0;
+			}
+		}
+		// If the socket is already closed, shutdown() will fail, so just ignore it.
+		let _ = us.stream.shutdown(Shutdown::Both);
+		let _ = writer_thread.join();
+
+		if let Disconnect::PeerDisconnected = disconnect_type {
+			peer_manager.socket_disconnected(&our_descriptor);
+			Self::event_trigger(&mut us.conn.lock().unwrap());
+		}
+	}
+
+	fn schedule_write<CMH, RMH, L>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<RMH>, Arc<L>>>, us: Arc<Self>, mut writer: TcpStream) where
+			CMH: ChannelMessageHandler + 'static,
+			RMH: RoutingMessageHandler + 'static,
+			L: Logger + 'static + ?Sized {
+		let mut our_descriptor = SocketDescriptor::new(Arc::clone(&us));
+		loop {
+			let (data, closing) = {
+				let mut us_lock = us.conn.lock().unwrap();
+				while us_lock.write_buffer.is_empty() && !us_lock.closed {
+					us_lock = us.cond.wait(us_lock).unwrap();
+				}
+				(mem::replace(&mut us_lock.write_buffer, Vec::new()), us_lock.closed)
+			};
+			if !data.is_empty() && writer.write_all(&data).is_err() {
+				// Shut the socket down so that the reader thread notices the connection is gone,
+				// if it hasn't already.
+				let _ = writer.shutdown(Shutdown::Both);
+				break;
+			}
+			if closing { break; }
+
+			let mut us_lock = us.conn.lock().unwrap();
+			if us_lock.write_blocked && !us_lock.closed && !us_lock.rl_requested_disconnect {
+				us_lock.write_blocked = false;
+				us_lock.rl_calls_in_progress += 1;
+				mem::drop(us_lock);
+
+				let write_res = peer_manager.write_buffer_space_avail(&mut our_descriptor);
+
+				let mut us_lock = us.conn.lock().unwrap();
+				us_lock.rl_calls_in_progress -= 1;
+				if write_res.is_err() {
+					// Close the connection the same way we would if rust-lightning called
+					// disconnect_socket, without calling socket_disconnected.
+					us_lock.rl_requested_disconnect = true;
+					us_lock.read_paused = true;
+					let _ = us.stream.shutdown(Shutdown::Read);
+				}
+				us.cond.notify_all();
+			}
+		}
+		us.conn.lock().unwrap().writer_done = true;
+		us.cond.notify_all();
+	}
+}
+
+/// Process incoming messages and feed outgoing messages on the provided socket generated by
+/// accepting an incoming connection.
+///
+/// All processing happens in threads spawned here, the returned JoinHandle completes once the peer
+/// is disconnected and said threads are done, though you do not need to join it.
+///
+/// See the module-level documentation for how to handle the event_notify mpsc::SyncSender.
+pub fn setup_inbound<CMH, RMH, L>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<RMH>, Arc<L>>>, event_notify: mpsc::SyncSender<()>, stream: TcpStream) -> thread::JoinHandle<()> where
+		CMH: ChannelMessageHandler + 'static,
+		RMH: RoutingMessageHandler + 'static,
+		L: Logger + 'static + ?Sized {
+	thread::spawn(move || {
+		let remote_addr = stream.peer_addr().ok().map(|addr| match addr {
+			SocketAddr::V4(addr) => NetAddress::IPv4 { addr: addr.ip().octets(), port: addr.port() },
+			SocketAddr::V6(addr) => NetAddress::IPv6 { addr: addr.ip().octets(), port: addr.port() },
+		});
+		let (us, reader, writer) = match ConnectionState::new(event_notify, stream) {
+			Ok(res) => res,
+			Err(_) => return,
+		};
+		// If the PeerManager refuses the connection, we skip socket_disconnected and just drop the
+		// socket, in accordance with the PeerManager requirements.
+		if let Ok(_) = peer_manager.new_inbound_connection_with_address(SocketDescriptor::new(Arc::clone(&us)), remote_addr) {
+			ConnectionState::run(peer_manager, us, reader, writer);
+		}
+	})
+}
+
+/// Process incoming messages and feed outgoing messages on the provided socket generated by
+/// making an outbound connection which is expected to be accepted by a peer with the given
+/// public key.
+///
+/// All processing happens in threads spawned here, the returned JoinHandle completes once the peer
+/// is disconnected and said threads are done, though you do not need to join it.
+///
+/// See the module-level documentation for how to handle the event_notify mpsc::SyncSender.
+pub fn setup_outbound<CMH, RMH, L>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<RMH>, Arc<L>>>, event_notify: mpsc::SyncSender<()>, their_node_id: PublicKey, stream: TcpStream) -> thread::JoinHandle<()> where
+		CMH: ChannelMessageHandler + 'static,
+		RMH: RoutingMessageHandler + 'static,
+		L: Logger + 'static + ?Sized {
+	thread::spawn(move || {
+		let (us, reader, writer) = match ConnectionState::new(event_notify, stream) {
+			Ok(res) => res,
+			Err(_) => return,
+		};
+		// If the PeerManager refuses the connection, we skip socket_disconnected and just drop the
+		// socket, in accordance with the PeerManager requirements.
+		if let Ok(initial_send) = peer_manager.new_outbound_connection(their_node_id, SocketDescriptor::new(Arc::clone(&us))) {
+			us.conn.lock().unwrap().write_buffer = initial_send;
+			ConnectionState::run(peer_manager, us, reader, writer);
+		}
+	})
+}
+
+/// Process incoming messages and feed outgoing messages on a new connection made to the given
+/// socket address which is expected to be accepted by a peer with the given public key (by
+/// scheduling threads with setup_outbound).
+///
+/// Blocks until the TCP connection is established (or we give up after 10 seconds), returning
+/// None if it failed, or otherwise the JoinHandle returned by setup_outbound.
+///
+/// See the module-level documentation for how to handle the event_notify mpsc::SyncSender.
+pub fn connect_outbound<CMH, RMH, L>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<RMH>, Arc<L>>>, event_notify: mpsc::SyncSender<()>, their_node_id: PublicKey, addr: SocketAddr) -> Option<thread::JoinHandle<()>> where
+		CMH: ChannelMessageHandler + 'static,
+		RMH: RoutingMessageHandler + 'static,
+		L: Logger + 'static + ?Sized {
+	if let Ok(stream) = TcpStream::connect_timeout(&addr, Duration::from_secs(CONNECT_TIMEOUT_SECS)) {
+		Some(setup_outbound(peer_manager, event_notify, their_node_id, stream))
+	} else { None }
+}
+
+/// The SocketDescriptor used to refer to sockets by a PeerHandler. This is pub only as it is a
+/// type in the template of PeerHandler.
+pub struct SocketDescriptor {
+	conn: Arc<ConnectionState>,
+	id: u64,
+}
+impl SocketDescriptor {
+	fn new(conn: Arc<ConnectionState>) -> Self {
+		let id = conn.conn.lock().unwrap().id;
+		Self { conn, id }
+	}
+}
+impl peer_handler::SocketDescriptor for SocketDescriptor {
+	fn send_data(&mut self, data: &[u8], resume_read: bool) -> usize {
+		// To send data, we hand it over to the writer thread, up to SEND_BUFFER_LIMIT bytes. If we
+		// can't take all of it, the writer thread will call write_buffer_space_avail once it has
+		// made room and we'll end up back here.
+		let mut us = self.conn.conn.lock().unwrap();
+		if us.closed || us.rl_requested_disconnect {
+			// We're shutting down, just fast-return 0 here.
+			return 0;
+		}
+
+		if resume_read && us.read_paused {
+			us.read_paused = false;
+			self.conn.cond.notify_all();
+		}
+		if data.is_empty() { return 0; }
+		let written_len = cmp::min(data.len(), SEND_BUFFER_LIMIT.saturating_sub(us.write_buffer.len()));
+		us.write_buffer.extend_from_slice(&data[..written_len]);
+		if written_len < data.len() {
+			// We need to make sure we pause read given we're now waiting on the writer thread (and
+			// in accordance with the send_data() docs).
+			us.write_blocked = true;
+			us.read_paused = true;
+		}
+		self.conn.cond.notify_all();
+		written_len
+	}
+
+	fn disconnect_socket(&mut self) {
+		let mut us = self.conn.conn.lock().unwrap();
+		us.rl_requested_disconnect = true;
+		us.read_paused = true;
+		// Wake up the reader thread, whether it's waiting for reads to be resumed or blocked reading
+		// from the socket. The writer thread still gets to flush its buffer.
+		let _ = self.conn.stream.shutdown(Shutdown::Read);
+		self.conn.cond.notify_all();
+		while us.rl_calls_in_progress != 0 {
+			us = self.conn.cond.wait(us).unwrap();
+		}
+	}
+}
+impl Clone for SocketDescriptor {
+	fn clone(&self) -> Self {
+		Self {
+			conn: Arc::clone(&self.conn),
+			id: self.id,
+		}
+	}
+}
+impl Eq for SocketDescriptor {}
+impl PartialEq for SocketDescriptor {
+	fn eq(&self, o: &Self) -> bool {
+		self.id == o.id
+	}
+}
+impl Hash for SocketDescriptor {
+	fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+		self.id.hash(state);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use lightning::ln::net_test_utils::{self, TestPeerManager};
+	use bitcoin::secp256k1::key::PublicKey;
+
+	use std::net::TcpStream;
+	use std::sync::{mpsc, Arc};
+	use std::thread;
+
+	struct StdTransport {
+		event_notify: mpsc::SyncSender<()>,
+		_event_receiver: mpsc::Receiver<()>,
+	}
+	impl StdTransport {
+		fn new() -> Self {
+			let (event_notify, _event_receiver) = mpsc::sync_channel(2);
+			Self { event_notify, _event_receiver }
+		}
+	}
+	impl net_test_utils::Transport for StdTransport {
+		type Descriptor = super::SocketDescriptor;
+		type Connection = thread::JoinHandle<()>;
+
+		fn setup_outbound(&self, peer_manager: Arc<TestPeerManager<super::SocketDescriptor>>, their_node_id: PublicKey, stream: TcpStream) -> thread::JoinHandle<()> {
+			super::setup_outbound(peer_manager, self.event_notify.clone(), their_node_id, stream)
+		}
+		fn setup_inbound(&self, peer_manager: Arc<TestPeerManager<super::SocketDescriptor>>, stream: TcpStream) -> thread::JoinHandle<()> {
+			super::setup_inbound(peer_manager, self.event_notify.clone(), stream)
+		}
+		fn wait_for_close(&self, connection: thread::JoinHandle<()>) {
+			connection.join().unwrap();
+		}
+	}
+
+	#[test]
+	fn basic_connection_test() {
+		net_test_utils::do_basic_connection_test(&StdTransport::new());
+	}
+
+	#[test]
+	fn large_write_test() {
+		net_test_utils::do_large_write_test(&StdTransport::new());
+	}
+}
@@ -71,6 +71,13 @@
 //!     }
 //! }
 //! ```
+//!
+//! Rather than connecting to the peers you have channels with yourself, you may spawn
+//! [maintain_peer_connections](fn.maintain_peer_connections.html), which reconnects to them as
+//! needed.
+//!
+//! The [tor](tor/index.html) module provides for connecting to peers at Onion addresses through a
+//! SOCKS5 proxy, and for accepting connections on an Onion service.
 
 use bitcoin::secp256k1::key::PublicKey;
 
@@ -81,16 +88,21 @@ use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use lightning::ln::peer_handler;
 use lightning::ln::peer_handler::SocketDescriptor as LnSocketTrait;
-use lightning::ln::msgs::{ChannelMessageHandler, RoutingMessageHandler};
+use lightning::ln::channelmanager::ChannelDetails;
+use lightning::ln::msgs::{ChannelMessageHandler, NetAddress, RoutingMessageHandler};
+use lightning::ln::peer_connection_manager::{PeerAddressSource, PeerConnectionManager};
 use lightning::util::logger::Logger;
 
-use std::{task, thread};
-use std::net::SocketAddr;
+use std::{mem, task, thread};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::Deref;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::hash::Hash;
 
+pub mod tor;
+
 static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
 
 /// Connection contains all our internal state for a connection - we hold a reference to the
@@ -122,6 +134,10 @@ struct Connection {
 	block_disconnect_socket: bool,
 	read_paused: bool,
 	rl_requested_disconnect: bool,
+	// The number of SocketDescriptors pointing to us which are still alive. Once we're
+	// disconnected, we wait for rust-lightning to drop the ones it holds before completing, so
+	// that the Connection is freed by the time the future returned by setup_* completes.
+	descriptor_count: usize,
 	id: u64,
 }
 impl Connection {
@@ -216,6 +232,13 @@ impl Connection {
 			peer_manager_ref.socket_disconnected(&our_descriptor);
 			Self::event_trigger(&mut us.lock().unwrap());
 		}
+		// If rust-lightning told us to disconnect, it may not yet have dropped the
+		// SocketDescriptor(s) it called disconnect_socket() on (or returned an Err for). It will do
+		// so shortly, so wait for it instead of leaving the Connection around after we return.
+		mem::drop(our_descriptor);
+		while us.lock().unwrap().descriptor_count != 0 {
+			time::delay_for(Duration::from_millis(1)).await;
+		}
 	}
 
 	fn new(event_notify: mpsc::Sender<()>, stream: TcpStream) -> (io::ReadHalf<TcpStream>, mpsc::Receiver<()>, mpsc::Receiver<()>, Arc<Mutex<Self>>) {
@@ -234,7 +257,7 @@ impl Connection {
 		(reader, write_receiver, read_receiver,
 		Arc::new(Mutex::new(Self {
 			writer: Some(writer), event_notify, write_avail, read_waker, read_paused: false,
-			block_disconnect_socket: false, rl_requested_disconnect: false,
+			block_disconnect_socket: false, rl_requested_disconnect: false, descriptor_count: 0,
 			id: ID_COUNTER.fetch_add(1, Ordering::AcqRel)
 		})))
 	}
@@ -252,11 +275,12 @@ pub fn setup_inbound<CMH, RMH, L>(peer_manager: Arc<peer_handler::PeerManager<So
 		CMH: ChannelMessageHandler + 'static,
 		RMH: RoutingMessageHandler + 'static,
 		L: Logger + 'static + ?Sized {
+	let remote_addr = stream.peer_addr().ok().map(|addr| from_socket_addr(&addr));
 	let (reader, write_receiver, read_receiver, us) = Connection::new(event_notify, stream);
 	#[cfg(debug_assertions)]
 	let last_us = Arc::clone(&us);
 
-	let handle_opt = if let Ok(_) = peer_manager.new_inbound_connection(SocketDescriptor::new(us.clone())) {
+	let handle_opt = if let Ok(_) = peer_manager.new_inbound_connection_with_address(SocketDescriptor::new(us.clone()), remote_addr) {
 		Some(tokio::spawn(Connection::schedule_read(peer_manager, us, reader, read_receiver, write_receiver)))
 	} else {
 		// Note that we will skip socket_disconnected here, in accordance with the PeerManager
@@ -371,6 +395,74 @@ pub async fn connect_outbound<CMH, RMH, L>(peer_manager: Arc<peer_handler::PeerM
 	} else { None }
 }
 
+/// Converts a peer's NetAddress to a SocketAddr we can connect to, if it's an IPv4 or IPv6 one.
+fn to_socket_addr(address: &NetAddress) -> Option<SocketAddr> {
+	match address {
+		&NetAddress::IPv4 { addr, port } => Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(addr)), port)),
+		&NetAddress::IPv6 { addr, port } => Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(addr)), port)),
+		_ => None,
+	}
+}
+
+/// Converts a SocketAddr to the NetAddress the PeerManager understands.
+fn from_socket_addr(address: &SocketAddr) -> NetAddress {
+	match address {
+		&SocketAddr::V4(addr) => NetAddress::IPv4 { addr: addr.ip().octets(), port: addr.port() },
+		&SocketAddr::V6(addr) => NetAddress::IPv6 { addr: addr.ip().octets(), port: addr.port() },
+	}
+}
+
+/// Keeps us connected to the peers we have channels with, connecting to them when
+/// `connection_manager` tells us to (by scheduling futures with tokio::spawn).
+///
+/// `list_channels` should return the result of ChannelManager::list_channels, and is called every
+/// second, when we also check for peers to (re)connect to. We connect to peers at the first of
+/// their IPv4 and IPv6 addresses which accepts our TCP connection, and tell `connection_manager`
+/// once we failed to, or once the connection closed, so that it schedules a reconnection.
+///
+/// If `socks5_proxy` is set, we instead make all connections through that proxy (see
+/// [tor::connect_outbound_via_proxy](tor/fn.connect_outbound_via_proxy.html)), which, if it is
+/// Tor's SocksPort, also lets us connect to peers at their Onion addresses.
+///
+/// The returned future never completes, and should thus be spawned with tokio::spawn.
+///
+/// See the module-level documentation for how to handle the event_notify mpsc::Sender.
+pub async fn maintain_peer_connections<CMH, RMH, L, A, CL, F>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<RMH>, Arc<L>>>, event_notify: mpsc::Sender<()>, connection_manager: Arc<PeerConnectionManager<A, CL>>, list_channels: F, socks5_proxy: Option<SocketAddr>) where
+		CMH: ChannelMessageHandler + 'static,
+		RMH: RoutingMessageHandler + 'static,
+		L: Logger + 'static + ?Sized,
+		A: Deref + Send + Sync + 'static,
+		A::Target: PeerAddressSource,
+		CL: Deref + Send + Sync + 'static,
+		CL::Target: Logger,
+		F: Fn() -> Vec<ChannelDetails> {
+	loop {
+		connection_manager.set_channel_peers(&list_channels());
+		for (their_node_id, addresses) in connection_manager.get_peers_to_connect(&peer_manager.get_peer_node_ids(), Instant::now()) {
+			let peer_manager = Arc::clone(&peer_manager);
+			let event_notify = event_notify.clone();
+			let connection_manager = Arc::clone(&connection_manager);
+			tokio::spawn(async move {
+				for address in addresses.iter() {
+					if let Some(proxy) = socks5_proxy {
+						if let Some(connection) = tor::connect_outbound_via_proxy(Arc::clone(&peer_manager), event_notify.clone(), their_node_id, proxy, address).await {
+							connection.await;
+							break;
+						}
+					} else if let Some(addr) = to_socket_addr(address) {
+						if let Some(connection) = connect_outbound(Arc::clone(&peer_manager), event_notify.clone(), their_node_id, addr).await {
+							connection.await;
+							break;
+						}
+					}
+				}
+				connection_manager.connection_closed(&their_node_id, Instant::now());
+			});
+		}
+		time::delay_for(Duration::from_secs(1)).await;
+	}
+}
+
 const SOCK_WAKER_VTABLE: task::RawWakerVTable =
 	task::RawWakerVTable::new(clone_socket_waker, wake_socket_waker, wake_socket_waker_by_ref, drop_socket_waker);
 
@@ -409,7 +501,11 @@ pub struct SocketDescriptor {
 }
 impl SocketDescriptor {
 	fn new(conn: Arc<Mutex<Connection>>) -> Self {
-		let id = conn.lock().unwrap().id;
+		let id = {
+			let mut us = conn.lock().unwrap();
+			us.descriptor_count += 1;
+			us.id
+		};
 		Self { conn, id }
 	}
 }
@@ -483,10 +579,12 @@ impl peer_handler::SocketDescriptor for SocketDescriptor {
 }
 impl Clone for SocketDescriptor {
 	fn clone(&self) -> Self {
-		Self {
-			conn: Arc::clone(&self.conn),
-			id: self.id,
-		}
+		Self::new(Arc::clone(&self.conn))
+	}
+}
+impl Drop for SocketDescriptor {
+	fn drop(&mut self) {
+		self.conn.lock().unwrap().descriptor_count -= 1;
 	}
 }
 impl Eq for SocketDescriptor {}
@@ -505,147 +603,159 @@ impl Hash for SocketDescriptor {
 mod tests {
 	use lightning::ln::features::*;
 	use lightning::ln::msgs::*;
-	use lightning::ln::peer_handler::{MessageHandler, PeerManager};
+	use lightning::ln::channelmanager::ChannelDetails;
+	use lightning::ln::net_test_utils::{self, TestPeerManager};
+	use lightning::ln::peer_connection_manager::{PeerAddressSource, PeerConnectionManager, PeerConnectionState};
 	use lightning::util::events::*;
-	use bitcoin::secp256k1::{Secp256k1, SecretKey, PublicKey};
+	use lightning::util::test_utils::TestLogger;
+	use bitcoin::secp256k1::key::PublicKey;
 
-	use tokio::sync::mpsc;
+	use tokio::runtime;
+	use tokio::sync::{mpsc, oneshot};
 
-	use std::mem;
-	use std::sync::{Arc, Mutex};
+	use std::net::TcpStream;
+	use std::sync::Arc;
+	use std::thread;
 	use std::time::Duration;
 
-	pub struct TestLogger();
-	impl lightning::util::logger::Logger for TestLogger {
-		fn log(&self, record: &lightning::util::logger::Record) {
-			println!("{:<5} [{} : {}, {}] {}", record.level.to_string(), record.module_path, record.file, record.line, record.args);
-		}
+	struct TestAddressSource(Vec<NetAddress>);
+	impl PeerAddressSource for TestAddressSource {
+		fn get_peer_addresses(&self, _node_id: &PublicKey) -> Vec<NetAddress> { self.0.clone() }
 	}
 
-	struct MsgHandler{
-		expected_pubkey: PublicKey,
-		pubkey_connected: mpsc::Sender<()>,
-		pubkey_disconnected: mpsc::Sender<()>,
-		msg_events: Mutex<Vec<MessageSendEvent>>,
-	}
-	impl RoutingMessageHandler for MsgHandler {
-		fn handle_node_announcement(&self, _msg: &NodeAnnouncement) -> Result<bool, LightningError> { Ok(false) }
-		fn handle_channel_announcement(&self, _msg: &ChannelAnnouncement) -> Result<bool, LightningError> { Ok(false) }
-		fn handle_channel_update(&self, _msg: &ChannelUpdate) -> Result<bool, LightningError> { Ok(false) }
-		fn handle_htlc_fail_channel_update(&self, _update: &HTLCFailChannelUpdate) { }
-		fn get_next_channel_announcements(&self, _starting_point: u64, _batch_amount: u8) -> Vec<(ChannelAnnouncement, Option<ChannelUpdate>, Option<ChannelUpdate>)> { Vec::new() }
-		fn get_next_node_announcements(&self, _starting_point: Option<&PublicKey>, _batch_amount: u8) -> Vec<NodeAnnouncement> { Vec::new() }
-		fn should_request_full_sync(&self, _node_id: &PublicKey) -> bool { false }
+	/// Runs the connections on a tokio runtime driven by a separate thread, so that the shared
+	/// tests can block the test thread.
+	struct TokioTransport {
+		runtime: runtime::Handle,
+		event_notify: mpsc::Sender<()>,
+		_event_receiver: mpsc::Receiver<()>,
+		shutdown: Option<oneshot::Sender<()>>,
+		runtime_thread: Option<thread::JoinHandle<()>>,
 	}
-	impl ChannelMessageHandler for MsgHandler {
-		fn handle_open_channel(&self, _their_node_id: &PublicKey, _their_features: InitFeatures, _msg: &OpenChannel) {}
-		fn handle_accept_channel(&self, _their_node_id: &PublicKey, _their_features: InitFeatures, _msg: &AcceptChannel) {}
-		fn handle_funding_created(&self, _their_node_id: &PublicKey, _msg: &FundingCreated) {}
-		fn handle_funding_signed(&self, _their_node_id: &PublicKey, _msg: &FundingSigned) {}
-		fn handle_funding_locked(&self, _their_node_id: &PublicKey, _msg: &FundingLocked) {}
-		fn handle_shutdown(&self, _their_node_id: &PublicKey, _msg: &Shutdown) {}
-		fn handle_closing_signed(&self, _their_node_id: &PublicKey, _msg: &ClosingSigned) {}
-		fn handle_update_add_htlc(&self, _their_node_id: &PublicKey, _msg: &UpdateAddHTLC) {}
-		fn handle_update_fulfill_htlc(&self, _their_node_id: &PublicKey, _msg: &UpdateFulfillHTLC) {}
-		fn handle_update_fail_htlc(&self, _their_node_id: &PublicKey, _msg: &UpdateFailHTLC) {}
-		fn handle_update_fail_malformed_htlc(&self, _their_node_id: &PublicKey, _msg: &UpdateFailMalformedHTLC) {}
-		fn handle_commitment_signed(&self, _their_node_id: &PublicKey, _msg: &CommitmentSigned) {}
-		fn handle_revoke_and_ack(&self, _their_node_id: &PublicKey, _msg: &RevokeAndACK) {}
-		fn handle_update_fee(&self, _their_node_id: &PublicKey, _msg: &UpdateFee) {}
-		fn handle_announcement_signatures(&self, _their_node_id: &PublicKey, _msg: &AnnouncementSignatures) {}
-		fn peer_disconnected(&self, their_node_id: &PublicKey, _no_connection_possible: bool) {
-			if *their_node_id == self.expected_pubkey {
-				self.pubkey_disconnected.clone().try_send(()).unwrap();
-			}
+	impl TokioTransport {
+		fn new(threaded: bool) -> Self {
+			let mut builder = runtime::Builder::new();
+			if threaded { builder.threaded_scheduler(); } else { builder.basic_scheduler(); }
+			let mut rt = builder.enable_all().build().unwrap();
+			let runtime = rt.handle().clone();
+			let (shutdown, shutdown_receiver) = oneshot::channel();
+			let runtime_thread = thread::spawn(move || { let _ = rt.block_on(shutdown_receiver); });
+			let (event_notify, _event_receiver) = mpsc::channel(2);
+			Self { runtime, event_notify, _event_receiver, shutdown: Some(shutdown), runtime_thread: Some(runtime_thread) }
 		}
-		fn peer_connected(&self, their_node_id: &PublicKey, _msg: &Init) {
-			if *their_node_id == self.expected_pubkey {
-				self.pubkey_connected.clone().try_send(()).unwrap();
-			}
+	}
+	impl Drop for TokioTransport {
+		fn drop(&mut self) {
+			let _ = self.shutdown.take().unwrap().send(());
+			self.runtime_thread.take().unwrap().join().unwrap();
 		}
-		fn handle_channel_reestablish(&self, _their_node_id: &PublicKey, _msg: &ChannelReestablish) {}
-		fn handle_error(&self, _their_node_id: &PublicKey, _msg: &ErrorMessage) {}
 	}
-	impl MessageSendEventsProvider for MsgHandler {
-		fn get_and_clear_pending_msg_events(&self) -> Vec<MessageSendEvent> {
-			let mut ret = Vec::new();
-			mem::swap(&mut *self.msg_events.lock().unwrap(), &mut ret);
-			ret
+	impl net_test_utils::Transport for TokioTransport {
+		type Descriptor = super::SocketDescriptor;
+		type Connection = std::sync::mpsc::Receiver<()>;
+
+		fn setup_outbound(&self, peer_manager: Arc<TestPeerManager<super::SocketDescriptor>>, their_node_id: PublicKey, stream: TcpStream) -> Self::Connection {
+			let (done_sender, done) = std::sync::mpsc::sync_channel(1);
+			let event_notify = self.event_notify.clone();
+			self.runtime.spawn(async move {
+				let stream = tokio::net::TcpStream::from_std(stream).unwrap();
+				super::setup_outbound(peer_manager, event_notify, their_node_id, stream).await;
+				done_sender.send(()).unwrap();
+			});
+			done
+		}
+		fn setup_inbound(&self, peer_manager: Arc<TestPeerManager<super::SocketDescriptor>>, stream: TcpStream) -> Self::Connection {
+			let (done_sender, done) = std::sync::mpsc::sync_channel(1);
+			let event_notify = self.event_notify.clone();
+			self.runtime.spawn(async move {
+				let stream = tokio::net::TcpStream::from_std(stream).unwrap();
+				super::setup_inbound(peer_manager, event_notify, stream).await;
+				done_sender.send(()).unwrap();
+			});
+			done
+		}
+		fn wait_for_close(&self, connection: Self::Connection) {
+			connection.recv_timeout(Duration::from_secs(10)).unwrap();
 		}
 	}
 
-	async fn do_basic_connection_test() {
-		let secp_ctx = Secp256k1::new();
-		let a_key = SecretKey::from_slice(&[1; 32]).unwrap();
-		let b_key = SecretKey::from_slice(&[1; 32]).unwrap();
-		let a_pub = PublicKey::from_secret_key(&secp_ctx, &a_key);
-		let b_pub = PublicKey::from_secret_key(&secp_ctx, &b_key);
-
-		let (a_connected_sender, mut a_connected) = mpsc::channel(1);
-		let (a_disconnected_sender, mut a_disconnected) = mpsc::channel(1);
-		let a_handler = Arc::new(MsgHandler {
-			expected_pubkey: b_pub,
-			pubkey_connected: a_connected_sender,
-			pubkey_disconnected: a_disconnected_sender,
-			msg_events: Mutex::new(Vec::new()),
-		});
-		let a_manager = Arc::new(PeerManager::new(MessageHandler {
-			chan_handler: Arc::clone(&a_handler),
-			route_handler: Arc::clone(&a_handler),
-		}, a_key.clone(), &[1; 32], Arc::new(TestLogger())));
-
-		let (b_connected_sender, mut b_connected) = mpsc::channel(1);
-		let (b_disconnected_sender, mut b_disconnected) = mpsc::channel(1);
-		let b_handler = Arc::new(MsgHandler {
-			expected_pubkey: a_pub,
-			pubkey_connected: b_connected_sender,
-			pubkey_disconnected: b_disconnected_sender,
-			msg_events: Mutex::new(Vec::new()),
+	fn do_reconnection_test(threaded: bool) {
+		let transport = TokioTransport::new(threaded);
+		let (a, b) = net_test_utils::create_nodes();
+
+		// b accepts connections, and a knows b's address from the address source.
+		let std_listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Failed to bind to v4 localhost");
+		let port = std_listener.local_addr().unwrap().port();
+		let b_manager = Arc::clone(&b.manager);
+		let accept_sender = transport.event_notify.clone();
+		transport.runtime.spawn(async move {
+			let mut listener = tokio::net::TcpListener::from_std(std_listener).unwrap();
+			loop {
+				let (stream, _) = listener.accept().await.unwrap();
+				tokio::spawn(super::setup_inbound(Arc::clone(&b_manager), accept_sender.clone(), stream));
+			}
 		});
-		let b_manager = Arc::new(PeerManager::new(MessageHandler {
-			chan_handler: Arc::clone(&b_handler),
-			route_handler: Arc::clone(&b_handler),
-		}, b_key.clone(), &[2; 32], Arc::new(TestLogger())));
-
-		// We bind on localhost, hoping the environment is properly configured with a local
-		// address. This may not always be the case in containers and the like, so if this test is
-		// failing for you check that you have a loopback interface and it is configured with
-		// 127.0.0.1.
-		let (conn_a, conn_b) = if let Ok(listener) = std::net::TcpListener::bind("127.0.0.1:9735") {
-			(std::net::TcpStream::connect("127.0.0.1:9735").unwrap(), listener.accept().unwrap().0)
-		} else if let Ok(listener) = std::net::TcpListener::bind("127.0.0.1:9999") {
-			(std::net::TcpStream::connect("127.0.0.1:9999").unwrap(), listener.accept().unwrap().0)
-		} else if let Ok(listener) = std::net::TcpListener::bind("127.0.0.1:46926") {
-			(std::net::TcpStream::connect("127.0.0.1:46926").unwrap(), listener.accept().unwrap().0)
-		} else { panic!("Failed to bind to v4 localhost on common ports"); };
-
-		let (sender, _receiver) = mpsc::channel(2);
-		let fut_a = super::setup_outbound(Arc::clone(&a_manager), sender.clone(), b_pub, tokio::net::TcpStream::from_std(conn_a).unwrap());
-		let fut_b = super::setup_inbound(b_manager, sender, tokio::net::TcpStream::from_std(conn_b).unwrap());
-
-		tokio::time::timeout(Duration::from_secs(10), a_connected.recv()).await.unwrap();
-		tokio::time::timeout(Duration::from_secs(1), b_connected.recv()).await.unwrap();
-
-		a_handler.msg_events.lock().unwrap().push(MessageSendEvent::HandleError {
-			node_id: b_pub, action: ErrorAction::DisconnectPeer { msg: None }
+
+		let address_source = Arc::new(TestAddressSource(vec![NetAddress::IPv4 { addr: [127, 0, 0, 1], port }]));
+		let connection_manager = Arc::new(PeerConnectionManager::new(address_source, Arc::new(TestLogger::new())));
+		let b_pub = b.pubkey;
+		transport.runtime.spawn(super::maintain_peer_connections(Arc::clone(&a.manager), transport.event_notify.clone(), Arc::clone(&connection_manager), move || vec![ChannelDetails {
+			channel_id: [0; 32],
+			short_channel_id: None,
+			remote_network_id: b_pub,
+			counterparty_features: InitFeatures::known(),
+			channel_value_satoshis: 0,
+			user_id: 0,
+			outbound_capacity_msat: 0,
+			inbound_capacity_msat: 0,
+			is_live: false,
+		}], None));
+
+		a.connected.recv_timeout(Duration::from_secs(10)).unwrap();
+		b.connected.recv_timeout(Duration::from_secs(1)).unwrap();
+
+		// Once disconnected, a reconnects to b.
+		a.handler.msg_events.lock().unwrap().push(MessageSendEvent::HandleError {
+			node_id: b.pubkey, action: ErrorAction::DisconnectPeer { msg: None }
 		});
-		assert!(a_disconnected.try_recv().is_err());
-		assert!(b_disconnected.try_recv().is_err());
+		a.manager.process_events();
+		a.disconnected.recv_timeout(Duration::from_secs(10)).unwrap();
+		b.disconnected.recv_timeout(Duration::from_secs(1)).unwrap();
+
+		a.connected.recv_timeout(Duration::from_secs(10)).unwrap();
+		b.connected.recv_timeout(Duration::from_secs(1)).unwrap();
+		// The connection state is only updated once maintain_peer_connections next runs.
+		for _ in 0..100 {
+			if connection_manager.get_peer_states() == vec![(b.pubkey, PeerConnectionState::Connected)] { return; }
+			thread::sleep(Duration::from_millis(100));
+		}
+		panic!("Peer state never got back to Connected");
+	}
 
-		a_manager.process_events();
-		tokio::time::timeout(Duration::from_secs(10), a_disconnected.recv()).await.unwrap();
-		tokio::time::timeout(Duration::from_secs(1), b_disconnected.recv()).await.unwrap();
+	#[test]
+	fn threaded_reconnection_test() {
+		do_reconnection_test(true);
+	}
+	#[test]
+	fn unthreaded_reconnection_test() {
+		do_reconnection_test(false);
+	}
 
-		fut_a.await;
-		fut_b.await;
+	#[test]
+	fn basic_threaded_connection_test() {
+		net_test_utils::do_basic_connection_test(&TokioTransport::new(true));
+	}
+	#[test]
+	fn basic_unthreaded_connection_test() {
+		net_test_utils::do_basic_connection_test(&TokioTransport::new(false));
 	}
 
-	#[tokio::test(threaded_scheduler)]
-	async fn basic_threaded_connection_test() {
-		do_basic_connection_test().await;
+	#[test]
+	fn large_write_threaded_test() {
+		net_test_utils::do_large_write_test(&TokioTransport::new(true));
 	}
-	#[tokio::test]
-	async fn basic_unthreaded_connection_test() {
-		do_basic_connection_test().await;
+	#[test]
+	fn large_write_unthreaded_test() {
+		net_test_utils::do_large_write_test(&TokioTransport::new(false));
 	}
 }
@@ -0,0 +1,428 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Tor support, both to connect to peers through a SOCKS5 proxy (ie Tor's SocksPort), which lets
+//! us reach peers at Onion addresses, and to accept connections from peers on an Onion service we
+//! publish through Tor's control port.
+//!
+//! Note that the Onion service only lives as long as the [TorControl](struct.TorControl.html)
+//! connection which published it, so you must keep it around.
+
+use bitcoin::secp256k1::key::PublicKey;
+
+use tokio::net::TcpStream;
+use tokio::{io, time};
+use tokio::sync::mpsc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+use lightning::chain;
+use lightning::chain::chaininterface::{BroadcasterInterface, FeeEstimator};
+use lightning::chain::keysinterface::{ChannelKeys, KeysInterface};
+use lightning::ln::channelmanager::ChannelManager;
+use lightning::ln::peer_handler;
+use lightning::ln::msgs::{ChannelMessageHandler, NetAddress, RoutingMessageHandler};
+use lightning::util::logger::Logger;
+
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{setup_outbound, SocketDescriptor};
+
+/// How long we give the SOCKS5 proxy to connect us to a peer. Building a circuit to an Onion
+/// service may take quite a bit longer than a direct TCP connection.
+const PROXY_CONNECT_TIMEOUT_SECS: u64 = 30;
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+fn base32_encode(data: &[u8]) -> String {
+	let mut res = String::with_capacity((data.len() * 8 + 4) / 5);
+	let mut buffer: u16 = 0;
+	let mut bits = 0;
+	for byte in data {
+		buffer = (buffer << 8) | *byte as u16;
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			res.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+		}
+	}
+	if bits > 0 {
+		res.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+	}
+	res
+}
+
+fn base32_decode(data: &str) -> Option<Vec<u8>> {
+	let mut res = Vec::with_capacity(data.len() * 5 / 8);
+	let mut buffer: u16 = 0;
+	let mut bits = 0;
+	for c in data.bytes() {
+		let val = BASE32_ALPHABET.iter().position(|a| *a == c.to_ascii_lowercase())? as u16;
+		buffer = (buffer << 5) | val;
+		bits += 5;
+		if bits >= 8 {
+			bits -= 8;
+			res.push((buffer >> bits) as u8);
+		}
+	}
+	Some(res)
+}
+
+/// Returns the hostname (ie "<base32>.onion") of the given Onion address, or None if it isn't
+/// one.
+pub fn onion_hostname(address: &NetAddress) -> Option<String> {
+	match address {
+		&NetAddress::OnionV2 { ref addr, .. } => Some(format!("{}.onion", base32_encode(addr))),
+		&NetAddress::OnionV3 { ref ed25519_pubkey, checksum, version, .. } => {
+			let mut data = [0; 35];
+			data[..32].copy_from_slice(ed25519_pubkey);
+			data[32..34].copy_from_slice(&[(checksum >> 8) as u8, checksum as u8]);
+			data[34] = version;
+			Some(format!("{}.onion", base32_encode(&data)))
+		},
+		_ => None,
+	}
+}
+
+/// Parses a v3 Onion address, with or without its ".onion" suffix, into a NetAddress with the
+/// given port.
+///
+/// Note that the checksum is not verified.
+pub fn parse_onion_v3(hostname: &str, port: u16) -> Option<NetAddress> {
+	let hostname = hostname.trim_end_matches(".onion");
+	if hostname.len() != 56 { return None; }
+	let data = base32_decode(hostname)?;
+	let mut ed25519_pubkey = [0; 32];
+	ed25519_pubkey.copy_from_slice(&data[..32]);
+	Some(NetAddress::OnionV3 {
+		ed25519_pubkey,
+		checksum: ((data[32] as u16) << 8) | data[33] as u16,
+		version: data[34],
+		port,
+	})
+}
+
+fn proxy_error(err: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::Other, err)
+}
+
+/// Opens a TCP connection to the given address through the SOCKS5 proxy at `proxy`, which
+/// may be any NetAddress, including Onion addresses if the proxy is Tor's SocksPort.
+///
+/// We do not authenticate to the proxy.
+pub async fn socks5_connect(proxy: SocketAddr, addr: &NetAddress) -> Result<TcpStream, io::Error> {
+	let mut stream = TcpStream::connect(&proxy).await?;
+
+	// Greeting, offering only the "no authentication required" method.
+	stream.write_all(&[5, 1, 0]).await?;
+	let mut method = [0; 2];
+	stream.read_exact(&mut method).await?;
+	if method != [5, 0] {
+		return Err(proxy_error("SOCKS5 proxy refused unauthenticated connections"));
+	}
+
+	let mut request = vec![5, 1, 0];
+	let port = match addr {
+		&NetAddress::IPv4 { ref addr, port } => {
+			request.push(1);
+			request.extend_from_slice(addr);
+			port
+		},
+		&NetAddress::IPv6 { ref addr, port } => {
+			request.push(4);
+			request.extend_from_slice(addr);
+			port
+		},
+		&NetAddress::OnionV2 { port, .. } | &NetAddress::OnionV3 { port, .. } => {
+			let hostname = onion_hostname(addr).unwrap();
+			request.push(3);
+			request.push(hostname.len() as u8);
+			request.extend_from_slice(hostname.as_bytes());
+			port
+		},
+	};
+	request.extend_from_slice(&[(port >> 8) as u8, port as u8]);
+	stream.write_all(&request).await?;
+
+	let mut reply = [0; 4];
+	stream.read_exact(&mut reply).await?;
+	if reply[0] != 5 {
+		return Err(proxy_error("SOCKS5 proxy sent an invalid reply"));
+	}
+	if reply[1] != 0 {
+		return Err(io::Error::new(io::ErrorKind::Other, format!("SOCKS5 proxy failed to connect with error {}", reply[1])));
+	}
+	// Skip the address the proxy bound to, which we don't care about.
+	let bound_addr_len = match reply[3] {
+		1 => 4,
+		3 => {
+			let mut len = [0; 1];
+			stream.read_exact(&mut len).await?;
+			len[0] as usize
+		},
+		4 => 16,
+		_ => return Err(proxy_error("SOCKS5 proxy sent an invalid reply")),
+	};
+	let mut bound_addr = vec![0; bound_addr_len + 2];
+	stream.read_exact(&mut bound_addr).await?;
+	Ok(stream)
+}
+
+/// Process incoming messages and feed outgoing messages on a new connection made through the
+/// SOCKS5 proxy at `proxy` to the given address, which is expected to be accepted by a peer with
+/// the given public key.
+///
+/// Works like [connect_outbound](../fn.connect_outbound.html), but can reach peers at Onion
+/// addresses if `proxy` is Tor's SocksPort.
+pub async fn connect_outbound_via_proxy<CMH, RMH, L>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<RMH>, Arc<L>>>, event_notify: mpsc::Sender<()>, their_node_id: PublicKey, proxy: SocketAddr, addr: &NetAddress) -> Option<impl std::future::Future<Output=()>> where
+		CMH: ChannelMessageHandler + 'static,
+		RMH: RoutingMessageHandler + 'static,
+		L: Logger + 'static + ?Sized {
+	if let Ok(Ok(stream)) = time::timeout(Duration::from_secs(PROXY_CONNECT_TIMEOUT_SECS), socks5_connect(proxy, addr)).await {
+		Some(setup_outbound(peer_manager, event_notify, their_node_id, stream))
+	} else { None }
+}
+
+/// How we authenticate to Tor's control port, see the Tor control protocol specification.
+pub enum TorAuthentication {
+	/// No authentication, if Tor's control port isn't protected at all.
+	Null,
+	/// The control port's HashedControlPassword.
+	Password(String),
+	/// The contents of Tor's control_auth_cookie file.
+	Cookie([u8; 32]),
+}
+
+/// A connection to Tor's control port, through which we can publish Onion services.
+pub struct TorControl {
+	stream: BufReader<TcpStream>,
+}
+
+impl TorControl {
+	/// Connects and authenticates to the Tor control port at `addr`.
+	pub async fn connect(addr: SocketAddr, auth: TorAuthentication) -> Result<Self, io::Error> {
+		let mut control = TorControl { stream: BufReader::new(TcpStream::connect(&addr).await?) };
+		let command = match auth {
+			TorAuthentication::Null => "AUTHENTICATE".to_string(),
+			TorAuthentication::Password(password) => {
+				format!("AUTHENTICATE \"{}\"", password.replace('\\', "\\\\").replace('"', "\\\""))
+			},
+			TorAuthentication::Cookie(cookie) => {
+				let hex: String = cookie.iter().map(|b| format!("{:02x}", b)).collect();
+				format!("AUTHENTICATE {}", hex)
+			},
+		};
+		control.send_command(&command).await?;
+		Ok(control)
+	}
+
+	/// Sends a command, returning the lines of Tor's reply (without the status code) if it
+	/// succeeded.
+	async fn send_command(&mut self, command: &str) -> Result<Vec<String>, io::Error> {
+		self.stream.get_mut().write_all(format!("{}\r\n", command).as_bytes()).await?;
+		let mut lines = Vec::new();
+		loop {
+			let mut line = String::new();
+			if self.stream.read_line(&mut line).await? == 0 {
+				return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Tor closed the control connection"));
+			}
+			let line = line.trim_end_matches(|c| c == '\r' || c == '\n');
+			if line.len() < 4 || !line.is_char_boundary(4) {
+				return Err(proxy_error("Tor sent an invalid control reply"));
+			}
+			if !line.starts_with("250") {
+				return Err(io::Error::new(io::ErrorKind::Other, format!("Tor control command failed: {}", line)));
+			}
+			lines.push(line[4..].to_string());
+			if line.as_bytes()[3] == b' ' { return Ok(lines); }
+		}
+	}
+
+	/// Publishes a v3 Onion service, whose connections on `virtual_port` are forwarded to
+	/// `target`, on which we should accept connections and hand them to
+	/// [setup_inbound](../fn.setup_inbound.html).
+	///
+	/// If `private_key` is set (as a "ED25519-V3:<base64>" key blob previously returned here), we
+	/// publish the Onion service with that key, keeping its address across restarts, otherwise Tor
+	/// generates a new one.
+	///
+	/// Returns the Onion service's address along with its private key.
+	pub async fn add_onion(&mut self, private_key: Option<&str>, virtual_port: u16, target: SocketAddr) -> Result<(NetAddress, String), io::Error> {
+		let key = private_key.unwrap_or("NEW:ED25519-V3");
+		let reply = self.send_command(&format!("ADD_ONION {} Port={},{}", key, virtual_port, target)).await?;
+
+		let mut address = None;
+		let mut new_private_key = None;
+		for line in reply {
+			if line.starts_with("ServiceID=") {
+				address = parse_onion_v3(&line["ServiceID=".len()..], virtual_port);
+			} else if line.starts_with("PrivateKey=") {
+				new_private_key = Some(line["PrivateKey=".len()..].to_string());
+			}
+		}
+		match (address, new_private_key.or(private_key.map(|key| key.to_string()))) {
+			(Some(address), Some(key)) => Ok((address, key)),
+			_ => Err(proxy_error("Tor did not give us a valid v3 Onion service")),
+		}
+	}
+}
+
+/// Publishes a v3 Onion service through `control` (see [TorControl::add_onion]) and announces it
+/// to the network, along with `addresses`, through
+/// [ChannelManager::broadcast_node_announcement].
+///
+/// Note that, as with any node_announcement, it will be ignored unless we have public channels.
+///
+/// Returns the Onion service's address along with its private key.
+///
+/// [TorControl::add_onion]: struct.TorControl.html#method.add_onion
+/// [ChannelManager::broadcast_node_announcement]: ../../lightning/ln/channelmanager/struct.ChannelManager.html#method.broadcast_node_announcement
+pub async fn announce_onion_service<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>(control: &mut TorControl, private_key: Option<&str>, virtual_port: u16, target: SocketAddr, channel_manager: &ChannelManager<ChanSigner, M, T, K, F, L>, rgb: [u8; 3], alias: [u8; 32], mut addresses: Vec<NetAddress>) -> Result<(NetAddress, String), io::Error>
+	where M::Target: chain::Watch<Keys=ChanSigner>,
+        T::Target: BroadcasterInterface,
+        K::Target: KeysInterface<ChanKeySigner = ChanSigner>,
+        F::Target: FeeEstimator,
+        L::Target: Logger,
+{
+	let (address, key) = control.add_onion(private_key, virtual_port, target).await?;
+	addresses.push(address.clone());
+	channel_manager.broadcast_node_announcement(rgb, alias, addresses);
+	Ok((address, key))
+}
+
+#[cfg(test)]
+mod tests {
+	use lightning::ln::msgs::NetAddress;
+	use super::*;
+
+	use tokio::net::TcpListener;
+
+	#[test]
+	fn onion_hostnames() {
+		let hostname = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd.onion";
+		let address = parse_onion_v3(hostname, 9735).unwrap();
+		match address {
+			NetAddress::OnionV3 { version, port, .. } => {
+				assert_eq!(version, 3);
+				assert_eq!(port, 9735);
+			},
+			_ => panic!(),
+		}
+		assert_eq!(onion_hostname(&address).unwrap(), hostname);
+		assert!(parse_onion_v3("vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyy.onion", 9735).is_none());
+		assert!(parse_onion_v3("vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyy1.onion", 9735).is_none());
+
+		assert_eq!(onion_hostname(&NetAddress::OnionV2 { addr: [0xff; 10], port: 9735 }).unwrap(), "7777777777777777.onion");
+		assert!(onion_hostname(&NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }).is_none());
+	}
+
+	async fn bind_localhost() -> TcpListener {
+		TcpListener::bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap()).await.unwrap()
+	}
+
+	/// Accepts a single SOCKS5 connection, checks the requested hostname and port, then forwards
+	/// the connection to `target`.
+	async fn mock_socks5_server(mut listener: TcpListener, expected_hostname: String, expected_port: u16, target: SocketAddr) {
+		let (mut stream, _) = listener.accept().await.unwrap();
+		let mut greeting = [0; 3];
+		stream.read_exact(&mut greeting).await.unwrap();
+		assert_eq!(greeting, [5, 1, 0]);
+		stream.write_all(&[5, 0]).await.unwrap();
+
+		let mut request = [0; 5];
+		stream.read_exact(&mut request).await.unwrap();
+		assert_eq!(request[..4], [5, 1, 0, 3]);
+		let mut hostname = vec![0; request[4] as usize];
+		stream.read_exact(&mut hostname).await.unwrap();
+		assert_eq!(String::from_utf8(hostname).unwrap(), expected_hostname);
+		let mut port = [0; 2];
+		stream.read_exact(&mut port).await.unwrap();
+		assert_eq!(((port[0] as u16) << 8) | port[1] as u16, expected_port);
+
+		let target_stream = TcpStream::connect(&target).await.unwrap();
+		stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).await.unwrap();
+
+		let (mut stream_read, mut stream_write) = io::split(stream);
+		let (mut target_read, mut target_write) = io::split(target_stream);
+		tokio::spawn(async move { let _ = io::copy(&mut stream_read, &mut target_write).await; });
+		tokio::spawn(async move { let _ = io::copy(&mut target_read, &mut stream_write).await; });
+	}
+
+	#[tokio::test]
+	async fn socks5_connection() {
+		let onion_address = parse_onion_v3("vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd", 9735).unwrap();
+
+		let mut target = bind_localhost().await;
+		let target_addr = target.local_addr().unwrap();
+		let proxy = bind_localhost().await;
+		let proxy_addr = proxy.local_addr().unwrap();
+		tokio::spawn(mock_socks5_server(proxy, onion_hostname(&onion_address).unwrap(), 9735, target_addr));
+
+		let mut stream = socks5_connect(proxy_addr, &onion_address).await.unwrap();
+		let (mut target_stream, _) = target.accept().await.unwrap();
+		stream.write_all(b"ping").await.unwrap();
+		let mut buf = [0; 4];
+		target_stream.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"ping");
+		target_stream.write_all(b"pong").await.unwrap();
+		stream.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"pong");
+	}
+
+	#[tokio::test]
+	async fn socks5_connection_failure() {
+		let mut proxy = bind_localhost().await;
+		let proxy_addr = proxy.local_addr().unwrap();
+		tokio::spawn(async move {
+			let (mut stream, _) = proxy.accept().await.unwrap();
+			let mut greeting = [0; 3];
+			stream.read_exact(&mut greeting).await.unwrap();
+			stream.write_all(&[5, 0]).await.unwrap();
+			let mut request = [0; 10];
+			stream.read_exact(&mut request).await.unwrap();
+			assert_eq!(request, [5, 1, 0, 1, 10, 0, 0, 1, 0x26, 0x07]);
+			// Host unreachable
+			stream.write_all(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+		});
+		assert!(socks5_connect(proxy_addr, &NetAddress::IPv4 { addr: [10, 0, 0, 1], port: 9735 }).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn tor_control_add_onion() {
+		let mut control = bind_localhost().await;
+		let control_addr = control.local_addr().unwrap();
+		tokio::spawn(async move {
+			let (stream, _) = control.accept().await.unwrap();
+			let mut stream = BufReader::new(stream);
+			let mut line = String::new();
+			stream.read_line(&mut line).await.unwrap();
+			assert_eq!(line, "AUTHENTICATE \"pass\\\"word\"\r\n");
+			stream.get_mut().write_all(b"250 OK\r\n").await.unwrap();
+
+			line.clear();
+			stream.read_line(&mut line).await.unwrap();
+			assert_eq!(line, "ADD_ONION NEW:ED25519-V3 Port=9735,127.0.0.1:9736\r\n");
+			stream.get_mut().write_all(b"250-ServiceID=vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd\r\n250-PrivateKey=ED25519-V3:key\r\n250 OK\r\n").await.unwrap();
+
+			line.clear();
+			stream.read_line(&mut line).await.unwrap();
+			assert_eq!(line, "ADD_ONION ED25519-V3:key Port=9735,127.0.0.1:9736\r\n");
+			stream.get_mut().write_all(b"550 Onion address collision\r\n").await.unwrap();
+		});
+
+		let mut control = TorControl::connect(control_addr, TorAuthentication::Password("pass\"word".to_string())).await.unwrap();
+		let target: SocketAddr = "127.0.0.1:9736".parse().unwrap();
+		let (address, key) = control.add_onion(None, 9735, target).await.unwrap();
+		assert_eq!(onion_hostname(&address).unwrap(), "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd.onion");
+		assert_eq!(key, "ED25519-V3:key");
+		assert!(control.add_onion(Some(&key), 9735, target).await.is_err());
+	}
+}
@@ -15,6 +15,8 @@
 
 use bitcoin::blockdata::transaction::Transaction;
 
+use std::cmp;
+
 /// An interface to send a transaction to the Bitcoin network.
 pub trait BroadcasterInterface: Sync + Send {
 	/// Sends a transaction out to (hopefully) be mined.
@@ -47,7 +49,122 @@ pub trait FeeEstimator: Sync + Send {
 	///  * satoshis-per-byte * 250
 	///  * ceil(satoshis-per-kbyte / 4)
 	fn get_est_sat_per_1000_weight(&self, confirmation_target: ConfirmationTarget) -> u32;
+
+	/// Gets the policy used to bump the fees of our time-sensitive claim transactions which did
+	/// not confirm yet. Defaults to [`DeadlineFeeBumpPolicy::default()`].
+	///
+	/// [`DeadlineFeeBumpPolicy::default()`]: struct.DeadlineFeeBumpPolicy.html
+	fn get_fee_bump_policy(&self) -> &dyn FeeBumpPolicy {
+		&DEFAULT_FEE_BUMP_POLICY
+	}
 }
 
 /// Minimum relay fee as required by bitcoin network mempool policy.
 pub const MIN_RELAY_FEE_SAT_PER_1000_WEIGHT: u64 = 4000;
+
+/// A policy deciding how the fees of our time-sensitive claim transactions are bumped while they
+/// don't confirm. Such transactions (eg claiming an HTLC output before our counterparty can time
+/// it out, or punishing a revoked commitment transaction before its relative timelock expires)
+/// have a deadline after which the funds they claim may be lost.
+///
+/// A claim transaction is first broadcast at the feerate given by [`FeeEstimator`] for
+/// [`ConfirmationTarget::HighPriority`]. It is then rebuilt at the height given by
+/// [`next_bump_height`] if it did not confirm yet, at the feerate given by [`bump_feerate`].
+///
+/// The policy used is given by [`FeeEstimator::get_fee_bump_policy`], which defaults to a
+/// [`DeadlineFeeBumpPolicy`].
+///
+/// [`FeeEstimator`]: trait.FeeEstimator.html
+/// [`ConfirmationTarget::HighPriority`]: enum.ConfirmationTarget.html#variant.HighPriority
+/// [`next_bump_height`]: #tymethod.next_bump_height
+/// [`bump_feerate`]: #tymethod.bump_feerate
+/// [`FeeEstimator::get_fee_bump_policy`]: trait.FeeEstimator.html#method.get_fee_bump_policy
+/// [`DeadlineFeeBumpPolicy`]: struct.DeadlineFeeBumpPolicy.html
+pub trait FeeBumpPolicy {
+	/// Gets the height at which a claim transaction (re)broadcast at `current_height` should be
+	/// bumped if it did not confirm by then, given the height at which its outputs may be claimed
+	/// by our counterparty, `deadline_height`.
+	///
+	/// Must be greater than `current_height`.
+	fn next_bump_height(&self, current_height: u32, deadline_height: u32) -> u32;
+
+	/// Gets the feerate, in satoshis per 1000 weight units, at which a claim transaction which did
+	/// not confirm should be rebuilt, or None if it isn't worth bumping anymore, in which case it
+	/// is left as-is.
+	///
+	/// `previous_feerate` is the feerate the transaction was last broadcast at and
+	/// `estimated_feerate` the current estimate for [`ConfirmationTarget::HighPriority`].
+	/// `amount_sat` is the total value claimed by the transaction, out of which the fee is paid,
+	/// and `predicted_weight` its expected weight.
+	///
+	/// Note that BIP 125 requires a replacement to pay for its own relay on top of the previous
+	/// transaction's fee, thus feerates below `previous_feerate` +
+	/// [`MIN_RELAY_FEE_SAT_PER_1000_WEIGHT`] are raised to that value.
+	///
+	/// [`ConfirmationTarget::HighPriority`]: enum.ConfirmationTarget.html#variant.HighPriority
+	/// [`MIN_RELAY_FEE_SAT_PER_1000_WEIGHT`]: constant.MIN_RELAY_FEE_SAT_PER_1000_WEIGHT.html
+	fn bump_feerate(&self, current_height: u32, deadline_height: u32, previous_feerate: u32, estimated_feerate: u32, amount_sat: u64, predicted_weight: u64) -> Option<u32>;
+}
+
+/// The default [`FeeBumpPolicy`], which bumps more often and more aggressively as the deadline
+/// gets closer, never targeting more than a given share of the claimed amount in fees until the
+/// deadline is reached. Once even a 25% bump would exceed that share, the transaction is no
+/// longer bumped, though note that the last bump may exceed it by what BIP 125 requires of a
+/// replacement.
+///
+/// A claim transaction is bumped every 15 blocks, by 25%, while its deadline is more than 15
+/// blocks away, then every 3 blocks, by 50%, then every block, doubling its feerate, once its
+/// deadline is 3 blocks away or less. The current feerate estimate is used instead whenever it is
+/// higher.
+///
+/// [`FeeBumpPolicy`]: trait.FeeBumpPolicy.html
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeadlineFeeBumpPolicy {
+	/// The maximum share, in percent, of the amount claimed by a transaction which we're willing
+	/// to pay in fees before its deadline. Once the deadline is reached, we may otherwise lose the
+	/// funds altogether, so we're then willing to pay up to all of it.
+	///
+	/// Defaults to 50, ie we pay at most half of the amount claimed in fees before the deadline.
+	pub max_fee_ratio_percent: u8,
+}
+
+impl Default for DeadlineFeeBumpPolicy {
+	fn default() -> Self {
+		DEFAULT_FEE_BUMP_POLICY
+	}
+}
+
+const DEFAULT_FEE_BUMP_POLICY: DeadlineFeeBumpPolicy = DeadlineFeeBumpPolicy { max_fee_ratio_percent: 50 };
+
+impl FeeBumpPolicy for DeadlineFeeBumpPolicy {
+	fn next_bump_height(&self, current_height: u32, deadline_height: u32) -> u32 {
+		if deadline_height <= current_height + 3 {
+			current_height + 1
+		} else if deadline_height - current_height <= 15 {
+			current_height + 3
+		} else {
+			current_height + 15
+		}
+	}
+
+	fn bump_feerate(&self, current_height: u32, deadline_height: u32, previous_feerate: u32, estimated_feerate: u32, amount_sat: u64, predicted_weight: u64) -> Option<u32> {
+		let blocks_left = deadline_height.saturating_sub(current_height);
+		let increase = if blocks_left <= 3 {
+			previous_feerate
+		} else if blocks_left <= 15 {
+			previous_feerate / 2
+		} else {
+			previous_feerate / 4
+		};
+		let feerate = cmp::max(previous_feerate as u64 + increase as u64, estimated_feerate as u64);
+
+		let max_fee_ratio_percent = if blocks_left == 0 { 100 } else { cmp::min(self.max_fee_ratio_percent, 100) as u64 };
+		let max_feerate = amount_sat * max_fee_ratio_percent * 10 / cmp::max(predicted_weight, 1);
+		// Give up once even our smallest bump would exceed our cap.
+		if previous_feerate as u64 + previous_feerate as u64 / 4 > max_feerate {
+			None
+		} else {
+			Some(cmp::min(feerate, max_feerate) as u32)
+		}
+	}
+}
@@ -30,12 +30,14 @@
 //! [`MonitorEvent`]: ../channelmonitor/enum.MonitorEvent.html
 
 use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
 
 use chain;
 use chain::Filter;
-use chain::chaininterface::{BroadcasterInterface, FeeEstimator};
+use chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator, MIN_RELAY_FEE_SAT_PER_1000_WEIGHT};
 use chain::channelmonitor;
-use chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, ChannelMonitorUpdateErr, MonitorEvent, Persist};
+use chain::channelmonitor::{Balance, ChannelMonitor, ChannelMonitorUpdate, ChannelMonitorUpdateErr, MonitorEvent, Persist};
 use chain::transaction::{OutPoint, TransactionData};
 use chain::keysinterface::ChannelKeys;
 use util::logger::Logger;
@@ -45,6 +47,17 @@ use util::events::Event;
 use std::collections::{HashMap, hash_map};
 use std::sync::Mutex;
 use std::ops::Deref;
+use std::cmp;
+
+/// The number of blocks after which a batched claim transaction which did not confirm is rebuilt
+/// at a higher feerate.
+pub const BATCH_CLAIM_BUMP_INTERVAL: u32 = 3;
+
+struct ClaimBatcher {
+	destination_script: Script,
+	// The latest batched claim transaction, with its feerate and the height at which it was built.
+	latest_batch: Option<(Transaction, u32, u32)>,
+}
 
 /// An implementation of [`chain::Watch`] for monitoring channels.
 ///
@@ -70,6 +83,7 @@ pub struct ChainMonitor<ChanSigner: ChannelKeys, C: Deref, T: Deref, F: Deref, L
 	logger: L,
 	fee_estimator: F,
 	persister: P,
+	claim_batcher: Mutex<Option<ClaimBatcher>>,
 }
 
 impl<ChanSigner: ChannelKeys, C: Deref, T: Deref, F: Deref, L: Deref, P: Deref> ChainMonitor<ChanSigner, C, T, F, L, P>
@@ -89,9 +103,13 @@ where C::Target: chain::Filter,
 	/// descendants of such transactions. It is not necessary to re-fetch the block to obtain
 	/// updated `txdata`.
 	///
+	/// If claim batching is enabled, the batched claim transaction is then rebuilt (or
+	/// rebroadcast) as described in [`enable_claim_batching`].
+	///
 	/// [`ChannelMonitor::block_connected`]: ../channelmonitor/struct.ChannelMonitor.html#method.block_connected
 	/// [`chain::Watch::release_pending_monitor_events`]: ../trait.Watch.html#tymethod.release_pending_monitor_events
 	/// [`chain::Filter`]: ../trait.Filter.html
+	/// [`enable_claim_batching`]: #method.enable_claim_batching
 	pub fn block_connected(&self, header: &BlockHeader, txdata: &TransactionData, height: u32) {
 		let mut monitors = self.monitors.lock().unwrap();
 		for monitor in monitors.values_mut() {
@@ -105,6 +123,7 @@ where C::Target: chain::Filter,
 				}
 			}
 		}
+		self.batch_claims(&monitors, height);
 	}
 
 	/// Dispatches to per-channel monitors, which are responsible for updating their on-chain view
@@ -136,7 +155,153 @@ where C::Target: chain::Filter,
 			logger,
 			fee_estimator: feeest,
 			persister,
+			claim_batcher: Mutex::new(None),
+		}
+	}
+
+	/// Enables claim batching: rather than each [`ChannelMonitor`] claiming its outputs on its
+	/// own, non-contentious claims of all channels are merged into a single shared transaction
+	/// paying to `destination_script`, saving on fees when many channels are closed at once.
+	///
+	/// Non-contentious claims are those of our CSV-delayed outputs (on our commitment or HTLC
+	/// transactions) once their delay has passed, which are then no longer handed over via
+	/// [`Event::SpendableOutputs`], and those of HTLC outputs on counterparty commitment
+	/// transactions for which we know the preimage, until they get within a few blocks of their
+	/// timeout, at which point they are claimed on their own again. HTLC outputs for which we only
+	/// learn the preimage once the commitment transaction confirmed are always claimed on their own.
+	///
+	/// The batched claim transaction is rebuilt on each new block whose claims differ, and its
+	/// feerate is bumped if it did not confirm within [`BATCH_CLAIM_BUMP_INTERVAL`] blocks.
+	///
+	/// [`ChannelMonitor`]: ../channelmonitor/struct.ChannelMonitor.html
+	/// [`Event::SpendableOutputs`]: ../../util/events/enum.Event.html#variant.SpendableOutputs
+	/// [`BATCH_CLAIM_BUMP_INTERVAL`]: constant.BATCH_CLAIM_BUMP_INTERVAL.html
+	pub fn enable_claim_batching(&self, destination_script: Script) {
+		let mut monitors = self.monitors.lock().unwrap();
+		for monitor in monitors.values_mut() {
+			monitor.set_claim_batching(true);
+		}
+		*self.claim_batcher.lock().unwrap() = Some(ClaimBatcher {
+			destination_script,
+			latest_batch: None,
+		});
+	}
+
+	/// Disables claim batching. Claims which were waiting to be batched are handed back to their
+	/// [`ChannelMonitor`] on the next block, though a previously broadcast batched claim
+	/// transaction may still confirm.
+	///
+	/// [`ChannelMonitor`]: ../channelmonitor/struct.ChannelMonitor.html
+	pub fn disable_claim_batching(&self) {
+		let mut monitors = self.monitors.lock().unwrap();
+		for monitor in monitors.values_mut() {
+			monitor.set_claim_batching(false);
+		}
+		*self.claim_batcher.lock().unwrap() = None;
+	}
+
+	/// Builds a new batched claim transaction spending all batchable claims of the given monitors
+	/// if they changed or a fee-bump is due, and (re)broadcasts the latest one.
+	fn batch_claims(&self, monitors: &HashMap<OutPoint, ChannelMonitor<ChanSigner>>, height: u32) {
+		let mut claim_batcher = self.claim_batcher.lock().unwrap();
+		let batcher = match *claim_batcher {
+			Some(ref mut batcher) => batcher,
+			None => return,
+		};
+
+		let mut inputs = Vec::new();
+		for (funding_txo, monitor) in monitors.iter() {
+			for input in monitor.get_batchable_claims(height) {
+				inputs.push((*funding_txo, input));
+			}
+		}
+		if inputs.is_empty() {
+			batcher.latest_batch = None;
+			return;
+		}
+		inputs.sort_unstable_by(|a, b| (a.1.outpoint.txid, a.1.outpoint.vout).cmp(&(b.1.outpoint.txid, b.1.outpoint.vout)));
+
+		let estimated_feerate = self.fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::HighPriority);
+		let feerate = match batcher.latest_batch {
+			None => estimated_feerate,
+			Some((ref tx, prev_feerate, built_height)) => {
+				let spends_same_inputs = tx.input.len() == inputs.len() && tx.input.iter().zip(inputs.iter()).all(|(txin, &(_, ref input))| txin.previous_output == input.outpoint);
+				if spends_same_inputs && height < built_height + BATCH_CLAIM_BUMP_INTERVAL {
+					log_trace!(self.logger, "Rebroadcasting batched claim transaction {}", tx.txid());
+					self.broadcaster.broadcast_transaction(tx);
+					return;
+				}
+				if spends_same_inputs || tx.input.iter().any(|txin| inputs.iter().any(|&(_, ref input)| txin.previous_output == input.outpoint)) {
+					// We have to replace the previous batch, so bump by 25%, but at least enough to
+					// satisfy BIP 125's relay fee requirement
+					let bumped_feerate = cmp::max(prev_feerate + prev_feerate / 4, prev_feerate + MIN_RELAY_FEE_SAT_PER_1000_WEIGHT as u32);
+					cmp::max(estimated_feerate, bumped_feerate)
+				} else {
+					estimated_feerate
+				}
+			},
+		};
+
+		let mut batch_tx = Transaction {
+			version: 2,
+			lock_time: 0,
+			input: inputs.iter().map(|&(_, ref input)| TxIn {
+				previous_output: input.outpoint,
+				script_sig: Script::new(),
+				sequence: input.sequence,
+				witness: Vec::new(),
+			}).collect(),
+			output: vec![TxOut {
+				script_pubkey: batcher.destination_script.clone(),
+				value: 0,
+			}],
+		};
+		let claimed_value: u64 = inputs.iter().map(|&(_, ref input)| input.amount).sum();
+		// The segwit marker and flag, plus each input's witness
+		let predicted_weight = batch_tx.get_weight() + 2 + inputs.iter().map(|&(_, ref input)| input.witness_weight).sum::<usize>();
+		let fee = feerate as u64 * predicted_weight as u64 / 1000;
+		if claimed_value <= fee + 546 {
+			log_error!(self.logger, "Not broadcasting batched claim of {} inputs as their value of {} sat doesn't cover a fee of {} sat", inputs.len(), claimed_value, fee);
+			return;
+		}
+		batch_tx.output[0].value = claimed_value - fee;
+
+		let mut witnesses = Vec::with_capacity(inputs.len());
+		for (idx, &(ref funding_txo, ref input)) in inputs.iter().enumerate() {
+			match monitors.get(funding_txo).and_then(|monitor| monitor.sign_batched_claim(&batch_tx, idx)) {
+				Some(witness) => witnesses.push(witness),
+				None => {
+					log_error!(self.logger, "Failed to sign batched claim of {}:{}", input.outpoint.txid, input.outpoint.vout);
+					return;
+				},
+			}
+		}
+		for (txin, witness) in batch_tx.input.iter_mut().zip(witnesses.drain(..)) {
+			txin.witness = witness;
+		}
+		debug_assert!(predicted_weight >= batch_tx.get_weight());
+
+		log_trace!(self.logger, "Broadcasting batched claim transaction {} spending {} inputs at feerate {}", batch_tx.txid(), inputs.len(), feerate);
+		self.broadcaster.broadcast_transaction(&batch_tx);
+		batcher.latest_batch = Some((batch_tx, feerate, height));
+	}
+
+	/// Gets the balances in the contained [`ChannelMonitor`]s which are claimable on-chain or
+	/// claimable on channel close. See [`ChannelMonitor::get_claimable_balances`] for details on
+	/// the individual entries.
+	///
+	/// Note that this includes the balances of channels which are still open, which are better
+	/// fetched via [`ChannelManager::list_channels`] as long as the channel remains open.
+	///
+	/// [`ChannelMonitor`]: ../channelmonitor/struct.ChannelMonitor.html
+	/// [`ChannelMonitor::get_claimable_balances`]: ../channelmonitor/struct.ChannelMonitor.html#method.get_claimable_balances
+	/// [`ChannelManager::list_channels`]: ../../ln/channelmanager/struct.ChannelManager.html#method.list_channels
+	pub fn get_claimable_balances(&self) -> Vec<Balance> {
+		let mut balances = Vec::new();
+		for monitor in self.monitors.lock().unwrap().values() {
+			balances.append(&mut monitor.get_claimable_balances());
 		}
+		balances
 	}
 }
 
@@ -157,7 +322,7 @@ where C::Target: chain::Filter,
 	/// monitors lock.
 	///
 	/// [`chain::Filter`]: ../trait.Filter.html
-	fn watch_channel(&self, funding_outpoint: OutPoint, monitor: ChannelMonitor<ChanSigner>) -> Result<(), ChannelMonitorUpdateErr> {
+	fn watch_channel(&self, funding_outpoint: OutPoint, mut monitor: ChannelMonitor<ChanSigner>) -> Result<(), ChannelMonitorUpdateErr> {
 		let mut monitors = self.monitors.lock().unwrap();
 		let entry = match monitors.entry(funding_outpoint) {
 			hash_map::Entry::Occupied(_) => {
@@ -169,6 +334,7 @@ where C::Target: chain::Filter,
 			log_error!(self.logger, "Failed to persist new channel data");
 			return Err(e);
 		}
+		monitor.set_claim_batching(self.claim_batcher.lock().unwrap().is_some());
 		{
 			let funding_txo = monitor.get_funding_txo();
 			log_trace!(self.logger, "Got new Channel Monitor for channel {}", log_bytes!(funding_txo.0.to_channel_id()[..]));
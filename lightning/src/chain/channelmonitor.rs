@@ -23,7 +23,7 @@
 //! [`chain::Watch`]: ../trait.Watch.html
 
 use bitcoin::blockdata::block::BlockHeader;
-use bitcoin::blockdata::transaction::{TxOut,Transaction};
+use bitcoin::blockdata::transaction::{TxIn,TxOut,Transaction,SigHashType};
 use bitcoin::blockdata::transaction::OutPoint as BitcoinOutPoint;
 use bitcoin::blockdata::script::{Script, Builder};
 use bitcoin::blockdata::opcodes;
@@ -39,10 +39,10 @@ use bitcoin::secp256k1;
 
 use ln::msgs::DecodeError;
 use ln::chan_utils;
-use ln::chan_utils::{CounterpartyCommitmentSecrets, HTLCOutputInCommitment, HolderCommitmentTransaction, HTLCType};
+use ln::chan_utils::{CounterpartyCommitmentSecrets, HTLCOutputInCommitment, HolderCommitmentTransaction, HTLCType, TxCreationKeys};
 use ln::channelmanager::{HTLCSource, PaymentPreimage, PaymentHash};
 use ln::onchaintx::{OnchainTxHandler, InputDescriptors};
-use chain::chaininterface::{BroadcasterInterface, FeeEstimator};
+use chain::chaininterface::{BroadcasterInterface, FeeEstimator, ConfirmationTarget};
 use chain::transaction::{OutPoint, TransactionData};
 use chain::keysinterface::{SpendableOutputDescriptor, ChannelKeys};
 use util::logger::Logger;
@@ -201,6 +201,79 @@ pub struct HTLCUpdate {
 }
 impl_writeable!(HTLCUpdate, 0, { payment_hash, payment_preimage, source });
 
+/// Details about a balance available for claim once the channel appears on chain, as returned by
+/// [`ChannelMonitor::get_claimable_balances`].
+///
+/// [`ChannelMonitor::get_claimable_balances`]: struct.ChannelMonitor.html#method.get_claimable_balances
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Balance {
+	/// The channel is not yet closed (or the commitment or closing transaction has not yet
+	/// appeared in a block). The given balance is claimable (less on-chain fees) if the channel is
+	/// force-closed now. It includes any inbound HTLCs for which we already know the preimage.
+	ClaimableOnChannelClose {
+		/// The amount available to claim, in satoshis, excluding the on-chain fees which will be
+		/// required to do so.
+		claimable_amount_satoshis: u64,
+	},
+	/// The channel has been closed, and the given balance is ours but awaiting confirmations until
+	/// we consider it spendable.
+	ClaimableAwaitingConfirmations {
+		/// The amount available to claim, in satoshis, possibly excluding the on-chain fees which
+		/// were spent in broadcasting the transaction.
+		claimable_amount_satoshis: u64,
+		/// The height at which the output becomes spendable by us, i.e. once it has reached
+		/// ANTI_REORG_DELAY confirmations and, for outputs of our own commitment or HTLC
+		/// transactions, once their relative (CSV) timelock has expired.
+		confirmation_height: u32,
+	},
+	/// The channel has been closed, and the given balance should be ours but awaiting spending
+	/// transaction confirmation. If the spending transaction does not confirm in time, it is
+	/// possible our counterparty can take the funds by broadcasting an HTLC timeout on-chain.
+	///
+	/// Once the spending transaction confirms, this will be replaced by a
+	/// `ClaimableAwaitingConfirmations` entry for the resulting output.
+	ContentiousClaimable {
+		/// The amount available to claim, in satoshis, excluding the on-chain fees which will be
+		/// required to do so.
+		claimable_amount_satoshis: u64,
+		/// The height at which the counterparty may be able to claim the balance if we have not
+		/// done so.
+		timeout_height: u32,
+	},
+	/// HTLCs which we sent to our counterparty which are claimable after a timeout (less on-chain
+	/// fees) if the counterparty does not know the preimage for the HTLCs. These are somewhat
+	/// likely to be claimed by our counterparty before we can do so.
+	MaybeClaimableHTLCAwaitingTimeout {
+		/// The amount available to claim, in satoshis, excluding the on-chain fees which will be
+		/// required to do so.
+		claimable_amount_satoshis: u64,
+		/// The height at which we will be able to claim the balance if our counterparty has not
+		/// done so.
+		claimable_height: u32,
+	},
+	/// HTLCs which our counterparty sent us for which we do not (yet) know the preimage. They are
+	/// only claimable by us if the preimage is provided before `expiry_height`, after which our
+	/// counterparty may claim them via timeout.
+	MaybeClaimableHTLCAwaitingPreimage {
+		/// The amount which we may be able to claim, in satoshis, excluding the on-chain fees
+		/// which will be required to do so.
+		claimable_amount_satoshis: u64,
+		/// The height at which our counterparty will be able to claim the balance if we have not
+		/// yet received the preimage and claimed it ourselves.
+		expiry_height: u32,
+	},
+	/// The channel has been closed, and our counterparty broadcasted a revoked commitment
+	/// transaction (or an HTLC transaction spending one). We are punishing them by claiming the
+	/// given output with the revocation key.
+	///
+	/// Note that our counterparty may race us to claim some of these outputs (eg HTLC outputs via
+	/// HTLC transactions, which we then claim in turn).
+	CounterpartyRevokedOutputClaimable {
+		/// The amount, in satoshis, of the output which we can claim.
+		claimable_amount_satoshis: u64,
+	},
+}
+
 /// If an HTLC expires within this many blocks, don't try to claim it in a shared transaction,
 /// instead claiming it in its own individual transaction.
 pub(crate) const CLTV_SHARED_CLAIM_BUFFER: u32 = 12;
@@ -229,6 +302,11 @@ pub(crate) const LATENCY_GRACE_PERIOD_BLOCKS: u32 = 3;
 /// solved by a previous claim tx. What we want to avoid is reorg evicting our claim tx and us not
 /// keeping bumping another claim tx to solve the outpoint.
 pub(crate) const ANTI_REORG_DELAY: u32 = 6;
+
+/// The multiples of our high-priority feerate estimate at which justice transactions are
+/// pre-signed for each revoked counterparty commitment transaction, leaving room for whoever
+/// broadcasts them to cope with a fee spike without access to our keys.
+const PRESIGNED_JUSTICE_FEERATE_MULTIPLIERS: [u32; 3] = [1, 2, 4];
 /// Number of blocks before confirmation at which we fail back an un-relayed HTLC or at which we
 /// refuse to accept a new HTLC.
 ///
@@ -482,7 +560,87 @@ enum OnchainEvent {
 	},
 }
 
-const SERIALIZATION_VERSION: u8 = 1;
+/// When claim batching is enabled in ChainMonitor, non-contentious claims are not handed to our
+/// OnchainTxHandler (or, for CSV-delayed outputs, upstream via Event::SpendableOutputs) but kept
+/// here until ChainMonitor merges them with other channels' claims into a shared transaction.
+#[derive(Clone, PartialEq)]
+enum BatchedClaimMaterial {
+	/// An HTLC output on a counterparty commitment transaction for which we know the preimage.
+	/// Handed back to OnchainTxHandler once absolute_timelock gets within CLTV_SHARED_CLAIM_BUFFER.
+	CounterpartyHTLC {
+		absolute_timelock: u32,
+		witness_data: InputMaterial,
+	},
+	/// One of our CSV-delayed outputs, which may be spent from spendable_height on.
+	DelayedOutput {
+		descriptor: SpendableOutputDescriptor,
+		spendable_height: u32,
+	},
+}
+
+#[derive(Clone, PartialEq)]
+struct BatchedClaim {
+	material: BatchedClaimMaterial,
+	// The height at which the transaction paying the output confirmed, used to drop the claim if
+	// the transaction is reorged out.
+	confirmation_height: u32,
+	// The height at which a transaction spending the output (ours or not) confirmed, if any. The
+	// claim is forgotten once this reaches ANTI_REORG_DELAY confirmations.
+	spend_height: Option<u32>,
+}
+
+impl Writeable for BatchedClaim {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		match &self.material {
+			&BatchedClaimMaterial::CounterpartyHTLC { ref absolute_timelock, ref witness_data } => {
+				0u8.write(writer)?;
+				absolute_timelock.write(writer)?;
+				witness_data.write(writer)?;
+			},
+			&BatchedClaimMaterial::DelayedOutput { ref descriptor, ref spendable_height } => {
+				1u8.write(writer)?;
+				descriptor.write(writer)?;
+				spendable_height.write(writer)?;
+			},
+		}
+		self.confirmation_height.write(writer)?;
+		self.spend_height.write(writer)?;
+		Ok(())
+	}
+}
+
+impl Readable for BatchedClaim {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let material = match <u8 as Readable>::read(reader)? {
+			0 => {
+				let absolute_timelock = Readable::read(reader)?;
+				let witness_data = Readable::read(reader)?;
+				BatchedClaimMaterial::CounterpartyHTLC { absolute_timelock, witness_data }
+			},
+			1 => {
+				let descriptor = Readable::read(reader)?;
+				let spendable_height = Readable::read(reader)?;
+				BatchedClaimMaterial::DelayedOutput { descriptor, spendable_height }
+			},
+			_ => return Err(DecodeError::InvalidValue),
+		};
+		let confirmation_height = Readable::read(reader)?;
+		let spend_height = Readable::read(reader)?;
+		Ok(BatchedClaim { material, confirmation_height, spend_height })
+	}
+}
+
+/// An input which ChainMonitor may include in a batched claim transaction, as returned by
+/// ChannelMonitor::get_batchable_claims.
+pub(crate) struct BatchableClaimInput {
+	pub(crate) outpoint: BitcoinOutPoint,
+	pub(crate) amount: u64,
+	pub(crate) sequence: u32,
+	// Expected weight of the input's witness, not including the segwit marker and flag.
+	pub(crate) witness_weight: usize,
+}
+
+const SERIALIZATION_VERSION: u8 = 2;
 const MIN_SERIALIZATION_VERSION: u8 = 1;
 
 #[cfg_attr(any(test, feature = "_test_utils"), derive(PartialEq))]
@@ -702,6 +860,25 @@ pub struct ChannelMonitor<ChanSigner: ChannelKeys> {
 	// remote monitor out-of-order with regards to the block view.
 	holder_tx_signed: bool,
 
+	// The txid and confirmation height of the transaction which spent the funding output, if one
+	// has been seen in a block. Used to decide which balances are still only claimable on close.
+	funding_spend_confirmed: Option<(Txid, u32)>,
+
+	// The unsigned counterparty commitment transactions which have not yet been revoked (usually
+	// the current and, until its revocation, the previous one), kept around so that justice
+	// transactions may be built for them once their revocation secret is provided.
+	unrevoked_counterparty_commitment_txn: HashMap<Txid, Transaction>,
+	// Justice transactions pre-signed at several feerates for the counterparty commitment
+	// transactions revoked by the latest secret, keyed by the commitment txid. Replaced on each
+	// new secret, so that it doesn't grow with the number of states of the channel.
+	presigned_justice_txn: HashMap<Txid, Vec<Transaction>>,
+
+	// Whether non-contentious claims should be left to ChainMonitor's claim batching instead of
+	// being claimed on their own. Not serialized, ChainMonitor sets it on each monitor it watches.
+	claim_batching: bool,
+	// Claims awaiting inclusion in (or confirmation of) a batched claim transaction.
+	batched_claims: HashMap<BitcoinOutPoint, BatchedClaim>,
+
 	// We simply modify last_block_hash in Channel's block_connected so that serialization is
 	// consistent but hopefully the users' copy handles block_connected in a consistent way.
 	// (we do *not*, however, update them in update_monitor to ensure any local user copies keep
@@ -744,7 +921,11 @@ impl<ChanSigner: ChannelKeys> PartialEq for ChannelMonitor<ChanSigner> {
 			self.onchain_events_waiting_threshold_conf != other.onchain_events_waiting_threshold_conf ||
 			self.outputs_to_watch != other.outputs_to_watch ||
 			self.lockdown_from_offchain != other.lockdown_from_offchain ||
-			self.holder_tx_signed != other.holder_tx_signed
+			self.holder_tx_signed != other.holder_tx_signed ||
+			self.funding_spend_confirmed != other.funding_spend_confirmed ||
+			self.unrevoked_counterparty_commitment_txn != other.unrevoked_counterparty_commitment_txn ||
+			self.presigned_justice_txn != other.presigned_justice_txn ||
+			self.batched_claims != other.batched_claims
 		{
 			false
 		} else {
@@ -942,6 +1123,28 @@ impl<ChanSigner: ChannelKeys + Writeable> ChannelMonitor<ChanSigner> {
 		self.lockdown_from_offchain.write(writer)?;
 		self.holder_tx_signed.write(writer)?;
 
+		// Fields added in version 2
+		self.funding_spend_confirmed.write(writer)?;
+
+		writer.write_all(&byte_utils::be64_to_array(self.unrevoked_counterparty_commitment_txn.len() as u64))?;
+		for (_, ref tx) in self.unrevoked_counterparty_commitment_txn.iter() {
+			tx.write(writer)?;
+		}
+		writer.write_all(&byte_utils::be64_to_array(self.presigned_justice_txn.len() as u64))?;
+		for (ref txid, ref justice_txn) in self.presigned_justice_txn.iter() {
+			writer.write_all(&txid[..])?;
+			writer.write_all(&byte_utils::be64_to_array(justice_txn.len() as u64))?;
+			for tx in justice_txn.iter() {
+				tx.write(writer)?;
+			}
+		}
+
+		writer.write_all(&byte_utils::be64_to_array(self.batched_claims.len() as u64))?;
+		for (ref outpoint, ref claim) in self.batched_claims.iter() {
+			outpoint.write(writer)?;
+			claim.write(writer)?;
+		}
+
 		Ok(())
 	}
 }
@@ -1024,6 +1227,14 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 			lockdown_from_offchain: false,
 			holder_tx_signed: false,
 
+			funding_spend_confirmed: None,
+
+			unrevoked_counterparty_commitment_txn: HashMap::new(),
+			presigned_justice_txn: HashMap::new(),
+
+			claim_batching: false,
+			batched_claims: HashMap::new(),
+
 			last_block_hash: Default::default(),
 			secp_ctx: Secp256k1::new(),
 		}
@@ -1032,11 +1243,40 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 	/// Inserts a revocation secret into this channel monitor. Prunes old preimages if neither
 	/// needed by holder commitment transactions HTCLs nor by counterparty ones. Unless we haven't already seen
 	/// counterparty commitment transaction's secret, they are de facto pruned (we can use revocation key).
-	fn provide_secret(&mut self, idx: u64, secret: [u8; 32]) -> Result<(), MonitorUpdateError> {
+	/// Justice transactions are pre-signed for any counterparty commitment transaction this revokes,
+	/// replacing those pre-signed on the previous call, see get_justice_txs_for_revoked.
+	fn provide_secret<F: Deref, L: Deref>(&mut self, idx: u64, secret: [u8; 32], fee_estimator: &F, logger: &L) -> Result<(), MonitorUpdateError>
+	where F::Target: FeeEstimator,
+	      L::Target: Logger,
+	{
 		if let Err(()) = self.commitment_secrets.provide_secret(idx, secret) {
 			return Err(MonitorUpdateError("Previous secret did not match new one"));
 		}
 
+		let min_seen_secret = self.get_min_seen_secret();
+		let obscure_factor = self.commitment_transaction_number_obscure_factor;
+		let mut revoked_txn = Vec::new();
+		self.unrevoked_counterparty_commitment_txn.retain(|_, tx| {
+			if tx.input.len() != 1 { return false; }
+			let commitment_number = 0xffffffffffff - ((((tx.input[0].sequence as u64 & 0xffffff) << 3*8) | (tx.lock_time as u64 & 0xffffff)) ^ obscure_factor);
+			if commitment_number >= min_seen_secret {
+				revoked_txn.push(tx.clone());
+				false
+			} else { true }
+		});
+		self.presigned_justice_txn.clear();
+		if !revoked_txn.is_empty() {
+			let high_priority_feerate = fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::HighPriority);
+			let feerates: Vec<u32> = PRESIGNED_JUSTICE_FEERATE_MULTIPLIERS.iter().map(|multiplier| high_priority_feerate.saturating_mul(*multiplier)).collect();
+			for tx in revoked_txn.drain(..) {
+				let txid = tx.txid();
+				if let Some(justice_txn) = self.build_justice_txn(&tx, &feerates) {
+					log_trace!(logger, "Pre-signed {} justice transactions for revoked counterparty commitment transaction {}", justice_txn.len(), txid);
+					self.presigned_justice_txn.insert(txid, justice_txn);
+				}
+			}
+		}
+
 		// Prune HTLCs from the previous counterparty commitment tx so we don't generate failure/fulfill
 		// events for now-revoked/fulfilled HTLCs.
 		if let Some(txid) = self.prev_counterparty_commitment_txid.take() {
@@ -1099,6 +1339,7 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 		self.prev_counterparty_commitment_txid = self.current_counterparty_commitment_txid.take();
 		self.current_counterparty_commitment_txid = Some(new_txid);
 		self.counterparty_claimable_outpoints.insert(new_txid, htlc_outputs.clone());
+		self.unrevoked_counterparty_commitment_txn.insert(new_txid, unsigned_commitment_tx.clone());
 		self.current_counterparty_commitment_number = commitment_number;
 		//TODO: Merge this into the other per-counterparty-transaction output storage stuff
 		match self.their_cur_revocation_points {
@@ -1251,7 +1492,7 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 				},
 				ChannelMonitorUpdateStep::CommitmentSecret { idx, secret } => {
 					log_trace!(logger, "Updating ChannelMonitor with commitment secret");
-					self.provide_secret(*idx, *secret)?
+					self.provide_secret(*idx, *secret, fee_estimator, logger)?
 				},
 				ChannelMonitorUpdateStep::ChannelForceClosed { should_broadcast } => {
 					log_trace!(logger, "Updating ChannelMonitor: channel force closed, should broadcast: {}", should_broadcast);
@@ -1315,6 +1556,445 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 		ret
 	}
 
+	/// Gets the balances in this channel which are either claimable by us if we were to
+	/// force-close the channel now or which are claimable on-chain (possibly awaiting
+	/// confirmation).
+	///
+	/// Until a transaction spending the funding output has been seen in a block, the balances are
+	/// computed from our latest holder commitment transaction. Afterwards, they are computed from
+	/// the claims we are still tracking for the confirmed transaction and from the outputs paying
+	/// us which are awaiting enough confirmations to be handed over via
+	/// [`Event::SpendableOutputs`]. Once such an event has been generated, the corresponding
+	/// balance is no longer included.
+	///
+	/// The entries are returned in no particular order.
+	///
+	/// [`Event::SpendableOutputs`]: ../../util/events/enum.Event.html#variant.SpendableOutputs
+	pub fn get_claimable_balances(&self) -> Vec<Balance> {
+		let mut res = Vec::new();
+
+		let confirmed_txid = match self.funding_spend_confirmed {
+			Some((txid, _)) => txid,
+			None => {
+				let holder_tx = &self.current_holder_commitment_tx;
+				let mut claimable_on_close_sat = 0;
+				if let Some(commitment_tx) = self.onchain_tx_handler.get_unsigned_holder_commitment_tx() {
+					let revokeable_p2wsh = chan_utils::get_revokeable_redeemscript(&holder_tx.revocation_key, self.on_holder_tx_csv, &holder_tx.delayed_payment_key).to_v0_p2wsh();
+					for outp in commitment_tx.output.iter() {
+						if outp.script_pubkey == revokeable_p2wsh {
+							claimable_on_close_sat += outp.value;
+						}
+					}
+				}
+				for &(ref htlc, _, _) in holder_tx.htlc_outputs.iter() {
+					if htlc.transaction_output_index.is_none() { continue; }
+					if htlc.offered {
+						res.push(Balance::MaybeClaimableHTLCAwaitingTimeout {
+							claimable_amount_satoshis: htlc.amount_msat / 1000,
+							claimable_height: htlc.cltv_expiry,
+						});
+					} else if self.payment_preimages.get(&htlc.payment_hash).is_some() {
+						claimable_on_close_sat += htlc.amount_msat / 1000;
+					} else {
+						res.push(Balance::MaybeClaimableHTLCAwaitingPreimage {
+							claimable_amount_satoshis: htlc.amount_msat / 1000,
+							expiry_height: htlc.cltv_expiry,
+						});
+					}
+				}
+				if claimable_on_close_sat != 0 {
+					res.push(Balance::ClaimableOnChannelClose {
+						claimable_amount_satoshis: claimable_on_close_sat,
+					});
+				}
+				return res;
+			}
+		};
+
+		for (height, events) in self.onchain_events_waiting_threshold_conf.iter() {
+			for ev in events.iter() {
+				if let &OnchainEvent::MaturingOutput { ref descriptor } = ev {
+					let (amount, confirmation_height) = match *descriptor {
+						SpendableOutputDescriptor::StaticOutput { ref output, .. } => (output.value, *height),
+						SpendableOutputDescriptor::StaticOutputCounterpartyPayment { ref output, .. } => (output.value, *height),
+						SpendableOutputDescriptor::DynamicOutputP2WSH { ref output, to_self_delay, .. } => {
+							// The output confirmed ANTI_REORG_DELAY - 1 blocks before the event
+							// height, after which its CSV timelock starts ticking.
+							let csv_height = *height + 1 - ANTI_REORG_DELAY + to_self_delay as u32;
+							(output.value, cmp::max(*height, csv_height))
+						},
+					};
+					res.push(Balance::ClaimableAwaitingConfirmations {
+						claimable_amount_satoshis: amount,
+						confirmation_height,
+					});
+				}
+			}
+		}
+
+		// Batched HTLC claims are handed back before the counterparty may time them out, thus any
+		// spend of a batched claim is ours.
+		for claim in self.batched_claims.values() {
+			let amount = match claim.material {
+				BatchedClaimMaterial::CounterpartyHTLC { witness_data: InputMaterial::CounterpartyHTLC { ref htlc, .. }, ref absolute_timelock } => {
+					if claim.spend_height.is_none() {
+						res.push(Balance::ContentiousClaimable {
+							claimable_amount_satoshis: htlc.amount_msat / 1000,
+							timeout_height: *absolute_timelock,
+						});
+						continue;
+					}
+					htlc.amount_msat / 1000
+				},
+				BatchedClaimMaterial::DelayedOutput { descriptor: SpendableOutputDescriptor::DynamicOutputP2WSH { ref output, .. }, ref spendable_height } => {
+					if claim.spend_height.is_none() {
+						res.push(Balance::ClaimableAwaitingConfirmations {
+							claimable_amount_satoshis: output.value,
+							confirmation_height: *spendable_height,
+						});
+						continue;
+					}
+					output.value
+				},
+				_ => continue,
+			};
+			res.push(Balance::ClaimableAwaitingConfirmations {
+				claimable_amount_satoshis: amount,
+				confirmation_height: claim.spend_height.unwrap() + ANTI_REORG_DELAY - 1,
+			});
+		}
+
+		let holder_htlc_cltv = |outpoint: &BitcoinOutPoint| -> Option<u32> {
+			let mut holder_txn = vec![&self.current_holder_commitment_tx];
+			if let Some(ref prev_holder_tx) = self.prev_holder_signed_commitment_tx {
+				holder_txn.push(prev_holder_tx);
+			}
+			for holder_tx in holder_txn {
+				if holder_tx.txid != outpoint.txid { continue; }
+				for &(ref htlc, _, _) in holder_tx.htlc_outputs.iter() {
+					if htlc.transaction_output_index == Some(outpoint.vout) {
+						return Some(htlc.cltv_expiry);
+					}
+				}
+			}
+			None
+		};
+
+		for (outpoint, input_material) in self.onchain_tx_handler.get_unresolved_claim_inputs() {
+			match input_material {
+				&InputMaterial::Revoked { ref amount, .. } => {
+					res.push(Balance::CounterpartyRevokedOutputClaimable {
+						claimable_amount_satoshis: *amount,
+					});
+				},
+				&InputMaterial::CounterpartyHTLC { ref preimage, ref htlc, .. } => {
+					if preimage.is_some() {
+						res.push(Balance::ContentiousClaimable {
+							claimable_amount_satoshis: htlc.amount_msat / 1000,
+							timeout_height: htlc.cltv_expiry,
+						});
+					} else {
+						res.push(Balance::MaybeClaimableHTLCAwaitingTimeout {
+							claimable_amount_satoshis: htlc.amount_msat / 1000,
+							claimable_height: htlc.cltv_expiry,
+						});
+					}
+				},
+				&InputMaterial::HolderHTLC { ref preimage, ref amount } => {
+					if let Some(cltv_expiry) = holder_htlc_cltv(outpoint) {
+						if preimage.is_some() {
+							res.push(Balance::ContentiousClaimable {
+								claimable_amount_satoshis: amount / 1000,
+								timeout_height: cltv_expiry,
+							});
+						} else {
+							res.push(Balance::MaybeClaimableHTLCAwaitingTimeout {
+								claimable_amount_satoshis: amount / 1000,
+								claimable_height: cltv_expiry,
+							});
+						}
+					}
+				},
+				&InputMaterial::Funding { .. } => {},
+			}
+		}
+
+		// Inbound HTLCs for which we don't have a preimage aren't claimed at all, but may still
+		// become ours if the preimage is provided in time.
+		let mut awaiting_preimage = |htlc: &HTLCOutputInCommitment| {
+			if htlc.transaction_output_index.is_some() && self.payment_preimages.get(&htlc.payment_hash).is_none() {
+				res.push(Balance::MaybeClaimableHTLCAwaitingPreimage {
+					claimable_amount_satoshis: htlc.amount_msat / 1000,
+					expiry_height: htlc.cltv_expiry,
+				});
+			}
+		};
+		if self.current_holder_commitment_tx.txid == confirmed_txid {
+			for &(ref htlc, _, _) in self.current_holder_commitment_tx.htlc_outputs.iter() {
+				if !htlc.offered { awaiting_preimage(htlc); }
+			}
+		} else if let Some(ref prev_holder_tx) = self.prev_holder_signed_commitment_tx.as_ref().filter(|tx| tx.txid == confirmed_txid) {
+			for &(ref htlc, _, _) in prev_holder_tx.htlc_outputs.iter() {
+				if !htlc.offered { awaiting_preimage(htlc); }
+			}
+		} else if let Some(&commitment_number) = self.counterparty_commitment_txn_on_chain.get(&confirmed_txid) {
+			if commitment_number < self.get_min_seen_secret() {
+				if let Some(htlc_outputs) = self.counterparty_claimable_outpoints.get(&confirmed_txid) {
+					for &(ref htlc, _) in htlc_outputs.iter() {
+						if htlc.offered { awaiting_preimage(htlc); }
+					}
+				}
+			}
+		}
+
+		res
+	}
+
+	/// Returns the justice transactions pre-signed, at several feerates, as soon as the given
+	/// counterparty commitment transaction was revoked, sorted by increasing feerate. They may be
+	/// handed over to a third party (eg a watchtower) to broadcast should the revoked state hit
+	/// the chain, without giving it access to our keys.
+	///
+	/// Each justice transaction claims, with the revocation key, every output of the revoked
+	/// commitment transaction which is ours to take, paying the claimed value less fees to our
+	/// destination script. Unlike the claims generated in [`block_connected`], they are never
+	/// fee-bumped.
+	///
+	/// Only the transactions revoked by the latest revocation secret are kept, so this should be
+	/// called from [`Persist::update_persisted_channel`] for any update containing a new secret,
+	/// with the txids returned by [`get_latest_revoked_counterparty_commitment_txids`].
+	///
+	/// Returns an empty Vec if the given txid is not that of a counterparty commitment transaction
+	/// revoked by the latest secret, or if none of its outputs were worth claiming.
+	///
+	/// [`block_connected`]: #method.block_connected
+	/// [`Persist::update_persisted_channel`]: trait.Persist.html#tymethod.update_persisted_channel
+	/// [`get_latest_revoked_counterparty_commitment_txids`]: #method.get_latest_revoked_counterparty_commitment_txids
+	pub fn get_justice_txs_for_revoked(&self, commitment_txid: &Txid) -> Vec<Transaction> {
+		match self.presigned_justice_txn.get(commitment_txid) {
+			Some(justice_txn) => justice_txn.clone(),
+			None => Vec::new(),
+		}
+	}
+
+	/// Gets the txids of the counterparty commitment transactions revoked by the latest revocation
+	/// secret, for which justice transactions may be fetched with [`get_justice_txs_for_revoked`].
+	///
+	/// [`get_justice_txs_for_revoked`]: #method.get_justice_txs_for_revoked
+	pub fn get_latest_revoked_counterparty_commitment_txids(&self) -> Vec<Txid> {
+		self.presigned_justice_txn.keys().cloned().collect()
+	}
+
+	/// Sets whether non-contentious claims should be left to ChainMonitor's claim batching. When
+	/// unset, claims which were waiting to be batched are handed back to the usual claiming logic
+	/// on the next block.
+	pub(crate) fn set_claim_batching(&mut self, claim_batching: bool) {
+		self.claim_batching = claim_batching;
+	}
+
+	/// Gets the inputs which may be included in a batched claim transaction at the given height,
+	/// ie those which are not yet spent and whose relative timelock, if any, has expired.
+	pub(crate) fn get_batchable_claims(&self, height: u32) -> Vec<BatchableClaimInput> {
+		let mut res = Vec::new();
+		for (outpoint, claim) in self.batched_claims.iter() {
+			if claim.spend_height.is_some() { continue; }
+			match claim.material {
+				BatchedClaimMaterial::CounterpartyHTLC { witness_data: InputMaterial::CounterpartyHTLC { ref htlc, .. }, .. } => {
+					res.push(BatchableClaimInput {
+						outpoint: *outpoint,
+						amount: htlc.amount_msat / 1000,
+						sequence: 0xfffffffd,
+						// get_witnesses_weight counts the segwit flags which are only paid once per
+						// transaction
+						witness_weight: OnchainTxHandler::<ChanSigner>::get_witnesses_weight(&[InputDescriptors::OfferedHTLC]) - 2,
+					});
+				},
+				BatchedClaimMaterial::DelayedOutput { descriptor: SpendableOutputDescriptor::DynamicOutputP2WSH { ref output, ref to_self_delay, .. }, ref spendable_height } => {
+					if *spendable_height > height { continue; }
+					res.push(BatchableClaimInput {
+						outpoint: *outpoint,
+						amount: output.value,
+						sequence: *to_self_delay as u32,
+						// number_of_witness_elements + sig_length + delayed_payment_sig + empty_vec_length + witness_script_length + witness_script
+						witness_weight: 1 + 1 + 73 + 1 + 1 + 77,
+					});
+				},
+				_ => {},
+			}
+		}
+		res
+	}
+
+	/// Signs the input at index `input` of the given batched claim transaction, returning its
+	/// witness, if it spends one of our batched claims.
+	pub(crate) fn sign_batched_claim(&self, tx: &Transaction, input: usize) -> Option<Vec<Vec<u8>>> {
+		let claim = self.batched_claims.get(&tx.input[input].previous_output)?;
+		match claim.material {
+			BatchedClaimMaterial::CounterpartyHTLC { witness_data: InputMaterial::CounterpartyHTLC { ref per_commitment_point, ref counterparty_delayed_payment_base_key, ref counterparty_htlc_base_key, preimage: Some(ref preimage), ref htlc }, .. } => {
+				let chan_keys = TxCreationKeys::derive_new(&self.secp_ctx, per_commitment_point, counterparty_delayed_payment_base_key, counterparty_htlc_base_key, &self.keys.pubkeys().revocation_basepoint, &self.keys.pubkeys().htlc_basepoint).ok()?;
+				let witness_script = chan_utils::get_htlc_redeemscript_with_explicit_keys(htlc, &chan_keys.broadcaster_htlc_key, &chan_keys.countersignatory_htlc_key, &chan_keys.revocation_key);
+				let sig = self.keys.sign_counterparty_htlc_transaction(tx, input, htlc.amount_msat / 1000, per_commitment_point, htlc, &self.secp_ctx).ok()?;
+				let mut sig_ser = sig.serialize_der().to_vec();
+				sig_ser.push(SigHashType::All as u8);
+				Some(vec![sig_ser, preimage.0.to_vec(), witness_script.into_bytes()])
+			},
+			BatchedClaimMaterial::DelayedOutput { descriptor: SpendableOutputDescriptor::DynamicOutputP2WSH { ref per_commitment_point, ref to_self_delay, ref output, ref revocation_pubkey, .. }, .. } => {
+				let delayed_payment_pubkey = chan_utils::derive_public_key(&self.secp_ctx, per_commitment_point, &self.keys.pubkeys().delayed_payment_basepoint).ok()?;
+				let witness_script = chan_utils::get_revokeable_redeemscript(revocation_pubkey, *to_self_delay, &delayed_payment_pubkey);
+				let sig = self.keys.sign_delayed_payment_input(tx, input, output.value, per_commitment_point, revocation_pubkey, *to_self_delay, &self.secp_ctx).ok()?;
+				let mut sig_ser = sig.serialize_der().to_vec();
+				sig_ser.push(SigHashType::All as u8);
+				Some(vec![sig_ser, vec![], witness_script.into_bytes()])
+			},
+			_ => None,
+		}
+	}
+
+	/// Updates our batched claims with a newly connected block, noting the ones spent in it and
+	/// forgetting those whose spend reached ANTI_REORG_DELAY confirmations. Claims which should no
+	/// longer be batched (all of them if claim batching was disabled, or HTLC claims getting close
+	/// to their timelock) are handed back, either as new ClaimRequests for our OnchainTxHandler or
+	/// upstream as Event::SpendableOutputs.
+	fn update_batched_claims<L: Deref>(&mut self, txdata: &TransactionData, height: u32, claimable_outpoints: &mut Vec<ClaimRequest>, logger: &L) where L::Target: Logger {
+		for &(_, tx) in txdata.iter() {
+			for input in tx.input.iter() {
+				if let Some(claim) = self.batched_claims.get_mut(&input.previous_output) {
+					if claim.spend_height.is_none() {
+						log_trace!(logger, "Batched claim of {}:{} spent by transaction {}", input.previous_output.txid, input.previous_output.vout, tx.txid());
+						claim.spend_height = Some(height);
+					}
+				}
+			}
+		}
+
+		let claim_batching = self.claim_batching;
+		let pending_events = &mut self.pending_events;
+		self.batched_claims.retain(|outpoint, claim| {
+			if let Some(spend_height) = claim.spend_height {
+				return spend_height + ANTI_REORG_DELAY - 1 > height;
+			}
+			match claim.material {
+				BatchedClaimMaterial::CounterpartyHTLC { ref absolute_timelock, ref witness_data } => {
+					if claim_batching && *absolute_timelock > height + CLTV_SHARED_CLAIM_BUFFER { return true; }
+					log_trace!(logger, "Handing back batched claim of {}:{} to be claimed on its own", outpoint.txid, outpoint.vout);
+					claimable_outpoints.push(ClaimRequest { absolute_timelock: *absolute_timelock, aggregable: true, outpoint: *outpoint, witness_data: witness_data.clone() });
+				},
+				BatchedClaimMaterial::DelayedOutput { ref descriptor, .. } => {
+					if claim_batching { return true; }
+					log_trace!(logger, "Handing back batched claim of {}:{} upstream", outpoint.txid, outpoint.vout);
+					pending_events.push(Event::SpendableOutputs {
+						outputs: vec![descriptor.clone()]
+					});
+				},
+			}
+			false
+		});
+	}
+
+	/// Builds one justice transaction per feerate in feerates_per_kw, as described in
+	/// get_justice_txs_for_revoked, skipping those whose output would be left below the dust
+	/// limit. All the transactions are signed at once through
+	/// ChannelKeys::sign_justice_transactions.
+	fn build_justice_txn(&self, commitment_tx: &Transaction, feerates_per_kw: &[u32]) -> Option<Vec<Transaction>> {
+		if commitment_tx.input.len() != 1 || commitment_tx.input[0].previous_output != self.funding_info.0.into_bitcoin_outpoint() {
+			return None;
+		}
+		let commitment_txid = commitment_tx.txid();
+		let commitment_number = 0xffffffffffff - ((((commitment_tx.input[0].sequence as u64 & 0xffffff) << 3*8) | (commitment_tx.lock_time as u64 & 0xffffff)) ^ self.commitment_transaction_number_obscure_factor);
+		if commitment_number < self.get_min_seen_secret() {
+			return None;
+		}
+		let secret = self.get_secret(commitment_number)?;
+		let per_commitment_key = SecretKey::from_slice(&secret).ok()?;
+		let per_commitment_point = PublicKey::from_secret_key(&self.secp_ctx, &per_commitment_key);
+		let chan_keys = TxCreationKeys::derive_new(&self.secp_ctx, &per_commitment_point, &self.counterparty_tx_cache.counterparty_delayed_payment_base_key, &self.counterparty_tx_cache.counterparty_htlc_base_key, &self.keys.pubkeys().revocation_basepoint, &self.keys.pubkeys().htlc_basepoint).ok()?;
+		let revokeable_redeemscript = chan_utils::get_revokeable_redeemscript(&chan_keys.revocation_key, self.counterparty_tx_cache.on_counterparty_tx_csv, &chan_keys.broadcaster_delayed_payment_key);
+		let revokeable_p2wsh = revokeable_redeemscript.to_v0_p2wsh();
+
+		// (vout, amount, htlc, input descriptor) for each output we can claim
+		let mut claimable_outputs = Vec::new();
+		for (idx, outp) in commitment_tx.output.iter().enumerate() {
+			if outp.script_pubkey == revokeable_p2wsh {
+				claimable_outputs.push((idx as u32, outp.value, None, InputDescriptors::RevokedOutput));
+			}
+		}
+		if let Some(htlc_outputs) = self.counterparty_claimable_outpoints.get(&commitment_txid) {
+			for &(ref htlc, _) in htlc_outputs.iter() {
+				if let Some(transaction_output_index) = htlc.transaction_output_index {
+					if transaction_output_index as usize >= commitment_tx.output.len() ||
+							commitment_tx.output[transaction_output_index as usize].value != htlc.amount_msat / 1000 {
+						return None;
+					}
+					let input_descriptor = if htlc.offered { InputDescriptors::RevokedOfferedHTLC } else { InputDescriptors::RevokedReceivedHTLC };
+					claimable_outputs.push((transaction_output_index, htlc.amount_msat / 1000, Some(htlc.clone()), input_descriptor));
+				}
+			}
+		}
+		if claimable_outputs.is_empty() {
+			return None;
+		}
+
+		let unsigned_justice_tx = Transaction {
+			version: 2,
+			lock_time: 0,
+			input: claimable_outputs.iter().map(|&(vout, _, _, _)| TxIn {
+				previous_output: BitcoinOutPoint { txid: commitment_txid, vout },
+				script_sig: Script::new(),
+				sequence: 0xfffffffd,
+				witness: Vec::new(),
+			}).collect(),
+			output: vec![TxOut {
+				script_pubkey: self.destination_script.clone(),
+				value: 0,
+			}],
+		};
+		let input_descriptors: Vec<InputDescriptors> = claimable_outputs.iter().map(|&(_, _, _, descriptor)| descriptor).collect();
+		let predicted_weight = unsigned_justice_tx.get_weight() + OnchainTxHandler::<ChanSigner>::get_witnesses_weight(&input_descriptors);
+		let total_amount: u64 = claimable_outputs.iter().map(|&(_, amount, _, _)| amount).sum();
+		let mut justice_txn = Vec::with_capacity(feerates_per_kw.len());
+		for feerate_per_kw in feerates_per_kw.iter() {
+			let fee = *feerate_per_kw as u64 * predicted_weight as u64 / 1000;
+			// A transaction with a dust output would never be relayed, there's no point in
+			// handing it out
+			if total_amount < fee + 546 {
+				continue;
+			}
+			let mut justice_tx = unsigned_justice_tx.clone();
+			justice_tx.output[0].value = total_amount - fee;
+			justice_txn.push(justice_tx);
+		}
+		if justice_txn.is_empty() {
+			return None;
+		}
+
+		let inputs: Vec<(u64, Option<HTLCOutputInCommitment>)> = claimable_outputs.iter().map(|&(_, amount, ref htlc, _)| (amount, htlc.clone())).collect();
+		let sigs = self.keys.sign_justice_transactions(&justice_txn, &inputs, &per_commitment_key, &self.secp_ctx).ok()?;
+		if sigs.len() != justice_txn.len() {
+			return None;
+		}
+		for (justice_tx, tx_sigs) in justice_txn.iter_mut().zip(sigs.iter()) {
+			if tx_sigs.len() != inputs.len() {
+				return None;
+			}
+			for (i, (&(_, ref htlc), sig)) in inputs.iter().zip(tx_sigs.iter()).enumerate() {
+				let witness_script = if let &Some(ref htlc) = htlc {
+					chan_utils::get_htlc_redeemscript_with_explicit_keys(&htlc, &chan_keys.broadcaster_htlc_key, &chan_keys.countersignatory_htlc_key, &chan_keys.revocation_key)
+				} else {
+					revokeable_redeemscript.clone()
+				};
+				justice_tx.input[i].witness.push(sig.serialize_der().to_vec());
+				justice_tx.input[i].witness[0].push(SigHashType::All as u8);
+				if htlc.is_some() {
+					justice_tx.input[i].witness.push(chan_keys.revocation_key.serialize().to_vec());
+				} else {
+					justice_tx.input[i].witness.push(vec!(1));
+				}
+				justice_tx.input[i].witness.push(witness_script.into_bytes());
+			}
+		}
+		Some(justice_txn)
+	}
+
 	/// Can only fail if idx is < get_min_seen_secret
 	fn get_secret(&self, idx: u64) -> Option<[u8; 32]> {
 		self.commitment_secrets.get_secret(idx)
@@ -1799,6 +2479,7 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 				// filters.
 				let prevout = &tx.input[0].previous_output;
 				if prevout.txid == self.funding_info.0.txid && prevout.vout == self.funding_info.0.index as u32 {
+					self.funding_spend_confirmed = Some((tx.txid(), height));
 					if (tx.input[0].sequence >> 8*3) as u8 == 0x80 && (tx.lock_time >> 8*3) as u8 == 0x20 {
 						let (mut new_outpoints, new_outputs) = self.check_spend_counterparty_transaction(&tx, height, &logger);
 						if !new_outputs.1.is_empty() {
@@ -1858,15 +2539,62 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 						}));
 					},
 					OnchainEvent::MaturingOutput { descriptor } => {
-						log_trace!(logger, "Descriptor {} has got enough confirmations to be passed upstream", log_spendable!(descriptor));
-						self.pending_events.push(Event::SpendableOutputs {
-							outputs: vec![descriptor]
-						});
+						let delayed_output = match descriptor {
+							SpendableOutputDescriptor::DynamicOutputP2WSH { ref outpoint, ref to_self_delay, .. } => Some((outpoint.into_bitcoin_outpoint(), *to_self_delay)),
+							_ => None,
+						};
+						match delayed_output {
+							Some((outpoint, to_self_delay)) if self.claim_batching => {
+								log_trace!(logger, "Descriptor {} has got enough confirmations, leaving it to claim batching", log_spendable!(descriptor));
+								let confirmation_height = height + 1 - ANTI_REORG_DELAY;
+								self.batched_claims.entry(outpoint).or_insert(BatchedClaim {
+									material: BatchedClaimMaterial::DelayedOutput {
+										descriptor,
+										spendable_height: cmp::max(height, confirmation_height + to_self_delay as u32),
+									},
+									confirmation_height,
+									spend_height: None,
+								});
+							},
+							_ => {
+								log_trace!(logger, "Descriptor {} has got enough confirmations to be passed upstream", log_spendable!(descriptor));
+								self.pending_events.push(Event::SpendableOutputs {
+									outputs: vec![descriptor]
+								});
+							},
+						}
 					}
 				}
 			}
 		}
 
+		if self.claim_batching {
+			// Claims of counterparty HTLC outputs for which we know the preimage aren't contentious
+			// until the HTLC times out, so we leave them to ChainMonitor's claim batching until then.
+			let mut remaining_outpoints = Vec::with_capacity(claimable_outpoints.len());
+			for claim in claimable_outpoints.drain(..) {
+				let batchable = claim.aggregable && claim.absolute_timelock > height + CLTV_SHARED_CLAIM_BUFFER && match claim.witness_data {
+					InputMaterial::CounterpartyHTLC { ref preimage, .. } => preimage.is_some(),
+					_ => false,
+				};
+				if batchable {
+					log_trace!(logger, "Leaving claim of {}:{} to claim batching", claim.outpoint.txid, claim.outpoint.vout);
+					self.batched_claims.entry(claim.outpoint).or_insert(BatchedClaim {
+						material: BatchedClaimMaterial::CounterpartyHTLC {
+							absolute_timelock: claim.absolute_timelock,
+							witness_data: claim.witness_data,
+						},
+						confirmation_height: height,
+						spend_height: None,
+					});
+				} else {
+					remaining_outpoints.push(claim);
+				}
+			}
+			claimable_outpoints = remaining_outpoints;
+		}
+		self.update_batched_claims(txdata, height, &mut claimable_outpoints, &logger);
+
 		self.onchain_tx_handler.update_claims_view(&txn_matched, claimable_outpoints, Some(height), &&*broadcaster, &&*fee_estimator, &&*logger);
 		self.last_block_hash = block_hash;
 
@@ -1909,6 +2637,21 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 			//- maturing spendable output has transaction paying us has been disconnected
 		}
 
+		if let Some((_, conf_height)) = self.funding_spend_confirmed {
+			if conf_height >= height {
+				self.funding_spend_confirmed = None;
+			}
+		}
+
+		self.batched_claims.retain(|_, claim| claim.confirmation_height < height);
+		for claim in self.batched_claims.values_mut() {
+			if let Some(spend_height) = claim.spend_height {
+				if spend_height >= height {
+					claim.spend_height = None;
+				}
+			}
+		}
+
 		self.onchain_tx_handler.block_disconnected(height, broadcaster, fee_estimator, logger);
 
 		self.last_block_hash = block_hash;
@@ -2295,7 +3038,7 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for (BlockHash, ChannelMonitor
 			}
 		}
 
-		let _ver: u8 = Readable::read(reader)?;
+		let ver: u8 = Readable::read(reader)?;
 		let min_ver: u8 = Readable::read(reader)?;
 		if min_ver > SERIALIZATION_VERSION {
 			return Err(DecodeError::UnknownVersion);
@@ -2523,6 +3266,48 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for (BlockHash, ChannelMonitor
 		let lockdown_from_offchain = Readable::read(reader)?;
 		let holder_tx_signed = Readable::read(reader)?;
 
+		// Monitors written before version 2 end here and are read with the new fields empty.
+		let funding_spend_confirmed = if ver >= 2 { Readable::read(reader)? } else { None };
+
+		let mut unrevoked_counterparty_commitment_txn = HashMap::new();
+		let mut presigned_justice_txn = HashMap::new();
+		if ver >= 2 {
+			let unrevoked_counterparty_commitment_txn_len: u64 = Readable::read(reader)?;
+			unrevoked_counterparty_commitment_txn.reserve(cmp::min(unrevoked_counterparty_commitment_txn_len as usize, MAX_ALLOC_SIZE / 128));
+			for _ in 0..unrevoked_counterparty_commitment_txn_len {
+				let tx: Transaction = Readable::read(reader)?;
+				if let Some(_) = unrevoked_counterparty_commitment_txn.insert(tx.txid(), tx) {
+					return Err(DecodeError::InvalidValue);
+				}
+			}
+			let presigned_justice_txn_len: u64 = Readable::read(reader)?;
+			presigned_justice_txn.reserve(cmp::min(presigned_justice_txn_len as usize, MAX_ALLOC_SIZE / 128));
+			for _ in 0..presigned_justice_txn_len {
+				let txid: Txid = Readable::read(reader)?;
+				let justice_txn_len: u64 = Readable::read(reader)?;
+				let mut justice_txn = Vec::with_capacity(cmp::min(justice_txn_len as usize, MAX_ALLOC_SIZE / 128));
+				for _ in 0..justice_txn_len {
+					justice_txn.push(Readable::read(reader)?);
+				}
+				if let Some(_) = presigned_justice_txn.insert(txid, justice_txn) {
+					return Err(DecodeError::InvalidValue);
+				}
+			}
+		}
+
+		let mut batched_claims = HashMap::new();
+		if ver >= 2 {
+			let batched_claims_len: u64 = Readable::read(reader)?;
+			batched_claims.reserve(cmp::min(batched_claims_len as usize, MAX_ALLOC_SIZE / 128));
+			for _ in 0..batched_claims_len {
+				let outpoint: BitcoinOutPoint = Readable::read(reader)?;
+				let claim = Readable::read(reader)?;
+				if let Some(_) = batched_claims.insert(outpoint, claim) {
+					return Err(DecodeError::InvalidValue);
+				}
+			}
+		}
+
 		Ok((last_block_hash.clone(), ChannelMonitor {
 			latest_update_id,
 			commitment_transaction_number_obscure_factor,
@@ -2566,6 +3351,14 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for (BlockHash, ChannelMonitor
 			lockdown_from_offchain,
 			holder_tx_signed,
 
+			funding_spend_confirmed,
+
+			unrevoked_counterparty_commitment_txn,
+			presigned_justice_txn,
+
+			claim_batching: false,
+			batched_claims,
+
 			last_block_hash,
 			secp_ctx: Secp256k1::new(),
 		}))
@@ -2595,6 +3388,9 @@ mod tests {
 	use bitcoin::secp256k1::Secp256k1;
 	use std::sync::{Arc, Mutex};
 	use chain::keysinterface::InMemoryChannelKeys;
+	use bitcoin::hash_types::BlockHash;
+	use util::ser::{Readable, VecWriter};
+	use std::io::Cursor;
 
 	#[test]
 	fn test_prune_preimages() {
@@ -2683,14 +3479,14 @@ mod tests {
 		// Now provide a secret, pruning preimages 10-15
 		let mut secret = [0; 32];
 		secret[0..32].clone_from_slice(&hex::decode("7cc854b54e3e0dcdb010d7a3fee464a9687be6e8db3be6854c475621e007a5dc").unwrap());
-		monitor.provide_secret(281474976710655, secret.clone()).unwrap();
+		monitor.provide_secret(281474976710655, secret.clone(), &fee_estimator, &logger).unwrap();
 		assert_eq!(monitor.payment_preimages.len(), 15);
 		test_preimages_exist!(&preimages[0..10], monitor);
 		test_preimages_exist!(&preimages[15..20], monitor);
 
 		// Now provide a further secret, pruning preimages 15-17
 		secret[0..32].clone_from_slice(&hex::decode("c7518c8ae4660ed02894df8976fa1a3659c1a8b4b5bec0c4b872abeba4cb8964").unwrap());
-		monitor.provide_secret(281474976710654, secret.clone()).unwrap();
+		monitor.provide_secret(281474976710654, secret.clone(), &fee_estimator, &logger).unwrap();
 		assert_eq!(monitor.payment_preimages.len(), 13);
 		test_preimages_exist!(&preimages[0..10], monitor);
 		test_preimages_exist!(&preimages[17..20], monitor);
@@ -2699,7 +3495,7 @@ mod tests {
 		// previous commitment tx's preimages too
 		monitor.provide_latest_holder_commitment_tx_info(HolderCommitmentTransaction::dummy(), preimages_to_holder_htlcs!(preimages[0..5])).unwrap();
 		secret[0..32].clone_from_slice(&hex::decode("2273e227a5b7449b6e70f1fb4652864038b1cbf9cd7c043a7d6456b7fc275ad8").unwrap());
-		monitor.provide_secret(281474976710653, secret.clone()).unwrap();
+		monitor.provide_secret(281474976710653, secret.clone(), &fee_estimator, &logger).unwrap();
 		assert_eq!(monitor.payment_preimages.len(), 12);
 		test_preimages_exist!(&preimages[0..10], monitor);
 		test_preimages_exist!(&preimages[18..20], monitor);
@@ -2707,7 +3503,7 @@ mod tests {
 		// But if we do it again, we'll prune 5-10
 		monitor.provide_latest_holder_commitment_tx_info(HolderCommitmentTransaction::dummy(), preimages_to_holder_htlcs!(preimages[0..3])).unwrap();
 		secret[0..32].clone_from_slice(&hex::decode("27cddaa5624534cb6cb9d7da077cf2b22ab21e9b506fd4998a51d54502e99116").unwrap());
-		monitor.provide_secret(281474976710652, secret.clone()).unwrap();
+		monitor.provide_secret(281474976710652, secret.clone(), &fee_estimator, &logger).unwrap();
 		assert_eq!(monitor.payment_preimages.len(), 5);
 		test_preimages_exist!(&preimages[0..5], monitor);
 	}
@@ -2830,5 +3626,54 @@ mod tests {
 		assert_eq!(base_weight + OnchainTxHandler::<InMemoryChannelKeys>::get_witnesses_weight(&inputs_des[..]), claim_tx.get_weight() + /* max_length_isg */ (73 * inputs_des.len() - sum_actual_sigs));
 	}
 
+	#[test]
+	fn test_read_version_1_monitor() {
+		// Monitors written before SERIALIZATION_VERSION 2 stop after holder_tx_signed. Check that
+		// we still read them, leaving the fields added in version 2 empty.
+		let secp_ctx = Secp256k1::new();
+		let keys = InMemoryChannelKeys::new(
+			&secp_ctx,
+			SecretKey::from_slice(&[41; 32]).unwrap(),
+			SecretKey::from_slice(&[41; 32]).unwrap(),
+			SecretKey::from_slice(&[41; 32]).unwrap(),
+			SecretKey::from_slice(&[41; 32]).unwrap(),
+			SecretKey::from_slice(&[41; 32]).unwrap(),
+			[41; 32],
+			0,
+			(0, 0)
+		);
+		let mut monitor = ChannelMonitor::new(keys,
+			&PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42; 32]).unwrap()), 0, &Script::new(),
+			(OutPoint { txid: Txid::from_slice(&[43; 32]).unwrap(), index: 0 }, Script::new()),
+			&PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[44; 32]).unwrap()),
+			&PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[45; 32]).unwrap()),
+			10, Script::new(), 46, 0, HolderCommitmentTransaction::dummy());
+		let logger = Arc::new(TestLogger::new());
+		let dummy_key = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42; 32]).unwrap());
+		let dummy_tx = Transaction { version: 0, lock_time: 0, input: Vec::new(), output: Vec::new() };
+		monitor.provide_latest_counterparty_commitment_tx_info(&dummy_tx, Vec::new(), 281474976710655, dummy_key, &logger);
+		// A version 1 monitor does not track the unrevoked counterparty commitment transaction.
+		monitor.unrevoked_counterparty_commitment_txn.clear();
+
+		let mut w = VecWriter(Vec::new());
+		monitor.serialize_for_disk(&mut w).unwrap();
+		let mut encoded = w.0;
+		assert_eq!(encoded[0], 2);
+
+		// funding_spend_confirmed (None) followed by three empty maps, each prefixed by a u64 length
+		let version_2_fields_len = 1 + 3 * 8;
+		let legacy_len = encoded.len() - version_2_fields_len;
+		assert!(encoded[legacy_len..].iter().all(|b| *b == 0));
+		encoded.truncate(legacy_len);
+		encoded[0] = 1;
+
+		let (_, read_monitor) = <(BlockHash, ChannelMonitor<InMemoryChannelKeys>)>::read(&mut Cursor::new(&encoded)).unwrap();
+		assert!(read_monitor == monitor);
+
+		// A version 2 monitor missing its trailing fields is still rejected
+		encoded[0] = 2;
+		assert!(<(BlockHash, ChannelMonitor<InMemoryChannelKeys>)>::read(&mut Cursor::new(&encoded)).is_err());
+	}
+
 	// Further testing is done in the ChannelManager integration tests.
 }
@@ -11,7 +11,7 @@
 //! spendable on-chain outputs which the user owns and is responsible for using just as any other
 //! on-chain output which is theirs.
 
-use bitcoin::blockdata::transaction::{Transaction, TxOut, SigHashType};
+use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut, SigHashType};
 use bitcoin::blockdata::script::{Script, Builder};
 use bitcoin::blockdata::opcodes;
 use bitcoin::network::constants::Network;
@@ -22,7 +22,7 @@ use bitcoin::hashes::{Hash, HashEngine};
 use bitcoin::hashes::sha256::HashEngine as Sha256State;
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
-use bitcoin::hash_types::WPubkeyHash;
+use bitcoin::hash_types::{WPubkeyHash, PubkeyHash};
 
 use bitcoin::secp256k1::key::{SecretKey, PublicKey};
 use bitcoin::secp256k1::{Secp256k1, Signature, Signing};
@@ -86,8 +86,8 @@ pub enum SpendableOutputDescriptor {
 	/// regenerated by passing the revocation_pubkey (derived as above), our delayed_payment pubkey
 	/// (derived as above), and the to_self_delay contained here to
 	/// chan_utils::get_revokeable_redeemscript.
-	//
-	// TODO: we need to expose utility methods in KeyManager to do all the relevant derivation.
+	///
+	/// If you use KeysManager, KeysManager::spend_spendable_outputs does all the above for you.
 	DynamicOutputP2WSH {
 		/// The outpoint which is spendable
 		outpoint: OutPoint,
@@ -111,6 +111,8 @@ pub enum SpendableOutputDescriptor {
 	///
 	/// These are generally the result of our counterparty having broadcast the current state,
 	/// allowing us to claim the non-HTLC-encumbered outputs immediately.
+	///
+	/// If you use KeysManager, KeysManager::spend_spendable_outputs can sign for these.
 	StaticOutputCounterpartyPayment {
 		/// The outpoint which is spendable
 		outpoint: OutPoint,
@@ -286,6 +288,18 @@ pub trait ChannelKeys : Send+Clone {
 	/// signatures).
 	fn sign_justice_transaction<T: secp256k1::Signing + secp256k1::Verification>(&self, justice_tx: &Transaction, input: usize, amount: u64, per_commitment_key: &SecretKey, htlc: &Option<HTLCOutputInCommitment>, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()>;
 
+	/// Create signatures for a set of justice transactions pre-built as soon as our counterparty
+	/// revokes one of its commitment transactions, so that they may be handed over to a third
+	/// party (eg a watchtower) able to broadcast them on our behalf without ever holding our keys.
+	///
+	/// Every transaction in justice_txn spends the same outputs of the same revoked commitment
+	/// transaction, in the same order, and only differs in the feerate it pays. inputs holds, for
+	/// each input, the amount of the output spent and its HTLC elements, if any, with the same
+	/// meaning as in sign_justice_transaction.
+	///
+	/// On success, returns for each transaction the signatures for each of its inputs, in order.
+	fn sign_justice_transactions<T: secp256k1::Signing + secp256k1::Verification>(&self, justice_txn: &[Transaction], inputs: &[(u64, Option<HTLCOutputInCommitment>)], per_commitment_key: &SecretKey, secp_ctx: &Secp256k1<T>) -> Result<Vec<Vec<Signature>>, ()>;
+
 	/// Create a signature for a claiming transaction for a HTLC output on a counterparty's commitment
 	/// transaction, either offered or received.
 	///
@@ -305,6 +319,22 @@ pub trait ChannelKeys : Send+Clone {
 	/// BIP 143 signature.
 	fn sign_counterparty_htlc_transaction<T: secp256k1::Signing + secp256k1::Verification>(&self, htlc_tx: &Transaction, input: usize, amount: u64, per_commitment_point: &PublicKey, htlc: &HTLCOutputInCommitment, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()>;
 
+	/// Create a signature for a transaction spending one of our CSV-delayed outputs (ie the
+	/// to_local output of a holder commitment transaction or the output of a holder HTLC
+	/// transaction, as described by SpendableOutputDescriptor::DynamicOutputP2WSH) once its
+	/// delay has passed.
+	///
+	/// Such a transaction may spend outputs of several channels at once, but only the input at
+	/// index `input` should be signed for here. It may be called multiple times for the same
+	/// output if a fee-bump is needed.
+	///
+	/// Amount is value of the output spent by this input, committed to in the BIP 143 signature.
+	///
+	/// Per_commitment_point is the holder per-commitment point of the state the output belongs
+	/// to, used to derive the delayed payment key. The witness script, committed to in the BIP
+	/// 143 signature, is the revokeable script for the given revocation_pubkey and to_self_delay.
+	fn sign_delayed_payment_input<T: secp256k1::Signing + secp256k1::Verification>(&self, spend_tx: &Transaction, input: usize, amount: u64, per_commitment_point: &PublicKey, revocation_pubkey: &PublicKey, to_self_delay: u16, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()>;
+
 	/// Create a signature for a (proposed) closing transaction.
 	///
 	/// Note that, due to rounding, there may be one "missing" satoshi, and either party may have
@@ -348,6 +378,30 @@ pub trait KeysInterface: Send + Sync {
 	fn get_secure_random_bytes(&self) -> [u8; 32];
 }
 
+/// A trait to describe an object which can spend the [`SpendableOutputDescriptor`]s of the channels
+/// it provided the keys for, eg to hand them to an [`OutputSweeper`].
+///
+/// [`SpendableOutputDescriptor`]: enum.SpendableOutputDescriptor.html
+/// [`OutputSweeper`]: ../sweeper/struct.OutputSweeper.html
+pub trait OutputSpender {
+	/// Creates a transaction which spends the given descriptors to the given outputs, plus an
+	/// output to the given change destination (if sufficient change value remains). The
+	/// transaction will have a feerate, at least, of the given value.
+	///
+	/// Returns Err(()) if the output value is greater than the input value minus required fee, if
+	/// a descriptor can't be signed for (eg a StaticOutput to a script other than our destination
+	/// script or the P2WPKH script of our shutdown pubkey), or if the transaction would be left
+	/// without any output.
+	///
+	/// The transaction is fully signed and, as spending DynamicOutputP2WSH descriptors requires,
+	/// respects their to_self_delay in the spending input's nSequence. It may thus not be
+	/// broadcastable until to_self_delay blocks after such an output confirmed.
+	///
+	/// Note that the change output is dropped (and its value added to the fee) if it would be
+	/// dust.
+	fn spend_spendable_outputs<C: Signing>(&self, descriptors: &[SpendableOutputDescriptor], outputs: Vec<TxOut>, change_destination_script: Script, feerate_sat_per_1000_weight: u32, secp_ctx: &Secp256k1<C>) -> Result<Transaction, ()>;
+}
+
 #[derive(Clone)]
 /// Holds late-bound channel data.
 /// This data is available after the channel is known to be accepted, either
@@ -454,6 +508,9 @@ impl InMemoryChannelKeys {
 	/// if they broadcast a transaction.
 	/// Will panic if on_accept wasn't called.
 	pub fn holder_selected_contest_delay(&self) -> u16 { self.accepted_channel_data.as_ref().unwrap().holder_selected_contest_delay }
+
+	/// Whether on_accept was called, ie whether the above getters may be used.
+	pub fn is_accepted(&self) -> bool { self.accepted_channel_data.is_some() }
 }
 
 impl ChannelKeys for InMemoryChannelKeys {
@@ -553,6 +610,19 @@ impl ChannelKeys for InMemoryChannelKeys {
 		return Ok(secp_ctx.sign(&sighash, &revocation_key))
 	}
 
+	fn sign_justice_transactions<T: secp256k1::Signing + secp256k1::Verification>(&self, justice_txn: &[Transaction], inputs: &[(u64, Option<HTLCOutputInCommitment>)], per_commitment_key: &SecretKey, secp_ctx: &Secp256k1<T>) -> Result<Vec<Vec<Signature>>, ()> {
+		let mut sigs = Vec::with_capacity(justice_txn.len());
+		for justice_tx in justice_txn.iter() {
+			if justice_tx.input.len() != inputs.len() { return Err(()); }
+			let mut tx_sigs = Vec::with_capacity(inputs.len());
+			for (input, &(amount, ref htlc)) in inputs.iter().enumerate() {
+				tx_sigs.push(self.sign_justice_transaction(justice_tx, input, amount, per_commitment_key, htlc, secp_ctx)?);
+			}
+			sigs.push(tx_sigs);
+		}
+		Ok(sigs)
+	}
+
 	fn sign_counterparty_htlc_transaction<T: secp256k1::Signing + secp256k1::Verification>(&self, htlc_tx: &Transaction, input: usize, amount: u64, per_commitment_point: &PublicKey, htlc: &HTLCOutputInCommitment, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
 		if let Ok(htlc_key) = chan_utils::derive_private_key(&secp_ctx, &per_commitment_point, &self.htlc_base_key) {
 			let witness_script = if let Ok(revocation_pubkey) = chan_utils::derive_public_revocation_key(&secp_ctx, &per_commitment_point, &self.pubkeys().revocation_basepoint) {
@@ -569,6 +639,18 @@ impl ChannelKeys for InMemoryChannelKeys {
 		Err(())
 	}
 
+	fn sign_delayed_payment_input<T: secp256k1::Signing + secp256k1::Verification>(&self, spend_tx: &Transaction, input: usize, amount: u64, per_commitment_point: &PublicKey, revocation_pubkey: &PublicKey, to_self_delay: u16, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		let delayed_payment_key = match chan_utils::derive_private_key(&secp_ctx, &per_commitment_point, &self.delayed_payment_base_key) {
+			Ok(delayed_payment_key) => delayed_payment_key,
+			Err(_) => return Err(())
+		};
+		let delayed_payment_pubkey = PublicKey::from_secret_key(secp_ctx, &delayed_payment_key);
+		let witness_script = chan_utils::get_revokeable_redeemscript(revocation_pubkey, to_self_delay, &delayed_payment_pubkey);
+		let mut sighash_parts = bip143::SigHashCache::new(spend_tx);
+		let sighash = hash_to_message!(&sighash_parts.signature_hash(input, &witness_script, amount, SigHashType::All)[..]);
+		Ok(secp_ctx.sign(&sighash, &delayed_payment_key))
+	}
+
 	fn sign_closing_transaction<T: secp256k1::Signing>(&self, closing_tx: &Transaction, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
 		if closing_tx.input.len() != 1 { return Err(()); }
 		if closing_tx.input[0].witness.len() != 0 { return Err(()); }
@@ -651,6 +733,11 @@ impl Readable for InMemoryChannelKeys {
 	}
 }
 
+// number_of_witness_elements + sig_length + sig + pubkey_length + pubkey
+const P2WPKH_WITNESS_WEIGHT: usize = 1 + 1 + 73 + 1 + 33;
+// number_of_witness_elements + sig_length + sig + empty_vec_length + witness_script_length + witness_script
+const DYNAMIC_OUTPUT_P2WSH_WITNESS_WEIGHT: usize = 1 + 1 + 73 + 1 + 1 + 77;
+
 /// Simple KeysInterface implementor that takes a 32-byte seed for use as a BIP 32 extended key
 /// and derives keys from that.
 ///
@@ -661,7 +748,9 @@ impl Readable for InMemoryChannelKeys {
 pub struct KeysManager {
 	secp_ctx: Secp256k1<secp256k1::SignOnly>,
 	node_secret: SecretKey,
+	destination_key: SecretKey,
 	destination_script: Script,
+	shutdown_key: SecretKey,
 	shutdown_pubkey: PublicKey,
 	channel_master_key: ExtendedPrivKey,
 	channel_child_index: AtomicUsize,
@@ -698,17 +787,17 @@ impl KeysManager {
 		match ExtendedPrivKey::new_master(network.clone(), seed) {
 			Ok(master_key) => {
 				let node_secret = master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(0).unwrap()).expect("Your RNG is busted").private_key.key;
-				let destination_script = match master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(1).unwrap()) {
+				let (destination_key, destination_script) = match master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(1).unwrap()) {
 					Ok(destination_key) => {
 						let wpubkey_hash = WPubkeyHash::hash(&ExtendedPubKey::from_private(&secp_ctx, &destination_key).public_key.to_bytes());
-						Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0)
+						(destination_key.private_key.key, Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0)
 						              .push_slice(&wpubkey_hash.into_inner())
-						              .into_script()
+						              .into_script())
 					},
 					Err(_) => panic!("Your RNG is busted"),
 				};
-				let shutdown_pubkey = match master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(2).unwrap()) {
-					Ok(shutdown_key) => ExtendedPubKey::from_private(&secp_ctx, &shutdown_key).public_key.key,
+				let (shutdown_key, shutdown_pubkey) = match master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(2).unwrap()) {
+					Ok(shutdown_key) => (shutdown_key.private_key.key, ExtendedPubKey::from_private(&secp_ctx, &shutdown_key).public_key.key),
 					Err(_) => panic!("Your RNG is busted"),
 				};
 				let channel_master_key = master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(3).unwrap()).expect("Your RNG is busted");
@@ -717,7 +806,9 @@ impl KeysManager {
 				KeysManager {
 					secp_ctx,
 					node_secret,
+					destination_key,
 					destination_script,
+					shutdown_key,
 					shutdown_pubkey,
 					channel_master_key,
 					channel_child_index: AtomicUsize::new(0),
@@ -792,6 +883,129 @@ impl KeysManager {
 			(params_1, params_2),
 		)
 	}
+
+	/// The BIP 143 scriptCode for a P2WPKH output to the given pubkey.
+	fn p2pkh_script_code(pubkey: &PublicKey) -> Script {
+		Builder::new().push_opcode(opcodes::all::OP_DUP)
+		              .push_opcode(opcodes::all::OP_HASH160)
+		              .push_slice(&PubkeyHash::hash(&pubkey.serialize()).into_inner())
+		              .push_opcode(opcodes::all::OP_EQUALVERIFY)
+		              .push_opcode(opcodes::all::OP_CHECKSIG)
+		              .into_script()
+	}
+
+	fn sig_with_sighash_all(sig: &Signature) -> Vec<u8> {
+		let mut sig_ser = sig.serialize_der().to_vec();
+		sig_ser.push(SigHashType::All as u8);
+		sig_ser
+	}
+}
+
+// The P2WPKH script ChannelMonitor expects our shutdown pubkey to be paid to.
+fn p2wpkh_script(pubkey: &PublicKey) -> Script {
+	Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0)
+	              .push_slice(&WPubkeyHash::hash(&pubkey.serialize()).into_inner())
+	              .into_script()
+}
+
+// Builds and signs a transaction spending the given descriptors, as described at
+// KeysManager::spend_spendable_outputs, given the keys of the KeysInterface which generated them.
+// static_output_keys are the keys StaticOutputs may be spent with, along with the P2WPKH script
+// paying to each.
+fn spend_outputs<C: Signing, F: Fn(u64, u64) -> InMemoryChannelKeys>(descriptors: &[SpendableOutputDescriptor], outputs: Vec<TxOut>, change_destination_script: Script, feerate_sat_per_1000_weight: u32, static_output_keys: &[(&SecretKey, Script)], derive_channel_keys: F, secp_ctx: &Secp256k1<C>) -> Result<Transaction, ()> {
+	let mut input = Vec::with_capacity(descriptors.len());
+	let mut input_value = 0;
+	// Count the segwit marker and flag, not included in get_weight() as we sign after
+	let mut witness_weight = 2;
+	for descriptor in descriptors.iter() {
+		let (outpoint, value, sequence) = match descriptor {
+			&SpendableOutputDescriptor::StaticOutput { ref outpoint, ref output } => {
+				if !static_output_keys.iter().any(|&(_, ref script)| *script == output.script_pubkey) { return Err(()); }
+				witness_weight += P2WPKH_WITNESS_WEIGHT;
+				(outpoint, output.value, 0xfffffffd)
+			},
+			&SpendableOutputDescriptor::DynamicOutputP2WSH { ref outpoint, ref output, to_self_delay, .. } => {
+				witness_weight += DYNAMIC_OUTPUT_P2WSH_WITNESS_WEIGHT;
+				(outpoint, output.value, to_self_delay as u32)
+			},
+			&SpendableOutputDescriptor::StaticOutputCounterpartyPayment { ref outpoint, ref output, .. } => {
+				witness_weight += P2WPKH_WITNESS_WEIGHT;
+				(outpoint, output.value, 0xfffffffd)
+			},
+		};
+		input.push(TxIn {
+			previous_output: outpoint.into_bitcoin_outpoint(),
+			script_sig: Script::new(),
+			sequence,
+			witness: Vec::new(),
+		});
+		input_value += value;
+	}
+	let output_value: u64 = outputs.iter().map(|output| output.value).sum();
+	if input_value < output_value { return Err(()); }
+
+	let mut spend_tx = Transaction {
+		version: 2,
+		lock_time: 0,
+		input,
+		output: outputs,
+	};
+	spend_tx.output.push(TxOut { script_pubkey: change_destination_script, value: 0 });
+	let fee_with_change = feerate_sat_per_1000_weight as u64 * (spend_tx.get_weight() + witness_weight) as u64 / 1000;
+	// Don't bother creating a change output which would be dust
+	if input_value - output_value >= fee_with_change + 546 {
+		let change_idx = spend_tx.output.len() - 1;
+		spend_tx.output[change_idx].value = input_value - output_value - fee_with_change;
+	} else {
+		spend_tx.output.pop();
+		let fee = feerate_sat_per_1000_weight as u64 * (spend_tx.get_weight() + witness_weight) as u64 / 1000;
+		if input_value - output_value < fee || spend_tx.output.is_empty() { return Err(()); }
+	}
+
+	let mut witnesses = Vec::with_capacity(descriptors.len());
+	{
+		let mut sighash_parts = bip143::SigHashCache::new(&spend_tx);
+		for (input_idx, descriptor) in descriptors.iter().enumerate() {
+			match descriptor {
+				&SpendableOutputDescriptor::StaticOutput { ref output, .. } => {
+					let key = static_output_keys.iter().find(|&&(_, ref script)| *script == output.script_pubkey).unwrap().0;
+					let pubkey = PublicKey::from_secret_key(secp_ctx, key);
+					let sighash = hash_to_message!(&sighash_parts.signature_hash(input_idx, &KeysManager::p2pkh_script_code(&pubkey), output.value, SigHashType::All)[..]);
+					let sig = secp_ctx.sign(&sighash, key);
+					witnesses.push(vec![KeysManager::sig_with_sighash_all(&sig), pubkey.serialize().to_vec()]);
+				},
+				&SpendableOutputDescriptor::DynamicOutputP2WSH { ref per_commitment_point, to_self_delay, ref output, ref key_derivation_params, ref revocation_pubkey, .. } => {
+					let chan_keys = derive_channel_keys(key_derivation_params.0, key_derivation_params.1);
+					let delayed_payment_key = chan_utils::derive_private_key(secp_ctx, per_commitment_point, &chan_keys.delayed_payment_base_key).map_err(|_| ())?;
+					let delayed_payment_pubkey = PublicKey::from_secret_key(secp_ctx, &delayed_payment_key);
+					let witness_script = chan_utils::get_revokeable_redeemscript(revocation_pubkey, to_self_delay, &delayed_payment_pubkey);
+					if output.script_pubkey != witness_script.to_v0_p2wsh() { return Err(()); }
+					let sighash = hash_to_message!(&sighash_parts.signature_hash(input_idx, &witness_script, output.value, SigHashType::All)[..]);
+					let sig = secp_ctx.sign(&sighash, &delayed_payment_key);
+					witnesses.push(vec![KeysManager::sig_with_sighash_all(&sig), Vec::new(), witness_script.into_bytes()]);
+				},
+				&SpendableOutputDescriptor::StaticOutputCounterpartyPayment { ref output, ref key_derivation_params, .. } => {
+					let chan_keys = derive_channel_keys(key_derivation_params.0, key_derivation_params.1);
+					let pubkey = PublicKey::from_secret_key(secp_ctx, &chan_keys.payment_key);
+					let sighash = hash_to_message!(&sighash_parts.signature_hash(input_idx, &KeysManager::p2pkh_script_code(&pubkey), output.value, SigHashType::All)[..]);
+					let sig = secp_ctx.sign(&sighash, &chan_keys.payment_key);
+					witnesses.push(vec![KeysManager::sig_with_sighash_all(&sig), pubkey.serialize().to_vec()]);
+				},
+			}
+		}
+	}
+	for (input, witness) in spend_tx.input.iter_mut().zip(witnesses.drain(..)) {
+		input.witness = witness;
+	}
+	Ok(spend_tx)
+}
+
+impl OutputSpender for KeysManager {
+	fn spend_spendable_outputs<C: Signing>(&self, descriptors: &[SpendableOutputDescriptor], outputs: Vec<TxOut>, change_destination_script: Script, feerate_sat_per_1000_weight: u32, secp_ctx: &Secp256k1<C>) -> Result<Transaction, ()> {
+		let static_output_keys = [(&self.destination_key, self.destination_script.clone()), (&self.shutdown_key, p2wpkh_script(&self.shutdown_pubkey))];
+		spend_outputs(descriptors, outputs, change_destination_script, feerate_sat_per_1000_weight, &static_output_keys,
+			|params_1, params_2| self.derive_channel_keys(0, params_1, params_2), secp_ctx)
+	}
 }
 
 impl KeysInterface for KeysManager {
@@ -826,3 +1040,200 @@ impl KeysInterface for KeysManager {
 		Sha256::from_engine(sha).into_inner()
 	}
 }
+
+/// A KeysInterface implementor which, unlike [`KeysManager`], derives all channel keys from a
+/// 32-byte seed along fixed BIP 32 paths, allowing the keys of any channel (and thus any
+/// [`SpendableOutputDescriptor`] it generated) to be recovered from the seed alone.
+///
+/// Keys are derived from the seed, used as a BIP 32 master key, as follows:
+///  * Your node_id is seed/0'
+///  * ChannelMonitor closes may use seed/1' (ie `get_destination_script`)
+///  * Cooperative closes may use seed/2' (ie `get_shutdown_pubkey`)
+///  * The keys of the channel with index i are, at seed/3'/i':
+///    - 0': the funding key
+///    - 1': the revocation base key
+///    - 2': the payment key
+///    - 3': the delayed payment base key
+///    - 4': the HTLC base key
+///    - 5': the commitment seed (as the raw private key bytes)
+///  * Random bytes are derived from seed/4' and the starting time, as in [`KeysManager`].
+///
+/// Channel indexes are allocated in order and the key derivation parameters of a channel are
+/// `(channel index, 0)`.
+///
+/// [`KeysManager`]: struct.KeysManager.html
+/// [`SpendableOutputDescriptor`]: enum.SpendableOutputDescriptor.html
+pub struct Bip32KeysManager {
+	secp_ctx: Secp256k1<secp256k1::SignOnly>,
+	node_secret: SecretKey,
+	destination_key: SecretKey,
+	destination_script: Script,
+	shutdown_key: SecretKey,
+	shutdown_pubkey: PublicKey,
+	channel_master_key: ExtendedPrivKey,
+	channel_child_index: AtomicUsize,
+	rand_bytes_master_key: ExtendedPrivKey,
+	rand_bytes_child_index: AtomicUsize,
+
+	seed: [u8; 32],
+	starting_time_secs: u64,
+	starting_time_nanos: u32,
+}
+
+impl Bip32KeysManager {
+	/// Constructs a Bip32KeysManager from a 32-byte seed. If the seed is in some way biased (eg
+	/// your CSRNG is busted) this may panic (but more importantly, you will possibly lose funds).
+	///
+	/// `next_channel_index` is the index of the keys handed to the next channel. As channel keys
+	/// MUST never be reused, it must be greater than the index of any channel ever opened with
+	/// this seed, even if you restarted with some stale data. It should thus be persisted (see
+	/// [`next_channel_index`]) before any new channel's data is, or recovered with
+	/// [`find_channel_keys`] after a data loss, with enough margin to skip channels opened
+	/// since the last backup.
+	///
+	/// starting_time is only used to generate random bytes and must be unique to each run, as
+	/// described at [`KeysManager::new`].
+	///
+	/// [`next_channel_index`]: #method.next_channel_index
+	/// [`find_channel_keys`]: #method.find_channel_keys
+	/// [`KeysManager::new`]: struct.KeysManager.html#method.new
+	pub fn new(seed: &[u8; 32], network: Network, next_channel_index: u32, starting_time_secs: u64, starting_time_nanos: u32) -> Self {
+		let secp_ctx = Secp256k1::signing_only();
+		let master_key = ExtendedPrivKey::new_master(network, seed).expect("Your RNG is busted");
+		let derive = |idx: u32| master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(idx).unwrap()).expect("Your RNG is busted");
+		let node_secret = derive(0).private_key.key;
+		let destination_key = derive(1).private_key.key;
+		let wpubkey_hash = WPubkeyHash::hash(&PublicKey::from_secret_key(&secp_ctx, &destination_key).serialize());
+		let destination_script = Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0)
+		                                       .push_slice(&wpubkey_hash.into_inner())
+		                                       .into_script();
+		let shutdown_key = derive(2).private_key.key;
+		let shutdown_pubkey = PublicKey::from_secret_key(&secp_ctx, &shutdown_key);
+		let channel_master_key = derive(3);
+		let rand_bytes_master_key = derive(4);
+
+		Bip32KeysManager {
+			secp_ctx,
+			node_secret,
+			destination_key,
+			destination_script,
+			shutdown_key,
+			shutdown_pubkey,
+			channel_master_key,
+			channel_child_index: AtomicUsize::new(next_channel_index as usize),
+			rand_bytes_master_key,
+			rand_bytes_child_index: AtomicUsize::new(0),
+
+			seed: *seed,
+			starting_time_secs,
+			starting_time_nanos,
+		}
+	}
+
+	/// Gets the index of the keys which will be handed to the next channel, which should be
+	/// persisted and given back to [`new`] on restart.
+	///
+	/// [`new`]: #method.new
+	pub fn next_channel_index(&self) -> u32 {
+		self.channel_child_index.load(Ordering::Acquire) as u32
+	}
+
+	/// Derives the ChannelKeys of the channel with the given index, as handed out by
+	/// get_channel_keys.
+	pub fn derive_channel_keys(&self, channel_value_satoshis: u64, channel_index: u32) -> InMemoryChannelKeys {
+		let channel_key = self.channel_master_key.ckd_priv(&self.secp_ctx, ChildNumber::from_hardened_idx(channel_index).expect("key space exhausted")).expect("Your RNG is busted");
+		let derive = |idx: u32| channel_key.ckd_priv(&self.secp_ctx, ChildNumber::from_hardened_idx(idx).unwrap()).expect("Your RNG is busted").private_key.key;
+		let mut commitment_seed = [0; 32];
+		commitment_seed.copy_from_slice(&derive(5)[..]);
+
+		InMemoryChannelKeys::new(
+			&self.secp_ctx,
+			derive(0),
+			derive(1),
+			derive(2),
+			derive(3),
+			derive(4),
+			commitment_seed,
+			channel_value_satoshis,
+			(channel_index as u64, 0),
+		)
+	}
+
+	/// Finds the ChannelKeys of a channel given its funding output, by deriving the keys of channel
+	/// indexes below `channel_index_limit` until the funding script built from one's funding key
+	/// and the counterparty's matches the output's script_pubkey.
+	///
+	/// The funding output is the output at the channel's funding outpoint (eg as stored in a
+	/// [`ChannelBackup`]), which can be looked up in the funding transaction, or with
+	/// [`chain::Access::get_utxo`] if the channel was announced. The counterparty's funding pubkey
+	/// appears in the channel's announcement or, once the funding output is spent, in the funding
+	/// redeemScript revealed by the spending transaction.
+	///
+	/// Returns None if no channel index below the limit matches.
+	///
+	/// [`ChannelBackup`]: ../../ln/channel_backup/struct.ChannelBackup.html
+	/// [`chain::Access::get_utxo`]: ../trait.Access.html#tymethod.get_utxo
+	pub fn find_channel_keys(&self, funding_output: &TxOut, counterparty_funding_pubkey: &PublicKey, channel_index_limit: u32) -> Option<InMemoryChannelKeys> {
+		for channel_index in 0..channel_index_limit {
+			let keys = self.derive_channel_keys(funding_output.value, channel_index);
+			if make_funding_redeemscript(&keys.pubkeys().funding_pubkey, counterparty_funding_pubkey).to_v0_p2wsh() == funding_output.script_pubkey {
+				return Some(keys);
+			}
+		}
+		None
+	}
+}
+
+/// As all keys are derived from the seed, this works for outputs of any channel opened with it,
+/// even if no other data about the channel survived.
+impl OutputSpender for Bip32KeysManager {
+	fn spend_spendable_outputs<C: Signing>(&self, descriptors: &[SpendableOutputDescriptor], outputs: Vec<TxOut>, change_destination_script: Script, feerate_sat_per_1000_weight: u32, secp_ctx: &Secp256k1<C>) -> Result<Transaction, ()> {
+		for descriptor in descriptors.iter() {
+			match descriptor {
+				&SpendableOutputDescriptor::DynamicOutputP2WSH { ref key_derivation_params, .. } |
+				&SpendableOutputDescriptor::StaticOutputCounterpartyPayment { ref key_derivation_params, .. } => {
+					if key_derivation_params.0 > u32::max_value() as u64 || key_derivation_params.1 != 0 { return Err(()); }
+				},
+				_ => {},
+			}
+		}
+		let static_output_keys = [(&self.destination_key, self.destination_script.clone()), (&self.shutdown_key, p2wpkh_script(&self.shutdown_pubkey))];
+		spend_outputs(descriptors, outputs, change_destination_script, feerate_sat_per_1000_weight, &static_output_keys,
+			|channel_index, _| self.derive_channel_keys(0, channel_index as u32), secp_ctx)
+	}
+}
+
+impl KeysInterface for Bip32KeysManager {
+	type ChanKeySigner = InMemoryChannelKeys;
+
+	fn get_node_secret(&self) -> SecretKey {
+		self.node_secret.clone()
+	}
+
+	fn get_destination_script(&self) -> Script {
+		self.destination_script.clone()
+	}
+
+	fn get_shutdown_pubkey(&self) -> PublicKey {
+		self.shutdown_pubkey.clone()
+	}
+
+	fn get_channel_keys(&self, _inbound: bool, channel_value_satoshis: u64) -> Self::ChanKeySigner {
+		let channel_index = self.channel_child_index.fetch_add(1, Ordering::AcqRel);
+		self.derive_channel_keys(channel_value_satoshis, channel_index as u32)
+	}
+
+	fn get_secure_random_bytes(&self) -> [u8; 32] {
+		let mut sha = Sha256::engine();
+		sha.input(&byte_utils::be64_to_array(self.starting_time_secs));
+		sha.input(&byte_utils::be32_to_array(self.starting_time_nanos));
+		sha.input(&self.seed);
+
+		let child_ix = self.rand_bytes_child_index.fetch_add(1, Ordering::AcqRel);
+		let child_privkey = self.rand_bytes_master_key.ckd_priv(&self.secp_ctx, ChildNumber::from_hardened_idx(child_ix as u32).expect("key space exhausted")).expect("Your RNG is busted");
+		sha.input(&child_privkey.private_key.key[..]);
+
+		sha.input(b"Unique Secure Random Bytes Salt");
+		Sha256::from_engine(sha).into_inner()
+	}
+}
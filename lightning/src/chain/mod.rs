@@ -22,6 +22,10 @@ pub mod chainmonitor;
 pub mod channelmonitor;
 pub mod transaction;
 pub mod keysinterface;
+pub mod watchtower;
+pub mod remotesigner;
+pub mod validatingkeys;
+pub mod sweeper;
 
 /// An error when accessing the chain via [`Access`].
 ///
@@ -33,17 +37,28 @@ pub enum AccessError {
 
 	/// The requested transaction doesn't exist or hasn't confirmed.
 	UnknownTx,
+
+	/// The requested transaction output is being looked up asynchronously, e.g. over RPC, and its
+	/// result will be provided later with [`NetGraphMsgHandler::utxo_lookup_completed`].
+	///
+	/// [`NetGraphMsgHandler::utxo_lookup_completed`]: ../routing/network_graph/struct.NetGraphMsgHandler.html#method.utxo_lookup_completed
+	Pending,
 }
 
 /// The `Access` trait defines behavior for accessing chain data and state, such as blocks and
 /// UTXOs.
 pub trait Access: Send + Sync {
-	/// Returns the transaction output of a funding transaction encoded by [`short_channel_id`].
+	/// Returns the transaction output of a funding transaction encoded by [`short_channel_id`],
+	/// along with its outpoint, which is registered with a [`Filter`] to notice when the channel
+	/// is closed.
 	/// Returns an error if `genesis_hash` is for a different chain or if such a transaction output
-	/// is unknown.
+	/// is unknown, or [`AccessError::Pending`] if the lookup would block and its result will be
+	/// provided later instead.
 	///
 	/// [`short_channel_id`]: https://github.com/lightningnetwork/lightning-rfc/blob/master/07-routing-gossip.md#definition-of-short_channel_id
-	fn get_utxo(&self, genesis_hash: &BlockHash, short_channel_id: u64) -> Result<TxOut, AccessError>;
+	/// [`Filter`]: trait.Filter.html
+	/// [`AccessError::Pending`]: enum.AccessError.html#variant.Pending
+	fn get_utxo(&self, genesis_hash: &BlockHash, short_channel_id: u64) -> Result<(OutPoint, TxOut), AccessError>;
 }
 
 /// The `Watch` trait defines behavior for watching on-chain activity pertaining to channels as
@@ -0,0 +1,1088 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Keeping channel keys in a separate process (or on a separate host) from the node using them.
+//!
+//! Every [`ChannelKeys`] method has a corresponding [`SignerRequest`], answered by the signer with
+//! a [`SignerResponse`]. Both are serializable, so they can be sent over any byte stream.
+//!
+//! On the node side, [`RemoteChannelKeys`] implements `ChannelKeys` by forwarding each call over
+//! a [`SignerTransport`]. On the signing side, [`RemoteSigner`] wraps an [`InMemoryChannelKeys`]
+//! and answers requests on its behalf.
+//!
+//! Requests can be served in-process (`RemoteSigner` implements `SignerTransport` itself) or over
+//! any byte stream, such as a TCP connection or a unix socket, using [`StreamSignerTransport`] on
+//! the node side and [`RemoteSigner::handle_stream`] on the signing side.
+//!
+//! Note that the signer still has to trust the node to some extent: see the [`ChannelKeys`]
+//! documentation for the checks a signer should (but `RemoteSigner` currently doesn't) do.
+//!
+//! [`ChannelKeys`]: ../keysinterface/trait.ChannelKeys.html
+//! [`SignerRequest`]: enum.SignerRequest.html
+//! [`SignerResponse`]: enum.SignerResponse.html
+//! [`RemoteChannelKeys`]: struct.RemoteChannelKeys.html
+//! [`SignerTransport`]: trait.SignerTransport.html
+//! [`RemoteSigner`]: struct.RemoteSigner.html
+//! [`InMemoryChannelKeys`]: ../keysinterface/struct.InMemoryChannelKeys.html
+//! [`StreamSignerTransport`]: struct.StreamSignerTransport.html
+//! [`RemoteSigner::handle_stream`]: struct.RemoteSigner.html#method.handle_stream
+
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+use bitcoin::secp256k1::{Secp256k1, Signature};
+use bitcoin::secp256k1;
+
+use chain::keysinterface::{ChannelKeys, InMemoryChannelKeys};
+use ln::chan_utils::{ChannelPublicKeys, HolderCommitmentTransaction, HTLCOutputInCommitment, PreCalculatedTxCreationKeys, TxCreationKeys};
+use ln::msgs::{DecodeError, UnsignedChannelAnnouncement};
+use util::ser::{Readable, ReadableArgs, Writeable, Writer};
+
+use std::cmp;
+use std::io::{Cursor, Read, Write};
+use std::mem;
+use std::ops::Deref;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+const MAX_ALLOC_SIZE: usize = 64*1024;
+
+fn write_vec<W: Writer, T: Writeable>(writer: &mut W, vec: &[T]) -> Result<(), ::std::io::Error> {
+	(vec.len() as u64).write(writer)?;
+	for elem in vec.iter() {
+		elem.write(writer)?;
+	}
+	Ok(())
+}
+
+fn read_vec<R: Read, T: Readable>(reader: &mut R) -> Result<Vec<T>, DecodeError> {
+	let len: u64 = Readable::read(reader)?;
+	let mut vec = Vec::with_capacity(cmp::min(len as usize, MAX_ALLOC_SIZE / mem::size_of::<T>()));
+	for _ in 0..len {
+		vec.push(Readable::read(reader)?);
+	}
+	Ok(vec)
+}
+
+/// An error returned by a [`SignerTransport`] when a request could not be delivered to the
+/// signer or its response could not be read back. Contains a developer-readable error message.
+///
+/// [`SignerTransport`]: trait.SignerTransport.html
+#[derive(Clone, Debug)]
+pub struct SignerError(pub &'static str);
+
+/// A request to a remote signer, one for each [`ChannelKeys`] method. Fields match the
+/// parameters of the corresponding method, except for the secp256k1 context which the signer
+/// provides itself.
+///
+/// [`ChannelKeys`]: ../keysinterface/trait.ChannelKeys.html
+#[derive(Clone)]
+pub enum SignerRequest {
+	/// See [`ChannelKeys::get_per_commitment_point`].
+	///
+	/// [`ChannelKeys::get_per_commitment_point`]: ../keysinterface/trait.ChannelKeys.html#tymethod.get_per_commitment_point
+	GetPerCommitmentPoint {
+		/// The commitment number
+		idx: u64,
+	},
+	/// See [`ChannelKeys::release_commitment_secret`].
+	///
+	/// [`ChannelKeys::release_commitment_secret`]: ../keysinterface/trait.ChannelKeys.html#tymethod.release_commitment_secret
+	ReleaseCommitmentSecret {
+		/// The commitment number
+		idx: u64,
+	},
+	/// See [`ChannelKeys::pubkeys`].
+	///
+	/// [`ChannelKeys::pubkeys`]: ../keysinterface/trait.ChannelKeys.html#tymethod.pubkeys
+	GetPubKeys,
+	/// See [`ChannelKeys::key_derivation_params`].
+	///
+	/// [`ChannelKeys::key_derivation_params`]: ../keysinterface/trait.ChannelKeys.html#tymethod.key_derivation_params
+	GetKeyDerivationParams,
+	/// See [`ChannelKeys::sign_counterparty_commitment`].
+	///
+	/// [`ChannelKeys::sign_counterparty_commitment`]: ../keysinterface/trait.ChannelKeys.html#tymethod.sign_counterparty_commitment
+	SignCounterpartyCommitment {
+		/// The feerate of the commitment transaction
+		feerate_per_kw: u32,
+		/// The counterparty's commitment transaction
+		commitment_tx: Transaction,
+		/// The pre-calculated keys of the commitment transaction
+		keys: TxCreationKeys,
+		/// The HTLCs in the commitment transaction
+		htlcs: Vec<HTLCOutputInCommitment>,
+	},
+	/// See [`ChannelKeys::sign_holder_commitment`].
+	///
+	/// [`ChannelKeys::sign_holder_commitment`]: ../keysinterface/trait.ChannelKeys.html#tymethod.sign_holder_commitment
+	SignHolderCommitment {
+		/// Our commitment transaction
+		holder_commitment_tx: HolderCommitmentTransaction,
+	},
+	/// See [`ChannelKeys::unsafe_sign_holder_commitment`]. Only served by signers built with the
+	/// `unsafe_revoked_tx_signing` feature.
+	///
+	/// [`ChannelKeys::unsafe_sign_holder_commitment`]: ../keysinterface/trait.ChannelKeys.html#tymethod.unsafe_sign_holder_commitment
+	UnsafeSignHolderCommitment {
+		/// Our (possibly revoked) commitment transaction
+		holder_commitment_tx: HolderCommitmentTransaction,
+	},
+	/// See [`ChannelKeys::sign_holder_commitment_htlc_transactions`].
+	///
+	/// [`ChannelKeys::sign_holder_commitment_htlc_transactions`]: ../keysinterface/trait.ChannelKeys.html#tymethod.sign_holder_commitment_htlc_transactions
+	SignHolderCommitmentHTLCTransactions {
+		/// Our commitment transaction
+		holder_commitment_tx: HolderCommitmentTransaction,
+	},
+	/// See [`ChannelKeys::sign_justice_transaction`].
+	///
+	/// [`ChannelKeys::sign_justice_transaction`]: ../keysinterface/trait.ChannelKeys.html#tymethod.sign_justice_transaction
+	SignJusticeTransaction {
+		/// The justice transaction
+		justice_tx: Transaction,
+		/// The index of the input to sign
+		input: u64,
+		/// The value of the output spent by the input
+		amount: u64,
+		/// The revoked commitment transaction's per-commitment secret
+		per_commitment_key: SecretKey,
+		/// The HTLC spent by the input, if any
+		htlc: Option<HTLCOutputInCommitment>,
+	},
+	/// See [`ChannelKeys::sign_justice_transactions`].
+	///
+	/// [`ChannelKeys::sign_justice_transactions`]: ../keysinterface/trait.ChannelKeys.html#tymethod.sign_justice_transactions
+	SignJusticeTransactions {
+		/// The justice transactions
+		justice_txn: Vec<Transaction>,
+		/// The value and HTLC, if any, of the output spent by each input
+		inputs: Vec<(u64, Option<HTLCOutputInCommitment>)>,
+		/// The revoked commitment transaction's per-commitment secret
+		per_commitment_key: SecretKey,
+	},
+	/// See [`ChannelKeys::sign_counterparty_htlc_transaction`].
+	///
+	/// [`ChannelKeys::sign_counterparty_htlc_transaction`]: ../keysinterface/trait.ChannelKeys.html#tymethod.sign_counterparty_htlc_transaction
+	SignCounterpartyHTLCTransaction {
+		/// The HTLC claiming transaction
+		htlc_tx: Transaction,
+		/// The index of the input to sign
+		input: u64,
+		/// The value of the output spent by the input
+		amount: u64,
+		/// The per-commitment point of the counterparty's commitment transaction
+		per_commitment_point: PublicKey,
+		/// The HTLC spent by the input
+		htlc: HTLCOutputInCommitment,
+	},
+	/// See [`ChannelKeys::sign_delayed_payment_input`].
+	///
+	/// [`ChannelKeys::sign_delayed_payment_input`]: ../keysinterface/trait.ChannelKeys.html#tymethod.sign_delayed_payment_input
+	SignDelayedPaymentInput {
+		/// The spending transaction
+		spend_tx: Transaction,
+		/// The index of the input to sign
+		input: u64,
+		/// The value of the output spent by the input
+		amount: u64,
+		/// The per-commitment point of the transaction the output belongs to
+		per_commitment_point: PublicKey,
+		/// The revocation pubkey of the output
+		revocation_pubkey: PublicKey,
+		/// The relative timelock of the output
+		to_self_delay: u16,
+	},
+	/// See [`ChannelKeys::sign_closing_transaction`].
+	///
+	/// [`ChannelKeys::sign_closing_transaction`]: ../keysinterface/trait.ChannelKeys.html#tymethod.sign_closing_transaction
+	SignClosingTransaction {
+		/// The closing transaction
+		closing_tx: Transaction,
+	},
+	/// See [`ChannelKeys::sign_channel_announcement`].
+	///
+	/// [`ChannelKeys::sign_channel_announcement`]: ../keysinterface/trait.ChannelKeys.html#tymethod.sign_channel_announcement
+	SignChannelAnnouncement {
+		/// The channel announcement
+		msg: UnsignedChannelAnnouncement,
+	},
+	/// See [`ChannelKeys::on_accept`].
+	///
+	/// [`ChannelKeys::on_accept`]: ../keysinterface/trait.ChannelKeys.html#tymethod.on_accept
+	OnAccept {
+		/// The counterparty's public keys
+		channel_pubkeys: ChannelPublicKeys,
+		/// The contest delay selected by our counterparty
+		counterparty_selected_contest_delay: u16,
+		/// The contest delay selected by us
+		holder_selected_contest_delay: u16,
+	},
+}
+
+impl Writeable for SignerRequest {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		match self {
+			&SignerRequest::GetPerCommitmentPoint { ref idx } => {
+				0u8.write(writer)?;
+				idx.write(writer)?;
+			},
+			&SignerRequest::ReleaseCommitmentSecret { ref idx } => {
+				1u8.write(writer)?;
+				idx.write(writer)?;
+			},
+			&SignerRequest::GetPubKeys => {
+				2u8.write(writer)?;
+			},
+			&SignerRequest::GetKeyDerivationParams => {
+				3u8.write(writer)?;
+			},
+			&SignerRequest::SignCounterpartyCommitment { ref feerate_per_kw, ref commitment_tx, ref keys, ref htlcs } => {
+				4u8.write(writer)?;
+				feerate_per_kw.write(writer)?;
+				commitment_tx.write(writer)?;
+				keys.write(writer)?;
+				write_vec(writer, htlcs)?;
+			},
+			&SignerRequest::SignHolderCommitment { ref holder_commitment_tx } => {
+				5u8.write(writer)?;
+				holder_commitment_tx.write(writer)?;
+			},
+			&SignerRequest::UnsafeSignHolderCommitment { ref holder_commitment_tx } => {
+				6u8.write(writer)?;
+				holder_commitment_tx.write(writer)?;
+			},
+			&SignerRequest::SignHolderCommitmentHTLCTransactions { ref holder_commitment_tx } => {
+				7u8.write(writer)?;
+				holder_commitment_tx.write(writer)?;
+			},
+			&SignerRequest::SignJusticeTransaction { ref justice_tx, ref input, ref amount, ref per_commitment_key, ref htlc } => {
+				8u8.write(writer)?;
+				justice_tx.write(writer)?;
+				input.write(writer)?;
+				amount.write(writer)?;
+				per_commitment_key.write(writer)?;
+				htlc.write(writer)?;
+			},
+			&SignerRequest::SignJusticeTransactions { ref justice_txn, ref inputs, ref per_commitment_key } => {
+				9u8.write(writer)?;
+				write_vec(writer, justice_txn)?;
+				write_vec(writer, inputs)?;
+				per_commitment_key.write(writer)?;
+			},
+			&SignerRequest::SignCounterpartyHTLCTransaction { ref htlc_tx, ref input, ref amount, ref per_commitment_point, ref htlc } => {
+				10u8.write(writer)?;
+				htlc_tx.write(writer)?;
+				input.write(writer)?;
+				amount.write(writer)?;
+				per_commitment_point.write(writer)?;
+				htlc.write(writer)?;
+			},
+			&SignerRequest::SignDelayedPaymentInput { ref spend_tx, ref input, ref amount, ref per_commitment_point, ref revocation_pubkey, ref to_self_delay } => {
+				11u8.write(writer)?;
+				spend_tx.write(writer)?;
+				input.write(writer)?;
+				amount.write(writer)?;
+				per_commitment_point.write(writer)?;
+				revocation_pubkey.write(writer)?;
+				to_self_delay.write(writer)?;
+			},
+			&SignerRequest::SignClosingTransaction { ref closing_tx } => {
+				12u8.write(writer)?;
+				closing_tx.write(writer)?;
+			},
+			&SignerRequest::SignChannelAnnouncement { ref msg } => {
+				13u8.write(writer)?;
+				// The announcement is read up to the end of its reader (to account for excess data),
+				// so it needs to be length-prefixed here.
+				msg.encode().write(writer)?;
+			},
+			&SignerRequest::OnAccept { ref channel_pubkeys, ref counterparty_selected_contest_delay, ref holder_selected_contest_delay } => {
+				14u8.write(writer)?;
+				channel_pubkeys.write(writer)?;
+				counterparty_selected_contest_delay.write(writer)?;
+				holder_selected_contest_delay.write(writer)?;
+			},
+		}
+		Ok(())
+	}
+}
+
+impl Readable for SignerRequest {
+	fn read<R: Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		Ok(match <u8 as Readable>::read(reader)? {
+			0 => SignerRequest::GetPerCommitmentPoint {
+				idx: Readable::read(reader)?,
+			},
+			1 => SignerRequest::ReleaseCommitmentSecret {
+				idx: Readable::read(reader)?,
+			},
+			2 => SignerRequest::GetPubKeys,
+			3 => SignerRequest::GetKeyDerivationParams,
+			4 => SignerRequest::SignCounterpartyCommitment {
+				feerate_per_kw: Readable::read(reader)?,
+				commitment_tx: Readable::read(reader)?,
+				keys: Readable::read(reader)?,
+				htlcs: read_vec(reader)?,
+			},
+			5 => SignerRequest::SignHolderCommitment {
+				holder_commitment_tx: Readable::read(reader)?,
+			},
+			6 => SignerRequest::UnsafeSignHolderCommitment {
+				holder_commitment_tx: Readable::read(reader)?,
+			},
+			7 => SignerRequest::SignHolderCommitmentHTLCTransactions {
+				holder_commitment_tx: Readable::read(reader)?,
+			},
+			8 => SignerRequest::SignJusticeTransaction {
+				justice_tx: Readable::read(reader)?,
+				input: Readable::read(reader)?,
+				amount: Readable::read(reader)?,
+				per_commitment_key: Readable::read(reader)?,
+				htlc: Readable::read(reader)?,
+			},
+			9 => SignerRequest::SignJusticeTransactions {
+				justice_txn: read_vec(reader)?,
+				inputs: read_vec(reader)?,
+				per_commitment_key: Readable::read(reader)?,
+			},
+			10 => SignerRequest::SignCounterpartyHTLCTransaction {
+				htlc_tx: Readable::read(reader)?,
+				input: Readable::read(reader)?,
+				amount: Readable::read(reader)?,
+				per_commitment_point: Readable::read(reader)?,
+				htlc: Readable::read(reader)?,
+			},
+			11 => SignerRequest::SignDelayedPaymentInput {
+				spend_tx: Readable::read(reader)?,
+				input: Readable::read(reader)?,
+				amount: Readable::read(reader)?,
+				per_commitment_point: Readable::read(reader)?,
+				revocation_pubkey: Readable::read(reader)?,
+				to_self_delay: Readable::read(reader)?,
+			},
+			12 => SignerRequest::SignClosingTransaction {
+				closing_tx: Readable::read(reader)?,
+			},
+			13 => {
+				let msg_bytes: Vec<u8> = Readable::read(reader)?;
+				SignerRequest::SignChannelAnnouncement {
+					msg: Readable::read(&mut Cursor::new(&msg_bytes))?,
+				}
+			},
+			14 => SignerRequest::OnAccept {
+				channel_pubkeys: Readable::read(reader)?,
+				counterparty_selected_contest_delay: Readable::read(reader)?,
+				holder_selected_contest_delay: Readable::read(reader)?,
+			},
+			_ => return Err(DecodeError::InvalidValue),
+		})
+	}
+}
+
+/// A response from a remote signer to a [`SignerRequest`].
+///
+/// [`SignerRequest`]: enum.SignerRequest.html
+#[derive(Clone)]
+pub enum SignerResponse {
+	/// A per-commitment point, in response to `GetPerCommitmentPoint`
+	PerCommitmentPoint(PublicKey),
+	/// A commitment secret, in response to `ReleaseCommitmentSecret`
+	CommitmentSecret([u8; 32]),
+	/// The channel public keys, in response to `GetPubKeys`
+	PubKeys(ChannelPublicKeys),
+	/// The key derivation parameters, in response to `GetKeyDerivationParams`
+	KeyDerivationParams(u64, u64),
+	/// The commitment transaction signature and HTLC transaction signatures, in response to
+	/// `SignCounterpartyCommitment`
+	CounterpartyCommitmentSignatures(Signature, Vec<Signature>),
+	/// A single signature, in response to any other request for a single signature
+	Signature(Signature),
+	/// The HTLC transaction signatures, in response to `SignHolderCommitmentHTLCTransactions`
+	HolderHTLCSignatures(Vec<Option<Signature>>),
+	/// The signatures of each input of each justice transaction, in response to
+	/// `SignJusticeTransactions`
+	JusticeSignatures(Vec<Vec<Signature>>),
+	/// An acknowledgement of an `OnAccept` request
+	Accepted,
+	/// The signer refused or failed to handle the request
+	Rejected,
+}
+
+impl Writeable for SignerResponse {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		match self {
+			&SignerResponse::PerCommitmentPoint(ref point) => {
+				0u8.write(writer)?;
+				point.write(writer)?;
+			},
+			&SignerResponse::CommitmentSecret(ref secret) => {
+				1u8.write(writer)?;
+				secret.write(writer)?;
+			},
+			&SignerResponse::PubKeys(ref pubkeys) => {
+				2u8.write(writer)?;
+				pubkeys.write(writer)?;
+			},
+			&SignerResponse::KeyDerivationParams(ref params_1, ref params_2) => {
+				3u8.write(writer)?;
+				params_1.write(writer)?;
+				params_2.write(writer)?;
+			},
+			&SignerResponse::CounterpartyCommitmentSignatures(ref sig, ref htlc_sigs) => {
+				4u8.write(writer)?;
+				sig.write(writer)?;
+				write_vec(writer, htlc_sigs)?;
+			},
+			&SignerResponse::Signature(ref sig) => {
+				5u8.write(writer)?;
+				sig.write(writer)?;
+			},
+			&SignerResponse::HolderHTLCSignatures(ref htlc_sigs) => {
+				6u8.write(writer)?;
+				write_vec(writer, htlc_sigs)?;
+			},
+			&SignerResponse::JusticeSignatures(ref sigs) => {
+				7u8.write(writer)?;
+				(sigs.len() as u64).write(writer)?;
+				for tx_sigs in sigs.iter() {
+					write_vec(writer, tx_sigs)?;
+				}
+			},
+			&SignerResponse::Accepted => {
+				8u8.write(writer)?;
+			},
+			&SignerResponse::Rejected => {
+				9u8.write(writer)?;
+			},
+		}
+		Ok(())
+	}
+}
+
+impl Readable for SignerResponse {
+	fn read<R: Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		Ok(match <u8 as Readable>::read(reader)? {
+			0 => SignerResponse::PerCommitmentPoint(Readable::read(reader)?),
+			1 => SignerResponse::CommitmentSecret(Readable::read(reader)?),
+			2 => SignerResponse::PubKeys(Readable::read(reader)?),
+			3 => SignerResponse::KeyDerivationParams(Readable::read(reader)?, Readable::read(reader)?),
+			4 => SignerResponse::CounterpartyCommitmentSignatures(Readable::read(reader)?, read_vec(reader)?),
+			5 => SignerResponse::Signature(Readable::read(reader)?),
+			6 => SignerResponse::HolderHTLCSignatures(read_vec(reader)?),
+			7 => {
+				let len: u64 = Readable::read(reader)?;
+				let mut sigs = Vec::with_capacity(cmp::min(len as usize, MAX_ALLOC_SIZE / mem::size_of::<Vec<Signature>>()));
+				for _ in 0..len {
+					sigs.push(read_vec(reader)?);
+				}
+				SignerResponse::JusticeSignatures(sigs)
+			},
+			8 => SignerResponse::Accepted,
+			9 => SignerResponse::Rejected,
+			_ => return Err(DecodeError::InvalidValue),
+		})
+	}
+}
+
+/// A channel to a remote signer, over which requests are sent.
+pub trait SignerTransport: Send + Sync {
+	/// Sends a request to the signer, returning its response.
+	///
+	/// A request which failed may be sent again (see [`RemoteChannelKeys`]), thus a failure must
+	/// not leave a stale response behind for the next request to read (eg by reconnecting).
+	///
+	/// [`RemoteChannelKeys`]: struct.RemoteChannelKeys.html
+	fn send_request(&self, request: &SignerRequest) -> Result<SignerResponse, SignerError>;
+}
+
+/// The number of times [`RemoteChannelKeys`] sends a request which can't fail before giving up
+/// on a signer which can't be reached.
+///
+/// [`RemoteChannelKeys`]: struct.RemoteChannelKeys.html
+pub const MAX_REQUEST_ATTEMPTS: u32 = 5;
+/// The time [`RemoteChannelKeys`] waits before retrying a request which can't fail, doubled after
+/// each failed attempt.
+///
+/// [`RemoteChannelKeys`]: struct.RemoteChannelKeys.html
+pub const INITIAL_RETRY_BACKOFF_MS: u64 = 100;
+
+/// A [`ChannelKeys`] implementation which forwards every call to a remote signer over a
+/// [`SignerTransport`].
+///
+/// The channel's public keys and key derivation parameters are fetched once at creation and
+/// cached, as [`ChannelKeys::pubkeys`] returns a reference to them. They are also all this
+/// object serializes, so it can be read back (with [`ReadableArgs`], given a transport to the
+/// same signer) without exposing any secret.
+///
+/// Signing methods fail if the signer can't be reached or refuses to sign. However, as
+/// `get_per_commitment_point`, `release_commitment_secret` and `on_accept` cannot fail, their
+/// requests are retried up to [`MAX_REQUEST_ATTEMPTS`] times if the transport fails, backing off
+/// exponentially from [`INITIAL_RETRY_BACKOFF_MS`] (blocking the calling thread meanwhile). They
+/// panic if the signer still can't be reached, or if it refuses the request.
+///
+/// [`ChannelKeys`]: ../keysinterface/trait.ChannelKeys.html
+/// [`SignerTransport`]: trait.SignerTransport.html
+/// [`ChannelKeys::pubkeys`]: ../keysinterface/trait.ChannelKeys.html#tymethod.pubkeys
+/// [`ReadableArgs`]: ../../util/ser/trait.ReadableArgs.html
+/// [`MAX_REQUEST_ATTEMPTS`]: constant.MAX_REQUEST_ATTEMPTS.html
+/// [`INITIAL_RETRY_BACKOFF_MS`]: constant.INITIAL_RETRY_BACKOFF_MS.html
+#[derive(Clone)]
+pub struct RemoteChannelKeys<T: Deref + Clone + Send> where T::Target: SignerTransport {
+	transport: T,
+	pubkeys: ChannelPublicKeys,
+	key_derivation_params: (u64, u64),
+}
+
+impl<T: Deref + Clone + Send> RemoteChannelKeys<T> where T::Target: SignerTransport {
+	/// Creates a new `RemoteChannelKeys` for the channel whose keys are held by the signer at the
+	/// other end of the given transport, fetching its public keys.
+	pub fn new(transport: T) -> Result<Self, SignerError> {
+		let pubkeys = match transport.send_request(&SignerRequest::GetPubKeys)? {
+			SignerResponse::PubKeys(pubkeys) => pubkeys,
+			_ => return Err(SignerError("Unexpected response to public keys request")),
+		};
+		let key_derivation_params = match transport.send_request(&SignerRequest::GetKeyDerivationParams)? {
+			SignerResponse::KeyDerivationParams(params_1, params_2) => (params_1, params_2),
+			_ => return Err(SignerError("Unexpected response to key derivation parameters request")),
+		};
+		Ok(RemoteChannelKeys { transport, pubkeys, key_derivation_params })
+	}
+
+	fn send_request(&self, request: &SignerRequest) -> Option<SignerResponse> {
+		self.transport.send_request(request).ok()
+	}
+
+	/// Sends a request for a ChannelKeys method which can't fail, retrying with exponential
+	/// backoff while the transport fails.
+	fn send_request_with_retries(&self, request: &SignerRequest) -> Result<SignerResponse, SignerError> {
+		let mut backoff_ms = INITIAL_RETRY_BACKOFF_MS;
+		let mut attempts = 1;
+		loop {
+			match self.transport.send_request(request) {
+				Ok(response) => return Ok(response),
+				Err(e) => {
+					if attempts >= MAX_REQUEST_ATTEMPTS { return Err(e); }
+					thread::sleep(Duration::from_millis(backoff_ms));
+					backoff_ms *= 2;
+					attempts += 1;
+				},
+			}
+		}
+	}
+
+	fn sign(&self, request: &SignerRequest) -> Result<Signature, ()> {
+		match self.send_request(request) {
+			Some(SignerResponse::Signature(sig)) => Ok(sig),
+			_ => Err(()),
+		}
+	}
+}
+
+impl<T: Deref + Clone + Send> ChannelKeys for RemoteChannelKeys<T> where T::Target: SignerTransport {
+	fn get_per_commitment_point<C: secp256k1::Signing + secp256k1::Verification>(&self, idx: u64, _secp_ctx: &Secp256k1<C>) -> PublicKey {
+		match self.send_request_with_retries(&SignerRequest::GetPerCommitmentPoint { idx }) {
+			Ok(SignerResponse::PerCommitmentPoint(point)) => point,
+			Ok(_) => panic!("Remote signer refused to provide a per-commitment point"),
+			Err(SignerError(e)) => panic!("Failed to get per-commitment point from the remote signer after {} attempts: {}", MAX_REQUEST_ATTEMPTS, e),
+		}
+	}
+
+	fn release_commitment_secret(&self, idx: u64) -> [u8; 32] {
+		match self.send_request_with_retries(&SignerRequest::ReleaseCommitmentSecret { idx }) {
+			Ok(SignerResponse::CommitmentSecret(secret)) => secret,
+			Ok(_) => panic!("Remote signer refused to release a commitment secret"),
+			Err(SignerError(e)) => panic!("Failed to get commitment secret from the remote signer after {} attempts: {}", MAX_REQUEST_ATTEMPTS, e),
+		}
+	}
+
+	fn pubkeys(&self) -> &ChannelPublicKeys { &self.pubkeys }
+	fn key_derivation_params(&self) -> (u64, u64) { self.key_derivation_params }
+
+	fn sign_counterparty_commitment<C: secp256k1::Signing + secp256k1::Verification>(&self, feerate_per_kw: u32, commitment_tx: &Transaction, keys: &PreCalculatedTxCreationKeys, htlcs: &[&HTLCOutputInCommitment], _secp_ctx: &Secp256k1<C>) -> Result<(Signature, Vec<Signature>), ()> {
+		let request = SignerRequest::SignCounterpartyCommitment {
+			feerate_per_kw,
+			commitment_tx: commitment_tx.clone(),
+			keys: keys.trust_key_derivation().clone(),
+			htlcs: htlcs.iter().map(|htlc| (*htlc).clone()).collect(),
+		};
+		match self.send_request(&request) {
+			Some(SignerResponse::CounterpartyCommitmentSignatures(sig, htlc_sigs)) => Ok((sig, htlc_sigs)),
+			_ => Err(()),
+		}
+	}
+
+	fn sign_holder_commitment<C: secp256k1::Signing + secp256k1::Verification>(&self, holder_commitment_tx: &HolderCommitmentTransaction, _secp_ctx: &Secp256k1<C>) -> Result<Signature, ()> {
+		self.sign(&SignerRequest::SignHolderCommitment { holder_commitment_tx: holder_commitment_tx.clone() })
+	}
+
+	#[cfg(any(test,feature = "unsafe_revoked_tx_signing"))]
+	fn unsafe_sign_holder_commitment<C: secp256k1::Signing + secp256k1::Verification>(&self, holder_commitment_tx: &HolderCommitmentTransaction, _secp_ctx: &Secp256k1<C>) -> Result<Signature, ()> {
+		self.sign(&SignerRequest::UnsafeSignHolderCommitment { holder_commitment_tx: holder_commitment_tx.clone() })
+	}
+
+	fn sign_holder_commitment_htlc_transactions<C: secp256k1::Signing + secp256k1::Verification>(&self, holder_commitment_tx: &HolderCommitmentTransaction, _secp_ctx: &Secp256k1<C>) -> Result<Vec<Option<Signature>>, ()> {
+		match self.send_request(&SignerRequest::SignHolderCommitmentHTLCTransactions { holder_commitment_tx: holder_commitment_tx.clone() }) {
+			Some(SignerResponse::HolderHTLCSignatures(htlc_sigs)) => Ok(htlc_sigs),
+			_ => Err(()),
+		}
+	}
+
+	fn sign_justice_transaction<C: secp256k1::Signing + secp256k1::Verification>(&self, justice_tx: &Transaction, input: usize, amount: u64, per_commitment_key: &SecretKey, htlc: &Option<HTLCOutputInCommitment>, _secp_ctx: &Secp256k1<C>) -> Result<Signature, ()> {
+		self.sign(&SignerRequest::SignJusticeTransaction {
+			justice_tx: justice_tx.clone(),
+			input: input as u64,
+			amount,
+			per_commitment_key: per_commitment_key.clone(),
+			htlc: htlc.clone(),
+		})
+	}
+
+	fn sign_justice_transactions<C: secp256k1::Signing + secp256k1::Verification>(&self, justice_txn: &[Transaction], inputs: &[(u64, Option<HTLCOutputInCommitment>)], per_commitment_key: &SecretKey, _secp_ctx: &Secp256k1<C>) -> Result<Vec<Vec<Signature>>, ()> {
+		let request = SignerRequest::SignJusticeTransactions {
+			justice_txn: justice_txn.to_vec(),
+			inputs: inputs.to_vec(),
+			per_commitment_key: per_commitment_key.clone(),
+		};
+		match self.send_request(&request) {
+			Some(SignerResponse::JusticeSignatures(sigs)) => Ok(sigs),
+			_ => Err(()),
+		}
+	}
+
+	fn sign_counterparty_htlc_transaction<C: secp256k1::Signing + secp256k1::Verification>(&self, htlc_tx: &Transaction, input: usize, amount: u64, per_commitment_point: &PublicKey, htlc: &HTLCOutputInCommitment, _secp_ctx: &Secp256k1<C>) -> Result<Signature, ()> {
+		self.sign(&SignerRequest::SignCounterpartyHTLCTransaction {
+			htlc_tx: htlc_tx.clone(),
+			input: input as u64,
+			amount,
+			per_commitment_point: per_commitment_point.clone(),
+			htlc: htlc.clone(),
+		})
+	}
+
+	fn sign_delayed_payment_input<C: secp256k1::Signing + secp256k1::Verification>(&self, spend_tx: &Transaction, input: usize, amount: u64, per_commitment_point: &PublicKey, revocation_pubkey: &PublicKey, to_self_delay: u16, _secp_ctx: &Secp256k1<C>) -> Result<Signature, ()> {
+		self.sign(&SignerRequest::SignDelayedPaymentInput {
+			spend_tx: spend_tx.clone(),
+			input: input as u64,
+			amount,
+			per_commitment_point: per_commitment_point.clone(),
+			revocation_pubkey: revocation_pubkey.clone(),
+			to_self_delay,
+		})
+	}
+
+	fn sign_closing_transaction<C: secp256k1::Signing>(&self, closing_tx: &Transaction, _secp_ctx: &Secp256k1<C>) -> Result<Signature, ()> {
+		self.sign(&SignerRequest::SignClosingTransaction { closing_tx: closing_tx.clone() })
+	}
+
+	fn sign_channel_announcement<C: secp256k1::Signing>(&self, msg: &UnsignedChannelAnnouncement, _secp_ctx: &Secp256k1<C>) -> Result<Signature, ()> {
+		self.sign(&SignerRequest::SignChannelAnnouncement { msg: msg.clone() })
+	}
+
+	fn on_accept(&mut self, channel_pubkeys: &ChannelPublicKeys, counterparty_selected_contest_delay: u16, holder_selected_contest_delay: u16) {
+		let request = SignerRequest::OnAccept {
+			channel_pubkeys: channel_pubkeys.clone(),
+			counterparty_selected_contest_delay,
+			holder_selected_contest_delay,
+		};
+		match self.send_request_with_retries(&request) {
+			Ok(SignerResponse::Accepted) => {},
+			Ok(_) => panic!("Remote signer refused to accept the channel"),
+			Err(SignerError(e)) => panic!("Failed to have the remote signer accept the channel after {} attempts: {}", MAX_REQUEST_ATTEMPTS, e),
+		}
+	}
+}
+
+impl<T: Deref + Clone + Send> Writeable for RemoteChannelKeys<T> where T::Target: SignerTransport {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		self.pubkeys.write(writer)?;
+		self.key_derivation_params.0.write(writer)?;
+		self.key_derivation_params.1.write(writer)?;
+		Ok(())
+	}
+}
+
+impl<T: Deref + Clone + Send> ReadableArgs<T> for RemoteChannelKeys<T> where T::Target: SignerTransport {
+	fn read<R: Read>(reader: &mut R, transport: T) -> Result<Self, DecodeError> {
+		let pubkeys = Readable::read(reader)?;
+		let params_1 = Readable::read(reader)?;
+		let params_2 = Readable::read(reader)?;
+		Ok(RemoteChannelKeys {
+			transport,
+			pubkeys,
+			key_derivation_params: (params_1, params_2),
+		})
+	}
+}
+
+/// The signing side of [`RemoteChannelKeys`], answering requests using the [`InMemoryChannelKeys`]
+/// of a channel.
+///
+/// Signing requests are rejected until the channel was accepted with an `OnAccept` request, and
+/// further `OnAccept` requests are rejected. The up-to-date keys, which have to be persisted once
+/// the channel was accepted, can be fetched with [`get_keys`].
+///
+/// [`RemoteChannelKeys`]: struct.RemoteChannelKeys.html
+/// [`InMemoryChannelKeys`]: ../keysinterface/struct.InMemoryChannelKeys.html
+/// [`get_keys`]: #method.get_keys
+pub struct RemoteSigner {
+	keys: Mutex<InMemoryChannelKeys>,
+	secp_ctx: Secp256k1<secp256k1::All>,
+}
+
+impl RemoteSigner {
+	/// Creates a new `RemoteSigner` holding the given channel keys.
+	pub fn new(keys: InMemoryChannelKeys) -> Self {
+		RemoteSigner {
+			keys: Mutex::new(keys),
+			secp_ctx: Secp256k1::new(),
+		}
+	}
+
+	/// Gets a copy of the channel keys held by this signer.
+	pub fn get_keys(&self) -> InMemoryChannelKeys {
+		self.keys.lock().unwrap().clone()
+	}
+
+	/// Handles a single request.
+	pub fn handle_request(&self, request: &SignerRequest) -> SignerResponse {
+		fn signature_response(res: Result<Signature, ()>) -> SignerResponse {
+			match res {
+				Ok(sig) => SignerResponse::Signature(sig),
+				Err(()) => SignerResponse::Rejected,
+			}
+		}
+
+		let mut keys = self.keys.lock().unwrap();
+		match request {
+			&SignerRequest::GetPerCommitmentPoint { idx } => SignerResponse::PerCommitmentPoint(keys.get_per_commitment_point(idx, &self.secp_ctx)),
+			&SignerRequest::ReleaseCommitmentSecret { idx } => SignerResponse::CommitmentSecret(keys.release_commitment_secret(idx)),
+			&SignerRequest::GetPubKeys => SignerResponse::PubKeys(keys.pubkeys().clone()),
+			&SignerRequest::GetKeyDerivationParams => {
+				let (params_1, params_2) = keys.key_derivation_params();
+				SignerResponse::KeyDerivationParams(params_1, params_2)
+			},
+			&SignerRequest::OnAccept { ref channel_pubkeys, counterparty_selected_contest_delay, holder_selected_contest_delay } => {
+				if keys.is_accepted() {
+					SignerResponse::Rejected
+				} else {
+					keys.on_accept(channel_pubkeys, counterparty_selected_contest_delay, holder_selected_contest_delay);
+					SignerResponse::Accepted
+				}
+			},
+			_ if !keys.is_accepted() => SignerResponse::Rejected,
+			&SignerRequest::SignCounterpartyCommitment { feerate_per_kw, ref commitment_tx, keys: ref tx_keys, ref htlcs } => {
+				let htlcs: Vec<&HTLCOutputInCommitment> = htlcs.iter().collect();
+				match keys.sign_counterparty_commitment(feerate_per_kw, commitment_tx, &PreCalculatedTxCreationKeys::new(tx_keys.clone()), &htlcs, &self.secp_ctx) {
+					Ok((sig, htlc_sigs)) => SignerResponse::CounterpartyCommitmentSignatures(sig, htlc_sigs),
+					Err(()) => SignerResponse::Rejected,
+				}
+			},
+			&SignerRequest::SignHolderCommitment { ref holder_commitment_tx } => {
+				signature_response(keys.sign_holder_commitment(holder_commitment_tx, &self.secp_ctx))
+			},
+			&SignerRequest::UnsafeSignHolderCommitment { ref holder_commitment_tx } => {
+				signature_response(self.unsafe_sign_holder_commitment(&keys, holder_commitment_tx))
+			},
+			&SignerRequest::SignHolderCommitmentHTLCTransactions { ref holder_commitment_tx } => {
+				match keys.sign_holder_commitment_htlc_transactions(holder_commitment_tx, &self.secp_ctx) {
+					Ok(htlc_sigs) => SignerResponse::HolderHTLCSignatures(htlc_sigs),
+					Err(()) => SignerResponse::Rejected,
+				}
+			},
+			&SignerRequest::SignJusticeTransaction { ref justice_tx, input, amount, ref per_commitment_key, ref htlc } => {
+				if input as usize >= justice_tx.input.len() { return SignerResponse::Rejected; }
+				signature_response(keys.sign_justice_transaction(justice_tx, input as usize, amount, per_commitment_key, htlc, &self.secp_ctx))
+			},
+			&SignerRequest::SignJusticeTransactions { ref justice_txn, ref inputs, ref per_commitment_key } => {
+				if justice_txn.iter().any(|tx| tx.input.len() != inputs.len()) { return SignerResponse::Rejected; }
+				match keys.sign_justice_transactions(justice_txn, inputs, per_commitment_key, &self.secp_ctx) {
+					Ok(sigs) => SignerResponse::JusticeSignatures(sigs),
+					Err(()) => SignerResponse::Rejected,
+				}
+			},
+			&SignerRequest::SignCounterpartyHTLCTransaction { ref htlc_tx, input, amount, ref per_commitment_point, ref htlc } => {
+				if input as usize >= htlc_tx.input.len() { return SignerResponse::Rejected; }
+				signature_response(keys.sign_counterparty_htlc_transaction(htlc_tx, input as usize, amount, per_commitment_point, htlc, &self.secp_ctx))
+			},
+			&SignerRequest::SignDelayedPaymentInput { ref spend_tx, input, amount, ref per_commitment_point, ref revocation_pubkey, to_self_delay } => {
+				if input as usize >= spend_tx.input.len() { return SignerResponse::Rejected; }
+				signature_response(keys.sign_delayed_payment_input(spend_tx, input as usize, amount, per_commitment_point, revocation_pubkey, to_self_delay, &self.secp_ctx))
+			},
+			&SignerRequest::SignClosingTransaction { ref closing_tx } => {
+				signature_response(keys.sign_closing_transaction(closing_tx, &self.secp_ctx))
+			},
+			&SignerRequest::SignChannelAnnouncement { ref msg } => {
+				signature_response(keys.sign_channel_announcement(msg, &self.secp_ctx))
+			},
+		}
+	}
+
+	#[cfg(any(test,feature = "unsafe_revoked_tx_signing"))]
+	fn unsafe_sign_holder_commitment(&self, keys: &InMemoryChannelKeys, holder_commitment_tx: &HolderCommitmentTransaction) -> Result<Signature, ()> {
+		keys.unsafe_sign_holder_commitment(holder_commitment_tx, &self.secp_ctx)
+	}
+
+	#[cfg(not(any(test,feature = "unsafe_revoked_tx_signing")))]
+	fn unsafe_sign_holder_commitment(&self, _keys: &InMemoryChannelKeys, _holder_commitment_tx: &HolderCommitmentTransaction) -> Result<Signature, ()> {
+		Err(())
+	}
+
+	/// Serves requests read from the given stream, writing back the response to each, until the
+	/// stream is closed.
+	///
+	/// Each request is expected to be serialized as with [`SignerRequest::write`]. This is the
+	/// signer side of [`StreamSignerTransport`], and may be run on a `TcpStream` accepted from a
+	/// `TcpListener`.
+	///
+	/// [`SignerRequest::write`]: enum.SignerRequest.html#method.write
+	/// [`StreamSignerTransport`]: struct.StreamSignerTransport.html
+	pub fn handle_stream<S: Read + Write>(&self, stream: &mut S) -> Result<(), DecodeError> {
+		loop {
+			let request: SignerRequest = match Readable::read(stream) {
+				Ok(request) => request,
+				Err(DecodeError::ShortRead) => return Ok(()),
+				Err(e) => return Err(e),
+			};
+			self.handle_request(&request).write(stream)?;
+			stream.flush()?;
+		}
+	}
+}
+
+impl SignerTransport for RemoteSigner {
+	fn send_request(&self, request: &SignerRequest) -> Result<SignerResponse, SignerError> {
+		Ok(self.handle_request(request))
+	}
+}
+
+/// A [`SignerTransport`] which writes requests to a byte stream (eg a `TcpStream` connected to a
+/// signer) and reads back the signer's responses. The other end of the stream should be served by
+/// [`RemoteSigner::handle_stream`].
+///
+/// [`SignerTransport`]: trait.SignerTransport.html
+/// [`RemoteSigner::handle_stream`]: struct.RemoteSigner.html#method.handle_stream
+pub struct StreamSignerTransport<S: Read + Write + Send> {
+	stream: Mutex<S>,
+}
+
+impl<S: Read + Write + Send> StreamSignerTransport<S> {
+	/// Creates a new `StreamSignerTransport` over the given (connected) stream.
+	pub fn new(stream: S) -> Self {
+		StreamSignerTransport { stream: Mutex::new(stream) }
+	}
+}
+
+impl<S: Read + Write + Send> SignerTransport for StreamSignerTransport<S> {
+	fn send_request(&self, request: &SignerRequest) -> Result<SignerResponse, SignerError> {
+		let mut stream = self.stream.lock().unwrap();
+		if request.write(&mut *stream).is_err() || stream.flush().is_err() {
+			return Err(SignerError("Failed to write request to the stream"));
+		}
+		match Readable::read(&mut *stream) {
+			Ok(response) => Ok(response),
+			Err(_) => Err(SignerError("Failed to read response from the stream")),
+		}
+	}
+}
+
+// Tests run over a unix socketpair
+#[cfg(all(test, unix))]
+mod tests {
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::blockdata::script::Script;
+	use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut, OutPoint as BitcoinOutPoint};
+	use bitcoin::network::constants::Network;
+	use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+	use bitcoin::secp256k1::Secp256k1;
+
+	use chain::keysinterface::{ChannelKeys, InMemoryChannelKeys};
+	use chain::remotesigner::{MAX_REQUEST_ATTEMPTS, RemoteChannelKeys, RemoteSigner, SignerError, SignerRequest, SignerResponse, SignerTransport, StreamSignerTransport};
+	use ln::chan_utils::{ChannelPublicKeys, HolderCommitmentTransaction, HTLCOutputInCommitment, PreCalculatedTxCreationKeys, TxCreationKeys};
+	use ln::channelmanager::PaymentHash;
+	use ln::features::ChannelFeatures;
+	use ln::msgs::UnsignedChannelAnnouncement;
+	use util::ser::{ReadableArgs, Writeable};
+
+	use std::io::Cursor;
+	use std::os::unix::net::UnixStream;
+	use std::sync::{Arc, Mutex};
+	use std::thread;
+
+	/// A transport to an in-process signer which fails the given number of requests first.
+	struct FlakyTransport {
+		signer: RemoteSigner,
+		failures_left: Mutex<u32>,
+	}
+	impl SignerTransport for FlakyTransport {
+		fn send_request(&self, request: &SignerRequest) -> Result<SignerResponse, SignerError> {
+			let mut failures_left = self.failures_left.lock().unwrap();
+			if *failures_left > 0 {
+				*failures_left -= 1;
+				return Err(SignerError("Connection reset"));
+			}
+			self.signer.send_request(request)
+		}
+	}
+
+	fn flaky_remote_keys(failures: u32) -> (InMemoryChannelKeys, RemoteChannelKeys<Arc<FlakyTransport>>, Arc<FlakyTransport>) {
+		let secp_ctx = Secp256k1::new();
+		let key = |byte: u8| SecretKey::from_slice(&[byte; 32]).unwrap();
+		let local_keys = InMemoryChannelKeys::new(&secp_ctx, key(1), key(2), key(3), key(4), key(5), [6; 32], 1_000_000, (42, 43));
+		let transport = Arc::new(FlakyTransport { signer: RemoteSigner::new(local_keys.clone()), failures_left: Mutex::new(0) });
+		let remote_keys = RemoteChannelKeys::new(Arc::clone(&transport)).unwrap();
+		*transport.failures_left.lock().unwrap() = failures;
+		(local_keys, remote_keys, transport)
+	}
+
+	#[test]
+	fn remote_signer_retries_infallible_requests() {
+		// Methods which can't fail are retried while the transport fails, while signing methods
+		// fail right away.
+		let secp_ctx = Secp256k1::new();
+		let (local_keys, remote_keys, transport) = flaky_remote_keys(MAX_REQUEST_ATTEMPTS - 1);
+		assert_eq!(remote_keys.get_per_commitment_point(1, &secp_ctx), local_keys.get_per_commitment_point(1, &secp_ctx));
+		assert_eq!(*transport.failures_left.lock().unwrap(), 0);
+
+		*transport.failures_left.lock().unwrap() = 1;
+		assert!(remote_keys.sign_closing_transaction(&dummy_tx(990_000), &secp_ctx).is_err());
+		assert_eq!(remote_keys.release_commitment_secret(1), local_keys.release_commitment_secret(1));
+	}
+
+	#[test]
+	#[should_panic(expected = "Failed to get commitment secret from the remote signer after 5 attempts: Connection reset")]
+	fn remote_signer_unreachable() {
+		let (_, remote_keys, _) = flaky_remote_keys(MAX_REQUEST_ATTEMPTS);
+		remote_keys.release_commitment_secret(1);
+	}
+
+	fn dummy_tx(value: u64) -> Transaction {
+		Transaction {
+			version: 2,
+			lock_time: 0,
+			input: vec![TxIn {
+				previous_output: BitcoinOutPoint { txid: Default::default(), vout: 0 },
+				script_sig: Script::new(),
+				sequence: 0xfffffffd,
+				witness: Vec::new(),
+			}],
+			output: vec![TxOut { script_pubkey: Script::new(), value }],
+		}
+	}
+
+	#[test]
+	fn remote_signer_over_socketpair() {
+		// Checks that every ChannelKeys method called on a RemoteChannelKeys, through a signer
+		// served at the other end of a socket, gives the same result as the InMemoryChannelKeys
+		// held by the signer.
+		let secp_ctx = Secp256k1::new();
+		let key = |byte: u8| SecretKey::from_slice(&[byte; 32]).unwrap();
+		let mut local_keys = InMemoryChannelKeys::new(&secp_ctx, key(1), key(2), key(3), key(4), key(5), [6; 32], 1_000_000, (42, 43));
+		let signer = Arc::new(RemoteSigner::new(local_keys.clone()));
+
+		let (client_stream, mut server_stream) = UnixStream::pair().unwrap();
+		let server_signer = Arc::clone(&signer);
+		let server = thread::spawn(move || {
+			server_signer.handle_stream(&mut server_stream).unwrap();
+		});
+
+		let counterparty_pubkeys = ChannelPublicKeys {
+			funding_pubkey: PublicKey::from_secret_key(&secp_ctx, &key(11)),
+			revocation_basepoint: PublicKey::from_secret_key(&secp_ctx, &key(12)),
+			payment_point: PublicKey::from_secret_key(&secp_ctx, &key(13)),
+			delayed_payment_basepoint: PublicKey::from_secret_key(&secp_ctx, &key(14)),
+			htlc_basepoint: PublicKey::from_secret_key(&secp_ctx, &key(15)),
+		};
+		let per_commitment_point = local_keys.get_per_commitment_point(1, &secp_ctx);
+		let htlc = HTLCOutputInCommitment {
+			offered: true,
+			amount_msat: 100_000_000,
+			cltv_expiry: 500,
+			payment_hash: PaymentHash([7; 32]),
+			transaction_output_index: Some(0),
+		};
+		{
+			let transport = Arc::new(StreamSignerTransport::new(client_stream));
+			let mut remote_keys = RemoteChannelKeys::new(Arc::clone(&transport)).unwrap();
+			assert!(remote_keys.pubkeys() == local_keys.pubkeys());
+			assert_eq!(remote_keys.key_derivation_params(), (42, 43));
+			assert_eq!(remote_keys.get_per_commitment_point(1, &secp_ctx), per_commitment_point);
+			assert_eq!(remote_keys.release_commitment_secret(1), local_keys.release_commitment_secret(1));
+
+			// Nothing is signed before the channel is accepted
+			let closing_tx = dummy_tx(990_000);
+			assert!(remote_keys.sign_closing_transaction(&closing_tx, &secp_ctx).is_err());
+			remote_keys.on_accept(&counterparty_pubkeys, 144, 145);
+			local_keys.on_accept(&counterparty_pubkeys, 144, 145);
+			match transport.send_request(&SignerRequest::OnAccept { channel_pubkeys: counterparty_pubkeys.clone(), counterparty_selected_contest_delay: 144, holder_selected_contest_delay: 145 }) {
+				Ok(SignerResponse::Rejected) => {},
+				_ => panic!("Channel accepted twice"),
+			}
+			assert_eq!(remote_keys.sign_closing_transaction(&closing_tx, &secp_ctx), local_keys.sign_closing_transaction(&closing_tx, &secp_ctx));
+
+			let tx_keys = PreCalculatedTxCreationKeys::new(TxCreationKeys {
+				per_commitment_point,
+				revocation_key: PublicKey::from_secret_key(&secp_ctx, &key(21)),
+				broadcaster_htlc_key: PublicKey::from_secret_key(&secp_ctx, &key(22)),
+				countersignatory_htlc_key: PublicKey::from_secret_key(&secp_ctx, &key(23)),
+				broadcaster_delayed_payment_key: PublicKey::from_secret_key(&secp_ctx, &key(24)),
+			});
+			let commitment_tx = dummy_tx(999_000);
+			let remote_sigs = remote_keys.sign_counterparty_commitment(253, &commitment_tx, &tx_keys, &[&htlc], &secp_ctx).unwrap();
+			let local_sigs = local_keys.sign_counterparty_commitment(253, &commitment_tx, &tx_keys, &[&htlc], &secp_ctx).unwrap();
+			assert_eq!(remote_sigs.1.len(), 1);
+			assert_eq!(remote_sigs, local_sigs);
+
+			let holder_commitment_tx = HolderCommitmentTransaction::dummy();
+			assert_eq!(remote_keys.sign_holder_commitment(&holder_commitment_tx, &secp_ctx), local_keys.sign_holder_commitment(&holder_commitment_tx, &secp_ctx));
+			assert_eq!(remote_keys.unsafe_sign_holder_commitment(&holder_commitment_tx, &secp_ctx), local_keys.unsafe_sign_holder_commitment(&holder_commitment_tx, &secp_ctx));
+			assert_eq!(remote_keys.sign_holder_commitment_htlc_transactions(&holder_commitment_tx, &secp_ctx), local_keys.sign_holder_commitment_htlc_transactions(&holder_commitment_tx, &secp_ctx));
+
+			let justice_tx = dummy_tx(90_000);
+			let per_commitment_key = key(31);
+			assert_eq!(remote_keys.sign_justice_transaction(&justice_tx, 0, 100_000, &per_commitment_key, &Some(htlc.clone()), &secp_ctx),
+				local_keys.sign_justice_transaction(&justice_tx, 0, 100_000, &per_commitment_key, &Some(htlc.clone()), &secp_ctx));
+			// Out-of-bounds inputs are rejected rather than crashing the signer
+			assert!(remote_keys.sign_justice_transaction(&justice_tx, 1, 100_000, &per_commitment_key, &None, &secp_ctx).is_err());
+			let justice_txn = [justice_tx.clone(), dummy_tx(80_000)];
+			let remote_justice_sigs = remote_keys.sign_justice_transactions(&justice_txn, &[(100_000, None)], &per_commitment_key, &secp_ctx).unwrap();
+			assert_eq!(remote_justice_sigs.len(), 2);
+			assert_eq!(remote_justice_sigs, local_keys.sign_justice_transactions(&justice_txn, &[(100_000, None)], &per_commitment_key, &secp_ctx).unwrap());
+
+			let htlc_tx = dummy_tx(95_000);
+			assert_eq!(remote_keys.sign_counterparty_htlc_transaction(&htlc_tx, 0, 100_000, &per_commitment_point, &htlc, &secp_ctx),
+				local_keys.sign_counterparty_htlc_transaction(&htlc_tx, 0, 100_000, &per_commitment_point, &htlc, &secp_ctx));
+			let revocation_pubkey = PublicKey::from_secret_key(&secp_ctx, &key(32));
+			assert_eq!(remote_keys.sign_delayed_payment_input(&htlc_tx, 0, 100_000, &per_commitment_point, &revocation_pubkey, 144, &secp_ctx),
+				local_keys.sign_delayed_payment_input(&htlc_tx, 0, 100_000, &per_commitment_point, &revocation_pubkey, 144, &secp_ctx));
+
+			let announcement = UnsignedChannelAnnouncement {
+				features: ChannelFeatures::known(),
+				chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+				short_channel_id: 42,
+				node_id_1: PublicKey::from_secret_key(&secp_ctx, &key(41)),
+				node_id_2: PublicKey::from_secret_key(&secp_ctx, &key(42)),
+				bitcoin_key_1: remote_keys.pubkeys().funding_pubkey,
+				bitcoin_key_2: counterparty_pubkeys.funding_pubkey,
+				excess_data: Vec::new(),
+			};
+			assert_eq!(remote_keys.sign_channel_announcement(&announcement, &secp_ctx), local_keys.sign_channel_announcement(&announcement, &secp_ctx));
+
+			// Only public data is serialized, which is enough to talk to the same signer again
+			let serialized = remote_keys.encode();
+			let read_keys: RemoteChannelKeys<Arc<StreamSignerTransport<UnixStream>>> = ReadableArgs::read(&mut Cursor::new(&serialized), Arc::clone(&transport)).unwrap();
+			assert!(read_keys.pubkeys() == local_keys.pubkeys());
+			assert_eq!(read_keys.key_derivation_params(), (42, 43));
+			assert_eq!(read_keys.sign_closing_transaction(&closing_tx, &secp_ctx), local_keys.sign_closing_transaction(&closing_tx, &secp_ctx));
+		}
+		// Closing the client's end of the socket stops the signer
+		server.join().unwrap();
+		assert_eq!(signer.get_keys().counterparty_pubkeys().funding_pubkey, counterparty_pubkeys.funding_pubkey);
+	}
+}
@@ -0,0 +1,359 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Sweeping of the on-chain outputs handed to us in [`Event::SpendableOutputs`] back to our
+//! wallet.
+//!
+//! [`OutputSweeper`] keeps track of the [`SpendableOutputDescriptor`]s it is given, spends them
+//! to a change script with [`OutputSpender::spend_spendable_outputs`] as soon as they are
+//! spendable, and keeps rebroadcasting (and regularly fee-bumping) the sweep transaction until
+//! it is confirmed. It implements [`Writeable`] and should be persisted every time it is handed
+//! new outputs or a block is connected or disconnected, so that outputs are not forgotten across
+//! restarts.
+//!
+//! [`Event::SpendableOutputs`]: ../../util/events/enum.Event.html#variant.SpendableOutputs
+//! [`OutputSweeper`]: struct.OutputSweeper.html
+//! [`SpendableOutputDescriptor`]: ../keysinterface/enum.SpendableOutputDescriptor.html
+//! [`OutputSpender::spend_spendable_outputs`]: ../keysinterface/trait.OutputSpender.html#tymethod.spend_spendable_outputs
+//! [`Writeable`]: ../../util/ser/trait.Writeable.html
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::secp256k1;
+
+use chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator, MIN_RELAY_FEE_SAT_PER_1000_WEIGHT};
+use chain::channelmonitor::ANTI_REORG_DELAY;
+use chain::keysinterface::{OutputSpender, SpendableOutputDescriptor};
+use chain::transaction::{OutPoint, TransactionData};
+use ln::msgs::DecodeError;
+use util::logger::Logger;
+use util::ser::{Readable, ReadableArgs, Writeable, Writer};
+
+use std::cmp;
+use std::io::Read;
+use std::ops::Deref;
+use std::sync::Mutex;
+
+/// The number of blocks a sweep transaction may stay unconfirmed before we fee-bump it. Sweeps
+/// aren't time-sensitive, so we give each feerate a few blocks to succeed.
+pub const SWEEP_BUMP_INTERVAL: u32 = 6;
+
+struct TrackedOutput {
+	descriptor: SpendableOutputDescriptor,
+	// The height of the first block in which the output may be spent, accounting for its
+	// to_self_delay if it is a DynamicOutputP2WSH.
+	spendable_height: u32,
+	// The height at which a transaction spending the output was confirmed, if any.
+	spend_confirmation_height: Option<u32>,
+}
+
+impl TrackedOutput {
+	fn outpoint(&self) -> &OutPoint {
+		match self.descriptor {
+			SpendableOutputDescriptor::StaticOutput { ref outpoint, .. } => outpoint,
+			SpendableOutputDescriptor::DynamicOutputP2WSH { ref outpoint, .. } => outpoint,
+			SpendableOutputDescriptor::StaticOutputCounterpartyPayment { ref outpoint, .. } => outpoint,
+		}
+	}
+}
+
+struct SweeperState {
+	outputs: Vec<TrackedOutput>,
+	best_height: u32,
+	// The latest sweep transaction we broadcast, with its feerate and the height at which it was
+	// built, so that we know when to fee-bump it.
+	latest_sweep: Option<(Transaction, u32, u32)>,
+}
+
+/// Tracks outputs from [`Event::SpendableOutputs`] and sweeps them to a change script, fee-bumping
+/// the sweep every [`SWEEP_BUMP_INTERVAL`] blocks until it confirms. Outputs are forgotten once
+/// a transaction spending them reaches 6 confirmations.
+///
+/// The sweeper must be told about every block through [`block_connected`] and
+/// [`block_disconnected`], and should be persisted (through its [`Writeable`] implementation)
+/// after each such call as well as after each call to [`track_spendable_outputs`]. It may be
+/// read back with [`OutputSweeperReadArgs`].
+///
+/// [`Event::SpendableOutputs`]: ../../util/events/enum.Event.html#variant.SpendableOutputs
+/// [`SWEEP_BUMP_INTERVAL`]: constant.SWEEP_BUMP_INTERVAL.html
+/// [`block_connected`]: #method.block_connected
+/// [`block_disconnected`]: #method.block_disconnected
+/// [`Writeable`]: ../../util/ser/trait.Writeable.html
+/// [`track_spendable_outputs`]: #method.track_spendable_outputs
+/// [`OutputSweeperReadArgs`]: struct.OutputSweeperReadArgs.html
+pub struct OutputSweeper<B: Deref, F: Deref, K: Deref, L: Deref>
+	where B::Target: BroadcasterInterface,
+	      F::Target: FeeEstimator,
+	      K::Target: OutputSpender,
+	      L::Target: Logger,
+{
+	broadcaster: B,
+	fee_estimator: F,
+	keys_manager: K,
+	logger: L,
+	change_destination_script: Script,
+	state: Mutex<SweeperState>,
+	secp_ctx: Secp256k1<secp256k1::SignOnly>,
+}
+
+impl<B: Deref, F: Deref, K: Deref, L: Deref> OutputSweeper<B, F, K, L>
+	where B::Target: BroadcasterInterface,
+	      F::Target: FeeEstimator,
+	      K::Target: OutputSpender,
+	      L::Target: Logger,
+{
+	/// Creates a new OutputSweeper, sweeping outputs to the given change_destination_script.
+	/// current_height must be the height of the current best block.
+	pub fn new(broadcaster: B, fee_estimator: F, keys_manager: K, logger: L, change_destination_script: Script, current_height: u32) -> Self {
+		OutputSweeper {
+			broadcaster,
+			fee_estimator,
+			keys_manager,
+			logger,
+			change_destination_script,
+			state: Mutex::new(SweeperState {
+				outputs: Vec::new(),
+				best_height: current_height,
+				latest_sweep: None,
+			}),
+			secp_ctx: Secp256k1::signing_only(),
+		}
+	}
+
+	/// Starts tracking the given outputs, as provided by an [`Event::SpendableOutputs`], and
+	/// broadcasts a transaction sweeping every output which is already spendable. Outputs we
+	/// already track are ignored.
+	///
+	/// Outputs the OutputSpender is unable to sign for (eg a StaticOutput paying to a script it did
+	/// not provide) are not tracked, as they would prevent every other output from being swept.
+	/// They are returned instead, and have to be spent by other means.
+	///
+	/// Should be called as soon as the event is received: the to_self_delay of DynamicOutputP2WSH
+	/// descriptors is counted from the current best block, minus the 6 confirmations a
+	/// ChannelMonitor waits for before handing the output out.
+	///
+	/// [`Event::SpendableOutputs`]: ../../util/events/enum.Event.html#variant.SpendableOutputs
+	pub fn track_spendable_outputs(&self, descriptors: Vec<SpendableOutputDescriptor>) -> Vec<SpendableOutputDescriptor> {
+		let mut state = self.state.lock().unwrap();
+		let mut unspendable_descriptors = Vec::new();
+		for descriptor in descriptors {
+			if !self.can_sign(&descriptor) {
+				log_error!(self.logger, "Unable to sign for spendable output {}, not sweeping it", log_spendable!(descriptor));
+				unspendable_descriptors.push(descriptor);
+				continue;
+			}
+			let spendable_height = match descriptor {
+				SpendableOutputDescriptor::DynamicOutputP2WSH { to_self_delay, .. } =>
+					cmp::max(state.best_height + 1, (state.best_height + 1 + to_self_delay as u32).saturating_sub(ANTI_REORG_DELAY)),
+				_ => state.best_height + 1,
+			};
+			let output = TrackedOutput { descriptor, spendable_height, spend_confirmation_height: None };
+			if state.outputs.iter().any(|tracked| tracked.outpoint() == output.outpoint()) { continue; }
+			log_trace!(self.logger, "Tracking spendable output {}:{}, spendable from height {}", output.outpoint().txid, output.outpoint().index, spendable_height);
+			state.outputs.push(output);
+		}
+		self.sweep(&mut state);
+		unspendable_descriptors
+	}
+
+	/// Checks that the OutputSpender is able to sign for the given descriptor by having it spend
+	/// the output, alone and without fee, to a zero-value output (so that it doesn't matter
+	/// whether the output's value covers a fee or a change output).
+	fn can_sign(&self, descriptor: &SpendableOutputDescriptor) -> bool {
+		let outputs = vec![TxOut { script_pubkey: self.change_destination_script.clone(), value: 0 }];
+		self.keys_manager.spend_spendable_outputs(&[descriptor.clone()], outputs, self.change_destination_script.clone(), 0, &self.secp_ctx).is_ok()
+	}
+
+	/// Returns the descriptors of the outputs not yet swept with enough confirmations.
+	pub fn get_tracked_outputs(&self) -> Vec<SpendableOutputDescriptor> {
+		self.state.lock().unwrap().outputs.iter().map(|tracked| tracked.descriptor.clone()).collect()
+	}
+
+	/// Processes transactions in a newly connected block: outputs spent in it are marked as
+	/// such, and forgotten once the spend is 6 blocks deep. The sweep of the remaining outputs is
+	/// then rebroadcast, fee-bumped if it has been pending for [`SWEEP_BUMP_INTERVAL`] blocks.
+	///
+	/// [`SWEEP_BUMP_INTERVAL`]: constant.SWEEP_BUMP_INTERVAL.html
+	pub fn block_connected(&self, _header: &BlockHeader, txdata: &TransactionData, height: u32) {
+		let mut state = self.state.lock().unwrap();
+		state.best_height = height;
+		for &(_, tx) in txdata.iter() {
+			for input in tx.input.iter() {
+				for tracked in state.outputs.iter_mut() {
+					if tracked.spend_confirmation_height.is_none() && tracked.outpoint().into_bitcoin_outpoint() == input.previous_output {
+						log_trace!(self.logger, "Output {} spent by transaction {} at height {}", input.previous_output, tx.txid(), height);
+						tracked.spend_confirmation_height = Some(height);
+					}
+				}
+			}
+		}
+		let logger = &self.logger;
+		state.outputs.retain(|tracked| {
+			if let Some(conf_height) = tracked.spend_confirmation_height {
+				if conf_height + ANTI_REORG_DELAY - 1 <= height {
+					log_trace!(logger, "Output {}:{} swept", tracked.outpoint().txid, tracked.outpoint().index);
+					return false;
+				}
+			}
+			true
+		});
+		self.sweep(&mut state);
+	}
+
+	/// Unmarks outputs whose spend was confirmed in the block at disconnected_height. They will
+	/// be swept again on the next block if the spend is not confirmed anew.
+	pub fn block_disconnected(&self, _header: &BlockHeader, disconnected_height: u32) {
+		let mut state = self.state.lock().unwrap();
+		state.best_height = disconnected_height - 1;
+		for tracked in state.outputs.iter_mut() {
+			if let Some(conf_height) = tracked.spend_confirmation_height {
+				if conf_height >= disconnected_height {
+					tracked.spend_confirmation_height = None;
+				}
+			}
+		}
+	}
+
+	/// Builds a new sweep of every spendable output whose spend isn't confirmed if the set of
+	/// such outputs changed or a fee-bump is due, and (re)broadcasts the latest sweep.
+	fn sweep(&self, state: &mut SweeperState) {
+		let best_height = state.best_height;
+		let mut descriptors = Vec::new();
+		let mut outpoints = Vec::new();
+		for tracked in state.outputs.iter() {
+			if tracked.spend_confirmation_height.is_none() && tracked.spendable_height <= best_height + 1 {
+				descriptors.push(tracked.descriptor.clone());
+				outpoints.push(tracked.outpoint().into_bitcoin_outpoint());
+			}
+		}
+		if descriptors.is_empty() {
+			// Keep the latest sweep around while its spend may still be reorged out, so that we
+			// don't lose track of its feerate.
+			if state.outputs.is_empty() { state.latest_sweep = None; }
+			return;
+		}
+
+		let estimated_feerate = self.fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Normal);
+		let (feerate, rebuild) = match state.latest_sweep {
+			None => (estimated_feerate, true),
+			Some((ref tx, prev_feerate, built_height)) => {
+				let inputs_changed = tx.input.iter().map(|input| input.previous_output).collect::<Vec<_>>() != outpoints;
+				// Bump by 25%, but at least enough to satisfy BIP 125's relay fee requirement
+				let bumped_feerate = cmp::max(prev_feerate + prev_feerate / 4, prev_feerate + MIN_RELAY_FEE_SAT_PER_1000_WEIGHT as u32);
+				// A new sweep spending some of the previous sweep's inputs replaces it, and thus has
+				// to pay for its own relay on top of the previous sweep's fee (BIP 125 rule 4). As
+				// a replacement only ever adds inputs (those removed were spent in a block), the
+				// bumped feerate is sufficient.
+				let replaces_prev_sweep = tx.input.iter().any(|input| outpoints.contains(&input.previous_output));
+				if best_height >= built_height + SWEEP_BUMP_INTERVAL || (inputs_changed && replaces_prev_sweep) {
+					(cmp::max(estimated_feerate, bumped_feerate), true)
+				} else {
+					(cmp::max(estimated_feerate, prev_feerate), inputs_changed)
+				}
+			},
+		};
+		if rebuild {
+			match self.keys_manager.spend_spendable_outputs(&descriptors, Vec::new(), self.change_destination_script.clone(), feerate, &self.secp_ctx) {
+				Ok(sweep_tx) => {
+					log_trace!(self.logger, "Built sweep transaction {} spending {} outputs at feerate {}", sweep_tx.txid(), descriptors.len(), feerate);
+					state.latest_sweep = Some((sweep_tx, feerate, best_height));
+				},
+				Err(()) => {
+					log_error!(self.logger, "Failed to build a sweep transaction for {} outputs at feerate {}", descriptors.len(), feerate);
+				},
+			}
+		}
+		if let Some((ref sweep_tx, _, _)) = state.latest_sweep {
+			self.broadcaster.broadcast_transaction(sweep_tx);
+		}
+	}
+}
+
+impl<B: Deref, F: Deref, K: Deref, L: Deref> Writeable for OutputSweeper<B, F, K, L>
+	where B::Target: BroadcasterInterface,
+	      F::Target: FeeEstimator,
+	      K::Target: OutputSpender,
+	      L::Target: Logger,
+{
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		let state = self.state.lock().unwrap();
+		self.change_destination_script.write(writer)?;
+		state.best_height.write(writer)?;
+		(state.outputs.len() as u64).write(writer)?;
+		for tracked in state.outputs.iter() {
+			tracked.descriptor.write(writer)?;
+			tracked.spendable_height.write(writer)?;
+			tracked.spend_confirmation_height.write(writer)?;
+		}
+		match state.latest_sweep {
+			Some((ref tx, feerate, built_height)) => {
+				1u8.write(writer)?;
+				tx.write(writer)?;
+				feerate.write(writer)?;
+				built_height.write(writer)?;
+			},
+			None => 0u8.write(writer)?,
+		}
+		Ok(())
+	}
+}
+
+/// Arguments for the deserialization of an OutputSweeper which are not serialized.
+pub struct OutputSweeperReadArgs<B: Deref, F: Deref, K: Deref, L: Deref>
+	where B::Target: BroadcasterInterface,
+	      F::Target: FeeEstimator,
+	      K::Target: OutputSpender,
+	      L::Target: Logger,
+{
+	/// The broadcaster sweep transactions are handed to.
+	pub broadcaster: B,
+	/// The fee estimator used to pick the feerate of sweep transactions.
+	pub fee_estimator: F,
+	/// The OutputSpender (eg the KeysManager) which derived the keys of the tracked outputs.
+	pub keys_manager: K,
+	/// The logger.
+	pub logger: L,
+}
+
+impl<B: Deref, F: Deref, K: Deref, L: Deref> ReadableArgs<OutputSweeperReadArgs<B, F, K, L>> for OutputSweeper<B, F, K, L>
+	where B::Target: BroadcasterInterface,
+	      F::Target: FeeEstimator,
+	      K::Target: OutputSpender,
+	      L::Target: Logger,
+{
+	fn read<R: Read>(reader: &mut R, args: OutputSweeperReadArgs<B, F, K, L>) -> Result<Self, DecodeError> {
+		let change_destination_script = Readable::read(reader)?;
+		let best_height = Readable::read(reader)?;
+		let outputs_len: u64 = Readable::read(reader)?;
+		let mut outputs = Vec::with_capacity(cmp::min(outputs_len as usize, 1024));
+		for _ in 0..outputs_len {
+			outputs.push(TrackedOutput {
+				descriptor: Readable::read(reader)?,
+				spendable_height: Readable::read(reader)?,
+				spend_confirmation_height: Readable::read(reader)?,
+			});
+		}
+		let latest_sweep = match <u8 as Readable>::read(reader)? {
+			0 => None,
+			1 => Some((Readable::read(reader)?, Readable::read(reader)?, Readable::read(reader)?)),
+			_ => return Err(DecodeError::InvalidValue),
+		};
+		Ok(OutputSweeper {
+			broadcaster: args.broadcaster,
+			fee_estimator: args.fee_estimator,
+			keys_manager: args.keys_manager,
+			logger: args.logger,
+			change_destination_script,
+			state: Mutex::new(SweeperState { outputs, best_height, latest_sweep }),
+			secp_ctx: Secp256k1::signing_only(),
+		})
+	}
+}
@@ -0,0 +1,687 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! A [`ChannelKeys`] wrapper enforcing the `ChannelKeys` contract and a configurable signing
+//! policy on top of any signer, meant to run in production (eg in front of the keys of a
+//! [`RemoteSigner`]).
+//!
+//! [`ChannelKeys`]: ../keysinterface/trait.ChannelKeys.html
+//! [`RemoteSigner`]: ../remotesigner/struct.RemoteSigner.html
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+use bitcoin::secp256k1::{Secp256k1, Signature};
+use bitcoin::secp256k1;
+
+use chain::keysinterface::ChannelKeys;
+use ln::chan_utils::{ChannelPublicKeys, HolderCommitmentTransaction, HTLCOutputInCommitment, PreCalculatedTxCreationKeys};
+use ln::msgs::{DecodeError, UnsignedChannelAnnouncement};
+use util::ser::{Readable, ReadableArgs, Writeable, Writer};
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::Read;
+use std::ops::Deref;
+use std::sync::Mutex;
+
+const INITIAL_COMMITMENT_NUMBER: u64 = (1 << 48) - 1;
+
+/// Limits on the transactions a [`ValidatingChannelKeys`] agrees to sign. The default policy
+/// doesn't limit anything.
+///
+/// Limits only apply to transactions which may move funds away from us, ie not to our own
+/// commitment transactions, which we may need to broadcast whatever their feerate or HTLCs.
+///
+/// [`ValidatingChannelKeys`]: struct.ValidatingChannelKeys.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct SigningPolicy {
+	/// The maximum feerate, in satoshis per 1000 weight units, of counterparty commitment
+	/// transactions.
+	pub max_feerate_per_kw: u32,
+	/// The maximum value of any HTLC in counterparty commitment transactions.
+	pub max_htlc_value_msat: u64,
+	/// The scripts which our claiming transactions (justice, HTLC-claiming and delayed output
+	/// sweeping transactions) may pay to, if restricted. Cooperative closing transactions may pay
+	/// to at most one other script, the counterparty's.
+	///
+	/// This should include the [`KeysInterface::get_destination_script`] and the script derived
+	/// from [`KeysInterface::get_shutdown_pubkey`].
+	///
+	/// [`KeysInterface::get_destination_script`]: ../keysinterface/trait.KeysInterface.html#tymethod.get_destination_script
+	/// [`KeysInterface::get_shutdown_pubkey`]: ../keysinterface/trait.KeysInterface.html#tymethod.get_shutdown_pubkey
+	pub allowed_destination_scripts: Option<Vec<Script>>,
+}
+
+impl Default for SigningPolicy {
+	fn default() -> Self {
+		SigningPolicy {
+			max_feerate_per_kw: u32::max_value(),
+			max_htlc_value_msat: u64::max_value(),
+			allowed_destination_scripts: None,
+		}
+	}
+}
+
+impl Writeable for SigningPolicy {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		self.max_feerate_per_kw.write(writer)?;
+		self.max_htlc_value_msat.write(writer)?;
+		match self.allowed_destination_scripts {
+			Some(ref scripts) => {
+				1u8.write(writer)?;
+				(scripts.len() as u16).write(writer)?;
+				for script in scripts.iter() {
+					script.write(writer)?;
+				}
+			},
+			None => 0u8.write(writer)?,
+		}
+		Ok(())
+	}
+}
+
+impl Readable for SigningPolicy {
+	fn read<R: Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let max_feerate_per_kw = Readable::read(reader)?;
+		let max_htlc_value_msat = Readable::read(reader)?;
+		let allowed_destination_scripts = match <u8 as Readable>::read(reader)? {
+			0 => None,
+			1 => {
+				let len: u16 = Readable::read(reader)?;
+				let mut scripts = Vec::with_capacity(cmp::min(len as usize, 64));
+				for _ in 0..len {
+					scripts.push(Readable::read(reader)?);
+				}
+				Some(scripts)
+			},
+			_ => return Err(DecodeError::InvalidValue),
+		};
+		Ok(SigningPolicy { max_feerate_per_kw, max_htlc_value_msat, allowed_destination_scripts })
+	}
+}
+
+/// Computes the factor commitment numbers are obscured with, as described in BOLT 3, from the
+/// payment basepoints of the channel's opener and acceptor.
+fn commitment_number_obscure_factor(opener_payment_point: &PublicKey, acceptor_payment_point: &PublicKey) -> u64 {
+	let mut sha = Sha256::engine();
+	sha.input(&opener_payment_point.serialize());
+	sha.input(&acceptor_payment_point.serialize());
+	let res = Sha256::from_engine(sha).into_inner();
+
+	((res[26] as u64) << 5*8) |
+	((res[27] as u64) << 4*8) |
+	((res[28] as u64) << 3*8) |
+	((res[29] as u64) << 2*8) |
+	((res[30] as u64) << 1*8) |
+	((res[31] as u64) << 0*8)
+}
+
+// Commitment numbers here count up from 0, as they appear (obscured) in commitment transactions,
+// unlike the ChannelKeys indexes which count down from INITIAL_COMMITMENT_NUMBER.
+struct ValidationState {
+	// The obscure factor if we opened the channel and if our counterparty did, computed from both
+	// payment basepoints in on_accept, as ChannelKeys aren't told which side opened the channel.
+	candidate_obscure_factors: Option<(u64, u64)>,
+	// Picked among the candidates by the first counterparty commitment transaction we sign, which
+	// has to be number 0 with one of them.
+	commitment_number_obscure_factor: Option<u64>,
+	last_counterparty_commitment_number: u64,
+	// The highest holder commitment number whose secret was released.
+	revoked_holder_commitment_number: Option<u64>,
+	// The highest holder commitment number we signed for broadcast.
+	signed_holder_commitment_number: Option<u64>,
+}
+
+impl ValidationState {
+	fn new() -> Self {
+		ValidationState {
+			candidate_obscure_factors: None,
+			commitment_number_obscure_factor: None,
+			last_counterparty_commitment_number: 0,
+			revoked_holder_commitment_number: None,
+			signed_holder_commitment_number: None,
+		}
+	}
+}
+
+impl Writeable for ValidationState {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		match self.candidate_obscure_factors {
+			Some((if_outbound, if_inbound)) => {
+				1u8.write(writer)?;
+				if_outbound.write(writer)?;
+				if_inbound.write(writer)?;
+			},
+			None => 0u8.write(writer)?,
+		}
+		self.commitment_number_obscure_factor.write(writer)?;
+		self.last_counterparty_commitment_number.write(writer)?;
+		self.revoked_holder_commitment_number.write(writer)?;
+		self.signed_holder_commitment_number.write(writer)?;
+		Ok(())
+	}
+}
+
+impl Readable for ValidationState {
+	fn read<R: Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let candidate_obscure_factors = match <u8 as Readable>::read(reader)? {
+			0 => None,
+			1 => Some((Readable::read(reader)?, Readable::read(reader)?)),
+			_ => return Err(DecodeError::InvalidValue),
+		};
+		Ok(ValidationState {
+			candidate_obscure_factors,
+			commitment_number_obscure_factor: Readable::read(reader)?,
+			last_counterparty_commitment_number: Readable::read(reader)?,
+			revoked_holder_commitment_number: Readable::read(reader)?,
+			signed_holder_commitment_number: Readable::read(reader)?,
+		})
+	}
+}
+
+/// The state tracked by [`ValidatingChannelKeys`] for all channels, keyed by the channel's (holder)
+/// funding pubkey.
+///
+/// A single store should be shared by reference by all the `ValidatingChannelKeys` (ie by each
+/// `Channel` and its `ChannelMonitor`, which hold distinct copies of the keys), including those
+/// read back with [`ReadableArgs`], so they never act on diverging states.
+///
+/// The store has to be persisted whenever it changes, in particular before any commitment secret
+/// is sent to our counterparty. As a `ChannelMonitorUpdate` revealing a commitment secret is
+/// persisted before the secret is sent, writing the store from [`Persist::update_persisted_channel`]
+/// (and [`Persist::persist_new_channel`]) is enough.
+///
+/// [`ValidatingChannelKeys`]: struct.ValidatingChannelKeys.html
+/// [`ReadableArgs`]: ../../util/ser/trait.ReadableArgs.html
+/// [`Persist::update_persisted_channel`]: ../channelmonitor/trait.Persist.html#tymethod.update_persisted_channel
+/// [`Persist::persist_new_channel`]: ../channelmonitor/trait.Persist.html#tymethod.persist_new_channel
+pub struct ValidationStateStore {
+	states: Mutex<HashMap<PublicKey, ValidationState>>,
+}
+
+impl ValidationStateStore {
+	/// Creates an empty store.
+	pub fn new() -> Self {
+		ValidationStateStore { states: Mutex::new(HashMap::new()) }
+	}
+
+	/// Forgets the state of the channel with the given funding pubkey, once it's closed and its
+	/// `ChannelMonitor` no longer needs to sign anything.
+	pub fn remove_channel(&self, funding_pubkey: &PublicKey) {
+		self.states.lock().unwrap().remove(funding_pubkey);
+	}
+}
+
+impl Writeable for ValidationStateStore {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		let states = self.states.lock().unwrap();
+		(states.len() as u64).write(writer)?;
+		for (funding_pubkey, state) in states.iter() {
+			funding_pubkey.write(writer)?;
+			state.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for ValidationStateStore {
+	fn read<R: Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let len: u64 = Readable::read(reader)?;
+		let mut states = HashMap::with_capacity(cmp::min(len as usize, 1024));
+		for _ in 0..len {
+			let funding_pubkey = Readable::read(reader)?;
+			let state = Readable::read(reader)?;
+			if states.insert(funding_pubkey, state).is_some() {
+				return Err(DecodeError::InvalidValue);
+			}
+		}
+		Ok(ValidationStateStore { states: Mutex::new(states) })
+	}
+}
+
+/// A [`ChannelKeys`] wrapper which refuses to sign anything breaking the `ChannelKeys` contract or
+/// its [`SigningPolicy`], before handing the rest over to the wrapped signer.
+///
+/// It tracks, for the channel:
+///  * the counterparty commitment transactions signed, refusing to sign one which isn't the
+///    current or next one,
+///  * the holder commitment transactions revoked, refusing to sign them for broadcast,
+///  * the holder commitment transactions signed for broadcast, refusing to release their secret.
+///    As `release_commitment_secret` can't fail, an invalid secret is returned instead, which
+///    leads our counterparty to close the channel.
+///
+/// This state lives in a [`ValidationStateStore`], shared with all other `ValidatingChannelKeys`
+/// for the same channel. Only the wrapped signer and the policy are serialized with the keys,
+/// which are read back given a reference to the store.
+///
+/// [`ChannelKeys`]: ../keysinterface/trait.ChannelKeys.html
+/// [`SigningPolicy`]: struct.SigningPolicy.html
+/// [`ValidationStateStore`]: struct.ValidationStateStore.html
+#[derive(Clone)]
+pub struct ValidatingChannelKeys<CK: ChannelKeys, S: Deref<Target = ValidationStateStore> + Clone + Send> {
+	inner: CK,
+	policy: SigningPolicy,
+	store: S,
+}
+
+impl<CK: ChannelKeys, S: Deref<Target = ValidationStateStore> + Clone + Send> ValidatingChannelKeys<CK, S> {
+	/// Wraps the given signer for a new channel, enforcing the given policy and tracking the
+	/// channel's state in the given store.
+	pub fn new(inner: CK, policy: SigningPolicy, store: S) -> Self {
+		store.states.lock().unwrap().entry(inner.pubkeys().funding_pubkey).or_insert_with(ValidationState::new);
+		ValidatingChannelKeys { inner, policy, store }
+	}
+
+	/// Gets the wrapped signer.
+	pub fn inner(&self) -> &CK {
+		&self.inner
+	}
+
+	/// Gets the policy enforced.
+	pub fn policy(&self) -> &SigningPolicy {
+		&self.policy
+	}
+
+	fn obscured_commitment_number(tx: &Transaction) -> u64 {
+		(tx.lock_time & 0xffffff) as u64 | ((tx.input[0].sequence as u64 & 0xffffff) << 3*8)
+	}
+
+	/// Calls f with the state of this channel. If it was lost (eg removed from the store), a fresh
+	/// state is used, with which we refuse to sign any commitment transaction.
+	fn with_state<R, F: FnOnce(&mut ValidationState) -> R>(&self, f: F) -> R {
+		let mut states = self.store.states.lock().unwrap();
+		f(states.entry(self.inner.pubkeys().funding_pubkey).or_insert_with(ValidationState::new))
+	}
+
+	fn check_holder_commitment(&self, holder_commitment_tx: &HolderCommitmentTransaction, sign_for_broadcast: bool, allow_revoked: bool) -> Result<(), ()> {
+		if holder_commitment_tx.unsigned_tx.input.len() != 1 { return Err(()); }
+		self.with_state(|state| {
+			let commitment_number = match state.commitment_number_obscure_factor {
+				Some(factor) => Self::obscured_commitment_number(&holder_commitment_tx.unsigned_tx) ^ factor,
+				None => return Err(()),
+			};
+			if let Some(revoked) = state.revoked_holder_commitment_number {
+				if commitment_number <= revoked && !allow_revoked { return Err(()); }
+			}
+			if sign_for_broadcast {
+				state.signed_holder_commitment_number = Some(cmp::max(commitment_number, state.signed_holder_commitment_number.unwrap_or(0)));
+			}
+			Ok(())
+		})
+	}
+
+	fn check_destination(&self, tx: &Transaction, foreign_outputs_allowed: usize) -> Result<(), ()> {
+		if let Some(ref scripts) = self.policy.allowed_destination_scripts {
+			if tx.output.iter().filter(|output| !scripts.contains(&output.script_pubkey)).count() > foreign_outputs_allowed {
+				return Err(());
+			}
+		}
+		Ok(())
+	}
+}
+
+impl<CK: ChannelKeys, S: Deref<Target = ValidationStateStore> + Clone + Send> ChannelKeys for ValidatingChannelKeys<CK, S> {
+	fn get_per_commitment_point<T: secp256k1::Signing + secp256k1::Verification>(&self, idx: u64, secp_ctx: &Secp256k1<T>) -> PublicKey {
+		self.inner.get_per_commitment_point(idx, secp_ctx)
+	}
+
+	fn release_commitment_secret(&self, idx: u64) -> [u8; 32] {
+		let commitment_number = INITIAL_COMMITMENT_NUMBER - idx;
+		let refused = self.with_state(|state| {
+			if let Some(signed) = state.signed_holder_commitment_number {
+				// We may have broadcast this commitment transaction, so its secret must never leak.
+				if commitment_number <= signed { return true; }
+			}
+			state.revoked_holder_commitment_number = Some(cmp::max(commitment_number, state.revoked_holder_commitment_number.unwrap_or(0)));
+			false
+		});
+		// An all-zero secret isn't a valid key, so our counterparty will reject our revocation and
+		// close the channel.
+		if refused { return [0; 32]; }
+		self.inner.release_commitment_secret(idx)
+	}
+
+	fn pubkeys(&self) -> &ChannelPublicKeys { self.inner.pubkeys() }
+	fn key_derivation_params(&self) -> (u64, u64) { self.inner.key_derivation_params() }
+
+	fn sign_counterparty_commitment<T: secp256k1::Signing + secp256k1::Verification>(&self, feerate_per_kw: u32, commitment_tx: &Transaction, keys: &PreCalculatedTxCreationKeys, htlcs: &[&HTLCOutputInCommitment], secp_ctx: &Secp256k1<T>) -> Result<(Signature, Vec<Signature>), ()> {
+		if commitment_tx.input.len() != 1 { return Err(()); }
+		if feerate_per_kw > self.policy.max_feerate_per_kw { return Err(()); }
+		if htlcs.iter().any(|htlc| htlc.amount_msat > self.policy.max_htlc_value_msat) { return Err(()); }
+
+		let inner = &self.inner;
+		self.with_state(|state| {
+			let obscured_commitment_number = Self::obscured_commitment_number(commitment_tx);
+			let factor = match (state.commitment_number_obscure_factor, state.candidate_obscure_factors) {
+				(Some(factor), _) => factor,
+				// Only trust the first commitment transaction to tell us which side opened the
+				// channel, never to tell us the factor itself.
+				(None, Some((if_outbound, if_inbound))) => {
+					if obscured_commitment_number ^ if_outbound == 0 { if_outbound }
+					else if obscured_commitment_number ^ if_inbound == 0 { if_inbound }
+					else { return Err(()); }
+				},
+				(None, None) => return Err(()),
+			};
+			let commitment_number = obscured_commitment_number ^ factor;
+			if commitment_number != state.last_counterparty_commitment_number && commitment_number != state.last_counterparty_commitment_number + 1 {
+				return Err(());
+			}
+			let sigs = inner.sign_counterparty_commitment(feerate_per_kw, commitment_tx, keys, htlcs, secp_ctx)?;
+			state.commitment_number_obscure_factor = Some(factor);
+			state.last_counterparty_commitment_number = commitment_number;
+			Ok(sigs)
+		})
+	}
+
+	fn sign_holder_commitment<T: secp256k1::Signing + secp256k1::Verification>(&self, holder_commitment_tx: &HolderCommitmentTransaction, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.check_holder_commitment(holder_commitment_tx, true, false)?;
+		self.inner.sign_holder_commitment(holder_commitment_tx, secp_ctx)
+	}
+
+	#[cfg(any(test,feature = "unsafe_revoked_tx_signing"))]
+	fn unsafe_sign_holder_commitment<T: secp256k1::Signing + secp256k1::Verification>(&self, holder_commitment_tx: &HolderCommitmentTransaction, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		// Revoked commitment transactions may be signed, but it must still be one of ours, and it's
+		// still tracked as signed for broadcast.
+		self.check_holder_commitment(holder_commitment_tx, true, true)?;
+		self.inner.unsafe_sign_holder_commitment(holder_commitment_tx, secp_ctx)
+	}
+
+	fn sign_holder_commitment_htlc_transactions<T: secp256k1::Signing + secp256k1::Verification>(&self, holder_commitment_tx: &HolderCommitmentTransaction, secp_ctx: &Secp256k1<T>) -> Result<Vec<Option<Signature>>, ()> {
+		self.check_holder_commitment(holder_commitment_tx, false, false)?;
+		self.inner.sign_holder_commitment_htlc_transactions(holder_commitment_tx, secp_ctx)
+	}
+
+	fn sign_justice_transaction<T: secp256k1::Signing + secp256k1::Verification>(&self, justice_tx: &Transaction, input: usize, amount: u64, per_commitment_key: &SecretKey, htlc: &Option<HTLCOutputInCommitment>, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.check_destination(justice_tx, 0)?;
+		self.inner.sign_justice_transaction(justice_tx, input, amount, per_commitment_key, htlc, secp_ctx)
+	}
+
+	fn sign_justice_transactions<T: secp256k1::Signing + secp256k1::Verification>(&self, justice_txn: &[Transaction], inputs: &[(u64, Option<HTLCOutputInCommitment>)], per_commitment_key: &SecretKey, secp_ctx: &Secp256k1<T>) -> Result<Vec<Vec<Signature>>, ()> {
+		for justice_tx in justice_txn.iter() {
+			self.check_destination(justice_tx, 0)?;
+		}
+		self.inner.sign_justice_transactions(justice_txn, inputs, per_commitment_key, secp_ctx)
+	}
+
+	fn sign_counterparty_htlc_transaction<T: secp256k1::Signing + secp256k1::Verification>(&self, htlc_tx: &Transaction, input: usize, amount: u64, per_commitment_point: &PublicKey, htlc: &HTLCOutputInCommitment, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.check_destination(htlc_tx, 0)?;
+		self.inner.sign_counterparty_htlc_transaction(htlc_tx, input, amount, per_commitment_point, htlc, secp_ctx)
+	}
+
+	fn sign_delayed_payment_input<T: secp256k1::Signing + secp256k1::Verification>(&self, spend_tx: &Transaction, input: usize, amount: u64, per_commitment_point: &PublicKey, revocation_pubkey: &PublicKey, to_self_delay: u16, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.check_destination(spend_tx, 0)?;
+		self.inner.sign_delayed_payment_input(spend_tx, input, amount, per_commitment_point, revocation_pubkey, to_self_delay, secp_ctx)
+	}
+
+	fn sign_closing_transaction<T: secp256k1::Signing>(&self, closing_tx: &Transaction, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.check_destination(closing_tx, 1)?;
+		self.inner.sign_closing_transaction(closing_tx, secp_ctx)
+	}
+
+	fn sign_channel_announcement<T: secp256k1::Signing>(&self, msg: &UnsignedChannelAnnouncement, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.inner.sign_channel_announcement(msg, secp_ctx)
+	}
+
+	fn on_accept(&mut self, channel_pubkeys: &ChannelPublicKeys, counterparty_selected_contest_delay: u16, holder_selected_contest_delay: u16) {
+		let holder_payment_point = &self.inner.pubkeys().payment_point;
+		let if_outbound = commitment_number_obscure_factor(holder_payment_point, &channel_pubkeys.payment_point);
+		let if_inbound = commitment_number_obscure_factor(&channel_pubkeys.payment_point, holder_payment_point);
+		self.with_state(|state| {
+			if state.commitment_number_obscure_factor.is_none() {
+				state.candidate_obscure_factors = Some((if_outbound, if_inbound));
+			}
+		});
+		self.inner.on_accept(channel_pubkeys, counterparty_selected_contest_delay, holder_selected_contest_delay)
+	}
+}
+
+impl<CK: ChannelKeys + Writeable, S: Deref<Target = ValidationStateStore> + Clone + Send> Writeable for ValidatingChannelKeys<CK, S> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		self.inner.write(writer)?;
+		self.policy.write(writer)?;
+		Ok(())
+	}
+}
+
+impl<CK: ChannelKeys + Readable, S: Deref<Target = ValidationStateStore> + Clone + Send> ReadableArgs<S> for ValidatingChannelKeys<CK, S> {
+	fn read<R: Read>(reader: &mut R, store: S) -> Result<Self, DecodeError> {
+		let inner = Readable::read(reader)?;
+		let policy = Readable::read(reader)?;
+		Ok(ValidatingChannelKeys { inner, policy, store })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::blockdata::script::{Builder, Script};
+	use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut, OutPoint as BitcoinOutPoint};
+	use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+	use bitcoin::secp256k1::Secp256k1;
+
+	use chain::keysinterface::{ChannelKeys, InMemoryChannelKeys};
+	use chain::validatingkeys::{commitment_number_obscure_factor, SigningPolicy, ValidatingChannelKeys, ValidationStateStore, INITIAL_COMMITMENT_NUMBER};
+	use ln::chan_utils::{ChannelPublicKeys, HolderCommitmentTransaction, HTLCOutputInCommitment, PreCalculatedTxCreationKeys, TxCreationKeys};
+	use ln::channelmanager::PaymentHash;
+	use util::ser::{Readable, ReadableArgs, Writeable};
+
+	use std::io::Cursor;
+	use std::sync::Arc;
+
+	// The obscure factor of the channel set up in setup(), if we opened it or if our counterparty
+	// did.
+	fn obscure_factor(outbound: bool) -> u64 {
+		let secp_ctx = Secp256k1::new();
+		let holder_payment_point = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[3; 32]).unwrap());
+		let counterparty_payment_point = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[13; 32]).unwrap());
+		if outbound {
+			commitment_number_obscure_factor(&holder_payment_point, &counterparty_payment_point)
+		} else {
+			commitment_number_obscure_factor(&counterparty_payment_point, &holder_payment_point)
+		}
+	}
+
+	fn tx(commitment_number: u64, outputs: Vec<Script>) -> Transaction {
+		tx_with_factor(obscure_factor(true), commitment_number, outputs)
+	}
+
+	fn tx_with_factor(factor: u64, commitment_number: u64, outputs: Vec<Script>) -> Transaction {
+		let obscured_commitment_number = commitment_number ^ factor;
+		Transaction {
+			version: 2,
+			lock_time: ((0x20 as u32) << 8*3) | ((obscured_commitment_number & 0xffffff) as u32),
+			input: vec![TxIn {
+				previous_output: BitcoinOutPoint { txid: Default::default(), vout: 0 },
+				script_sig: Script::new(),
+				sequence: ((0x80 as u32) << 8*3) | ((obscured_commitment_number >> 3*8) as u32),
+				witness: Vec::new(),
+			}],
+			output: outputs.into_iter().map(|script_pubkey| TxOut { script_pubkey, value: 10_000 }).collect(),
+		}
+	}
+
+	fn holder_commitment_tx(commitment_number: u64) -> HolderCommitmentTransaction {
+		let mut holder_commitment_tx = HolderCommitmentTransaction::dummy();
+		holder_commitment_tx.unsigned_tx = tx(commitment_number, Vec::new());
+		holder_commitment_tx
+	}
+
+	fn script(byte: u8) -> Script {
+		Builder::new().push_slice(&[byte; 20]).into_script()
+	}
+
+	type TestKeys = ValidatingChannelKeys<InMemoryChannelKeys, Arc<ValidationStateStore>>;
+
+	struct Setup {
+		keys: TestKeys,
+		store: Arc<ValidationStateStore>,
+		tx_keys: PreCalculatedTxCreationKeys,
+		htlc: HTLCOutputInCommitment,
+	}
+
+	fn setup(policy: SigningPolicy) -> Setup {
+		let secp_ctx = Secp256k1::new();
+		let key = |byte: u8| SecretKey::from_slice(&[byte; 32]).unwrap();
+		let inner = InMemoryChannelKeys::new(&secp_ctx, key(1), key(2), key(3), key(4), key(5), [6; 32], 1_000_000, (42, 43));
+		let store = Arc::new(ValidationStateStore::new());
+		let mut keys = ValidatingChannelKeys::new(inner, policy, Arc::clone(&store));
+		keys.on_accept(&ChannelPublicKeys {
+			funding_pubkey: PublicKey::from_secret_key(&secp_ctx, &key(11)),
+			revocation_basepoint: PublicKey::from_secret_key(&secp_ctx, &key(12)),
+			payment_point: PublicKey::from_secret_key(&secp_ctx, &key(13)),
+			delayed_payment_basepoint: PublicKey::from_secret_key(&secp_ctx, &key(14)),
+			htlc_basepoint: PublicKey::from_secret_key(&secp_ctx, &key(15)),
+		}, 144, 145);
+		let tx_keys = PreCalculatedTxCreationKeys::new(TxCreationKeys {
+			per_commitment_point: PublicKey::from_secret_key(&secp_ctx, &key(20)),
+			revocation_key: PublicKey::from_secret_key(&secp_ctx, &key(21)),
+			broadcaster_htlc_key: PublicKey::from_secret_key(&secp_ctx, &key(22)),
+			countersignatory_htlc_key: PublicKey::from_secret_key(&secp_ctx, &key(23)),
+			broadcaster_delayed_payment_key: PublicKey::from_secret_key(&secp_ctx, &key(24)),
+		});
+		let htlc = HTLCOutputInCommitment {
+			offered: true,
+			amount_msat: 100_000_000,
+			cltv_expiry: 500,
+			payment_hash: PaymentHash([7; 32]),
+			transaction_output_index: Some(0),
+		};
+		Setup { keys, store, tx_keys, htlc }
+	}
+
+	#[test]
+	fn test_commitment_tracking() {
+		let secp_ctx = Secp256k1::new();
+		let Setup { keys, store, tx_keys, htlc } = setup(SigningPolicy::default());
+
+		// Holder commitment transactions can't be signed until we know which side opened the channel
+		assert!(keys.sign_holder_commitment(&holder_commitment_tx(0), &secp_ctx).is_err());
+		assert!(keys.unsafe_sign_holder_commitment(&holder_commitment_tx(0), &secp_ctx).is_err());
+
+		// Counterparty commitment transactions must be signed in order, re-signing the last one
+		assert!(keys.sign_counterparty_commitment(253, &tx(0, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_ok());
+		assert!(keys.sign_counterparty_commitment(253, &tx(0, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_ok());
+		assert!(keys.sign_counterparty_commitment(253, &tx(2, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_err());
+		assert!(keys.sign_counterparty_commitment(253, &tx(1, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_ok());
+		assert!(keys.sign_counterparty_commitment(253, &tx(0, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_err());
+
+		// Revoked holder commitment transactions are never signed, while clones share the state
+		let clone = keys.clone();
+		clone.release_commitment_secret(INITIAL_COMMITMENT_NUMBER);
+		assert!(keys.sign_holder_commitment(&holder_commitment_tx(0), &secp_ctx).is_err());
+		assert!(keys.sign_holder_commitment_htlc_transactions(&holder_commitment_tx(0), &secp_ctx).is_err());
+		assert!(keys.unsafe_sign_holder_commitment(&holder_commitment_tx(0), &secp_ctx).is_ok());
+		assert!(keys.sign_holder_commitment_htlc_transactions(&holder_commitment_tx(1), &secp_ctx).is_ok());
+		assert!(keys.sign_holder_commitment(&holder_commitment_tx(1), &secp_ctx).is_ok());
+
+		// The state survives serialization of the store
+		let read_store: Arc<ValidationStateStore> = Arc::new(Readable::read(&mut Cursor::new(&store.encode())).unwrap());
+		let read_keys: TestKeys = ReadableArgs::read(&mut Cursor::new(&keys.encode()), Arc::clone(&read_store)).unwrap();
+		assert_eq!(read_keys.policy(), &SigningPolicy::default());
+		assert!(read_keys.sign_holder_commitment(&holder_commitment_tx(0), &secp_ctx).is_err());
+		assert!(read_keys.sign_holder_commitment(&holder_commitment_tx(1), &secp_ctx).is_ok());
+		assert!(read_keys.sign_counterparty_commitment(253, &tx(1, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_ok());
+		assert!(read_keys.sign_counterparty_commitment(253, &tx(0, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_err());
+		assert!(read_keys.sign_counterparty_commitment(253, &tx(2, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_ok());
+
+		// Keys read back share the state of the store they're given (but not of any other store)
+		let shared_keys: TestKeys = ReadableArgs::read(&mut Cursor::new(&keys.encode()), Arc::clone(&store)).unwrap();
+		assert!(keys.sign_counterparty_commitment(253, &tx(2, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_ok());
+		assert!(shared_keys.sign_counterparty_commitment(253, &tx(1, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_err());
+		assert!(shared_keys.sign_counterparty_commitment(253, &tx(3, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_ok());
+		assert!(read_keys.sign_counterparty_commitment(253, &tx(3, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_ok());
+		assert!(keys.sign_counterparty_commitment(253, &tx(5, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_err());
+		assert!(keys.sign_counterparty_commitment(253, &tx(4, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_ok());
+	}
+
+	#[test]
+	fn test_obscure_factor() {
+		// The obscure factor is never learned from the first commitment transaction, which has to
+		// be number 0 with the factor computed for either side having opened the channel.
+		let secp_ctx = Secp256k1::new();
+		let Setup { keys, tx_keys, htlc, .. } = setup(SigningPolicy::default());
+		let crafted_factor = obscure_factor(true) ^ 0x1234;
+		assert!(keys.sign_counterparty_commitment(253, &tx_with_factor(crafted_factor, 0, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_err());
+		assert!(keys.sign_counterparty_commitment(253, &tx_with_factor(obscure_factor(true), 1, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_err());
+		assert!(keys.sign_holder_commitment(&holder_commitment_tx(0), &secp_ctx).is_err());
+
+		// If our counterparty opened the channel, the factor is computed the other way around
+		let inbound_factor = obscure_factor(false);
+		assert!(keys.sign_counterparty_commitment(253, &tx_with_factor(inbound_factor, 0, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_ok());
+		assert!(keys.sign_counterparty_commitment(253, &tx_with_factor(inbound_factor, 1, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_ok());
+		assert!(keys.sign_counterparty_commitment(253, &tx(2, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_err());
+
+		// Keys which were never told about the counterparty's keys don't sign commitment transactions
+		let store = Arc::new(ValidationStateStore::new());
+		let inner = InMemoryChannelKeys::new(&secp_ctx, SecretKey::from_slice(&[1; 32]).unwrap(), SecretKey::from_slice(&[2; 32]).unwrap(),
+			SecretKey::from_slice(&[3; 32]).unwrap(), SecretKey::from_slice(&[4; 32]).unwrap(), SecretKey::from_slice(&[5; 32]).unwrap(), [6; 32], 1_000_000, (42, 43));
+		let unaccepted_keys: TestKeys = ValidatingChannelKeys::new(inner, SigningPolicy::default(), store);
+		assert!(unaccepted_keys.sign_counterparty_commitment(253, &tx(0, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_err());
+	}
+
+	#[test]
+	fn test_release_signed_commitment_secret() {
+		// The secret of a commitment transaction signed for broadcast is never released, and it
+		// isn't considered revoked.
+		let secp_ctx = Secp256k1::new();
+		let Setup { keys, tx_keys, htlc, .. } = setup(SigningPolicy::default());
+		let secret = keys.inner().release_commitment_secret(INITIAL_COMMITMENT_NUMBER);
+		assert!(keys.sign_counterparty_commitment(253, &tx(0, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_ok());
+		assert!(keys.sign_holder_commitment(&holder_commitment_tx(0), &secp_ctx).is_ok());
+		assert_eq!(keys.release_commitment_secret(INITIAL_COMMITMENT_NUMBER), [0; 32]);
+		assert!(keys.sign_holder_commitment(&holder_commitment_tx(0), &secp_ctx).is_ok());
+
+		// Even when signed unsafely
+		assert_eq!(keys.release_commitment_secret(INITIAL_COMMITMENT_NUMBER - 1), keys.inner().release_commitment_secret(INITIAL_COMMITMENT_NUMBER - 1));
+		assert!(keys.unsafe_sign_holder_commitment(&holder_commitment_tx(2), &secp_ctx).is_ok());
+		assert_eq!(keys.release_commitment_secret(INITIAL_COMMITMENT_NUMBER - 2), [0; 32]);
+		assert!(secret != [0; 32]);
+	}
+
+	#[test]
+	fn test_signing_policy() {
+		let secp_ctx = Secp256k1::new();
+		let policy = SigningPolicy {
+			max_feerate_per_kw: 1000,
+			max_htlc_value_msat: 100_000_000,
+			allowed_destination_scripts: Some(vec![script(1), script(2)]),
+		};
+		let Setup { keys, store, tx_keys, mut htlc } = setup(policy.clone());
+		let read_keys: TestKeys = ReadableArgs::read(&mut Cursor::new(&keys.encode()), store).unwrap();
+		assert_eq!(read_keys.policy(), &policy);
+
+		assert!(keys.sign_counterparty_commitment(1001, &tx(0, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_err());
+		assert!(keys.sign_counterparty_commitment(1000, &tx(0, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_ok());
+		htlc.amount_msat += 1;
+		assert!(keys.sign_counterparty_commitment(1000, &tx(1, Vec::new()), &tx_keys, &[&htlc], &secp_ctx).is_err());
+
+		// Our own commitment transactions are signed whatever their feerate
+		let mut holder_tx = holder_commitment_tx(0);
+		holder_tx.feerate_per_kw = 5000;
+		assert!(keys.sign_holder_commitment(&holder_tx, &secp_ctx).is_ok());
+
+		let per_commitment_key = SecretKey::from_slice(&[31; 32]).unwrap();
+		let per_commitment_point = PublicKey::from_secret_key(&secp_ctx, &per_commitment_key);
+		assert!(keys.sign_justice_transaction(&tx(0, vec![script(1)]), 0, 100_000, &per_commitment_key, &None, &secp_ctx).is_ok());
+		assert!(keys.sign_justice_transaction(&tx(0, vec![script(3)]), 0, 100_000, &per_commitment_key, &None, &secp_ctx).is_err());
+		assert!(keys.sign_justice_transactions(&[tx(0, vec![script(1)]), tx(0, vec![script(2)])], &[(100_000, None)], &per_commitment_key, &secp_ctx).is_ok());
+		assert!(keys.sign_justice_transactions(&[tx(0, vec![script(1)]), tx(0, vec![script(3)])], &[(100_000, None)], &per_commitment_key, &secp_ctx).is_err());
+		assert!(keys.sign_counterparty_htlc_transaction(&tx(0, vec![script(2)]), 0, 100_000, &per_commitment_point, &htlc, &secp_ctx).is_ok());
+		assert!(keys.sign_counterparty_htlc_transaction(&tx(0, vec![script(3)]), 0, 100_000, &per_commitment_point, &htlc, &secp_ctx).is_err());
+		assert!(keys.sign_delayed_payment_input(&tx(0, vec![script(1)]), 0, 100_000, &per_commitment_point, &per_commitment_point, 144, &secp_ctx).is_ok());
+		assert!(keys.sign_delayed_payment_input(&tx(0, vec![script(1), script(3)]), 0, 100_000, &per_commitment_point, &per_commitment_point, 144, &secp_ctx).is_err());
+
+		// Closing transactions may pay our counterparty too, but no one else
+		assert!(keys.sign_closing_transaction(&tx(0, vec![script(1), script(3)]), &secp_ctx).is_ok());
+		assert!(keys.sign_closing_transaction(&tx(0, vec![script(3), script(4)]), &secp_ctx).is_err());
+	}
+}
@@ -0,0 +1,819 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Outsourcing the punishment of revoked counterparty states to watchtowers.
+//!
+//! A [`ChannelMonitor`] is able to punish a counterparty broadcasting a revoked commitment
+//! transaction, but only as long as we run it ourselves. This module lets the punishment be
+//! delegated to one or more third parties (towers) which never learn anything about our channels
+//! unless a revoked state actually hits the chain.
+//!
+//! On the client side, [`WatchtowerClient`] wraps a [`Persist`] implementation. Each time a
+//! counterparty commitment transaction is revoked, it fetches the justice transactions the
+//! [`ChannelMonitor`] pre-signed for it, encrypts them with a key derived from the revoked
+//! commitment txid and queues the resulting [`Appointment`]s. Queued appointments are shipped to
+//! its towers via a [`TowerTransport`] when the user calls
+//! [`WatchtowerClient::deliver_pending_appointments`], so that no I/O happens while a monitor
+//! update is being persisted.
+//!
+//! On the tower side, [`Watchtower`] stores appointments from the clients it was told to serve,
+//! indexed by a locator (the first half of the commitment txid). When a transaction with a
+//! matching txid is seen in [`Watchtower::block_connected`], the tower decrypts the justice
+//! transaction and broadcasts it.
+//!
+//! Appointments can be delivered in-process (`Watchtower` implements `TowerTransport` itself) or
+//! over any byte stream, such as a TCP connection, using [`StreamTowerTransport`] on the client
+//! side and [`Watchtower::handle_stream`] on the tower side.
+//!
+//! [`ChannelMonitor`]: ../channelmonitor/struct.ChannelMonitor.html
+//! [`Persist`]: ../channelmonitor/trait.Persist.html
+//! [`WatchtowerClient`]: struct.WatchtowerClient.html
+//! [`Appointment`]: struct.Appointment.html
+//! [`TowerTransport`]: trait.TowerTransport.html
+//! [`WatchtowerClient::deliver_pending_appointments`]: struct.WatchtowerClient.html#method.deliver_pending_appointments
+//! [`Watchtower`]: struct.Watchtower.html
+//! [`Watchtower::block_connected`]: struct.Watchtower.html#method.block_connected
+//! [`StreamTowerTransport`]: struct.StreamTowerTransport.html
+//! [`Watchtower::handle_stream`]: struct.Watchtower.html#method.handle_stream
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode;
+use bitcoin::hash_types::Txid;
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::sha256::Hash as Sha256;
+
+use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+use bitcoin::secp256k1;
+use bitcoin::secp256k1::{Secp256k1, Signature, SignOnly, VerifyOnly};
+
+use chain::chaininterface::BroadcasterInterface;
+use chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, ChannelMonitorUpdateErr, ChannelMonitorUpdateStep, Persist};
+use chain::keysinterface::ChannelKeys;
+use chain::transaction::{OutPoint, TransactionData};
+use ln::msgs::DecodeError;
+use util::byte_utils;
+use util::chacha20poly1305rfc::ChaCha20Poly1305RFC;
+use util::logger::Logger;
+use util::ser::{Readable, ReadableArgs, Writeable, Writer};
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::ops::Deref;
+use std::time::Duration;
+use std::io;
+
+/// An error returned by a [`TowerTransport`] when an appointment could not be delivered.
+/// Contains a developer-readable error message.
+///
+/// [`TowerTransport`]: trait.TowerTransport.html
+#[derive(Clone, Debug)]
+pub struct TowerError(pub &'static str);
+
+/// An encrypted justice transaction, handed to a tower to be broadcast once the revoked
+/// commitment transaction it spends appears on chain.
+///
+/// The justice transaction is encrypted with the SHA256 of the revoked commitment txid and
+/// indexed by the first 16 bytes of the txid, so a tower can only decrypt it once it has seen the
+/// full commitment transaction. As several justice transactions may be built for the same
+/// commitment transaction, each is encrypted with its own nonce, which is stored in the blob.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Appointment {
+	/// The first 16 bytes of the revoked commitment txid.
+	pub locator: [u8; 16],
+	/// The 8-byte nonce, followed by the encrypted justice transaction and its 16-byte
+	/// authentication tag.
+	pub encrypted_blob: Vec<u8>,
+}
+impl_writeable!(Appointment, 0, { locator, encrypted_blob });
+
+impl Appointment {
+	/// Encrypts `justice_tx` for the revoked commitment transaction with the given txid, using the
+	/// given nonce, which must never be reused for another justice transaction spending the same
+	/// commitment transaction.
+	pub fn new(commitment_txid: &Txid, justice_tx: &Transaction, nonce: [u8; 8]) -> Self {
+		let tx_bytes = encode::serialize(justice_tx);
+		let mut encrypted_blob = vec![0; 8 + tx_bytes.len() + 16];
+		encrypted_blob[0..8].copy_from_slice(&nonce);
+		let mut tag = [0; 16];
+		let mut chacha = ChaCha20Poly1305RFC::new(&Sha256::hash(&commitment_txid[..]).into_inner(), &chacha_nonce(&nonce), &[]);
+		chacha.encrypt(&tx_bytes, &mut encrypted_blob[8..8 + tx_bytes.len()], &mut tag);
+		encrypted_blob[8 + tx_bytes.len()..].copy_from_slice(&tag);
+		Appointment {
+			locator: Self::locator_for(commitment_txid),
+			encrypted_blob,
+		}
+	}
+
+	/// Gets the locator under which appointments for the given commitment txid are indexed.
+	pub fn locator_for(commitment_txid: &Txid) -> [u8; 16] {
+		let mut locator = [0; 16];
+		locator.copy_from_slice(&commitment_txid[..16]);
+		locator
+	}
+
+	/// Attempts to decrypt the justice transaction using the full commitment txid. Returns None if
+	/// this appointment was not built for the given txid.
+	pub fn decrypt(&self, commitment_txid: &Txid) -> Option<Transaction> {
+		if self.encrypted_blob.len() < 8 + 16 || self.locator != Self::locator_for(commitment_txid) {
+			return None;
+		}
+		let tx_len = self.encrypted_blob.len() - 8 - 16;
+		let mut tx_bytes = vec![0; tx_len];
+		let mut chacha = ChaCha20Poly1305RFC::new(&Sha256::hash(&commitment_txid[..]).into_inner(), &chacha_nonce(&self.encrypted_blob[0..8]), &[]);
+		if !chacha.decrypt(&self.encrypted_blob[8..8 + tx_len], &mut tx_bytes, &self.encrypted_blob[8 + tx_len..]) {
+			return None;
+		}
+		encode::deserialize(&tx_bytes).ok()
+	}
+}
+
+// Our ChaCha20 only supports 64-bit nonces, which are prefixed with 4 zero bytes.
+fn chacha_nonce(nonce: &[u8]) -> [u8; 12] {
+	let mut res = [0; 12];
+	res[4..].copy_from_slice(nonce);
+	res
+}
+
+/// An [`Appointment`] signed by the key of the [`WatchtowerClient`] which built it, allowing a
+/// tower to only store appointments from the clients it serves.
+///
+/// [`Appointment`]: struct.Appointment.html
+/// [`WatchtowerClient`]: struct.WatchtowerClient.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignedAppointment {
+	/// The public key identifying the client which built the appointment.
+	pub client_id: PublicKey,
+	/// The appointment itself.
+	pub appointment: Appointment,
+	/// A signature by `client_id` over the SHA256 of the serialized appointment.
+	pub signature: Signature,
+}
+impl_writeable!(SignedAppointment, 0, { client_id, appointment, signature });
+
+impl SignedAppointment {
+	/// Signs `appointment` with the given client key.
+	pub fn new<C: secp256k1::Signing>(appointment: Appointment, client_key: &SecretKey, secp_ctx: &Secp256k1<C>) -> Self {
+		let msghash = hash_to_message!(&Sha256::hash(&appointment.encode()[..])[..]);
+		SignedAppointment {
+			client_id: PublicKey::from_secret_key(secp_ctx, client_key),
+			signature: secp_ctx.sign(&msghash, client_key),
+			appointment,
+		}
+	}
+
+	/// Checks that the signature is valid for the appointment and `client_id`.
+	pub fn verify<C: secp256k1::Verification>(&self, secp_ctx: &Secp256k1<C>) -> bool {
+		let msghash = hash_to_message!(&Sha256::hash(&self.appointment.encode()[..])[..]);
+		secp_ctx.verify(&msghash, &self.signature, &self.client_id).is_ok()
+	}
+}
+
+/// A channel to a watchtower, over which appointments are delivered.
+pub trait TowerTransport: Send + Sync {
+	/// Delivers an appointment to the tower, returning once the tower has acknowledged it.
+	///
+	/// This is only ever called from [`WatchtowerClient::deliver_pending_appointments`], never
+	/// while a monitor update is being persisted, so it may block on I/O. It should still time out
+	/// rather than wait forever on an unresponsive tower.
+	///
+	/// [`WatchtowerClient::deliver_pending_appointments`]: struct.WatchtowerClient.html#method.deliver_pending_appointments
+	fn send_appointment(&self, appointment: &SignedAppointment) -> Result<(), TowerError>;
+}
+
+/// The maximum number of appointments a [`WatchtowerClient`] keeps queued for delivery. Once
+/// reached, the oldest appointments are dropped (and an error logged) as new ones are queued.
+///
+/// [`WatchtowerClient`]: struct.WatchtowerClient.html
+pub const MAX_PENDING_APPOINTMENTS: usize = 10_000;
+
+struct PendingAppointment {
+	appointment: SignedAppointment,
+	/// Indexes in `WatchtowerClient::towers` which have not acknowledged this appointment yet.
+	undelivered_towers: Vec<usize>,
+}
+
+/// A [`Persist`] wrapper which, in addition to persisting monitors through the wrapped
+/// persister, queues the justice transactions pre-signed for each revoked counterparty commitment
+/// transaction as encrypted [`Appointment`]s for each of its towers.
+///
+/// Justice transactions are taken from [`ChannelMonitor::get_justice_txs_for_revoked`] whenever a
+/// [`ChannelMonitorUpdate`] provides a new revocation secret. Each of the feerates they are
+/// pre-signed at results in a separate appointment; they are never fee-bumped by the tower.
+///
+/// Appointments are not sent while persisting the monitor update, as `ChannelManager` locks are
+/// held at that point. Instead, [`deliver_pending_appointments`] should be called regularly (eg
+/// from a background thread, after each call to `process_pending_events`). Appointments a tower
+/// fails to acknowledge stay queued and are retried on the next call. Note that the queue is not
+/// persisted, so appointments still pending on restart are lost; we are still able to punish the
+/// counterparty ourselves.
+///
+/// Appointments are signed with a client key, whose public key each tower must have authorized
+/// via [`Watchtower::authorize_client`].
+///
+/// [`Persist`]: ../channelmonitor/trait.Persist.html
+/// [`deliver_pending_appointments`]: #method.deliver_pending_appointments
+/// [`Watchtower::authorize_client`]: struct.Watchtower.html#method.authorize_client
+/// [`Appointment`]: struct.Appointment.html
+/// [`ChannelMonitor::get_justice_txs_for_revoked`]: ../channelmonitor/struct.ChannelMonitor.html#method.get_justice_txs_for_revoked
+/// [`ChannelMonitorUpdate`]: ../channelmonitor/struct.ChannelMonitorUpdate.html
+pub struct WatchtowerClient<P: Deref, T: Deref, L: Deref>
+	where T::Target: TowerTransport,
+	      L::Target: Logger,
+{
+	persister: P,
+	towers: Vec<T>,
+	client_key: SecretKey,
+	logger: L,
+	pending_appointments: Mutex<VecDeque<PendingAppointment>>,
+	secp_ctx: Secp256k1<SignOnly>,
+}
+
+impl<P: Deref, T: Deref, L: Deref> WatchtowerClient<P, T, L>
+	where T::Target: TowerTransport,
+	      L::Target: Logger,
+{
+	/// Creates a new `WatchtowerClient` persisting monitors via `persister` and delivering
+	/// appointments, signed with `client_key`, to each of `towers`.
+	pub fn new(persister: P, towers: Vec<T>, client_key: SecretKey, logger: L) -> Self {
+		WatchtowerClient {
+			persister,
+			towers,
+			client_key,
+			logger,
+			pending_appointments: Mutex::new(VecDeque::new()),
+			secp_ctx: Secp256k1::signing_only(),
+		}
+	}
+
+	/// Gets the public key towers should authorize to accept appointments from this client.
+	pub fn client_id(&self) -> PublicKey {
+		PublicKey::from_secret_key(&self.secp_ctx, &self.client_key)
+	}
+
+	/// Gets the number of appointments which have not yet been delivered to every tower.
+	pub fn pending_appointment_count(&self) -> usize {
+		self.pending_appointments.lock().unwrap().len()
+	}
+
+	/// Attempts to deliver each queued appointment to the towers which have not acknowledged it
+	/// yet. Appointments which could not be delivered to some tower are kept for the next call.
+	///
+	/// This performs (potentially blocking) I/O through the [`TowerTransport`]s, and should be
+	/// called regularly from a context where that is acceptable.
+	///
+	/// [`TowerTransport`]: trait.TowerTransport.html
+	pub fn deliver_pending_appointments(&self) {
+		// Take the queue so that monitor updates aren't blocked on us while we talk to towers.
+		let mut pending: Vec<PendingAppointment> = self.pending_appointments.lock().unwrap().drain(..).collect();
+		for pending_appointment in pending.iter_mut() {
+			let PendingAppointment { ref appointment, ref mut undelivered_towers } = *pending_appointment;
+			undelivered_towers.retain(|idx| {
+				match self.towers[*idx].send_appointment(appointment) {
+					Ok(()) => false,
+					Err(e) => {
+						log_debug!(self.logger, "Failed to deliver appointment {} to watchtower {}, will retry: {:?}", log_bytes!(appointment.appointment.locator), idx, e);
+						true
+					},
+				}
+			});
+		}
+		pending.retain(|pending_appointment| !pending_appointment.undelivered_towers.is_empty());
+
+		// Appointments queued while we were delivering go after those we're retrying.
+		let mut pending_appointments = self.pending_appointments.lock().unwrap();
+		for (idx, pending_appointment) in pending.drain(..).enumerate() {
+			pending_appointments.insert(idx, pending_appointment);
+		}
+		self.drop_excess_pending_appointments(&mut pending_appointments);
+	}
+
+	fn drop_excess_pending_appointments(&self, pending_appointments: &mut VecDeque<PendingAppointment>) {
+		while pending_appointments.len() > MAX_PENDING_APPOINTMENTS {
+			let dropped = pending_appointments.pop_front().unwrap();
+			log_error!(self.logger, "Too many undelivered watchtower appointments, dropping appointment {}", log_bytes!(dropped.appointment.appointment.locator));
+		}
+	}
+
+	fn queue_justice_txn<ChanSigner: ChannelKeys>(&self, commitment_txid: &Txid, monitor: &ChannelMonitor<ChanSigner>) {
+		let justice_txn = monitor.get_justice_txs_for_revoked(commitment_txid);
+		if justice_txn.is_empty() {
+			log_trace!(self.logger, "Nothing to claim from revoked commitment transaction {}, not queueing an appointment", commitment_txid);
+			return;
+		}
+		let mut pending_appointments = self.pending_appointments.lock().unwrap();
+		// Each justice transaction is pre-signed at a different feerate, and is only ever built
+		// once for a given commitment transaction, so its index is a unique nonce for the key.
+		for (idx, justice_tx) in justice_txn.iter().enumerate() {
+			let nonce = byte_utils::be64_to_array(idx as u64);
+			let appointment = SignedAppointment::new(Appointment::new(commitment_txid, justice_tx, nonce), &self.client_key, &self.secp_ctx);
+			pending_appointments.push_back(PendingAppointment { appointment, undelivered_towers: (0..self.towers.len()).collect() });
+		}
+		self.drop_excess_pending_appointments(&mut pending_appointments);
+		log_trace!(self.logger, "Queued {} justice transactions for revoked commitment transaction {} for {} watchtowers", justice_txn.len(), commitment_txid, self.towers.len());
+	}
+}
+
+impl<ChanSigner: ChannelKeys, P: Deref + Sync + Send, T: Deref + Sync + Send, L: Deref + Sync + Send> Persist<ChanSigner> for WatchtowerClient<P, T, L>
+	where P::Target: Persist<ChanSigner>,
+	      T::Target: TowerTransport,
+	      L::Target: Logger,
+{
+	fn persist_new_channel(&self, id: OutPoint, data: &ChannelMonitor<ChanSigner>) -> Result<(), ChannelMonitorUpdateErr> {
+		self.persister.persist_new_channel(id, data)
+	}
+
+	fn update_persisted_channel(&self, id: OutPoint, update: &ChannelMonitorUpdate, data: &ChannelMonitor<ChanSigner>) -> Result<(), ChannelMonitorUpdateErr> {
+		self.persister.update_persisted_channel(id, update, data)?;
+
+		// The monitor only keeps the justice transactions for the states revoked by its latest
+		// secret, which is the one in this update if it has one.
+		let provides_secret = update.updates.iter().any(|step| match step {
+			&ChannelMonitorUpdateStep::CommitmentSecret { .. } => true,
+			_ => false,
+		});
+		if provides_secret {
+			for commitment_txid in data.get_latest_revoked_counterparty_commitment_txids().iter() {
+				self.queue_justice_txn(commitment_txid, data);
+			}
+		}
+		Ok(())
+	}
+}
+
+/// A watchtower which stores [`Appointment`]s and broadcasts the justice transaction they contain
+/// when a matching revoked commitment transaction is seen on chain.
+///
+/// Only appointments signed by a client authorized via [`authorize_client`] are accepted, and
+/// each client may store at most a fixed number of appointments.
+///
+/// When several appointments match a revoked commitment transaction (eg the same justice
+/// transaction pre-signed at several feerates), all of them are broadcast in the order they were
+/// received, leaving it to the mempool to keep the one paying the highest fee. Appointments are
+/// kept after they have been acted upon so that the justice transactions are broadcast again if
+/// the commitment transaction is reorganized out and back in.
+///
+/// The stored appointments and authorized clients are only held in memory. The tower should be
+/// persisted (through its [`Writeable`] implementation) after each call to [`authorize_client`]
+/// and each appointment accepted via [`add_appointment`] (or [`handle_stream`]), and read back
+/// with [`WatchtowerReadArgs`] on startup. Clients consider an appointment delivered once it is
+/// acknowledged, so any appointment accepted after the last write is lost if the tower restarts.
+///
+/// [`Appointment`]: struct.Appointment.html
+/// [`authorize_client`]: #method.authorize_client
+/// [`Writeable`]: ../../util/ser/trait.Writeable.html
+/// [`add_appointment`]: #method.add_appointment
+/// [`handle_stream`]: #method.handle_stream
+/// [`WatchtowerReadArgs`]: struct.WatchtowerReadArgs.html
+pub struct Watchtower<B: Deref, L: Deref>
+	where B::Target: BroadcasterInterface,
+	      L::Target: Logger,
+{
+	state: Mutex<TowerState>,
+	max_appointments_per_client: usize,
+	broadcaster: B,
+	logger: L,
+	secp_ctx: Secp256k1<VerifyOnly>,
+}
+
+struct TowerState {
+	appointments: HashMap<[u8; 16], Vec<Vec<u8>>>,
+	/// The number of appointments stored for each authorized client.
+	appointments_per_client: HashMap<PublicKey, usize>,
+}
+
+/// The maximum size, in bytes, of an appointment's encrypted blob which a `Watchtower` accepts.
+/// This comfortably fits a justice transaction claiming every output of a commitment transaction
+/// with the maximum number of HTLCs.
+pub const MAX_APPOINTMENT_BLOB_SIZE: usize = 32 * 1024;
+
+impl<B: Deref, L: Deref> Watchtower<B, L>
+	where B::Target: BroadcasterInterface,
+	      L::Target: Logger,
+{
+	/// Creates a new `Watchtower` broadcasting justice transactions via `broadcaster` and storing
+	/// at most `max_appointments_per_client` appointments for each authorized client.
+	pub fn new(broadcaster: B, logger: L, max_appointments_per_client: usize) -> Self {
+		Watchtower {
+			state: Mutex::new(TowerState {
+				appointments: HashMap::new(),
+				appointments_per_client: HashMap::new(),
+			}),
+			max_appointments_per_client,
+			broadcaster,
+			logger,
+			secp_ctx: Secp256k1::verification_only(),
+		}
+	}
+
+	/// Allows the client with the given [`WatchtowerClient::client_id`] to store appointments.
+	///
+	/// [`WatchtowerClient::client_id`]: struct.WatchtowerClient.html#method.client_id
+	pub fn authorize_client(&self, client_id: PublicKey) {
+		self.state.lock().unwrap().appointments_per_client.entry(client_id).or_insert(0);
+	}
+
+	/// Stores an appointment. Fails if the encrypted blob is obviously malformed or too large, if
+	/// the signature is invalid, if the client is not authorized or if it has reached its
+	/// appointment limit.
+	pub fn add_appointment(&self, signed_appointment: SignedAppointment) -> Result<(), TowerError> {
+		let appointment = &signed_appointment.appointment;
+		if appointment.encrypted_blob.len() <= 8 + 16 {
+			return Err(TowerError("Appointment blob is too short"));
+		}
+		if appointment.encrypted_blob.len() > MAX_APPOINTMENT_BLOB_SIZE {
+			return Err(TowerError("Appointment blob is too large"));
+		}
+		if !signed_appointment.verify(&self.secp_ctx) {
+			return Err(TowerError("Invalid appointment signature"));
+		}
+		let mut state = self.state.lock().unwrap();
+		let state = &mut *state;
+		let client_appointments = match state.appointments_per_client.get_mut(&signed_appointment.client_id) {
+			Some(count) => count,
+			None => return Err(TowerError("Appointment from an unauthorized client")),
+		};
+		let blobs = state.appointments.entry(appointment.locator).or_insert_with(Vec::new);
+		if !blobs.contains(&appointment.encrypted_blob) {
+			if *client_appointments >= self.max_appointments_per_client {
+				return Err(TowerError("Client reached its appointment limit"));
+			}
+			*client_appointments += 1;
+			blobs.push(appointment.encrypted_blob.clone());
+		}
+		Ok(())
+	}
+
+	/// Gets the number of appointments currently stored.
+	pub fn appointment_count(&self) -> usize {
+		self.state.lock().unwrap().appointments.values().map(|blobs| blobs.len()).sum()
+	}
+
+	/// Checks the transactions in a newly connected block against stored appointments,
+	/// broadcasting the justice transactions of any revoked commitment transaction found.
+	///
+	/// Unlike `ChannelMonitor`s, a tower has no outputs to watch and thus needs to be given every
+	/// transaction in the block.
+	pub fn block_connected(&self, header: &BlockHeader, txdata: &TransactionData, height: u32) {
+		let state = self.state.lock().unwrap();
+		let appointments = &state.appointments;
+		log_trace!(self.logger, "Block {} at height {} connected, checking {} transactions against {} locators", header.block_hash(), height, txdata.len(), appointments.len());
+		for &(_, tx) in txdata.iter() {
+			let txid = tx.txid();
+			if let Some(blobs) = appointments.get(&Appointment::locator_for(&txid)) {
+				for blob in blobs.iter() {
+					let appointment = Appointment { locator: Appointment::locator_for(&txid), encrypted_blob: blob.clone() };
+					match appointment.decrypt(&txid) {
+						Some(justice_tx) => {
+							if justice_tx.input.iter().all(|input| input.previous_output.txid == txid) {
+								log_info!(self.logger, "Broadcasting justice transaction {} for revoked commitment transaction {}", justice_tx.txid(), txid);
+								self.broadcaster.broadcast_transaction(&justice_tx);
+							} else {
+								log_error!(self.logger, "Decrypted an appointment for {} which does not spend it", txid);
+							}
+						},
+						None => {
+							log_trace!(self.logger, "Failed to decrypt an appointment matching the locator of {}", txid);
+						},
+					}
+				}
+			}
+		}
+	}
+
+	/// Serves appointments read from the given stream, acknowledging each with a single byte (0 if
+	/// it was stored, 1 if it was rejected), until the stream is closed.
+	///
+	/// Each appointment is expected to be serialized as with [`SignedAppointment::write`]. This is
+	/// the tower side of [`StreamTowerTransport`], and may be run on a `TcpStream` accepted from a
+	/// `TcpListener`, in which case a read timeout should be set so that idle clients don't hold on
+	/// to the serving thread forever.
+	///
+	/// [`SignedAppointment::write`]: struct.SignedAppointment.html#method.write
+	/// [`StreamTowerTransport`]: struct.StreamTowerTransport.html
+	pub fn handle_stream<S: Read + Write>(&self, stream: &mut S) -> Result<(), DecodeError> {
+		loop {
+			let appointment: SignedAppointment = match Readable::read(stream) {
+				Ok(appointment) => appointment,
+				Err(DecodeError::ShortRead) => return Ok(()),
+				Err(e) => return Err(e),
+			};
+			let ack = match self.add_appointment(appointment) {
+				Ok(()) => 0u8,
+				Err(e) => {
+					log_debug!(self.logger, "Rejected appointment: {:?}", e);
+					1u8
+				},
+			};
+			stream.write_all(&[ack])?;
+			stream.flush()?;
+		}
+	}
+}
+
+impl<B: Deref, L: Deref> Writeable for Watchtower<B, L>
+	where B::Target: BroadcasterInterface,
+	      L::Target: Logger,
+{
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		let state = self.state.lock().unwrap();
+		(state.appointments.len() as u64).write(writer)?;
+		for (locator, blobs) in state.appointments.iter() {
+			locator.write(writer)?;
+			(blobs.len() as u64).write(writer)?;
+			for blob in blobs.iter() {
+				blob.write(writer)?;
+			}
+		}
+		(state.appointments_per_client.len() as u64).write(writer)?;
+		for (client_id, count) in state.appointments_per_client.iter() {
+			client_id.write(writer)?;
+			(*count as u64).write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+/// Arguments for the deserialization of a Watchtower which are not serialized.
+pub struct WatchtowerReadArgs<B: Deref, L: Deref>
+	where B::Target: BroadcasterInterface,
+	      L::Target: Logger,
+{
+	/// The broadcaster justice transactions are handed to.
+	pub broadcaster: B,
+	/// The logger.
+	pub logger: L,
+	/// The maximum number of appointments stored for each authorized client. Clients which
+	/// already store more appointments than this keep them, but can't add new ones.
+	pub max_appointments_per_client: usize,
+}
+
+impl<B: Deref, L: Deref> ReadableArgs<WatchtowerReadArgs<B, L>> for Watchtower<B, L>
+	where B::Target: BroadcasterInterface,
+	      L::Target: Logger,
+{
+	fn read<R: Read>(reader: &mut R, args: WatchtowerReadArgs<B, L>) -> Result<Self, DecodeError> {
+		let appointments_len: u64 = Readable::read(reader)?;
+		let mut appointments = HashMap::with_capacity(cmp::min(appointments_len as usize, 1024));
+		for _ in 0..appointments_len {
+			let locator: [u8; 16] = Readable::read(reader)?;
+			let blobs_len: u64 = Readable::read(reader)?;
+			let mut blobs = Vec::with_capacity(cmp::min(blobs_len as usize, 16));
+			for _ in 0..blobs_len {
+				blobs.push(Readable::read(reader)?);
+			}
+			if appointments.insert(locator, blobs).is_some() {
+				return Err(DecodeError::InvalidValue);
+			}
+		}
+		let clients_len: u64 = Readable::read(reader)?;
+		let mut appointments_per_client = HashMap::with_capacity(cmp::min(clients_len as usize, 1024));
+		for _ in 0..clients_len {
+			let client_id = Readable::read(reader)?;
+			let count: u64 = Readable::read(reader)?;
+			if appointments_per_client.insert(client_id, count as usize).is_some() {
+				return Err(DecodeError::InvalidValue);
+			}
+		}
+		Ok(Watchtower {
+			state: Mutex::new(TowerState { appointments, appointments_per_client }),
+			max_appointments_per_client: args.max_appointments_per_client,
+			broadcaster: args.broadcaster,
+			logger: args.logger,
+			secp_ctx: Secp256k1::verification_only(),
+		})
+	}
+}
+
+impl<B: Deref + Sync + Send, L: Deref + Sync + Send> TowerTransport for Watchtower<B, L>
+	where B::Target: BroadcasterInterface,
+	      L::Target: Logger,
+{
+	fn send_appointment(&self, appointment: &SignedAppointment) -> Result<(), TowerError> {
+		self.add_appointment(appointment.clone())
+	}
+}
+
+/// A [`TowerTransport`] which writes appointments to a byte stream (eg a `TcpStream` connected to
+/// a tower) and waits for the tower's acknowledgement. The other end of the stream should be
+/// served by [`Watchtower::handle_stream`].
+///
+/// The stream should time out reads and writes, as otherwise an unresponsive tower blocks
+/// [`WatchtowerClient::deliver_pending_appointments`] forever. [`StreamTowerTransport::connect`]
+/// sets such timeouts on a `TcpStream`.
+///
+/// [`TowerTransport`]: trait.TowerTransport.html
+/// [`Watchtower::handle_stream`]: struct.Watchtower.html#method.handle_stream
+/// [`WatchtowerClient::deliver_pending_appointments`]: struct.WatchtowerClient.html#method.deliver_pending_appointments
+/// [`StreamTowerTransport::connect`]: struct.StreamTowerTransport.html#method.connect
+pub struct StreamTowerTransport<S: Read + Write + Send> {
+	stream: Mutex<S>,
+}
+
+impl<S: Read + Write + Send> StreamTowerTransport<S> {
+	/// Creates a new `StreamTowerTransport` over the given (connected) stream.
+	pub fn new(stream: S) -> Self {
+		StreamTowerTransport { stream: Mutex::new(stream) }
+	}
+}
+
+impl StreamTowerTransport<TcpStream> {
+	/// Connects to the tower at `addr`, timing out the connection attempt as well as any later
+	/// read or write after `timeout`.
+	pub fn connect(addr: &SocketAddr, timeout: Duration) -> io::Result<Self> {
+		let stream = TcpStream::connect_timeout(addr, timeout)?;
+		stream.set_read_timeout(Some(timeout))?;
+		stream.set_write_timeout(Some(timeout))?;
+		Ok(Self::new(stream))
+	}
+}
+
+impl<S: Read + Write + Send> TowerTransport for StreamTowerTransport<S> {
+	fn send_appointment(&self, appointment: &SignedAppointment) -> Result<(), TowerError> {
+		let mut stream = self.stream.lock().unwrap();
+		if appointment.write(&mut *stream).is_err() || stream.flush().is_err() {
+			return Err(TowerError("Failed to write appointment to the stream"));
+		}
+		let mut ack = [0; 1];
+		if stream.read_exact(&mut ack).is_err() {
+			return Err(TowerError("Failed to read appointment acknowledgement from the stream"));
+		}
+		match ack[0] {
+			0 => Ok(()),
+			_ => Err(TowerError("Appointment rejected by the tower")),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::blockdata::block::BlockHeader;
+	use bitcoin::blockdata::script::Script;
+	use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut, OutPoint as BitcoinOutPoint};
+	use bitcoin::consensus::encode;
+	use bitcoin::hash_types::Txid;
+	use bitcoin::hashes::Hash;
+	use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+	use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+	use bitcoin::secp256k1::Secp256k1;
+
+	use chain::watchtower::{Appointment, SignedAppointment, StreamTowerTransport, TowerTransport, Watchtower, WatchtowerReadArgs, MAX_APPOINTMENT_BLOB_SIZE};
+	use util::ser::{ReadableArgs, Writeable};
+	use util::test_utils::{TestBroadcaster, TestLogger};
+
+	use std::io::Cursor;
+	use std::net::TcpListener;
+	use std::sync::{Arc, Mutex};
+	use std::thread;
+	use std::time::Duration;
+
+	fn dummy_tx(prev_txid: Txid, value: u64) -> Transaction {
+		Transaction {
+			version: 2,
+			lock_time: 0,
+			input: vec![TxIn {
+				previous_output: BitcoinOutPoint { txid: prev_txid, vout: 0 },
+				script_sig: Script::new(),
+				sequence: 0xfffffffd,
+				witness: vec![vec![42; 72]],
+			}],
+			output: vec![TxOut { script_pubkey: Script::new(), value }],
+		}
+	}
+
+	#[test]
+	fn appointment_encryption() {
+		let commitment_tx = dummy_tx(Txid::from_hash(Sha256dHash::hash(&[1; 32])), 10_000);
+		let justice_tx = dummy_tx(commitment_tx.txid(), 9_000);
+		let appointment = Appointment::new(&commitment_tx.txid(), &justice_tx, [0; 8]);
+		assert_eq!(&appointment.locator[..], &commitment_tx.txid()[..16]);
+		assert_eq!(appointment.decrypt(&commitment_tx.txid()), Some(justice_tx.clone()));
+
+		// Another justice transaction for the same commitment transaction uses its own keystream
+		let other_justice_tx = dummy_tx(commitment_tx.txid(), 8_000);
+		let other_appointment = Appointment::new(&commitment_tx.txid(), &other_justice_tx, [1; 8]);
+		assert_eq!(other_appointment.decrypt(&commitment_tx.txid()), Some(other_justice_tx.clone()));
+		let keystream = |a: &Appointment, tx: &Transaction| -> Vec<u8> {
+			a.encrypted_blob[8..].iter().zip(encode::serialize(tx).iter()).map(|(c, p)| c ^ p).collect()
+		};
+		assert_ne!(keystream(&appointment, &justice_tx), keystream(&other_appointment, &other_justice_tx));
+
+		// A txid sharing the locator but not the rest can't decrypt the blob
+		let mut other_txid = commitment_tx.txid().into_inner();
+		other_txid[31] ^= 1;
+		assert_eq!(appointment.decrypt(&Txid::from_inner(other_txid)), None);
+		assert_eq!(appointment.decrypt(&justice_tx.txid()), None);
+	}
+
+	#[test]
+	fn tower_client_authorization() {
+		let secp_ctx = Secp256k1::new();
+		let broadcaster = TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) };
+		let logger = TestLogger::new();
+		let tower = Watchtower::new(&broadcaster, &logger, 2);
+		let client_key = SecretKey::from_slice(&[42; 32]).unwrap();
+		let other_key = SecretKey::from_slice(&[43; 32]).unwrap();
+
+		let appointment_for = |seed: u8| {
+			let commitment_tx = dummy_tx(Txid::from_hash(Sha256dHash::hash(&[seed; 32])), 10_000);
+			Appointment::new(&commitment_tx.txid(), &dummy_tx(commitment_tx.txid(), 9_000), [0; 8])
+		};
+
+		// Appointments from clients which weren't authorized are rejected
+		assert!(tower.send_appointment(&SignedAppointment::new(appointment_for(1), &client_key, &secp_ctx)).is_err());
+		tower.authorize_client(PublicKey::from_secret_key(&secp_ctx, &client_key));
+		tower.send_appointment(&SignedAppointment::new(appointment_for(1), &client_key, &secp_ctx)).unwrap();
+		assert!(tower.send_appointment(&SignedAppointment::new(appointment_for(2), &other_key, &secp_ctx)).is_err());
+
+		// A signature from another key doesn't pass for the authorized client
+		let mut forged = SignedAppointment::new(appointment_for(2), &other_key, &secp_ctx);
+		forged.client_id = PublicKey::from_secret_key(&secp_ctx, &client_key);
+		assert!(tower.send_appointment(&forged).is_err());
+		assert_eq!(tower.appointment_count(), 1);
+
+		// Resending a stored appointment doesn't count against the limit, new ones past it fail
+		tower.send_appointment(&SignedAppointment::new(appointment_for(1), &client_key, &secp_ctx)).unwrap();
+		tower.send_appointment(&SignedAppointment::new(appointment_for(2), &client_key, &secp_ctx)).unwrap();
+		assert!(tower.send_appointment(&SignedAppointment::new(appointment_for(3), &client_key, &secp_ctx)).is_err());
+		assert_eq!(tower.appointment_count(), 2);
+	}
+
+	#[test]
+	fn tower_over_tcp() {
+		let secp_ctx = Secp256k1::new();
+		let client_key = SecretKey::from_slice(&[42; 32]).unwrap();
+		let broadcaster = Arc::new(TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) });
+		let logger = Arc::new(TestLogger::new());
+		let tower = Arc::new(Watchtower::new(Arc::clone(&broadcaster), Arc::clone(&logger), 10));
+		tower.authorize_client(PublicKey::from_secret_key(&secp_ctx, &client_key));
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let server_tower = Arc::clone(&tower);
+		let server = thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+			server_tower.handle_stream(&mut stream).unwrap();
+		});
+
+		let commitment_tx = dummy_tx(Txid::from_hash(Sha256dHash::hash(&[2; 32])), 10_000);
+		let justice_tx = dummy_tx(commitment_tx.txid(), 9_000);
+		{
+			let transport = StreamTowerTransport::connect(&addr, Duration::from_secs(10)).unwrap();
+			transport.send_appointment(&SignedAppointment::new(Appointment::new(&commitment_tx.txid(), &justice_tx, [0; 8]), &client_key, &secp_ctx)).unwrap();
+			let oversized_appointment = Appointment { locator: [0; 16], encrypted_blob: vec![0; MAX_APPOINTMENT_BLOB_SIZE + 1] };
+			assert!(transport.send_appointment(&SignedAppointment::new(oversized_appointment, &client_key, &secp_ctx)).is_err());
+		}
+		server.join().unwrap();
+		assert_eq!(tower.appointment_count(), 1);
+
+		// Unrelated transactions don't trigger anything, the revoked commitment transaction does
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		let unrelated_tx = dummy_tx(Txid::from_hash(Sha256dHash::hash(&[3; 32])), 5_000);
+		tower.block_connected(&header, &[(0, &unrelated_tx)], 1);
+		assert!(broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+		tower.block_connected(&header, &[(0, &unrelated_tx), (1, &commitment_tx)], 2);
+		assert_eq!(*broadcaster.txn_broadcasted.lock().unwrap(), vec![justice_tx]);
+	}
+
+	#[test]
+	fn tower_persistence() {
+		let secp_ctx = Secp256k1::new();
+		let client_key = SecretKey::from_slice(&[42; 32]).unwrap();
+		let broadcaster = TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) };
+		let logger = TestLogger::new();
+		let commitment_tx = dummy_tx(Txid::from_hash(Sha256dHash::hash(&[1; 32])), 10_000);
+		let justice_tx = dummy_tx(commitment_tx.txid(), 9_000);
+
+		let encoded_tower = {
+			let tower = Watchtower::new(&broadcaster, &logger, 1);
+			tower.authorize_client(PublicKey::from_secret_key(&secp_ctx, &client_key));
+			tower.add_appointment(SignedAppointment::new(Appointment::new(&commitment_tx.txid(), &justice_tx, [0; 8]), &client_key, &secp_ctx)).unwrap();
+			tower.encode()
+		};
+
+		// Both the appointment and the client's appointment count survive a restart
+		let tower: Watchtower<&TestBroadcaster, &TestLogger> = ReadableArgs::read(&mut Cursor::new(&encoded_tower), WatchtowerReadArgs {
+			broadcaster: &broadcaster,
+			logger: &logger,
+			max_appointments_per_client: 1,
+		}).unwrap();
+		assert_eq!(tower.appointment_count(), 1);
+		let other_commitment_tx = dummy_tx(Txid::from_hash(Sha256dHash::hash(&[2; 32])), 10_000);
+		let other_appointment = Appointment::new(&other_commitment_tx.txid(), &dummy_tx(other_commitment_tx.txid(), 9_000), [0; 8]);
+		assert!(tower.add_appointment(SignedAppointment::new(other_appointment, &client_key, &secp_ctx)).is_err());
+
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		tower.block_connected(&header, &[(0, &commitment_tx)], 1);
+		assert_eq!(*broadcaster.txn_broadcasted.lock().unwrap(), vec![justice_tx]);
+	}
+}
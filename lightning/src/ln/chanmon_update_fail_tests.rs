@@ -18,7 +18,7 @@ use bitcoin::network::constants::Network;
 use chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdateErr};
 use chain::transaction::OutPoint;
 use chain::Watch;
-use ln::channelmanager::{RAACommitmentOrder, PaymentPreimage, PaymentHash, PaymentSecret, PaymentSendFailure};
+use ln::channelmanager::{RAACommitmentOrder, PaymentPreimage, PaymentHash, PaymentSecret, PaymentId, PaymentSendFailure};
 use ln::features::InitFeatures;
 use ln::msgs;
 use ln::msgs::{ChannelMessageHandler, ErrorAction, RoutingMessageHandler};
@@ -54,7 +54,7 @@ fn do_test_simple_monitor_permanent_update_fail(persister_fail: bool) {
 	}
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-	unwrap_send_err!(nodes[0].node.send_payment(&route, payment_hash_1, &None), true, APIError::ChannelUnavailable {..}, {});
+	unwrap_send_err!(nodes[0].node.send_payment(&route, payment_hash_1, &None, PaymentId(payment_hash_1.0)), true, APIError::ChannelUnavailable {..}, {});
 	check_added_monitors!(nodes[0], 2);
 
 	let events_1 = nodes[0].node.get_and_clear_pending_msg_events();
@@ -171,7 +171,7 @@ fn do_test_simple_monitor_temporary_update_fail(disconnect: bool, persister_fail
 	{
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		unwrap_send_err!(nodes[0].node.send_payment(&route, payment_hash_1, &None), false, APIError::MonitorUpdateFailed, {});
+		unwrap_send_err!(nodes[0].node.send_payment(&route, payment_hash_1, &None, PaymentId(payment_hash_1.0)), false, APIError::MonitorUpdateFailed, {});
 		check_added_monitors!(nodes[0], 1);
 	}
 
@@ -205,7 +205,7 @@ fn do_test_simple_monitor_temporary_update_fail(disconnect: bool, persister_fail
 	let events_3 = nodes[1].node.get_and_clear_pending_events();
 	assert_eq!(events_3.len(), 1);
 	match events_3[0] {
-		Event::PaymentReceived { ref payment_hash, ref payment_secret, amt } => {
+		Event::PaymentReceived { ref payment_hash, ref payment_secret, amt, .. } => {
 			assert_eq!(payment_hash_1, *payment_hash);
 			assert_eq!(*payment_secret, None);
 			assert_eq!(amt, 1000000);
@@ -224,7 +224,7 @@ fn do_test_simple_monitor_temporary_update_fail(disconnect: bool, persister_fail
 		}
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		unwrap_send_err!(nodes[0].node.send_payment(&route, payment_hash_2, &None), false, APIError::MonitorUpdateFailed, {});
+		unwrap_send_err!(nodes[0].node.send_payment(&route, payment_hash_2, &None, PaymentId(payment_hash_2.0)), false, APIError::MonitorUpdateFailed, {});
 		check_added_monitors!(nodes[0], 1);
 	}
 
@@ -294,7 +294,7 @@ fn do_test_monitor_temporary_update_fail(disconnect_count: usize) {
 		*nodes[0].chain_monitor.update_ret.lock().unwrap() = Some(Err(ChannelMonitorUpdateErr::TemporaryFailure));
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		unwrap_send_err!(nodes[0].node.send_payment(&route, payment_hash_2, &None), false, APIError::MonitorUpdateFailed, {});
+		unwrap_send_err!(nodes[0].node.send_payment(&route, payment_hash_2, &None, PaymentId(payment_hash_2.0)), false, APIError::MonitorUpdateFailed, {});
 		check_added_monitors!(nodes[0], 1);
 	}
 
@@ -573,7 +573,7 @@ fn do_test_monitor_temporary_update_fail(disconnect_count: usize) {
 	let events_5 = nodes[1].node.get_and_clear_pending_events();
 	assert_eq!(events_5.len(), 1);
 	match events_5[0] {
-		Event::PaymentReceived { ref payment_hash, ref payment_secret, amt } => {
+		Event::PaymentReceived { ref payment_hash, ref payment_secret, amt, .. } => {
 			assert_eq!(payment_hash_2, *payment_hash);
 			assert_eq!(*payment_secret, None);
 			assert_eq!(amt, 1000000);
@@ -625,7 +625,7 @@ fn test_monitor_update_fail_cs() {
 	{
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+		nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 	}
 
@@ -687,7 +687,7 @@ fn test_monitor_update_fail_cs() {
 	let events = nodes[1].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 1);
 	match events[0] {
-		Event::PaymentReceived { payment_hash, payment_secret, amt } => {
+		Event::PaymentReceived { payment_hash, payment_secret, amt, .. } => {
 			assert_eq!(payment_hash, our_payment_hash);
 			assert_eq!(payment_secret, None);
 			assert_eq!(amt, 1000000);
@@ -714,7 +714,7 @@ fn test_monitor_update_fail_no_rebroadcast() {
 	{
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+		nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 	}
 
@@ -765,7 +765,7 @@ fn test_monitor_update_raa_while_paused() {
 	{
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, our_payment_hash_1, &None).unwrap();
+		nodes[0].node.send_payment(&route, our_payment_hash_1, &None, PaymentId(our_payment_hash_1.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 	}
 	let send_event_1 = SendEvent::from_event(nodes[0].node.get_and_clear_pending_msg_events().remove(0));
@@ -774,7 +774,7 @@ fn test_monitor_update_raa_while_paused() {
 	{
 		let net_graph_msg_handler = &nodes[1].net_graph_msg_handler;
 		let route = get_route(&nodes[1].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[0].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[1].node.send_payment(&route, our_payment_hash_2, &None).unwrap();
+		nodes[1].node.send_payment(&route, our_payment_hash_2, &None, PaymentId(our_payment_hash_2.0)).unwrap();
 		check_added_monitors!(nodes[1], 1);
 	}
 	let send_event_2 = SendEvent::from_event(nodes[1].node.get_and_clear_pending_msg_events().remove(0));
@@ -866,7 +866,7 @@ fn do_test_monitor_update_fail_raa(test_ignore_second_cs: bool) {
 	{
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, payment_hash_2, &None).unwrap();
+		nodes[0].node.send_payment(&route, payment_hash_2, &None, PaymentId(payment_hash_2.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 	}
 
@@ -893,7 +893,7 @@ fn do_test_monitor_update_fail_raa(test_ignore_second_cs: bool) {
 	{
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, payment_hash_3, &None).unwrap();
+		nodes[0].node.send_payment(&route, payment_hash_3, &None, PaymentId(payment_hash_3.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 	}
 
@@ -942,7 +942,7 @@ fn do_test_monitor_update_fail_raa(test_ignore_second_cs: bool) {
 		let (payment_preimage_4, payment_hash_4) = get_payment_preimage_hash!(nodes[0]);
 		let net_graph_msg_handler = &nodes[2].net_graph_msg_handler;
 		let route = get_route(&nodes[2].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[0].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[2].node.send_payment(&route, payment_hash_4, &None).unwrap();
+		nodes[2].node.send_payment(&route, payment_hash_4, &None, PaymentId(payment_hash_4.0)).unwrap();
 		check_added_monitors!(nodes[2], 1);
 
 		send_event = SendEvent::from_event(nodes[2].node.get_and_clear_pending_msg_events().remove(0));
@@ -1196,9 +1196,9 @@ fn raa_no_response_awaiting_raa_state() {
 	{
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, payment_hash_1, &None).unwrap();
+		nodes[0].node.send_payment(&route, payment_hash_1, &None, PaymentId(payment_hash_1.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
-		nodes[0].node.send_payment(&route, payment_hash_2, &None).unwrap();
+		nodes[0].node.send_payment(&route, payment_hash_2, &None, PaymentId(payment_hash_2.0)).unwrap();
 		check_added_monitors!(nodes[0], 0);
 	}
 
@@ -1250,7 +1250,7 @@ fn raa_no_response_awaiting_raa_state() {
 	{
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, payment_hash_3, &None).unwrap();
+		nodes[0].node.send_payment(&route, payment_hash_3, &None, PaymentId(payment_hash_3.0)).unwrap();
 		check_added_monitors!(nodes[0], 0);
 		assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
 	}
@@ -1342,7 +1342,7 @@ fn claim_while_disconnected_monitor_update_fail() {
 	{
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, payment_hash_2, &None).unwrap();
+		nodes[0].node.send_payment(&route, payment_hash_2, &None, PaymentId(payment_hash_2.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 	}
 
@@ -1438,7 +1438,7 @@ fn monitor_failed_no_reestablish_response() {
 	{
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, payment_hash_1, &None).unwrap();
+		nodes[0].node.send_payment(&route, payment_hash_1, &None, PaymentId(payment_hash_1.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 	}
 
@@ -1512,7 +1512,7 @@ fn first_message_on_recv_ordering() {
 	{
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, payment_hash_1, &None).unwrap();
+		nodes[0].node.send_payment(&route, payment_hash_1, &None, PaymentId(payment_hash_1.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 	}
 
@@ -1537,7 +1537,7 @@ fn first_message_on_recv_ordering() {
 	{
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, payment_hash_2, &None).unwrap();
+		nodes[0].node.send_payment(&route, payment_hash_2, &None, PaymentId(payment_hash_2.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 	}
 	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
@@ -1617,7 +1617,7 @@ fn test_monitor_update_fail_claim() {
 	{
 		let net_graph_msg_handler = &nodes[2].net_graph_msg_handler;
 		let route = get_route(&nodes[2].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[0].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[2].node.send_payment(&route, payment_hash_2, &None).unwrap();
+		nodes[2].node.send_payment(&route, payment_hash_2, &None, PaymentId(payment_hash_2.0)).unwrap();
 		check_added_monitors!(nodes[2], 1);
 	}
 
@@ -1702,7 +1702,7 @@ fn test_monitor_update_on_pending_forwards() {
 	{
 		let net_graph_msg_handler = &nodes[2].net_graph_msg_handler;
 		let route = get_route(&nodes[2].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[0].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[2].node.send_payment(&route, payment_hash_2, &None).unwrap();
+		nodes[2].node.send_payment(&route, payment_hash_2, &None, PaymentId(payment_hash_2.0)).unwrap();
 		check_added_monitors!(nodes[2], 1);
 	}
 
@@ -1765,7 +1765,7 @@ fn monitor_update_claim_fail_no_response() {
 	{
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, payment_hash_2, &None).unwrap();
+		nodes[0].node.send_payment(&route, payment_hash_2, &None, PaymentId(payment_hash_2.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 	}
 
@@ -1949,7 +1949,7 @@ fn test_path_paused_mpp() {
 	// Now check that we get the right return value, indicating that the first path succeeded but
 	// the second got a MonitorUpdateFailed err. This implies PaymentSendFailure::PartialFailure as
 	// some paths succeeded, preventing retry.
-	if let Err(PaymentSendFailure::PartialFailure(results)) = nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)) {
+	if let Err(PaymentSendFailure::PartialFailure(results)) = nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret), PaymentId(payment_hash.0)) {
 		assert_eq!(results.len(), 2);
 		if let Ok(()) = results[0] {} else { panic!(); }
 		if let Err(APIError::MonitorUpdateFailed) = results[1] {} else { panic!(); }
@@ -162,6 +162,8 @@ struct OutboundHTLCOutput {
 	payment_hash: PaymentHash,
 	state: OutboundHTLCState,
 	source: HTLCSource,
+	/// The blinding point to send alongside the HTLC, if we're forwarding it within a blinded path.
+	blinding_point: Option<PublicKey>,
 }
 
 /// See AwaitingRemoteRevoke ChannelState for more info
@@ -173,6 +175,7 @@ enum HTLCUpdateAwaitingACK {
 		payment_hash: PaymentHash,
 		source: HTLCSource,
 		onion_routing_packet: msgs::OnionPacket,
+		blinding_point: Option<PublicKey>,
 	},
 	ClaimHTLC {
 		payment_preimage: PaymentPreimage,
@@ -2165,8 +2168,8 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 				// handling this case better and maybe fulfilling some of the HTLCs while attempting
 				// to rebalance channels.
 				match &htlc_update {
-					&HTLCUpdateAwaitingACK::AddHTLC {amount_msat, cltv_expiry, ref payment_hash, ref source, ref onion_routing_packet, ..} => {
-						match self.send_htlc(amount_msat, *payment_hash, cltv_expiry, source.clone(), onion_routing_packet.clone()) {
+					&HTLCUpdateAwaitingACK::AddHTLC {amount_msat, cltv_expiry, ref payment_hash, ref source, ref onion_routing_packet, blinding_point} => {
+						match self.send_htlc(amount_msat, *payment_hash, cltv_expiry, source.clone(), onion_routing_packet.clone(), blinding_point) {
 							Ok(update_add_msg_option) => update_add_htlcs.push(update_add_msg_option.unwrap()),
 							Err(e) => {
 								match e {
@@ -2696,6 +2699,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 					payment_hash: htlc.payment_hash,
 					cltv_expiry: htlc.cltv_expiry,
 					onion_routing_packet: (**onion_packet).clone(),
+					blinding_point: htlc.blinding_point,
 				});
 			}
 		}
@@ -3167,7 +3171,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			// Upper bound by capacity. We make it a bit less than full capacity to prevent attempts
 			// to use full capacity. This is an effort to reduce routing failures, because in many cases
 			// channel might have been used to route very small values (either by honest users or as DoS).
-			self.channel_value_satoshis * 9 / 10,
+			self.channel_value_satoshis * 1000 * 9 / 10,
 
 			Channel::<ChanSigner>::get_holder_max_htlc_value_in_flight_msat(self.channel_value_satoshis)
 		);
@@ -3208,6 +3212,14 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 		&self.holder_keys
 	}
 
+	pub fn get_holder_pubkeys(&self) -> &ChannelPublicKeys {
+		self.holder_keys.pubkeys()
+	}
+
+	pub fn get_key_derivation_params(&self) -> (u64, u64) {
+		self.holder_keys.key_derivation_params()
+	}
+
 	#[cfg(test)]
 	pub fn get_value_stat(&self) -> ChannelValueStat {
 		ChannelValueStat {
@@ -3700,7 +3712,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 	/// HTLCs on the wire or we wouldn't be able to determine what they actually ACK'ed.
 	/// You MUST call send_commitment prior to any other calls on this Channel
 	/// If an Err is returned, it's a ChannelError::Ignore!
-	pub fn send_htlc(&mut self, amount_msat: u64, payment_hash: PaymentHash, cltv_expiry: u32, source: HTLCSource, onion_routing_packet: msgs::OnionPacket) -> Result<Option<msgs::UpdateAddHTLC>, ChannelError> {
+	pub fn send_htlc(&mut self, amount_msat: u64, payment_hash: PaymentHash, cltv_expiry: u32, source: HTLCSource, onion_routing_packet: msgs::OnionPacket, blinding_point: Option<PublicKey>) -> Result<Option<msgs::UpdateAddHTLC>, ChannelError> {
 		if (self.channel_state & (ChannelState::ChannelFunded as u32 | BOTH_SIDES_SHUTDOWN_MASK)) != (ChannelState::ChannelFunded as u32) {
 			return Err(ChannelError::Ignore("Cannot send HTLC until channel is fully established and we haven't started shutting down".to_owned()));
 		}
@@ -3777,6 +3789,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 				cltv_expiry,
 				source,
 				onion_routing_packet,
+				blinding_point,
 			});
 			return Ok(None);
 		}
@@ -3788,6 +3801,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			cltv_expiry,
 			state: OutboundHTLCState::LocalAnnounced(Box::new(onion_routing_packet.clone())),
 			source,
+			blinding_point,
 		});
 
 		let res = msgs::UpdateAddHTLC {
@@ -3797,6 +3811,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			payment_hash,
 			cltv_expiry,
 			onion_routing_packet,
+			blinding_point,
 		};
 		self.next_holder_htlc_id += 1;
 
@@ -3936,8 +3951,8 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 	/// to send to the remote peer in one go.
 	/// Shorthand for calling send_htlc() followed by send_commitment(), see docs on those for
 	/// more info.
-	pub fn send_htlc_and_commit<L: Deref>(&mut self, amount_msat: u64, payment_hash: PaymentHash, cltv_expiry: u32, source: HTLCSource, onion_routing_packet: msgs::OnionPacket, logger: &L) -> Result<Option<(msgs::UpdateAddHTLC, msgs::CommitmentSigned, ChannelMonitorUpdate)>, ChannelError> where L::Target: Logger {
-		match self.send_htlc(amount_msat, payment_hash, cltv_expiry, source, onion_routing_packet)? {
+	pub fn send_htlc_and_commit<L: Deref>(&mut self, amount_msat: u64, payment_hash: PaymentHash, cltv_expiry: u32, source: HTLCSource, onion_routing_packet: msgs::OnionPacket, blinding_point: Option<PublicKey>, logger: &L) -> Result<Option<(msgs::UpdateAddHTLC, msgs::CommitmentSigned, ChannelMonitorUpdate)>, ChannelError> where L::Target: Logger {
+		match self.send_htlc(amount_msat, payment_hash, cltv_expiry, source, onion_routing_packet, blinding_point)? {
 			Some(update_add_htlc) => {
 				let (commitment_signed, monitor_update) = self.send_commitment_no_status_check(logger)?;
 				Ok(Some((update_add_htlc, commitment_signed, monitor_update)))
@@ -4168,7 +4183,7 @@ impl<ChanSigner: ChannelKeys + Writeable> Writeable for Channel<ChanSigner> {
 		(self.holding_cell_htlc_updates.len() as u64).write(writer)?;
 		for update in self.holding_cell_htlc_updates.iter() {
 			match update {
-				&HTLCUpdateAwaitingACK::AddHTLC { ref amount_msat, ref cltv_expiry, ref payment_hash, ref source, ref onion_routing_packet } => {
+				&HTLCUpdateAwaitingACK::AddHTLC { ref amount_msat, ref cltv_expiry, ref payment_hash, ref source, ref onion_routing_packet, .. } => {
 					0u8.write(writer)?;
 					amount_msat.write(writer)?;
 					cltv_expiry.write(writer)?;
@@ -4256,6 +4271,15 @@ impl<ChanSigner: ChannelKeys + Writeable> Writeable for Channel<ChanSigner> {
 		self.counterparty_shutdown_scriptpubkey.write(writer)?;
 
 		self.commitment_secrets.write(writer)?;
+
+		for htlc in self.pending_outbound_htlcs.iter() {
+			htlc.blinding_point.write(writer)?;
+		}
+		for update in self.holding_cell_htlc_updates.iter() {
+			if let &HTLCUpdateAwaitingACK::AddHTLC { ref blinding_point, .. } = update {
+				blinding_point.write(writer)?;
+			}
+		}
 		Ok(())
 	}
 }
@@ -4313,6 +4337,7 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for Channel<ChanSigner> {
 				cltv_expiry: Readable::read(reader)?,
 				payment_hash: Readable::read(reader)?,
 				source: Readable::read(reader)?,
+				blinding_point: None,
 				state: match <u8 as Readable>::read(reader)? {
 					0 => OutboundHTLCState::LocalAnnounced(Box::new(Readable::read(reader)?)),
 					1 => OutboundHTLCState::Committed,
@@ -4334,6 +4359,7 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for Channel<ChanSigner> {
 					payment_hash: Readable::read(reader)?,
 					source: Readable::read(reader)?,
 					onion_routing_packet: Readable::read(reader)?,
+					blinding_point: None,
 				},
 				1 => HTLCUpdateAwaitingACK::ClaimHTLC {
 					payment_preimage: Readable::read(reader)?,
@@ -4410,6 +4436,15 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for Channel<ChanSigner> {
 		let counterparty_shutdown_scriptpubkey = Readable::read(reader)?;
 		let commitment_secrets = Readable::read(reader)?;
 
+		for htlc in pending_outbound_htlcs.iter_mut() {
+			htlc.blinding_point = Readable::read(reader)?;
+		}
+		for update in holding_cell_htlc_updates.iter_mut() {
+			if let &mut HTLCUpdateAwaitingACK::AddHTLC { ref mut blinding_point, .. } = update {
+				*blinding_point = Readable::read(reader)?;
+			}
+		}
+
 		Ok(Channel {
 			user_id,
 
@@ -4827,6 +4862,7 @@ mod tests {
 				payment_hash: PaymentHash([0; 32]),
 				state: OutboundHTLCState::Committed,
 				source: HTLCSource::dummy(),
+				blinding_point: None,
 			};
 			out.payment_hash.0 = Sha256::hash(&hex::decode("0202020202020202020202020202020202020202020202020202020202020202").unwrap()).into_inner();
 			out
@@ -4839,6 +4875,7 @@ mod tests {
 				payment_hash: PaymentHash([0; 32]),
 				state: OutboundHTLCState::Committed,
 				source: HTLCSource::dummy(),
+				blinding_point: None,
 			};
 			out.payment_hash.0 = Sha256::hash(&hex::decode("0303030303030303030303030303030303030303030303030303030303030303").unwrap()).into_inner();
 			out
@@ -0,0 +1,192 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Static channel backups allow recovering the balance of our channels after losing our
+//! ChannelMonitors (and ChannelManager), as long as the seed of our KeysInterface survived.
+//!
+//! A static channel backup only changes when channels are opened or closed, not on each payment,
+//! so it is cheap to keep a copy of it elsewhere, eg on a remote server. It holds, encrypted with
+//! a key derived from our node secret, what we need to get our counterparties to close our
+//! channels and to claim our balance once they did, ie for each channel our counterparty's
+//! node id and addresses, the channel funding outpoint and the key derivation parameters of our
+//! channel keys.
+//!
+//! It is created with [`ChannelManager::get_static_channel_backup`] and recovered from with
+//! [`ChannelManager::recover_from_static_channel_backup`] on a fresh ChannelManager. The recovering
+//! ChannelManager registers the funding outpoints with the given [`chain::Filter`], if any, and then,
+//! on connection to each of our counterparties, sends a `channel_reestablish`
+//! claiming we lost all channel state (as is allowed by `option_data_loss_protect`), followed by an
+//! error, so that they force-close the channel. Once their commitment transaction confirms, an
+//! [`Event::SpendableOutputs`] is generated for our `to_remote` output, if any. Note that any
+//! funds in HTLCs are lost.
+//!
+//! [`ChannelManager::get_static_channel_backup`]: ../channelmanager/struct.ChannelManager.html#method.get_static_channel_backup
+//! [`ChannelManager::recover_from_static_channel_backup`]: ../channelmanager/struct.ChannelManager.html#method.recover_from_static_channel_backup
+//! [`Event::SpendableOutputs`]: ../../util/events/enum.Event.html#variant.SpendableOutputs
+//! [`chain::Filter`]: ../../chain/trait.Filter.html
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+
+use chain::transaction::OutPoint;
+use ln::msgs::{DecodeError, NetAddress};
+use util::chacha20poly1305rfc::ChaCha20Poly1305RFC;
+use util::ser::{Readable, Writeable, Writer};
+
+use std::cmp;
+use std::io::{Cursor, Read};
+
+const BACKUP_AAD: &[u8] = b"LDK static channel backup";
+
+/// The static data about one of our channels which we need to recover our balance in it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelBackup {
+	/// The channel id
+	pub channel_id: [u8; 32],
+	/// The node id of our counterparty
+	pub counterparty_node_id: PublicKey,
+	/// Addresses our counterparty may be reached at, if known
+	pub counterparty_addresses: Vec<NetAddress>,
+	/// The outpoint of the channel funding output
+	pub funding_outpoint: OutPoint,
+	/// The script_pubkey of the channel funding output, needed to watch for its spend
+	pub funding_script_pubkey: Script,
+	/// The value of the channel funding output
+	pub channel_value_satoshis: u64,
+	/// The key derivation parameters of our ChannelKeys for the channel, as given by
+	/// ChannelKeys::key_derivation_params, which are needed to spend our `to_remote` output.
+	pub key_derivation_params: (u64, u64),
+	/// Our payment point in the channel (ie ChannelKeys::pubkeys().payment_point), to which our
+	/// `to_remote` output pays.
+	pub holder_payment_point: PublicKey,
+}
+
+impl Writeable for ChannelBackup {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		self.channel_id.write(writer)?;
+		self.counterparty_node_id.write(writer)?;
+		(self.counterparty_addresses.len() as u16).write(writer)?;
+		for address in self.counterparty_addresses.iter() {
+			address.write(writer)?;
+		}
+		self.funding_outpoint.write(writer)?;
+		self.funding_script_pubkey.write(writer)?;
+		self.channel_value_satoshis.write(writer)?;
+		self.key_derivation_params.0.write(writer)?;
+		self.key_derivation_params.1.write(writer)?;
+		self.holder_payment_point.write(writer)?;
+		Ok(())
+	}
+}
+
+impl Readable for ChannelBackup {
+	fn read<R: Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let channel_id = Readable::read(reader)?;
+		let counterparty_node_id = Readable::read(reader)?;
+		let addresses_count: u16 = Readable::read(reader)?;
+		let mut counterparty_addresses = Vec::with_capacity(cmp::min(addresses_count as usize, 16));
+		for _ in 0..addresses_count {
+			match <Result<NetAddress, u8> as Readable>::read(reader)? {
+				Ok(address) => counterparty_addresses.push(address),
+				Err(_) => return Err(DecodeError::InvalidValue),
+			}
+		}
+		let funding_outpoint = Readable::read(reader)?;
+		let funding_script_pubkey = Readable::read(reader)?;
+		let channel_value_satoshis = Readable::read(reader)?;
+		let key_derivation_params = (Readable::read(reader)?, Readable::read(reader)?);
+		let holder_payment_point = Readable::read(reader)?;
+		Ok(ChannelBackup {
+			channel_id,
+			counterparty_node_id,
+			counterparty_addresses,
+			funding_outpoint,
+			funding_script_pubkey,
+			channel_value_satoshis,
+			key_derivation_params,
+			holder_payment_point,
+		})
+	}
+}
+
+/// A backup of the static data of all our funded channels, see the module-level documentation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StaticChannelBackup {
+	/// The channels backed up
+	pub channels: Vec<ChannelBackup>,
+}
+
+impl Writeable for StaticChannelBackup {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		(self.channels.len() as u64).write(writer)?;
+		for channel in self.channels.iter() {
+			channel.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for StaticChannelBackup {
+	fn read<R: Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let channels_count: u64 = Readable::read(reader)?;
+		let mut channels = Vec::with_capacity(cmp::min(channels_count as usize, 64));
+		for _ in 0..channels_count {
+			channels.push(Readable::read(reader)?);
+		}
+		Ok(StaticChannelBackup { channels })
+	}
+}
+
+fn backup_key(node_secret: &SecretKey) -> [u8; 32] {
+	let mut sha = Sha256::engine();
+	sha.input(BACKUP_AAD);
+	sha.input(&node_secret[..]);
+	Sha256::from_engine(sha).into_inner()
+}
+
+// Our ChaCha20 only supports 64-bit nonces, which are prefixed with 4 zero bytes.
+fn chacha_nonce(nonce: &[u8]) -> [u8; 12] {
+	let mut res = [0; 12];
+	res[4..].copy_from_slice(nonce);
+	res
+}
+
+impl StaticChannelBackup {
+	/// Encrypts the backup with a key derived from the given node secret (ie
+	/// KeysInterface::get_node_secret), using the given nonce, which must never be reused.
+	pub fn encrypt(&self, node_secret: &SecretKey, nonce: [u8; 8]) -> Vec<u8> {
+		let plaintext = self.encode();
+		let mut res = vec![0; 8 + plaintext.len() + 16];
+		res[0..8].copy_from_slice(&nonce);
+		let mut chacha = ChaCha20Poly1305RFC::new(&backup_key(node_secret), &chacha_nonce(&nonce), BACKUP_AAD);
+		let mut tag = [0; 16];
+		chacha.encrypt(&plaintext, &mut res[8..8 + plaintext.len()], &mut tag);
+		res[8 + plaintext.len()..].copy_from_slice(&tag);
+		res
+	}
+
+	/// Decrypts a backup created by [`encrypt`] with the same node secret.
+	///
+	/// [`encrypt`]: #method.encrypt
+	pub fn decrypt(data: &[u8], node_secret: &SecretKey) -> Result<Self, DecodeError> {
+		if data.len() < 8 + 16 { return Err(DecodeError::ShortRead); }
+		let ciphertext_len = data.len() - 8 - 16;
+		let mut plaintext = vec![0; ciphertext_len];
+		let mut chacha = ChaCha20Poly1305RFC::new(&backup_key(node_secret), &chacha_nonce(&data[0..8]), BACKUP_AAD);
+		if !chacha.decrypt(&data[8..8 + ciphertext_len], &mut plaintext, &data[8 + ciphertext_len..]) {
+			return Err(DecodeError::InvalidValue);
+		}
+		let mut reader = Cursor::new(&plaintext);
+		let res = Readable::read(&mut reader)?;
+		if reader.position() != plaintext.len() as u64 { return Err(DecodeError::InvalidValue); }
+		Ok(res)
+	}
+}
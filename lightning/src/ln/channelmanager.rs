@@ -20,6 +20,8 @@
 
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::blockdata::opcodes;
 use bitcoin::network::constants::Network;
 
 use bitcoin::hashes::{Hash, HashEngine};
@@ -27,7 +29,7 @@ use bitcoin::hashes::hmac::{Hmac, HmacEngine};
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use bitcoin::hashes::cmp::fixed_time_eq;
-use bitcoin::hash_types::BlockHash;
+use bitcoin::hash_types::{BlockHash, WPubkeyHash};
 
 use bitcoin::secp256k1::key::{SecretKey,PublicKey};
 use bitcoin::secp256k1::Secp256k1;
@@ -35,18 +37,20 @@ use bitcoin::secp256k1::ecdh::SharedSecret;
 use bitcoin::secp256k1;
 
 use chain;
-use chain::Watch;
+use chain::{Filter, Watch};
 use chain::chaininterface::{BroadcasterInterface, FeeEstimator};
 use chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, ChannelMonitorUpdateStep, ChannelMonitorUpdateErr, HTLC_FAIL_BACK_BUFFER, CLTV_CLAIM_BUFFER, LATENCY_GRACE_PERIOD_BLOCKS, ANTI_REORG_DELAY, MonitorEvent, CLOSED_CHANNEL_UPDATE_ID};
 use chain::transaction::{OutPoint, TransactionData};
 use ln::channel::{Channel, ChannelError};
+use ln::channel_backup::{ChannelBackup, StaticChannelBackup};
 use ln::features::{InitFeatures, NodeFeatures};
-use routing::router::{Route, RouteHop};
+use routing::router::{get_route, BlindedPath, Route, RouteHint, RouteHop, TrampolineHop};
+use routing::network_graph::NetworkGraph;
 use ln::msgs;
 use ln::msgs::NetAddress;
 use ln::onion_utils;
-use ln::msgs::{ChannelMessageHandler, DecodeError, LightningError, OptionalField};
-use chain::keysinterface::{ChannelKeys, KeysInterface, KeysManager, InMemoryChannelKeys};
+use ln::msgs::{ChannelMessageHandler, DecodeError, LightningError, OptionalField, DataLossProtect};
+use chain::keysinterface::{ChannelKeys, KeysInterface, KeysManager, InMemoryChannelKeys, SpendableOutputDescriptor};
 use util::config::UserConfig;
 use util::events::{Event, EventsProvider, MessageSendEvent, MessageSendEventsProvider};
 use util::{byte_utils, events};
@@ -87,10 +91,23 @@ enum PendingHTLCRouting {
 	Forward {
 		onion_packet: msgs::OnionPacket,
 		short_channel_id: u64, // This should be NonZero<u64> eventually when we bump MSRV
+		/// The blinding point to hand the next hop, if we're forwarding within a blinded path.
+		blinding_point: Option<PublicKey>,
 	},
 	Receive {
 		payment_data: Option<msgs::FinalOnionHopData>,
 		incoming_cltv_expiry: u32, // Used to track when we should expire pending HTLCs that go unclaimed
+		custom_tlvs: Vec<(u64, Vec<u8>)>,
+	},
+	TrampolineForward {
+		next_node_id: PublicKey,
+		/// The trampoline onion to forward to next_node_id, or None if next_node_id is the
+		/// recipient and doesn't support trampoline routing.
+		trampoline_packet: Option<msgs::TrampolineOnionPacket>,
+		/// Filled in only when paying a recipient which doesn't support trampoline routing.
+		payment_data: Option<msgs::FinalOnionHopData>,
+		incoming_amt_msat: u64,
+		incoming_cltv_expiry: u32,
 	},
 }
 
@@ -155,6 +172,123 @@ struct ClaimableHTLC {
 	/// are part of the same payment.
 	payment_data: Option<msgs::FinalOnionHopData>,
 	cltv_expiry: u32,
+	/// The custom TLV records the sender included in the onion, which must be the same for all
+	/// HTLCs which are part of the same payment.
+	custom_tlvs: Vec<(u64, Vec<u8>)>,
+}
+
+/// An HTLC we received as a trampoline node, held until process_pending_trampoline_forwards is
+/// called to find a route to the next trampoline node (or the recipient).
+struct PendingTrampolineForward {
+	prev_hop: HTLCPreviousHopData,
+	payment_hash: PaymentHash,
+	next_node_id: PublicKey,
+	trampoline_packet: Option<msgs::TrampolineOnionPacket>,
+	payment_data: Option<msgs::FinalOnionHopData>,
+	incoming_amt_msat: u64,
+	incoming_cltv_expiry: u32,
+	amt_to_forward: u64,
+	outgoing_cltv_value: u32,
+}
+
+/// A single path over which we sent (part of) an outbound payment. The session_priv is unique per
+/// HTLC and is used to match the path up with the HTLCSource::OutboundRoute we get back when the
+/// HTLC is resolved.
+struct OutboundPaymentPath {
+	path: Vec<RouteHop>,
+	session_priv: SecretKey,
+	status: PaymentStatus,
+}
+
+/// Tracks an outbound payment which we sent via send_payment, across all of its paths.
+struct OutboundPayment {
+	payment_hash: PaymentHash,
+	/// Kept so that retry_payment can build onions for new paths.
+	payment_secret: Option<PaymentSecret>,
+	amount_msat: u64,
+	paths: Vec<OutboundPaymentPath>,
+	/// Filled in once any path of the payment has been fulfilled.
+	payment_preimage: Option<PaymentPreimage>,
+	/// Set by abandon_payment, after which no further paths may be sent.
+	abandoned: bool,
+	/// The trampoline nodes (and the recipient) for payments sent via send_trampoline_payment,
+	/// otherwise empty.
+	trampoline_hops: Vec<TrampolineHop>,
+	/// Set if this isn't our own payment but rather an HTLC we're forwarding as a trampoline
+	/// node, in which case this is the HTLC we received which is to be failed or claimed once
+	/// the payment is resolved.
+	forwarded_from: Option<HTLCPreviousHopData>,
+	/// Set for payments sent via send_payment_to_blinded_path.
+	blinded_tail: Option<BlindedPaymentTail>,
+	/// The custom TLV records for payments sent via send_payment_with_custom_tlvs, included in
+	/// the onion of every path.
+	custom_tlvs: Vec<(u64, Vec<u8>)>,
+}
+
+/// The part of a payment sent via send_payment_to_blinded_path which lies beyond the end of each
+/// path we route, kept so that retry_payment can build onions for new paths.
+#[derive(Clone)]
+struct BlindedPaymentTail {
+	blinded_path: BlindedPath,
+	amount_msat: u64,
+	final_cltv_expiry_delta: u32,
+}
+
+impl OutboundPayment {
+	/// The total value, in msat, which is to be delivered to the recipient by the paths of this
+	/// payment which have not yet been resolved.
+	fn pending_value_msat(&self) -> u64 {
+		let tail_value_msat = self.trampoline_hops.last().map(|hop| hop.fee_msat)
+			.or(self.blinded_tail.as_ref().map(|tail| tail.amount_msat));
+		self.paths.iter().filter(|path| path.status == PaymentStatus::Pending)
+			.map(|path| tail_value_msat.unwrap_or(path.path.last().map(|hop| hop.fee_msat).unwrap_or(0))).sum()
+	}
+
+	/// Gets the Event::PaymentAbandoned to generate for an abandoned payment, if all of its HTLCs
+	/// have now been resolved and none were claimed.
+	fn abandoned_event(&self, payment_id: PaymentId) -> Option<events::Event> {
+		if self.abandoned && self.status() == PaymentStatus::Failed {
+			Some(events::Event::PaymentAbandoned { payment_id, payment_hash: self.payment_hash })
+		} else { None }
+	}
+
+	fn status(&self) -> PaymentStatus {
+		if self.payment_preimage.is_some() {
+			PaymentStatus::Succeeded
+		} else if self.paths.iter().any(|path| path.status == PaymentStatus::Pending) {
+			PaymentStatus::Pending
+		} else {
+			PaymentStatus::Failed
+		}
+	}
+
+	fn details(&self, payment_id: PaymentId) -> PaymentDetails {
+		let status = self.status();
+		let fee_paid_msat = if status == PaymentStatus::Succeeded {
+			let trampoline_fee_msat: u64 = self.trampoline_hops.split_last()
+				.map(|(_, hops)| hops.iter().map(|hop| hop.fee_msat).sum()).unwrap_or(0);
+			Some(self.paths.iter().filter(|path| path.status == PaymentStatus::Succeeded).map(|path| {
+				let blinded_fee_msat = match (&self.blinded_tail, path.path.last()) {
+					(&Some(ref tail), Some(last_hop)) => last_hop.fee_msat.saturating_sub(tail.amount_msat),
+					_ => 0,
+				};
+				path.path.split_last().map(|(_, hops)| hops.iter().map(|hop| hop.fee_msat).sum()).unwrap_or(0) + blinded_fee_msat
+			}).sum::<u64>() + trampoline_fee_msat)
+		} else { None };
+		PaymentDetails {
+			payment_id,
+			payment_hash: self.payment_hash,
+			status,
+			amount_msat: self.amount_msat,
+			paths: self.paths.iter().map(|path| PaymentPathDetails {
+				path: path.path.clone(),
+				status: path.status,
+			}).collect(),
+			fee_paid_msat,
+			payment_preimage: self.payment_preimage,
+			abandoned: self.abandoned,
+		}
+	}
 }
 
 /// Tracks the inbound corresponding to an outbound HTLC
@@ -167,6 +301,9 @@ pub(crate) enum HTLCSource {
 		/// Technically we can recalculate this from the route, but we cache it here to avoid
 		/// doing a double-pass on route when we get a failure back
 		first_hop_htlc_msat: u64,
+		/// The payment this HTLC is a part of, used to update our outbound payment tracking once
+		/// the HTLC is resolved.
+		payment_id: PaymentId,
 	},
 }
 #[cfg(test)]
@@ -176,6 +313,7 @@ impl HTLCSource {
 			path: Vec::new(),
 			session_priv: SecretKey::from_slice(&[1; 32]).unwrap(),
 			first_hop_htlc_msat: 0,
+			payment_id: PaymentId([0; 32]),
 		}
 	}
 }
@@ -203,6 +341,11 @@ pub struct PaymentPreimage(pub [u8;32]);
 /// (C-not exported) as we just use [u8; 32] directly
 #[derive(Hash, Copy, Clone, PartialEq, Eq, Debug)]
 pub struct PaymentSecret(pub [u8;32]);
+/// payment_id type, a caller-chosen unique identifier for an outbound payment, used to make
+/// ChannelManager::send_payment idempotent and to look the payment up later
+/// (C-not exported) as we just use [u8; 32] directly
+#[derive(Hash, Copy, Clone, PartialEq, Eq, Debug)]
+pub struct PaymentId(pub [u8;32]);
 
 type ShutdownResult = (Option<OutPoint>, ChannelMonitorUpdate, Vec<(HTLCSource, PaymentHash)>);
 
@@ -326,6 +469,9 @@ pub(super) struct ChannelHolder<ChanSigner: ChannelKeys> {
 	/// guarantees are made about the channels given here actually existing anymore by the time you
 	/// go to read them!
 	claimable_htlcs: HashMap<(PaymentHash, Option<PaymentSecret>), Vec<ClaimableHTLC>>,
+	/// HTLCs we received as a trampoline node which are waiting to be routed onwards by
+	/// process_pending_trampoline_forwards.
+	pending_trampoline_forwards: Vec<PendingTrampolineForward>,
 	/// Messages to send to peers - pushed to in the same lock that they are generated in (except
 	/// for broadcast messages, where ordering isn't as strict).
 	pub(super) pending_msg_events: Vec<MessageSendEvent>,
@@ -337,6 +483,16 @@ struct PeerState {
 	latest_features: InitFeatures,
 }
 
+/// A channel recovered from a static channel backup, which we're waiting for our counterparty to
+/// close on-chain.
+struct RecoveredChannel {
+	backup: ChannelBackup,
+	/// The height at which the funding output was spent, if it was.
+	closing_height: Option<u32>,
+	/// Our to_remote output in the transaction spending the funding output, if any.
+	to_remote_output: Option<SpendableOutputDescriptor>,
+}
+
 #[cfg(not(any(target_pointer_width = "32", target_pointer_width = "64")))]
 const ERR: () = "You need at least 32 bit pointers (well, usize, but we'll assume they're the same) for ChannelManager::latest_block_height";
 
@@ -434,6 +590,13 @@ pub struct ChannelManager<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref,
 	per_peer_state: RwLock<HashMap<PublicKey, Mutex<PeerState>>>,
 
 	pending_events: Mutex<Vec<events::Event>>,
+	/// The outbound payments we've sent via send_payment, keyed by their PaymentId.
+	/// Entries are kept (and persisted) until the user calls remove_payment.
+	/// Locked after channel_state if both are held.
+	pending_outbound_payments: Mutex<HashMap<PaymentId, OutboundPayment>>,
+	/// Channels recovered from a static channel backup whose balance we haven't claimed yet.
+	/// Locked after channel_state if both are held.
+	recovered_channels: Mutex<Vec<RecoveredChannel>>,
 	/// Used when we have to take a BIG lock to make sure everything is self-consistent.
 	/// Essentially just when we're serializing ourselves out.
 	/// Taken first everywhere where we are making changes before any other locks.
@@ -538,13 +701,68 @@ pub enum PaymentSendFailure {
 	///
 	/// The results here are ordered the same as the paths in the route object which was passed to
 	/// send_payment, and any Errs which are not APIError::MonitorUpdateFailed can be safely
-	/// retried (though there is currently no API with which to do so).
+	/// retried via ChannelManager::retry_payment.
 	///
 	/// Any entries which contain Err(APIError::MonitorUpdateFailed) or Ok(()) MUST NOT be retried
 	/// as they will result in over-/re-payment. These HTLCs all either successfully sent (in the
 	/// case of Ok(())) or will send once channel_monitor_updated is called on the next-hop channel
 	/// with the latest update_id.
 	PartialFailure(Vec<Result<(), APIError>>),
+	/// A payment with the given payment_id has already been sent (or partially sent), so nothing
+	/// was sent this time. You can check on the original payment via
+	/// ChannelManager::payment_status, or send additional paths for it via
+	/// ChannelManager::retry_payment.
+	DuplicatePayment,
+}
+
+/// The state of an outbound payment, or of a single path of one, as tracked by ChannelManager.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaymentStatus {
+	/// At least one HTLC of the payment is still in flight and none have yet been fulfilled. For a
+	/// single path, its HTLC has not yet been resolved.
+	Pending,
+	/// The recipient claimed the payment, revealing the payment preimage. For a single path, its
+	/// HTLC was fulfilled.
+	Succeeded,
+	/// Every HTLC of the payment failed and none remain in flight. For a single path, its HTLC
+	/// was failed back to us.
+	Failed,
+}
+
+/// A single path over which (part of) an outbound payment was sent, as returned in
+/// PaymentDetails.
+#[derive(Clone)]
+pub struct PaymentPathDetails {
+	/// The hops of the path, as they appeared in the Route passed to send_payment.
+	pub path: Vec<RouteHop>,
+	/// Whether the HTLC sent over this path is still pending, was fulfilled or was failed.
+	pub status: PaymentStatus,
+}
+
+/// Details of an outbound payment, as returned by ChannelManager::list_payments and
+/// ChannelManager::payment_status
+#[derive(Clone)]
+pub struct PaymentDetails {
+	/// The PaymentId which was passed to send_payment for this payment.
+	pub payment_id: PaymentId,
+	/// The payment_hash which was passed to send_payment.
+	pub payment_hash: PaymentHash,
+	/// The overall state of the payment.
+	pub status: PaymentStatus,
+	/// The total value, in millisatoshis, which the recipient is to receive, not including fees.
+	pub amount_msat: u64,
+	/// Each path over which an HTLC for this payment was sent, in the order they were sent. Paths
+	/// which failed to send entirely (ie were returned as an Err in a PaymentSendFailure) are not
+	/// included.
+	pub paths: Vec<PaymentPathDetails>,
+	/// The total fees paid, in millisatoshis, across all paths which have been fulfilled. None
+	/// unless the payment has succeeded.
+	pub fee_paid_msat: Option<u64>,
+	/// The payment preimage, which serves as proof-of-payment. None unless the payment has
+	/// succeeded.
+	pub payment_preimage: Option<PaymentPreimage>,
+	/// Whether the payment has been abandoned via ChannelManager::abandon_payment.
+	pub abandoned: bool,
 }
 
 macro_rules! handle_error {
@@ -749,6 +967,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 				short_to_id: HashMap::new(),
 				forward_htlcs: HashMap::new(),
 				claimable_htlcs: HashMap::new(),
+				pending_trampoline_forwards: Vec::new(),
 				pending_msg_events: Vec::new(),
 			}),
 			our_network_key: keys_manager.get_node_secret(),
@@ -758,6 +977,8 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			per_peer_state: RwLock::new(HashMap::new()),
 
 			pending_events: Mutex::new(Vec::new()),
+			pending_outbound_payments: Mutex::new(HashMap::new()),
+			recovered_channels: Mutex::new(Vec::new()),
 			total_consistency_lock: RwLock::new(()),
 
 			keys_manager,
@@ -951,6 +1172,127 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		}
 	}
 
+	/// Gets an encrypted static channel backup of all our funded channels, which allows recovering
+	/// our balance in them from our seed if we lose our ChannelMonitors. It should be stored
+	/// somewhere safe and updated whenever a channel is funded. See the
+	/// [`channel_backup`] module-level documentation for more.
+	///
+	/// `peer_addresses` gives the addresses our counterparties may be reached at (eg as found in
+	/// their node_announcement), which are stored in the backup to reconnect to them.
+	///
+	/// Channels recovered from a previous backup whose balance we didn't claim yet are included.
+	///
+	/// [`channel_backup`]: ../channel_backup/index.html
+	pub fn get_static_channel_backup(&self, peer_addresses: &HashMap<PublicKey, Vec<NetAddress>>) -> Vec<u8> {
+		let mut channels = Vec::new();
+		{
+			let channel_state = self.channel_state.lock().unwrap();
+			for (channel_id, channel) in channel_state.by_id.iter() {
+				if let Some(funding_outpoint) = channel.get_funding_txo() {
+					let counterparty_node_id = channel.get_counterparty_node_id();
+					channels.push(ChannelBackup {
+						channel_id: *channel_id,
+						counterparty_node_id,
+						counterparty_addresses: peer_addresses.get(&counterparty_node_id).cloned().unwrap_or(Vec::new()),
+						funding_outpoint,
+						funding_script_pubkey: channel.get_funding_redeemscript().to_v0_p2wsh(),
+						channel_value_satoshis: channel.get_value_satoshis(),
+						key_derivation_params: channel.get_key_derivation_params(),
+						holder_payment_point: channel.get_holder_pubkeys().payment_point,
+					});
+				}
+			}
+			for recovered in self.recovered_channels.lock().unwrap().iter() {
+				channels.push(recovered.backup.clone());
+			}
+		}
+		let mut nonce = [0; 8];
+		nonce.copy_from_slice(&self.keys_manager.get_secure_random_bytes()[0..8]);
+		StaticChannelBackup { channels }.encrypt(&self.our_network_key, nonce)
+	}
+
+	/// Starts recovering our balance in the channels of an encrypted static channel backup, as
+	/// given by get_static_channel_backup, after our ChannelMonitors were lost.
+	///
+	/// This should be called on a new ChannelManager whose KeysInterface gives the same node
+	/// secret as when the backup was made (eg a KeysManager with the same seed). Channels we still
+	/// have are skipped.
+	///
+	/// Returns the decrypted backup, with the addresses to connect to each counterparty at. Once
+	/// connected, our counterparty is asked to force-close the channel and an
+	/// Event::SpendableOutputs is generated for our to_remote output once their commitment
+	/// transaction is ANTI_REORG_DELAY blocks deep. Until then, recovered channels are persisted
+	/// as part of the ChannelManager.
+	///
+	/// If a chain::Filter is given, the funding outpoint of each recovered channel is registered
+	/// with it, so that the closing transaction is included in the blocks we're given. As
+	/// filters generally don't persist what they watch, this should be called again with the same
+	/// backup after a restart, until the recovered channels are closed.
+	///
+	/// Note that balances in HTLCs pending when the ChannelMonitors were lost are not recovered.
+	pub fn recover_from_static_channel_backup<C: Deref>(&self, backup: &[u8], chain_source: Option<C>) -> Result<StaticChannelBackup, DecodeError>
+		where C::Target: Filter
+	{
+		let backup = StaticChannelBackup::decrypt(backup, &self.our_network_key)?;
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
+		let connected_peers: HashSet<PublicKey> = self.per_peer_state.read().unwrap().keys().cloned().collect();
+
+		let mut channel_state_lock = self.channel_state.lock().unwrap();
+		let channel_state = &mut *channel_state_lock;
+		let mut recovered_channels = self.recovered_channels.lock().unwrap();
+		for channel in backup.channels.iter() {
+			if channel_state.by_id.contains_key(&channel.channel_id) {
+				continue;
+			}
+			if let Some(ref chain_source) = chain_source {
+				chain_source.register_output(&channel.funding_outpoint, &channel.funding_script_pubkey);
+			}
+			if recovered_channels.iter().any(|recovered| recovered.backup.channel_id == channel.channel_id) {
+				continue;
+			}
+			log_info!(self.logger, "Recovering channel {} from static channel backup", log_bytes!(channel.channel_id));
+			if connected_peers.contains(&channel.counterparty_node_id) {
+				Self::request_recovered_channel_close(channel, &mut channel_state.pending_msg_events);
+			}
+			recovered_channels.push(RecoveredChannel {
+				backup: channel.clone(),
+				closing_height: None,
+				to_remote_output: None,
+			});
+		}
+		Ok(backup)
+	}
+
+	/// Asks our counterparty to force-close a channel we recovered from a static channel backup.
+	fn request_recovered_channel_close(channel: &ChannelBackup, pending_msg_events: &mut Vec<MessageSendEvent>) {
+		// We claim to have lost all state, as we did, which is all we can do as we can't prove
+		// anything to our counterparty. If it doesn't fail the channel by itself (ie if it
+		// wasn't ever updated), the error which follows makes it close.
+		let mut pk = [2; 33]; pk[1] = 0xff;
+		let dummy_pubkey = PublicKey::from_slice(&pk).unwrap();
+		pending_msg_events.push(events::MessageSendEvent::SendChannelReestablish {
+			node_id: channel.counterparty_node_id,
+			msg: msgs::ChannelReestablish {
+				channel_id: channel.channel_id,
+				next_local_commitment_number: 1,
+				next_remote_commitment_number: 0,
+				data_loss_protect: OptionalField::Present(DataLossProtect {
+					your_last_per_commitment_secret: [0; 32],
+					my_current_per_commitment_point: dummy_pubkey,
+				}),
+			},
+		});
+		pending_msg_events.push(events::MessageSendEvent::HandleError {
+			node_id: channel.counterparty_node_id,
+			action: msgs::ErrorAction::SendErrorMessage {
+				msg: msgs::ErrorMessage {
+					channel_id: channel.channel_id,
+					data: "Lost channel state, please force-close the channel".to_owned(),
+				},
+			},
+		});
+	}
+
 	fn decode_update_add_htlc_onion(&self, msg: &msgs::UpdateAddHTLC) -> (PendingHTLCStatus, MutexGuard<ChannelHolder<ChanSigner>>) {
 		macro_rules! return_malformed_err {
 			($msg: expr, $err_code: expr) => {
@@ -960,7 +1302,8 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 						channel_id: msg.channel_id,
 						htlc_id: msg.htlc_id,
 						sha256_of_onion: Sha256::hash(&msg.onion_routing_packet.hop_data).into_inner(),
-						failure_code: $err_code,
+						// Within a blinded path, we must not reveal why we failed the HTLC.
+						failure_code: if msg.blinding_point.is_some() { 0x8000 | 0x4000 | 24 } else { $err_code },
 					})), self.channel_state.lock().unwrap());
 				}
 			}
@@ -970,9 +1313,20 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			return_malformed_err!("invalid ephemeral pubkey", 0x8000 | 0x4000 | 6);
 		}
 
+		// If we're within a blinded path (but aren't its introduction node), the onion was
+		// encrypted to our blinded node id, which we derive from the blinding point we were given.
+		let blinded_shared_secret = msg.blinding_point.as_ref().map(|blinding_point| onion_utils::blinded_hop_shared_secret(&self.our_network_key, blinding_point));
+		let onion_node_secret = match blinded_shared_secret {
+			Some(ref blinded_shared_secret) => match onion_utils::blinded_node_secret(&self.our_network_key, blinded_shared_secret) {
+				Ok(secret) => secret,
+				Err(_) => return_malformed_err!("Unable to derive our blinded node secret", 0x8000 | 0x4000 | 24),
+			},
+			None => self.our_network_key.clone(),
+		};
+
 		let shared_secret = {
 			let mut arr = [0; 32];
-			arr.copy_from_slice(&SharedSecret::new(&msg.onion_routing_packet.public_key.unwrap(), &self.our_network_key)[..]);
+			arr.copy_from_slice(&SharedSecret::new(&msg.onion_routing_packet.public_key.unwrap(), &onion_node_secret)[..]);
 			arr
 		};
 		let (rho, mu) = onion_utils::gen_rho_mu_from_shared_secret(&shared_secret);
@@ -995,6 +1349,12 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		}
 
 		let mut channel_state = None;
+		let mut is_blinded_intro_node = false;
+		// Within a blinded path, we must not reveal why we failed the HTLC, lest the sender learn
+		// about the hidden part of the path, so we always fail with invalid_onion_blinding. Only
+		// the introduction node, which the sender knows of, fails back with an onion error.
+		// Note that failures which happen after we've accepted the HTLC (eg when the next hop
+		// fails it) are not yet replaced in this way.
 		macro_rules! return_err {
 			($msg: expr, $err_code: expr, $data: expr) => {
 				{
@@ -1002,11 +1362,28 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					if channel_state.is_none() {
 						channel_state = Some(self.channel_state.lock().unwrap());
 					}
-					return (PendingHTLCStatus::Fail(HTLCFailureMsg::Relay(msgs::UpdateFailHTLC {
-						channel_id: msg.channel_id,
-						htlc_id: msg.htlc_id,
-						reason: onion_utils::build_first_hop_failure_packet(&shared_secret, $err_code, $data),
-					})), channel_state.unwrap());
+					let sha256_of_onion = Sha256::hash(&msg.onion_routing_packet.hop_data).into_inner();
+					let failure = if msg.blinding_point.is_some() {
+						HTLCFailureMsg::Malformed(msgs::UpdateFailMalformedHTLC {
+							channel_id: msg.channel_id,
+							htlc_id: msg.htlc_id,
+							sha256_of_onion,
+							failure_code: 0x8000 | 0x4000 | 24,
+						})
+					} else if is_blinded_intro_node {
+						HTLCFailureMsg::Relay(msgs::UpdateFailHTLC {
+							channel_id: msg.channel_id,
+							htlc_id: msg.htlc_id,
+							reason: onion_utils::build_first_hop_failure_packet(&shared_secret, 0x8000 | 0x4000 | 24, &sha256_of_onion),
+						})
+					} else {
+						HTLCFailureMsg::Relay(msgs::UpdateFailHTLC {
+							channel_id: msg.channel_id,
+							htlc_id: msg.htlc_id,
+							reason: onion_utils::build_first_hop_failure_packet(&shared_secret, $err_code, $data),
+						})
+					};
+					return (PendingHTLCStatus::Fail(failure), channel_state.unwrap());
 				}
 			}
 		}
@@ -1035,6 +1412,33 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			}
 		};
 
+		// If this hop is within a blinded path, decrypt the data the path's creator left for us,
+		// using the blinding point from either the update_add_htlc or, if we're the path's
+		// introduction node, the onion.
+		let blinded_hop = match next_hop_data.format {
+			msgs::OnionHopDataFormat::BlindedForward { ref encrypted_data, ref intro_node_blinding_point } |
+			msgs::OnionHopDataFormat::BlindedReceive { ref encrypted_data, ref intro_node_blinding_point, .. } => {
+				let (blinding_point, blinded_shared_secret) = match (msg.blinding_point, intro_node_blinding_point, blinded_shared_secret) {
+					(Some(blinding_point), &None, Some(blinded_shared_secret)) => (blinding_point, blinded_shared_secret),
+					(None, &Some(blinding_point), None) => {
+						is_blinded_intro_node = true;
+						(blinding_point, onion_utils::blinded_hop_shared_secret(&self.our_network_key, &blinding_point))
+					},
+					_ => return_err!("Got a blinding point in both the onion and update_add_htlc", 0x4000 | 22, &[0;0]),
+				};
+				match onion_utils::decrypt_blinded_hop_data(&blinded_shared_secret, encrypted_data) {
+					Ok(data) => Some((data, blinding_point, blinded_shared_secret)),
+					Err(()) => return_err!("Unable to decrypt our blinded hop data", 0x4000 | 22, &[0;0]),
+				}
+			},
+			_ => {
+				if msg.blinding_point.is_some() {
+					return_err!("Got a blinding point for a hop outside of a blinded path", 0x4000 | 22, &[0;0]);
+				}
+				None
+			},
+		};
+
 		let pending_forward_info = if next_hop_hmac == [0; 32] {
 				#[cfg(test)]
 				{
@@ -1070,10 +1474,87 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					return_err!("Upstream node set CLTV to the wrong value", 18, &byte_utils::be32_to_array(msg.cltv_expiry));
 				}
 
-				let payment_data = match next_hop_data.format {
-					msgs::OnionHopDataFormat::Legacy { .. } => None,
+				let (routing, amt_to_forward, outgoing_cltv_value) = match next_hop_data.format {
+					msgs::OnionHopDataFormat::Legacy { .. } => (PendingHTLCRouting::Receive {
+						payment_data: None,
+						incoming_cltv_expiry: msg.cltv_expiry,
+						custom_tlvs: Vec::new(),
+					}, next_hop_data.amt_to_forward, next_hop_data.outgoing_cltv_value),
 					msgs::OnionHopDataFormat::NonFinalNode { .. } => return_err!("Got non final data with an HMAC of 0", 0x4000 | 22, &[0;0]),
-					msgs::OnionHopDataFormat::FinalNode { payment_data } => payment_data,
+					msgs::OnionHopDataFormat::FinalNode { payment_data, custom_tlvs } => (PendingHTLCRouting::Receive {
+						payment_data,
+						incoming_cltv_expiry: msg.cltv_expiry,
+						custom_tlvs,
+					}, next_hop_data.amt_to_forward, next_hop_data.outgoing_cltv_value),
+					msgs::OnionHopDataFormat::TrampolineForward { .. } => return_err!("Got trampoline forward data outside of a trampoline onion", 0x4000 | 22, &[0;0]),
+					msgs::OnionHopDataFormat::BlindedForward { .. } => return_err!("Got blinded forward data with an HMAC of 0", 0x4000 | 22, &[0;0]),
+					msgs::OnionHopDataFormat::BlindedReceive { total_msat, .. } => {
+						let path_id = match blinded_hop {
+							Some((msgs::EncryptedBlindedHopData { path_id: Some(path_id), short_channel_id: None, .. }, _, _)) => path_id,
+							_ => return_err!("Got blinded receive data which wasn't meant for the recipient", 0x4000 | 22, &[0;0]),
+						};
+						// The path_id is the payment_secret we handed to create_blinded_path, so
+						// it authenticates the sender just as a payment_secret would.
+						(PendingHTLCRouting::Receive {
+							payment_data: Some(msgs::FinalOnionHopData {
+								payment_secret: PaymentSecret(path_id),
+								total_msat,
+							}),
+							incoming_cltv_expiry: msg.cltv_expiry,
+							custom_tlvs: Vec::new(),
+						}, next_hop_data.amt_to_forward, next_hop_data.outgoing_cltv_value)
+					},
+					msgs::OnionHopDataFormat::TrampolineEntry { trampoline_packet, .. } => {
+						let (trampoline_hop_data, next_trampoline_packet) = match onion_utils::decode_trampoline_onion(&self.secp_ctx, &self.our_network_key, &trampoline_packet, &msg.payment_hash) {
+							Ok(res) => res,
+							Err(e) => return_err!(e, 0x4000 | 22, &[0;0]),
+						};
+						match (trampoline_hop_data.format, next_trampoline_packet) {
+							(msgs::OnionHopDataFormat::FinalNode { payment_data, custom_tlvs }, None) => {
+								// final_incorrect_htlc_amount
+								if trampoline_hop_data.amt_to_forward > msg.amount_msat {
+									return_err!("Upstream node sent less than we were supposed to receive in trampoline payment", 19, &byte_utils::be64_to_array(msg.amount_msat));
+								}
+								// final_incorrect_cltv_expiry
+								if trampoline_hop_data.outgoing_cltv_value != msg.cltv_expiry {
+									return_err!("Upstream node set CLTV to the wrong value for trampoline payment", 18, &byte_utils::be32_to_array(msg.cltv_expiry));
+								}
+								(PendingHTLCRouting::Receive {
+									payment_data,
+									incoming_cltv_expiry: msg.cltv_expiry,
+									custom_tlvs,
+								}, trampoline_hop_data.amt_to_forward, trampoline_hop_data.outgoing_cltv_value)
+							},
+							(msgs::OnionHopDataFormat::TrampolineForward { outgoing_node_id, payment_data }, next_trampoline_packet) => {
+								// required_node_feature_missing, as we don't advertise trampoline
+								// routing unless configured to forward trampoline payments.
+								if !self.default_configuration.accept_trampoline_forwards {
+									return_err!("We don't forward trampoline payments", 0x4000 | 0x2000 | 3, &[0;0]);
+								}
+								// We either forward a trampoline onion to the next trampoline node or
+								// pay a recipient which doesn't support trampoline routing directly.
+								if next_trampoline_packet.is_some() == payment_data.is_some() {
+									return_err!("Trampoline forward data must have either a next trampoline onion or payment data", 0x4000 | 22, &[0;0]);
+								}
+								// trampoline_fee_insufficient
+								if trampoline_hop_data.amt_to_forward > msg.amount_msat {
+									return_err!("Prior hops have taken too much value for us to forward the trampoline payment", 0x2000 | 26, &[0;0]);
+								}
+								// trampoline_expiry_too_soon
+								if (trampoline_hop_data.outgoing_cltv_value as u64) + (CLTV_EXPIRY_DELTA as u64) > msg.cltv_expiry as u64 {
+									return_err!("Trampoline CLTV expiry is too soon to find a route to the next node", 0x2000 | 27, &[0;0]);
+								}
+								(PendingHTLCRouting::TrampolineForward {
+									next_node_id: outgoing_node_id,
+									trampoline_packet: next_trampoline_packet,
+									payment_data,
+									incoming_amt_msat: msg.amount_msat,
+									incoming_cltv_expiry: msg.cltv_expiry,
+								}, trampoline_hop_data.amt_to_forward, trampoline_hop_data.outgoing_cltv_value)
+							},
+							_ => return_err!("Got invalid trampoline hop data", 0x4000 | 22, &[0;0]),
+						}
+					},
 				};
 
 				// Note that we could obviously respond immediately with an update_fulfill_htlc
@@ -1082,14 +1563,11 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 				// delay) once they've send us a commitment_signed!
 
 				PendingHTLCStatus::Forward(PendingHTLCInfo {
-					routing: PendingHTLCRouting::Receive {
-						payment_data,
-						incoming_cltv_expiry: msg.cltv_expiry,
-					},
+					routing,
 					payment_hash: msg.payment_hash.clone(),
 					incoming_shared_secret: shared_secret,
-					amt_to_forward: next_hop_data.amt_to_forward,
-					outgoing_cltv_value: next_hop_data.outgoing_cltv_value,
+					amt_to_forward,
+					outgoing_cltv_value,
 				})
 			} else {
 				let mut new_packet_data = [0; 20*65];
@@ -1127,10 +1605,37 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					hmac: next_hop_hmac.clone(),
 				};
 
-				let short_channel_id = match next_hop_data.format {
-					msgs::OnionHopDataFormat::Legacy { short_channel_id } => short_channel_id,
-					msgs::OnionHopDataFormat::NonFinalNode { short_channel_id } => short_channel_id,
-					msgs::OnionHopDataFormat::FinalNode { .. } => {
+				let (short_channel_id, blinding_point, amt_to_forward, outgoing_cltv_value) = match next_hop_data.format {
+					msgs::OnionHopDataFormat::Legacy { short_channel_id } |
+					msgs::OnionHopDataFormat::NonFinalNode { short_channel_id } =>
+						(short_channel_id, None, next_hop_data.amt_to_forward, next_hop_data.outgoing_cltv_value),
+					msgs::OnionHopDataFormat::BlindedForward { .. } => {
+						let (data, blinding_point, blinded_shared_secret) = blinded_hop.unwrap();
+						let (short_channel_id, payment_relay) = match data {
+							msgs::EncryptedBlindedHopData { short_channel_id: Some(short_channel_id), payment_relay: Some(payment_relay), path_id: None } => (short_channel_id, payment_relay),
+							_ => return_err!("Got blinded forward data without a next hop and relay parameters", 0x4000 | 22, &[0;0]),
+						};
+						// The sender doesn't know our fee or CLTV delta, so we deduct them from
+						// what we received as the path's creator instructed, rounding the amount
+						// down so that we always collect at least our fee.
+						let amt_to_forward = match msg.amount_msat.checked_sub(payment_relay.fee_base_msat as u64).and_then(|amt| amt.checked_mul(1_000_000)) {
+							Some(amt) => amt / (1_000_000 + payment_relay.fee_proportional_millionths as u64),
+							None => return_err!("Prior hops have taken too much value for us to forward within the blinded path", 0x1000 | 12, &[0;0]),
+						};
+						let outgoing_cltv_value = match msg.cltv_expiry.checked_sub(payment_relay.cltv_expiry_delta as u32) {
+							Some(cltv) => cltv,
+							None => return_err!("Prior hops have taken too much CLTV delta for us to forward within the blinded path", 0x1000 | 13, &[0;0]),
+						};
+						let next_blinding_point = match onion_utils::next_blinding_point(&self.secp_ctx, &blinding_point, &blinded_shared_secret) {
+							Ok(point) => point,
+							Err(_) => return_err!("Blinding point for the next hop was invalid", 0x4000 | 22, &[0;0]),
+						};
+						(short_channel_id, Some(next_blinding_point), amt_to_forward, outgoing_cltv_value)
+					},
+					msgs::OnionHopDataFormat::FinalNode { .. } |
+					msgs::OnionHopDataFormat::TrampolineEntry { .. } |
+					msgs::OnionHopDataFormat::TrampolineForward { .. } |
+					msgs::OnionHopDataFormat::BlindedReceive { .. } => {
 						return_err!("Final Node OnionHopData provided for us as an intermediary node", 0x4000 | 22, &[0;0]);
 					},
 				};
@@ -1139,11 +1644,12 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					routing: PendingHTLCRouting::Forward {
 						onion_packet: outgoing_packet,
 						short_channel_id,
+						blinding_point,
 					},
 					payment_hash: msg.payment_hash.clone(),
 					incoming_shared_secret: shared_secret,
-					amt_to_forward: next_hop_data.amt_to_forward,
-					outgoing_cltv_value: next_hop_data.outgoing_cltv_value,
+					amt_to_forward,
+					outgoing_cltv_value,
 				})
 			};
 
@@ -1254,19 +1760,22 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	}
 
 	// Only public for testing, this should otherwise never be called direcly
-	pub(crate) fn send_payment_along_path(&self, path: &Vec<RouteHop>, payment_hash: &PaymentHash, payment_secret: &Option<PaymentSecret>, total_value: u64, cur_height: u32) -> Result<(), APIError> {
+	pub(crate) fn send_payment_along_path(&self, path: &Vec<RouteHop>, payment_hash: &PaymentHash, payment_secret: &Option<PaymentSecret>, custom_tlvs: &[(u64, Vec<u8>)], total_value: u64, cur_height: u32, payment_id: PaymentId, session_priv: &SecretKey, trampoline_packet: Option<msgs::TrampolineOnionPacket>) -> Result<(), APIError> {
 		log_trace!(self.logger, "Attempting to send payment for path with next hop {}", path.first().unwrap().short_channel_id);
 		let prng_seed = self.keys_manager.get_secure_random_bytes();
-		let session_priv = SecretKey::from_slice(&self.keys_manager.get_secure_random_bytes()[..]).expect("RNG is busted");
 
 		let onion_keys = onion_utils::construct_onion_keys(&self.secp_ctx, &path, &session_priv)
 			.map_err(|_| APIError::RouteError{err: "Pubkey along hop was maliciously selected"})?;
-		let (onion_payloads, htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(path, total_value, payment_secret, cur_height)?;
+		let (onion_payloads, htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(path, total_value, payment_secret, custom_tlvs, cur_height, trampoline_packet)?;
 		if onion_utils::route_size_insane(&onion_payloads) {
 			return Err(APIError::RouteError{err: "Route size too large considering onion data"});
 		}
 		let onion_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, prng_seed, payment_hash);
+		self.send_onion_along_path(path, payment_hash, payment_id, session_priv, onion_packet, htlc_msat, htlc_cltv)
+	}
 
+	/// Sends an HTLC carrying the given (already-built) onion to the first hop in path.
+	fn send_onion_along_path(&self, path: &Vec<RouteHop>, payment_hash: &PaymentHash, payment_id: PaymentId, session_priv: &SecretKey, onion_packet: msgs::OnionPacket, htlc_msat: u64, htlc_cltv: u32) -> Result<(), APIError> {
 		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 
 		let err: Result<(), _> = loop {
@@ -1289,7 +1798,8 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 						path: path.clone(),
 						session_priv: session_priv.clone(),
 						first_hop_htlc_msat: htlc_msat,
-					}, onion_packet, &self.logger), channel_state, chan)
+						payment_id,
+					}, onion_packet, None, &self.logger), channel_state, chan)
 				} {
 					Some((update_add, commitment_signed, monitor_update)) => {
 						if let Err(e) = self.chain_monitor.update_channel(chan.get().get_funding_txo().unwrap(), monitor_update) {
@@ -1333,12 +1843,24 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	/// Value parameters are provided via the last hop in route, see documentation for RouteHop
 	/// fields for more info.
 	///
-	/// Note that if the payment_hash already exists elsewhere (eg you're sending a duplicative
-	/// payment), we don't do anything to stop you! We always try to ensure that if the provided
-	/// next hop knows the preimage to payment_hash they can claim an additional amount as
-	/// specified in the last hop in the route! Thus, you should probably do your own
-	/// payment_preimage tracking (which you should already be doing as they represent "proof of
-	/// payment") and prevent double-sends yourself.
+	/// payment_id is a caller-chosen identifier for this payment which must be unique across all
+	/// payments you've sent (and not yet removed via remove_payment). It makes sending idempotent:
+	/// if a payment with the same payment_id has already been sent (or partially sent), we will
+	/// not send anything and will return PaymentSendFailure::DuplicatePayment instead. Thus, after
+	/// a restart, you can always safely call send_payment again for any payment you're not sure
+	/// was sent. Using the invoice's payment_hash as the payment_id is a fine choice if you never
+	/// intend to pay the same invoice twice.
+	///
+	/// Once (any part of) the payment has been sent, it is tracked and its progress can be
+	/// queried via payment_status or list_payments. If some paths failed to send (see
+	/// PaymentSendFailure::PartialFailure) they may be re-sent via retry_payment.
+	///
+	/// Note that if the payment_hash already exists elsewhere under a different payment_id (eg
+	/// you're sending a duplicative payment), we don't do anything to stop you! We always try to
+	/// ensure that if the provided next hop knows the preimage to payment_hash they can claim an
+	/// additional amount as specified in the last hop in the route! Thus, you should probably do
+	/// your own payment_preimage tracking (which you should already be doing as they represent
+	/// "proof of payment") and pick your payment_ids so as to prevent double-sends.
 	///
 	/// May generate SendHTLCs message(s) event on success, which should be relayed.
 	///
@@ -1357,7 +1879,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	///
 	/// Note that depending on the type of the PaymentSendFailure the HTLC may have been
 	/// irrevocably committed to on our end. In such a case, do NOT retry the payment with a
-	/// different route unless you intend to pay twice!
+	/// different route and a different payment_id unless you intend to pay twice!
 	///
 	/// payment_secret is unrelated to payment_hash (or PaymentPreimage) and exists to authenticate
 	/// the sender to the recipient and prevent payment-probing (deanonymization) attacks. For
@@ -1367,7 +1889,164 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	/// If a payment_secret *is* provided, we assume that the invoice had the payment_secret feature
 	/// bit set (either as required or as available). If multiple paths are present in the Route,
 	/// we assume the invoice had the basic_mpp feature set.
-	pub fn send_payment(&self, route: &Route, payment_hash: PaymentHash, payment_secret: &Option<PaymentSecret>) -> Result<(), PaymentSendFailure> {
+	///
+	/// To include custom TLV records in the onion for the recipient, use
+	/// send_payment_with_custom_tlvs instead.
+	pub fn send_payment(&self, route: &Route, payment_hash: PaymentHash, payment_secret: &Option<PaymentSecret>, payment_id: PaymentId) -> Result<(), PaymentSendFailure> {
+		self.send_payment_internal(route, &[], None, payment_hash, payment_secret, Vec::new(), payment_id, false)
+	}
+
+	/// Sends a payment exactly as send_payment does, but including the given custom TLV records
+	/// in the onion for the recipient, eg to attach application-specific metadata to the
+	/// payment. The recipient will see them in Event::PaymentReceived.
+	///
+	/// Each record is a (type, value) pair and all types must be unique and at least
+	/// msgs::MIN_CUSTOM_TLV_TYPE, otherwise a PaymentSendFailure::ParameterError is returned.
+	/// Note that recipients reject payments including even types they don't understand (and we
+	/// always reject even types), so you should generally use odd types. The recipient must
+	/// support variable-length onions, and the records must leave enough room in the onion for
+	/// the rest of the route, otherwise each path will fail with an APIError::RouteError.
+	///
+	/// The records are included in every path of the payment, including those sent via
+	/// retry_payment.
+	///
+	/// This is a separate method rather than a send_payment parameter so that the (far more
+	/// common) payments without custom records don't have to pass an empty Vec, and so that
+	/// existing send_payment callers keep working.
+	pub fn send_payment_with_custom_tlvs(&self, route: &Route, payment_hash: PaymentHash, payment_secret: &Option<PaymentSecret>, mut custom_tlvs: Vec<(u64, Vec<u8>)>, payment_id: PaymentId) -> Result<(), PaymentSendFailure> {
+		custom_tlvs.sort_unstable_by_key(|&(typ, _)| typ);
+		if custom_tlvs.first().map(|&(typ, _)| typ < msgs::MIN_CUSTOM_TLV_TYPE).unwrap_or(false) {
+			return Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError { err: format!("Custom TLV types must be at least {}", msgs::MIN_CUSTOM_TLV_TYPE) }));
+		}
+		if custom_tlvs.windows(2).any(|records| records[0].0 == records[1].0) {
+			return Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError { err: "Custom TLV types must be unique".to_owned() }));
+		}
+		self.send_payment_internal(route, &[], None, payment_hash, payment_secret, custom_tlvs, payment_id, false)
+	}
+
+	/// Sends a payment via one or more trampoline nodes, which find the route to the next
+	/// trampoline node (or the recipient) on our behalf, so that we only need a route to the
+	/// first trampoline node.
+	///
+	/// trampoline_hops lists the trampoline nodes in order, followed by the recipient, see the
+	/// documentation for TrampolineHop fields for how fees and CLTV deltas are specified. The
+	/// route must contain a single path to the first trampoline node, and the last hop of that
+	/// path must carry the sum of the fee_msat and of the cltv_expiry_delta values of all the
+	/// trampoline_hops. All trampoline nodes must support trampoline routing, though the recipient
+	/// need not, in which case a payment_secret is required.
+	///
+	/// The payment is otherwise tracked, retried and abandoned exactly as those sent via
+	/// send_payment, and errors are returned as they are from send_payment.
+	pub fn send_trampoline_payment(&self, route: &Route, trampoline_hops: &[TrampolineHop], payment_hash: PaymentHash, payment_secret: &Option<PaymentSecret>, payment_id: PaymentId) -> Result<(), PaymentSendFailure> {
+		if trampoline_hops.is_empty() {
+			return Err(PaymentSendFailure::ParameterError(APIError::RouteError{err: "Trampoline hops didn't go anywhere"}));
+		}
+		self.send_payment_internal(route, trampoline_hops, None, payment_hash, payment_secret, Vec::new(), payment_id, false)
+	}
+
+	/// Sends a payment of amount_msat into a blinded path the recipient provided in place of its
+	/// node_id, so that we only need a route to the path's introduction node.
+	///
+	/// The route must contain a single path to the introduction node, the last hop of which must
+	/// carry amount_msat plus the blinded path's fees (see BlindedPath::fee_msat) and the blinded
+	/// path's cltv_expiry_delta plus final_cltv_expiry_delta, the recipient's own final CLTV
+	/// delta. No payment_secret is needed as the blinded path authenticates us to the recipient.
+	///
+	/// The payment is otherwise tracked, retried and abandoned exactly as those sent via
+	/// send_payment, and errors are returned as they are from send_payment.
+	pub fn send_payment_to_blinded_path(&self, route: &Route, blinded_path: &BlindedPath, amount_msat: u64, final_cltv_expiry_delta: u32, payment_hash: PaymentHash, payment_id: PaymentId) -> Result<(), PaymentSendFailure> {
+		let tail = BlindedPaymentTail { blinded_path: blinded_path.clone(), amount_msat, final_cltv_expiry_delta };
+		self.send_payment_internal(route, &[], Some(&tail), payment_hash, &None, Vec::new(), payment_id, false)
+	}
+
+	/// Creates a blinded path to us, which may be included in an invoice in place of our node_id
+	/// and RouteHints so that payers learn neither who we are nor which channels we have.
+	///
+	/// hops lists the channels the path runs over, in order, with the src_node_id of the first
+	/// being the path's introduction node and the last being one of our channels. The fees and
+	/// CLTV delta of each must be (at least) those its src_node_id charges for forwarding over it
+	/// or it will refuse to forward. If hops is empty, we are the introduction node ourselves,
+	/// which hides only our channels.
+	///
+	/// payment_secret is encrypted to us within the path, authenticating payers exactly as a
+	/// payment_secret included in an invoice does. HTLCs received via the path are surfaced in
+	/// Event::PaymentReceived with it and must be claimed with it.
+	pub fn create_blinded_path(&self, hops: &[RouteHint], payment_secret: PaymentSecret) -> Result<BlindedPath, APIError> {
+		if hops.len() > 19 {
+			return Err(APIError::APIMisuseError { err: "Blinded paths of more than 20 hops won't fit in an onion".to_owned() });
+		}
+		if let Some(last_hop) = hops.last() {
+			if !self.channel_state.lock().unwrap().short_to_id.contains_key(&last_hop.short_channel_id) {
+				return Err(APIError::APIMisuseError { err: "The last hop of a blinded path must be one of our channels".to_owned() });
+			}
+		}
+
+		let mut path_hops = Vec::with_capacity(hops.len() + 1);
+		for hop in hops.iter() {
+			path_hops.push((hop.src_node_id, msgs::EncryptedBlindedHopData {
+				short_channel_id: Some(hop.short_channel_id),
+				path_id: None,
+				payment_relay: Some(msgs::PaymentRelay {
+					cltv_expiry_delta: hop.cltv_expiry_delta,
+					fee_proportional_millionths: hop.fees.proportional_millionths,
+					fee_base_msat: hop.fees.base_msat,
+				}),
+			}));
+		}
+		path_hops.push((self.get_our_node_id(), msgs::EncryptedBlindedHopData {
+			short_channel_id: None,
+			path_id: Some(payment_secret.0),
+			payment_relay: None,
+		}));
+
+		// Aggregate the fees and CLTV deltas of the hops, working back from us, such that paying
+		// the aggregate always covers what each hop deducts.
+		let mut fee_base_msat = 0u64;
+		let mut fee_proportional_millionths = 0u64;
+		let mut cltv_expiry_delta = 0u64;
+		for hop in hops.iter().rev() {
+			let (hop_base_msat, hop_proportional_millionths) = (hop.fees.base_msat as u64, hop.fees.proportional_millionths as u64);
+			fee_base_msat = (hop_base_msat * 1_000_000 + fee_base_msat * (1_000_000 + hop_proportional_millionths) + 999_999) / 1_000_000;
+			fee_proportional_millionths = ((fee_proportional_millionths + hop_proportional_millionths) * 1_000_000 + fee_proportional_millionths * hop_proportional_millionths + 999_999) / 1_000_000;
+			cltv_expiry_delta += hop.cltv_expiry_delta as u64;
+			if fee_base_msat > ::std::u32::MAX as u64 || fee_proportional_millionths > ::std::u32::MAX as u64 || cltv_expiry_delta > ::std::u16::MAX as u64 {
+				return Err(APIError::APIMisuseError { err: "Blinded path fees or CLTV delta overflowed".to_owned() });
+			}
+		}
+
+		let session_priv = SecretKey::from_slice(&self.keys_manager.get_secure_random_bytes()[..]).expect("RNG is busted");
+		let (blinding_point, blinded_hops) = onion_utils::construct_blinded_hops(&self.secp_ctx, &path_hops, &session_priv)
+			.map_err(|_| APIError::APIMisuseError { err: "Pubkey along blinded path was invalid".to_owned() })?;
+		Ok(BlindedPath {
+			introduction_node_id: path_hops[0].0,
+			blinding_point,
+			blinded_hops,
+			fee_base_msat: fee_base_msat as u32,
+			fee_proportional_millionths: fee_proportional_millionths as u32,
+			cltv_expiry_delta: cltv_expiry_delta as u16,
+		})
+	}
+
+	/// Sends additional HTLCs for a payment previously sent via send_payment, eg to re-send the
+	/// paths which failed to send in a PaymentSendFailure::PartialFailure or to replace paths for
+	/// which we received a PaymentFailed event.
+	///
+	/// The value of the paths in route, together with the value of all paths of the payment which
+	/// are still pending, must not exceed the value originally passed to send_payment, otherwise
+	/// a PaymentSendFailure::ParameterError is returned, preventing over-payment. Similarly, a
+	/// ParameterError is returned if the payment is not known, has been abandoned via
+	/// abandon_payment, or has already succeeded.
+	///
+	/// Errors are otherwise returned as they are from send_payment.
+	pub fn retry_payment(&self, route: &Route, payment_id: PaymentId) -> Result<(), PaymentSendFailure> {
+		let (payment_hash, payment_secret, trampoline_hops, blinded_tail, custom_tlvs) = match self.pending_outbound_payments.lock().unwrap().get(&payment_id) {
+			Some(payment) if payment.forwarded_from.is_none() => (payment.payment_hash, payment.payment_secret, payment.trampoline_hops.clone(), payment.blinded_tail.clone(), payment.custom_tlvs.clone()),
+			_ => return Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError { err: "Unknown payment_id".to_owned() })),
+		};
+		self.send_payment_internal(route, &trampoline_hops, blinded_tail.as_ref(), payment_hash, &payment_secret, custom_tlvs, payment_id, true)
+	}
+
+	fn send_payment_internal(&self, route: &Route, trampoline_hops: &[TrampolineHop], blinded_tail: Option<&BlindedPaymentTail>, payment_hash: PaymentHash, payment_secret: &Option<PaymentSecret>, custom_tlvs: Vec<(u64, Vec<u8>)>, payment_id: PaymentId, is_retry: bool) -> Result<(), PaymentSendFailure> {
 		if route.paths.len() < 1 {
 			return Err(PaymentSendFailure::ParameterError(APIError::RouteError{err: "There must be at least one path to send over"}));
 		}
@@ -1377,7 +2056,13 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			// for now more than 10 paths likely carries too much one-path failure.
 			return Err(PaymentSendFailure::ParameterError(APIError::RouteError{err: "Sending over more than 10 paths is not currently supported"}));
 		}
-		let mut total_value = 0;
+		if !trampoline_hops.is_empty() && route.paths.len() != 1 {
+			return Err(PaymentSendFailure::ParameterError(APIError::RouteError{err: "Trampoline payments must be sent over a single path"}));
+		}
+		if blinded_tail.is_some() && route.paths.len() != 1 {
+			return Err(PaymentSendFailure::ParameterError(APIError::RouteError{err: "Payments to blinded paths must be sent over a single path"}));
+		}
+		let mut route_value = 0;
 		let our_node_id = self.get_our_node_id();
 		let mut path_errs = Vec::with_capacity(route.paths.len());
 		'path_check: for path in route.paths.iter() {
@@ -1391,18 +2076,123 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					continue 'path_check;
 				}
 			}
-			total_value += path.last().unwrap().fee_msat;
+			if let Some(first_trampoline) = trampoline_hops.first() {
+				let last_hop = path.last().unwrap();
+				if last_hop.pubkey != first_trampoline.pubkey {
+					path_errs.push(Err(APIError::RouteError{err: "Path didn't end at the first trampoline node"}));
+					continue 'path_check;
+				}
+				if last_hop.fee_msat != trampoline_hops.iter().map(|hop| hop.fee_msat).sum::<u64>() ||
+						last_hop.cltv_expiry_delta != trampoline_hops.iter().map(|hop| hop.cltv_expiry_delta).sum::<u32>() {
+					path_errs.push(Err(APIError::RouteError{err: "Path value or CLTV delta didn't match the trampoline hops"}));
+					continue 'path_check;
+				}
+				route_value += trampoline_hops.last().unwrap().fee_msat;
+			} else if let Some(tail) = blinded_tail {
+				// The path itself is checked against the blinded path when building the onion.
+				route_value += tail.amount_msat;
+			} else {
+				route_value += path.last().unwrap().fee_msat;
+			}
 			path_errs.push(Ok(()));
 		}
 		if path_errs.iter().any(|e| e.is_err()) {
 			return Err(PaymentSendFailure::PathParameterError(path_errs));
 		}
 
+		let mut session_privs = Vec::with_capacity(route.paths.len());
+		for _ in 0..route.paths.len() {
+			session_privs.push(SecretKey::from_slice(&self.keys_manager.get_secure_random_bytes()[..]).expect("RNG is busted"));
+		}
+
+		// Before sending anything, check (and update) our payment tracking under a single lock,
+		// ensuring we can never send twice for the same payment_id nor over-pay on retries.
+		let total_value = {
+			let mut pending_outbound_payments = self.pending_outbound_payments.lock().unwrap();
+			let payment = match pending_outbound_payments.entry(payment_id) {
+				hash_map::Entry::Occupied(payment) => {
+					if !is_retry {
+						return Err(PaymentSendFailure::DuplicatePayment);
+					}
+					let payment = payment.into_mut();
+					if payment.abandoned {
+						return Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError { err: "Payment has been abandoned".to_owned() }));
+					}
+					if payment.payment_preimage.is_some() {
+						return Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError { err: "Payment has already succeeded".to_owned() }));
+					}
+					if payment.pending_value_msat() + route_value > payment.amount_msat {
+						return Err(PaymentSendFailure::ParameterError(APIError::RouteError { err: "Retrying this payment over the given route would over-pay the recipient" }));
+					}
+					payment
+				},
+				hash_map::Entry::Vacant(entry) => {
+					if is_retry {
+						return Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError { err: "Unknown payment_id".to_owned() }));
+					}
+					entry.insert(OutboundPayment {
+						payment_hash,
+						payment_secret: *payment_secret,
+						amount_msat: route_value,
+						paths: Vec::new(),
+						payment_preimage: None,
+						abandoned: false,
+						trampoline_hops: trampoline_hops.to_vec(),
+						forwarded_from: None,
+						blinded_tail: blinded_tail.cloned(),
+						custom_tlvs: custom_tlvs.clone(),
+					})
+				},
+			};
+			for (path, session_priv) in route.paths.iter().zip(session_privs.iter()) {
+				payment.paths.push(OutboundPaymentPath {
+					path: path.clone(),
+					session_priv: session_priv.clone(),
+					status: PaymentStatus::Pending,
+				});
+			}
+			payment.amount_msat
+		};
+
 		let cur_height = self.latest_block_height.load(Ordering::Acquire) as u32 + 1;
 		let mut results = Vec::new();
-		for path in route.paths.iter() {
-			results.push(self.send_payment_along_path(&path, &payment_hash, payment_secret, total_value, cur_height));
+		for (path, session_priv) in route.paths.iter().zip(session_privs.iter()) {
+			if let Some(tail) = blinded_tail {
+				results.push(onion_utils::build_blinded_onion(&self.secp_ctx, path, &tail.blinded_path, tail.amount_msat, tail.final_cltv_expiry_delta,
+						cur_height, session_priv, self.keys_manager.get_secure_random_bytes(), &payment_hash)
+					.and_then(|(onion_packet, htlc_msat, htlc_cltv)| self.send_onion_along_path(path, &payment_hash, payment_id, session_priv, onion_packet, htlc_msat, htlc_cltv)));
+				continue;
+			}
+			if trampoline_hops.is_empty() {
+				results.push(self.send_payment_along_path(&path, &payment_hash, payment_secret, &custom_tlvs, total_value, cur_height, payment_id, session_priv, None));
+				continue;
+			}
+			// The trampoline onion is addressed to the first trampoline node, which receives
+			// it in the final hop payload of the outer onion, with no payment_secret.
+			let trampoline_session_priv = SecretKey::from_slice(&self.keys_manager.get_secure_random_bytes()[..]).expect("RNG is busted");
+			results.push(onion_utils::build_trampoline_onion(&self.secp_ctx, trampoline_hops, payment_secret, cur_height,
+					&trampoline_session_priv, self.keys_manager.get_secure_random_bytes(), &payment_hash)
+				.and_then(|packet| self.send_payment_along_path(&path, &payment_hash, &None, &[], path.last().unwrap().fee_msat, cur_height, payment_id, session_priv, Some(packet))));
+		}
+
+		// Stop tracking any paths which never made it into a channel, as they will never be
+		// resolved (and may be retried). Note that MonitorUpdateFailed paths will be sent once the
+		// monitor update completes, so they remain pending.
+		{
+			let mut pending_outbound_payments = self.pending_outbound_payments.lock().unwrap();
+			if let hash_map::Entry::Occupied(mut payment) = pending_outbound_payments.entry(payment_id) {
+				for (res, session_priv) in results.iter().zip(session_privs.iter()) {
+					match res {
+						&Ok(()) | &Err(APIError::MonitorUpdateFailed) => {},
+						&Err(_) => payment.get_mut().paths.retain(|path| path.session_priv != *session_priv),
+					}
+				}
+				if !is_retry && payment.get().paths.is_empty() {
+					payment.remove();
+				}
+			}
 		}
+
 		let mut has_ok = false;
 		let mut has_err = false;
 		for res in results.iter() {
@@ -1425,6 +2215,110 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		}
 	}
 
+	/// Abandons the outbound payment with the given payment_id, preventing any further HTLCs
+	/// from being sent for it via retry_payment.
+	///
+	/// HTLCs which are already in flight cannot be recalled, and may still be claimed by the
+	/// recipient, in which case a PaymentSent event will be generated as usual. Once every HTLC
+	/// of the payment has been resolved without any being claimed (which may be immediately, if
+	/// none are in flight), a final Event::PaymentAbandoned is generated, after which you can be
+	/// sure no part of this payment will ever be paid.
+	///
+	/// Returns false if no such payment exists, or if it was already abandoned or has succeeded.
+	pub fn abandon_payment(&self, payment_id: &PaymentId) -> bool {
+		let mut pending_outbound_payments = self.pending_outbound_payments.lock().unwrap();
+		let abandoned_event = match pending_outbound_payments.get_mut(payment_id) {
+			Some(payment) if payment.forwarded_from.is_none() => {
+				if payment.abandoned || payment.payment_preimage.is_some() { return false; }
+				payment.abandoned = true;
+				payment.abandoned_event(*payment_id)
+			},
+			_ => return false,
+		};
+		mem::drop(pending_outbound_payments);
+		if let Some(event) = abandoned_event {
+			self.pending_events.lock().unwrap().push(event);
+		}
+		true
+	}
+
+	/// Gets the details of every outbound payment we're tracking, see payment_status.
+	pub fn list_payments(&self) -> Vec<PaymentDetails> {
+		let pending_outbound_payments = self.pending_outbound_payments.lock().unwrap();
+		pending_outbound_payments.iter().filter(|&(_, payment)| payment.forwarded_from.is_none())
+			.map(|(payment_id, payment)| payment.details(*payment_id)).collect()
+	}
+
+	/// Gets the details of the outbound payment with the given PaymentId, as passed to
+	/// send_payment, including whether it is still pending, succeeded or failed, the paths it
+	/// was sent over, the fees paid and, once it has succeeded, the payment preimage.
+	///
+	/// Returns None if no such payment exists (or it was removed via remove_payment).
+	pub fn payment_status(&self, payment_id: &PaymentId) -> Option<PaymentDetails> {
+		self.pending_outbound_payments.lock().unwrap().get(payment_id).filter(|payment| payment.forwarded_from.is_none())
+			.map(|payment| payment.details(*payment_id))
+	}
+
+	/// Stops tracking the outbound payment with the given PaymentId, removing it from
+	/// list_payments and from our serialized state. Payments are otherwise kept forever, so you
+	/// should call this once you no longer need to query a payment.
+	///
+	/// Returns false (and does nothing) if no such payment exists or if it is still pending.
+	pub fn remove_payment(&self, payment_id: &PaymentId) -> bool {
+		let mut pending_outbound_payments = self.pending_outbound_payments.lock().unwrap();
+		match pending_outbound_payments.entry(*payment_id) {
+			hash_map::Entry::Occupied(payment) => {
+				if payment.get().forwarded_from.is_some() { return false; }
+				if payment.get().status() == PaymentStatus::Pending { return false; }
+				payment.remove();
+				true
+			},
+			hash_map::Entry::Vacant(_) => false,
+		}
+	}
+
+	/// Updates our tracking of the outbound payment with the given id once the HTLC with the
+	/// given session_priv has been resolved, either successfully with a preimage or not.
+	///
+	/// If the payment was a trampoline forward rather than our own payment, it is no longer
+	/// tracked and the HTLC we received for it is returned so that it can be claimed or failed.
+	fn outbound_payment_path_resolved(&self, payment_id: &PaymentId, session_priv: &SecretKey, payment_preimage: Option<PaymentPreimage>) -> Option<HTLCPreviousHopData> {
+		let mut pending_outbound_payments = self.pending_outbound_payments.lock().unwrap();
+		let abandoned_event = if let hash_map::Entry::Occupied(mut entry) = pending_outbound_payments.entry(*payment_id) {
+			{
+				let payment = entry.get_mut();
+				match payment.paths.iter_mut().find(|path| path.session_priv == *session_priv) {
+					// We may see the same HTLC resolved more than once (eg from both the channel and
+					// a ChannelMonitor), only act on the first resolution.
+					Some(ref mut path) if path.status == PaymentStatus::Pending => {
+						path.status = if payment_preimage.is_some() { PaymentStatus::Succeeded } else { PaymentStatus::Failed };
+					},
+					_ => return None,
+				}
+				if payment_preimage.is_some() {
+					payment.payment_preimage = payment_preimage;
+				}
+			}
+			if entry.get().forwarded_from.is_some() {
+				return entry.remove().forwarded_from;
+			}
+			if entry.get().abandoned { entry.get().abandoned_event(*payment_id) } else { None }
+		} else {
+			log_trace!(self.logger, "Got a resolution for an HTLC of an untracked outbound payment (probably removed via remove_payment)");
+			None
+		};
+		mem::drop(pending_outbound_payments);
+		if let Some(event) = abandoned_event {
+			self.pending_events.lock().unwrap().push(event);
+		}
+		None
+	}
+
+	/// Checks whether the given payment is one we're forwarding as a trampoline node.
+	fn is_trampoline_forward(&self, payment_id: &PaymentId) -> bool {
+		self.pending_outbound_payments.lock().unwrap().get(payment_id).map(|payment| payment.forwarded_from.is_some()).unwrap_or(false)
+	}
+
 	/// Call this upon creation of a funding transaction for the given channel.
 	///
 	/// Note that ALL inputs in the transaction pointed to by funding_txo MUST spend SegWit outputs
@@ -1524,8 +2418,12 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			panic!("More than half the message size was taken up by public addresses!");
 		}
 
+		let mut features = NodeFeatures::known();
+		if !self.default_configuration.accept_trampoline_forwards {
+			features = features.clear_trampoline_routing();
+		}
 		let announcement = msgs::UnsignedNodeAnnouncement {
-			features: NodeFeatures::known(),
+			features,
 			timestamp: self.last_node_announcement_serial.fetch_add(1, Ordering::AcqRel) as u32,
 			node_id: self.get_our_node_id(),
 			rgb, alias, addresses,
@@ -1595,7 +2493,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 							match forward_info {
 								HTLCForwardInfo::AddHTLC { prev_short_channel_id, prev_htlc_id, forward_info: PendingHTLCInfo {
 										routing: PendingHTLCRouting::Forward {
-											onion_packet, ..
+											onion_packet, blinding_point, ..
 										}, incoming_shared_secret, payment_hash, amt_to_forward, outgoing_cltv_value },
 										prev_funding_outpoint } => {
 									log_trace!(self.logger, "Adding HTLC from short id {} with payment_hash {} to channel with short id {} after delay", log_bytes!(payment_hash.0), prev_short_channel_id, short_chan_id);
@@ -1605,7 +2503,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 										htlc_id: prev_htlc_id,
 										incoming_packet_shared_secret: incoming_shared_secret,
 									});
-									match chan.get_mut().send_htlc(amt_to_forward, payment_hash, outgoing_cltv_value, htlc_source.clone(), onion_packet) {
+									match chan.get_mut().send_htlc(amt_to_forward, payment_hash, outgoing_cltv_value, htlc_source.clone(), onion_packet, blinding_point) {
 										Err(e) => {
 											if let ChannelError::Ignore(msg) = e {
 												log_trace!(self.logger, "Failed to forward HTLC with payment_hash {}: {}", log_bytes!(payment_hash.0), msg);
@@ -1715,7 +2613,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					for forward_info in pending_forwards.drain(..) {
 						match forward_info {
 							HTLCForwardInfo::AddHTLC { prev_short_channel_id, prev_htlc_id, forward_info: PendingHTLCInfo {
-									routing: PendingHTLCRouting::Receive { payment_data, incoming_cltv_expiry },
+									routing: PendingHTLCRouting::Receive { payment_data, incoming_cltv_expiry, custom_tlvs },
 									incoming_shared_secret, payment_hash, amt_to_forward, .. },
 									prev_funding_outpoint } => {
 								let prev_hop = HTLCPreviousHopData {
@@ -1735,11 +2633,12 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 									value: amt_to_forward,
 									payment_data: payment_data.clone(),
 									cltv_expiry: incoming_cltv_expiry,
+									custom_tlvs: custom_tlvs.clone(),
 								});
 								if let &Some(ref data) = &payment_data {
 									for htlc in htlcs.iter() {
 										total_value += htlc.value;
-										if htlc.payment_data.as_ref().unwrap().total_msat != data.total_msat {
+										if htlc.payment_data.as_ref().unwrap().total_msat != data.total_msat || htlc.custom_tlvs != custom_tlvs {
 											total_value = msgs::MAX_VALUE_MSAT;
 										}
 										if total_value >= msgs::MAX_VALUE_MSAT { break; }
@@ -1767,6 +2666,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 											payment_hash,
 											payment_secret: Some(data.payment_secret),
 											amt: total_value,
+											custom_tlvs,
 										});
 									}
 								} else {
@@ -1774,11 +2674,39 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 										payment_hash,
 										payment_secret: None,
 										amt: amt_to_forward,
+										custom_tlvs,
 									});
 								}
 							},
+							HTLCForwardInfo::AddHTLC { prev_short_channel_id, prev_htlc_id, forward_info: PendingHTLCInfo {
+									routing: PendingHTLCRouting::TrampolineForward { next_node_id, trampoline_packet, payment_data, incoming_amt_msat, incoming_cltv_expiry },
+									incoming_shared_secret, payment_hash, amt_to_forward, outgoing_cltv_value },
+									prev_funding_outpoint } => {
+								// We need a route to the next trampoline node, which the user has to
+								// provide us with the network graph for, see
+								// process_pending_trampoline_forwards.
+								if channel_state.pending_trampoline_forwards.is_empty() {
+									new_events.push(events::Event::PendingTrampolineForwards {});
+								}
+								channel_state.pending_trampoline_forwards.push(PendingTrampolineForward {
+									prev_hop: HTLCPreviousHopData {
+										short_channel_id: prev_short_channel_id,
+										outpoint: prev_funding_outpoint,
+										htlc_id: prev_htlc_id,
+										incoming_packet_shared_secret: incoming_shared_secret,
+									},
+									payment_hash,
+									next_node_id,
+									trampoline_packet,
+									payment_data,
+									incoming_amt_msat,
+									incoming_cltv_expiry,
+									amt_to_forward,
+									outgoing_cltv_value,
+								});
+							},
 							HTLCForwardInfo::AddHTLC { .. } => {
-								panic!("short_channel_id == 0 should imply any pending_forward entries are of type Receive");
+								panic!("short_channel_id == 0 should imply any pending_forward entries are of type Receive or TrampolineForward");
 							},
 							HTLCForwardInfo::FailHTLC { .. } => {
 								panic!("Got pending fail of our own HTLC");
@@ -1802,6 +2730,115 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		events.append(&mut new_events);
 	}
 
+	/// Routes HTLCs we received as a trampoline node onwards to the next trampoline node (or the
+	/// recipient), finding a route to it in the given network graph.
+	///
+	/// Should be called in response to an Event::PendingTrampolineForwards. HTLCs for which no
+	/// route can be found are failed backwards with temporary_trampoline_failure, while those for
+	/// which the route found doesn't fit in the fee (including our own proportional fee) or CLTV
+	/// budget given to us by the sender are failed with trampoline_fee_insufficient or
+	/// trampoline_expiry_too_soon.
+	///
+	/// Once a trampoline forward has been sent onwards, it is resolved automatically, claiming or
+	/// failing the HTLC we received as the payment we sent is claimed or failed. Such payments are
+	/// not included in list_payments.
+	///
+	/// We only receive trampoline forwards if UserConfig::accept_trampoline_forwards is set.
+	pub fn process_pending_trampoline_forwards(&self, network_graph: &NetworkGraph) {
+		let mut pending_forwards = mem::replace(&mut self.channel_state.lock().unwrap().pending_trampoline_forwards, Vec::new());
+		if pending_forwards.is_empty() { return; }
+
+		let our_node_id = self.get_our_node_id();
+		let first_hops = self.list_usable_channels();
+		let first_hop_refs: Vec<_> = first_hops.iter().collect();
+		let cur_height = self.latest_block_height.load(Ordering::Acquire) as u32 + 1;
+
+		let mut failed_forwards = Vec::new();
+		for forward in pending_forwards.drain(..) {
+			macro_rules! fail_forward {
+				($msg: expr, $err_code: expr) => { {
+					log_info!(self.logger, "Failed to forward trampoline HTLC with payment_hash {}: {}", log_bytes!(forward.payment_hash.0), $msg);
+					failed_forwards.push((HTLCSource::PreviousHopData(forward.prev_hop), forward.payment_hash,
+						HTLCFailReason::Reason { failure_code: $err_code, data: Vec::new() }));
+					continue;
+				} }
+			}
+
+			if forward.outgoing_cltv_value <= cur_height {
+				fail_forward!("Trampoline CLTV expiry is already in the past", 0x2000 | 27);
+			}
+			let route = match get_route(&our_node_id, network_graph, &forward.next_node_id, Some(&first_hop_refs), &[],
+					forward.amt_to_forward, forward.outgoing_cltv_value - cur_height, &*self.logger) {
+				Ok(route) => route,
+				Err(e) => fail_forward!(e.err, 0x2000 | 25),
+			};
+			let mut path = route.paths[0].clone();
+			// The sender gave us either a trampoline onion or a payment_secret for the next node,
+			// neither of which can be relayed to it without variable-length onions, so it must
+			// support them even if we haven't (yet) seen its features.
+			path.last_mut().unwrap().node_features.set_variable_length_onion_optional();
+			let route_value_msat: u64 = path.iter().map(|hop| hop.fee_msat).sum();
+			let route_cltv: u32 = path.iter().map(|hop| hop.cltv_expiry_delta).sum();
+			let our_fee_msat = forward.amt_to_forward * (self.default_configuration.channel_options.fee_proportional_millionths as u64) / 1_000_000;
+			if route_value_msat + our_fee_msat > forward.incoming_amt_msat {
+				fail_forward!("Route to the next trampoline node was too expensive", 0x2000 | 26);
+			}
+			if cur_height + route_cltv + (CLTV_EXPIRY_DELTA as u32) > forward.incoming_cltv_expiry {
+				fail_forward!("Route to the next trampoline node had too large a CLTV delta", 0x2000 | 27);
+			}
+
+			// Derive the PaymentId from the HTLC we received, so that we never forward the same HTLC
+			// twice.
+			let payment_id = {
+				let mut sha = Sha256::engine();
+				sha.input(&byte_utils::be64_to_array(forward.prev_hop.short_channel_id));
+				sha.input(&byte_utils::be64_to_array(forward.prev_hop.htlc_id));
+				sha.input(&forward.payment_hash.0[..]);
+				PaymentId(Sha256::from_engine(sha).into_inner())
+			};
+			let session_priv = SecretKey::from_slice(&self.keys_manager.get_secure_random_bytes()[..]).expect("RNG is busted");
+			match self.pending_outbound_payments.lock().unwrap().entry(payment_id) {
+				hash_map::Entry::Occupied(_) => fail_forward!("Trampoline HTLC was already forwarded", 0x2000 | 25),
+				hash_map::Entry::Vacant(entry) => {
+					entry.insert(OutboundPayment {
+						payment_hash: forward.payment_hash,
+						payment_secret: None,
+						amount_msat: forward.amt_to_forward,
+						paths: vec![OutboundPaymentPath {
+							path: path.clone(),
+							session_priv: session_priv.clone(),
+							status: PaymentStatus::Pending,
+						}],
+						payment_preimage: None,
+						abandoned: false,
+						trampoline_hops: Vec::new(),
+						forwarded_from: Some(forward.prev_hop.clone()),
+						blinded_tail: None,
+						custom_tlvs: Vec::new(),
+					});
+				},
+			}
+
+			let (payment_secret, total_value) = match forward.payment_data {
+				Some(ref data) => (Some(data.payment_secret), data.total_msat),
+				None => (None, forward.amt_to_forward),
+			};
+			match self.send_payment_along_path(&path, &forward.payment_hash, &payment_secret, &[], total_value, cur_height, payment_id, &session_priv, forward.trampoline_packet.clone()) {
+				Ok(()) => {},
+				// The HTLC will be sent once the monitor update completes, so it is still pending.
+				Err(APIError::MonitorUpdateFailed) => {},
+				Err(e) => {
+					self.pending_outbound_payments.lock().unwrap().remove(&payment_id);
+					fail_forward!(format!("{:?}", e), 0x2000 | 25);
+				},
+			}
+		}
+
+		for (htlc_source, payment_hash, failure_reason) in failed_forwards.drain(..) {
+			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), htlc_source, &payment_hash, failure_reason);
+		}
+	}
+
 	/// If a peer is disconnected we mark any channels with that peer as 'disabled'.
 	/// After some time, if channels are still disabled we need to broadcast a ChannelUpdate
 	/// to inform the network about the uselessness of these channels.
@@ -1874,7 +2911,15 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					self.fail_htlc_backwards_internal(channel_state,
 						htlc_src, &payment_hash, HTLCFailReason::Reason { failure_code, data: onion_failure_data});
 				},
-				HTLCSource::OutboundRoute { .. } => {
+				HTLCSource::OutboundRoute { ref session_priv, ref payment_id, .. } => {
+					if self.is_trampoline_forward(payment_id) {
+						if let Some(prev_hop) = self.outbound_payment_path_resolved(payment_id, session_priv, None) {
+							let channel_state = self.channel_state.lock().unwrap();
+							self.fail_htlc_backwards_internal(channel_state, HTLCSource::PreviousHopData(prev_hop),
+								&payment_hash, HTLCFailReason::Reason { failure_code: 0x2000|25, data: Vec::new() });
+						}
+						continue;
+					}
 					self.pending_events.lock().unwrap().push(
 						events::Event::PaymentFailed {
 							payment_hash,
@@ -1884,7 +2929,10 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 #[cfg(test)]
 							error_data: None,
 						}
-					)
+					);
+					// Update the payment only after generating PaymentFailed so that any
+					// PaymentAbandoned event follows it.
+					self.outbound_payment_path_resolved(payment_id, session_priv, None);
 				},
 			};
 		}
@@ -1902,9 +2950,13 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		//between the branches here. We should make this async and move it into the forward HTLCs
 		//timer handling.
 		match source {
-			HTLCSource::OutboundRoute { ref path, .. } => {
+			HTLCSource::OutboundRoute { ref path, ref session_priv, ref payment_id, .. } => {
 				log_trace!(self.logger, "Failing outbound payment HTLC with payment_hash {}", log_bytes!(payment_hash.0));
 				mem::drop(channel_state_lock);
+				if self.is_trampoline_forward(payment_id) {
+					self.fail_trampoline_forward_backwards(&source, payment_hash, onion_error);
+					return;
+				}
 				match &onion_error {
 					&HTLCFailReason::LightningError { ref err } => {
 #[cfg(test)]
@@ -1957,6 +3009,9 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 						);
 					}
 				}
+				// Update the payment only after generating PaymentFailed so that any
+				// PaymentAbandoned event follows it.
+				self.outbound_payment_path_resolved(payment_id, session_priv, None);
 			},
 			HTLCSource::PreviousHopData(HTLCPreviousHopData { short_channel_id, htlc_id, incoming_packet_shared_secret, .. }) => {
 				let err_packet = match onion_error {
@@ -1994,6 +3049,38 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		}
 	}
 
+	/// Fails the HTLC we received for a trampoline forward back once the payment we sent for it
+	/// has failed. If the next trampoline node (or the recipient) rejected the payment outright
+	/// we relay its error, otherwise we return temporary_trampoline_failure.
+	fn fail_trampoline_forward_backwards(&self, source: &HTLCSource, payment_hash: &PaymentHash, onion_error: HTLCFailReason) {
+		let (path, session_priv, payment_id) = match source {
+			&HTLCSource::OutboundRoute { ref path, ref session_priv, ref payment_id, .. } => (path, session_priv, payment_id),
+			_ => unreachable!(),
+		};
+		let (failure_code, data) = match onion_error {
+			HTLCFailReason::LightningError { err } => {
+				let (channel_update, payment_retryable, onion_error_code, onion_error_data) = onion_utils::process_onion_failure(&self.secp_ctx, &self.logger, source, err.data);
+				if let Some(update) = channel_update {
+					self.channel_state.lock().unwrap().pending_msg_events.push(
+						events::MessageSendEvent::PaymentFailureNetworkUpdate {
+							update,
+						}
+					);
+				}
+				match (payment_retryable, onion_error_code) {
+					(false, Some(code)) => (code, onion_error_data.unwrap_or(Vec::new())),
+					_ => (0x2000|25, Vec::new()),
+				}
+			},
+			HTLCFailReason::Reason { .. } => (0x2000|25, Vec::new()),
+		};
+		log_trace!(self.logger, "Failing trampoline forward with payment_hash {} over path ending at {} backwards with code {}", log_bytes!(payment_hash.0), log_pubkey!(path.last().unwrap().pubkey), failure_code);
+		if let Some(prev_hop) = self.outbound_payment_path_resolved(payment_id, session_priv, None) {
+			let channel_state = self.channel_state.lock().unwrap();
+			self.fail_htlc_backwards_internal(channel_state, HTLCSource::PreviousHopData(prev_hop), payment_hash, HTLCFailReason::Reason { failure_code, data });
+		}
+	}
+
 	/// Provides a payment preimage in response to a PaymentReceived event, returning true and
 	/// generating message events for the net layer to claim the payment, if possible. Thus, you
 	/// should probably kick the net layer to go send messages if this returns true!
@@ -2145,8 +3232,14 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 
 	fn claim_funds_internal(&self, mut channel_state_lock: MutexGuard<ChannelHolder<ChanSigner>>, source: HTLCSource, payment_preimage: PaymentPreimage) {
 		match source {
-			HTLCSource::OutboundRoute { .. } => {
+			HTLCSource::OutboundRoute { session_priv, payment_id, .. } => {
 				mem::drop(channel_state_lock);
+				if let Some(prev_hop) = self.outbound_payment_path_resolved(&payment_id, &session_priv, Some(payment_preimage)) {
+					// We were forwarding this payment as a trampoline node, claim the HTLC we
+					// received for it.
+					self.claim_funds_internal(self.channel_state.lock().unwrap(), HTLCSource::PreviousHopData(prev_hop), payment_preimage);
+					return;
+				}
 				let mut pending_events = self.pending_events.lock().unwrap();
 				pending_events.push(events::Event::PaymentSent {
 					payment_preimage
@@ -2731,6 +3824,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					match channel_state.forward_htlcs.entry(match forward_info.routing {
 							PendingHTLCRouting::Forward { short_channel_id, .. } => short_channel_id,
 							PendingHTLCRouting::Receive { .. } => 0,
+							PendingHTLCRouting::TrampolineForward { .. } => 0,
 					}) {
 						hash_map::Entry::Occupied(mut entry) => {
 							entry.get_mut().push(HTLCForwardInfo::AddHTLC { prev_short_channel_id, prev_funding_outpoint,
@@ -3183,6 +4277,18 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 				});
 				!htlcs.is_empty() // Only retain this entry if htlcs has at least one entry.
 			});
+
+			channel_state.pending_trampoline_forwards.retain(|forward| {
+				// Trampoline forwards we haven't yet routed onwards time out just like HTLCs
+				// waiting to be claimed.
+				if height >= forward.incoming_cltv_expiry - HTLC_FAIL_BACK_BUFFER {
+					timed_out_htlcs.push((HTLCSource::PreviousHopData(forward.prev_hop.clone()), forward.payment_hash.clone(), HTLCFailReason::Reason {
+						failure_code: 0x2000 | 27,
+						data: Vec::new(),
+					}));
+					false
+				} else { true }
+			});
 		}
 		for failure in failed_channels.drain(..) {
 			self.finish_force_close_channel(failure);
@@ -3191,6 +4297,42 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		for (source, payment_hash, reason) in timed_out_htlcs.drain(..) {
 			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), source, &payment_hash, reason);
 		}
+		{
+			let mut recovered_channels = self.recovered_channels.lock().unwrap();
+			for recovered in recovered_channels.iter_mut() {
+				if recovered.closing_height.is_some() { continue; }
+				let funding_outpoint = recovered.backup.funding_outpoint.into_bitcoin_outpoint();
+				for &(_, tx) in txdata.iter() {
+					if tx.input.iter().any(|input| input.previous_output == funding_outpoint) {
+						log_info!(self.logger, "Detected tx {} closing recovered channel {}", tx.txid(), log_bytes!(recovered.backup.channel_id));
+						let to_remote_script = Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0)
+							.push_slice(&WPubkeyHash::hash(&recovered.backup.holder_payment_point.serialize())[..])
+							.into_script();
+						recovered.closing_height = Some(height);
+						recovered.to_remote_output = tx.output.iter().enumerate().find(|&(_, output)| output.script_pubkey == to_remote_script).map(|(idx, output)| {
+							SpendableOutputDescriptor::StaticOutputCounterpartyPayment {
+								outpoint: OutPoint { txid: tx.txid(), index: idx as u16 },
+								output: output.clone(),
+								key_derivation_params: recovered.backup.key_derivation_params,
+							}
+						});
+						break;
+					}
+				}
+			}
+			let mut pending_events = self.pending_events.lock().unwrap();
+			recovered_channels.retain(|recovered| {
+				match recovered.closing_height {
+					Some(closing_height) if closing_height + ANTI_REORG_DELAY - 1 <= height => {
+						if let Some(ref output) = recovered.to_remote_output {
+							pending_events.push(events::Event::SpendableOutputs { outputs: vec![output.clone()] });
+						}
+						false
+					},
+					_ => true,
+				}
+			});
+		}
 		self.latest_block_height.store(height as usize, Ordering::Release);
 		*self.last_block_hash.try_lock().expect("block_(dis)connected must not be called in parallel") = header_hash;
 		loop {
@@ -3239,6 +4381,15 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		for failure in failed_channels.drain(..) {
 			self.finish_force_close_channel(failure);
 		}
+		let height = self.latest_block_height.load(Ordering::Acquire) as u32;
+		for recovered in self.recovered_channels.lock().unwrap().iter_mut() {
+			if let Some(closing_height) = recovered.closing_height {
+				if closing_height >= height {
+					recovered.closing_height = None;
+					recovered.to_remote_output = None;
+				}
+			}
+		}
 		self.latest_block_height.fetch_sub(1, Ordering::AcqRel);
 		*self.last_block_hash.try_lock().expect("block_(dis)connected must not be called in parallel") = header.block_hash();
 	}
@@ -3402,8 +4553,14 @@ impl<ChanSigner: ChannelKeys, M: Deref + Sync + Send, T: Deref + Sync + Send, K:
 					&events::MessageSendEvent::BroadcastChannelAnnouncement { .. } => true,
 					&events::MessageSendEvent::BroadcastNodeAnnouncement { .. } => true,
 					&events::MessageSendEvent::BroadcastChannelUpdate { .. } => true,
+					&events::MessageSendEvent::RelayChannelAnnouncement { .. } => true,
 					&events::MessageSendEvent::HandleError { ref node_id, .. } => node_id != counterparty_node_id,
 					&events::MessageSendEvent::PaymentFailureNetworkUpdate { .. } => true,
+					&events::MessageSendEvent::SendChannelRangeQuery { .. } => false,
+					&events::MessageSendEvent::SendReplyChannelRange { .. } => false,
+					&events::MessageSendEvent::SendShortIdsQuery { .. } => false,
+					&events::MessageSendEvent::SendShortIdsReply { .. } => false,
+					&events::MessageSendEvent::SendGossipTimestampFilter { .. } => false,
 				}
 			});
 		}
@@ -3421,6 +4578,14 @@ impl<ChanSigner: ChannelKeys, M: Deref + Sync + Send, T: Deref + Sync + Send, K:
 		}
 	}
 
+	fn provided_init_features(&self) -> InitFeatures {
+		if self.default_configuration.accept_trampoline_forwards {
+			InitFeatures::known()
+		} else {
+			InitFeatures::known().clear_trampoline_routing()
+		}
+	}
+
 	fn peer_connected(&self, counterparty_node_id: &PublicKey, init_msg: &msgs::Init) {
 		log_debug!(self.logger, "Generating channel_reestablish events for {}", log_pubkey!(counterparty_node_id));
 
@@ -3460,9 +4625,18 @@ impl<ChanSigner: ChannelKeys, M: Deref + Sync + Send, T: Deref + Sync + Send, K:
 				}
 			} else { true }
 		});
+		for recovered in self.recovered_channels.lock().unwrap().iter() {
+			if recovered.backup.counterparty_node_id == *counterparty_node_id && recovered.closing_height.is_none() {
+				Self::request_recovered_channel_close(&recovered.backup, pending_msg_events);
+			}
+		}
 		//TODO: Also re-broadcast announcement_signatures
 	}
 
+	fn has_channels_with_peer(&self, counterparty_node_id: &PublicKey) -> bool {
+		self.channel_state.lock().unwrap().by_id.values().any(|chan| chan.get_counterparty_node_id() == *counterparty_node_id)
+	}
+
 	fn handle_error(&self, counterparty_node_id: &PublicKey, msg: &msgs::ErrorMessage) {
 		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 
@@ -3478,22 +4652,46 @@ impl<ChanSigner: ChannelKeys, M: Deref + Sync + Send, T: Deref + Sync + Send, K:
 	}
 }
 
-const SERIALIZATION_VERSION: u8 = 1;
-const MIN_SERIALIZATION_VERSION: u8 = 1;
+// Version 2 added outbound payment tracking (and the HTLCSource::OutboundRoute payment_id it
+// requires, which version 1 readers cannot parse) as well as the fields written after
+// last_node_announcement_serial.
+// Version 3 moved the ClaimableHTLC custom_tlvs from a trailer into the ClaimableHTLCs.
+const SERIALIZATION_VERSION: u8 = 2;
+const MIN_SERIALIZATION_VERSION: u8 = 2;
 
 impl Writeable for PendingHTLCInfo {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
 		match &self.routing {
-			&PendingHTLCRouting::Forward { ref onion_packet, ref short_channel_id } => {
+			&PendingHTLCRouting::Forward { ref onion_packet, ref short_channel_id, blinding_point: None } => {
 				0u8.write(writer)?;
 				onion_packet.write(writer)?;
 				short_channel_id.write(writer)?;
 			},
-			&PendingHTLCRouting::Receive { ref payment_data, ref incoming_cltv_expiry } => {
+			&PendingHTLCRouting::Forward { ref onion_packet, ref short_channel_id, blinding_point: Some(ref blinding_point) } => {
+				3u8.write(writer)?;
+				onion_packet.write(writer)?;
+				short_channel_id.write(writer)?;
+				blinding_point.write(writer)?;
+			},
+			&PendingHTLCRouting::Receive { ref payment_data, ref incoming_cltv_expiry, ref custom_tlvs } if custom_tlvs.is_empty() => {
 				1u8.write(writer)?;
 				payment_data.write(writer)?;
 				incoming_cltv_expiry.write(writer)?;
 			},
+			&PendingHTLCRouting::Receive { ref payment_data, ref incoming_cltv_expiry, ref custom_tlvs } => {
+				4u8.write(writer)?;
+				payment_data.write(writer)?;
+				incoming_cltv_expiry.write(writer)?;
+				custom_tlvs.write(writer)?;
+			},
+			&PendingHTLCRouting::TrampolineForward { ref next_node_id, ref trampoline_packet, ref payment_data, ref incoming_amt_msat, ref incoming_cltv_expiry } => {
+				2u8.write(writer)?;
+				next_node_id.write(writer)?;
+				trampoline_packet.write(writer)?;
+				payment_data.write(writer)?;
+				incoming_amt_msat.write(writer)?;
+				incoming_cltv_expiry.write(writer)?;
+			},
 		}
 		self.incoming_shared_secret.write(writer)?;
 		self.payment_hash.write(writer)?;
@@ -3510,10 +4708,29 @@ impl Readable for PendingHTLCInfo {
 				0u8 => PendingHTLCRouting::Forward {
 					onion_packet: Readable::read(reader)?,
 					short_channel_id: Readable::read(reader)?,
+					blinding_point: None,
 				},
 				1u8 => PendingHTLCRouting::Receive {
 					payment_data: Readable::read(reader)?,
 					incoming_cltv_expiry: Readable::read(reader)?,
+					custom_tlvs: Vec::new(),
+				},
+				2u8 => PendingHTLCRouting::TrampolineForward {
+					next_node_id: Readable::read(reader)?,
+					trampoline_packet: Readable::read(reader)?,
+					payment_data: Readable::read(reader)?,
+					incoming_amt_msat: Readable::read(reader)?,
+					incoming_cltv_expiry: Readable::read(reader)?,
+				},
+				3u8 => PendingHTLCRouting::Forward {
+					onion_packet: Readable::read(reader)?,
+					short_channel_id: Readable::read(reader)?,
+					blinding_point: Some(Readable::read(reader)?),
+				},
+				4u8 => PendingHTLCRouting::Receive {
+					payment_data: Readable::read(reader)?,
+					incoming_cltv_expiry: Readable::read(reader)?,
+					custom_tlvs: Readable::read(reader)?,
 				},
 				_ => return Err(DecodeError::InvalidValue),
 			},
@@ -3584,11 +4801,45 @@ impl_writeable!(HTLCPreviousHopData, 0, {
 	incoming_packet_shared_secret
 });
 
-impl_writeable!(ClaimableHTLC, 0, {
+impl Writeable for ClaimableHTLC {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		self.prev_hop.write(writer)?;
+		self.value.write(writer)?;
+		self.payment_data.write(writer)?;
+		self.cltv_expiry.write(writer)?;
+		self.custom_tlvs.write(writer)?;
+		Ok(())
+	}
+}
+
+impl Readable for ClaimableHTLC {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<ClaimableHTLC, DecodeError> {
+		Ok(ClaimableHTLC {
+			prev_hop: Readable::read(reader)?,
+			value: Readable::read(reader)?,
+			payment_data: Readable::read(reader)?,
+			cltv_expiry: Readable::read(reader)?,
+			custom_tlvs: Readable::read(reader)?,
+		})
+	}
+}
+
+impl_writeable!(RecoveredChannel, 0, {
+	backup,
+	closing_height,
+	to_remote_output
+});
+
+impl_writeable!(PendingTrampolineForward, 0, {
 	prev_hop,
-	value,
+	payment_hash,
+	next_node_id,
+	trampoline_packet,
 	payment_data,
-	cltv_expiry
+	incoming_amt_msat,
+	incoming_cltv_expiry,
+	amt_to_forward,
+	outgoing_cltv_value
 });
 
 impl Writeable for HTLCSource {
@@ -3598,11 +4849,13 @@ impl Writeable for HTLCSource {
 				0u8.write(writer)?;
 				hop_data.write(writer)?;
 			},
-			&HTLCSource::OutboundRoute { ref path, ref session_priv, ref first_hop_htlc_msat } => {
-				1u8.write(writer)?;
+			&HTLCSource::OutboundRoute { ref path, ref session_priv, ref first_hop_htlc_msat, ref payment_id } => {
+				// 1 was used for OutboundRoutes without a payment_id
+				2u8.write(writer)?;
 				path.write(writer)?;
 				session_priv.write(writer)?;
 				first_hop_htlc_msat.write(writer)?;
+				payment_id.write(writer)?;
 			}
 		}
 		Ok(())
@@ -3613,16 +4866,103 @@ impl Readable for HTLCSource {
 	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<HTLCSource, DecodeError> {
 		match <u8 as Readable>::read(reader)? {
 			0 => Ok(HTLCSource::PreviousHopData(Readable::read(reader)?)),
-			1 => Ok(HTLCSource::OutboundRoute {
+			1 => {
+				// HTLCs sent before we tracked outbound payments aren't part of any payment in
+				// pending_outbound_payments, give them a payment_id which won't match any.
+				let path = Readable::read(reader)?;
+				let session_priv: SecretKey = Readable::read(reader)?;
+				let first_hop_htlc_msat = Readable::read(reader)?;
+				let payment_id = PaymentId(Sha256::hash(&session_priv[..]).into_inner());
+				Ok(HTLCSource::OutboundRoute { path, session_priv, first_hop_htlc_msat, payment_id })
+			},
+			2 => Ok(HTLCSource::OutboundRoute {
 				path: Readable::read(reader)?,
 				session_priv: Readable::read(reader)?,
 				first_hop_htlc_msat: Readable::read(reader)?,
+				payment_id: Readable::read(reader)?,
 			}),
 			_ => Err(DecodeError::InvalidValue),
 		}
 	}
 }
 
+impl Writeable for PaymentStatus {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		match self {
+			&PaymentStatus::Pending => 0u8.write(writer),
+			&PaymentStatus::Succeeded => 1u8.write(writer),
+			&PaymentStatus::Failed => 2u8.write(writer),
+		}
+	}
+}
+
+impl Readable for PaymentStatus {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<PaymentStatus, DecodeError> {
+		match <u8 as Readable>::read(reader)? {
+			0 => Ok(PaymentStatus::Pending),
+			1 => Ok(PaymentStatus::Succeeded),
+			2 => Ok(PaymentStatus::Failed),
+			_ => Err(DecodeError::InvalidValue),
+		}
+	}
+}
+
+impl_writeable!(OutboundPaymentPath, 0, {
+	path,
+	session_priv,
+	status
+});
+
+impl_writeable!(BlindedPaymentTail, 0, {
+	blinded_path,
+	amount_msat,
+	final_cltv_expiry_delta
+});
+
+impl Writeable for OutboundPayment {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		self.payment_hash.write(writer)?;
+		self.payment_secret.write(writer)?;
+		self.amount_msat.write(writer)?;
+		(self.paths.len() as u64).write(writer)?;
+		for path in self.paths.iter() {
+			path.write(writer)?;
+		}
+		self.payment_preimage.write(writer)?;
+		self.abandoned.write(writer)?;
+		self.trampoline_hops.write(writer)?;
+		self.forwarded_from.write(writer)?;
+		self.blinded_tail.write(writer)?;
+		self.custom_tlvs.write(writer)?;
+		Ok(())
+	}
+}
+
+impl Readable for OutboundPayment {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<OutboundPayment, DecodeError> {
+		let payment_hash = Readable::read(reader)?;
+		let payment_secret = Readable::read(reader)?;
+		let amount_msat = Readable::read(reader)?;
+		let paths_count: u64 = Readable::read(reader)?;
+		let mut paths = Vec::with_capacity(cmp::min(paths_count as usize, 16));
+		for _ in 0..paths_count {
+			paths.push(Readable::read(reader)?);
+		}
+		Ok(OutboundPayment {
+			payment_hash,
+			payment_secret,
+			amount_msat,
+			paths,
+			payment_preimage: Readable::read(reader)?,
+			abandoned: Readable::read(reader)?,
+			trampoline_hops: Readable::read(reader)?,
+			forwarded_from: Readable::read(reader)?,
+			blinded_tail: Readable::read(reader)?,
+			custom_tlvs: Readable::read(reader)?,
+		})
+	}
+}
+
 impl Writeable for HTLCFailReason {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
 		match self {
@@ -3756,6 +5096,24 @@ impl<ChanSigner: ChannelKeys + Writeable, M: Deref, T: Deref, K: Deref, F: Deref
 
 		(self.last_node_announcement_serial.load(Ordering::Acquire) as u32).write(writer)?;
 
+		let pending_outbound_payments = self.pending_outbound_payments.lock().unwrap();
+		(pending_outbound_payments.len() as u64).write(writer)?;
+		for (payment_id, payment) in pending_outbound_payments.iter() {
+			payment_id.write(writer)?;
+			payment.write(writer)?;
+		}
+
+		(channel_state.pending_trampoline_forwards.len() as u64).write(writer)?;
+		for forward in channel_state.pending_trampoline_forwards.iter() {
+			forward.write(writer)?;
+		}
+
+		let recovered_channels = self.recovered_channels.lock().unwrap();
+		(recovered_channels.len() as u64).write(writer)?;
+		for recovered in recovered_channels.iter() {
+			recovered.write(writer)?;
+		}
+
 		Ok(())
 	}
 }
@@ -3867,7 +5225,7 @@ impl<'a, ChanSigner: ChannelKeys + Readable, M: Deref, T: Deref, K: Deref, F: De
         L::Target: Logger,
 {
 	fn read<R: ::std::io::Read>(reader: &mut R, mut args: ChannelManagerReadArgs<'a, ChanSigner, M, T, K, F, L>) -> Result<Self, DecodeError> {
-		let _ver: u8 = Readable::read(reader)?;
+		let ver: u8 = Readable::read(reader)?;
 		let min_ver: u8 = Readable::read(reader)?;
 		if min_ver > SERIALIZATION_VERSION {
 			return Err(DecodeError::UnknownVersion);
@@ -3939,11 +5297,22 @@ impl<'a, ChanSigner: ChannelKeys + Readable, M: Deref, T: Deref, K: Deref, F: De
 		let claimable_htlcs_count: u64 = Readable::read(reader)?;
 		let mut claimable_htlcs = HashMap::with_capacity(cmp::min(claimable_htlcs_count as usize, 128));
 		for _ in 0..claimable_htlcs_count {
-			let payment_hash = Readable::read(reader)?;
+			let payment_hash: (PaymentHash, Option<PaymentSecret>) = Readable::read(reader)?;
 			let previous_hops_len: u64 = Readable::read(reader)?;
-			let mut previous_hops = Vec::with_capacity(cmp::min(previous_hops_len as usize, MAX_ALLOC_SIZE/mem::size_of::<ClaimableHTLC>()));
+			let mut previous_hops: Vec<ClaimableHTLC> = Vec::with_capacity(cmp::min(previous_hops_len as usize, MAX_ALLOC_SIZE/mem::size_of::<ClaimableHTLC>()));
 			for _ in 0..previous_hops_len {
-				previous_hops.push(Readable::read(reader)?);
+				if ver >= 2 {
+					previous_hops.push(Readable::read(reader)?);
+				} else {
+					// Version 1 ClaimableHTLCs have no custom_tlvs.
+					previous_hops.push(ClaimableHTLC {
+						prev_hop: Readable::read(reader)?,
+						value: Readable::read(reader)?,
+						payment_data: Readable::read(reader)?,
+						cltv_expiry: Readable::read(reader)?,
+						custom_tlvs: Vec::new(),
+					});
+				}
 			}
 			claimable_htlcs.insert(payment_hash, previous_hops);
 		}
@@ -3969,6 +5338,32 @@ impl<'a, ChanSigner: ChannelKeys + Readable, M: Deref, T: Deref, K: Deref, F: De
 
 		let last_node_announcement_serial: u32 = Readable::read(reader)?;
 
+		// Version 1 ChannelManagers end here, all later fields default to empty.
+		let mut pending_outbound_payments = HashMap::new();
+		let mut pending_trampoline_forwards = Vec::new();
+		let mut recovered_channels = Vec::new();
+		if ver >= 2 {
+			let outbound_payments_count: u64 = Readable::read(reader)?;
+			pending_outbound_payments.reserve(cmp::min(outbound_payments_count as usize, MAX_ALLOC_SIZE/mem::size_of::<(PaymentId, OutboundPayment)>()));
+			for _ in 0..outbound_payments_count {
+				let payment_id = Readable::read(reader)?;
+				let payment = Readable::read(reader)?;
+				pending_outbound_payments.insert(payment_id, payment);
+			}
+
+			let pending_trampoline_forwards_count: u64 = Readable::read(reader)?;
+			pending_trampoline_forwards.reserve(cmp::min(pending_trampoline_forwards_count as usize, MAX_ALLOC_SIZE/mem::size_of::<PendingTrampolineForward>()));
+			for _ in 0..pending_trampoline_forwards_count {
+				pending_trampoline_forwards.push(Readable::read(reader)?);
+			}
+
+			let recovered_channels_count: u64 = Readable::read(reader)?;
+			recovered_channels.reserve(cmp::min(recovered_channels_count as usize, MAX_ALLOC_SIZE/mem::size_of::<RecoveredChannel>()));
+			for _ in 0..recovered_channels_count {
+				recovered_channels.push(Readable::read(reader)?);
+			}
+		}
+
 		let channel_manager = ChannelManager {
 			genesis_hash,
 			fee_estimator: args.fee_estimator,
@@ -3984,6 +5379,7 @@ impl<'a, ChanSigner: ChannelKeys + Readable, M: Deref, T: Deref, K: Deref, F: De
 				short_to_id,
 				forward_htlcs,
 				claimable_htlcs,
+				pending_trampoline_forwards,
 				pending_msg_events: Vec::new(),
 			}),
 			our_network_key: args.keys_manager.get_node_secret(),
@@ -3993,6 +5389,8 @@ impl<'a, ChanSigner: ChannelKeys + Readable, M: Deref, T: Deref, K: Deref, F: De
 			per_peer_state: RwLock::new(per_peer_state),
 
 			pending_events: Mutex::new(pending_events_read),
+			pending_outbound_payments: Mutex::new(pending_outbound_payments),
+			recovered_channels: Mutex::new(recovered_channels),
 			total_consistency_lock: RwLock::new(()),
 			keys_manager: args.keys_manager,
 			logger: args.logger,
@@ -4009,3 +5407,38 @@ impl<'a, ChanSigner: ChannelKeys + Readable, M: Deref, T: Deref, K: Deref, F: De
 		Ok((last_block_hash.clone(), channel_manager))
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use chain::transaction::OutPoint;
+	use ln::channelmanager::{ClaimableHTLC, HTLCPreviousHopData};
+	use util::ser::{Readable, Writeable};
+
+	use bitcoin::hashes::Hash;
+	use bitcoin::hash_types::Txid;
+
+	#[test]
+	fn claimable_htlc_serialization() {
+		// The custom TLV records of a ClaimableHTLC are serialized along with it.
+		let htlc = ClaimableHTLC {
+			prev_hop: HTLCPreviousHopData {
+				short_channel_id: 42,
+				htlc_id: 1,
+				incoming_packet_shared_secret: [2; 32],
+				outpoint: OutPoint { txid: Txid::from_slice(&[3; 32]).unwrap(), index: 0 },
+			},
+			value: 100_000,
+			payment_data: None,
+			cltv_expiry: 500,
+			custom_tlvs: vec![(65537, vec![0x42; 100]), (5482373485, b"episode 42".to_vec())],
+		};
+		let encoded = htlc.encode();
+		let mut reader = &encoded[..];
+		let read_htlc: ClaimableHTLC = Readable::read(&mut reader).unwrap();
+		assert!(reader.is_empty());
+		assert_eq!(read_htlc.prev_hop.short_channel_id, 42);
+		assert_eq!(read_htlc.value, 100_000);
+		assert_eq!(read_htlc.cltv_expiry, 500);
+		assert_eq!(read_htlc.custom_tlvs, htlc.custom_tlvs);
+	}
+}
@@ -101,14 +101,34 @@ mod sealed {
 			StaticRemoteKey,
 			// Byte 2
 			,
+			// Byte 3
+			,
+			// Byte 4
+			,
+			// Byte 5
+			,
+			// Byte 6
+			,
+			// Byte 7
+			,
 		],
 		optional_features: [
 			// Byte 0
-			DataLossProtect | InitialRoutingSync | UpfrontShutdownScript,
+			DataLossProtect | InitialRoutingSync | UpfrontShutdownScript | GossipQueries,
 			// Byte 1
 			VariableLengthOnion | PaymentSecret,
 			// Byte 2
 			BasicMPP,
+			// Byte 3
+			,
+			// Byte 4
+			,
+			// Byte 5
+			,
+			// Byte 6
+			,
+			// Byte 7
+			TrampolineRouting,
 		],
 	});
 	define_context!(NodeContext {
@@ -119,14 +139,34 @@ mod sealed {
 			StaticRemoteKey,
 			// Byte 2
 			,
+			// Byte 3
+			,
+			// Byte 4
+			,
+			// Byte 5
+			,
+			// Byte 6
+			,
+			// Byte 7
+			,
 		],
 		optional_features: [
 			// Byte 0
-			DataLossProtect | UpfrontShutdownScript,
+			DataLossProtect | UpfrontShutdownScript | GossipQueries,
 			// Byte 1
 			VariableLengthOnion | PaymentSecret,
 			// Byte 2
 			BasicMPP,
+			// Byte 3
+			,
+			// Byte 4
+			,
+			// Byte 5
+			,
+			// Byte 6
+			,
+			// Byte 7
+			TrampolineRouting,
 		],
 	});
 	define_context!(ChannelContext {
@@ -243,6 +283,8 @@ mod sealed {
 		"Feature flags for `initial_routing_sync`.");
 	define_feature!(5, UpfrontShutdownScript, [InitContext, NodeContext],
 		"Feature flags for `option_upfront_shutdown_script`.");
+	define_feature!(7, GossipQueries, [InitContext, NodeContext],
+		"Feature flags for `gossip_queries`.");
 	define_feature!(9, VariableLengthOnion, [InitContext, NodeContext],
 		"Feature flags for `var_onion_optin`.");
 	define_feature!(13, StaticRemoteKey, [InitContext, NodeContext],
@@ -251,6 +293,8 @@ mod sealed {
 		"Feature flags for `payment_secret`.");
 	define_feature!(17, BasicMPP, [InitContext, NodeContext],
 		"Feature flags for `basic_mpp`.");
+	define_feature!(57, TrampolineRouting, [InitContext, NodeContext],
+		"Feature flags for `option_trampoline_routing`.");
 
 	#[cfg(test)]
 	define_context!(TestingContext {
@@ -473,6 +517,21 @@ impl<T: sealed::UpfrontShutdownScript> Features<T> {
 	}
 }
 
+impl<T: sealed::GossipQueries> Features<T> {
+	#[cfg(test)]
+	pub(crate) fn requires_gossip_queries(&self) -> bool {
+		<T as sealed::GossipQueries>::requires_feature(&self.flags)
+	}
+	pub(crate) fn supports_gossip_queries(&self) -> bool {
+		<T as sealed::GossipQueries>::supports_feature(&self.flags)
+	}
+	#[cfg(test)]
+	pub(crate) fn clear_gossip_queries(mut self) -> Self {
+		<T as sealed::GossipQueries>::clear_bits(&mut self.flags);
+		self
+	}
+}
+
 impl<T: sealed::VariableLengthOnion> Features<T> {
 	#[cfg(test)]
 	pub(crate) fn requires_variable_length_onion(&self) -> bool {
@@ -481,6 +540,9 @@ impl<T: sealed::VariableLengthOnion> Features<T> {
 	pub(crate) fn supports_variable_length_onion(&self) -> bool {
 		<T as sealed::VariableLengthOnion>::supports_feature(&self.flags)
 	}
+	pub(crate) fn set_variable_length_onion_optional(&mut self) {
+		<T as sealed::VariableLengthOnion>::set_optional_bit(&mut self.flags)
+	}
 }
 
 impl<T: sealed::StaticRemoteKey> Features<T> {
@@ -528,6 +590,20 @@ impl<T: sealed::BasicMPP> Features<T> {
 	}
 }
 
+impl<T: sealed::TrampolineRouting> Features<T> {
+	#[cfg(test)]
+	pub(crate) fn requires_trampoline_routing(&self) -> bool {
+		<T as sealed::TrampolineRouting>::requires_feature(&self.flags)
+	}
+	pub(crate) fn supports_trampoline_routing(&self) -> bool {
+		<T as sealed::TrampolineRouting>::supports_feature(&self.flags)
+	}
+	pub(crate) fn clear_trampoline_routing(mut self) -> Self {
+		<T as sealed::TrampolineRouting>::clear_bits(&mut self.flags);
+		self
+	}
+}
+
 impl<T: sealed::Context> Writeable for Features<T> {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
 		w.size_hint(self.flags.len() + 2);
@@ -573,6 +649,11 @@ mod tests {
 		assert!(!InitFeatures::known().requires_data_loss_protect());
 		assert!(!NodeFeatures::known().requires_data_loss_protect());
 
+		assert!(InitFeatures::known().supports_gossip_queries());
+		assert!(NodeFeatures::known().supports_gossip_queries());
+		assert!(!InitFeatures::known().requires_gossip_queries());
+		assert!(!NodeFeatures::known().requires_gossip_queries());
+
 		assert!(InitFeatures::known().supports_variable_length_onion());
 		assert!(NodeFeatures::known().supports_variable_length_onion());
 		assert!(!InitFeatures::known().requires_variable_length_onion());
@@ -593,6 +674,11 @@ mod tests {
 		assert!(!InitFeatures::known().requires_basic_mpp());
 		assert!(!NodeFeatures::known().requires_basic_mpp());
 
+		assert!(InitFeatures::known().supports_trampoline_routing());
+		assert!(NodeFeatures::known().supports_trampoline_routing());
+		assert!(!InitFeatures::known().requires_trampoline_routing());
+		assert!(!NodeFeatures::known().requires_trampoline_routing());
+
 		let mut init_features = InitFeatures::known();
 		assert!(init_features.initial_routing_sync());
 		init_features.clear_initial_routing_sync();
@@ -627,13 +713,19 @@ mod tests {
 		let node_features: NodeFeatures = init_features.to_context();
 		{
 			// Check that the flags are as expected:
-			// - option_data_loss_protect
+			// - option_data_loss_protect | gossip_queries
 			// - var_onion_optin | static_remote_key (req) | payment_secret
 			// - basic_mpp
-			assert_eq!(node_features.flags.len(), 3);
-			assert_eq!(node_features.flags[0], 0b00000010);
+			// - option_trampoline_routing
+			assert_eq!(node_features.flags.len(), 8);
+			assert_eq!(node_features.flags[0], 0b10000010);
 			assert_eq!(node_features.flags[1], 0b10010010);
 			assert_eq!(node_features.flags[2], 0b00000010);
+			assert_eq!(node_features.flags[3], 0b00000000);
+			assert_eq!(node_features.flags[4], 0b00000000);
+			assert_eq!(node_features.flags[5], 0b00000000);
+			assert_eq!(node_features.flags[6], 0b00000000);
+			assert_eq!(node_features.flags[7], 0b00000010);
 		}
 
 		// Check that cleared flags are kept blank when converting back:
@@ -15,12 +15,18 @@ use chain::Watch;
 use chain::channelmonitor;
 use chain::channelmonitor::{ChannelMonitor, CLTV_CLAIM_BUFFER, LATENCY_GRACE_PERIOD_BLOCKS, ANTI_REORG_DELAY};
 use chain::transaction::OutPoint;
-use chain::keysinterface::{ChannelKeys, KeysInterface, SpendableOutputDescriptor};
+use chain::watchtower::{Watchtower, WatchtowerClient};
+use chain::sweeper::{OutputSweeper, OutputSweeperReadArgs, SWEEP_BUMP_INTERVAL};
+use chain::chainmonitor::BATCH_CLAIM_BUMP_INTERVAL;
+use chain::chaininterface::MIN_RELAY_FEE_SAT_PER_1000_WEIGHT;
+use chain::keysinterface::{Bip32KeysManager, ChannelKeys, KeysInterface, KeysManager, OutputSpender, SpendableOutputDescriptor};
 use ln::channel::{COMMITMENT_TX_BASE_WEIGHT, COMMITMENT_TX_WEIGHT_PER_HTLC};
-use ln::channelmanager::{ChannelManager, ChannelManagerReadArgs, RAACommitmentOrder, PaymentPreimage, PaymentHash, PaymentSecret, PaymentSendFailure, BREAKDOWN_TIMEOUT};
+use ln::channelmanager::{ChannelManager, ChannelManagerReadArgs, RAACommitmentOrder, PaymentPreimage, PaymentHash, PaymentSecret, PaymentId, PaymentSendFailure, PaymentStatus, BREAKDOWN_TIMEOUT};
 use ln::channel::{Channel, ChannelError};
+use ln::channel_backup::StaticChannelBackup;
 use ln::{chan_utils, onion_utils};
-use routing::router::{Route, RouteHop, get_route};
+use routing::router::{BlindedPath, Route, RouteHint, RouteHop, TrampolineHop, get_route};
+use routing::network_graph::NetworkGraph;
 use ln::features::{ChannelFeatures, InitFeatures, NodeFeatures};
 use ln::msgs;
 use ln::msgs::{ChannelMessageHandler,RoutingMessageHandler,HTLCFailChannelUpdate, ErrorAction};
@@ -169,7 +175,7 @@ fn test_async_inbound_update_fee() {
 	// ...but before it's delivered, nodes[1] starts to send a payment back to nodes[0]...
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	let net_graph_msg_handler = &nodes[1].net_graph_msg_handler;
-	nodes[1].node.send_payment(&get_route(&nodes[1].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[0].node.get_our_node_id(), None, &Vec::new(), 40000, TEST_FINAL_CLTV, &logger).unwrap(), our_payment_hash, &None).unwrap();
+	nodes[1].node.send_payment(&get_route(&nodes[1].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[0].node.get_our_node_id(), None, &Vec::new(), 40000, TEST_FINAL_CLTV, &logger).unwrap(), our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 	check_added_monitors!(nodes[1], 1);
 
 	let payment_event = {
@@ -267,7 +273,7 @@ fn test_update_fee_unordered_raa() {
 	// ...but before it's delivered, nodes[1] starts to send a payment back to nodes[0]...
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	let net_graph_msg_handler = &nodes[1].net_graph_msg_handler;
-	nodes[1].node.send_payment(&get_route(&nodes[1].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[0].node.get_our_node_id(), None, &Vec::new(), 40000, TEST_FINAL_CLTV, &logger).unwrap(), our_payment_hash, &None).unwrap();
+	nodes[1].node.send_payment(&get_route(&nodes[1].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[0].node.get_our_node_id(), None, &Vec::new(), 40000, TEST_FINAL_CLTV, &logger).unwrap(), our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 	check_added_monitors!(nodes[1], 1);
 
 	let payment_event = {
@@ -657,7 +663,7 @@ fn test_update_fee_with_fundee_update_add_htlc() {
 	let route = get_route(&nodes[1].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[0].node.get_our_node_id(), None, &Vec::new(), 800000, TEST_FINAL_CLTV, &logger).unwrap();
 
 	// nothing happens since node[1] is in AwaitingRemoteRevoke
-	nodes[1].node.send_payment(&route, our_payment_hash, &None).unwrap();
+	nodes[1].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 	{
 		let mut added_monitors = nodes[0].chain_monitor.added_monitors.lock().unwrap();
 		assert_eq!(added_monitors.len(), 0);
@@ -880,8 +886,8 @@ fn updates_shutdown_wait() {
 	let net_graph_msg_handler1 = &nodes[1].net_graph_msg_handler;
 	let route_1 = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler0.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 100000, TEST_FINAL_CLTV, &logger).unwrap();
 	let route_2 = get_route(&nodes[1].node.get_our_node_id(), &net_graph_msg_handler1.network_graph.read().unwrap(), &nodes[0].node.get_our_node_id(), None, &[], 100000, TEST_FINAL_CLTV, &logger).unwrap();
-	unwrap_send_err!(nodes[0].node.send_payment(&route_1, payment_hash, &None), true, APIError::ChannelUnavailable {..}, {});
-	unwrap_send_err!(nodes[1].node.send_payment(&route_2, payment_hash, &None), true, APIError::ChannelUnavailable {..}, {});
+	unwrap_send_err!(nodes[0].node.send_payment(&route_1, payment_hash, &None, PaymentId(payment_hash.0)), true, APIError::ChannelUnavailable {..}, {});
+	unwrap_send_err!(nodes[1].node.send_payment(&route_2, payment_hash, &None, PaymentId(payment_hash.0)), true, APIError::ChannelUnavailable {..}, {});
 
 	assert!(nodes[2].node.claim_funds(our_payment_preimage, &None, 100_000));
 	check_added_monitors!(nodes[2], 1);
@@ -943,7 +949,7 @@ fn htlc_fail_async_shutdown() {
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &[], 100000, TEST_FINAL_CLTV, &logger).unwrap();
-	nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+	nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 	check_added_monitors!(nodes[0], 1);
 	let updates = get_htlc_update_msgs!(nodes[0], nodes[1].node.get_our_node_id());
 	assert_eq!(updates.update_add_htlcs.len(), 1);
@@ -1318,7 +1324,7 @@ fn holding_cell_htlc_counting() {
 		let (payment_preimage, payment_hash) = get_payment_preimage_hash!(nodes[0]);
 		let net_graph_msg_handler = &nodes[1].net_graph_msg_handler;
 		let route = get_route(&nodes[1].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &Vec::new(), 100000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[1].node.send_payment(&route, payment_hash, &None).unwrap();
+		nodes[1].node.send_payment(&route, payment_hash, &None, PaymentId(payment_hash.0)).unwrap();
 		payments.push((payment_preimage, payment_hash));
 	}
 	check_added_monitors!(nodes[1], 1);
@@ -1335,7 +1341,7 @@ fn holding_cell_htlc_counting() {
 	{
 		let net_graph_msg_handler = &nodes[1].net_graph_msg_handler;
 		let route = get_route(&nodes[1].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &Vec::new(), 100000, TEST_FINAL_CLTV, &logger).unwrap();
-		unwrap_send_err!(nodes[1].node.send_payment(&route, payment_hash_1, &None), true, APIError::ChannelUnavailable { ref err },
+		unwrap_send_err!(nodes[1].node.send_payment(&route, payment_hash_1, &None, PaymentId(payment_hash_1.0)), true, APIError::ChannelUnavailable { ref err },
 			assert!(regex::Regex::new(r"Cannot push more than their max accepted HTLCs \(\d+\)").unwrap().is_match(err)));
 		assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
 		nodes[1].logger.assert_log_contains("lightning::ln::channelmanager".to_string(), "Cannot push more than their max accepted HTLCs".to_string(), 1);
@@ -1346,7 +1352,7 @@ fn holding_cell_htlc_counting() {
 	{
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &Vec::new(), 100000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, payment_hash_2, &None).unwrap();
+		nodes[0].node.send_payment(&route, payment_hash_2, &None, PaymentId(payment_hash_2.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 	}
 
@@ -1551,7 +1557,7 @@ fn test_basic_channel_reserve() {
 	let max_can_send = 5000000 - channel_reserve - commit_tx_fee;
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes.last().unwrap().node.get_our_node_id(), None, &Vec::new(), max_can_send + 1, TEST_FINAL_CLTV, &logger).unwrap();
-	let err = nodes[0].node.send_payment(&route, our_payment_hash, &None).err().unwrap();
+	let err = nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).err().unwrap();
 	match err {
 		PaymentSendFailure::AllFailedRetrySafe(ref fails) => {
 			match &fails[0] {
@@ -1594,7 +1600,7 @@ fn test_fee_spike_violation_fails_htlc() {
 	let cur_height = nodes[1].node.latest_block_height.load(Ordering::Acquire) as u32 + 1;
 
 	let onion_keys = onion_utils::construct_onion_keys(&secp_ctx, &route.paths[0], &session_priv).unwrap();
-	let (onion_payloads, htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(&route.paths[0], 3460001, &None, cur_height).unwrap();
+	let (onion_payloads, htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(&route.paths[0], 3460001, &None, &[], cur_height, None).unwrap();
 	let onion_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, [0; 32], &payment_hash);
 	let msg = msgs::UpdateAddHTLC {
 		channel_id: chan.2,
@@ -1603,6 +1609,7 @@ fn test_fee_spike_violation_fails_htlc() {
 		payment_hash: payment_hash,
 		cltv_expiry: htlc_cltv,
 		onion_routing_packet: onion_packet,
+		blinding_point: None,
 	};
 
 	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &msg);
@@ -1765,7 +1772,7 @@ fn test_chan_reserve_violation_outbound_htlc_inbound_chan() {
 	};
 
 	let (route, our_payment_hash, _) = get_route_and_payment_hash!(1000);
-	unwrap_send_err!(nodes[1].node.send_payment(&route, our_payment_hash, &None), true, APIError::ChannelUnavailable { ref err },
+	unwrap_send_err!(nodes[1].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)), true, APIError::ChannelUnavailable { ref err },
 		assert_eq!(err, "Cannot send value that would put counterparty balance under holder-announced channel reserve value"));
 	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
 	nodes[1].logger.assert_log("lightning::ln::channelmanager".to_string(), "Cannot send value that would put counterparty balance under holder-announced channel reserve value".to_string(), 1);
@@ -1802,7 +1809,7 @@ fn test_chan_reserve_violation_inbound_htlc_outbound_channel() {
 	let session_priv = SecretKey::from_slice(&[42; 32]).unwrap();
 	let cur_height = nodes[1].node.latest_block_height.load(Ordering::Acquire) as u32 + 1;
 	let onion_keys = onion_utils::construct_onion_keys(&secp_ctx, &route.paths[0], &session_priv).unwrap();
-	let (onion_payloads, htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(&route.paths[0], 1000, &None, cur_height).unwrap();
+	let (onion_payloads, htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(&route.paths[0], 1000, &None, &[], cur_height, None).unwrap();
 	let onion_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, [0; 32], &payment_hash);
 	let msg = msgs::UpdateAddHTLC {
 		channel_id: chan.2,
@@ -1811,6 +1818,7 @@ fn test_chan_reserve_violation_inbound_htlc_outbound_channel() {
 		payment_hash: payment_hash,
 		cltv_expiry: htlc_cltv,
 		onion_routing_packet: onion_packet,
+		blinding_point: None,
 	};
 
 	nodes[0].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &msg);
@@ -1854,7 +1862,7 @@ fn test_chan_reserve_violation_inbound_htlc_inbound_chan() {
 	// Add a pending HTLC.
 	let (route_1, our_payment_hash_1, _) = get_route_and_payment_hash!(amt_msat_1);
 	let payment_event_1 = {
-		nodes[0].node.send_payment(&route_1, our_payment_hash_1, &None).unwrap();
+		nodes[0].node.send_payment(&route_1, our_payment_hash_1, &None, PaymentId(our_payment_hash_1.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 
 		let mut events = nodes[0].node.get_and_clear_pending_msg_events();
@@ -1874,7 +1882,7 @@ fn test_chan_reserve_violation_inbound_htlc_inbound_chan() {
 	let session_priv = SecretKey::from_slice(&[42; 32]).unwrap();
 	let cur_height = nodes[0].node.latest_block_height.load(Ordering::Acquire) as u32 + 1;
 	let onion_keys = onion_utils::construct_onion_keys(&secp_ctx, &route_2.paths[0], &session_priv).unwrap();
-	let (onion_payloads, htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(&route_2.paths[0], recv_value_2, &None, cur_height).unwrap();
+	let (onion_payloads, htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(&route_2.paths[0], recv_value_2, &None, &[], cur_height, None).unwrap();
 	let onion_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, [0; 32], &our_payment_hash_1);
 	let msg = msgs::UpdateAddHTLC {
 		channel_id: chan.2,
@@ -1883,6 +1891,7 @@ fn test_chan_reserve_violation_inbound_htlc_inbound_chan() {
 		payment_hash: our_payment_hash_1,
 		cltv_expiry: htlc_cltv,
 		onion_routing_packet: onion_packet,
+		blinding_point: None,
 	};
 
 	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &msg);
@@ -1960,9 +1969,11 @@ fn test_channel_reserve_holding_cell_htlcs() {
 
 	// attempt to send amt_msat > their_max_htlc_value_in_flight_msat
 	{
-		let (route, our_payment_hash, _) = get_route_and_payment_hash!(recv_value_0 + 1);
+		// get_route won't exceed the channel's htlc_maximum_msat, so go over it manually.
+		let (mut route, our_payment_hash, _) = get_route_and_payment_hash!(recv_value_0);
+		route.paths[0].last_mut().unwrap().fee_msat += 1;
 		assert!(route.paths[0].iter().rev().skip(1).all(|h| h.fee_msat == feemsat));
-		unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None), true, APIError::ChannelUnavailable { ref err },
+		unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)), true, APIError::ChannelUnavailable { ref err },
 			assert!(regex::Regex::new(r"Cannot send value that would put us over the max HTLC value in flight our peer will accept \(\d+\)").unwrap().is_match(err)));
 		assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
 		nodes[0].logger.assert_log_contains("lightning::ln::channelmanager".to_string(), "Cannot send value that would put us over the max HTLC value in flight our peer will accept".to_string(), 1);
@@ -2013,7 +2024,7 @@ fn test_channel_reserve_holding_cell_htlcs() {
 
 	let (route_1, our_payment_hash_1, our_payment_preimage_1) = get_route_and_payment_hash!(recv_value_1);
 	let payment_event_1 = {
-		nodes[0].node.send_payment(&route_1, our_payment_hash_1, &None).unwrap();
+		nodes[0].node.send_payment(&route_1, our_payment_hash_1, &None, PaymentId(our_payment_hash_1.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 
 		let mut events = nodes[0].node.get_and_clear_pending_msg_events();
@@ -2026,7 +2037,7 @@ fn test_channel_reserve_holding_cell_htlcs() {
 	let recv_value_2 = stat01.value_to_self_msat - amt_msat_1 - stat01.channel_reserve_msat - total_fee_msat - commit_tx_fee_2_htlcs;
 	{
 		let (route, our_payment_hash, _) = get_route_and_payment_hash!(recv_value_2 + 1);
-		unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None), true, APIError::ChannelUnavailable { ref err },
+		unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)), true, APIError::ChannelUnavailable { ref err },
 			assert!(regex::Regex::new(r"Cannot send value that would put our balance under counterparty-announced channel reserve value \(\d+\)").unwrap().is_match(err)));
 		assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
 	}
@@ -2044,7 +2055,7 @@ fn test_channel_reserve_holding_cell_htlcs() {
 	// now see if they go through on both sides
 	let (route_21, our_payment_hash_21, our_payment_preimage_21) = get_route_and_payment_hash!(recv_value_21);
 	// but this will stuck in the holding cell
-	nodes[0].node.send_payment(&route_21, our_payment_hash_21, &None).unwrap();
+	nodes[0].node.send_payment(&route_21, our_payment_hash_21, &None, PaymentId(our_payment_hash_21.0)).unwrap();
 	check_added_monitors!(nodes[0], 0);
 	let events = nodes[0].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 0);
@@ -2052,7 +2063,7 @@ fn test_channel_reserve_holding_cell_htlcs() {
 	// test with outbound holding cell amount > 0
 	{
 		let (route, our_payment_hash, _) = get_route_and_payment_hash!(recv_value_22+1);
-		unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None), true, APIError::ChannelUnavailable { ref err },
+		unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)), true, APIError::ChannelUnavailable { ref err },
 			assert!(regex::Regex::new(r"Cannot send value that would put our balance under counterparty-announced channel reserve value \(\d+\)").unwrap().is_match(err)));
 		assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
 		nodes[0].logger.assert_log_contains("lightning::ln::channelmanager".to_string(), "Cannot send value that would put our balance under counterparty-announced channel reserve value".to_string(), 2);
@@ -2060,7 +2071,7 @@ fn test_channel_reserve_holding_cell_htlcs() {
 
 	let (route_22, our_payment_hash_22, our_payment_preimage_22) = get_route_and_payment_hash!(recv_value_22);
 	// this will also stuck in the holding cell
-	nodes[0].node.send_payment(&route_22, our_payment_hash_22, &None).unwrap();
+	nodes[0].node.send_payment(&route_22, our_payment_hash_22, &None, PaymentId(our_payment_hash_22.0)).unwrap();
 	check_added_monitors!(nodes[0], 0);
 	assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
 	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
@@ -2111,7 +2122,7 @@ fn test_channel_reserve_holding_cell_htlcs() {
 	let events = nodes[2].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 2);
 	match events[0] {
-		Event::PaymentReceived { ref payment_hash, ref payment_secret, amt } => {
+		Event::PaymentReceived { ref payment_hash, ref payment_secret, amt, .. } => {
 			assert_eq!(our_payment_hash_21, *payment_hash);
 			assert_eq!(*payment_secret, None);
 			assert_eq!(recv_value_21, amt);
@@ -2119,7 +2130,7 @@ fn test_channel_reserve_holding_cell_htlcs() {
 		_ => panic!("Unexpected event"),
 	}
 	match events[1] {
-		Event::PaymentReceived { ref payment_hash, ref payment_secret, amt } => {
+		Event::PaymentReceived { ref payment_hash, ref payment_secret, amt, .. } => {
 			assert_eq!(our_payment_hash_22, *payment_hash);
 			assert_eq!(None, *payment_secret);
 			assert_eq!(recv_value_22, amt);
@@ -2135,7 +2146,7 @@ fn test_channel_reserve_holding_cell_htlcs() {
 	let recv_value_3 = commit_tx_fee_2_htlcs - commit_tx_fee_0_htlcs - total_fee_msat;
 	{
 		let (route, our_payment_hash, _) = get_route_and_payment_hash!(recv_value_3 + 1);
-		let err = nodes[0].node.send_payment(&route, our_payment_hash, &None).err().unwrap();
+		let err = nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).err().unwrap();
 		match err {
 			PaymentSendFailure::AllFailedRetrySafe(ref fails) => {
 				match &fails[0] {
@@ -2203,7 +2214,7 @@ fn channel_reserve_in_flight_removes() {
 	let send_1 = {
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 100000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, payment_hash_3, &None).unwrap();
+		nodes[0].node.send_payment(&route, payment_hash_3, &None, PaymentId(payment_hash_3.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 		let mut events = nodes[0].node.get_and_clear_pending_msg_events();
 		assert_eq!(events.len(), 1);
@@ -2276,7 +2287,7 @@ fn channel_reserve_in_flight_removes() {
 	let send_2 = {
 		let net_graph_msg_handler = &nodes[1].net_graph_msg_handler;
 		let route = get_route(&nodes[1].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[0].node.get_our_node_id(), None, &[], 10000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[1].node.send_payment(&route, payment_hash_4, &None).unwrap();
+		nodes[1].node.send_payment(&route, payment_hash_4, &None, PaymentId(payment_hash_4.0)).unwrap();
 		check_added_monitors!(nodes[1], 1);
 		let mut events = nodes[1].node.get_and_clear_pending_msg_events();
 		assert_eq!(events.len(), 1);
@@ -3225,7 +3236,7 @@ fn do_test_commitment_revoked_fail_backward_exhaustive(deliver_bs_raa: bool, use
 	let net_graph_msg_handler = &nodes[1].net_graph_msg_handler;
 	let logger = test_utils::TestLogger::new();
 	let route = get_route(&nodes[1].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-	nodes[1].node.send_payment(&route, fourth_payment_hash, &None).unwrap();
+	nodes[1].node.send_payment(&route, fourth_payment_hash, &None, PaymentId(fourth_payment_hash.0)).unwrap();
 	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
 	assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
 	check_added_monitors!(nodes[1], 0);
@@ -3371,7 +3382,7 @@ fn fail_backward_pending_htlc_upon_channel_failure() {
 		let (_, payment_hash) = get_payment_preimage_hash!(nodes[0]);
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 50_000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, payment_hash, &None).unwrap();
+		nodes[0].node.send_payment(&route, payment_hash, &None, PaymentId(payment_hash.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 
 		let payment_event = {
@@ -3388,7 +3399,7 @@ fn fail_backward_pending_htlc_upon_channel_failure() {
 	{
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 50_000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, failed_payment_hash, &None).unwrap();
+		nodes[0].node.send_payment(&route, failed_payment_hash, &None, PaymentId(failed_payment_hash.0)).unwrap();
 		check_added_monitors!(nodes[0], 0);
 
 		assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
@@ -3403,7 +3414,7 @@ fn fail_backward_pending_htlc_upon_channel_failure() {
 		let current_height = nodes[1].node.latest_block_height.load(Ordering::Acquire) as u32 + 1;
 		let net_graph_msg_handler = &nodes[1].net_graph_msg_handler;
 		let route = get_route(&nodes[1].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[0].node.get_our_node_id(), None, &Vec::new(), 50_000, TEST_FINAL_CLTV, &logger).unwrap();
-		let (onion_payloads, _amount_msat, cltv_expiry) = onion_utils::build_onion_payloads(&route.paths[0], 50_000, &None, current_height).unwrap();
+		let (onion_payloads, _amount_msat, cltv_expiry) = onion_utils::build_onion_payloads(&route.paths[0], 50_000, &None, &[], current_height, None).unwrap();
 		let onion_keys = onion_utils::construct_onion_keys(&secp_ctx, &route.paths[0], &session_priv).unwrap();
 		let onion_routing_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, [0; 32], &payment_hash);
 
@@ -3415,6 +3426,7 @@ fn fail_backward_pending_htlc_upon_channel_failure() {
 			payment_hash,
 			cltv_expiry,
 			onion_routing_packet,
+			blinding_point: None,
 		};
 		nodes[0].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &update_add_htlc);
 	}
@@ -3469,7 +3481,7 @@ fn test_force_close_fail_back() {
 	let mut payment_event = {
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &Vec::new(), 1000000, 42, &logger).unwrap();
-		nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+		nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 
 		let mut events = nodes[0].node.get_and_clear_pending_msg_events();
@@ -3645,7 +3657,7 @@ fn do_test_drop_messages_peer_disconnect(messages_delivered: u8) {
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(),
 			&nodes[1].node.get_our_node_id(), Some(&nodes[0].node.list_usable_channels().iter().collect::<Vec<_>>()),
 			&Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, payment_hash_1, &None).unwrap();
+		nodes[0].node.send_payment(&route, payment_hash_1, &None, PaymentId(payment_hash_1.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 
 		let mut events = nodes[0].node.get_and_clear_pending_msg_events();
@@ -3720,7 +3732,7 @@ fn do_test_drop_messages_peer_disconnect(messages_delivered: u8) {
 	let events_2 = nodes[1].node.get_and_clear_pending_events();
 	assert_eq!(events_2.len(), 1);
 	match events_2[0] {
-		Event::PaymentReceived { ref payment_hash, ref payment_secret, amt } => {
+		Event::PaymentReceived { ref payment_hash, ref payment_secret, amt, .. } => {
 			assert_eq!(payment_hash_1, *payment_hash);
 			assert_eq!(*payment_secret, None);
 			assert_eq!(amt, 1000000);
@@ -3944,7 +3956,7 @@ fn test_drop_messages_peer_disconnect_dual_htlc() {
 	let (payment_preimage_2, payment_hash_2) = get_payment_preimage_hash!(nodes[0]);
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-	nodes[0].node.send_payment(&route, payment_hash_2, &None).unwrap();
+	nodes[0].node.send_payment(&route, payment_hash_2, &None, PaymentId(payment_hash_2.0)).unwrap();
 	check_added_monitors!(nodes[0], 1);
 
 	let events_1 = nodes[0].node.get_and_clear_pending_msg_events();
@@ -4057,7 +4069,7 @@ fn test_drop_messages_peer_disconnect_dual_htlc() {
 	let events_5 = nodes[1].node.get_and_clear_pending_events();
 	assert_eq!(events_5.len(), 1);
 	match events_5[0] {
-		Event::PaymentReceived { ref payment_hash, ref payment_secret, amt: _ } => {
+		Event::PaymentReceived { ref payment_hash, ref payment_secret, amt: _, .. } => {
 			assert_eq!(payment_hash_2, *payment_hash);
 			assert_eq!(*payment_secret, None);
 		},
@@ -4089,7 +4101,7 @@ fn do_test_htlc_timeout(send_partial_mpp: bool) {
 		let payment_secret = PaymentSecret([0xdb; 32]);
 		// Use the utility function send_payment_along_path to send the payment with MPP data which
 		// indicates there are more HTLCs coming.
-		nodes[0].node.send_payment_along_path(&route.paths[0], &our_payment_hash, &Some(payment_secret), 200000, CHAN_CONFIRM_DEPTH).unwrap();
+		nodes[0].node.send_payment_along_path(&route.paths[0], &our_payment_hash, &Some(payment_secret), &[], 200000, CHAN_CONFIRM_DEPTH, PaymentId(our_payment_hash.0), &SecretKey::from_slice(&[42; 32]).unwrap(), None).unwrap();
 		check_added_monitors!(nodes[0], 1);
 		let mut events = nodes[0].node.get_and_clear_pending_msg_events();
 		assert_eq!(events.len(), 1);
@@ -4151,7 +4163,7 @@ fn do_test_holding_cell_htlc_add_timeouts(forwarded_htlc: bool) {
 	{
 		let net_graph_msg_handler = &nodes[1].net_graph_msg_handler;
 		let route = get_route(&nodes[1].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &Vec::new(), 100000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[1].node.send_payment(&route, first_payment_hash, &None).unwrap();
+		nodes[1].node.send_payment(&route, first_payment_hash, &None, PaymentId(first_payment_hash.0)).unwrap();
 	}
 	assert_eq!(nodes[1].node.get_and_clear_pending_msg_events().len(), 1);
 	check_added_monitors!(nodes[1], 1);
@@ -4161,7 +4173,7 @@ fn do_test_holding_cell_htlc_add_timeouts(forwarded_htlc: bool) {
 	if forwarded_htlc {
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &Vec::new(), 100000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, second_payment_hash, &None).unwrap();
+		nodes[0].node.send_payment(&route, second_payment_hash, &None, PaymentId(second_payment_hash.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 		let payment_event = SendEvent::from_event(nodes[0].node.get_and_clear_pending_msg_events().remove(0));
 		nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
@@ -4171,7 +4183,7 @@ fn do_test_holding_cell_htlc_add_timeouts(forwarded_htlc: bool) {
 	} else {
 		let net_graph_msg_handler = &nodes[1].net_graph_msg_handler;
 		let route = get_route(&nodes[1].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &Vec::new(), 100000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[1].node.send_payment(&route, second_payment_hash, &None).unwrap();
+		nodes[1].node.send_payment(&route, second_payment_hash, &None, PaymentId(second_payment_hash.0)).unwrap();
 		check_added_monitors!(nodes[1], 0);
 	}
 
@@ -4555,8 +4567,15 @@ fn test_simple_manager_serialize_deserialize() {
 
 	reconnect_nodes(&nodes[0], &nodes[1], (false, false), (0, 0), (0, 0), (0, 0), (0, 0), (false, false));
 
+	// Both payments should still be tracked, and still pending, after the reload
+	let payments = nodes[0].node.list_payments();
+	assert_eq!(payments.len(), 2);
+	assert!(payments.iter().all(|payment| payment.status == PaymentStatus::Pending));
+	let pending_payment_id = payments.iter().find(|payment| payment.payment_hash == our_payment_hash).unwrap().payment_id;
+
 	fail_payment(&nodes[0], &[&nodes[1]], our_payment_hash);
 	claim_payment(&nodes[0], &[&nodes[1]], our_payment_preimage, 1_000_000);
+	assert_eq!(nodes[0].node.payment_status(&pending_payment_id).unwrap().status, PaymentStatus::Failed);
 }
 
 #[test]
@@ -5289,8 +5308,13 @@ fn test_duplicate_payment_hash_one_failure_one_success() {
 	let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
 
 	let (our_payment_preimage, duplicate_payment_hash) = route_payment(&nodes[0], &vec!(&nodes[1], &nodes[2])[..], 900000);
-	*nodes[0].network_payment_count.borrow_mut() -= 1;
-	assert_eq!(route_payment(&nodes[0], &vec!(&nodes[1], &nodes[2])[..], 900000).1, duplicate_payment_hash);
+	// Send the second payment under a different payment_id, as otherwise send_payment would refuse
+	// to send it.
+	let logger = test_utils::TestLogger::new();
+	let route = get_route(&nodes[0].node.get_our_node_id(), &nodes[0].net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &Vec::new(), 900000, TEST_FINAL_CLTV, &logger).unwrap();
+	nodes[0].node.send_payment(&route, duplicate_payment_hash, &None, PaymentId([42; 32])).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	pass_along_route(&nodes[0], &[&[&nodes[1], &nodes[2]]], 900000, duplicate_payment_hash, None);
 
 	let commitment_txn = get_local_commitment_txn!(nodes[2], chan_2.2);
 	assert_eq!(commitment_txn[0].input.len(), 1);
@@ -5892,7 +5916,7 @@ fn do_htlc_claim_current_remote_commitment_only(use_dust: bool) {
 	let (_, payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &Vec::new(), if use_dust { 50000 } else { 3000000 }, TEST_FINAL_CLTV, &logger).unwrap();
-	nodes[0].node.send_payment(&route, payment_hash, &None).unwrap();
+	nodes[0].node.send_payment(&route, payment_hash, &None, PaymentId(payment_hash.0)).unwrap();
 	check_added_monitors!(nodes[0], 1);
 
 	let _as_update = get_htlc_update_msgs!(nodes[0], nodes[1].node.get_our_node_id());
@@ -6062,8 +6086,9 @@ fn bolt2_open_channel_sending_node_checks_part2() {
 // originated from our node, its failure is surfaced to the user. We trigger this failure to
 // free the HTLC by increasing our fee while the HTLC is in the holding cell such that the HTLC
 // is no longer affordable once it's freed.
-#[test]
-fn test_fail_holding_cell_htlc_upon_free() {
+fn do_test_fail_holding_cell_htlc_upon_free(abandon: bool) {
+	// If the payment was abandoned while its HTLC sat in the holding cell, PaymentAbandoned must
+	// follow the PaymentFailed for it.
 	let chanmon_cfgs = create_chanmon_cfgs(2);
 	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
 	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
@@ -6098,9 +6123,12 @@ fn test_fail_holding_cell_htlc_upon_free() {
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], max_can_send, TEST_FINAL_CLTV, &logger).unwrap();
 
 	// Send a payment which passes reserve checks but gets stuck in the holding cell.
-	nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+	nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 	chan_stat = get_channel_value_stat!(nodes[0], chan.2);
 	assert_eq!(chan_stat.holding_cell_outbound_amount_msat, max_can_send);
+	if abandon {
+		assert!(nodes[0].node.abandon_payment(&PaymentId(our_payment_hash.0)));
+	}
 
 	// Flush the pending fee update.
 	nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), commitment_signed);
@@ -6120,7 +6148,7 @@ fn test_fail_holding_cell_htlc_upon_free() {
 
 	// Check that the payment failed to be sent out.
 	let events = nodes[0].node.get_and_clear_pending_events();
-	assert_eq!(events.len(), 1);
+	assert_eq!(events.len(), if abandon { 2 } else { 1 });
 	match &events[0] {
 		&Event::PaymentFailed { ref payment_hash, ref rejected_by_dest, ref error_code, ref error_data } => {
 			assert_eq!(our_payment_hash.clone(), *payment_hash);
@@ -6130,6 +6158,21 @@ fn test_fail_holding_cell_htlc_upon_free() {
 		},
 		_ => panic!("Unexpected event"),
 	}
+	if abandon {
+		match &events[1] {
+			&Event::PaymentAbandoned { ref payment_id, ref payment_hash } => {
+				assert_eq!(*payment_id, PaymentId(our_payment_hash.0));
+				assert_eq!(*payment_hash, our_payment_hash);
+			},
+			_ => panic!("Unexpected event"),
+		}
+	}
+}
+
+#[test]
+fn test_fail_holding_cell_htlc_upon_free() {
+	do_test_fail_holding_cell_htlc_upon_free(false);
+	do_test_fail_holding_cell_htlc_upon_free(true);
 }
 
 // Test that if multiple HTLCs are released from the holding cell and one is
@@ -6174,10 +6217,10 @@ fn test_free_and_fail_holding_cell_htlcs() {
 	let route_2 = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], amt_2, TEST_FINAL_CLTV, &logger).unwrap();
 
 	// Send 2 payments which pass reserve checks but get stuck in the holding cell.
-	nodes[0].node.send_payment(&route_1, payment_hash_1, &None).unwrap();
+	nodes[0].node.send_payment(&route_1, payment_hash_1, &None, PaymentId(payment_hash_1.0)).unwrap();
 	chan_stat = get_channel_value_stat!(nodes[0], chan.2);
 	assert_eq!(chan_stat.holding_cell_outbound_amount_msat, amt_1);
-	nodes[0].node.send_payment(&route_2, payment_hash_2, &None).unwrap();
+	nodes[0].node.send_payment(&route_2, payment_hash_2, &None, PaymentId(payment_hash_2.0)).unwrap();
 	chan_stat = get_channel_value_stat!(nodes[0], chan.2);
 	assert_eq!(chan_stat.holding_cell_outbound_amount_msat, amt_1 + amt_2);
 
@@ -6295,7 +6338,7 @@ fn test_fail_holding_cell_htlc_upon_free_multihop() {
 	let payment_event = {
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &[], max_can_send, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+		nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 
 		let mut events = nodes[0].node.get_and_clear_pending_msg_events();
@@ -6413,7 +6456,7 @@ fn test_update_add_htlc_bolt2_sender_value_below_minimum_msat() {
 	let mut route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 100000, TEST_FINAL_CLTV, &logger).unwrap();
 	route.paths[0][0].fee_msat = 100;
 
-	unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None), true, APIError::ChannelUnavailable { ref err },
+	unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)), true, APIError::ChannelUnavailable { ref err },
 		assert!(regex::Regex::new(r"Cannot send less than their minimum HTLC value \(\d+\)").unwrap().is_match(err)));
 	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
 	nodes[0].logger.assert_log_contains("lightning::ln::channelmanager".to_string(), "Cannot send less than their minimum HTLC value".to_string(), 1);
@@ -6433,7 +6476,7 @@ fn test_update_add_htlc_bolt2_sender_zero_value_msat() {
 	let logger = test_utils::TestLogger::new();
 	let mut route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 100000, TEST_FINAL_CLTV, &logger).unwrap();
 	route.paths[0][0].fee_msat = 0;
-	unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None), true, APIError::ChannelUnavailable { ref err },
+	unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)), true, APIError::ChannelUnavailable { ref err },
 		assert_eq!(err, "Cannot send 0-msat HTLC"));
 
 	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
@@ -6453,7 +6496,7 @@ fn test_update_add_htlc_bolt2_receiver_zero_value_msat() {
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let logger = test_utils::TestLogger::new();
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 100000, TEST_FINAL_CLTV, &logger).unwrap();
-	nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+	nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 	check_added_monitors!(nodes[0], 1);
 	let mut updates = get_htlc_update_msgs!(nodes[0], nodes[1].node.get_our_node_id());
 	updates.update_add_htlcs[0].amount_msat = 0;
@@ -6478,8 +6521,8 @@ fn test_update_add_htlc_bolt2_sender_cltv_expiry_too_high() {
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
-	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 100000000, 500000001, &logger).unwrap();
-	unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None), true, APIError::RouteError { ref err },
+	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 1000000, 500000001, &logger).unwrap();
+	unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)), true, APIError::RouteError { ref err },
 		assert_eq!(err, &"Channel CLTV overflowed?"));
 }
 
@@ -6501,7 +6544,7 @@ fn test_update_add_htlc_bolt2_sender_exceed_max_htlc_num_and_htlc_id_increment()
 		let payment_event = {
 			let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 			let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 100000, TEST_FINAL_CLTV, &logger).unwrap();
-			nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+			nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 			check_added_monitors!(nodes[0], 1);
 
 			let mut events = nodes[0].node.get_and_clear_pending_msg_events();
@@ -6523,7 +6566,7 @@ fn test_update_add_htlc_bolt2_sender_exceed_max_htlc_num_and_htlc_id_increment()
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 100000, TEST_FINAL_CLTV, &logger).unwrap();
-	unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None), true, APIError::ChannelUnavailable { ref err },
+	unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)), true, APIError::ChannelUnavailable { ref err },
 		assert!(regex::Regex::new(r"Cannot push more than their max accepted HTLCs \(\d+\)").unwrap().is_match(err)));
 
 	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
@@ -6546,8 +6589,10 @@ fn test_update_add_htlc_bolt2_sender_exceed_max_htlc_value_in_flight() {
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let logger = test_utils::TestLogger::new();
-	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], max_in_flight+1, TEST_FINAL_CLTV, &logger).unwrap();
-	unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None), true, APIError::ChannelUnavailable { ref err },
+	// get_route won't exceed the channel's htlc_maximum_msat, so go over it manually.
+	let mut route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], max_in_flight, TEST_FINAL_CLTV, &logger).unwrap();
+	route.paths[0].last_mut().unwrap().fee_msat += 1;
+	unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)), true, APIError::ChannelUnavailable { ref err },
 		assert!(regex::Regex::new(r"Cannot send value that would put us over the max HTLC value in flight our peer will accept \(\d+\)").unwrap().is_match(err)));
 
 	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
@@ -6576,7 +6621,7 @@ fn test_update_add_htlc_bolt2_receiver_check_amount_received_more_than_min() {
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let logger = test_utils::TestLogger::new();
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], htlc_minimum_msat, TEST_FINAL_CLTV, &logger).unwrap();
-	nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+	nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 	check_added_monitors!(nodes[0], 1);
 	let mut updates = get_htlc_update_msgs!(nodes[0], nodes[1].node.get_our_node_id());
 	updates.update_add_htlcs[0].amount_msat = htlc_minimum_msat-1;
@@ -6607,7 +6652,7 @@ fn test_update_add_htlc_bolt2_receiver_sender_can_afford_amount_sent() {
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], max_can_send, TEST_FINAL_CLTV, &logger).unwrap();
-	nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+	nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 	check_added_monitors!(nodes[0], 1);
 	let mut updates = get_htlc_update_msgs!(nodes[0], nodes[1].node.get_our_node_id());
 
@@ -6642,7 +6687,7 @@ fn test_update_add_htlc_bolt2_receiver_check_max_htlc_limit() {
 
 	let cur_height = nodes[0].node.latest_block_height.load(Ordering::Acquire) as u32 + 1;
 	let onion_keys = onion_utils::construct_onion_keys(&Secp256k1::signing_only(), &route.paths[0], &session_priv).unwrap();
-	let (onion_payloads, _htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(&route.paths[0], 3999999, &None, cur_height).unwrap();
+	let (onion_payloads, _htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(&route.paths[0], 3999999, &None, &[], cur_height, None).unwrap();
 	let onion_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, [0; 32], &our_payment_hash);
 
 	let mut msg = msgs::UpdateAddHTLC {
@@ -6652,6 +6697,7 @@ fn test_update_add_htlc_bolt2_receiver_check_max_htlc_limit() {
 		payment_hash: our_payment_hash,
 		cltv_expiry: htlc_cltv,
 		onion_routing_packet: onion_packet.clone(),
+		blinding_point: None,
 	};
 
 	for i in 0..super::channel::OUR_MAX_HTLCS {
@@ -6680,7 +6726,7 @@ fn test_update_add_htlc_bolt2_receiver_check_max_in_flight_msat() {
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-	nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+	nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 	check_added_monitors!(nodes[0], 1);
 	let mut updates = get_htlc_update_msgs!(nodes[0], nodes[1].node.get_our_node_id());
 	updates.update_add_htlcs[0].amount_msat = get_channel_value_stat!(nodes[1], chan.2).counterparty_max_htlc_value_in_flight_msat + 1;
@@ -6705,7 +6751,7 @@ fn test_update_add_htlc_bolt2_receiver_check_cltv_expiry() {
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-	nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+	nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 	check_added_monitors!(nodes[0], 1);
 	let mut updates = get_htlc_update_msgs!(nodes[0], nodes[1].node.get_our_node_id());
 	updates.update_add_htlcs[0].cltv_expiry = 500000000;
@@ -6732,7 +6778,7 @@ fn test_update_add_htlc_bolt2_receiver_check_repeated_id_ignore() {
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-	nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+	nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 	check_added_monitors!(nodes[0], 1);
 	let updates = get_htlc_update_msgs!(nodes[0], nodes[1].node.get_our_node_id());
 	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &updates.update_add_htlcs[0]);
@@ -6779,7 +6825,7 @@ fn test_update_fulfill_htlc_bolt2_update_fulfill_htlc_before_commitment() {
 	let (our_payment_preimage, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-	nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+	nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 
 	check_added_monitors!(nodes[0], 1);
 	let updates = get_htlc_update_msgs!(nodes[0], nodes[1].node.get_our_node_id());
@@ -6813,7 +6859,7 @@ fn test_update_fulfill_htlc_bolt2_update_fail_htlc_before_commitment() {
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-	nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+	nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 	check_added_monitors!(nodes[0], 1);
 	let updates = get_htlc_update_msgs!(nodes[0], nodes[1].node.get_our_node_id());
 	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &updates.update_add_htlcs[0]);
@@ -6846,7 +6892,7 @@ fn test_update_fulfill_htlc_bolt2_update_fail_malformed_htlc_before_commitment()
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-	nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+	nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 	check_added_monitors!(nodes[0], 1);
 	let updates = get_htlc_update_msgs!(nodes[0], nodes[1].node.get_our_node_id());
 	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &updates.update_add_htlcs[0]);
@@ -6962,7 +7008,7 @@ fn test_update_fulfill_htlc_bolt2_missing_badonion_bit_for_malformed_htlc_messag
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 1000000, TEST_FINAL_CLTV, &logger).unwrap();
-	nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+	nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 	check_added_monitors!(nodes[0], 1);
 
 	let mut updates = get_htlc_update_msgs!(nodes[0], nodes[1].node.get_our_node_id());
@@ -7015,7 +7061,7 @@ fn test_update_fulfill_htlc_bolt2_after_malformed_htlc_message_must_forward_upda
 	let mut payment_event = {
 		let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 		let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &Vec::new(), 100000, TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[0].node.send_payment(&route, our_payment_hash, &None).unwrap();
+		nodes[0].node.send_payment(&route, our_payment_hash, &None, PaymentId(our_payment_hash.0)).unwrap();
 		check_added_monitors!(nodes[0], 1);
 		let mut events = nodes[0].node.get_and_clear_pending_msg_events();
 		assert_eq!(events.len(), 1);
@@ -8018,8 +8064,8 @@ fn test_set_outpoints_partial_claiming() {
 	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
 
 	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 1000000, 59000000, InitFeatures::known(), InitFeatures::known());
-	let payment_preimage_1 = route_payment(&nodes[1], &vec!(&nodes[0])[..], 3_000_000).0;
-	let payment_preimage_2 = route_payment(&nodes[1], &vec!(&nodes[0])[..], 3_000_000).0;
+	let payment_preimage_1 = route_payment(&nodes[1], &vec!(&nodes[0])[..], 10_000_000).0;
+	let payment_preimage_2 = route_payment(&nodes[1], &vec!(&nodes[0])[..], 10_000_000).0;
 
 	// Remote commitment txn with 4 outputs: to_local, to_remote, 2 outgoing HTLC
 	let remote_txn = get_local_commitment_txn!(nodes[1], chan.2);
@@ -8033,8 +8079,8 @@ fn test_set_outpoints_partial_claiming() {
 	// Connect blocks on node A to advance height towards TEST_FINAL_CLTV
 	let prev_header_100 = connect_blocks(&nodes[1], 100, 0, false, Default::default());
 	// Provide node A with both preimage
-	nodes[0].node.claim_funds(payment_preimage_1, &None, 3_000_000);
-	nodes[0].node.claim_funds(payment_preimage_2, &None, 3_000_000);
+	nodes[0].node.claim_funds(payment_preimage_1, &None, 10_000_000);
+	nodes[0].node.claim_funds(payment_preimage_2, &None, 10_000_000);
 	check_added_monitors!(nodes[0], 2);
 	nodes[0].node.get_and_clear_pending_events();
 	nodes[0].node.get_and_clear_pending_msg_events();
@@ -8247,6 +8293,466 @@ fn test_simple_payment_secret() {
 	claim_payment_along_route_with_secret(&nodes[0], &[&[&nodes[1], &nodes[2]]], false, payment_preimage, Some(payment_secret.clone()), 100_000);
 }
 
+#[test]
+fn test_outbound_payment_status() {
+	// Test that we track outbound payments via the PaymentId passed to send_payment, updating their
+	// status as their HTLCs are claimed or failed.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+	let logger = test_utils::TestLogger::new();
+
+	let (payment_preimage_1, payment_hash_1) = get_payment_preimage_hash!(&nodes[0]);
+	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
+	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &[], 100000, TEST_FINAL_CLTV, &logger).unwrap();
+	let route_fee_msat = route.paths[0][0].fee_msat;
+	assert!(route_fee_msat > 0);
+	let payment_id_1 = PaymentId([1; 32]);
+	nodes[0].node.send_payment(&route, payment_hash_1, &None, payment_id_1).unwrap();
+	check_added_monitors!(nodes[0], 1);
+
+	let details = nodes[0].node.payment_status(&payment_id_1).unwrap();
+	assert_eq!(details.payment_id, payment_id_1);
+	assert_eq!(details.payment_hash, payment_hash_1);
+	assert_eq!(details.status, PaymentStatus::Pending);
+	assert_eq!(details.amount_msat, 100000);
+	assert_eq!(details.paths.len(), 1);
+	assert!(details.paths[0].path == route.paths[0]);
+	assert_eq!(details.paths[0].status, PaymentStatus::Pending);
+	assert_eq!(details.fee_paid_msat, None);
+	assert_eq!(details.payment_preimage, None);
+	// Pending payments can't be removed
+	assert!(!nodes[0].node.remove_payment(&payment_id_1));
+
+	pass_along_route(&nodes[0], &[&[&nodes[1], &nodes[2]]], 100000, payment_hash_1, None);
+	claim_payment(&nodes[0], &[&nodes[1], &nodes[2]], payment_preimage_1, 100_000);
+
+	let details = nodes[0].node.payment_status(&payment_id_1).unwrap();
+	assert_eq!(details.status, PaymentStatus::Succeeded);
+	assert_eq!(details.paths[0].status, PaymentStatus::Succeeded);
+	assert_eq!(details.fee_paid_msat, Some(route_fee_msat));
+	assert_eq!(details.payment_preimage, Some(payment_preimage_1));
+
+	let (_, payment_hash_2) = get_payment_preimage_hash!(&nodes[0]);
+	let payment_id_2 = PaymentId([2; 32]);
+	nodes[0].node.send_payment(&route, payment_hash_2, &None, payment_id_2).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	pass_along_route(&nodes[0], &[&[&nodes[1], &nodes[2]]], 100000, payment_hash_2, None);
+	fail_payment(&nodes[0], &[&nodes[1], &nodes[2]], payment_hash_2);
+
+	let details = nodes[0].node.payment_status(&payment_id_2).unwrap();
+	assert_eq!(details.status, PaymentStatus::Failed);
+	assert_eq!(details.paths[0].status, PaymentStatus::Failed);
+	assert_eq!(details.fee_paid_msat, None);
+	assert_eq!(details.payment_preimage, None);
+	assert_eq!(nodes[0].node.list_payments().len(), 2);
+
+	assert!(nodes[0].node.remove_payment(&payment_id_2));
+	assert!(nodes[0].node.payment_status(&payment_id_2).is_none());
+	assert_eq!(nodes[0].node.list_payments().len(), 1);
+	assert_eq!(nodes[0].node.list_payments()[0].payment_id, payment_id_1);
+}
+
+#[test]
+fn test_read_pre_payment_tracking_serialization() {
+	// Test that ChannelManagers and HTLCSources written before we tracked outbound payments (ie
+	// without the trailing ChannelManager fields and the OutboundRoute payment_id) can still be read.
+	use ln::channelmanager::HTLCSource;
+
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let logger: test_utils::TestLogger;
+	let fee_estimator: test_utils::TestFeeEstimator;
+	let persister: test_utils::TestPersister;
+	let new_chain_monitor: test_utils::TestChainMonitor;
+	let keys_manager: test_utils::TestKeysInterface;
+	let nodes_0_deserialized: ChannelManager<EnforcingChannelKeys, &test_utils::TestChainMonitor, &test_utils::TestBroadcaster, &test_utils::TestKeysInterface, &test_utils::TestFeeEstimator, &test_utils::TestLogger>;
+	let mut nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+	// A version 1 ChannelManager is a version 2 one without the (here empty) outbound payments,
+	// pending trampoline forwards and recovered channels.
+	let mut nodes_0_serialized = nodes[0].node.encode();
+	assert_eq!(&nodes_0_serialized[..2], &[2, 2]);
+	nodes_0_serialized[0] = 1;
+	nodes_0_serialized[1] = 1;
+	let v2_fields_start = nodes_0_serialized.len() - 3 * 8;
+	assert!(nodes_0_serialized[v2_fields_start..].iter().all(|b| *b == 0));
+	nodes_0_serialized.truncate(v2_fields_start);
+	let mut chan_0_monitor_serialized = test_utils::TestVecWriter(Vec::new());
+	nodes[0].chain_monitor.chain_monitor.monitors.lock().unwrap().iter().next().unwrap().1.serialize_for_disk(&mut chan_0_monitor_serialized).unwrap();
+
+	logger = test_utils::TestLogger::new();
+	fee_estimator = test_utils::TestFeeEstimator { sat_per_kw: 253 };
+	persister = test_utils::TestPersister::new();
+	new_chain_monitor = test_utils::TestChainMonitor::new(Some(nodes[0].chain_source), nodes[0].tx_broadcaster.clone(), &logger, &fee_estimator, &persister);
+	nodes[0].chain_monitor = &new_chain_monitor;
+	let mut chan_0_monitor_read = &chan_0_monitor_serialized.0[..];
+	let (_, mut chan_0_monitor) = <(BlockHash, ChannelMonitor<EnforcingChannelKeys>)>::read(&mut chan_0_monitor_read).unwrap();
+
+	let mut nodes_0_read = &nodes_0_serialized[..];
+	keys_manager = test_utils::TestKeysInterface::new(&nodes[0].node_seed, Network::Testnet);
+	let (_, nodes_0_deserialized_tmp) = {
+		let mut channel_monitors = HashMap::new();
+		channel_monitors.insert(chan_0_monitor.get_funding_txo().0, &mut chan_0_monitor);
+		<(BlockHash, ChannelManager<EnforcingChannelKeys, &test_utils::TestChainMonitor, &test_utils::TestBroadcaster, &test_utils::TestKeysInterface, &test_utils::TestFeeEstimator, &test_utils::TestLogger>)>::read(&mut nodes_0_read, ChannelManagerReadArgs {
+			default_config: UserConfig::default(),
+			keys_manager: &keys_manager,
+			fee_estimator: &fee_estimator,
+			chain_monitor: nodes[0].chain_monitor,
+			tx_broadcaster: nodes[0].tx_broadcaster.clone(),
+			logger: &logger,
+			channel_monitors,
+		}).unwrap()
+	};
+	nodes_0_deserialized = nodes_0_deserialized_tmp;
+	assert!(nodes_0_read.is_empty());
+	assert!(nodes[0].chain_monitor.watch_channel(chan_0_monitor.get_funding_txo().0, chan_0_monitor).is_ok());
+	check_added_monitors!(nodes[0], 1);
+	nodes[0].node = &nodes_0_deserialized;
+	assert_eq!(nodes[0].node.list_channels().len(), 1);
+	assert!(nodes[0].node.list_payments().is_empty());
+
+	// An OutboundRoute HTLCSource written without a payment_id gets one derived from its
+	// session_priv, which doesn't match any tracked payment.
+	let session_priv = SecretKey::from_slice(&[42; 32]).unwrap();
+	let mut v1_source = Vec::new();
+	v1_source.push(1u8);
+	v1_source.extend_from_slice(&Vec::<RouteHop>::new().encode());
+	v1_source.extend_from_slice(&session_priv.encode());
+	v1_source.extend_from_slice(&1000u64.encode());
+	match <HTLCSource as Readable>::read(&mut &v1_source[..]).unwrap() {
+		HTLCSource::OutboundRoute { session_priv: read_session_priv, first_hop_htlc_msat, payment_id, .. } => {
+			assert!(read_session_priv == session_priv);
+			assert_eq!(first_hop_htlc_msat, 1000);
+			assert_eq!(payment_id, PaymentId(Sha256::hash(&session_priv[..]).into_inner()));
+		},
+		_ => panic!("Unexpected HTLCSource"),
+	}
+}
+
+#[test]
+fn test_idempotent_send_and_abandon_payment() {
+	// Test that send_payment refuses to send twice for the same PaymentId, that retry_payment won't
+	// overpay and that abandon_payment generates a single PaymentAbandoned once all HTLCs fail.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let logger = test_utils::TestLogger::new();
+
+	let (_, payment_hash) = get_payment_preimage_hash!(&nodes[0]);
+	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
+	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 100000, TEST_FINAL_CLTV, &logger).unwrap();
+	let payment_id = PaymentId([1; 32]);
+	nodes[0].node.send_payment(&route, payment_hash, &None, payment_id).unwrap();
+	check_added_monitors!(nodes[0], 1);
+
+	// A second send for the same PaymentId is refused, as is a retry which would overpay.
+	match nodes[0].node.send_payment(&route, payment_hash, &None, payment_id) {
+		Err(PaymentSendFailure::DuplicatePayment) => {},
+		_ => panic!("Unexpected result"),
+	}
+	match nodes[0].node.retry_payment(&route, payment_id) {
+		Err(PaymentSendFailure::ParameterError(APIError::RouteError { .. })) => {},
+		_ => panic!("Unexpected result"),
+	}
+	match nodes[0].node.retry_payment(&route, PaymentId([2; 32])) {
+		Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError { .. })) => {},
+		_ => panic!("Unexpected result"),
+	}
+	check_added_monitors!(nodes[0], 0);
+
+	pass_along_route(&nodes[0], &[&[&nodes[1]]], 100000, payment_hash, None);
+
+	// Abandoning a pending payment doesn't generate an event until its HTLCs are resolved.
+	assert!(nodes[0].node.abandon_payment(&payment_id));
+	assert!(!nodes[0].node.abandon_payment(&payment_id));
+	assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+	assert!(nodes[0].node.payment_status(&payment_id).unwrap().abandoned);
+
+	assert!(nodes[1].node.fail_htlc_backwards(&payment_hash, &None));
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	check_added_monitors!(nodes[1], 1);
+	let updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert_eq!(updates.update_fail_htlcs.len(), 1);
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], updates.commitment_signed, false);
+
+	let events = nodes[0].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 2);
+	match events[0] {
+		Event::PaymentFailed { payment_hash: ref hash, .. } => assert_eq!(*hash, payment_hash),
+		_ => panic!("Unexpected event"),
+	}
+	match events[1] {
+		Event::PaymentAbandoned { payment_id: ref id, payment_hash: ref hash } => {
+			assert_eq!(*id, payment_id);
+			assert_eq!(*hash, payment_hash);
+		},
+		_ => panic!("Unexpected event"),
+	}
+	assert_eq!(nodes[0].node.payment_status(&payment_id).unwrap().status, PaymentStatus::Failed);
+
+	// An abandoned payment can no longer be retried.
+	match nodes[0].node.retry_payment(&route, payment_id) {
+		Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError { .. })) => {},
+		_ => panic!("Unexpected result"),
+	}
+	check_added_monitors!(nodes[0], 0);
+	assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+}
+
+fn do_test_trampoline_payment(recipient_supports_trampoline: bool, claim: bool) {
+	// Test sending a payment from nodes[0] to nodes[2] using nodes[1] as a trampoline node, which
+	// finds the route to nodes[2] itself, both claiming and failing it.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let mut trampoline_config = UserConfig::default();
+	trampoline_config.channel_options.announced_channel = true;
+	trampoline_config.peer_channel_config_limits.force_announced_channel_preference = false;
+	trampoline_config.own_channel_config.our_htlc_minimum_msat = 1000;
+	trampoline_config.accept_trampoline_forwards = true;
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, Some(trampoline_config), None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+	let logger = test_utils::TestLogger::new();
+
+	let (payment_preimage, payment_hash) = get_payment_preimage_hash!(&nodes[0]);
+	let payment_secret = PaymentSecret([0xdb; 32]);
+	let recipient_features = if recipient_supports_trampoline { NodeFeatures::known() } else { NodeFeatures::known().clear_trampoline_routing() };
+	let trampoline_hops = vec![TrampolineHop {
+		pubkey: nodes[1].node.get_our_node_id(),
+		node_features: NodeFeatures::known(),
+		fee_msat: 1000,
+		cltv_expiry_delta: 100,
+	}, TrampolineHop {
+		pubkey: nodes[2].node.get_our_node_id(),
+		node_features: recipient_features,
+		fee_msat: 100000,
+		cltv_expiry_delta: TEST_FINAL_CLTV,
+	}];
+
+	// We only need a route to the trampoline node, which carries the full trampoline fee and CLTV
+	// budget.
+	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
+	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 101000, 100 + TEST_FINAL_CLTV, &logger).unwrap();
+	let payment_id = PaymentId(payment_hash.0);
+
+	// The route must end at the first trampoline node and carry the trampoline budget.
+	let mut bogus_hops = trampoline_hops.clone();
+	bogus_hops[0].fee_msat = 2000;
+	match nodes[0].node.send_trampoline_payment(&route, &bogus_hops, payment_hash, &Some(payment_secret), payment_id) {
+		Err(PaymentSendFailure::PathParameterError(_)) => {},
+		_ => panic!("Unexpected result"),
+	}
+
+	nodes[0].node.send_trampoline_payment(&route, &trampoline_hops, payment_hash, &Some(payment_secret), payment_id).unwrap();
+	check_added_monitors!(nodes[0], 1);
+
+	let payment_event = SendEvent::from_node(&nodes[0]);
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[1]);
+
+	let events = nodes[1].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PendingTrampolineForwards {} => {},
+		_ => panic!("Unexpected event"),
+	}
+	nodes[1].node.process_pending_trampoline_forwards(&nodes[1].net_graph_msg_handler.network_graph.read().unwrap());
+	check_added_monitors!(nodes[1], 1);
+	let mut events = nodes[1].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	pass_along_path(&nodes[1], &[&nodes[2]], 100000, payment_hash, Some(payment_secret), events.remove(0), true);
+	// The trampoline node's own payment isn't visible to its user.
+	assert!(nodes[1].node.list_payments().is_empty());
+
+	if claim {
+		claim_payment_along_route_with_secret(&nodes[0], &[&[&nodes[1], &nodes[2]]], false, payment_preimage, Some(payment_secret), 100000);
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+		let details = nodes[0].node.payment_status(&payment_id).unwrap();
+		assert_eq!(details.status, PaymentStatus::Succeeded);
+		assert_eq!(details.fee_paid_msat, Some(1000));
+	} else {
+		assert!(nodes[2].node.fail_htlc_backwards(&payment_hash, &Some(payment_secret)));
+		expect_pending_htlcs_forwardable!(nodes[2]);
+		check_added_monitors!(nodes[2], 1);
+		let updates = get_htlc_update_msgs!(nodes[2], nodes[1].node.get_our_node_id());
+		assert_eq!(updates.update_fail_htlcs.len(), 1);
+		nodes[1].node.handle_update_fail_htlc(&nodes[2].node.get_our_node_id(), &updates.update_fail_htlcs[0]);
+		commitment_signed_dance!(nodes[1], nodes[2], updates.commitment_signed, true);
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+
+		// The recipient's rejection is relayed back to us by the trampoline node.
+		let updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+		assert_eq!(updates.update_fail_htlcs.len(), 1);
+		nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &updates.update_fail_htlcs[0]);
+		commitment_signed_dance!(nodes[0], nodes[1], updates.commitment_signed, false);
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentFailed { payment_hash: ref hash, rejected_by_dest, ref error_code, .. } => {
+				assert_eq!(*hash, payment_hash);
+				assert!(rejected_by_dest);
+				assert_eq!(*error_code, Some(0x4000|15));
+			},
+			_ => panic!("Unexpected event"),
+		}
+		assert_eq!(nodes[0].node.payment_status(&payment_id).unwrap().status, PaymentStatus::Failed);
+	}
+}
+
+#[test]
+fn test_trampoline_payment() {
+	do_test_trampoline_payment(true, true);
+	do_test_trampoline_payment(true, false);
+	do_test_trampoline_payment(false, true);
+	do_test_trampoline_payment(false, false);
+}
+
+#[test]
+fn test_trampoline_forwards_disabled_by_default() {
+	// Test that nodes which aren't configured to forward trampoline payments don't advertise
+	// trampoline routing and fail trampoline forwards with required_node_feature_missing.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+	let logger = test_utils::TestLogger::new();
+	assert!(!nodes[1].node.provided_init_features().supports_trampoline_routing());
+
+	let (_, payment_hash) = get_payment_preimage_hash!(&nodes[0]);
+	let payment_secret = PaymentSecret([0xdb; 32]);
+	let trampoline_hops = vec![TrampolineHop {
+		pubkey: nodes[1].node.get_our_node_id(),
+		node_features: NodeFeatures::known(),
+		fee_msat: 1000,
+		cltv_expiry_delta: 100,
+	}, TrampolineHop {
+		pubkey: nodes[2].node.get_our_node_id(),
+		node_features: NodeFeatures::known(),
+		fee_msat: 100000,
+		cltv_expiry_delta: TEST_FINAL_CLTV,
+	}];
+	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
+	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 101000, 100 + TEST_FINAL_CLTV, &logger).unwrap();
+	nodes[0].node.send_trampoline_payment(&route, &trampoline_hops, payment_hash, &Some(payment_secret), PaymentId(payment_hash.0)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+
+	let payment_event = SendEvent::from_node(&nodes[0]);
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false, true);
+	assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+
+	let updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert_eq!(updates.update_fail_htlcs.len(), 1);
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], updates.commitment_signed, false, true);
+	let msg_events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(msg_events.len(), 1);
+	match msg_events[0] {
+		MessageSendEvent::PaymentFailureNetworkUpdate { update: msgs::HTLCFailChannelUpdate::NodeFailure { ref node_id, is_permanent: true } } => {
+			assert_eq!(*node_id, nodes[1].node.get_our_node_id());
+		},
+		_ => panic!("Unexpected event"),
+	}
+	let events = nodes[0].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PaymentFailed { payment_hash: ref hash, ref error_code, .. } => {
+			assert_eq!(*hash, payment_hash);
+			assert_eq!(*error_code, Some(0x4000|0x2000|3));
+		},
+		_ => panic!("Unexpected event"),
+	}
+}
+
+#[test]
+fn test_trampoline_fee_insufficient() {
+	// Test that a trampoline node which can't find a route to the next node within the fee budget
+	// the sender gave it (including its own fee) fails the HTLC with trampoline_fee_insufficient.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let mut trampoline_config = UserConfig::default();
+	trampoline_config.channel_options.announced_channel = true;
+	trampoline_config.peer_channel_config_limits.force_announced_channel_preference = false;
+	trampoline_config.channel_options.fee_proportional_millionths = 10_000;
+	trampoline_config.accept_trampoline_forwards = true;
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, Some(trampoline_config), None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+	let logger = test_utils::TestLogger::new();
+
+	// nodes[1] wants 1% of the 100_000 msat it forwards, but we only offer it 500 msat.
+	let (_, payment_hash) = get_payment_preimage_hash!(&nodes[0]);
+	let trampoline_hops = vec![TrampolineHop {
+		pubkey: nodes[1].node.get_our_node_id(),
+		node_features: NodeFeatures::known(),
+		fee_msat: 500,
+		cltv_expiry_delta: 100,
+	}, TrampolineHop {
+		pubkey: nodes[2].node.get_our_node_id(),
+		node_features: NodeFeatures::known(),
+		fee_msat: 100000,
+		cltv_expiry_delta: TEST_FINAL_CLTV,
+	}];
+	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
+	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 100500, 100 + TEST_FINAL_CLTV, &logger).unwrap();
+	nodes[0].node.send_trampoline_payment(&route, &trampoline_hops, payment_hash, &None, PaymentId(payment_hash.0)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+
+	let payment_event = SendEvent::from_node(&nodes[0]);
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	let events = nodes[1].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PendingTrampolineForwards {} => {},
+		_ => panic!("Unexpected event"),
+	}
+	nodes[1].node.process_pending_trampoline_forwards(&nodes[1].net_graph_msg_handler.network_graph.read().unwrap());
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	check_added_monitors!(nodes[1], 1);
+
+	let updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert_eq!(updates.update_fail_htlcs.len(), 1);
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], updates.commitment_signed, false, true);
+	let msg_events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(msg_events.len(), 1);
+	match msg_events[0] {
+		MessageSendEvent::PaymentFailureNetworkUpdate { .. } => {},
+		_ => panic!("Unexpected event"),
+	}
+	let events = nodes[0].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PaymentFailed { payment_hash: ref hash, rejected_by_dest, ref error_code, .. } => {
+			assert_eq!(*hash, payment_hash);
+			assert!(!rejected_by_dest);
+			assert_eq!(*error_code, Some(0x2000|26));
+		},
+		_ => panic!("Unexpected event"),
+	}
+}
+
 #[test]
 fn test_simple_mpp() {
 	// Simple test of sending a multi-path payment.
@@ -8415,7 +8921,7 @@ fn test_concurrent_monitor_claim() {
 	{
 		let net_graph_msg_handler = &nodes[1].net_graph_msg_handler;
 		let route = get_route(&nodes[1].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[0].node.get_our_node_id(), None, &Vec::new(), 3000000 , TEST_FINAL_CLTV, &logger).unwrap();
-		nodes[1].node.send_payment(&route, payment_hash, &None).unwrap();
+		nodes[1].node.send_payment(&route, payment_hash, &None, PaymentId(payment_hash.0)).unwrap();
 	}
 	check_added_monitors!(nodes[1], 1);
 
@@ -8864,3 +9370,1104 @@ fn test_duplicate_chan_id() {
 	update_nodes_with_chan_announce(&nodes, 0, 1, &announcement, &as_update, &bs_update);
 	send_payment(&nodes[0], &[&nodes[1]], 8000000, 8_000_000);
 }
+
+// Builds the RouteHint a recipient would use to create a blinded path over the given channel,
+// using the forwarding parameters it has learned from the channel's src_node_id.
+fn blinded_path_hint(network_graph: &NetworkGraph, src_node_id: &PublicKey, short_channel_id: u64) -> RouteHint {
+	let chan = network_graph.get_channels().get(&short_channel_id).unwrap();
+	let info = if chan.node_one == *src_node_id { chan.one_to_two.as_ref() } else { chan.two_to_one.as_ref() }.unwrap();
+	RouteHint {
+		src_node_id: *src_node_id,
+		short_channel_id,
+		fees: info.fees,
+		cltv_expiry_delta: info.cltv_expiry_delta,
+		htlc_minimum_msat: info.htlc_minimum_msat,
+	}
+}
+
+#[test]
+fn test_blinded_path_payment() {
+	// Test nodes[0] paying nodes[3] via a blinded path through nodes[1] (the introduction node)
+	// and nodes[2], learning neither nodes[2]'s nor nodes[3]'s node_id.
+	let chanmon_cfgs = create_chanmon_cfgs(4);
+	let node_cfgs = create_node_cfgs(4, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(4, &node_cfgs, &[None, None, None, None]);
+	let nodes = create_network(4, &node_cfgs, &node_chanmgrs);
+
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_1_2 = create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+	let chan_2_3 = create_announced_chan_between_nodes(&nodes, 2, 3, InitFeatures::known(), InitFeatures::known());
+	let logger = test_utils::TestLogger::new();
+
+	let payment_secret = PaymentSecret([0xbb; 32]);
+	let blinded_path = {
+		let network_graph = nodes[3].net_graph_msg_handler.network_graph.read().unwrap();
+		let hops = vec![blinded_path_hint(&network_graph, &nodes[1].node.get_our_node_id(), chan_1_2.0.contents.short_channel_id),
+			blinded_path_hint(&network_graph, &nodes[2].node.get_our_node_id(), chan_2_3.0.contents.short_channel_id)];
+		nodes[3].node.create_blinded_path(&hops, payment_secret).unwrap()
+	};
+	assert_eq!(blinded_path.introduction_node_id, nodes[1].node.get_our_node_id());
+	assert_eq!(blinded_path.blinded_hops.len(), 3);
+	for (hop, node) in blinded_path.blinded_hops.iter().zip(nodes[1..].iter()) {
+		assert_ne!(hop.blinded_node_id, node.node.get_our_node_id());
+	}
+	assert!(blinded_path.cltv_expiry_delta > TEST_FINAL_CLTV as u16);
+
+	// The blinded path survives serialization, eg when included in an invoice.
+	let blinded_path = BlindedPath::read(&mut ::std::io::Cursor::new(blinded_path.encode())).unwrap();
+
+	let (payment_preimage, payment_hash) = get_payment_preimage_hash!(&nodes[0]);
+	let payment_id = PaymentId(payment_hash.0);
+	let amt_msat = 100000;
+	let fee_msat = blinded_path.fee_msat(amt_msat).unwrap();
+	let route = get_route(&nodes[0].node.get_our_node_id(), &nodes[0].net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], amt_msat + fee_msat, blinded_path.cltv_expiry_delta as u32 + TEST_FINAL_CLTV, &logger).unwrap();
+
+	// The route must pay the blinded path's fees to the introduction node.
+	let mut bogus_route = route.clone();
+	bogus_route.paths[0].last_mut().unwrap().fee_msat -= 1;
+	match nodes[0].node.send_payment_to_blinded_path(&bogus_route, &blinded_path, amt_msat, TEST_FINAL_CLTV, payment_hash, payment_id) {
+		Err(PaymentSendFailure::AllFailedRetrySafe(_)) => {},
+		_ => panic!("Unexpected result"),
+	}
+
+	nodes[0].node.send_payment_to_blinded_path(&route, &blinded_path, amt_msat, TEST_FINAL_CLTV, payment_hash, payment_id).unwrap();
+	check_added_monitors!(nodes[0], 1);
+
+	// Each hop after the introduction node is handed the blinding point in the update_add_htlc.
+	let mut payment_event = SendEvent::from_node(&nodes[0]);
+	assert!(payment_event.msgs[0].blinding_point.is_none());
+	for (prev_node, node) in nodes[0..3].iter().zip(nodes[1..].iter()) {
+		assert_eq!(payment_event.node_id, node.node.get_our_node_id());
+		node.node.handle_update_add_htlc(&prev_node.node.get_our_node_id(), &payment_event.msgs[0]);
+		commitment_signed_dance!(node, prev_node, payment_event.commitment_msg, false);
+		expect_pending_htlcs_forwardable!(node);
+		if node.node.get_our_node_id() == nodes[3].node.get_our_node_id() { break; }
+		check_added_monitors!(node, 1);
+		payment_event = SendEvent::from_node(node);
+		assert!(payment_event.msgs[0].blinding_point.is_some());
+	}
+
+	// We receive the payment with the payment_secret we created the blinded path with.
+	let events = nodes[3].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PaymentReceived { payment_hash: ref hash, payment_secret: ref secret, amt, .. } => {
+			assert_eq!(*hash, payment_hash);
+			assert_eq!(*secret, Some(payment_secret));
+			assert!(amt >= amt_msat);
+		},
+		_ => panic!("Unexpected event"),
+	}
+
+	claim_payment_along_route_with_secret(&nodes[0], &[&[&nodes[1], &nodes[2], &nodes[3]]], false, payment_preimage, Some(payment_secret), amt_msat);
+	let details = nodes[0].node.payment_status(&payment_id).unwrap();
+	assert_eq!(details.status, PaymentStatus::Succeeded);
+	assert_eq!(details.fee_paid_msat, Some(fee_msat));
+}
+
+fn do_test_blinded_path_failure(fail_at_introduction_node: bool) {
+	// Test that a node within a blinded path which can't forward our payment doesn't tell us why,
+	// failing instead with invalid_onion_blinding. Only the introduction node, which we know,
+	// fails back with an onion error, others fail it back to the previous hop as malformed.
+	let chanmon_cfgs = create_chanmon_cfgs(4);
+	let node_cfgs = create_node_cfgs(4, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(4, &node_cfgs, &[None, None, None, None]);
+	let nodes = create_network(4, &node_cfgs, &node_chanmgrs);
+
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_1_2 = create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+	let chan_2_3 = create_announced_chan_between_nodes(&nodes, 2, 3, InitFeatures::known(), InitFeatures::known());
+	let logger = test_utils::TestLogger::new();
+
+	// Ask one of the hops to forward with less CLTV delta than it requires.
+	let blinded_path = {
+		let network_graph = nodes[3].net_graph_msg_handler.network_graph.read().unwrap();
+		let mut hops = vec![blinded_path_hint(&network_graph, &nodes[1].node.get_our_node_id(), chan_1_2.0.contents.short_channel_id),
+			blinded_path_hint(&network_graph, &nodes[2].node.get_our_node_id(), chan_2_3.0.contents.short_channel_id)];
+		hops[if fail_at_introduction_node { 0 } else { 1 }].cltv_expiry_delta -= 1;
+		nodes[3].node.create_blinded_path(&hops, PaymentSecret([0xbb; 32])).unwrap()
+	};
+
+	let (_, payment_hash) = get_payment_preimage_hash!(&nodes[0]);
+	let amt_msat = 100000;
+	let route = get_route(&nodes[0].node.get_our_node_id(), &nodes[0].net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], amt_msat + blinded_path.fee_msat(amt_msat).unwrap(), blinded_path.cltv_expiry_delta as u32 + TEST_FINAL_CLTV, &logger).unwrap();
+	nodes[0].node.send_payment_to_blinded_path(&route, &blinded_path, amt_msat, TEST_FINAL_CLTV, payment_hash, PaymentId(payment_hash.0)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+
+	let payment_event = SendEvent::from_node(&nodes[0]);
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	if fail_at_introduction_node {
+		commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, true, true);
+	} else {
+		commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+		expect_pending_htlcs_forwardable!(nodes[1]);
+		check_added_monitors!(nodes[1], 1);
+		let payment_event = SendEvent::from_node(&nodes[1]);
+		nodes[2].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &payment_event.msgs[0]);
+		commitment_signed_dance!(nodes[2], nodes[1], payment_event.commitment_msg, true, true);
+
+		let updates = get_htlc_update_msgs!(nodes[2], nodes[1].node.get_our_node_id());
+		assert!(updates.update_fail_htlcs.is_empty());
+		assert_eq!(updates.update_fail_malformed_htlcs.len(), 1);
+		assert_eq!(updates.update_fail_malformed_htlcs[0].failure_code, 0x8000 | 0x4000 | 24);
+		nodes[1].node.handle_update_fail_malformed_htlc(&nodes[2].node.get_our_node_id(), &updates.update_fail_malformed_htlcs[0]);
+		commitment_signed_dance!(nodes[1], nodes[2], updates.commitment_signed, true);
+	}
+
+	let updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert_eq!(updates.update_fail_htlcs.len(), 1);
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &updates.update_fail_htlcs[0]);
+	// The failure is attributed to the blinded path rather than to the channel into the
+	// introduction node, so we don't update our network graph.
+	commitment_signed_dance!(nodes[0], nodes[1], updates.commitment_signed, false);
+	let events = nodes[0].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PaymentFailed { payment_hash: ref hash, rejected_by_dest, ref error_code, .. } => {
+			assert_eq!(*hash, payment_hash);
+			assert!(rejected_by_dest);
+			assert_eq!(*error_code, Some(0x8000 | 0x4000 | 24));
+		},
+		_ => panic!("Unexpected event"),
+	}
+}
+
+#[test]
+fn test_blinded_path_failure() {
+	do_test_blinded_path_failure(true);
+	do_test_blinded_path_failure(false);
+}
+
+#[test]
+fn test_custom_tlvs() {
+	// Test that custom TLV records included by the sender in the final onion payload are surfaced
+	// to the recipient in Event::PaymentReceived.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+	let logger = test_utils::TestLogger::new();
+
+	let (payment_preimage, payment_hash) = get_payment_preimage_hash!(&nodes[0]);
+	let payment_secret = PaymentSecret([0xdb; 32]);
+	let route = get_route(&nodes[0].node.get_our_node_id(), &nodes[0].net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &[], 100000, TEST_FINAL_CLTV, &logger).unwrap();
+
+	// Types must be unique and out of the range reserved for the protocol.
+	match nodes[0].node.send_payment_with_custom_tlvs(&route, payment_hash, &Some(payment_secret), vec![(msgs::MIN_CUSTOM_TLV_TYPE - 1, Vec::new())], PaymentId(payment_hash.0)) {
+		Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError { ref err })) => assert!(err.contains("must be at least")),
+		_ => panic!("Unexpected result"),
+	}
+	match nodes[0].node.send_payment_with_custom_tlvs(&route, payment_hash, &Some(payment_secret), vec![(65537, vec![1]), (65537, vec![2])], PaymentId(payment_hash.0)) {
+		Err(PaymentSendFailure::ParameterError(APIError::APIMisuseError { ref err })) => assert_eq!(err, "Custom TLV types must be unique"),
+		_ => panic!("Unexpected result"),
+	}
+	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
+
+	// Records are sent in increasing type order, whatever order they are given in.
+	let custom_tlvs = vec![(5482373485, b"episode 42".to_vec()), (65537, vec![0x42; 100])];
+	nodes[0].node.send_payment_with_custom_tlvs(&route, payment_hash, &Some(payment_secret), custom_tlvs, PaymentId(payment_hash.0)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+
+	let payment_event = SendEvent::from_node(&nodes[0]);
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	check_added_monitors!(nodes[1], 1);
+	let payment_event = SendEvent::from_node(&nodes[1]);
+	nodes[2].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[2], nodes[1], payment_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[2]);
+
+	let events = nodes[2].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PaymentReceived { payment_hash: ref hash, ref custom_tlvs, amt, .. } => {
+			assert_eq!(*hash, payment_hash);
+			assert_eq!(amt, 100000);
+			assert_eq!(*custom_tlvs, vec![(65537, vec![0x42; 100]), (5482373485, b"episode 42".to_vec())]);
+		},
+		_ => panic!("Unexpected event"),
+	}
+	claim_payment_along_route_with_secret(&nodes[0], &[&[&nodes[1], &nodes[2]]], false, payment_preimage, Some(payment_secret), 100000);
+
+	// An even type the recipient doesn't understand causes it to reject the payment.
+	let (_, payment_hash) = get_payment_preimage_hash!(&nodes[0]);
+	nodes[0].node.send_payment_with_custom_tlvs(&route, payment_hash, &Some(payment_secret), vec![(65536, Vec::new())], PaymentId(payment_hash.0)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+
+	let payment_event = SendEvent::from_node(&nodes[0]);
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	check_added_monitors!(nodes[1], 1);
+	let payment_event = SendEvent::from_node(&nodes[1]);
+	nodes[2].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[2], nodes[1], payment_event.commitment_msg, true, true);
+
+	let updates = get_htlc_update_msgs!(nodes[2], nodes[1].node.get_our_node_id());
+	assert_eq!(updates.update_fail_htlcs.len(), 1);
+	nodes[1].node.handle_update_fail_htlc(&nodes[2].node.get_our_node_id(), &updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[1], nodes[2], updates.commitment_signed, true);
+	let updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert_eq!(updates.update_fail_htlcs.len(), 1);
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], updates.commitment_signed, false, true);
+	let msg_events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(msg_events.len(), 1);
+	match msg_events[0] {
+		MessageSendEvent::PaymentFailureNetworkUpdate { .. } => {},
+		_ => panic!("Unexpected event"),
+	}
+	let events = nodes[0].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PaymentFailed { payment_hash: ref hash, rejected_by_dest, ref error_code, .. } => {
+			assert_eq!(*hash, payment_hash);
+			assert!(rejected_by_dest);
+			assert_eq!(*error_code, Some(0x4000 | 22));
+		},
+		_ => panic!("Unexpected event"),
+	}
+}
+
+#[test]
+fn test_claimable_balances() {
+	// Tests that the balances reported by ChannelMonitor::get_claimable_balances (via the
+	// ChainMonitor aggregate) move from being claimable on close to awaiting confirmations (or
+	// contentious) once a commitment transaction confirms, and back again on reorg.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 20_000_000, InitFeatures::known(), InitFeatures::known());
+	let (payment_preimage, _) = route_payment(&nodes[0], &[&nodes[1]], 3_000_000);
+
+	// Before close, nodes[0] (the funder) can claim its balance less the commitment fee, and the
+	// HTLC once it times out.
+	let pre_close_balances = nodes[0].chain_monitor.chain_monitor.get_claimable_balances();
+	assert_eq!(pre_close_balances.len(), 2);
+	let htlc_cltv_expiry = match pre_close_balances[0] {
+		channelmonitor::Balance::MaybeClaimableHTLCAwaitingTimeout { claimable_amount_satoshis: 3_000, claimable_height } => claimable_height,
+		_ => panic!("Unexpected balance"),
+	};
+	let to_self_sat = match pre_close_balances[1] {
+		channelmonitor::Balance::ClaimableOnChannelClose { claimable_amount_satoshis } => claimable_amount_satoshis,
+		_ => panic!("Unexpected balance"),
+	};
+	assert!(to_self_sat < 100_000 - 20_000 - 3_000);
+
+	assert_eq!(nodes[1].chain_monitor.chain_monitor.get_claimable_balances(), vec![
+		channelmonitor::Balance::MaybeClaimableHTLCAwaitingPreimage { claimable_amount_satoshis: 3_000, expiry_height: htlc_cltv_expiry },
+		channelmonitor::Balance::ClaimableOnChannelClose { claimable_amount_satoshis: 20_000 },
+	]);
+
+	// Once nodes[1] learns the preimage, the HTLC counts towards its balance on close.
+	assert!(nodes[1].node.claim_funds(payment_preimage, &None, 3_000_000));
+	check_added_monitors!(nodes[1], 1);
+	get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert_eq!(nodes[1].chain_monitor.chain_monitor.get_claimable_balances(), vec![
+		channelmonitor::Balance::ClaimableOnChannelClose { claimable_amount_satoshis: 23_000 },
+	]);
+
+	// Broadcasting the commitment transaction doesn't change anything until it confirms.
+	nodes[0].node.force_close_channel(&chan.2);
+	check_closed_broadcast!(nodes[0], false);
+	check_added_monitors!(nodes[0], 1);
+	let commitment_tx = {
+		let mut node_txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(node_txn.len(), 2); // commitment tx and HTLC-Timeout tx
+		check_spends!(node_txn[0], chan.3);
+		check_spends!(node_txn[1], node_txn[0]);
+		let commitment_tx = node_txn[0].clone();
+		node_txn.clear();
+		commitment_tx
+	};
+	assert!(commitment_tx.output.iter().any(|outp| outp.value == to_self_sat));
+	assert_eq!(nodes[0].chain_monitor.chain_monitor.get_claimable_balances(), pre_close_balances);
+
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[0], &Block { header, txdata: vec![commitment_tx.clone()] }, 10);
+	let balances = nodes[0].chain_monitor.chain_monitor.get_claimable_balances();
+	assert_eq!(balances.len(), 2);
+	assert!(balances.contains(&channelmonitor::Balance::ClaimableAwaitingConfirmations {
+		claimable_amount_satoshis: to_self_sat,
+		confirmation_height: 10 + BREAKDOWN_TIMEOUT as u32,
+	}));
+	assert!(balances.contains(&channelmonitor::Balance::MaybeClaimableHTLCAwaitingTimeout {
+		claimable_amount_satoshis: 3_000,
+		claimable_height: htlc_cltv_expiry,
+	}));
+
+	// A reorg of the commitment transaction puts us back where we started.
+	disconnect_block(&nodes[0], &header, 10);
+	assert_eq!(nodes[0].chain_monitor.chain_monitor.get_claimable_balances(), pre_close_balances);
+
+	// nodes[1] sees its to_remote output maturing and races to claim the HTLC with the preimage.
+	connect_block(&nodes[1], &Block { header, txdata: vec![commitment_tx.clone()] }, 10);
+	check_closed_broadcast!(nodes[1], false);
+	check_added_monitors!(nodes[1], 1);
+	let balances = nodes[1].chain_monitor.chain_monitor.get_claimable_balances();
+	assert_eq!(balances.len(), 2);
+	assert!(balances.contains(&channelmonitor::Balance::ClaimableAwaitingConfirmations {
+		claimable_amount_satoshis: 20_000,
+		confirmation_height: 10 + ANTI_REORG_DELAY - 1,
+	}));
+	assert!(balances.contains(&channelmonitor::Balance::ContentiousClaimable {
+		claimable_amount_satoshis: 3_000,
+		timeout_height: htlc_cltv_expiry,
+	}));
+
+	let preimage_claim_tx = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap().iter()
+		.find(|tx| tx.input[0].previous_output.txid == commitment_tx.txid()).unwrap().clone();
+	check_spends!(preimage_claim_tx, commitment_tx);
+	let header_11 = BlockHeader { version: 0x20000000, prev_blockhash: header.block_hash(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[1], &Block { header: header_11, txdata: vec![preimage_claim_tx.clone()] }, 11);
+	let balances = nodes[1].chain_monitor.chain_monitor.get_claimable_balances();
+	assert_eq!(balances.len(), 2);
+	assert!(balances.contains(&channelmonitor::Balance::ClaimableAwaitingConfirmations {
+		claimable_amount_satoshis: 20_000,
+		confirmation_height: 10 + ANTI_REORG_DELAY - 1,
+	}));
+	assert!(balances.contains(&channelmonitor::Balance::ClaimableAwaitingConfirmations {
+		claimable_amount_satoshis: preimage_claim_tx.output[0].value,
+		confirmation_height: 11 + ANTI_REORG_DELAY - 1,
+	}));
+
+	// Once the outputs have been handed over as SpendableOutputs, nothing is left to claim.
+	connect_blocks(&nodes[1], ANTI_REORG_DELAY - 1, 11, true, header_11.block_hash());
+	assert!(nodes[1].chain_monitor.chain_monitor.get_claimable_balances().is_empty());
+	let events = nodes[1].chain_monitor.chain_monitor.get_and_clear_pending_events();
+	assert_eq!(events.len(), 2);
+	for event in events {
+		match event {
+			Event::SpendableOutputs { .. } => {},
+			_ => panic!("Unexpected event"),
+		}
+	}
+}
+
+#[test]
+fn test_watchtower_justice_tx() {
+	// Tests that a WatchtowerClient hands a justice transaction for each revoked counterparty
+	// commitment transaction to a Watchtower, which broadcasts it once the revoked state hits the
+	// chain.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let tower_broadcaster = test_utils::TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) };
+	let tower_logger = test_utils::TestLogger::with_id("tower".to_owned());
+	let tower = Watchtower::new(&tower_broadcaster, &tower_logger, 100);
+	let client_key = SecretKey::from_slice(&[42; 32]).unwrap();
+	let client = WatchtowerClient::new(&chanmon_cfgs[0].persister, vec![&tower], client_key, &chanmon_cfgs[0].logger);
+	let mut node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	node_cfgs[0].chain_monitor = test_utils::TestChainMonitor::new(Some(&chanmon_cfgs[0].chain_source), &chanmon_cfgs[0].tx_broadcaster, &chanmon_cfgs[0].logger, &chanmon_cfgs[0].fee_estimator, &client);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 50_000_000, InitFeatures::known(), InitFeatures::known());
+	let (payment_preimage, _) = route_payment(&nodes[0], &[&nodes[1]], 3_000_000);
+	let revoked_local_txn = get_local_commitment_txn!(nodes[1], chan.2);
+	assert_eq!(revoked_local_txn[0].output.len(), 3); // to_local, to_remote and the HTLC
+	// The initial commitment transaction was revoked, queueing one appointment per pre-signed
+	// feerate, which are only sent to the tower once we ask for it
+	assert_eq!(client.pending_appointment_count(), 3);
+	assert_eq!(tower.appointment_count(), 0);
+
+	// The tower rejects us until we're authorized, the appointments are kept around until then
+	client.deliver_pending_appointments();
+	assert_eq!(client.pending_appointment_count(), 3);
+	assert_eq!(tower.appointment_count(), 0);
+	tower.authorize_client(client.client_id());
+	client.deliver_pending_appointments();
+	assert_eq!(client.pending_appointment_count(), 0);
+	assert_eq!(tower.appointment_count(), 3);
+
+	// Claiming the payment revokes the commitment transaction with the HTLC
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage, 3_000_000);
+	assert_eq!(client.pending_appointment_count(), 3);
+	client.deliver_pending_appointments();
+	assert_eq!(client.pending_appointment_count(), 0);
+	assert_eq!(tower.appointment_count(), 6);
+
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	tower.block_connected(&header, &[(0, &chan.3)], 1);
+	assert!(tower_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+
+	tower.block_connected(&header, &[(0, &revoked_local_txn[0])], 2);
+	let justice_txn = tower_broadcaster.txn_broadcasted.lock().unwrap().clone();
+	assert_eq!(justice_txn.len(), 3); // One per pre-signed feerate
+	for tx in justice_txn.iter() {
+		assert_eq!(tx.input.len(), 2); // to_local and the HTLC
+		check_spends!(tx, revoked_local_txn[0]);
+		assert_eq!(tx.output[0].script_pubkey, nodes[0].keys_manager.get_destination_script());
+	}
+
+	// Our own ChannelMonitor would punish the same outputs
+	connect_block(&nodes[0], &Block { header, txdata: vec![revoked_local_txn[0].clone()] }, 2);
+	check_closed_broadcast!(nodes[0], false);
+	check_added_monitors!(nodes[0], 1);
+	let node_txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().clone();
+	let monitor_justice_tx = node_txn.iter().find(|tx| tx.input[0].previous_output.txid == revoked_local_txn[0].txid()).unwrap();
+	let mut tower_outpoints: Vec<_> = justice_txn[0].input.iter().map(|input| input.previous_output).collect();
+	let mut monitor_outpoints: Vec<_> = monitor_justice_tx.input.iter().map(|input| input.previous_output).collect();
+	tower_outpoints.sort();
+	monitor_outpoints.sort();
+	assert_eq!(tower_outpoints, monitor_outpoints);
+}
+
+#[test]
+fn test_presigned_justice_txn() {
+	// Tests that a ChannelMonitor pre-signs justice transactions at several feerates for each
+	// counterparty commitment transaction as soon as it is revoked, and that they spend every
+	// output of the revoked state we may claim.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 50_000_000, InitFeatures::known(), InitFeatures::known());
+	let initial_local_txn = get_local_commitment_txn!(nodes[1], chan.2);
+	let (payment_preimage, _) = route_payment(&nodes[0], &[&nodes[1]], 3_000_000);
+	let revoked_local_txn = get_local_commitment_txn!(nodes[1], chan.2);
+	assert_eq!(revoked_local_txn[0].output.len(), 3); // to_local, to_remote and the HTLC
+
+	{
+		let monitors = nodes[0].chain_monitor.chain_monitor.monitors.lock().unwrap();
+		let monitor = monitors.iter().next().unwrap().1;
+		// The initial commitment transaction was revoked by the HTLC addition
+		let justice_txn = monitor.get_justice_txs_for_revoked(&initial_local_txn[0].txid());
+		assert_eq!(justice_txn.len(), 3);
+		for tx in justice_txn.iter() {
+			assert_eq!(tx.input.len(), 1);
+			check_spends!(tx, initial_local_txn[0]);
+		}
+		// ...but not the one holding the HTLC, yet
+		assert!(monitor.get_justice_txs_for_revoked(&revoked_local_txn[0].txid()).is_empty());
+	}
+
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage, 3_000_000);
+	let current_local_txn = get_local_commitment_txn!(nodes[1], chan.2);
+
+	let monitors = nodes[0].chain_monitor.chain_monitor.monitors.lock().unwrap();
+	let monitor = monitors.iter().next().unwrap().1;
+	let justice_txn = monitor.get_justice_txs_for_revoked(&revoked_local_txn[0].txid());
+	assert_eq!(justice_txn.len(), 3);
+	for (i, tx) in justice_txn.iter().enumerate() {
+		assert_eq!(tx.input.len(), 2); // to_local and the HTLC
+		check_spends!(tx, revoked_local_txn[0]);
+		assert_eq!(tx.output.len(), 1);
+		assert_eq!(tx.output[0].script_pubkey, nodes[0].keys_manager.get_destination_script());
+		if i > 0 {
+			// Each transaction pays a higher feerate than the previous one
+			assert!(tx.output[0].value < justice_txn[i - 1].output[0].value);
+		}
+	}
+	// The first transaction pays our current feerate, the next ones twice and four times it
+	let claimed_value = revoked_local_txn[0].output.iter().map(|outp| outp.value).sum::<u64>() - revoked_local_txn[0].output.iter().find(|outp| outp.script_pubkey.is_v0_p2wpkh()).unwrap().value;
+	let fees: Vec<u64> = justice_txn.iter().map(|tx| claimed_value - tx.output[0].value).collect();
+	// (fees are computed on the predicted weight, which assumes maximum-length signatures)
+	assert!(fees[0] >= 253 * justice_txn[0].get_weight() as u64 / 1000 && fees[0] <= 253 * (justice_txn[0].get_weight() as u64 + 10) / 1000);
+	assert!(fees[1] >= fees[0] * 2 && fees[1] <= fees[0] * 2 + 1);
+	assert!(fees[2] >= fees[0] * 4 && fees[2] <= fees[0] * 4 + 3);
+	assert!(monitor.get_justice_txs_for_revoked(&current_local_txn[0].txid()).is_empty());
+	// Only the states revoked by the latest secret are kept around
+	assert!(monitor.get_justice_txs_for_revoked(&initial_local_txn[0].txid()).is_empty());
+	assert_eq!(monitor.get_latest_revoked_counterparty_commitment_txids(), vec![revoked_local_txn[0].txid()]);
+}
+
+#[test]
+fn test_presigned_justice_txn_dust() {
+	// Tests that no justice transaction is pre-signed at a feerate which would leave its output
+	// below the dust limit.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	// nodes[1] has no balance, so its commitment transactions only hold a small HTLC we may claim
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 0, InitFeatures::known(), InitFeatures::known());
+	let (payment_preimage, _) = route_payment(&nodes[0], &[&nodes[1]], 1_000_000);
+	let revoked_local_txn = get_local_commitment_txn!(nodes[1], chan.2);
+	assert_eq!(revoked_local_txn[0].output.len(), 2); // to_remote and the HTLC
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage, 1_000_000);
+
+	let monitors = nodes[0].chain_monitor.chain_monitor.monitors.lock().unwrap();
+	let monitor = monitors.iter().next().unwrap().1;
+	let justice_txn = monitor.get_justice_txs_for_revoked(&revoked_local_txn[0].txid());
+	// At four times our current feerate, less than 546 sat would be left of the HTLC
+	assert_eq!(justice_txn.len(), 2);
+	for tx in justice_txn.iter() {
+		check_spends!(tx, revoked_local_txn[0]);
+		assert!(tx.output[0].value >= 546);
+	}
+	// ...while doubling the fee of the second one would have
+	let fee = 1000 - justice_txn[1].output[0].value;
+	assert!(1000 < fee * 2 + 546);
+}
+
+fn get_spendable_outputs<'a, 'b, 'c>(node: &Node<'a, 'b, 'c>) -> Vec<SpendableOutputDescriptor> {
+	let mut descriptors = Vec::new();
+	for event in node.chain_monitor.chain_monitor.get_and_clear_pending_events() {
+		match event {
+			Event::SpendableOutputs { mut outputs } => descriptors.append(&mut outputs),
+			_ => panic!("Unexpected event"),
+		}
+	}
+	descriptors
+}
+
+#[test]
+fn test_spend_spendable_outputs() {
+	// Tests that KeysManager::spend_spendable_outputs builds valid, fully-signed transactions
+	// spending both our CSV-delayed to_local output and our to_remote output, respecting the
+	// to_self_delay of the former.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 50_000_000, InitFeatures::known(), InitFeatures::known());
+	nodes[0].node.force_close_channel(&chan.2);
+	check_closed_broadcast!(nodes[0], false);
+	check_added_monitors!(nodes[0], 1);
+	let commitment_tx = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap()[0].clone();
+	check_spends!(commitment_tx, chan.3);
+
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	for node in nodes.iter() {
+		connect_block(node, &Block { header, txdata: vec![commitment_tx.clone()] }, 1);
+		connect_blocks(node, ANTI_REORG_DELAY - 1, 1, true, header.block_hash());
+	}
+	check_closed_broadcast!(nodes[1], false);
+	check_added_monitors!(nodes[1], 1);
+
+	let secp_ctx = Secp256k1::new();
+	let change_script = Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script();
+	let to_remote_outputs = get_spendable_outputs(&nodes[1]);
+	assert_eq!(to_remote_outputs.len(), 1);
+	if let SpendableOutputDescriptor::StaticOutputCounterpartyPayment { .. } = to_remote_outputs[0] {} else { panic!(); }
+	let to_remote_spend = node_cfgs[1].keys_manager.backing.spend_spendable_outputs(&to_remote_outputs, Vec::new(), change_script.clone(), 253, &secp_ctx).unwrap();
+	assert_eq!(to_remote_spend.output.len(), 1);
+	check_spends!(to_remote_spend, commitment_tx);
+
+	let to_local_outputs = get_spendable_outputs(&nodes[0]);
+	assert_eq!(to_local_outputs.len(), 1);
+	let to_self_delay = if let SpendableOutputDescriptor::DynamicOutputP2WSH { to_self_delay, .. } = to_local_outputs[0] { to_self_delay } else { panic!(); };
+	// Paying to an explicit output leaves the rest as change
+	let outputs = vec![TxOut { script_pubkey: nodes[0].keys_manager.get_destination_script(), value: 10_000 }];
+	let to_local_spend = node_cfgs[0].keys_manager.backing.spend_spendable_outputs(&to_local_outputs, outputs.clone(), change_script.clone(), 253, &secp_ctx).unwrap();
+	assert_eq!(to_local_spend.input[0].sequence, to_self_delay as u32);
+	assert_eq!(to_local_spend.output.len(), 2);
+	assert_eq!(to_local_spend.output[1].script_pubkey, change_script);
+	check_spends!(to_local_spend, commitment_tx);
+
+	// We can't pay out more than the outputs are worth, nor sign for the other node's outputs
+	let outputs = vec![TxOut { script_pubkey: change_script.clone(), value: commitment_tx.output.iter().map(|o| o.value).sum() }];
+	assert!(node_cfgs[0].keys_manager.backing.spend_spendable_outputs(&to_local_outputs, outputs, change_script.clone(), 253, &secp_ctx).is_err());
+	let wrong_key_spend = node_cfgs[0].keys_manager.backing.spend_spendable_outputs(&to_remote_outputs, Vec::new(), change_script.clone(), 253, &secp_ctx).unwrap();
+	assert!(wrong_key_spend.verify(|outpoint| if outpoint.txid == commitment_tx.txid() { commitment_tx.output.get(outpoint.vout as usize).cloned() } else { None }).is_err());
+}
+
+#[test]
+fn test_spend_shutdown_script_output() {
+	// Tests that KeysManager::spend_spendable_outputs can sign for the StaticOutput paying to our
+	// shutdown script which a ChannelMonitor hands out after a cooperative close.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 50_000_000, InitFeatures::known(), InitFeatures::known());
+	let (_, _, closing_tx) = close_channel(&nodes[0], &nodes[1], &chan.2, chan.3, true);
+
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[0], &Block { header, txdata: vec![closing_tx.clone()] }, 1);
+	connect_blocks(&nodes[0], ANTI_REORG_DELAY - 1, 1, true, header.block_hash());
+	let outputs = get_spendable_outputs(&nodes[0]);
+	assert_eq!(outputs.len(), 1);
+	let shutdown_script = Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0)
+		.push_slice(&WPubkeyHash::hash(&nodes[0].keys_manager.get_shutdown_pubkey().serialize())[..]).into_script();
+	if let SpendableOutputDescriptor::StaticOutput { ref output, .. } = outputs[0] {
+		assert_eq!(output.script_pubkey, shutdown_script);
+	} else { panic!(); }
+
+	let secp_ctx = Secp256k1::new();
+	let change_script = Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script();
+	let spend_tx = node_cfgs[0].keys_manager.backing.spend_spendable_outputs(&outputs, Vec::new(), change_script, 253, &secp_ctx).unwrap();
+	check_spends!(spend_tx, closing_tx);
+}
+
+#[test]
+fn test_bip32_keys_manager_recovery() {
+	// Tests that a Bip32KeysManager hands out distinct channel keys which, as well as the outputs
+	// paying to them, can be recovered from the seed alone.
+	let secp_ctx = Secp256k1::new();
+	let seed = [42; 32];
+	let keys_manager = Bip32KeysManager::new(&seed, Network::Testnet, 0, 42, 43);
+	let first_keys = keys_manager.get_channel_keys(false, 100_000);
+	let second_keys = keys_manager.get_channel_keys(true, 200_000);
+	assert!(first_keys.pubkeys() != second_keys.pubkeys());
+	assert_eq!(first_keys.key_derivation_params(), (0, 0));
+	assert_eq!(second_keys.key_derivation_params(), (1, 0));
+	assert_eq!(keys_manager.next_channel_index(), 2);
+
+	// Restarting with a different starting time gives the same keys, but fresh random bytes
+	let recovered_manager = Bip32KeysManager::new(&seed, Network::Testnet, 2, 44, 45);
+	assert_eq!(recovered_manager.get_node_secret(), keys_manager.get_node_secret());
+	assert_eq!(recovered_manager.get_destination_script(), keys_manager.get_destination_script());
+	assert_eq!(recovered_manager.get_shutdown_pubkey(), keys_manager.get_shutdown_pubkey());
+	assert!(recovered_manager.derive_channel_keys(200_000, 1).pubkeys() == second_keys.pubkeys());
+	assert_eq!(recovered_manager.derive_channel_keys(200_000, 1).commitment_seed, second_keys.commitment_seed);
+	assert!(recovered_manager.get_secure_random_bytes() != keys_manager.get_secure_random_bytes());
+	assert_eq!(recovered_manager.get_channel_keys(false, 100_000).key_derivation_params(), (2, 0));
+
+	// The keys of a channel are found from its funding output
+	let counterparty_funding_pubkey = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[43; 32]).unwrap());
+	let funding_output = TxOut {
+		script_pubkey: chan_utils::make_funding_redeemscript(&second_keys.pubkeys().funding_pubkey, &counterparty_funding_pubkey).to_v0_p2wsh(),
+		value: 200_000,
+	};
+	let found_keys = recovered_manager.find_channel_keys(&funding_output, &counterparty_funding_pubkey, 10).unwrap();
+	assert_eq!(found_keys.key_derivation_params(), (1, 0));
+	assert!(found_keys.pubkeys() == second_keys.pubkeys());
+	assert!(recovered_manager.find_channel_keys(&funding_output, &counterparty_funding_pubkey, 1).is_none());
+	assert!(recovered_manager.find_channel_keys(&funding_output, &second_keys.pubkeys().funding_pubkey, 10).is_none());
+
+	// Outputs paying to the keys of a channel are spendable with the recovered manager
+	let per_commitment_point = second_keys.get_per_commitment_point(42, &secp_ctx);
+	let delayed_payment_pubkey = chan_utils::derive_public_key(&secp_ctx, &per_commitment_point, &second_keys.pubkeys().delayed_payment_basepoint).unwrap();
+	let revocation_pubkey = counterparty_funding_pubkey;
+	let witness_script = chan_utils::get_revokeable_redeemscript(&revocation_pubkey, 144, &delayed_payment_pubkey);
+	let payment_script = Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0)
+		.push_slice(&WPubkeyHash::hash(&second_keys.pubkeys().payment_point.serialize())[..])
+		.into_script();
+	let prev_tx = Transaction {
+		version: 2,
+		lock_time: 0,
+		input: Vec::new(),
+		output: vec![TxOut { script_pubkey: witness_script.to_v0_p2wsh(), value: 50_000 }, TxOut { script_pubkey: payment_script, value: 40_000 }],
+	};
+	let mut descriptors = vec![SpendableOutputDescriptor::DynamicOutputP2WSH {
+		outpoint: OutPoint { txid: prev_tx.txid(), index: 0 },
+		per_commitment_point,
+		to_self_delay: 144,
+		output: prev_tx.output[0].clone(),
+		key_derivation_params: (1, 0),
+		revocation_pubkey,
+	}, SpendableOutputDescriptor::StaticOutputCounterpartyPayment {
+		outpoint: OutPoint { txid: prev_tx.txid(), index: 1 },
+		output: prev_tx.output[1].clone(),
+		key_derivation_params: (1, 0),
+	}];
+	let change_script = Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script();
+	let spend_tx = recovered_manager.spend_spendable_outputs(&descriptors, Vec::new(), change_script.clone(), 253, &secp_ctx).unwrap();
+	assert_eq!(spend_tx.input[0].sequence, 144);
+	check_spends!(spend_tx, prev_tx);
+
+	// ...including by an OutputSweeper, which tracks them until they can be swept
+	let broadcaster = test_utils::TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) };
+	let fee_estimator = test_utils::TestFeeEstimator { sat_per_kw: 253 };
+	let logger = test_utils::TestLogger::with_id("sweeper".to_owned());
+	let sweeper = OutputSweeper::new(&broadcaster, &fee_estimator, &recovered_manager, &logger, change_script.clone(), 100);
+	assert!(sweeper.track_spendable_outputs(descriptors.clone()).is_empty());
+
+	// Descriptors with key derivation parameters we never handed out are refused
+	descriptors[1] = SpendableOutputDescriptor::StaticOutputCounterpartyPayment {
+		outpoint: OutPoint { txid: prev_tx.txid(), index: 1 },
+		output: prev_tx.output[1].clone(),
+		key_derivation_params: (1, 42),
+	};
+	assert!(recovered_manager.spend_spendable_outputs(&descriptors, Vec::new(), change_script, 253, &secp_ctx).is_err());
+}
+
+#[test]
+fn test_static_channel_backup_recovery() {
+	// Tests that a node which lost all its channel state but its seed and a static channel backup
+	// gets its counterparty to force-close their channel and recovers its balance from the
+	// counterparty's commitment transaction.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	// Update the channel so that our counterparty can tell we're behind
+	send_payment(&nodes[0], &vec!(&nodes[1])[..], 8_000_000, 8_000_000);
+
+	let mut peer_addresses = HashMap::new();
+	peer_addresses.insert(nodes[1].node.get_our_node_id(), vec![msgs::NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }]);
+	let backup = nodes[0].node.get_static_channel_backup(&peer_addresses);
+	assert!(StaticChannelBackup::decrypt(&backup, &nodes[1].keys_manager.get_node_secret()).is_err());
+
+	// Node 0 restarts with nothing but its keys and the backup
+	nodes[1].node.peer_disconnected(&nodes[0].node.get_our_node_id(), false);
+	let recovered_node = ChannelManager::new(Network::Testnet, node_cfgs[0].fee_estimator, &node_cfgs[0].chain_monitor, node_cfgs[0].tx_broadcaster, node_cfgs[0].logger, &node_cfgs[0].keys_manager, UserConfig::default(), 0);
+	let chain_source = test_utils::TestChainSource::new(Network::Testnet);
+	let recovered = recovered_node.recover_from_static_channel_backup(&backup, Some(&chain_source)).unwrap();
+	assert_eq!(recovered.channels.len(), 1);
+	assert_eq!(recovered.channels[0].channel_id, chan.2);
+	assert_eq!(recovered.channels[0].counterparty_addresses, peer_addresses[&nodes[1].node.get_our_node_id()]);
+	assert_eq!(recovered.channels[0].funding_outpoint.txid, chan.3.txid());
+	// The funding output is watched, so that we learn about the closing transaction
+	let funding_outpoint = recovered.channels[0].funding_outpoint;
+	assert_eq!(chan.3.output[funding_outpoint.index as usize].script_pubkey, recovered.channels[0].funding_script_pubkey);
+	assert!(chain_source.watched_outputs.lock().unwrap().contains(&(funding_outpoint, recovered.channels[0].funding_script_pubkey.clone())));
+	// Recovering twice doesn't duplicate the channel, but watches it again (eg after a restart)
+	chain_source.watched_outputs.lock().unwrap().clear();
+	assert_eq!(recovered_node.recover_from_static_channel_backup(&backup, Some(&chain_source)).unwrap().channels.len(), 1);
+	assert_eq!(chain_source.watched_outputs.lock().unwrap().len(), 1);
+
+	// Recovered channels are persisted until their balance is claimed
+	let recovered_node = {
+		let mut recovered_node_read = &recovered_node.encode()[..];
+		let (_, recovered_node) = <(BlockHash, ChannelManager<EnforcingChannelKeys, &test_utils::TestChainMonitor, &test_utils::TestBroadcaster, &test_utils::TestKeysInterface, &test_utils::TestFeeEstimator, &test_utils::TestLogger>)>::read(&mut recovered_node_read, ChannelManagerReadArgs {
+			default_config: UserConfig::default(),
+			keys_manager: &node_cfgs[0].keys_manager,
+			fee_estimator: node_cfgs[0].fee_estimator,
+			chain_monitor: &node_cfgs[0].chain_monitor,
+			tx_broadcaster: node_cfgs[0].tx_broadcaster,
+			logger: node_cfgs[0].logger,
+			channel_monitors: HashMap::new(),
+		}).unwrap();
+		assert!(recovered_node_read.is_empty());
+		recovered_node
+	};
+
+	recovered_node.peer_connected(&nodes[1].node.get_our_node_id(), &msgs::Init { features: InitFeatures::empty() });
+	nodes[1].node.peer_connected(&nodes[0].node.get_our_node_id(), &msgs::Init { features: InitFeatures::empty() });
+	let events = recovered_node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 2);
+	match events[0] {
+		MessageSendEvent::SendChannelReestablish { ref node_id, ref msg } => {
+			assert_eq!(*node_id, nodes[1].node.get_our_node_id());
+			nodes[1].node.handle_channel_reestablish(&nodes[0].node.get_our_node_id(), msg);
+		},
+		_ => panic!("Unexpected event"),
+	}
+	match events[1] {
+		MessageSendEvent::HandleError { action: ErrorAction::SendErrorMessage { ref msg }, .. } => {
+			assert_eq!(msg.channel_id, chan.2);
+			nodes[1].node.handle_error(&nodes[0].node.get_our_node_id(), msg);
+		},
+		_ => panic!("Unexpected event"),
+	}
+	check_added_monitors!(nodes[1], 1);
+	assert!(nodes[1].node.list_channels().is_empty());
+	nodes[1].node.get_and_clear_pending_msg_events();
+	let commitment_tx = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap()[0].clone();
+	check_spends!(commitment_tx, chan.3);
+
+	// Our to_remote output is handed out once the commitment transaction is ANTI_REORG_DELAY
+	// blocks deep, surviving a reorg
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	recovered_node.block_connected(&header, &[(0, &commitment_tx)], 1);
+	recovered_node.block_disconnected(&header);
+	let header_2 = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 43, bits: 42, nonce: 42 };
+	recovered_node.block_connected(&header_2, &[], 1);
+	recovered_node.block_connected(&header, &[(0, &commitment_tx)], 2);
+	for height in 3..ANTI_REORG_DELAY + 2 {
+		assert!(recovered_node.get_and_clear_pending_events().is_empty());
+		recovered_node.block_connected(&header, &[], height);
+	}
+	let events = recovered_node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	let outputs = match events[0] {
+		Event::SpendableOutputs { ref outputs } => outputs.clone(),
+		_ => panic!("Unexpected event"),
+	};
+	if let SpendableOutputDescriptor::StaticOutputCounterpartyPayment { ref output, .. } = outputs[0] {
+		assert_eq!(output.value, 100000 - 8000 - 194); // Less the commitment transaction fee, which we paid
+	} else { panic!(); }
+	let secp_ctx = Secp256k1::new();
+	let change_script = Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script();
+	let spend_tx = node_cfgs[0].keys_manager.backing.spend_spendable_outputs(&outputs, Vec::new(), change_script, 253, &secp_ctx).unwrap();
+	check_spends!(spend_tx, commitment_tx);
+
+	// Once claimed, the channel is dropped from the ChannelManager and its backups
+	let backup = recovered_node.get_static_channel_backup(&HashMap::new());
+	assert!(StaticChannelBackup::decrypt(&backup, &nodes[0].keys_manager.get_node_secret()).unwrap().channels.is_empty());
+}
+
+#[test]
+fn test_output_sweeper() {
+	// Tests that an OutputSweeper waits for a CSV-delayed output to mature, then sweeps it,
+	// fee-bumps the sweep until it confirms and forgets the output once the sweep is buried.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 50_000_000, InitFeatures::known(), InitFeatures::known());
+	nodes[0].node.force_close_channel(&chan.2);
+	check_closed_broadcast!(nodes[0], false);
+	check_added_monitors!(nodes[0], 1);
+	let commitment_tx = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap()[0].clone();
+
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[0], &Block { header, txdata: vec![commitment_tx.clone()] }, 1);
+	connect_blocks(&nodes[0], ANTI_REORG_DELAY - 1, 1, true, header.block_hash());
+	let outputs = get_spendable_outputs(&nodes[0]);
+	assert_eq!(outputs.len(), 1);
+	let to_self_delay = if let SpendableOutputDescriptor::DynamicOutputP2WSH { to_self_delay, .. } = outputs[0] { to_self_delay as u32 } else { panic!(); };
+
+	let broadcaster = test_utils::TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) };
+	let fee_estimator = test_utils::TestFeeEstimator { sat_per_kw: 253 };
+	let logger = test_utils::TestLogger::with_id("sweeper".to_owned());
+	let change_script = Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script();
+	let sweeper = OutputSweeper::new(&broadcaster, &fee_estimator, &node_cfgs[0].keys_manager.backing, &logger, change_script.clone(), ANTI_REORG_DELAY);
+	sweeper.track_spendable_outputs(outputs.clone());
+	// Tracking the same output twice is a no-op
+	sweeper.track_spendable_outputs(outputs.clone());
+	assert_eq!(sweeper.get_tracked_outputs(), outputs);
+
+	// The output only becomes spendable in the block to_self_delay blocks after the commitment
+	// transaction, which confirmed at height 1
+	for height in ANTI_REORG_DELAY + 1..to_self_delay {
+		sweeper.block_connected(&header, &[], height);
+	}
+	assert!(broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+	sweeper.block_connected(&header, &[], to_self_delay);
+	let sweep_tx = {
+		let mut txn = broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(txn.len(), 1);
+		txn.pop().unwrap()
+	};
+	assert_eq!(sweep_tx.input[0].sequence, to_self_delay);
+	assert_eq!(sweep_tx.output[0].script_pubkey, change_script);
+	check_spends!(sweep_tx, commitment_tx);
+
+	// The sweep is rebroadcast as-is until it has been pending for SWEEP_BUMP_INTERVAL blocks...
+	for height in to_self_delay + 1..to_self_delay + SWEEP_BUMP_INTERVAL {
+		sweeper.block_connected(&header, &[], height);
+	}
+	{
+		let mut txn = broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(txn.len(), (SWEEP_BUMP_INTERVAL - 1) as usize);
+		for tx in txn.iter() { assert_eq!(*tx, sweep_tx); }
+		txn.clear();
+	}
+	// ...after which it is fee-bumped
+	sweeper.block_connected(&header, &[], to_self_delay + SWEEP_BUMP_INTERVAL);
+	let bumped_sweep_tx = broadcaster.txn_broadcasted.lock().unwrap().pop().unwrap();
+	assert_eq!(bumped_sweep_tx.input, sweep_tx.input.iter().map(|input| TxIn { witness: bumped_sweep_tx.input[0].witness.clone(), ..input.clone() }).collect::<Vec<_>>());
+	assert!(bumped_sweep_tx.output[0].value < sweep_tx.output[0].value);
+	check_spends!(bumped_sweep_tx, commitment_tx);
+
+	// The sweeper state round-trips, sweep included
+	let sweeper_ser = sweeper.encode();
+	let sweeper: OutputSweeper<_, _, _, _> = ReadableArgs::read(&mut ::std::io::Cursor::new(&sweeper_ser), OutputSweeperReadArgs {
+		broadcaster: &broadcaster,
+		fee_estimator: &fee_estimator,
+		keys_manager: &node_cfgs[0].keys_manager.backing,
+		logger: &logger,
+	}).unwrap();
+	assert_eq!(sweeper.encode(), sweeper_ser);
+	assert_eq!(sweeper.get_tracked_outputs(), outputs);
+
+	// Once the sweep confirms, we stop broadcasting it, and forget the output after
+	// ANTI_REORG_DELAY blocks
+	let conf_height = to_self_delay + SWEEP_BUMP_INTERVAL + 1;
+	sweeper.block_connected(&header, &[(0, &bumped_sweep_tx)], conf_height);
+	for height in conf_height + 1..conf_height + ANTI_REORG_DELAY - 1 {
+		sweeper.block_connected(&header, &[], height);
+	}
+	assert!(broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+	assert_eq!(sweeper.get_tracked_outputs(), outputs);
+
+	// A reorg unconfirming the sweep has us rebroadcast it
+	for height in (conf_height..conf_height + ANTI_REORG_DELAY - 1).rev() {
+		sweeper.block_disconnected(&header, height);
+	}
+	sweeper.block_connected(&header, &[], conf_height);
+	assert_eq!(broadcaster.txn_broadcasted.lock().unwrap().pop().unwrap(), bumped_sweep_tx);
+	sweeper.block_connected(&header, &[(0, &bumped_sweep_tx)], conf_height + 1);
+	for height in conf_height + 2..conf_height + ANTI_REORG_DELAY + 1 {
+		sweeper.block_connected(&header, &[], height);
+	}
+	assert!(sweeper.get_tracked_outputs().is_empty());
+}
+
+#[test]
+fn test_output_sweeper_replacement() {
+	// Tests that an OutputSweeper refuses outputs it can't sign for, and that a sweep replacing a
+	// pending one to add an output pays enough additional fee for BIP 125 relay.
+	let keys_manager = KeysManager::new(&[42; 32], Network::Testnet, 42, 42);
+	let broadcaster = test_utils::TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) };
+	let fee_estimator = test_utils::TestFeeEstimator { sat_per_kw: 253 };
+	let logger = test_utils::TestLogger::with_id("sweeper".to_owned());
+	let change_script = Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script();
+	let sweeper = OutputSweeper::new(&broadcaster, &fee_estimator, &keys_manager, &logger, change_script.clone(), 100);
+
+	let funding_tx = Transaction { version: 2, lock_time: 0, input: Vec::new(), output: vec![
+		TxOut { script_pubkey: keys_manager.get_destination_script(), value: 100_000 },
+		TxOut { script_pubkey: Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0)
+			.push_slice(&WPubkeyHash::hash(&keys_manager.get_shutdown_pubkey().serialize())[..]).into_script(), value: 200_000 },
+		TxOut { script_pubkey: change_script.clone(), value: 300_000 },
+	]};
+	let descriptor = |index: u16| SpendableOutputDescriptor::StaticOutput {
+		outpoint: OutPoint { txid: funding_tx.txid(), index },
+		output: funding_tx.output[index as usize].clone(),
+	};
+
+	// An output to a script we don't have the key for is handed back rather than tracked
+	assert_eq!(sweeper.track_spendable_outputs(vec![descriptor(0), descriptor(2)]), vec![descriptor(2)]);
+	assert_eq!(sweeper.get_tracked_outputs(), vec![descriptor(0)]);
+	let sweep_tx = broadcaster.txn_broadcasted.lock().unwrap().pop().unwrap();
+	check_spends!(sweep_tx, funding_tx);
+	let sweep_fee = 100_000 - sweep_tx.output[0].value;
+
+	// Adding an output while the sweep is pending replaces it, paying for the replacement's relay
+	assert!(sweeper.track_spendable_outputs(vec![descriptor(1)]).is_empty());
+	let replacement_tx = broadcaster.txn_broadcasted.lock().unwrap().pop().unwrap();
+	assert_eq!(replacement_tx.input.len(), 2);
+	check_spends!(replacement_tx, funding_tx);
+	let replacement_fee = 300_000 - replacement_tx.output[0].value;
+	assert!(replacement_fee >= sweep_fee + MIN_RELAY_FEE_SAT_PER_1000_WEIGHT * replacement_tx.get_weight() as u64 / 1000);
+}
+
+#[test]
+fn test_claim_batching_htlcs() {
+	// Tests that, with claim batching enabled in its ChainMonitor, a node claims the HTLC outputs
+	// it knows the preimage for on two different channels' counterparty commitment transactions
+	// in a single transaction, fee-bumping it until it confirms.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+
+	let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_2 = create_announced_chan_between_nodes(&nodes, 2, 1, InitFeatures::known(), InitFeatures::known());
+	let (payment_preimage_1, _) = route_payment(&nodes[0], &[&nodes[1]], 3_000_000);
+	let (payment_preimage_2, _) = route_payment(&nodes[2], &[&nodes[1]], 4_000_000);
+
+	let destination_script = Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script();
+	nodes[1].chain_monitor.chain_monitor.enable_claim_batching(destination_script.clone());
+	assert!(nodes[1].node.claim_funds(payment_preimage_1, &None, 3_000_000));
+	assert!(nodes[1].node.claim_funds(payment_preimage_2, &None, 4_000_000));
+	check_added_monitors!(nodes[1], 2);
+	assert_eq!(nodes[1].node.get_and_clear_pending_msg_events().len(), 2);
+
+	let commitment_tx_1 = get_local_commitment_txn!(nodes[0], chan_1.2)[0].clone();
+	check_spends!(commitment_tx_1, chan_1.3);
+	let commitment_tx_2 = get_local_commitment_txn!(nodes[2], chan_2.2)[0].clone();
+	check_spends!(commitment_tx_2, chan_2.3);
+
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[1], &Block { header, txdata: vec![commitment_tx_1.clone(), commitment_tx_2.clone()] }, 1);
+	assert_eq!(nodes[1].node.get_and_clear_pending_msg_events().len(), 2);
+	check_added_monitors!(nodes[1], 2);
+
+	// Only the batched claim transaction spends the commitment transactions' outputs
+	let claim_txn = |node: &Node| -> Vec<Transaction> {
+		let mut node_txn = node.tx_broadcaster.txn_broadcasted.lock().unwrap();
+		let claim_txn = node_txn.iter().filter(|tx| tx.input.iter().any(|input| input.previous_output.txid == commitment_tx_1.txid() || input.previous_output.txid == commitment_tx_2.txid())).cloned().collect();
+		node_txn.clear();
+		claim_txn
+	};
+	let batch_tx = {
+		let mut txn = claim_txn(&nodes[1]);
+		assert_eq!(txn.len(), 1);
+		txn.pop().unwrap()
+	};
+	assert_eq!(batch_tx.input.len(), 2);
+	assert_eq!(batch_tx.output.len(), 1);
+	assert_eq!(batch_tx.output[0].script_pubkey, destination_script);
+	check_spends!(batch_tx, commitment_tx_1, commitment_tx_2);
+	let contentious_claims = nodes[1].chain_monitor.chain_monitor.get_claimable_balances().iter().filter(|balance| match balance {
+		&&channelmonitor::Balance::ContentiousClaimable { claimable_amount_satoshis, .. } => claimable_amount_satoshis == 3_000 || claimable_amount_satoshis == 4_000,
+		_ => false,
+	}).count();
+	assert_eq!(contentious_claims, 2);
+
+	// The batch is rebroadcast as-is until it has been pending for BATCH_CLAIM_BUMP_INTERVAL
+	// blocks, after which it is fee-bumped
+	let header = BlockHeader { prev_blockhash: header.block_hash(), ..header };
+	let block_hash = connect_blocks(&nodes[1], BATCH_CLAIM_BUMP_INTERVAL - 1, 1, true, header.block_hash());
+	let txn = claim_txn(&nodes[1]);
+	assert_eq!(txn.len(), (BATCH_CLAIM_BUMP_INTERVAL - 1) as usize);
+	for tx in txn.iter() { assert_eq!(*tx, batch_tx); }
+	let block_hash = connect_blocks(&nodes[1], 1, BATCH_CLAIM_BUMP_INTERVAL, true, block_hash);
+	let bumped_batch_tx = {
+		let mut txn = claim_txn(&nodes[1]);
+		assert_eq!(txn.len(), 1);
+		txn.pop().unwrap()
+	};
+	assert_eq!(bumped_batch_tx.input.iter().map(|input| input.previous_output).collect::<Vec<_>>(), batch_tx.input.iter().map(|input| input.previous_output).collect::<Vec<_>>());
+	assert!(bumped_batch_tx.output[0].value < batch_tx.output[0].value);
+	check_spends!(bumped_batch_tx, commitment_tx_1, commitment_tx_2);
+
+	// Once the batch confirms, it isn't rebroadcast anymore and the claimed balances await
+	// ANTI_REORG_DELAY confirmations before being forgotten
+	let conf_height = BATCH_CLAIM_BUMP_INTERVAL + 1;
+	let header = BlockHeader { prev_blockhash: block_hash, ..header };
+	connect_block(&nodes[1], &Block { header, txdata: vec![bumped_batch_tx.clone()] }, conf_height);
+	connect_blocks(&nodes[1], ANTI_REORG_DELAY - 2, conf_height, true, header.block_hash());
+	assert!(claim_txn(&nodes[1]).is_empty());
+	let awaiting_confirmations = nodes[1].chain_monitor.chain_monitor.get_claimable_balances().iter().filter(|balance| match balance {
+		&&channelmonitor::Balance::ClaimableAwaitingConfirmations { claimable_amount_satoshis, confirmation_height } => {
+			(claimable_amount_satoshis == 3_000 || claimable_amount_satoshis == 4_000) && confirmation_height == conf_height + ANTI_REORG_DELAY - 1
+		},
+		_ => false,
+	}).count();
+	assert_eq!(awaiting_confirmations, 2);
+	connect_blocks(&nodes[1], 1, conf_height + ANTI_REORG_DELAY - 2, false, Default::default());
+	assert!(claim_txn(&nodes[1]).is_empty());
+	assert!(nodes[1].chain_monitor.chain_monitor.get_claimable_balances().iter().all(|balance| match balance {
+		&channelmonitor::Balance::ClaimableAwaitingConfirmations { claimable_amount_satoshis, .. } => claimable_amount_satoshis != 3_000 && claimable_amount_satoshis != 4_000,
+		_ => true,
+	}));
+}
+
+#[test]
+fn test_claim_batching_delayed_output() {
+	// Tests that, with claim batching enabled in its ChainMonitor, a node claims its CSV-delayed
+	// to_local output in a batched claim transaction once the delay expires rather than handing
+	// it over via Event::SpendableOutputs, and hands it back once claim batching is disabled.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 50_000_000, InitFeatures::known(), InitFeatures::known());
+	let destination_script = Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script();
+	nodes[0].chain_monitor.chain_monitor.enable_claim_batching(destination_script.clone());
+	nodes[0].node.force_close_channel(&chan.2);
+	check_closed_broadcast!(nodes[0], false);
+	check_added_monitors!(nodes[0], 1);
+	let commitment_tx = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap()[0].clone();
+	check_spends!(commitment_tx, chan.3);
+
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[0], &Block { header, txdata: vec![commitment_tx.clone()] }, 1);
+	let block_hash = connect_blocks(&nodes[0], ANTI_REORG_DELAY - 1, 1, true, header.block_hash());
+	assert!(get_spendable_outputs(&nodes[0]).is_empty());
+
+	// The output only becomes spendable in the block to_self_delay blocks after the commitment
+	// transaction, which confirmed at height 1
+	let spendable_height = match nodes[0].chain_monitor.chain_monitor.get_claimable_balances()[..] {
+		[channelmonitor::Balance::ClaimableAwaitingConfirmations { confirmation_height, .. }] => confirmation_height,
+		_ => panic!("Unexpected balances"),
+	};
+	let to_self_delay = spendable_height - 1;
+	let block_hash = connect_blocks(&nodes[0], spendable_height - 1 - ANTI_REORG_DELAY, ANTI_REORG_DELAY, true, block_hash);
+	nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().clear();
+	let block_hash = connect_blocks(&nodes[0], 1, spendable_height - 1, true, block_hash);
+	let batch_tx = {
+		let mut node_txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		let batch_txn: Vec<_> = node_txn.drain(..).filter(|tx| tx.input[0].previous_output.txid == commitment_tx.txid()).collect();
+		assert_eq!(batch_txn.len(), 1);
+		batch_txn[0].clone()
+	};
+	assert_eq!(batch_tx.input.len(), 1);
+	assert_eq!(batch_tx.input[0].sequence, to_self_delay);
+	assert_eq!(batch_tx.output[0].script_pubkey, destination_script);
+	check_spends!(batch_tx, commitment_tx);
+
+	// Disabling claim batching before the batch confirms hands the output back
+	nodes[0].chain_monitor.chain_monitor.disable_claim_batching();
+	connect_blocks(&nodes[0], 1, spendable_height, true, block_hash);
+	let outputs = get_spendable_outputs(&nodes[0]);
+	assert_eq!(outputs.len(), 1);
+	if let SpendableOutputDescriptor::DynamicOutputP2WSH { to_self_delay: delay, .. } = outputs[0] { assert_eq!(delay as u32, to_self_delay); } else { panic!(); }
+}
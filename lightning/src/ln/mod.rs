@@ -21,8 +21,10 @@
 pub mod channelmanager;
 pub mod msgs;
 pub mod peer_handler;
+pub mod peer_connection_manager;
 pub mod chan_utils;
 pub mod features;
+pub mod channel_backup;
 pub(crate) mod onchaintx;
 
 #[cfg(feature = "fuzztarget")]
@@ -41,6 +43,8 @@ mod wire;
 #[cfg(any(test, feature = "_test_utils"))]
 #[macro_use]
 pub mod functional_test_utils;
+#[cfg(any(test, feature = "_test_utils"))]
+pub mod net_test_utils;
 #[cfg(test)]
 #[allow(unused_mut)]
 mod functional_tests;
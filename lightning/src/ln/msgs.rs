@@ -31,18 +31,23 @@ use bitcoin::blockdata::script::Script;
 use bitcoin::hash_types::{Txid, BlockHash};
 
 use ln::features::{ChannelFeatures, InitFeatures, NodeFeatures};
+use ln::onion_utils::TRAMPOLINE_ONION_DATA_LEN;
 
 use std::{cmp, fmt};
 use std::io::Read;
 
 use util::events;
-use util::ser::{Readable, Writeable, Writer, FixedLengthReader, HighZeroBytesDroppedVarInt};
+use util::ser::{BigSize, Readable, Writeable, Writer, FixedLengthReader, HighZeroBytesDroppedVarInt, VecReadToEnd, VecWriter};
 
 use ln::channelmanager::{PaymentPreimage, PaymentHash, PaymentSecret};
 
 /// 21 million * 10^8 * 1000
 pub(crate) const MAX_VALUE_MSAT: u64 = 21_000_000_0000_0000_000;
 
+/// The lowest TLV type which may be used for custom records in the final hop's onion payload, see
+/// ChannelManager::send_payment_with_custom_tlvs. Lower types are reserved for the protocol.
+pub const MIN_CUSTOM_TLV_TYPE: u64 = 1 << 16;
+
 /// An error in decoding a message or struct.
 #[derive(Debug)]
 pub enum DecodeError {
@@ -246,6 +251,9 @@ pub struct UpdateAddHTLC {
 	/// The expiry height of the HTLC
 	pub cltv_expiry: u32,
 	pub(crate) onion_routing_packet: OnionPacket,
+	/// The ephemeral key used to blind our node id, if this HTLC is being routed through a blinded
+	/// path in which we are not the introduction node.
+	pub blinding_point: Option<PublicKey>,
 }
 
 /// An update_fulfill_htlc message to be sent or received from a peer
@@ -577,7 +585,7 @@ pub struct ChannelUpdate {
 /// UTXOs in a range of blocks. The recipient of a query makes a best
 /// effort to reply to the query using one or more reply_channel_range
 /// messages.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct QueryChannelRange {
 	/// The genesis hash of the blockchain being queried
 	pub chain_hash: BlockHash,
@@ -594,7 +602,7 @@ pub struct QueryChannelRange {
 /// not be a perfect view of the network. The short_channel_ids in the
 /// reply are encoded. We only support encoding_type=0 uncompressed
 /// serialization and do not support encoding_type=1 zlib serialization.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ReplyChannelRange {
 	/// The genesis hash of the blockchain being queried
 	pub chain_hash: BlockHash,
@@ -617,7 +625,7 @@ pub struct ReplyChannelRange {
 /// reply_short_channel_ids_end message. The short_channel_ids sent in
 /// this query are encoded. We only support encoding_type=0 uncompressed
 /// serialization and do not support encoding_type=1 zlib serialization.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct QueryShortChannelIds {
 	/// The genesis hash of the blockchain being queried
 	pub chain_hash: BlockHash,
@@ -629,7 +637,7 @@ pub struct QueryShortChannelIds {
 /// query_short_channel_ids message. The query recipient makes a best
 /// effort to respond based on their local network view which may not be
 /// a perfect view of the network.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ReplyShortChannelIdsEnd {
 	/// The genesis hash of the blockchain that was queried
 	pub chain_hash: BlockHash,
@@ -641,7 +649,7 @@ pub struct ReplyShortChannelIdsEnd {
 /// A gossip_timestamp_filter message is used by a node to request
 /// gossip relay for messages in the requested time range when the
 /// gossip_queries feature has been negotiated.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct GossipTimestampFilter {
 	/// The genesis hash of the blockchain for channel and node information
 	pub chain_hash: BlockHash,
@@ -795,6 +803,20 @@ pub trait ChannelMessageHandler : events::MessageSendEventsProvider + Send + Syn
 
 	/// Handle a peer reconnecting, possibly generating channel_reestablish message(s).
 	fn peer_connected(&self, their_node_id: &PublicKey, msg: &Init);
+	/// Gets the features we advertise to peers in our Init message. By default, these are all
+	/// the features we know except for option_trampoline_routing, which we only support if
+	/// configured to forward trampoline payments.
+	fn provided_init_features(&self) -> InitFeatures {
+		InitFeatures::known().clear_trampoline_routing()
+	}
+	/// Returns true if we have channels with the given peer, in which case the PeerManager accepts
+	/// its connections even if it already has too many peers we have no channels with.
+	///
+	/// By default, this returns true for all peers, so that no connections are closed for being
+	/// from peers we have no channels with unless the handler tracks its channels.
+	fn has_channels_with_peer(&self, _their_node_id: &PublicKey) -> bool {
+		true
+	}
 	/// Handle an incoming channel_reestablish message from the given peer.
 	fn handle_channel_reestablish(&self, their_node_id: &PublicKey, msg: &ChannelReestablish);
 
@@ -804,7 +826,7 @@ pub trait ChannelMessageHandler : events::MessageSendEventsProvider + Send + Syn
 }
 
 /// A trait to describe an object which can receive routing messages.
-pub trait RoutingMessageHandler : Send + Sync {
+pub trait RoutingMessageHandler : events::MessageSendEventsProvider + Send + Sync {
 	/// Handle an incoming node_announcement message, returning true if it should be forwarded on,
 	/// false or returning an Err otherwise.
 	fn handle_node_announcement(&self, msg: &NodeAnnouncement) -> Result<bool, LightningError>;
@@ -827,9 +849,32 @@ pub trait RoutingMessageHandler : Send + Sync {
 	fn get_next_node_announcements(&self, starting_point: Option<&PublicKey>, batch_amount: u8) -> Vec<NodeAnnouncement>;
 	/// Returns whether a full sync should be requested from a peer.
 	fn should_request_full_sync(&self, node_id: &PublicKey) -> bool;
+	/// Called when a connection is established with a peer. This can be used to perform routing
+	/// table synchronization using a strategy defined by the implementor, eg by querying the
+	/// peer's channels if it supports `gossip_queries`.
+	fn sync_routing_table(&self, their_node_id: &PublicKey, init: &Init);
+	/// Handles the reply of a query we initiated to learn about channels for a given range of
+	/// blocks. We can expect to receive one or more replies to a single query.
+	fn handle_reply_channel_range(&self, their_node_id: &PublicKey, msg: &ReplyChannelRange) -> Result<(), LightningError>;
+	/// Handles the reply of a query we initiated asking for routing gossip messages for a list
+	/// of channels. The gossip itself is handled as it arrives, this only indicates that the
+	/// peer is done replying and a new query may be sent.
+	fn handle_reply_short_channel_ids_end(&self, their_node_id: &PublicKey, msg: &ReplyShortChannelIdsEnd) -> Result<(), LightningError>;
+	/// Handles when a peer asks us to send a list of short_channel_ids for the requested range of
+	/// blocks.
+	fn handle_query_channel_range(&self, their_node_id: &PublicKey, msg: &QueryChannelRange) -> Result<(), LightningError>;
+	/// Handles when a peer asks us to send routing gossip messages for a list of
+	/// short_channel_ids. If Ok is returned, the PeerManager replies with the gossip for the
+	/// queried channels, fetched in batches with get_gossip_for_short_channel_ids as the peer's
+	/// outbound buffer drains, followed by a reply_short_channel_ids_end.
+	fn handle_query_short_channel_ids(&self, their_node_id: &PublicKey, msg: &QueryShortChannelIds) -> Result<(), LightningError>;
+	/// Gets the channel announcements and updates of the given channels which we know about, along
+	/// with the node announcements of their nodes, to reply to a query_short_channel_ids.
+	fn get_gossip_for_short_channel_ids(&self, short_channel_ids: &[u64]) -> (Vec<(ChannelAnnouncement, Option<ChannelUpdate>, Option<ChannelUpdate>)>, Vec<NodeAnnouncement>);
 }
 
 mod fuzzy_internal_msgs {
+	use bitcoin::secp256k1::key::PublicKey;
 	use ln::channelmanager::PaymentSecret;
 
 	// These types aren't intended to be pub, but are exposed for direct fuzzing (as we deserialize
@@ -851,7 +896,56 @@ mod fuzzy_internal_msgs {
 		},
 		FinalNode {
 			payment_data: Option<FinalOnionHopData>,
+			/// Records of type MIN_CUSTOM_TLV_TYPE or above, in increasing type order. As we
+			/// reject unknown even types, only odd types are ever read.
+			custom_tlvs: Vec<(u64, Vec<u8>)>,
+		},
+		/// The final hop of the outer onion for a trampoline payment, sent to either a trampoline
+		/// node or a recipient which supports trampoline routing.
+		TrampolineEntry {
+			payment_data: Option<FinalOnionHopData>,
+			trampoline_packet: TrampolineOnionPacket,
+		},
+		/// A hop within a trampoline onion, asking the trampoline node to find a route to
+		/// outgoing_node_id. If payment_data is set, the next node doesn't support trampoline
+		/// routing and is the recipient, so should be paid directly using the given payment_data.
+		TrampolineForward {
+			outgoing_node_id: PublicKey,
+			payment_data: Option<FinalOnionHopData>,
 		},
+		/// A hop within a blinded path which is not the recipient. The next hop is given in the
+		/// encrypted_data, which only we can decrypt. intro_node_blinding_point is set if we are
+		/// the introduction node of the blinded path, as we then did not receive it in the
+		/// update_add_htlc.
+		BlindedForward {
+			encrypted_data: Vec<u8>,
+			intro_node_blinding_point: Option<PublicKey>,
+		},
+		/// The final hop of a blinded path, ie us.
+		BlindedReceive {
+			encrypted_data: Vec<u8>,
+			intro_node_blinding_point: Option<PublicKey>,
+			total_msat: u64,
+		},
+	}
+
+	/// The data a blinded path's creator encrypts to each hop in the path, once decrypted.
+	pub(crate) struct EncryptedBlindedHopData {
+		/// The channel to forward the HTLC over, unset for the recipient.
+		pub(crate) short_channel_id: Option<u64>,
+		/// An identifier only the recipient can set, allowing it to authenticate the payment, unset
+		/// for forwarding nodes.
+		pub(crate) path_id: Option<[u8; 32]>,
+		/// The fees and CLTV delta the forwarding node should deduct, unset for the recipient.
+		pub(crate) payment_relay: Option<PaymentRelay>,
+	}
+
+	/// The forwarding parameters for a hop within a blinded path, as chosen by the path's creator.
+	#[derive(Clone)]
+	pub(crate) struct PaymentRelay {
+		pub(crate) cltv_expiry_delta: u16,
+		pub(crate) fee_proportional_millionths: u32,
+		pub(crate) fee_base_msat: u32,
 	}
 
 	pub struct OnionHopData {
@@ -863,6 +957,16 @@ mod fuzzy_internal_msgs {
 		// 12 bytes of 0-padding for Legacy format
 	}
 
+	/// An onion for trampoline nodes, carried in the payload of the final hop of the outer onion.
+	/// It is constructed the same way as the outer OnionPacket, though is much smaller.
+	#[derive(Clone, PartialEq)]
+	pub struct TrampolineOnionPacket {
+		pub(crate) version: u8,
+		pub(crate) public_key: PublicKey,
+		pub(crate) hop_data: Vec<u8>,
+		pub(crate) hmac: [u8; 32],
+	}
+
 	pub struct DecodedOnionErrorPacket {
 		pub(crate) hmac: [u8; 32],
 		pub(crate) failuremsg: Vec<u8>,
@@ -1202,14 +1306,48 @@ impl Readable for OnionPacket {
 	}
 }
 
-impl_writeable!(UpdateAddHTLC, 32+8+8+32+4+1366, {
-	channel_id,
-	htlc_id,
-	amount_msat,
-	payment_hash,
-	cltv_expiry,
-	onion_routing_packet
-});
+impl Writeable for UpdateAddHTLC {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		w.size_hint(32+8+8+32+4+1366 + if self.blinding_point.is_some() { 2+33 } else { 0 });
+		self.channel_id.write(w)?;
+		self.htlc_id.write(w)?;
+		self.amount_msat.write(w)?;
+		self.payment_hash.write(w)?;
+		self.cltv_expiry.write(w)?;
+		self.onion_routing_packet.write(w)?;
+		if let Some(ref blinding_point) = self.blinding_point {
+			encode_tlv!(w, {
+				(0, blinding_point)
+			});
+		}
+		Ok(())
+	}
+}
+
+impl Readable for UpdateAddHTLC {
+	fn read<R: Read>(mut r: &mut R) -> Result<Self, DecodeError> {
+		let channel_id = Readable::read(r)?;
+		let htlc_id = Readable::read(r)?;
+		let amount_msat = Readable::read(r)?;
+		let payment_hash = Readable::read(r)?;
+		let cltv_expiry = Readable::read(r)?;
+		let onion_routing_packet = Readable::read(r)?;
+		// The TLV stream extends to the end of the message.
+		let mut blinding_point: Option<PublicKey> = None;
+		decode_tlv!(&mut r, {}, {
+			(0, blinding_point)
+		});
+		Ok(UpdateAddHTLC {
+			channel_id,
+			htlc_id,
+			amount_msat,
+			payment_hash,
+			cltv_expiry,
+			onion_routing_packet,
+			blinding_point,
+		})
+	}
+}
 
 impl Writeable for FinalOnionHopData {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
@@ -1227,6 +1365,32 @@ impl Readable for FinalOnionHopData {
 	}
 }
 
+impl Writeable for TrampolineOnionPacket {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		w.size_hint(1 + 33 + TRAMPOLINE_ONION_DATA_LEN + 32);
+		self.version.write(w)?;
+		self.public_key.write(w)?;
+		w.write_all(&self.hop_data)?;
+		self.hmac.write(w)?;
+		Ok(())
+	}
+}
+
+impl Readable for TrampolineOnionPacket {
+	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+		let version = Readable::read(r)?;
+		let public_key = Readable::read(r)?;
+		let mut hop_data = vec![0; TRAMPOLINE_ONION_DATA_LEN];
+		r.read_exact(&mut hop_data)?;
+		Ok(TrampolineOnionPacket {
+			version,
+			public_key,
+			hop_data,
+			hmac: Readable::read(r)?,
+		})
+	}
+}
+
 impl Writeable for OnionHopData {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
 		w.size_hint(33);
@@ -1249,18 +1413,90 @@ impl Writeable for OnionHopData {
 					(6, short_channel_id)
 				});
 			},
-			OnionHopDataFormat::FinalNode { payment_data: Some(ref final_data) } => {
+			OnionHopDataFormat::FinalNode { ref payment_data, ref custom_tlvs } => {
+				// Custom records follow the standard ones, so we can't use
+				// encode_varint_length_prefixed_tlv and instead build the TLV stream first.
+				let mut tlv_stream = VecWriter(Vec::new());
+				encode_tlv!(&mut tlv_stream, {
+					(2, HighZeroBytesDroppedVarInt(self.amt_to_forward)),
+					(4, HighZeroBytesDroppedVarInt(self.outgoing_cltv_value))
+				});
+				if let &Some(ref final_data) = payment_data {
+					if final_data.total_msat > MAX_VALUE_MSAT { panic!("We should never be sending infinite/overflow onion payments"); }
+					encode_tlv!(&mut tlv_stream, {
+						(8, final_data)
+					});
+				}
+				for &(typ, ref value) in custom_tlvs.iter() {
+					BigSize(typ).write(&mut tlv_stream)?;
+					BigSize(value.len() as u64).write(&mut tlv_stream)?;
+					tlv_stream.write_all(value)?;
+				}
+				BigSize(tlv_stream.0.len() as u64).write(w)?;
+				w.write_all(&tlv_stream.0)?;
+			},
+			OnionHopDataFormat::TrampolineEntry { payment_data: Some(ref final_data), ref trampoline_packet } => {
 				if final_data.total_msat > MAX_VALUE_MSAT { panic!("We should never be sending infinite/overflow onion payments"); }
 				encode_varint_length_prefixed_tlv!(w, {
 					(2, HighZeroBytesDroppedVarInt(self.amt_to_forward)),
 					(4, HighZeroBytesDroppedVarInt(self.outgoing_cltv_value)),
-					(8, final_data)
+					(8, final_data),
+					(20, trampoline_packet)
 				});
 			},
-			OnionHopDataFormat::FinalNode { payment_data: None } => {
+			OnionHopDataFormat::TrampolineEntry { payment_data: None, ref trampoline_packet } => {
 				encode_varint_length_prefixed_tlv!(w, {
 					(2, HighZeroBytesDroppedVarInt(self.amt_to_forward)),
-					(4, HighZeroBytesDroppedVarInt(self.outgoing_cltv_value))
+					(4, HighZeroBytesDroppedVarInt(self.outgoing_cltv_value)),
+					(20, trampoline_packet)
+				});
+			},
+			OnionHopDataFormat::TrampolineForward { ref outgoing_node_id, payment_data: Some(ref final_data) } => {
+				if final_data.total_msat > MAX_VALUE_MSAT { panic!("We should never be sending infinite/overflow onion payments"); }
+				encode_varint_length_prefixed_tlv!(w, {
+					(2, HighZeroBytesDroppedVarInt(self.amt_to_forward)),
+					(4, HighZeroBytesDroppedVarInt(self.outgoing_cltv_value)),
+					(8, final_data),
+					(14, outgoing_node_id)
+				});
+			},
+			OnionHopDataFormat::TrampolineForward { ref outgoing_node_id, payment_data: None } => {
+				encode_varint_length_prefixed_tlv!(w, {
+					(2, HighZeroBytesDroppedVarInt(self.amt_to_forward)),
+					(4, HighZeroBytesDroppedVarInt(self.outgoing_cltv_value)),
+					(14, outgoing_node_id)
+				});
+			},
+			// Hops within a blinded path learn the amount and CLTV to forward from the encrypted
+			// data, so only the recipient is told them here.
+			OnionHopDataFormat::BlindedForward { ref encrypted_data, intro_node_blinding_point: Some(ref blinding_point) } => {
+				encode_varint_length_prefixed_tlv!(w, {
+					(10, VecReadToEnd(encrypted_data.clone())),
+					(12, blinding_point)
+				});
+			},
+			OnionHopDataFormat::BlindedForward { ref encrypted_data, intro_node_blinding_point: None } => {
+				encode_varint_length_prefixed_tlv!(w, {
+					(10, VecReadToEnd(encrypted_data.clone()))
+				});
+			},
+			OnionHopDataFormat::BlindedReceive { ref encrypted_data, intro_node_blinding_point: Some(ref blinding_point), total_msat } => {
+				if total_msat > MAX_VALUE_MSAT { panic!("We should never be sending infinite/overflow onion payments"); }
+				encode_varint_length_prefixed_tlv!(w, {
+					(2, HighZeroBytesDroppedVarInt(self.amt_to_forward)),
+					(4, HighZeroBytesDroppedVarInt(self.outgoing_cltv_value)),
+					(10, VecReadToEnd(encrypted_data.clone())),
+					(12, blinding_point),
+					(18, HighZeroBytesDroppedVarInt(total_msat))
+				});
+			},
+			OnionHopDataFormat::BlindedReceive { ref encrypted_data, intro_node_blinding_point: None, total_msat } => {
+				if total_msat > MAX_VALUE_MSAT { panic!("We should never be sending infinite/overflow onion payments"); }
+				encode_varint_length_prefixed_tlv!(w, {
+					(2, HighZeroBytesDroppedVarInt(self.amt_to_forward)),
+					(4, HighZeroBytesDroppedVarInt(self.outgoing_cltv_value)),
+					(10, VecReadToEnd(encrypted_data.clone())),
+					(18, HighZeroBytesDroppedVarInt(total_msat))
 				});
 			},
 		}
@@ -1269,44 +1505,97 @@ impl Writeable for OnionHopData {
 }
 
 impl Readable for OnionHopData {
-	fn read<R: Read>(mut r: &mut R) -> Result<Self, DecodeError> {
-		use bitcoin::consensus::encode::{Decodable, Error, VarInt};
-		let v: VarInt = Decodable::consensus_decode(&mut r)
-			.map_err(|e| match e {
-				Error::Io(ioe) => DecodeError::from(ioe),
-				_ => DecodeError::InvalidValue
-			})?;
+	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+		// Note that the length is a BigSize, not a Bitcoin VarInt - the two only agree for lengths
+		// below 0xfd, which payloads carrying a trampoline onion exceed.
+		let v: BigSize = Readable::read(r)?;
 		const LEGACY_ONION_HOP_FLAG: u64 = 0;
 		let (format, amt, cltv_value) = if v.0 != LEGACY_ONION_HOP_FLAG {
 			let mut rd = FixedLengthReader::new(r, v.0);
-			let mut amt = HighZeroBytesDroppedVarInt(0u64);
-			let mut cltv_value = HighZeroBytesDroppedVarInt(0u32);
+			// amt and cltv_value are required for all but hops within a blinded path, which we
+			// check below.
+			let mut amt: Option<HighZeroBytesDroppedVarInt<u64>> = None;
+			let mut cltv_value: Option<HighZeroBytesDroppedVarInt<u32>> = None;
 			let mut short_id: Option<u64> = None;
 			let mut payment_data: Option<FinalOnionHopData> = None;
-			decode_tlv!(&mut rd, {
+			let mut encrypted_data: Option<VecReadToEnd> = None;
+			let mut blinding_point: Option<PublicKey> = None;
+			let mut outgoing_node_id: Option<PublicKey> = None;
+			let mut total_msat: Option<HighZeroBytesDroppedVarInt<u64>> = None;
+			let mut trampoline_packet: Option<TrampolineOnionPacket> = None;
+			let mut custom_tlvs = Vec::new();
+			decode_tlv!(&mut rd, {}, {
 				(2, amt),
-				(4, cltv_value)
-			}, {
+				(4, cltv_value),
 				(6, short_id),
-				(8, payment_data)
-			});
+				(8, payment_data),
+				(10, encrypted_data),
+				(12, blinding_point),
+				(14, outgoing_node_id),
+				(18, total_msat),
+				(20, trampoline_packet)
+			}, Some(&mut custom_tlvs));
 			rd.eat_remaining().map_err(|_| DecodeError::ShortRead)?;
-			let format = if let Some(short_channel_id) = short_id {
-				if payment_data.is_some() { return Err(DecodeError::InvalidValue); }
-				OnionHopDataFormat::NonFinalNode {
-					short_channel_id,
+			if let &Some(ref data) = &payment_data {
+				if data.total_msat > MAX_VALUE_MSAT {
+					return Err(DecodeError::InvalidValue);
+				}
+			}
+			if let Some(encrypted_data) = encrypted_data {
+				if short_id.is_some() || payment_data.is_some() || outgoing_node_id.is_some() || trampoline_packet.is_some() {
+					return Err(DecodeError::InvalidValue);
+				}
+				match (amt, cltv_value, total_msat) {
+					(None, None, None) => (OnionHopDataFormat::BlindedForward {
+						encrypted_data: encrypted_data.0,
+						intro_node_blinding_point: blinding_point,
+					}, 0, 0),
+					(Some(amt), Some(cltv_value), Some(total_msat)) => {
+						if total_msat.0 > MAX_VALUE_MSAT {
+							return Err(DecodeError::InvalidValue);
+						}
+						(OnionHopDataFormat::BlindedReceive {
+							encrypted_data: encrypted_data.0,
+							intro_node_blinding_point: blinding_point,
+							total_msat: total_msat.0,
+						}, amt.0, cltv_value.0)
+					},
+					_ => return Err(DecodeError::InvalidValue),
 				}
 			} else {
-				if let &Some(ref data) = &payment_data {
-					if data.total_msat > MAX_VALUE_MSAT {
+				if blinding_point.is_some() || total_msat.is_some() {
+					return Err(DecodeError::InvalidValue);
+				}
+				let (amt, cltv_value) = match (amt, cltv_value) {
+					(Some(amt), Some(cltv_value)) => (amt, cltv_value),
+					_ => return Err(DecodeError::InvalidValue),
+				};
+				let format = if let Some(short_channel_id) = short_id {
+					if payment_data.is_some() || outgoing_node_id.is_some() || trampoline_packet.is_some() {
 						return Err(DecodeError::InvalidValue);
 					}
-				}
-				OnionHopDataFormat::FinalNode {
-					payment_data
-				}
-			};
-			(format, amt.0, cltv_value.0)
+					OnionHopDataFormat::NonFinalNode {
+						short_channel_id,
+					}
+				} else if let Some(outgoing_node_id) = outgoing_node_id {
+					if trampoline_packet.is_some() { return Err(DecodeError::InvalidValue); }
+					OnionHopDataFormat::TrampolineForward {
+						outgoing_node_id,
+						payment_data,
+					}
+				} else if let Some(trampoline_packet) = trampoline_packet {
+					OnionHopDataFormat::TrampolineEntry {
+						payment_data,
+						trampoline_packet,
+					}
+				} else {
+					OnionHopDataFormat::FinalNode {
+						payment_data,
+						custom_tlvs,
+					}
+				};
+				(format, amt.0, cltv_value.0)
+			}
 		} else {
 			let format = OnionHopDataFormat::Legacy {
 				short_channel_id: Readable::read(r)?,
@@ -1328,6 +1617,60 @@ impl Readable for OnionHopData {
 	}
 }
 
+impl Writeable for PaymentRelay {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		w.size_hint(2 + 4 + 4);
+		self.cltv_expiry_delta.write(w)?;
+		self.fee_proportional_millionths.write(w)?;
+		HighZeroBytesDroppedVarInt(self.fee_base_msat).write(w)
+	}
+}
+
+impl Readable for PaymentRelay {
+	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+		let cltv_expiry_delta = Readable::read(r)?;
+		let fee_proportional_millionths = Readable::read(r)?;
+		let fee_base_msat: HighZeroBytesDroppedVarInt<u32> = Readable::read(r)?;
+		Ok(PaymentRelay { cltv_expiry_delta, fee_proportional_millionths, fee_base_msat: fee_base_msat.0 })
+	}
+}
+
+impl Writeable for EncryptedBlindedHopData {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		// Note that this is a bare TLV stream, as its length is implied by the encrypted data.
+		if let Some(ref short_channel_id) = self.short_channel_id {
+			encode_tlv!(w, {
+				(2, short_channel_id)
+			});
+		}
+		if let Some(ref path_id) = self.path_id {
+			encode_tlv!(w, {
+				(6, path_id)
+			});
+		}
+		if let Some(ref payment_relay) = self.payment_relay {
+			encode_tlv!(w, {
+				(10, payment_relay)
+			});
+		}
+		Ok(())
+	}
+}
+
+impl Readable for EncryptedBlindedHopData {
+	fn read<R: Read>(mut r: &mut R) -> Result<Self, DecodeError> {
+		let mut short_channel_id: Option<u64> = None;
+		let mut path_id: Option<[u8; 32]> = None;
+		let mut payment_relay: Option<PaymentRelay> = None;
+		decode_tlv!(&mut r, {}, {
+			(2, short_channel_id),
+			(6, path_id),
+			(10, payment_relay)
+		});
+		Ok(EncryptedBlindedHopData { short_channel_id, path_id, payment_relay })
+	}
+}
+
 impl Writeable for Ping {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
 		w.size_hint(self.byteslen as usize + 4);
@@ -2288,7 +2631,8 @@ mod tests {
 			amount_msat: 3608586615801332854,
 			payment_hash: PaymentHash([1; 32]),
 			cltv_expiry: 821716,
-			onion_routing_packet
+			onion_routing_packet,
+			blinding_point: None,
 		};
 		let encoded_value = update_add_htlc.encode();
 		let target_value = hex::decode("020202020202020202020202020202020202020202020202020202020202020200083a840000034d32144668701144760101010101010101010101010101010101010101010101010101010101010101000c89d4ff031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010202020202020202020202020202020202020202020202020202020202020202").unwrap();
@@ -2475,6 +2819,7 @@ mod tests {
 		let mut msg = msgs::OnionHopData {
 			format: OnionHopDataFormat::FinalNode {
 				payment_data: None,
+				custom_tlvs: Vec::new(),
 			},
 			amt_to_forward: 0x0badf00d01020304,
 			outgoing_cltv_value: 0xffffffff,
@@ -2483,7 +2828,7 @@ mod tests {
 		let target_value = hex::decode("1002080badf00d010203040404ffffffff").unwrap();
 		assert_eq!(encoded_value, target_value);
 		msg = Readable::read(&mut Cursor::new(&target_value[..])).unwrap();
-		if let OnionHopDataFormat::FinalNode { payment_data: None } = msg.format { } else { panic!(); }
+		if let OnionHopDataFormat::FinalNode { payment_data: None, .. } = msg.format { } else { panic!(); }
 		assert_eq!(msg.amt_to_forward, 0x0badf00d01020304);
 		assert_eq!(msg.outgoing_cltv_value, 0xffffffff);
 	}
@@ -2497,6 +2842,7 @@ mod tests {
 					payment_secret: expected_payment_secret,
 					total_msat: 0x1badca1f
 				}),
+				custom_tlvs: Vec::new(),
 			},
 			amt_to_forward: 0x0badf00d01020304,
 			outgoing_cltv_value: 0xffffffff,
@@ -2509,7 +2855,8 @@ mod tests {
 			payment_data: Some(FinalOnionHopData {
 				payment_secret,
 				total_msat: 0x1badca1f
-			})
+			}),
+			..
 		} = msg.format {
 			assert_eq!(payment_secret, expected_payment_secret);
 		} else { panic!(); }
@@ -2517,6 +2864,140 @@ mod tests {
 		assert_eq!(msg.outgoing_cltv_value, 0xffffffff);
 	}
 
+	#[test]
+	fn encoding_final_onion_hop_data_with_custom_tlvs() {
+		let msg = msgs::OnionHopData {
+			format: OnionHopDataFormat::FinalNode {
+				payment_data: None,
+				custom_tlvs: vec![(0x10001, vec![0x42; 3]), (0x10003, Vec::new())],
+			},
+			amt_to_forward: 0x0badf00d01020304,
+			outgoing_cltv_value: 0xffffffff,
+		};
+		let encoded_value = msg.encode();
+		let target_value = hex::decode("1f02080badf00d010203040404fffffffffe0001000103424242fe0001000300").unwrap();
+		assert_eq!(encoded_value, target_value);
+		let read_msg: msgs::OnionHopData = Readable::read(&mut Cursor::new(&target_value[..])).unwrap();
+		if let OnionHopDataFormat::FinalNode { payment_data: None, custom_tlvs } = read_msg.format {
+			assert_eq!(custom_tlvs, vec![(0x10001, vec![0x42; 3]), (0x10003, Vec::new())]);
+		} else { panic!(); }
+
+		// Unknown even custom types are rejected, as with any other unknown even type
+		let target_value = hex::decode("1602080badf00d010203040404fffffffffe0001000200").unwrap();
+		match <msgs::OnionHopData as Readable>::read(&mut Cursor::new(&target_value[..])) {
+			Err(msgs::DecodeError::UnknownRequiredFeature) => {},
+			_ => panic!(),
+		}
+	}
+
+	#[test]
+	fn encoding_trampoline_onion_hop_data() {
+		let secp_ctx = Secp256k1::new();
+		let pubkey = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[0x42; 32]).unwrap());
+		let trampoline_packet = msgs::TrampolineOnionPacket {
+			version: 0,
+			public_key: pubkey,
+			hop_data: vec![0x5a; ::ln::onion_utils::TRAMPOLINE_ONION_DATA_LEN],
+			hmac: [0x77; 32],
+		};
+		let msg = msgs::OnionHopData {
+			format: OnionHopDataFormat::TrampolineEntry {
+				payment_data: None,
+				trampoline_packet: trampoline_packet.clone(),
+			},
+			amt_to_forward: 0x0badf00d01020304,
+			outgoing_cltv_value: 0xffffffff,
+		};
+		// The payload is longer than 0xfc bytes, so its length takes a three-byte BigSize.
+		let encoded_value = msg.encode();
+		assert_eq!(&encoded_value[..3], &[0xfd, 0x01, 0xe6][..]);
+		let decoded: msgs::OnionHopData = Readable::read(&mut Cursor::new(&encoded_value[..])).unwrap();
+		if let OnionHopDataFormat::TrampolineEntry { payment_data: None, trampoline_packet: ref packet } = decoded.format {
+			assert!(*packet == trampoline_packet);
+		} else { panic!(); }
+		assert_eq!(decoded.amt_to_forward, 0x0badf00d01020304);
+		assert_eq!(decoded.outgoing_cltv_value, 0xffffffff);
+
+		let msg = msgs::OnionHopData {
+			format: OnionHopDataFormat::TrampolineForward {
+				outgoing_node_id: pubkey,
+				payment_data: None,
+			},
+			amt_to_forward: 0x0badf00d01020304,
+			outgoing_cltv_value: 0xffffffff,
+		};
+		let decoded: msgs::OnionHopData = Readable::read(&mut Cursor::new(&msg.encode()[..])).unwrap();
+		if let OnionHopDataFormat::TrampolineForward { outgoing_node_id, payment_data: None } = decoded.format {
+			assert_eq!(outgoing_node_id, pubkey);
+		} else { panic!(); }
+	}
+
+	#[test]
+	fn encoding_blinded_onion_hop_data() {
+		let secp_ctx = Secp256k1::new();
+		let blinding_point = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[0x42; 32]).unwrap());
+		let msg = msgs::OnionHopData {
+			format: OnionHopDataFormat::BlindedForward {
+				encrypted_data: vec![0x5a; 50],
+				intro_node_blinding_point: Some(blinding_point),
+			},
+			amt_to_forward: 0,
+			outgoing_cltv_value: 0,
+		};
+		let read_msg: msgs::OnionHopData = Readable::read(&mut Cursor::new(&msg.encode())).unwrap();
+		if let OnionHopDataFormat::BlindedForward { encrypted_data, intro_node_blinding_point } = read_msg.format {
+			assert_eq!(encrypted_data, vec![0x5a; 50]);
+			assert_eq!(intro_node_blinding_point, Some(blinding_point));
+		} else { panic!(); }
+
+		let msg = msgs::OnionHopData {
+			format: OnionHopDataFormat::BlindedReceive {
+				encrypted_data: vec![0xa5; 50],
+				intro_node_blinding_point: None,
+				total_msat: 0x1badca1f,
+			},
+			amt_to_forward: 0x0badf00d01020304,
+			outgoing_cltv_value: 0xffffffff,
+		};
+		let read_msg: msgs::OnionHopData = Readable::read(&mut Cursor::new(&msg.encode())).unwrap();
+		if let OnionHopDataFormat::BlindedReceive { encrypted_data, intro_node_blinding_point: None, total_msat: 0x1badca1f } = read_msg.format {
+			assert_eq!(encrypted_data, vec![0xa5; 50]);
+		} else { panic!(); }
+		assert_eq!(read_msg.amt_to_forward, 0x0badf00d01020304);
+		assert_eq!(read_msg.outgoing_cltv_value, 0xffffffff);
+
+		// A blinding point without encrypted_data is invalid
+		let mut target_value = hex::decode("3302080badf00d010203040404ffffffff0c21").unwrap();
+		target_value.extend_from_slice(&blinding_point.serialize());
+		assert!(<msgs::OnionHopData as Readable>::read(&mut Cursor::new(&target_value[..])).is_err());
+	}
+
+	#[test]
+	fn encoding_update_add_htlc_blinding_point() {
+		let secp_ctx = Secp256k1::new();
+		let (_, pubkey_1) = get_keys_from!("0101010101010101010101010101010101010101010101010101010101010101", secp_ctx);
+		let update_add_htlc = msgs::UpdateAddHTLC {
+			channel_id: [2; 32],
+			htlc_id: 2316138423780173,
+			amount_msat: 3608586615801332854,
+			payment_hash: PaymentHash([1; 32]),
+			cltv_expiry: 821716,
+			onion_routing_packet: msgs::OnionPacket {
+				version: 0,
+				public_key: Ok(pubkey_1),
+				hop_data: [1; 20*65],
+				hmac: [2; 32]
+			},
+			blinding_point: Some(pubkey_1),
+		};
+		let encoded_value = update_add_htlc.encode();
+		// The blinding point is appended as TLV type 0 after the fixed-length fields
+		assert_eq!(encoded_value[encoded_value.len() - 35..encoded_value.len() - 33], [0, 33]);
+		let read_msg: msgs::UpdateAddHTLC = Readable::read(&mut Cursor::new(&encoded_value)).unwrap();
+		assert_eq!(read_msg.blinding_point, Some(pubkey_1));
+		assert_eq!(read_msg.encode(), encoded_value);
+	}
+
 	#[test]
 	fn encoding_query_channel_range() {
 		let mut query_channel_range = msgs::QueryChannelRange {
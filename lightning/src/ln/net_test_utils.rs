@@ -0,0 +1,243 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Tests shared by the network stack implementations (lightning-net-tokio and lightning-net-std),
+//! which run them over real TCP sockets through their implementation of [`Transport`].
+//!
+//! [`Transport`]: trait.Transport.html
+
+use ln::features::InitFeatures;
+use ln::msgs::*;
+use ln::peer_handler::{MessageHandler, PeerManager, PeerManagerLimits, SocketDescriptor};
+use util::events::{MessageSendEvent, MessageSendEventsProvider};
+use util::test_utils::TestLogger;
+
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::{Secp256k1, SecretKey, PublicKey};
+
+use std::mem;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// The PeerManager type the shared tests drive.
+pub type TestPeerManager<Descriptor> = PeerManager<Descriptor, Arc<TestNetMsgHandler>, Arc<TestNetMsgHandler>, Arc<TestLogger>>;
+
+/// A network stack implementation to run the shared tests over.
+pub trait Transport {
+	/// The SocketDescriptor the network stack hands to the PeerManager.
+	type Descriptor: SocketDescriptor;
+	/// A handle to a connection's processing, which completes once the peer is disconnected.
+	type Connection;
+
+	/// Starts processing an outbound connection to the peer with the given node id.
+	fn setup_outbound(&self, peer_manager: Arc<TestPeerManager<Self::Descriptor>>, their_node_id: PublicKey, stream: TcpStream) -> Self::Connection;
+	/// Starts processing an inbound connection.
+	fn setup_inbound(&self, peer_manager: Arc<TestPeerManager<Self::Descriptor>>, stream: TcpStream) -> Self::Connection;
+	/// Blocks until the given connection's processing has completed.
+	fn wait_for_close(&self, connection: Self::Connection);
+}
+
+/// A ChannelMessageHandler and RoutingMessageHandler which notifies the test of connections and
+/// disconnections of the peer it expects and lets it queue messages to send.
+pub struct TestNetMsgHandler {
+	expected_pubkey: PublicKey,
+	pubkey_connected: Mutex<mpsc::SyncSender<()>>,
+	pubkey_disconnected: Mutex<mpsc::SyncSender<()>>,
+	/// Events returned by the next get_and_clear_pending_msg_events call.
+	pub msg_events: Mutex<Vec<MessageSendEvent>>,
+	/// The number of reply_channel_range messages we've received.
+	pub channel_range_replies: AtomicUsize,
+}
+impl RoutingMessageHandler for TestNetMsgHandler {
+	fn handle_node_announcement(&self, _msg: &NodeAnnouncement) -> Result<bool, LightningError> { Ok(false) }
+	fn handle_channel_announcement(&self, _msg: &ChannelAnnouncement) -> Result<bool, LightningError> { Ok(false) }
+	fn handle_channel_update(&self, _msg: &ChannelUpdate) -> Result<bool, LightningError> { Ok(false) }
+	fn handle_htlc_fail_channel_update(&self, _update: &HTLCFailChannelUpdate) { }
+	fn get_next_channel_announcements(&self, _starting_point: u64, _batch_amount: u8) -> Vec<(ChannelAnnouncement, Option<ChannelUpdate>, Option<ChannelUpdate>)> { Vec::new() }
+	fn get_next_node_announcements(&self, _starting_point: Option<&PublicKey>, _batch_amount: u8) -> Vec<NodeAnnouncement> { Vec::new() }
+	fn should_request_full_sync(&self, _node_id: &PublicKey) -> bool { false }
+	fn sync_routing_table(&self, _their_node_id: &PublicKey, _init_msg: &Init) { }
+	fn handle_reply_channel_range(&self, _their_node_id: &PublicKey, _msg: &ReplyChannelRange) -> Result<(), LightningError> {
+		self.channel_range_replies.fetch_add(1, Ordering::AcqRel);
+		Ok(())
+	}
+	fn handle_reply_short_channel_ids_end(&self, _their_node_id: &PublicKey, _msg: &ReplyShortChannelIdsEnd) -> Result<(), LightningError> { Ok(()) }
+	fn handle_query_channel_range(&self, _their_node_id: &PublicKey, _msg: &QueryChannelRange) -> Result<(), LightningError> { Ok(()) }
+	fn handle_query_short_channel_ids(&self, _their_node_id: &PublicKey, _msg: &QueryShortChannelIds) -> Result<(), LightningError> { Ok(()) }
+	fn get_gossip_for_short_channel_ids(&self, _short_channel_ids: &[u64]) -> (Vec<(ChannelAnnouncement, Option<ChannelUpdate>, Option<ChannelUpdate>)>, Vec<NodeAnnouncement>) { (Vec::new(), Vec::new()) }
+}
+impl ChannelMessageHandler for TestNetMsgHandler {
+	fn handle_open_channel(&self, _their_node_id: &PublicKey, _their_features: InitFeatures, _msg: &OpenChannel) {}
+	fn handle_accept_channel(&self, _their_node_id: &PublicKey, _their_features: InitFeatures, _msg: &AcceptChannel) {}
+	fn handle_funding_created(&self, _their_node_id: &PublicKey, _msg: &FundingCreated) {}
+	fn handle_funding_signed(&self, _their_node_id: &PublicKey, _msg: &FundingSigned) {}
+	fn handle_funding_locked(&self, _their_node_id: &PublicKey, _msg: &FundingLocked) {}
+	fn handle_shutdown(&self, _their_node_id: &PublicKey, _msg: &Shutdown) {}
+	fn handle_closing_signed(&self, _their_node_id: &PublicKey, _msg: &ClosingSigned) {}
+	fn handle_update_add_htlc(&self, _their_node_id: &PublicKey, _msg: &UpdateAddHTLC) {}
+	fn handle_update_fulfill_htlc(&self, _their_node_id: &PublicKey, _msg: &UpdateFulfillHTLC) {}
+	fn handle_update_fail_htlc(&self, _their_node_id: &PublicKey, _msg: &UpdateFailHTLC) {}
+	fn handle_update_fail_malformed_htlc(&self, _their_node_id: &PublicKey, _msg: &UpdateFailMalformedHTLC) {}
+	fn handle_commitment_signed(&self, _their_node_id: &PublicKey, _msg: &CommitmentSigned) {}
+	fn handle_revoke_and_ack(&self, _their_node_id: &PublicKey, _msg: &RevokeAndACK) {}
+	fn handle_update_fee(&self, _their_node_id: &PublicKey, _msg: &UpdateFee) {}
+	fn handle_announcement_signatures(&self, _their_node_id: &PublicKey, _msg: &AnnouncementSignatures) {}
+	fn peer_disconnected(&self, their_node_id: &PublicKey, _no_connection_possible: bool) {
+		if *their_node_id == self.expected_pubkey {
+			self.pubkey_disconnected.lock().unwrap().try_send(()).unwrap();
+		}
+	}
+	fn peer_connected(&self, their_node_id: &PublicKey, _msg: &Init) {
+		if *their_node_id == self.expected_pubkey {
+			self.pubkey_connected.lock().unwrap().try_send(()).unwrap();
+		}
+	}
+	fn handle_channel_reestablish(&self, _their_node_id: &PublicKey, _msg: &ChannelReestablish) {}
+	fn has_channels_with_peer(&self, _their_node_id: &PublicKey) -> bool { false }
+	fn handle_error(&self, _their_node_id: &PublicKey, _msg: &ErrorMessage) {}
+}
+impl MessageSendEventsProvider for TestNetMsgHandler {
+	fn get_and_clear_pending_msg_events(&self) -> Vec<MessageSendEvent> {
+		let mut ret = Vec::new();
+		mem::swap(&mut *self.msg_events.lock().unwrap(), &mut ret);
+		ret
+	}
+}
+
+/// A node in the shared tests, with receivers notified when it connects to or disconnects from
+/// the other node.
+pub struct TestNetNode<Descriptor: SocketDescriptor> {
+	/// Our node id.
+	pub pubkey: PublicKey,
+	/// The handler for both channel and routing messages.
+	pub handler: Arc<TestNetMsgHandler>,
+	/// Our PeerManager.
+	pub manager: Arc<TestPeerManager<Descriptor>>,
+	/// Receives a message whenever we connect to the other node.
+	pub connected: mpsc::Receiver<()>,
+	/// Receives a message whenever we disconnect from the other node.
+	pub disconnected: mpsc::Receiver<()>,
+}
+
+/// Creates two nodes, each expecting to connect to the other.
+pub fn create_nodes<Descriptor: SocketDescriptor>() -> (TestNetNode<Descriptor>, TestNetNode<Descriptor>) {
+	let secp_ctx = Secp256k1::new();
+	let a_key = SecretKey::from_slice(&[1; 32]).unwrap();
+	let b_key = SecretKey::from_slice(&[2; 32]).unwrap();
+	let a_pub = PublicKey::from_secret_key(&secp_ctx, &a_key);
+	let b_pub = PublicKey::from_secret_key(&secp_ctx, &b_key);
+
+	let mut nodes = Vec::new();
+	for (key, pubkey, expected_pubkey, seed) in vec![(a_key, a_pub, b_pub, [1; 32]), (b_key, b_pub, a_pub, [2; 32])] {
+		let (connected_sender, connected) = mpsc::sync_channel(1);
+		let (disconnected_sender, disconnected) = mpsc::sync_channel(1);
+		let handler = Arc::new(TestNetMsgHandler {
+			expected_pubkey,
+			pubkey_connected: Mutex::new(connected_sender),
+			pubkey_disconnected: Mutex::new(disconnected_sender),
+			msg_events: Mutex::new(Vec::new()),
+			channel_range_replies: AtomicUsize::new(0),
+		});
+		// do_large_write_test queues more than the default outbound buffer limit at once.
+		let limits = PeerManagerLimits { max_outbound_buffer_bytes: 16 * 1024 * 1024, ..Default::default() };
+		let manager = Arc::new(PeerManager::new_with_limits(MessageHandler {
+			chan_handler: Arc::clone(&handler),
+			route_handler: Arc::clone(&handler),
+		}, key, &seed, Arc::new(TestLogger::new()), limits));
+		nodes.push(TestNetNode { pubkey, handler, manager, connected, disconnected });
+	}
+	let b = nodes.pop().unwrap();
+	(nodes.pop().unwrap(), b)
+}
+
+/// Returns both ends of a new TCP connection over localhost.
+pub fn connect_sockets() -> (TcpStream, TcpStream) {
+	// We bind on localhost, hoping the environment is properly configured with a local
+	// address. This may not always be the case in containers and the like, so if this test is
+	// failing for you check that you have a loopback interface and it is configured with
+	// 127.0.0.1.
+	let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind to v4 localhost");
+	let conn_a = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+	(conn_a, listener.accept().unwrap().0)
+}
+
+/// Connects two nodes and disconnects them from the outbound side.
+pub fn do_basic_connection_test<T: Transport>(transport: &T) {
+	let (a, b) = create_nodes();
+	let (conn_a, conn_b) = connect_sockets();
+
+	let connection_a = transport.setup_outbound(Arc::clone(&a.manager), b.pubkey, conn_a);
+	let connection_b = transport.setup_inbound(Arc::clone(&b.manager), conn_b);
+
+	a.connected.recv_timeout(Duration::from_secs(10)).unwrap();
+	b.connected.recv_timeout(Duration::from_secs(1)).unwrap();
+
+	a.handler.msg_events.lock().unwrap().push(MessageSendEvent::HandleError {
+		node_id: b.pubkey, action: ErrorAction::DisconnectPeer { msg: None }
+	});
+	assert!(a.disconnected.try_recv().is_err());
+	assert!(b.disconnected.try_recv().is_err());
+
+	a.manager.process_events();
+	a.disconnected.recv_timeout(Duration::from_secs(10)).unwrap();
+	b.disconnected.recv_timeout(Duration::from_secs(1)).unwrap();
+
+	transport.wait_for_close(connection_a);
+	transport.wait_for_close(connection_b);
+}
+
+/// Sends far more data than fits in the transport's send buffer (and the kernel's), checking that
+/// it resumes writing through write_buffer_space_avail and that all of it gets through, then
+/// disconnects from the inbound side.
+pub fn do_large_write_test<T: Transport>(transport: &T) {
+	let (a, b) = create_nodes();
+	let (conn_a, conn_b) = connect_sockets();
+
+	let connection_a = transport.setup_outbound(Arc::clone(&a.manager), b.pubkey, conn_a);
+	let connection_b = transport.setup_inbound(Arc::clone(&b.manager), conn_b);
+
+	a.connected.recv_timeout(Duration::from_secs(10)).unwrap();
+	b.connected.recv_timeout(Duration::from_secs(1)).unwrap();
+
+	const REPLY_COUNT: usize = 64;
+	for _ in 0..REPLY_COUNT {
+		a.handler.msg_events.lock().unwrap().push(MessageSendEvent::SendReplyChannelRange {
+			node_id: b.pubkey,
+			msg: ReplyChannelRange {
+				chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+				first_blocknum: 0,
+				number_of_blocks: 0xffff_ffff,
+				full_information: true,
+				short_channel_ids: (0..8000).collect(),
+			},
+		});
+	}
+	a.manager.process_events();
+
+	for _ in 0..100 {
+		if b.handler.channel_range_replies.load(Ordering::Acquire) == REPLY_COUNT { break; }
+		thread::sleep(Duration::from_millis(100));
+	}
+	assert_eq!(b.handler.channel_range_replies.load(Ordering::Acquire), REPLY_COUNT);
+
+	// Disconnecting from b's side this time, a notices the socket was closed.
+	b.handler.msg_events.lock().unwrap().push(MessageSendEvent::HandleError {
+		node_id: a.pubkey, action: ErrorAction::DisconnectPeer { msg: None }
+	});
+	b.manager.process_events();
+	b.disconnected.recv_timeout(Duration::from_secs(10)).unwrap();
+	a.disconnected.recv_timeout(Duration::from_secs(10)).unwrap();
+
+	transport.wait_for_close(connection_a);
+	transport.wait_for_close(connection_b);
+}
@@ -32,7 +32,7 @@ use util::logger::Logger;
 use util::ser::{Readable, Writer, Writeable};
 use util::byte_utils;
 
-use std::collections::{HashMap, hash_map};
+use std::collections::{HashMap, HashSet, hash_map};
 use std::cmp;
 use std::ops::Deref;
 
@@ -461,23 +461,11 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 		tx_weight
 	}
 
-	/// In LN, output claimed are time-sensitive, which means we have to spend them before reaching some timelock expiration. At in-channel
-	/// output detection, we generate a first version of a claim tx and associate to it a height timer. A height timer is an absolute block
-	/// height than once reached we should generate a new bumped "version" of the claim tx to be sure than we safely claim outputs before
-	/// than our counterparty can do it too. If timelock expires soon, height timer is going to be scale down in consequence to increase
-	/// frequency of the bump and so increase our bets of success.
-	fn get_height_timer(current_height: u32, timelock_expiration: u32) -> u32 {
-		if timelock_expiration <= current_height + 3 {
-			return current_height + 1
-		} else if timelock_expiration - current_height <= 15 {
-			return current_height + 3
-		}
-		current_height + 15
-	}
-
 	/// Lightning security model (i.e being able to redeem/timeout HTLC or penalize coutnerparty onchain) lays on the assumption of claim transactions getting confirmed before timelock expiration
 	/// (CSV or CLTV following cases). In case of high-fee spikes, claim tx may stuck in the mempool, so you need to bump its feerate quickly using Replace-By-Fee or Child-Pay-For-Parent.
-	fn generate_claim_tx<F: Deref, L: Deref>(&mut self, height: u32, cached_claim_datas: &ClaimTxBumpMaterial, fee_estimator: &F, logger: &L) -> Option<(Option<u32>, u32, Transaction)>
+	/// If inputs_changed is set, the set of outpoints claimed changed since the previous claim tx was generated, thus we must
+	/// rebuild it even if the fee-bump policy declines to bump it, in which case it pays the least fee a replacement may.
+	fn generate_claim_tx<F: Deref, L: Deref>(&mut self, height: u32, cached_claim_datas: &ClaimTxBumpMaterial, inputs_changed: bool, fee_estimator: &F, logger: &L) -> Option<(Option<u32>, u32, Transaction)>
 		where F::Target: FeeEstimator,
 					L::Target: Logger,
 	{
@@ -502,48 +490,12 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 			}],
 		};
 
-		macro_rules! RBF_bump {
-			($amount: expr, $old_feerate: expr, $fee_estimator: expr, $predicted_weight: expr) => {
-				{
-					let mut used_feerate: u32;
-					// If old feerate inferior to actual one given back by Fee Estimator, use it to compute new fee...
-					let new_fee = if $old_feerate < $fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::HighPriority) {
-						let mut value = $amount;
-						if subtract_high_prio_fee!(logger, $fee_estimator, value, $predicted_weight, used_feerate) {
-							// Overflow check is done in subtract_high_prio_fee
-							($amount - value)
-						} else {
-							log_trace!(logger, "Can't new-estimation bump new claiming tx, amount {} is too small", $amount);
-							return None;
-						}
-					// ...else just increase the previous feerate by 25% (because that's a nice number)
-					} else {
-						let fee = $old_feerate as u64 * ($predicted_weight as u64) / 750;
-						if $amount <= fee {
-							log_trace!(logger, "Can't 25% bump new claiming tx, amount {} is too small", $amount);
-							return None;
-						}
-						fee
-					};
-
-					let previous_fee = $old_feerate as u64 * ($predicted_weight as u64) / 1000;
-					let min_relay_fee = MIN_RELAY_FEE_SAT_PER_1000_WEIGHT * ($predicted_weight as u64) / 1000;
-					// BIP 125 Opt-in Full Replace-by-Fee Signaling
-					// 	* 3. The replacement transaction pays an absolute fee of at least the sum paid by the original transactions.
-					//	* 4. The replacement transaction must also pay for its own bandwidth at or above the rate set by the node's minimum relay fee setting.
-					let new_fee = if new_fee < previous_fee + min_relay_fee {
-						new_fee + previous_fee + min_relay_fee - new_fee
-					} else {
-						new_fee
-					};
-					Some((new_fee, new_fee * 1000 / ($predicted_weight as u64)))
-				}
-			}
-		}
-
-		// Compute new height timer to decide when we need to regenerate a new bumped version of the claim tx (if we
-		// didn't receive confirmation of it before, or not enough reorg-safe depth on top of it).
-		let new_timer = Some(Self::get_height_timer(height, cached_claim_datas.soonest_timelock));
+		// In LN, output claimed are time-sensitive, which means we have to spend them before reaching some timelock
+		// expiration. Compute new height timer to decide when we need to regenerate a new bumped version of the claim
+		// tx (if we didn't receive confirmation of it before, or not enough reorg-safe depth on top of it), leaving it
+		// to the fee-bump policy to scale it down as the timelock expiration gets closer.
+		let fee_bump_policy = fee_estimator.get_fee_bump_policy();
+		let new_timer = Some(fee_bump_policy.next_bump_height(height, cached_claim_datas.soonest_timelock));
 		let mut inputs_witnesses_weight = 0;
 		let mut amt = 0;
 		let mut dynamic_fee = true;
@@ -570,15 +522,29 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 			let mut new_feerate;
 			// If old feerate is 0, first iteration of this claim, use normal fee calculation
 			if cached_claim_datas.feerate_previous != 0 {
-				if let Some((new_fee, feerate)) = RBF_bump!(amt, cached_claim_datas.feerate_previous, fee_estimator, predicted_weight) {
-					// If new computed fee is superior at the whole claimable amount burn all in fees
-					if new_fee as u64 > amt {
-						bumped_tx.output[0].value = 0;
-					} else {
-						bumped_tx.output[0].value = amt - new_fee as u64;
-					}
-					new_feerate = feerate;
-				} else { return None; }
+				let previous_feerate = cached_claim_datas.feerate_previous;
+				let previous_fee = previous_feerate as u64 * predicted_weight / 1000;
+				let estimated_feerate = fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::HighPriority);
+				let min_relay_fee = MIN_RELAY_FEE_SAT_PER_1000_WEIGHT * predicted_weight / 1000;
+				// BIP 125 Opt-in Full Replace-by-Fee Signaling
+				// 	* 3. The replacement transaction pays an absolute fee of at least the sum paid by the original transactions.
+				//	* 4. The replacement transaction must also pay for its own bandwidth at or above the rate set by the node's minimum relay fee setting.
+				let new_fee = match fee_bump_policy.bump_feerate(height, cached_claim_datas.soonest_timelock, previous_feerate, estimated_feerate, amt, predicted_weight) {
+					Some(feerate) => cmp::max(feerate as u64 * predicted_weight / 1000, previous_fee + min_relay_fee),
+					None if inputs_changed => previous_fee + min_relay_fee,
+					None => {
+						log_trace!(logger, "Fee-bump policy declined to bump claiming tx with feerate {} and amount {}", previous_feerate, amt);
+						return None;
+					},
+				};
+				// BIP 125 may require a higher fee than the policy allowed for, don't bump if we'd be
+				// left with nothing to claim.
+				if new_fee >= amt {
+					log_trace!(logger, "Can't bump claiming tx with feerate {}, new fee {} exceeds amount {}", previous_feerate, new_fee, amt);
+					return None;
+				}
+				bumped_tx.output[0].value = amt - new_fee;
+				new_feerate = (new_fee * 1000 / predicted_weight) as u32;
 			} else {
 				if subtract_high_prio_fee!(logger, fee_estimator, amt, predicted_weight, new_feerate) {
 					bumped_tx.output[0].value = amt;
@@ -705,7 +671,7 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 		// height timer expiration (i.e in how many blocks we're going to take action).
 		for (soonest_timelock, claim) in new_claims.drain(..) {
 			let mut claim_material = ClaimTxBumpMaterial { height_timer: None, feerate_previous: 0, soonest_timelock, per_input_material: claim };
-			if let Some((new_timer, new_feerate, tx)) = self.generate_claim_tx(height, &claim_material, &*fee_estimator, &*logger) {
+			if let Some((new_timer, new_feerate, tx)) = self.generate_claim_tx(height, &claim_material, false, &*fee_estimator, &*logger) {
 				claim_material.height_timer = new_timer;
 				claim_material.feerate_previous = new_feerate;
 				let txid = tx.txid();
@@ -776,7 +742,7 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 							}
 							//TODO: recompute soonest_timelock to avoid wasting a bit on fees
 							if at_least_one_drop {
-								bump_candidates.insert(first_claim_txid_height.0.clone(), claim_material.clone());
+								bump_candidates.insert(first_claim_txid_height.0.clone(), (claim_material.clone(), true));
 							}
 						}
 						break; //No need to iterate further, either tx is our or their
@@ -824,21 +790,27 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 		for (first_claim_txid, ref claim_data) in self.pending_claim_requests.iter() {
 			if let Some(h) = claim_data.height_timer {
 				if h == height {
-					bump_candidates.insert(*first_claim_txid, (*claim_data).clone());
+					bump_candidates.entry(*first_claim_txid).or_insert(((*claim_data).clone(), false));
 				}
 			}
 		}
 
 		// Build, bump and rebroadcast tx accordingly
 		log_trace!(logger, "Bumping {} candidates", bump_candidates.len());
-		for (first_claim_txid, claim_material) in bump_candidates.iter() {
-			if let Some((new_timer, new_feerate, bump_tx)) = self.generate_claim_tx(height, &claim_material, &*fee_estimator, &*logger) {
+		for (first_claim_txid, &(ref claim_material, inputs_changed)) in bump_candidates.iter() {
+			if let Some((new_timer, new_feerate, bump_tx)) = self.generate_claim_tx(height, &claim_material, inputs_changed, &*fee_estimator, &*logger) {
 				log_trace!(logger, "Broadcast onchain {}", log_tx!(bump_tx));
 				broadcaster.broadcast_transaction(&bump_tx);
 				if let Some(claim_material) = self.pending_claim_requests.get_mut(first_claim_txid) {
 					claim_material.height_timer = new_timer;
 					claim_material.feerate_previous = new_feerate;
 				}
+			} else if let Some(claim_material) = self.pending_claim_requests.get_mut(first_claim_txid) {
+				// Keep the previous claim tx, but try again later, as the fee-bump policy may be
+				// willing to bump it closer to the deadline.
+				if claim_material.height_timer.is_some() {
+					claim_material.height_timer = Some(fee_estimator.get_fee_bump_policy().next_bump_height(height, claim_material.soonest_timelock));
+				}
 			}
 		}
 	}
@@ -869,7 +841,7 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 			}
 		}
 		for (_, claim_material) in bump_candidates.iter_mut() {
-			if let Some((new_timer, new_feerate, bump_tx)) = self.generate_claim_tx(height, &claim_material, &&*fee_estimator, &&*logger) {
+			if let Some((new_timer, new_feerate, bump_tx)) = self.generate_claim_tx(height, &claim_material, true, &&*fee_estimator, &&*logger) {
 				claim_material.height_timer = new_timer;
 				claim_material.feerate_previous = new_feerate;
 				broadcaster.broadcast_transaction(&bump_tx);
@@ -896,6 +868,33 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 		self.holder_commitment = Some(tx);
 	}
 
+	/// Returns the latest holder commitment transaction, unsigned, if we have one.
+	pub(crate) fn get_unsigned_holder_commitment_tx(&self) -> Option<&Transaction> {
+		self.holder_commitment.as_ref().map(|holder_commitment| &holder_commitment.unsigned_tx)
+	}
+
+	/// Returns the outpoints we are still trying to claim along with their input material. Claim
+	/// requests whose transaction has already confirmed, and which are only kept around until
+	/// ANTI_REORG_DELAY, are skipped.
+	pub(crate) fn get_unresolved_claim_inputs(&self) -> Vec<(&BitcoinOutPoint, &InputMaterial)> {
+		let mut resolved_claims = HashSet::new();
+		for events in self.onchain_events_waiting_threshold_conf.values() {
+			for ev in events.iter() {
+				if let &OnchainEvent::Claim { ref claim_request } = ev {
+					resolved_claims.insert(claim_request);
+				}
+			}
+		}
+		let mut res = Vec::new();
+		for (claim_txid, claim_material) in self.pending_claim_requests.iter() {
+			if resolved_claims.contains(claim_txid) { continue; }
+			for (outpoint, input_material) in claim_material.per_input_material.iter() {
+				res.push((outpoint, input_material));
+			}
+		}
+		res
+	}
+
 	fn sign_latest_holder_htlcs(&mut self) {
 		if let Some(ref holder_commitment) = self.holder_commitment {
 			if let Ok(sigs) = self.key_storage.sign_holder_commitment_htlc_transactions(holder_commitment, &self.secp_ctx) {
@@ -9,9 +9,10 @@
 
 use ln::channelmanager::{PaymentHash, PaymentSecret, HTLCSource};
 use ln::msgs;
-use routing::router::RouteHop;
+use routing::router::{BlindedHop, BlindedPath, RouteHop, TrampolineHop};
 use util::byte_utils;
-use util::chacha20::ChaCha20;
+use util::chacha20::{ChaCha20, ChaChaReader};
+use util::chacha20poly1305rfc::ChaCha20Poly1305RFC;
 use util::errors::{self, APIError};
 use util::ser::{Readable, Writeable, LengthCalculatingWriter};
 use util::logger::Logger;
@@ -26,7 +27,7 @@ use bitcoin::secp256k1::Secp256k1;
 use bitcoin::secp256k1::ecdh::SharedSecret;
 use bitcoin::secp256k1;
 
-use std::io::Cursor;
+use std::io::{Cursor, Read};
 use std::ops::Deref;
 
 pub(super) struct OnionKeys {
@@ -72,12 +73,17 @@ pub(super) fn gen_ammag_from_shared_secret(shared_secret: &[u8]) -> [u8; 32] {
 
 // can only fail if an intermediary hop has an invalid public key or session_priv is invalid
 #[inline]
-pub(super) fn construct_onion_keys_callback<T: secp256k1::Signing, FType: FnMut(SharedSecret, [u8; 32], PublicKey, &RouteHop)> (secp_ctx: &Secp256k1<T>, path: &Vec<RouteHop>, session_priv: &SecretKey, mut callback: FType) -> Result<(), secp256k1::Error> {
+pub(super) fn construct_onion_keys_callback<T: secp256k1::Signing, FType: FnMut(SharedSecret, [u8; 32], PublicKey, &RouteHop)> (secp_ctx: &Secp256k1<T>, path: &Vec<RouteHop>, session_priv: &SecretKey, callback: FType) -> Result<(), secp256k1::Error> {
+	construct_onion_keys_generic_callback(secp_ctx, path.iter().map(|hop| (&hop.pubkey, hop)), session_priv, callback)
+}
+
+#[inline]
+fn construct_onion_keys_generic_callback<'a, T: secp256k1::Signing, H, I: Iterator<Item=(&'a PublicKey, H)>, FType: FnMut(SharedSecret, [u8; 32], PublicKey, H)> (secp_ctx: &Secp256k1<T>, hops: I, session_priv: &SecretKey, mut callback: FType) -> Result<(), secp256k1::Error> {
 	let mut blinded_priv = session_priv.clone();
 	let mut blinded_pub = PublicKey::from_secret_key(secp_ctx, &blinded_priv);
 
-	for hop in path.iter() {
-		let shared_secret = SharedSecret::new(&hop.pubkey, &blinded_priv);
+	for (pubkey, hop) in hops {
+		let shared_secret = SharedSecret::new(pubkey, &blinded_priv);
 
 		let mut sha = Sha256::engine();
 		sha.input(&blinded_pub.serialize()[..]);
@@ -97,9 +103,14 @@ pub(super) fn construct_onion_keys_callback<T: secp256k1::Signing, FType: FnMut(
 
 // can only fail if an intermediary hop has an invalid public key or session_priv is invalid
 pub(super) fn construct_onion_keys<T: secp256k1::Signing>(secp_ctx: &Secp256k1<T>, path: &Vec<RouteHop>, session_priv: &SecretKey) -> Result<Vec<OnionKeys>, secp256k1::Error> {
-	let mut res = Vec::with_capacity(path.len());
+	construct_onion_keys_for_pubkeys(secp_ctx, path.iter().map(|hop| &hop.pubkey), path.len(), session_priv)
+}
 
-	construct_onion_keys_callback(secp_ctx, path, session_priv, |shared_secret, _blinding_factor, ephemeral_pubkey, _| {
+// can only fail if an intermediary hop has an invalid public key or session_priv is invalid
+fn construct_onion_keys_for_pubkeys<'a, T: secp256k1::Signing, I: Iterator<Item=&'a PublicKey>>(secp_ctx: &Secp256k1<T>, pubkeys: I, hop_count: usize, session_priv: &SecretKey) -> Result<Vec<OnionKeys>, secp256k1::Error> {
+	let mut res = Vec::with_capacity(hop_count);
+
+	construct_onion_keys_generic_callback(secp_ctx, pubkeys.map(|pubkey| (pubkey, ())), session_priv, |shared_secret, _blinding_factor, ephemeral_pubkey, _| {
 		let (rho, mu) = gen_rho_mu_from_shared_secret(&shared_secret[..]);
 
 		res.push(OnionKeys {
@@ -117,7 +128,8 @@ pub(super) fn construct_onion_keys<T: secp256k1::Signing>(secp_ctx: &Secp256k1<T
 }
 
 /// returns the hop data, as well as the first-hop value_msat and CLTV value we should send.
-pub(super) fn build_onion_payloads(path: &Vec<RouteHop>, total_msat: u64, payment_secret_option: &Option<PaymentSecret>, starting_htlc_offset: u32) -> Result<(Vec<msgs::OnionHopData>, u64, u32), APIError> {
+/// If a trampoline_packet is provided, it is included in the payload for the last hop in path.
+pub(super) fn build_onion_payloads(path: &Vec<RouteHop>, total_msat: u64, payment_secret_option: &Option<PaymentSecret>, custom_tlvs: &[(u64, Vec<u8>)], starting_htlc_offset: u32, mut trampoline_packet: Option<msgs::TrampolineOnionPacket>) -> Result<(Vec<msgs::OnionHopData>, u64, u32), APIError> {
 	let mut cur_value_msat = 0u64;
 	let mut cur_cltv = starting_htlc_offset;
 	let mut last_short_channel_id = 0;
@@ -132,13 +144,25 @@ pub(super) fn build_onion_payloads(path: &Vec<RouteHop>, total_msat: u64, paymen
 		res.insert(0, msgs::OnionHopData {
 			format: if hop.node_features.supports_variable_length_onion() {
 				if idx == 0 {
-					msgs::OnionHopDataFormat::FinalNode {
-						payment_data: if let &Some(ref payment_secret) = payment_secret_option {
-							Some(msgs::FinalOnionHopData {
-								payment_secret: payment_secret.clone(),
-								total_msat,
-							})
-						} else { None },
+					let payment_data = if let &Some(ref payment_secret) = payment_secret_option {
+						Some(msgs::FinalOnionHopData {
+							payment_secret: payment_secret.clone(),
+							total_msat,
+						})
+					} else { None };
+					if let Some(trampoline_packet) = trampoline_packet.take() {
+						if !custom_tlvs.is_empty() {
+							return Err(APIError::RouteError{err: "Custom TLVs can't be sent to a trampoline node"});
+						}
+						msgs::OnionHopDataFormat::TrampolineEntry {
+							payment_data,
+							trampoline_packet,
+						}
+					} else {
+						msgs::OnionHopDataFormat::FinalNode {
+							payment_data,
+							custom_tlvs: custom_tlvs.to_vec(),
+						}
 					}
 				} else {
 					msgs::OnionHopDataFormat::NonFinalNode {
@@ -146,6 +170,12 @@ pub(super) fn build_onion_payloads(path: &Vec<RouteHop>, total_msat: u64, paymen
 					}
 				}
 			} else {
+				if idx == 0 && trampoline_packet.is_some() {
+					return Err(APIError::RouteError{err: "Trampoline node doesn't support variable-length onions"});
+				}
+				if idx == 0 && !custom_tlvs.is_empty() {
+					return Err(APIError::RouteError{err: "Destination doesn't support variable-length onions, which custom TLVs require"});
+				}
 				msgs::OnionHopDataFormat::Legacy {
 					short_channel_id: last_short_channel_id,
 				}
@@ -166,13 +196,325 @@ pub(super) fn build_onion_payloads(path: &Vec<RouteHop>, total_msat: u64, paymen
 	Ok((res, cur_value_msat, cur_cltv))
 }
 
+/// Builds the payloads for a trampoline onion paying the last hop in hops via the trampoline nodes
+/// before it. Returns the hop data as well as the node_ids of the nodes which the trampoline onion
+/// is for, which excludes the last hop if it does not support trampoline routing (in which case
+/// the last trampoline node pays it directly).
+pub(super) fn build_trampoline_onion_payloads(hops: &[TrampolineHop], payment_secret_option: &Option<PaymentSecret>, starting_htlc_offset: u32) -> Result<(Vec<msgs::OnionHopData>, Vec<PublicKey>), APIError> {
+	let recipient = match hops.last() {
+		Some(hop) => hop,
+		None => return Err(APIError::RouteError{err: "Trampoline hops didn't go anywhere"}),
+	};
+	let recipient_supports_trampoline = recipient.node_features.supports_trampoline_routing();
+	if !recipient_supports_trampoline && hops.len() < 2 {
+		return Err(APIError::RouteError{err: "Recipient doesn't support trampoline routing, at least one trampoline node is required"});
+	}
+	if !recipient_supports_trampoline && payment_secret_option.is_none() {
+		return Err(APIError::RouteError{err: "Paying a recipient which doesn't support trampoline routing requires a payment_secret"});
+	}
+	let payment_data = if let &Some(ref payment_secret) = payment_secret_option {
+		Some(msgs::FinalOnionHopData {
+			payment_secret: payment_secret.clone(),
+			total_msat: recipient.fee_msat,
+		})
+	} else { None };
+
+	let mut cur_value_msat = 0u64;
+	let mut cur_cltv = starting_htlc_offset;
+	let mut next_node_id = None;
+	let mut res: Vec<msgs::OnionHopData> = Vec::with_capacity(hops.len());
+	let mut node_ids = Vec::with_capacity(hops.len());
+
+	for (idx, hop) in hops.iter().rev().enumerate() {
+		let value_msat = if idx == 0 { hop.fee_msat } else { cur_value_msat };
+		let cltv = if idx == 0 { hop.cltv_expiry_delta + starting_htlc_offset } else { cur_cltv };
+		let format = if idx == 0 {
+			if recipient_supports_trampoline {
+				Some(msgs::OnionHopDataFormat::FinalNode {
+					payment_data: payment_data.clone(),
+					custom_tlvs: Vec::new(),
+				})
+			} else { None }
+		} else {
+			if !hop.node_features.supports_trampoline_routing() || !hop.node_features.supports_variable_length_onion() {
+				return Err(APIError::RouteError{err: "Trampoline hop doesn't support trampoline routing"});
+			}
+			Some(msgs::OnionHopDataFormat::TrampolineForward {
+				outgoing_node_id: next_node_id.unwrap(),
+				payment_data: if idx == 1 && !recipient_supports_trampoline { payment_data.clone() } else { None },
+			})
+		};
+		if let Some(format) = format {
+			res.insert(0, msgs::OnionHopData {
+				format,
+				amt_to_forward: value_msat,
+				outgoing_cltv_value: cltv,
+			});
+			node_ids.insert(0, hop.pubkey);
+		}
+		cur_value_msat += hop.fee_msat;
+		if cur_value_msat >= 21000000 * 100000000 * 1000 {
+			return Err(APIError::RouteError{err: "Trampoline fees overflowed?"});
+		}
+		cur_cltv += hop.cltv_expiry_delta as u32;
+		if cur_cltv >= 500000000 {
+			return Err(APIError::RouteError{err: "Trampoline CLTV overflowed?"});
+		}
+		next_node_id = Some(hop.pubkey);
+	}
+	Ok((res, node_ids))
+}
+
+/// Builds a trampoline onion paying the last hop in hops via the trampoline nodes before it.
+pub(super) fn build_trampoline_onion<T: secp256k1::Signing>(secp_ctx: &Secp256k1<T>, hops: &[TrampolineHop], payment_secret_option: &Option<PaymentSecret>, starting_htlc_offset: u32, session_priv: &SecretKey, prng_seed: [u8; 32], payment_hash: &PaymentHash) -> Result<msgs::TrampolineOnionPacket, APIError> {
+	let (payloads, node_ids) = build_trampoline_onion_payloads(hops, payment_secret_option, starting_htlc_offset)?;
+	let onion_keys = construct_onion_keys_for_pubkeys(secp_ctx, node_ids.iter(), node_ids.len(), session_priv)
+		.map_err(|_| APIError::RouteError{err: "Pubkey along trampoline hop was maliciously selected"})?;
+	if payloads_size_insane(&payloads, TRAMPOLINE_ONION_DATA_LEN) {
+		return Err(APIError::RouteError{err: "Trampoline route size too large considering onion data"});
+	}
+
+	let mut packet_data = [0; TRAMPOLINE_ONION_DATA_LEN];
+	let mut chacha = ChaCha20::new(&prng_seed, &[0; 8]);
+	chacha.process(&[0; TRAMPOLINE_ONION_DATA_LEN], &mut packet_data);
+
+	let hmac = encrypt_onion_packet_data(payloads, &onion_keys, &mut packet_data, payment_hash);
+	Ok(msgs::TrampolineOnionPacket {
+		version: 0,
+		public_key: onion_keys.first().unwrap().ephemeral_pubkey,
+		hop_data: packet_data.to_vec(),
+		hmac,
+	})
+}
+
+/// Decodes the payload for us from a trampoline onion we received, returning it along with the
+/// trampoline onion to forward to the next trampoline hop, if there is one. On failure, returns
+/// a message describing the failure, which should be reported as an invalid_onion_payload.
+pub(super) fn decode_trampoline_onion<T: secp256k1::Verification>(secp_ctx: &Secp256k1<T>, node_secret: &SecretKey, packet: &msgs::TrampolineOnionPacket, payment_hash: &PaymentHash) -> Result<(msgs::OnionHopData, Option<msgs::TrampolineOnionPacket>), &'static str> {
+	if packet.version != 0 {
+		return Err("Unknown trampoline onion packet version");
+	}
+	if packet.hop_data.len() != TRAMPOLINE_ONION_DATA_LEN {
+		return Err("Trampoline onion packet was of the wrong length");
+	}
+
+	let shared_secret = {
+		let mut arr = [0; 32];
+		arr.copy_from_slice(&SharedSecret::new(&packet.public_key, node_secret)[..]);
+		arr
+	};
+	let (rho, mu) = gen_rho_mu_from_shared_secret(&shared_secret);
+
+	let mut hmac = HmacEngine::<Sha256>::new(&mu);
+	hmac.input(&packet.hop_data);
+	hmac.input(&payment_hash.0[..]);
+	if !fixed_time_eq(&Hmac::from_engine(hmac).into_inner(), &packet.hmac) {
+		return Err("Trampoline onion HMAC check failed");
+	}
+
+	let mut chacha = ChaCha20::new(&rho, &[0u8; 8]);
+	let mut chacha_stream = ChaChaReader { chacha: &mut chacha, read: Cursor::new(&packet.hop_data[..]) };
+	let hop_data = msgs::OnionHopData::read(&mut chacha_stream).map_err(|_| "Unable to decode our trampoline hop data")?;
+	let mut next_hmac = [0; 32];
+	chacha_stream.read_exact(&mut next_hmac[..]).map_err(|_| "Unable to decode trampoline hop data")?;
+	if next_hmac == [0; 32] {
+		return Ok((hop_data, None));
+	}
+
+	let mut new_packet_data = vec![0; TRAMPOLINE_ONION_DATA_LEN];
+	let read_pos = chacha_stream.read(&mut new_packet_data).unwrap();
+	// Once we've emptied the set of bytes the packet contained, encrypt 0 bytes until we fill the
+	// onion hop data we'll forward to the next trampoline node.
+	chacha_stream.chacha.process_in_place(&mut new_packet_data[read_pos..]);
+
+	let mut new_pubkey = packet.public_key.clone();
+	let blinding_factor = {
+		let mut sha = Sha256::engine();
+		sha.input(&new_pubkey.serialize()[..]);
+		sha.input(&shared_secret);
+		Sha256::from_engine(sha).into_inner()
+	};
+	new_pubkey.mul_assign(secp_ctx, &blinding_factor[..]).map_err(|_| "Trampoline onion blinding resulted in an invalid key")?;
+
+	Ok((hop_data, Some(msgs::TrampolineOnionPacket {
+		version: 0,
+		public_key: new_pubkey,
+		hop_data: new_packet_data,
+		hmac: next_hmac,
+	})))
+}
+
+#[inline]
+fn gen_blinded_node_id_tweak_from_shared_secret(shared_secret: &[u8]) -> [u8; 32] {
+	let mut hmac = HmacEngine::<Sha256>::new(&[0x62, 0x6c, 0x69, 0x6e, 0x64, 0x65, 0x64, 0x5f, 0x6e, 0x6f, 0x64, 0x65, 0x5f, 0x69, 0x64]); // blinded_node_id
+	hmac.input(&shared_secret[..]);
+	Hmac::from_engine(hmac).into_inner()
+}
+
+#[inline]
+fn gen_blinding_factor(blinding_point: &PublicKey, shared_secret: &[u8]) -> [u8; 32] {
+	let mut sha = Sha256::engine();
+	sha.input(&blinding_point.serialize()[..]);
+	sha.input(&shared_secret[..]);
+	Sha256::from_engine(sha).into_inner()
+}
+
+/// Builds a blinded path through the given hops, encrypting to each the data given alongside it.
+/// Returns the blinding point for the first hop along with the blinded hops.
+// can only fail if a hop has an invalid public key or session_priv is invalid
+pub(super) fn construct_blinded_hops<T: secp256k1::Signing + secp256k1::Verification>(secp_ctx: &Secp256k1<T>, hops: &[(PublicKey, msgs::EncryptedBlindedHopData)], session_priv: &SecretKey) -> Result<(PublicKey, Vec<BlindedHop>), secp256k1::Error> {
+	let mut blinded_priv = session_priv.clone();
+	let mut blinding_point = PublicKey::from_secret_key(secp_ctx, &blinded_priv);
+	let first_blinding_point = blinding_point.clone();
+	let mut res = Vec::with_capacity(hops.len());
+
+	for &(ref node_id, ref data) in hops.iter() {
+		let shared_secret = SharedSecret::new(node_id, &blinded_priv);
+
+		let mut blinded_node_id = node_id.clone();
+		blinded_node_id.mul_assign(secp_ctx, &gen_blinded_node_id_tweak_from_shared_secret(&shared_secret[..])[..])?;
+
+		let (rho, _) = gen_rho_mu_from_shared_secret(&shared_secret[..]);
+		let plaintext = data.encode();
+		let mut encrypted_payload = vec![0; plaintext.len() + 16];
+		let mut tag = [0; 16];
+		ChaCha20Poly1305RFC::new(&rho, &[0; 12], &[]).encrypt(&plaintext, &mut encrypted_payload[..plaintext.len()], &mut tag);
+		encrypted_payload[plaintext.len()..].copy_from_slice(&tag);
+
+		res.push(BlindedHop {
+			blinded_node_id,
+			encrypted_payload,
+		});
+
+		let blinding_factor = gen_blinding_factor(&blinding_point, &shared_secret[..]);
+		blinded_priv.mul_assign(&blinding_factor)?;
+		blinding_point.mul_assign(secp_ctx, &blinding_factor)?;
+	}
+
+	Ok((first_blinding_point, res))
+}
+
+/// Gets the secret we share with a blinded path's creator, given the blinding point for our hop.
+pub(super) fn blinded_hop_shared_secret(node_secret: &SecretKey, blinding_point: &PublicKey) -> [u8; 32] {
+	let mut arr = [0; 32];
+	arr.copy_from_slice(&SharedSecret::new(blinding_point, node_secret)[..]);
+	arr
+}
+
+/// Gets the private key for our blinded node id, which the onion sent to us is encrypted to.
+pub(super) fn blinded_node_secret(node_secret: &SecretKey, blinded_shared_secret: &[u8; 32]) -> Result<SecretKey, secp256k1::Error> {
+	let mut res = node_secret.clone();
+	res.mul_assign(&gen_blinded_node_id_tweak_from_shared_secret(blinded_shared_secret)[..])?;
+	Ok(res)
+}
+
+/// Gets the blinding point to hand the next hop in a blinded path.
+pub(super) fn next_blinding_point<T: secp256k1::Verification>(secp_ctx: &Secp256k1<T>, blinding_point: &PublicKey, blinded_shared_secret: &[u8; 32]) -> Result<PublicKey, secp256k1::Error> {
+	let mut res = blinding_point.clone();
+	res.mul_assign(secp_ctx, &gen_blinding_factor(blinding_point, blinded_shared_secret)[..])?;
+	Ok(res)
+}
+
+/// Decrypts the data a blinded path's creator left for us. Fails if the data was not encrypted to
+/// us or is otherwise invalid.
+pub(super) fn decrypt_blinded_hop_data(blinded_shared_secret: &[u8; 32], encrypted_data: &[u8]) -> Result<msgs::EncryptedBlindedHopData, ()> {
+	if encrypted_data.len() < 16 {
+		return Err(());
+	}
+	let (rho, _) = gen_rho_mu_from_shared_secret(blinded_shared_secret);
+	let data_len = encrypted_data.len() - 16;
+	let mut plaintext = vec![0; data_len];
+	if !ChaCha20Poly1305RFC::new(&rho, &[0; 12], &[]).decrypt(&encrypted_data[..data_len], &mut plaintext, &encrypted_data[data_len..]) {
+		return Err(());
+	}
+	msgs::EncryptedBlindedHopData::read(&mut Cursor::new(&plaintext[..])).map_err(|_| ())
+}
+
+/// Builds the payloads for an onion which pays amount_msat into the given blinded path, with the
+/// last hop of path being its introduction node. Returns the hop data and the node_ids they are
+/// encrypted to, as well as the first-hop value_msat and CLTV value we should send.
+pub(super) fn build_blinded_onion_payloads(path: &Vec<RouteHop>, blinded_path: &BlindedPath, amount_msat: u64, final_cltv_expiry_delta: u32, starting_htlc_offset: u32) -> Result<(Vec<msgs::OnionHopData>, Vec<PublicKey>, u64, u32), APIError> {
+	let introduction_node = match path.last() {
+		Some(hop) => hop,
+		None => return Err(APIError::RouteError{err: "Path didn't go anywhere"}),
+	};
+	if blinded_path.blinded_hops.is_empty() {
+		return Err(APIError::RouteError{err: "Blinded path didn't go anywhere"});
+	}
+	if introduction_node.pubkey != blinded_path.introduction_node_id {
+		return Err(APIError::RouteError{err: "Path must end at the blinded path's introduction node"});
+	}
+	if !introduction_node.node_features.supports_variable_length_onion() {
+		return Err(APIError::RouteError{err: "Blinded path's introduction node doesn't support variable-length onions"});
+	}
+	match blinded_path.fee_msat(amount_msat) {
+		Some(fee_msat) if introduction_node.fee_msat == amount_msat + fee_msat => {},
+		_ => return Err(APIError::RouteError{err: "Path must pay the blinded path's fees to its introduction node"}),
+	}
+	if introduction_node.cltv_expiry_delta != blinded_path.cltv_expiry_delta as u32 + final_cltv_expiry_delta {
+		return Err(APIError::RouteError{err: "Path must add the blinded path's CLTV delta for its introduction node"});
+	}
+
+	// Build the onion up to the introduction node as normal, replacing its payload as it is the
+	// first hop within the blinded path.
+	let (mut payloads, htlc_msat, htlc_cltv) = build_onion_payloads(path, amount_msat, &None, &[], starting_htlc_offset, None)?;
+	payloads.pop();
+	let mut node_ids: Vec<PublicKey> = path.iter().map(|hop| hop.pubkey).collect();
+
+	let last_idx = blinded_path.blinded_hops.len() - 1;
+	for (idx, hop) in blinded_path.blinded_hops.iter().enumerate() {
+		let intro_node_blinding_point = if idx == 0 { Some(blinded_path.blinding_point) } else { None };
+		payloads.push(if idx == last_idx {
+			msgs::OnionHopData {
+				format: msgs::OnionHopDataFormat::BlindedReceive {
+					encrypted_data: hop.encrypted_payload.clone(),
+					intro_node_blinding_point,
+					total_msat: amount_msat,
+				},
+				amt_to_forward: amount_msat,
+				outgoing_cltv_value: starting_htlc_offset + final_cltv_expiry_delta,
+			}
+		} else {
+			msgs::OnionHopData {
+				format: msgs::OnionHopDataFormat::BlindedForward {
+					encrypted_data: hop.encrypted_payload.clone(),
+					intro_node_blinding_point,
+				},
+				amt_to_forward: 0,
+				outgoing_cltv_value: 0,
+			}
+		});
+		if idx != 0 {
+			node_ids.push(hop.blinded_node_id);
+		}
+	}
+	Ok((payloads, node_ids, htlc_msat, htlc_cltv))
+}
+
+/// Builds an onion which pays amount_msat into the given blinded path, with the last hop of path
+/// being its introduction node. Returns the onion along with the first-hop value_msat and CLTV
+/// value we should send.
+pub(super) fn build_blinded_onion<T: secp256k1::Signing>(secp_ctx: &Secp256k1<T>, path: &Vec<RouteHop>, blinded_path: &BlindedPath, amount_msat: u64, final_cltv_expiry_delta: u32, starting_htlc_offset: u32, session_priv: &SecretKey, prng_seed: [u8; 32], payment_hash: &PaymentHash) -> Result<(msgs::OnionPacket, u64, u32), APIError> {
+	let (payloads, node_ids, htlc_msat, htlc_cltv) = build_blinded_onion_payloads(path, blinded_path, amount_msat, final_cltv_expiry_delta, starting_htlc_offset)?;
+	let onion_keys = construct_onion_keys_for_pubkeys(secp_ctx, node_ids.iter(), node_ids.len(), session_priv)
+		.map_err(|_| APIError::RouteError{err: "Pubkey along hop was maliciously selected"})?;
+	if route_size_insane(&payloads) {
+		return Err(APIError::RouteError{err: "Route size too large considering onion data"});
+	}
+	Ok((construct_onion_packet(payloads, onion_keys, prng_seed, payment_hash), htlc_msat, htlc_cltv))
+}
+
 /// Length of the onion data packet. Before TLV-based onions this was 20 65-byte hops, though now
 /// the hops can be of variable length.
 pub(crate) const ONION_DATA_LEN: usize = 20*65;
 
+/// Length of the onion data packet within a trampoline onion. This is much smaller than
+/// ONION_DATA_LEN as the trampoline onion must fit within the payload of the outer onion.
+pub(crate) const TRAMPOLINE_ONION_DATA_LEN: usize = 400;
+
 #[inline]
-fn shift_arr_right(arr: &mut [u8; ONION_DATA_LEN], amt: usize) {
-	for i in (amt..ONION_DATA_LEN).rev() {
+fn shift_arr_right(arr: &mut [u8], amt: usize) {
+	for i in (amt..arr.len()).rev() {
 		arr[i] = arr[i-amt];
 	}
 	for i in 0..amt {
@@ -181,13 +523,19 @@ fn shift_arr_right(arr: &mut [u8; ONION_DATA_LEN], amt: usize) {
 }
 
 pub(super) fn route_size_insane(payloads: &Vec<msgs::OnionHopData>) -> bool {
+	payloads_size_insane(payloads, ONION_DATA_LEN)
+}
+
+fn payloads_size_insane(payloads: &Vec<msgs::OnionHopData>, onion_data_len: usize) -> bool {
 	let mut len = 0;
 	for payload in payloads.iter() {
 		let mut payload_len = LengthCalculatingWriter(0);
 		payload.write(&mut payload_len).expect("Failed to calculate length");
-		assert!(payload_len.0 + 32 < ONION_DATA_LEN);
+		if payload_len.0 + 32 >= onion_data_len {
+			return true;
+		}
 		len += payload_len.0 + 32;
-		if len > ONION_DATA_LEN {
+		if len > onion_data_len {
 			return true;
 		}
 	}
@@ -217,7 +565,22 @@ pub(super) fn construct_onion_packet_bogus_hopdata<HD: Writeable>(payloads: Vec<
 }
 
 /// panics if route_size_insane(paylods)
-fn construct_onion_packet_with_init_noise<HD: Writeable>(mut payloads: Vec<HD>, onion_keys: Vec<OnionKeys>, mut packet_data: [u8; ONION_DATA_LEN], associated_data: &PaymentHash) -> msgs::OnionPacket {
+fn construct_onion_packet_with_init_noise<HD: Writeable>(payloads: Vec<HD>, onion_keys: Vec<OnionKeys>, mut packet_data: [u8; ONION_DATA_LEN], associated_data: &PaymentHash) -> msgs::OnionPacket {
+	let hmac_res = encrypt_onion_packet_data(payloads, &onion_keys, &mut packet_data, associated_data);
+
+	msgs::OnionPacket {
+		version: 0,
+		public_key: Ok(onion_keys.first().unwrap().ephemeral_pubkey),
+		hop_data: packet_data,
+		hmac: hmac_res,
+	}
+}
+
+/// Encrypts the given payloads into packet_data (which must be pre-filled with noise), returning
+/// the HMAC for the first hop. Used for both the outer onion and trampoline onions.
+/// panics if the payloads don't fit in packet_data
+fn encrypt_onion_packet_data<HD: Writeable>(mut payloads: Vec<HD>, onion_keys: &Vec<OnionKeys>, packet_data: &mut [u8], associated_data: &PaymentHash) -> [u8; 32] {
+	let onion_data_len = packet_data.len();
 	let filler = {
 		const ONION_HOP_DATA_LEN: usize = 65; // We may decrease this eventually after TLV is common
 		let mut res = Vec::with_capacity(ONION_HOP_DATA_LEN * (payloads.len() - 1));
@@ -227,7 +590,7 @@ fn construct_onion_packet_with_init_noise<HD: Writeable>(mut payloads: Vec<HD>,
 			if i == payloads.len() - 1 { break; }
 
 			let mut chacha = ChaCha20::new(&keys.rho, &[0u8; 8]);
-			for _ in 0..(ONION_DATA_LEN - pos) { // TODO: Batch this.
+			for _ in 0..(onion_data_len - pos) { // TODO: Batch this.
 				let mut dummy = [0; 1];
 				chacha.process_in_place(&mut dummy); // We don't have a seek function :(
 			}
@@ -235,7 +598,7 @@ fn construct_onion_packet_with_init_noise<HD: Writeable>(mut payloads: Vec<HD>,
 			let mut payload_len = LengthCalculatingWriter(0);
 			payload.write(&mut payload_len).expect("Failed to calculate length");
 			pos += payload_len.0 + 32;
-			assert!(pos <= ONION_DATA_LEN);
+			assert!(pos <= onion_data_len);
 
 			res.resize(pos, 0u8);
 			chacha.process_in_place(&mut res);
@@ -247,29 +610,24 @@ fn construct_onion_packet_with_init_noise<HD: Writeable>(mut payloads: Vec<HD>,
 	for (i, (payload, keys)) in payloads.iter_mut().zip(onion_keys.iter()).rev().enumerate() {
 		let mut payload_len = LengthCalculatingWriter(0);
 		payload.write(&mut payload_len).expect("Failed to calculate length");
-		shift_arr_right(&mut packet_data, payload_len.0 + 32);
+		shift_arr_right(packet_data, payload_len.0 + 32);
 		packet_data[0..payload_len.0].copy_from_slice(&payload.encode()[..]);
 		packet_data[payload_len.0..(payload_len.0 + 32)].copy_from_slice(&hmac_res);
 
 		let mut chacha = ChaCha20::new(&keys.rho, &[0u8; 8]);
-		chacha.process_in_place(&mut packet_data);
+		chacha.process_in_place(packet_data);
 
 		if i == 0 {
-			packet_data[ONION_DATA_LEN - filler.len()..ONION_DATA_LEN].copy_from_slice(&filler[..]);
+			packet_data[onion_data_len - filler.len()..onion_data_len].copy_from_slice(&filler[..]);
 		}
 
 		let mut hmac = HmacEngine::<Sha256>::new(&keys.mu);
-		hmac.input(&packet_data);
+		hmac.input(packet_data);
 		hmac.input(&associated_data.0[..]);
 		hmac_res = Hmac::from_engine(hmac).into_inner();
 	}
 
-	msgs::OnionPacket {
-		version: 0,
-		public_key: Ok(onion_keys.first().unwrap().ephemeral_pubkey),
-		hop_data: packet_data,
-		hmac: hmac_res,
-	}
+	hmac_res
 }
 
 /// Encrypts a failure packet. raw_packet can either be a
@@ -328,7 +686,7 @@ pub(super) fn build_first_hop_failure_packet(shared_secret: &[u8], failure_type:
 /// Returns update, a boolean indicating that the payment itself failed, and the error code.
 #[inline]
 pub(super) fn process_onion_failure<T: secp256k1::Signing, L: Deref>(secp_ctx: &Secp256k1<T>, logger: &L, htlc_source: &HTLCSource, mut packet_decrypted: Vec<u8>) -> (Option<msgs::HTLCFailChannelUpdate>, bool, Option<u16>, Option<Vec<u8>>) where L::Target: Logger {
-	if let &HTLCSource::OutboundRoute { ref path, ref session_priv, ref first_hop_htlc_msat } = htlc_source {
+	if let &HTLCSource::OutboundRoute { ref path, ref session_priv, ref first_hop_htlc_msat, .. } = htlc_source {
 		let mut res = None;
 		let mut htlc_msat = *first_hop_htlc_msat;
 		let mut error_code_ret = None;
@@ -377,7 +735,10 @@ pub(super) fn process_onion_failure<T: secp256k1::Signing, L: Deref>(secp_ctx: &
 							15|16|17|18|19 => true,
 							_ => false,
 						} && is_from_final_node) // PERM bit observed below even this error is from the intermediate nodes
-						|| error_code == 21; // Special case error 21 as the Route object is bogus, TODO: Maybe fail the node if the CLTV was reasonable?
+						|| error_code == 21 // Special case error 21 as the Route object is bogus, TODO: Maybe fail the node if the CLTV was reasonable?
+						// invalid_onion_blinding from the last hop we know of (the introduction node) tells
+						// us something within the blinded path failed, not the channel we used to reach it.
+						|| (error_code == 0x8000|PERM|24 && is_from_final_node);
 
 						let mut fail_channel_update = None;
 
@@ -689,4 +1050,47 @@ mod tests {
 		// anyway...
 		assert_eq!(packet.encode(), hex::decode("0002eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619e5f14350c2a76fc232b5e46d421e9615471ab9e0bc887beff8c95fdb878f7b3a71a060daf367132b378b3a3883c0e2c0e026b8900b2b5cdbc784e1a3bb913f88a9c50f7d61ab590531cf08000178a333a347f8b4072ed056f820f77774345e183a342ec4729f3d84accf515e88adddb85ecc08daba68404bae9a8e8d7178977d7094a1ae549f89338c0777551f874159eb42d3a59fb9285ad4e24883f27de23942ec966611e99bee1cee503455be9e8e642cef6cef7b9864130f692283f8a973d47a8f1c1726b6e59969385975c766e35737c8d76388b64f748ee7943ffb0e2ee45c57a1abc40762ae598723d21bd184e2b338f68ebff47219357bd19cd7e01e2337b806ef4d717888e129e59cd3dc31e6201ccb2fd6d7499836f37a993262468bcb3a4dcd03a22818aca49c6b7b9b8e9e870045631d8e039b066ff86e0d1b7291f71cefa7264c70404a8e538b566c17ccc5feab231401e6c08a01bd5edfc1aa8e3e533b96e82d1f91118d508924b923531929aea889fcdf050597c681185f336b1da63b0939aa2b7c50b21b5eb7b6ad66c81fab98a3cdf73f658149e7e9ced4edde5d38c9b8f92e16f6b4ab13d7fca6a0e4ecc9f9de611a90da6e99c39551094c56e3196f282c5dffd9fc4b2fc12f3bca8e6fe47eb45fbdd3be21a8a8d200797eae3c9a0497132f92410d804977408494dff49dd3d8bce248e0b74fd9e6f0f7102c25ddfa02bd9ad9f746abbfa337ef811d5345a9e16b60de1767b209645ba40bd1f9a5f75bc04feca9b27c5554be4fe83fac2cb83aa447a817bb85ae966c68b420063833fada375e2f515965e687a45699632902672c654d1d18d7bcbf55e8fa57f63f2da449f8e1e606e8722df081e5f193fc4179feb99ad22819afdeef211f7c54afdba92aeef0c00b7bc2b65a4813c01f907a8377585708f2d4c940a25328e585714c8ded0a9a4d7a6de1027c1cb7a0198cd3db68b58c0704dfd0cfbe624e9cd18cc0ae5d96697bb476708b9ee0403d211e64e0d5a7683a7a9a140c02f0ff1c6e67a302941b4052bdea8a63e70a3ad62c5b89c698f1fd3c7685cb49705096cad702d02d93bcb1c27a409f4c9bddec001205ca4a2740f19b50900be81c7e847f1a863deea8d35701f1355cad8db57b1d4eb2ab4e29587734785abfb46ddede71928213d7d089dfdeda052827f459f1688cc0935bd47e7bcec27427c8376dcce7e22699567c0d145f8a7db33f6758815f1f15f9f7a9760dec4f34ae095edda4c64e9735bdd029c4e32c2ee31ba47ec5e6bdb97813d52dbd15b4e0b7a2c7f790ae64104d99f38c127f0a093288fa34144adb16b8968d4fa7656fcec99de8503dd46d3b03620a71c7cd085364abd30dccf7fbda25a1cdc102600149c9af1c97aa0372cd2e1909f28ac5c686f432b310e79528c9b8b9e8f314c1e74621ce6308ad2278b81d460892e0d9dd38b7c76d58be6dfd10ae7583ee1e7ef5b3f6f78dc60af0950df1b00cc55b6d178ba2e476bea0eaeef49323b83f05804159e7aef4eed4cc60dd07be76f067dfd0bcfb0b806b69ba921336a20c43c832d0cab8fa3ddeb29e3bf07b0d98a112eb07802756235a49d44a8b82a950d84e95e01971f0e106ccb337f07384e21620e0ad39e16ed9edca123226cf55ac44f449eeb53e38a7f27d101806e4823e4efcc887414240ee6826c4a5cb1c6443ad36ebf905a435c1d9054e54173911b17b5b40f60b3d9fd5f12eac54ca1e20191f5f18544d5fd3d665e9bcef96fb44b76110aa64d9db4c86c9513cbdad546538e8aec521fbe83ceac5e74a15629f1ed0b870a1d0d1e5680b6d6100d1bd3f3b9043bd35b8919c4088f1949b8be89e4701eb870f8ed64fafa446c78df3ea").unwrap());
 	}
+	#[test]
+	fn blinded_path_hops() {
+		// Build a blinded path over three nodes and check that each can find its blinded node id and
+		// read its data, then hand the next hop its blinding point.
+		let secp_ctx = Secp256k1::new();
+		let node_secrets: Vec<SecretKey> = (1..4u8).map(|i| SecretKey::from_slice(&[i; 32]).unwrap()).collect();
+		let relay = msgs::PaymentRelay { cltv_expiry_delta: 144, fee_proportional_millionths: 100, fee_base_msat: 1000 };
+		let hops: Vec<(PublicKey, msgs::EncryptedBlindedHopData)> = node_secrets.iter().enumerate().map(|(idx, secret)| {
+			let data = if idx == node_secrets.len() - 1 {
+				msgs::EncryptedBlindedHopData { short_channel_id: None, path_id: Some([42; 32]), payment_relay: None }
+			} else {
+				msgs::EncryptedBlindedHopData { short_channel_id: Some(idx as u64), path_id: None, payment_relay: Some(relay.clone()) }
+			};
+			(PublicKey::from_secret_key(&secp_ctx, secret), data)
+		}).collect();
+
+		let session_priv = SecretKey::from_slice(&[0x41; 32]).unwrap();
+		let (mut blinding_point, blinded_hops) = super::construct_blinded_hops(&secp_ctx, &hops, &session_priv).unwrap();
+		assert_eq!(blinding_point, PublicKey::from_secret_key(&secp_ctx, &session_priv));
+		assert_eq!(blinded_hops.len(), 3);
+
+		for (idx, (secret, blinded_hop)) in node_secrets.iter().zip(blinded_hops.iter()).enumerate() {
+			let ss = super::blinded_hop_shared_secret(secret, &blinding_point);
+			let blinded_secret = super::blinded_node_secret(secret, &ss).unwrap();
+			assert_eq!(PublicKey::from_secret_key(&secp_ctx, &blinded_secret), blinded_hop.blinded_node_id);
+			assert_ne!(PublicKey::from_secret_key(&secp_ctx, secret), blinded_hop.blinded_node_id);
+
+			let data = super::decrypt_blinded_hop_data(&ss, &blinded_hop.encrypted_payload).unwrap();
+			if idx == 2 {
+				assert_eq!(data.path_id, Some([42; 32]));
+				assert!(data.short_channel_id.is_none());
+			} else {
+				assert_eq!(data.short_channel_id, Some(idx as u64));
+				assert_eq!(data.payment_relay.unwrap().fee_base_msat, 1000);
+			}
+
+			// Another node can't read our data
+			let other_ss = super::blinded_hop_shared_secret(&node_secrets[(idx + 1) % 3], &blinding_point);
+			assert!(super::decrypt_blinded_hop_data(&other_ss, &blinded_hop.encrypted_payload).is_err());
+
+			blinding_point = super::next_blinding_point(&secp_ctx, &blinding_point, &ss).unwrap();
+		}
+	}
 }
@@ -0,0 +1,327 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Keeps us connected to the peers we have channels with, reconnecting to them with exponential
+//! backoff when the connection drops or can't be established.
+//!
+//! [`PeerConnectionManager`] doesn't do any I/O itself, it only decides which peers to connect to
+//! and when, so it works with any networking stack driving a [`PeerManager`]. The stack has to
+//! regularly:
+//!  * feed it the peers we have channels with, from [`ChannelManager::list_channels`], with
+//!    [`set_channel_peers`],
+//!  * get the peers to connect to, along with their known addresses, from
+//!    [`get_peers_to_connect`], passing it the peers [`PeerManager::get_peer_node_ids`] reports as
+//!    connected,
+//!  * try to connect to each of them, and call [`connection_closed`] once the connection attempt
+//!    failed or, if it succeeded, once the connection was closed.
+//!
+//! [`PeerConnectionManager`]: struct.PeerConnectionManager.html
+//! [`PeerManager`]: ../peer_handler/struct.PeerManager.html
+//! [`PeerManager::get_peer_node_ids`]: ../peer_handler/struct.PeerManager.html#method.get_peer_node_ids
+//! [`ChannelManager::list_channels`]: ../channelmanager/struct.ChannelManager.html#method.list_channels
+//! [`set_channel_peers`]: struct.PeerConnectionManager.html#method.set_channel_peers
+//! [`get_peers_to_connect`]: struct.PeerConnectionManager.html#method.get_peers_to_connect
+//! [`connection_closed`]: struct.PeerConnectionManager.html#method.connection_closed
+
+use bitcoin::secp256k1::key::PublicKey;
+
+use chain;
+use ln::channelmanager::ChannelDetails;
+use ln::msgs::NetAddress;
+use routing::network_graph::{NetGraphMsgHandler, NetworkGraph};
+use util::logger::Logger;
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long we wait before reconnecting to a peer after a disconnection or a first failed
+/// connection attempt.
+pub const INITIAL_RECONNECT_DELAY_SECS: u64 = 1;
+
+/// The longest we wait between two connection attempts to a peer, the delay doubling after each
+/// failed attempt until it reaches it.
+pub const MAX_RECONNECT_DELAY_SECS: u64 = 60 * 60;
+
+/// Provides the addresses we may reach peers at, in the order we should try them.
+///
+/// Implemented for the [`NetworkGraph`], which knows the addresses of the nodes it received a
+/// node_announcement from, but a user-supplied store may be needed for peers which don't announce
+/// themselves.
+///
+/// [`NetworkGraph`]: ../../routing/network_graph/struct.NetworkGraph.html
+pub trait PeerAddressSource: Send + Sync {
+	/// Returns the addresses we may reach the given peer at, which may be empty if unknown.
+	fn get_peer_addresses(&self, node_id: &PublicKey) -> Vec<NetAddress>;
+}
+
+impl PeerAddressSource for NetworkGraph {
+	fn get_peer_addresses(&self, node_id: &PublicKey) -> Vec<NetAddress> {
+		self.get_addresses(node_id).cloned().unwrap_or(Vec::new())
+	}
+}
+
+impl<C: Deref + Sync + Send, L: Deref + Sync + Send> PeerAddressSource for NetGraphMsgHandler<C, L> where C::Target: chain::Access, L::Target: Logger {
+	fn get_peer_addresses(&self, node_id: &PublicKey) -> Vec<NetAddress> {
+		self.network_graph.read().unwrap().get_peer_addresses(node_id)
+	}
+}
+
+/// The state of our connection to a peer managed by a [`PeerConnectionManager`].
+///
+/// [`PeerConnectionManager`]: struct.PeerConnectionManager.html
+#[derive(Clone, Debug, PartialEq)]
+pub enum PeerConnectionState {
+	/// We are connected to the peer, whichever side initiated the connection.
+	Connected,
+	/// We are trying to connect to the peer.
+	Connecting,
+	/// We aren't connected to the peer, and will try to connect to it again at the given time.
+	Disconnected {
+		/// The number of connection attempts which failed since we were last connected.
+		failed_attempts: u32,
+		/// When we will next try to connect to the peer.
+		next_attempt: Instant,
+	},
+}
+
+struct PeerConnection {
+	connected: bool,
+	connecting: bool,
+	failed_attempts: u32,
+	/// When we should next try to connect, or None to do it as soon as possible.
+	next_attempt: Option<Instant>,
+}
+
+/// Keeps track of the peers we should stay connected to and of our connections to them, telling
+/// the networking stack when to (re)connect to each. See the [module-level documentation] for how
+/// to drive it.
+///
+/// [module-level documentation]: index.html
+pub struct PeerConnectionManager<A: Deref, L: Deref> where A::Target: PeerAddressSource, L::Target: Logger {
+	address_source: A,
+	peers: Mutex<HashMap<PublicKey, PeerConnection>>,
+	logger: L,
+}
+
+impl<A: Deref, L: Deref> PeerConnectionManager<A, L> where A::Target: PeerAddressSource, L::Target: Logger {
+	/// Constructs a new PeerConnectionManager, looking up peer addresses in `address_source`.
+	pub fn new(address_source: A, logger: L) -> Self {
+		PeerConnectionManager {
+			address_source,
+			peers: Mutex::new(HashMap::new()),
+			logger,
+		}
+	}
+
+	/// Sets the peers we should stay connected to as the counterparties of the given channels,
+	/// which should be the result of [`ChannelManager::list_channels`].
+	///
+	/// We connect to new peers as soon as possible, and stop reconnecting to the peers we no longer
+	/// have channels with, without disconnecting from them.
+	///
+	/// [`ChannelManager::list_channels`]: ../channelmanager/struct.ChannelManager.html#method.list_channels
+	pub fn set_channel_peers(&self, channels: &[ChannelDetails]) {
+		let channel_peers: HashSet<PublicKey> = channels.iter().map(|chan| chan.remote_network_id).collect();
+		let mut peers = self.peers.lock().unwrap();
+		peers.retain(|node_id, _| channel_peers.contains(node_id));
+		for node_id in channel_peers {
+			peers.entry(node_id).or_insert(PeerConnection {
+				connected: false,
+				connecting: false,
+				failed_attempts: 0,
+				next_attempt: None,
+			});
+		}
+	}
+
+	/// Returns the peers we should try to connect to now, along with the addresses we know them
+	/// at, and marks them as connecting until [`connection_closed`] is called for them.
+	///
+	/// `connected_peers` should be the peers we are connected to, ie the result of
+	/// [`PeerManager::get_peer_node_ids`], so that we notice disconnections even when the
+	/// connection was initiated by the peer.
+	///
+	/// [`connection_closed`]: #method.connection_closed
+	/// [`PeerManager::get_peer_node_ids`]: ../peer_handler/struct.PeerManager.html#method.get_peer_node_ids
+	pub fn get_peers_to_connect(&self, connected_peers: &[PublicKey], now: Instant) -> Vec<(PublicKey, Vec<NetAddress>)> {
+		let mut peers_to_connect = Vec::new();
+		let mut peers = self.peers.lock().unwrap();
+		for (node_id, peer) in peers.iter_mut() {
+			let connected = connected_peers.contains(node_id);
+			if connected {
+				peer.failed_attempts = 0;
+			} else if peer.connected {
+				log_trace!(self.logger, "Peer {} disconnected, reconnecting in {}s", log_pubkey!(node_id), INITIAL_RECONNECT_DELAY_SECS);
+				peer.next_attempt = Some(now + Duration::from_secs(INITIAL_RECONNECT_DELAY_SECS));
+			}
+			peer.connected = connected;
+			if peer.connected || peer.connecting {
+				continue;
+			}
+			if let Some(next_attempt) = peer.next_attempt {
+				if next_attempt > now { continue; }
+			}
+
+			let addresses = self.address_source.get_peer_addresses(node_id);
+			if addresses.is_empty() {
+				Self::schedule_next_attempt(peer, now);
+				log_trace!(self.logger, "No known address for peer {}, retrying in {:?}", log_pubkey!(node_id), peer.next_attempt.unwrap() - now);
+				continue;
+			}
+			log_trace!(self.logger, "Connecting to peer {}", log_pubkey!(node_id));
+			peer.connecting = true;
+			peers_to_connect.push((*node_id, addresses));
+		}
+		peers_to_connect
+	}
+
+	/// Indicates that a connection attempt to the given peer returned by [`get_peers_to_connect`]
+	/// failed, or, if it succeeded, that the connection was closed.
+	///
+	/// We then reconnect after a delay, which doubles after each failed attempt, starting from
+	/// [`INITIAL_RECONNECT_DELAY_SECS`] up to [`MAX_RECONNECT_DELAY_SECS`], and is reset once
+	/// we're connected.
+	///
+	/// [`get_peers_to_connect`]: #method.get_peers_to_connect
+	/// [`INITIAL_RECONNECT_DELAY_SECS`]: constant.INITIAL_RECONNECT_DELAY_SECS.html
+	/// [`MAX_RECONNECT_DELAY_SECS`]: constant.MAX_RECONNECT_DELAY_SECS.html
+	pub fn connection_closed(&self, node_id: &PublicKey, now: Instant) {
+		let mut peers = self.peers.lock().unwrap();
+		let peer = match peers.get_mut(node_id) {
+			Some(peer) => peer,
+			None => return,
+		};
+		if !peer.connecting { return; }
+		peer.connecting = false;
+		if peer.connected {
+			log_trace!(self.logger, "Connection to peer {} closed, reconnecting in {}s", log_pubkey!(node_id), INITIAL_RECONNECT_DELAY_SECS);
+			peer.connected = false;
+			peer.failed_attempts = 0;
+			peer.next_attempt = Some(now + Duration::from_secs(INITIAL_RECONNECT_DELAY_SECS));
+		} else {
+			Self::schedule_next_attempt(peer, now);
+			log_trace!(self.logger, "Failed to connect to peer {}, retrying in {:?}", log_pubkey!(node_id), peer.next_attempt.unwrap() - now);
+		}
+	}
+
+	/// Returns the state of our connection to each of the peers we should stay connected to, as of
+	/// the last call to [`get_peers_to_connect`].
+	///
+	/// [`get_peers_to_connect`]: #method.get_peers_to_connect
+	pub fn get_peer_states(&self) -> Vec<(PublicKey, PeerConnectionState)> {
+		self.peers.lock().unwrap().iter().map(|(node_id, peer)| {
+			let state = if peer.connected {
+				PeerConnectionState::Connected
+			} else if peer.connecting {
+				PeerConnectionState::Connecting
+			} else {
+				PeerConnectionState::Disconnected {
+					failed_attempts: peer.failed_attempts,
+					next_attempt: peer.next_attempt.unwrap_or(Instant::now()),
+				}
+			};
+			(*node_id, state)
+		}).collect()
+	}
+
+	fn schedule_next_attempt(peer: &mut PeerConnection, now: Instant) {
+		peer.failed_attempts = peer.failed_attempts.saturating_add(1);
+		// Once the shift gets us past MAX_RECONNECT_DELAY_SECS, we're capped anyway.
+		let shift = cmp::min(peer.failed_attempts - 1, 31);
+		let delay_secs = INITIAL_RECONNECT_DELAY_SECS.saturating_mul(1 << shift);
+		peer.next_attempt = Some(now + Duration::from_secs(cmp::min(delay_secs, MAX_RECONNECT_DELAY_SECS)));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ln::channelmanager::ChannelDetails;
+	use ln::features::InitFeatures;
+	use ln::msgs::NetAddress;
+	use ln::peer_connection_manager::{PeerConnectionManager, PeerConnectionState, INITIAL_RECONNECT_DELAY_SECS, MAX_RECONNECT_DELAY_SECS};
+	use util::test_utils;
+
+	use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+	use bitcoin::secp256k1::Secp256k1;
+
+	use std::time::{Duration, Instant};
+
+	fn get_channel(node_id: PublicKey) -> ChannelDetails {
+		ChannelDetails {
+			channel_id: [0; 32],
+			short_channel_id: None,
+			remote_network_id: node_id,
+			counterparty_features: InitFeatures::known(),
+			channel_value_satoshis: 0,
+			user_id: 0,
+			outbound_capacity_msat: 0,
+			inbound_capacity_msat: 0,
+			is_live: false,
+		}
+	}
+
+	#[test]
+	fn test_reconnection_backoff() {
+		let secp_ctx = Secp256k1::new();
+		let node_a = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[1; 32]).unwrap());
+		let node_b = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		let address = NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 };
+		let address_source = test_utils::TestPeerAddressSource::new();
+		address_source.addresses.lock().unwrap().insert(node_a, vec![address.clone()]);
+		let logger = test_utils::TestLogger::new();
+		let initial_delay = Duration::from_secs(INITIAL_RECONNECT_DELAY_SECS);
+		let manager = PeerConnectionManager::new(&address_source, &logger);
+
+		// We connect to new channel peers right away, once we know their address.
+		let mut now = Instant::now();
+		manager.set_channel_peers(&[get_channel(node_a), get_channel(node_a), get_channel(node_b)]);
+		assert_eq!(manager.get_peers_to_connect(&[], now), vec![(node_a, vec![address.clone()])]);
+		assert!(manager.get_peers_to_connect(&[], now).is_empty());
+		let mut states = manager.get_peer_states();
+		states.sort_by_key(|&(node_id, _)| node_id != node_a);
+		assert_eq!(states, vec![(node_a, PeerConnectionState::Connecting),
+			(node_b, PeerConnectionState::Disconnected { failed_attempts: 1, next_attempt: now + initial_delay })]);
+		manager.set_channel_peers(&[get_channel(node_a)]);
+
+		// Failed attempts are retried with exponential backoff.
+		let mut expected_delay = initial_delay;
+		for failed_attempts in 1..20 {
+			manager.connection_closed(&node_a, now);
+			assert_eq!(manager.get_peer_states(), vec![(node_a, PeerConnectionState::Disconnected { failed_attempts, next_attempt: now + expected_delay })]);
+			assert!(manager.get_peers_to_connect(&[], now + expected_delay - Duration::from_millis(1)).is_empty());
+			now += expected_delay;
+			assert_eq!(manager.get_peers_to_connect(&[], now).len(), 1);
+			expected_delay = std::cmp::min(expected_delay * 2, Duration::from_secs(MAX_RECONNECT_DELAY_SECS));
+		}
+		assert_eq!(expected_delay, Duration::from_secs(MAX_RECONNECT_DELAY_SECS));
+
+		// Once connected, the backoff is reset, and we reconnect shortly after a disconnection.
+		assert!(manager.get_peers_to_connect(&[node_a], now).is_empty());
+		assert_eq!(manager.get_peer_states(), vec![(node_a, PeerConnectionState::Connected)]);
+		manager.connection_closed(&node_a, now);
+		assert_eq!(manager.get_peer_states(), vec![(node_a, PeerConnectionState::Disconnected { failed_attempts: 0, next_attempt: now + initial_delay })]);
+		now += initial_delay;
+		assert_eq!(manager.get_peers_to_connect(&[], now).len(), 1);
+
+		// Connections the peer initiated are tracked too.
+		manager.connection_closed(&node_a, now);
+		now += Duration::from_secs(2);
+		assert!(manager.get_peers_to_connect(&[node_a], now).is_empty());
+		assert!(manager.get_peers_to_connect(&[], now).is_empty());
+		assert_eq!(manager.get_peer_states(), vec![(node_a, PeerConnectionState::Disconnected { failed_attempts: 0, next_attempt: now + initial_delay })]);
+
+		// We stop reconnecting to peers we no longer have channels with.
+		manager.set_channel_peers(&[]);
+		assert!(manager.get_peers_to_connect(&[], now + initial_delay).is_empty());
+		assert!(manager.get_peer_states().is_empty());
+	}
+}
@@ -16,6 +16,7 @@
 //! they should handle, and encoding/sending response messages.
 
 use bitcoin::secp256k1::key::{SecretKey,PublicKey};
+use bitcoin::hash_types::BlockHash;
 
 use ln::features::InitFeatures;
 use ln::msgs;
@@ -30,7 +31,7 @@ use util::events::{MessageSendEvent, MessageSendEventsProvider};
 use util::logger::Logger;
 use routing::network_graph::NetGraphMsgHandler;
 
-use std::collections::{HashMap,hash_map,HashSet,LinkedList};
+use std::collections::{HashMap,hash_map,HashSet,LinkedList,VecDeque};
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::{cmp,error,hash,fmt};
@@ -113,12 +114,99 @@ impl error::Error for PeerHandleError {
 	}
 }
 
+/// Limits on the resources peers may use, protecting a PeerManager against peers (or anyone who
+/// can open connections to us) trying to exhaust our memory or connection slots.
+#[derive(Clone, Copy, Debug)]
+pub struct PeerManagerLimits {
+	/// The maximum number of peers we have no channels with. Once we have that many, we close new
+	/// inbound connections from peers we have no channels with as soon as they completed the noise
+	/// handshake (and we thus know who they are). Whether we have channels with a peer is only
+	/// checked once, when its connection completes the handshake.
+	///
+	/// Default value: 250.
+	pub max_peers_without_channels: usize,
+	/// The maximum number of inbound connections from a single IP address, if its address was
+	/// provided in [`PeerManager::new_inbound_connection_with_address`]. Connections from loopback
+	/// addresses, which is where all connections to a Tor hidden service come from, aren't limited.
+	///
+	/// Default value: 10.
+	///
+	/// [`PeerManager::new_inbound_connection_with_address`]: struct.PeerManager.html#method.new_inbound_connection_with_address
+	pub max_inbound_connections_per_ip: usize,
+	/// The maximum number of bytes we buffer for a peer which doesn't read the data we send it.
+	/// Past half of this, we drop the gossip we would otherwise relay to the peer, and past this,
+	/// we drop the gossip we have yet to send it, disconnecting it if that isn't enough.
+	///
+	/// Default value: 1 MiB.
+	pub max_outbound_buffer_bytes: usize,
+	/// The number of calls to [`PeerManager::timer_tick_occured`] within which a peer has to
+	/// complete the noise handshake and send us its Init message, after which we disconnect it.
+	///
+	/// Default value: 2.
+	///
+	/// [`PeerManager::timer_tick_occured`]: struct.PeerManager.html#method.timer_tick_occured
+	pub max_handshake_timer_ticks: u8,
+}
+
+impl Default for PeerManagerLimits {
+	fn default() -> Self {
+		PeerManagerLimits {
+			max_peers_without_channels: 250,
+			max_inbound_connections_per_ip: 10,
+			max_outbound_buffer_bytes: 1024 * 1024,
+			max_handshake_timer_ticks: 2,
+		}
+	}
+}
+
+/// Counts what a PeerManager refused or dropped to stay within its [`PeerManagerLimits`].
+///
+/// [`PeerManagerLimits`]: struct.PeerManagerLimits.html
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeerManagerStats {
+	/// The inbound connections we closed because we had too many peers without channels.
+	pub peers_refused_without_channels: u64,
+	/// The inbound connections we refused because we had too many connections from their IP.
+	pub connections_refused_per_ip: u64,
+	/// The gossip messages we did not relay to a peer because its outbound buffer was full or we
+	/// relayed too much gossip to it recently.
+	pub gossip_messages_dropped: u64,
+	/// The peers we disconnected because they didn't read the data we sent them.
+	pub peers_disconnected_outbound_buffer_full: u64,
+	/// The peers we disconnected because they didn't complete the handshake in time.
+	pub peers_disconnected_handshake_timeout: u64,
+}
+
+/// When the outbound buffer has this many messages, we stop reading bytes from the peer until
+/// we have fewer than this many messages in the outbound buffer again. We also only fill the
+/// buffer with our routing table up to this size when sending it to the peer.
+const OUTBOUND_BUFFER_LIMIT_READ_PAUSE: usize = 10;
+/// When the outbound buffer has this many messages, we drop the gossip we would otherwise relay
+/// to the peer, so that a peer which doesn't read its data can't make us buffer gossip forever.
+const OUTBOUND_BUFFER_LIMIT_DROP_GOSSIP: usize = 20;
+/// The maximum number of gossip messages we relay to a single peer between two calls to
+/// PeerManager::timer_tick_occured.
+const MAX_GOSSIP_RELAY_PER_TICK: usize = 1000;
+/// The number of gossip messages a peer sent us or we relayed to it which we remember, to avoid
+/// relaying them to it again.
+const MAX_KNOWN_GOSSIP_PER_PEER: usize = 4096;
+
 enum InitSyncTracker{
 	NoSyncRequested,
 	ChannelsSyncing(u64),
 	NodesSyncing(PublicKey),
 }
 
+/// Tracks our reply to a query_short_channel_ids from a peer, which we stream to it as its
+/// outbound buffer drains, like our initial routing table sync.
+struct ShortIdsReplyTracker {
+	chain_hash: BlockHash,
+	/// The queried short_channel_ids we have yet to send the gossip for.
+	short_channel_ids: VecDeque<u64>,
+	/// The nodes whose node_announcement we already sent as part of this reply.
+	sent_node_ids: HashSet<PublicKey>,
+}
+
 struct Peer {
 	channel_encryptor: PeerChannelEncryptor,
 	outbound: bool,
@@ -128,17 +216,51 @@ struct Peer {
 	pending_outbound_buffer: LinkedList<Vec<u8>>,
 	pending_outbound_buffer_first_msg_offset: usize,
 	awaiting_write_event: bool,
+	/// Gossip messages we're relaying to the peer, encoded but not yet encrypted. We only move them
+	/// to pending_outbound_buffer as it drains, so that we can still drop them if the peer doesn't
+	/// read the data we send it.
+	pending_gossip_buffer: VecDeque<Vec<u8>>,
+	/// Set once we refused to queue a message for the peer as its outbound buffer was full, after
+	/// which we disconnect it.
+	outbound_buffer_full: bool,
 
 	pending_read_buffer: Vec<u8>,
 	pending_read_buffer_pos: usize,
 	pending_read_is_header: bool,
 
 	sync_status: InitSyncTracker,
+	/// The gossip_timestamp_filter the peer sent us, if any, restricting the gossip we send it.
+	gossip_timestamp_filter: Option<msgs::GossipTimestampFilter>,
+	/// Whether the peer's gossip_timestamp_filter already made us send it our routing table. We
+	/// only do so once per connection, later filters only apply to the gossip we relay.
+	filtered_sync_started: bool,
+	/// Our reply to the peer's outstanding query_short_channel_ids, if any.
+	short_ids_reply: Option<ShortIdsReplyTracker>,
+	/// The hashes of the gossip messages the peer is known to have, oldest first in the VecDeque.
+	known_gossip: HashSet<Sha256>,
+	known_gossip_order: VecDeque<Sha256>,
+	/// The number of gossip messages we relayed to the peer since the last timer tick.
+	gossip_relayed_since_tick: usize,
+
+	/// The IP address the peer connected to us from, if it is an inbound peer and we were told.
+	remote_ip: Option<[u8; 16]>,
+	/// Whether we had channels with the peer when it completed the handshake. This is only checked
+	/// once per connection, see PeerHolder::peers_without_channels.
+	has_channels: bool,
+	/// The number of timer ticks since the peer connected, until it completed the handshake.
+	handshake_timer_ticks: u8,
 
 	awaiting_pong: bool,
 }
 
 impl Peer {
+	/// Returns the number of bytes we have yet to send, including the gossip we have yet to move
+	/// to the outbound buffer.
+	fn pending_outbound_buffer_bytes(&self) -> usize {
+		self.pending_outbound_buffer.iter().map(|msg| msg.len()).sum::<usize>() - self.pending_outbound_buffer_first_msg_offset +
+			self.pending_gossip_buffer.iter().map(|msg| msg.len()).sum::<usize>()
+	}
+
 	/// Returns true if the channel announcements/updates for the given channel should be
 	/// forwarded to this peer.
 	/// If we are sending our routing table to this peer and we have not yet sent channel
@@ -161,6 +283,30 @@ impl Peer {
 			InitSyncTracker::NodesSyncing(pk) => pk < node_id,
 		}
 	}
+
+	/// Returns true if gossip with the given timestamp passes the gossip_timestamp_filter the peer
+	/// sent us, or if it didn't send us any.
+	fn should_forward_gossip_timestamp(&self, timestamp: u32) -> bool {
+		match self.gossip_timestamp_filter {
+			None => true,
+			Some(ref filter) => timestamp >= filter.first_timestamp &&
+				(timestamp as u64) < filter.first_timestamp as u64 + filter.timestamp_range as u64,
+		}
+	}
+
+	/// Records that the peer has the gossip message with the given hash, forgetting about the
+	/// oldest one if we remember too many. Returns false if we already knew the peer had it.
+	fn mark_gossip_known(&mut self, msg_hash: Sha256) -> bool {
+		if !self.known_gossip.insert(msg_hash) {
+			return false;
+		}
+		self.known_gossip_order.push_back(msg_hash);
+		if self.known_gossip_order.len() > MAX_KNOWN_GOSSIP_PER_PEER {
+			let oldest_hash = self.known_gossip_order.pop_front().unwrap();
+			self.known_gossip.remove(&oldest_hash);
+		}
+		true
+	}
 }
 
 struct PeerHolder<Descriptor: SocketDescriptor> {
@@ -170,6 +316,34 @@ struct PeerHolder<Descriptor: SocketDescriptor> {
 	peers_needing_send: HashSet<Descriptor>,
 	/// Only add to this set when noise completes:
 	node_id_to_descriptor: HashMap<PublicKey, Descriptor>,
+	/// The number of peers in node_id_to_descriptor without has_channels set. Kept up to date as
+	/// peers come and go so that we don't have to ask the ChannelMessageHandler about every peer
+	/// on each new connection.
+	peers_without_channels: usize,
+	stats: PeerManagerStats,
+}
+
+/// Returns the IP address of the given address, with IPv4 addresses mapped to IPv6, if it is an IP
+/// address at all.
+fn ip_address_bytes(address: &msgs::NetAddress) -> Option<[u8; 16]> {
+	match *address {
+		msgs::NetAddress::IPv4 { ref addr, .. } => {
+			let mut res = [0; 16];
+			res[10] = 0xff;
+			res[11] = 0xff;
+			res[12..].copy_from_slice(addr);
+			Some(res)
+		},
+		msgs::NetAddress::IPv6 { ref addr, .. } => Some(*addr),
+		_ => None,
+	}
+}
+
+/// Returns true if the given IP address (as returned by ip_address_bytes) is a loopback address.
+fn is_loopback(ip: &[u8; 16]) -> bool {
+	let is_v4_loopback = ip[..10] == [0; 10] && ip[10..12] == [0xff; 2] && ip[12] == 127;
+	let is_v6_loopback = ip[..15] == [0; 15] && ip[15] == 1;
+	is_v4_loopback || is_v6_loopback
 }
 
 #[cfg(not(any(target_pointer_width = "32", target_pointer_width = "64")))]
@@ -215,6 +389,7 @@ pub struct PeerManager<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: De
 	peer_counter_low: AtomicUsize,
 	peer_counter_high: AtomicUsize,
 
+	limits: PeerManagerLimits,
 	logger: L,
 }
 
@@ -253,6 +428,14 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 	/// ephemeral_random_data is used to derive per-connection ephemeral keys and must be
 	/// cryptographically secure random bytes.
 	pub fn new(message_handler: MessageHandler<CM, RM>, our_node_secret: SecretKey, ephemeral_random_data: &[u8; 32], logger: L) -> Self {
+		Self::new_with_limits(message_handler, our_node_secret, ephemeral_random_data, logger, PeerManagerLimits::default())
+	}
+
+	/// Constructs a new PeerManager like [`new`], but keeping peers within the given limits
+	/// instead of the default ones.
+	///
+	/// [`new`]: #method.new
+	pub fn new_with_limits(message_handler: MessageHandler<CM, RM>, our_node_secret: SecretKey, ephemeral_random_data: &[u8; 32], logger: L, limits: PeerManagerLimits) -> Self {
 		let mut ephemeral_key_midstate = Sha256::engine();
 		ephemeral_key_midstate.input(ephemeral_random_data);
 
@@ -261,12 +444,15 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 			peers: Mutex::new(PeerHolder {
 				peers: HashMap::new(),
 				peers_needing_send: HashSet::new(),
-				node_id_to_descriptor: HashMap::new()
+				node_id_to_descriptor: HashMap::new(),
+				peers_without_channels: 0,
+				stats: PeerManagerStats::default(),
 			}),
 			our_node_secret,
 			ephemeral_key_midstate,
 			peer_counter_low: AtomicUsize::new(0),
 			peer_counter_high: AtomicUsize::new(0),
+			limits,
 			logger,
 		}
 	}
@@ -286,6 +472,11 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 		}).collect()
 	}
 
+	/// Gets the counters of what we refused or dropped to stay within our PeerManagerLimits.
+	pub fn get_stats(&self) -> PeerManagerStats {
+		self.peers.lock().unwrap().stats.clone()
+	}
+
 	fn get_ephemeral_key(&self) -> SecretKey {
 		let mut ephemeral_hash = self.ephemeral_key_midstate.clone();
 		let low = self.peer_counter_low.fetch_add(1, Ordering::AcqRel);
@@ -322,12 +513,24 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 			pending_outbound_buffer: LinkedList::new(),
 			pending_outbound_buffer_first_msg_offset: 0,
 			awaiting_write_event: false,
+			pending_gossip_buffer: VecDeque::new(),
+			outbound_buffer_full: false,
 
 			pending_read_buffer,
 			pending_read_buffer_pos: 0,
 			pending_read_is_header: false,
 
 			sync_status: InitSyncTracker::NoSyncRequested,
+			gossip_timestamp_filter: None,
+			filtered_sync_started: false,
+			short_ids_reply: None,
+			known_gossip: HashSet::new(),
+			known_gossip_order: VecDeque::new(),
+			gossip_relayed_since_tick: 0,
+
+			remote_ip: None,
+			has_channels: false,
+			handshake_timer_ticks: 0,
 
 			awaiting_pong: false,
 		}).is_some() {
@@ -346,10 +549,32 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 	/// Panics if descriptor is duplicative with some other descriptor which has not yet had
 	/// socket_disconnected called.
 	pub fn new_inbound_connection(&self, descriptor: Descriptor) -> Result<(), PeerHandleError> {
+		self.new_inbound_connection_with_address(descriptor, None)
+	}
+
+	/// Indicates a new inbound connection has been established from the given address, refusing
+	/// it if we have too many connections from the same IP address already (see
+	/// PeerManagerLimits::max_inbound_connections_per_ip).
+	///
+	/// Otherwise works like [`new_inbound_connection`].
+	///
+	/// [`new_inbound_connection`]: #method.new_inbound_connection
+	pub fn new_inbound_connection_with_address(&self, descriptor: Descriptor, remote_network_address: Option<msgs::NetAddress>) -> Result<(), PeerHandleError> {
 		let peer_encryptor = PeerChannelEncryptor::new_inbound(&self.our_node_secret);
 		let pending_read_buffer = [0; 50].to_vec(); // Noise act one is 50 bytes
+		// All connections to a Tor hidden service come from a loopback address, we don't want to
+		// limit those to max_inbound_connections_per_ip.
+		let remote_ip = remote_network_address.as_ref().and_then(ip_address_bytes).filter(|ip| !is_loopback(ip));
 
 		let mut peers = self.peers.lock().unwrap();
+		if let Some(ip) = remote_ip {
+			let connections_from_ip = peers.peers.values().filter(|peer| peer.remote_ip == Some(ip)).count();
+			if connections_from_ip >= self.limits.max_inbound_connections_per_ip {
+				log_debug!(self.logger, "Refusing inbound connection as we already have {} connections from its IP address", connections_from_ip);
+				peers.stats.connections_refused_per_ip += 1;
+				return Err(PeerHandleError{ no_connection_possible: false });
+			}
+		}
 		if peers.peers.insert(descriptor, Peer {
 			channel_encryptor: peer_encryptor,
 			outbound: false,
@@ -359,12 +584,24 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 			pending_outbound_buffer: LinkedList::new(),
 			pending_outbound_buffer_first_msg_offset: 0,
 			awaiting_write_event: false,
+			pending_gossip_buffer: VecDeque::new(),
+			outbound_buffer_full: false,
 
 			pending_read_buffer,
 			pending_read_buffer_pos: 0,
 			pending_read_is_header: false,
 
 			sync_status: InitSyncTracker::NoSyncRequested,
+			gossip_timestamp_filter: None,
+			filtered_sync_started: false,
+			short_ids_reply: None,
+			known_gossip: HashSet::new(),
+			known_gossip_order: VecDeque::new(),
+			gossip_relayed_since_tick: 0,
+
+			remote_ip,
+			has_channels: false,
+			handshake_timer_ticks: 0,
 
 			awaiting_pong: false,
 		}).is_some() {
@@ -382,61 +619,113 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 				}
 			}
 		}
-		const MSG_BUFF_SIZE: usize = 10;
 		while !peer.awaiting_write_event {
-			if peer.pending_outbound_buffer.len() < MSG_BUFF_SIZE {
-				match peer.sync_status {
-					InitSyncTracker::NoSyncRequested => {},
-					InitSyncTracker::ChannelsSyncing(c) if c < 0xffff_ffff_ffff_ffff => {
-						let steps = ((MSG_BUFF_SIZE - peer.pending_outbound_buffer.len() + 2) / 3) as u8;
-						let all_messages = self.message_handler.route_handler.get_next_channel_announcements(c, steps);
-						for &(ref announce, ref update_a_option, ref update_b_option) in all_messages.iter() {
-							encode_and_send_msg!(announce);
-							if let &Some(ref update_a) = update_a_option {
-								encode_and_send_msg!(update_a);
-							}
-							if let &Some(ref update_b) = update_b_option {
-								encode_and_send_msg!(update_b);
-							}
-							peer.sync_status = InitSyncTracker::ChannelsSyncing(announce.contents.short_channel_id + 1);
-						}
-						if all_messages.is_empty() || all_messages.len() != steps as usize {
-							peer.sync_status = InitSyncTracker::ChannelsSyncing(0xffff_ffff_ffff_ffff);
-						}
-					},
-					InitSyncTracker::ChannelsSyncing(c) if c == 0xffff_ffff_ffff_ffff => {
-						let steps = (MSG_BUFF_SIZE - peer.pending_outbound_buffer.len()) as u8;
-						let all_messages = self.message_handler.route_handler.get_next_node_announcements(None, steps);
-						for msg in all_messages.iter() {
-							encode_and_send_msg!(msg);
-							peer.sync_status = InitSyncTracker::NodesSyncing(msg.contents.node_id);
-						}
-						if all_messages.is_empty() || all_messages.len() != steps as usize {
-							peer.sync_status = InitSyncTracker::NoSyncRequested;
+			// Move the gossip we're relaying to the outbound buffer as it drains, ahead of any
+			// routing table sync.
+			while peer.pending_outbound_buffer.len() < OUTBOUND_BUFFER_LIMIT_READ_PAUSE {
+				match peer.pending_gossip_buffer.pop_front() {
+					Some(msg) => peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&msg[..])),
+					None => break,
+				}
+			}
+			if peer.pending_outbound_buffer.len() < OUTBOUND_BUFFER_LIMIT_READ_PAUSE &&
+					peer.pending_outbound_buffer_bytes() < self.limits.max_outbound_buffer_bytes / 2 {
+				if let Some(mut reply) = peer.short_ids_reply.take() {
+					// Finish replying to the peer's query before going on with any routing table
+					// sync, the peer may not send another query until it gets our reply_short_channel_ids_end.
+					let steps = (OUTBOUND_BUFFER_LIMIT_READ_PAUSE - peer.pending_outbound_buffer.len() + 2) / 3;
+					let short_channel_ids: Vec<u64> = (0..cmp::min(steps, reply.short_channel_ids.len())).map(|_| reply.short_channel_ids.pop_front().unwrap()).collect();
+					let (channel_announcements, node_announcements) = self.message_handler.route_handler.get_gossip_for_short_channel_ids(&short_channel_ids);
+					for &(ref announce, ref update_a_option, ref update_b_option) in channel_announcements.iter() {
+						encode_and_send_msg!(announce);
+						if let &Some(ref update_a) = update_a_option {
+							encode_and_send_msg!(update_a);
 						}
-					},
-					InitSyncTracker::ChannelsSyncing(_) => unreachable!(),
-					InitSyncTracker::NodesSyncing(key) => {
-						let steps = (MSG_BUFF_SIZE - peer.pending_outbound_buffer.len()) as u8;
-						let all_messages = self.message_handler.route_handler.get_next_node_announcements(Some(&key), steps);
-						for msg in all_messages.iter() {
-							encode_and_send_msg!(msg);
-							peer.sync_status = InitSyncTracker::NodesSyncing(msg.contents.node_id);
+						if let &Some(ref update_b) = update_b_option {
+							encode_and_send_msg!(update_b);
 						}
-						if all_messages.is_empty() || all_messages.len() != steps as usize {
-							peer.sync_status = InitSyncTracker::NoSyncRequested;
+					}
+					for announce in node_announcements.iter() {
+						if reply.sent_node_ids.insert(announce.contents.node_id) {
+							encode_and_send_msg!(announce);
 						}
-					},
+					}
+					if reply.short_channel_ids.is_empty() {
+						encode_and_send_msg!(&msgs::ReplyShortChannelIdsEnd { chain_hash: reply.chain_hash, full_information: true });
+					} else {
+						peer.short_ids_reply = Some(reply);
+					}
+				} else {
+					match peer.sync_status {
+						InitSyncTracker::NoSyncRequested => {},
+						InitSyncTracker::ChannelsSyncing(c) if c < 0xffff_ffff_ffff_ffff => {
+							let steps = ((OUTBOUND_BUFFER_LIMIT_READ_PAUSE - peer.pending_outbound_buffer.len() + 2) / 3) as u8;
+							let all_messages = self.message_handler.route_handler.get_next_channel_announcements(c, steps);
+							for &(ref announce, ref update_a_option, ref update_b_option) in all_messages.iter() {
+								// Channel announcements have no timestamp, we send them along with
+								// any of their updates which pass the peer's filter.
+								let update_a_option = update_a_option.as_ref().filter(|update| peer.should_forward_gossip_timestamp(update.contents.timestamp));
+								let update_b_option = update_b_option.as_ref().filter(|update| peer.should_forward_gossip_timestamp(update.contents.timestamp));
+								if peer.gossip_timestamp_filter.is_none() || update_a_option.is_some() || update_b_option.is_some() {
+									encode_and_send_msg!(announce);
+								}
+								if let Some(update_a) = update_a_option {
+									encode_and_send_msg!(update_a);
+								}
+								if let Some(update_b) = update_b_option {
+									encode_and_send_msg!(update_b);
+								}
+								peer.sync_status = InitSyncTracker::ChannelsSyncing(announce.contents.short_channel_id + 1);
+							}
+							if all_messages.is_empty() || all_messages.len() != steps as usize {
+								peer.sync_status = InitSyncTracker::ChannelsSyncing(0xffff_ffff_ffff_ffff);
+							}
+						},
+						InitSyncTracker::ChannelsSyncing(c) if c == 0xffff_ffff_ffff_ffff => {
+							let steps = (OUTBOUND_BUFFER_LIMIT_READ_PAUSE - peer.pending_outbound_buffer.len()) as u8;
+							let all_messages = self.message_handler.route_handler.get_next_node_announcements(None, steps);
+							for msg in all_messages.iter() {
+								if peer.should_forward_gossip_timestamp(msg.contents.timestamp) {
+									encode_and_send_msg!(msg);
+								}
+								peer.sync_status = InitSyncTracker::NodesSyncing(msg.contents.node_id);
+							}
+							if all_messages.is_empty() || all_messages.len() != steps as usize {
+								peer.sync_status = InitSyncTracker::NoSyncRequested;
+							}
+						},
+						InitSyncTracker::ChannelsSyncing(_) => unreachable!(),
+						InitSyncTracker::NodesSyncing(key) => {
+							let steps = (OUTBOUND_BUFFER_LIMIT_READ_PAUSE - peer.pending_outbound_buffer.len()) as u8;
+							let all_messages = self.message_handler.route_handler.get_next_node_announcements(Some(&key), steps);
+							for msg in all_messages.iter() {
+								if peer.should_forward_gossip_timestamp(msg.contents.timestamp) {
+									encode_and_send_msg!(msg);
+								}
+								peer.sync_status = InitSyncTracker::NodesSyncing(msg.contents.node_id);
+							}
+							if all_messages.is_empty() || all_messages.len() != steps as usize {
+								peer.sync_status = InitSyncTracker::NoSyncRequested;
+							}
+						},
+					}
 				}
 			}
 
 			if {
 				let next_buff = match peer.pending_outbound_buffer.front() {
-					None => return,
+					None => {
+						// If the peer's gossip_timestamp_filter made us skip a whole batch of
+						// sync messages, move on to the next batch.
+						match peer.sync_status {
+							InitSyncTracker::NoSyncRequested if peer.short_ids_reply.is_none() => return,
+							_ => continue,
+						}
+					},
 					Some(buff) => buff,
 				};
 
-				let should_be_reading = peer.pending_outbound_buffer.len() < MSG_BUFF_SIZE;
+				let should_be_reading = peer.pending_outbound_buffer.len() < OUTBOUND_BUFFER_LIMIT_READ_PAUSE;
 				let pending = &next_buff[peer.pending_outbound_buffer_first_msg_offset..];
 				let data_sent = descriptor.send_data(pending, should_be_reading);
 				peer.pending_outbound_buffer_first_msg_offset += data_sent;
@@ -494,21 +783,45 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 		}
 	}
 
+	/// Makes room for msg_len more bytes for the peer within
+	/// PeerManagerLimits::max_outbound_buffer_bytes, dropping the gossip we have yet to send it if
+	/// needed. Returns false if there isn't enough room even then, in which case the peer should be
+	/// disconnected rather than sent anything more.
+	fn make_outbound_buffer_room(&self, stats: &mut PeerManagerStats, peer: &mut Peer, msg_len: usize) -> bool {
+		if peer.pending_outbound_buffer_bytes() + msg_len <= self.limits.max_outbound_buffer_bytes {
+			return true;
+		}
+		if !peer.pending_gossip_buffer.is_empty() {
+			log_trace!(self.logger, "Dropping {} gossip messages queued for {} as its outbound buffer is full", peer.pending_gossip_buffer.len(), log_pubkey!(peer.their_node_id.unwrap()));
+			stats.gossip_messages_dropped += peer.pending_gossip_buffer.len() as u64;
+			peer.pending_gossip_buffer.clear();
+		}
+		peer.pending_outbound_buffer_bytes() + msg_len <= self.limits.max_outbound_buffer_bytes
+	}
+
 	/// Append a message to a peer's pending outbound/write buffer, and update the map of peers needing sends accordingly.
-	fn enqueue_message<M: Encode + Writeable>(&self, peers_needing_send: &mut HashSet<Descriptor>, peer: &mut Peer, descriptor: Descriptor, message: &M) {
+	/// Fails if the peer's outbound buffer is full, in which case the peer should be disconnected.
+	fn enqueue_message<M: Encode + Writeable>(&self, peers_needing_send: &mut HashSet<Descriptor>, stats: &mut PeerManagerStats, peer: &mut Peer, descriptor: Descriptor, message: &M) -> Result<(), PeerHandleError> {
 		let mut buffer = VecWriter(Vec::new());
 		wire::write(message, &mut buffer).unwrap(); // crash if the write failed
 		let encoded_message = buffer.0;
 
+		if !self.make_outbound_buffer_room(stats, peer, encoded_message.len()) {
+			log_debug!(self.logger, "Disconnecting peer {} as its outbound buffer is full", log_pubkey!(peer.their_node_id.unwrap()));
+			stats.peers_disconnected_outbound_buffer_full += 1;
+			return Err(PeerHandleError{ no_connection_possible: false });
+		}
 		log_trace!(self.logger, "Enqueueing message of type {} to {}", message.type_id(), log_pubkey!(peer.their_node_id.unwrap()));
 		peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encoded_message[..]));
 		peers_needing_send.insert(descriptor);
+		Ok(())
 	}
 
 	fn do_read_event(&self, peer_descriptor: &mut Descriptor, data: &[u8]) -> Result<bool, PeerHandleError> {
 		let pause_read = {
 			let mut peers_lock = self.peers.lock().unwrap();
 			let peers = &mut *peers_lock;
+			let mut msgs_to_forward = Vec::new();
 			let pause_read = match peers.peers.get_mut(peer_descriptor) {
 				None => panic!("Descriptor for read_event is not already known to PeerManager"),
 				Some(peer) => {
@@ -544,7 +857,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 												},
 												msgs::ErrorAction::SendErrorMessage { msg } => {
 													log_trace!(self.logger, "Got Err handling message, sending Error message because {}", e.err);
-													self.enqueue_message(&mut peers.peers_needing_send, peer, peer_descriptor.clone(), &msg);
+													self.enqueue_message(&mut peers.peers_needing_send, &mut peers.stats, peer, peer_descriptor.clone(), &msg)?;
 													continue;
 												},
 											}
@@ -584,20 +897,31 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 
 									peer.their_node_id = Some(their_node_id);
 									insert_node_id!();
-									let mut features = InitFeatures::known();
+									peer.has_channels = self.message_handler.chan_handler.has_channels_with_peer(&their_node_id);
+									if !peer.has_channels { peers.peers_without_channels += 1; }
+									let mut features = self.message_handler.chan_handler.provided_init_features();
 									if !self.message_handler.route_handler.should_request_full_sync(&peer.their_node_id.unwrap()) {
 										features.clear_initial_routing_sync();
 									}
 
 									let resp = msgs::Init { features };
-									self.enqueue_message(&mut peers.peers_needing_send, peer, peer_descriptor.clone(), &resp);
+									self.enqueue_message(&mut peers.peers_needing_send, &mut peers.stats, peer, peer_descriptor.clone(), &resp)?;
 								},
 								NextNoiseStep::ActThree => {
 									let their_node_id = try_potential_handleerror!(peer.channel_encryptor.process_act_three(&peer.pending_read_buffer[..]));
+									peer.has_channels = self.message_handler.chan_handler.has_channels_with_peer(&their_node_id);
+									if !peer.has_channels {
+										if peers.peers_without_channels >= self.limits.max_peers_without_channels {
+											log_debug!(self.logger, "Closing connection with {} as we already have {} peers without channels", log_pubkey!(their_node_id), peers.peers_without_channels);
+											peers.stats.peers_refused_without_channels += 1;
+											return Err(PeerHandleError{ no_connection_possible: false });
+										}
+									}
 									peer.pending_read_buffer = [0; 18].to_vec(); // Message length header is 18 bytes
 									peer.pending_read_is_header = true;
 									peer.their_node_id = Some(their_node_id);
 									insert_node_id!();
+									if !peer.has_channels { peers.peers_without_channels += 1; }
 								},
 								NextNoiseStep::NoiseComplete => {
 									if peer.pending_read_is_header {
@@ -641,13 +965,13 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 											}
 										};
 
-										if let Err(handling_error) = self.handle_message(&mut peers.peers_needing_send, peer, peer_descriptor.clone(), message){
-											match handling_error {
-												MessageHandlingError::PeerHandleError(e) => { return Err(e) },
-												MessageHandlingError::LightningError(e) => {
-													try_potential_handleerror!(Err(e));
-												},
-											}
+										match self.handle_message(&mut peers.peers_needing_send, &mut peers.stats, peer, peer_descriptor.clone(), message) {
+											Err(MessageHandlingError::PeerHandleError(e)) => { return Err(e) },
+											Err(MessageHandlingError::LightningError(e)) => {
+												try_potential_handleerror!(Err(e));
+											},
+											Ok(Some(msg)) => { msgs_to_forward.push(msg); },
+											Ok(None) => {},
 										}
 									}
 								}
@@ -657,10 +981,14 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 
 					self.do_attempt_write_data(peer_descriptor, peer);
 
-					peer.pending_outbound_buffer.len() > 10 // pause_read
+					peer.pending_outbound_buffer.len() > OUTBOUND_BUFFER_LIMIT_READ_PAUSE // pause_read
 				}
 			};
 
+			for msg in msgs_to_forward.drain(..) {
+				self.forward_broadcast_msg(peers, &msg);
+			}
+
 			pause_read
 		};
 
@@ -668,7 +996,8 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 	}
 
 	/// Process an incoming message and return a decision (ok, lightning error, peer handling error) regarding the next action with the peer
-	fn handle_message(&self, peers_needing_send: &mut HashSet<Descriptor>, peer: &mut Peer, peer_descriptor: Descriptor, message: wire::Message) -> Result<(), MessageHandlingError> {
+	/// On success, returns the gossip message which should be relayed to our other peers, if any.
+	fn handle_message(&self, peers_needing_send: &mut HashSet<Descriptor>, stats: &mut PeerManagerStats, peer: &mut Peer, peer_descriptor: Descriptor, message: wire::Message) -> Result<Option<wire::Message>, MessageHandlingError> {
 		log_trace!(self.logger, "Received message of type {} from {}", message.type_id(), log_pubkey!(peer.their_node_id.unwrap()));
 
 		// Need an Init as first message
@@ -712,16 +1041,17 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 				}
 
 				if !peer.outbound {
-					let mut features = InitFeatures::known();
+					let mut features = self.message_handler.chan_handler.provided_init_features();
 					if !self.message_handler.route_handler.should_request_full_sync(&peer.their_node_id.unwrap()) {
 						features.clear_initial_routing_sync();
 					}
 
 					let resp = msgs::Init { features };
-					self.enqueue_message(peers_needing_send, peer, peer_descriptor.clone(), &resp);
+					self.enqueue_message(peers_needing_send, stats, peer, peer_descriptor.clone(), &resp)?;
 				}
 
 				self.message_handler.chan_handler.peer_connected(&peer.their_node_id.unwrap(), &msg);
+				self.message_handler.route_handler.sync_routing_table(&peer.their_node_id.unwrap(), &msg);
 				peer.their_features = Some(msg.features);
 			},
 			wire::Message::Error(msg) => {
@@ -747,7 +1077,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 			wire::Message::Ping(msg) => {
 				if msg.ponglen < 65532 {
 					let resp = msgs::Pong { byteslen: msg.ponglen };
-					self.enqueue_message(peers_needing_send, peer, peer_descriptor.clone(), &resp);
+					self.enqueue_message(peers_needing_send, stats, peer, peer_descriptor.clone(), &resp)?;
 				}
 			},
 			wire::Message::Pong(_msg) => {
@@ -811,33 +1141,82 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 				self.message_handler.chan_handler.handle_announcement_signatures(&peer.their_node_id.unwrap(), &msg);
 			},
 			wire::Message::ChannelAnnouncement(msg) => {
+				peer.mark_gossip_known(Sha256::hash(&encode_msg!(&msg)));
 				let should_forward = match self.message_handler.route_handler.handle_channel_announcement(&msg) {
 					Ok(v) => v,
 					Err(e) => { return Err(e.into()); },
 				};
 
 				if should_forward {
-					// TODO: forward msg along to all our other peers!
+					return Ok(Some(wire::Message::ChannelAnnouncement(msg)));
 				}
 			},
 			wire::Message::NodeAnnouncement(msg) => {
+				peer.mark_gossip_known(Sha256::hash(&encode_msg!(&msg)));
 				let should_forward = match self.message_handler.route_handler.handle_node_announcement(&msg) {
 					Ok(v) => v,
 					Err(e) => { return Err(e.into()); },
 				};
 
 				if should_forward {
-					// TODO: forward msg along to all our other peers!
+					return Ok(Some(wire::Message::NodeAnnouncement(msg)));
 				}
 			},
 			wire::Message::ChannelUpdate(msg) => {
+				peer.mark_gossip_known(Sha256::hash(&encode_msg!(&msg)));
 				let should_forward = match self.message_handler.route_handler.handle_channel_update(&msg) {
 					Ok(v) => v,
 					Err(e) => { return Err(e.into()); },
 				};
 
 				if should_forward {
-					// TODO: forward msg along to all our other peers!
+					return Ok(Some(wire::Message::ChannelUpdate(msg)));
+				}
+			},
+			wire::Message::QueryShortChannelIds(msg) => {
+				if peer.short_ids_reply.is_some() {
+					return Err(LightningError {
+						err: "Received query_short_channel_ids while still replying to a previous one".to_owned(),
+						action: msgs::ErrorAction::IgnoreError,
+					}.into());
+				}
+				if let Err(e) = self.message_handler.route_handler.handle_query_short_channel_ids(&peer.their_node_id.unwrap(), &msg) {
+					return Err(e.into());
+				}
+				peer.short_ids_reply = Some(ShortIdsReplyTracker {
+					chain_hash: msg.chain_hash,
+					short_channel_ids: msg.short_channel_ids.into_iter().collect(),
+					sent_node_ids: HashSet::new(),
+				});
+				peers_needing_send.insert(peer_descriptor.clone());
+			},
+			wire::Message::ReplyShortChannelIdsEnd(msg) => {
+				if let Err(e) = self.message_handler.route_handler.handle_reply_short_channel_ids_end(&peer.their_node_id.unwrap(), &msg) {
+					return Err(e.into());
+				}
+			},
+			wire::Message::QueryChannelRange(msg) => {
+				if let Err(e) = self.message_handler.route_handler.handle_query_channel_range(&peer.their_node_id.unwrap(), &msg) {
+					return Err(e.into());
+				}
+			},
+			wire::Message::ReplyChannelRange(msg) => {
+				if let Err(e) = self.message_handler.route_handler.handle_reply_channel_range(&peer.their_node_id.unwrap(), &msg) {
+					return Err(e.into());
+				}
+			},
+			wire::Message::GossipTimestampFilter(msg) => {
+				log_trace!(self.logger, "Peer {} set its gossip timestamp filter to {} + {}", log_pubkey!(peer.their_node_id.unwrap()), msg.first_timestamp, msg.timestamp_range);
+				peer.gossip_timestamp_filter = Some(msg);
+				// Send the gossip we already have which passes the filter, unless we're already
+				// sending our routing table to the peer, which is now filtered as well. Peers may
+				// update their filter at any time, but only the first one triggers a sync.
+				if !peer.filtered_sync_started {
+					peer.filtered_sync_started = true;
+					if let InitSyncTracker::NoSyncRequested = peer.sync_status {
+						peer.sync_status = InitSyncTracker::ChannelsSyncing(0);
+						peers_needing_send.insert(peer_descriptor.clone());
+					}
 				}
 			},
 
@@ -851,7 +1230,65 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 				log_trace!(self.logger, "Received unknown odd message of type {}, ignoring", msg_type);
 			}
 		};
-		Ok(())
+		Ok(None)
+	}
+
+	/// Relays the given gossip message to all our peers which aren't known to have it yet.
+	///
+	/// The message is queued in the peers' pending_gossip_buffer, and dropped for peers whose
+	/// outbound buffer is full (or half of PeerManagerLimits::max_outbound_buffer_bytes) or to
+	/// which we already relayed MAX_GOSSIP_RELAY_PER_TICK messages since the last timer tick. Like
+	/// do_read_event, this never calls send_data, peers are added to peers_needing_send instead.
+	fn forward_broadcast_msg(&self, peers: &mut PeerHolder<Descriptor>, msg: &wire::Message) {
+		let encoded_msg = match *msg {
+			wire::Message::ChannelAnnouncement(ref msg) => encode_msg!(msg),
+			wire::Message::NodeAnnouncement(ref msg) => encode_msg!(msg),
+			wire::Message::ChannelUpdate(ref msg) => encode_msg!(msg),
+			_ => unreachable!(),
+		};
+		let msg_hash = Sha256::hash(&encoded_msg[..]);
+
+		for (descriptor, peer) in peers.peers.iter_mut() {
+			if !peer.channel_encryptor.is_ready_for_encryption() || peer.their_features.is_none() {
+				continue;
+			}
+			let should_forward = match *msg {
+				wire::Message::ChannelAnnouncement(ref msg) => {
+					// Channel announcements have no timestamp, and the nodes of the channel know
+					// about it already.
+					peer.should_forward_channel_announcement(msg.contents.short_channel_id) &&
+						peer.their_node_id != Some(msg.contents.node_id_1) &&
+						peer.their_node_id != Some(msg.contents.node_id_2)
+				},
+				wire::Message::NodeAnnouncement(ref msg) => {
+					peer.should_forward_node_announcement(msg.contents.node_id) &&
+						peer.should_forward_gossip_timestamp(msg.contents.timestamp)
+				},
+				wire::Message::ChannelUpdate(ref msg) => {
+					peer.should_forward_channel_announcement(msg.contents.short_channel_id) &&
+						peer.should_forward_gossip_timestamp(msg.contents.timestamp)
+				},
+				_ => unreachable!(),
+			};
+			if !should_forward || peer.known_gossip.contains(&msg_hash) {
+				continue;
+			}
+			if peer.pending_outbound_buffer.len() + peer.pending_gossip_buffer.len() >= OUTBOUND_BUFFER_LIMIT_DROP_GOSSIP ||
+					peer.pending_outbound_buffer_bytes() >= self.limits.max_outbound_buffer_bytes / 2 {
+				log_trace!(self.logger, "Not relaying gossip message of type {} to {} as its outbound buffer is full", msg.type_id(), log_pubkey!(peer.their_node_id.unwrap()));
+				peers.stats.gossip_messages_dropped += 1;
+				continue;
+			}
+			if peer.gossip_relayed_since_tick >= MAX_GOSSIP_RELAY_PER_TICK {
+				log_trace!(self.logger, "Not relaying gossip message of type {} to {} as we relayed too much gossip to it recently", msg.type_id(), log_pubkey!(peer.their_node_id.unwrap()));
+				peers.stats.gossip_messages_dropped += 1;
+				continue;
+			}
+			peer.mark_gossip_known(msg_hash);
+			peer.gossip_relayed_since_tick += 1;
+			peer.pending_gossip_buffer.push_back(encoded_msg.clone());
+			peers.peers_needing_send.insert(descriptor.clone());
+		}
 	}
 
 	/// Checks for any events generated by our handlers and processes them. Includes sending most
@@ -859,11 +1296,8 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 	/// functions like ChannelManager::process_pending_htlc_forward or send_payment).
 	pub fn process_events(&self) {
 		{
-			// TODO: There are some DoS attacks here where you can flood someone's outbound send
-			// buffer by doing things like announcing channels on another node. We should be willing to
-			// drop optional-ish messages when send buffers get full!
-
 			let mut events_generated = self.message_handler.chan_handler.get_and_clear_pending_msg_events();
+			events_generated.append(&mut self.message_handler.route_handler.get_and_clear_pending_msg_events());
 			let mut peers_lock = self.peers.lock().unwrap();
 			let peers = &mut *peers_lock;
 			for event in events_generated.drain(..) {
@@ -890,6 +1324,21 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 						}
 					}
 				}
+				// Queues the given message for the peer, unless its outbound buffer is full even
+				// without the gossip we have yet to send it, in which case we skip the event and
+				// disconnect the peer below.
+				macro_rules! enqueue_msg {
+					($peer: expr, $msg: expr) => {
+						{
+							let encoded_msg = encode_msg!($msg);
+							if !self.make_outbound_buffer_room(&mut peers.stats, $peer, encoded_msg.len()) {
+								$peer.outbound_buffer_full = true;
+								continue;
+							}
+							$peer.pending_outbound_buffer.push_back($peer.channel_encryptor.encrypt_message(&encoded_msg[..]));
+						}
+					}
+				}
 				match event {
 					MessageSendEvent::SendAcceptChannel { ref node_id, ref msg } => {
 						log_trace!(self.logger, "Handling SendAcceptChannel event in peer_handler for node {} for channel {}",
@@ -898,7 +1347,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: Drop the pending channel? (or just let it timeout, but that sucks)
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+						enqueue_msg!(peer, msg);
 						self.do_attempt_write_data(&mut descriptor, peer);
 					},
 					MessageSendEvent::SendOpenChannel { ref node_id, ref msg } => {
@@ -908,7 +1357,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: Drop the pending channel? (or just let it timeout, but that sucks)
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+						enqueue_msg!(peer, msg);
 						self.do_attempt_write_data(&mut descriptor, peer);
 					},
 					MessageSendEvent::SendFundingCreated { ref node_id, ref msg } => {
@@ -920,7 +1369,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 								//TODO: generate a DiscardFunding event indicating to the wallet that
 								//they should just throw away this funding transaction
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+						enqueue_msg!(peer, msg);
 						self.do_attempt_write_data(&mut descriptor, peer);
 					},
 					MessageSendEvent::SendFundingSigned { ref node_id, ref msg } => {
@@ -931,7 +1380,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 								//TODO: generate a DiscardFunding event indicating to the wallet that
 								//they should just throw away this funding transaction
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+						enqueue_msg!(peer, msg);
 						self.do_attempt_write_data(&mut descriptor, peer);
 					},
 					MessageSendEvent::SendFundingLocked { ref node_id, ref msg } => {
@@ -941,7 +1390,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: Do whatever we're gonna do for handling dropped messages
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+						enqueue_msg!(peer, msg);
 						self.do_attempt_write_data(&mut descriptor, peer);
 					},
 					MessageSendEvent::SendAnnouncementSignatures { ref node_id, ref msg } => {
@@ -952,7 +1401,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 								//TODO: generate a DiscardFunding event indicating to the wallet that
 								//they should just throw away this funding transaction
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+						enqueue_msg!(peer, msg);
 						self.do_attempt_write_data(&mut descriptor, peer);
 					},
 					MessageSendEvent::UpdateHTLCs { ref node_id, updates: msgs::CommitmentUpdate { ref update_add_htlcs, ref update_fulfill_htlcs, ref update_fail_htlcs, ref update_fail_malformed_htlcs, ref update_fee, ref commitment_signed } } => {
@@ -966,21 +1415,21 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 								//TODO: Do whatever we're gonna do for handling dropped messages
 							});
 						for msg in update_add_htlcs {
-							peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+							enqueue_msg!(peer, msg);
 						}
 						for msg in update_fulfill_htlcs {
-							peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+							enqueue_msg!(peer, msg);
 						}
 						for msg in update_fail_htlcs {
-							peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+							enqueue_msg!(peer, msg);
 						}
 						for msg in update_fail_malformed_htlcs {
-							peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+							enqueue_msg!(peer, msg);
 						}
 						if let &Some(ref msg) = update_fee {
-							peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+							enqueue_msg!(peer, msg);
 						}
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(commitment_signed)));
+						enqueue_msg!(peer, commitment_signed);
 						self.do_attempt_write_data(&mut descriptor, peer);
 					},
 					MessageSendEvent::SendRevokeAndACK { ref node_id, ref msg } => {
@@ -990,7 +1439,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: Do whatever we're gonna do for handling dropped messages
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+						enqueue_msg!(peer, msg);
 						self.do_attempt_write_data(&mut descriptor, peer);
 					},
 					MessageSendEvent::SendClosingSigned { ref node_id, ref msg } => {
@@ -1000,7 +1449,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: Do whatever we're gonna do for handling dropped messages
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+						enqueue_msg!(peer, msg);
 						self.do_attempt_write_data(&mut descriptor, peer);
 					},
 					MessageSendEvent::SendShutdown { ref node_id, ref msg } => {
@@ -1010,7 +1459,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: Do whatever we're gonna do for handling dropped messages
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+						enqueue_msg!(peer, msg);
 						self.do_attempt_write_data(&mut descriptor, peer);
 					},
 					MessageSendEvent::SendChannelReestablish { ref node_id, ref msg } => {
@@ -1020,73 +1469,88 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: Do whatever we're gonna do for handling dropped messages
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+						enqueue_msg!(peer, msg);
 						self.do_attempt_write_data(&mut descriptor, peer);
 					},
-					MessageSendEvent::BroadcastChannelAnnouncement { ref msg, ref update_msg } => {
+					MessageSendEvent::BroadcastChannelAnnouncement { msg, update_msg } => {
 						log_trace!(self.logger, "Handling BroadcastChannelAnnouncement event in peer_handler for short channel id {}", msg.contents.short_channel_id);
-						if self.message_handler.route_handler.handle_channel_announcement(msg).is_ok() && self.message_handler.route_handler.handle_channel_update(update_msg).is_ok() {
-							let encoded_msg = encode_msg!(msg);
-							let encoded_update_msg = encode_msg!(update_msg);
-
-							for (ref descriptor, ref mut peer) in peers.peers.iter_mut() {
-								if !peer.channel_encryptor.is_ready_for_encryption() || peer.their_features.is_none() ||
-										!peer.should_forward_channel_announcement(msg.contents.short_channel_id) {
-									continue
-								}
-								match peer.their_node_id {
-									None => continue,
-									Some(their_node_id) => {
-										if their_node_id == msg.contents.node_id_1 || their_node_id == msg.contents.node_id_2 {
-											continue
-										}
-									}
-								}
-								peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encoded_msg[..]));
-								peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encoded_update_msg[..]));
-								self.do_attempt_write_data(&mut (*descriptor).clone(), peer);
-							}
+						if self.message_handler.route_handler.handle_channel_announcement(&msg).is_ok() && self.message_handler.route_handler.handle_channel_update(&update_msg).is_ok() {
+							self.forward_broadcast_msg(peers, &wire::Message::ChannelAnnouncement(msg));
+							self.forward_broadcast_msg(peers, &wire::Message::ChannelUpdate(update_msg));
 						}
 					},
-					MessageSendEvent::BroadcastNodeAnnouncement { ref msg } => {
+					MessageSendEvent::BroadcastNodeAnnouncement { msg } => {
 						log_trace!(self.logger, "Handling BroadcastNodeAnnouncement event in peer_handler");
-						if self.message_handler.route_handler.handle_node_announcement(msg).is_ok() {
-							let encoded_msg = encode_msg!(msg);
-
-							for (ref descriptor, ref mut peer) in peers.peers.iter_mut() {
-								if !peer.channel_encryptor.is_ready_for_encryption() || peer.their_features.is_none() ||
-										!peer.should_forward_node_announcement(msg.contents.node_id) {
-									continue
-								}
-								peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encoded_msg[..]));
-								self.do_attempt_write_data(&mut (*descriptor).clone(), peer);
-							}
+						if self.message_handler.route_handler.handle_node_announcement(&msg).is_ok() {
+							self.forward_broadcast_msg(peers, &wire::Message::NodeAnnouncement(msg));
 						}
 					},
-					MessageSendEvent::BroadcastChannelUpdate { ref msg } => {
+					MessageSendEvent::BroadcastChannelUpdate { msg } => {
 						log_trace!(self.logger, "Handling BroadcastChannelUpdate event in peer_handler for short channel id {}", msg.contents.short_channel_id);
-						if self.message_handler.route_handler.handle_channel_update(msg).is_ok() {
-							let encoded_msg = encode_msg!(msg);
-
-							for (ref descriptor, ref mut peer) in peers.peers.iter_mut() {
-								if !peer.channel_encryptor.is_ready_for_encryption() || peer.their_features.is_none() ||
-										!peer.should_forward_channel_announcement(msg.contents.short_channel_id)  {
-									continue
-								}
-								peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encoded_msg[..]));
-								self.do_attempt_write_data(&mut (*descriptor).clone(), peer);
-							}
+						if self.message_handler.route_handler.handle_channel_update(&msg).is_ok() {
+							self.forward_broadcast_msg(peers, &wire::Message::ChannelUpdate(msg));
+						}
+					},
+					MessageSendEvent::RelayChannelAnnouncement { msg, update_msgs } => {
+						log_trace!(self.logger, "Handling RelayChannelAnnouncement event in peer_handler for short channel id {} with {} channel_updates",
+								msg.contents.short_channel_id,
+								update_msgs.len());
+						self.forward_broadcast_msg(peers, &wire::Message::ChannelAnnouncement(msg));
+						for update_msg in update_msgs {
+							self.forward_broadcast_msg(peers, &wire::Message::ChannelUpdate(update_msg));
 						}
 					},
 					MessageSendEvent::PaymentFailureNetworkUpdate { ref update } => {
 						self.message_handler.route_handler.handle_htlc_fail_channel_update(update);
 					},
+					MessageSendEvent::SendChannelRangeQuery { ref node_id, ref msg } => {
+						log_trace!(self.logger, "Handling SendChannelRangeQuery event in peer_handler for node {} with first_blocknum={}, number_of_blocks={}",
+								log_pubkey!(node_id),
+								msg.first_blocknum,
+								msg.number_of_blocks);
+						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {});
+						enqueue_msg!(peer, msg);
+						self.do_attempt_write_data(&mut descriptor, peer);
+					},
+					MessageSendEvent::SendReplyChannelRange { ref node_id, ref msg } => {
+						log_trace!(self.logger, "Handling SendReplyChannelRange event in peer_handler for node {} with {} short_channel_ids",
+								log_pubkey!(node_id),
+								msg.short_channel_ids.len());
+						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {});
+						enqueue_msg!(peer, msg);
+						self.do_attempt_write_data(&mut descriptor, peer);
+					},
+					MessageSendEvent::SendShortIdsQuery { ref node_id, ref msg } => {
+						log_trace!(self.logger, "Handling SendShortIdsQuery event in peer_handler for node {} with {} short_channel_ids",
+								log_pubkey!(node_id),
+								msg.short_channel_ids.len());
+						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {});
+						enqueue_msg!(peer, msg);
+						self.do_attempt_write_data(&mut descriptor, peer);
+					},
+					MessageSendEvent::SendShortIdsReply { ref node_id, ref msg } => {
+						log_trace!(self.logger, "Handling SendShortIdsReply event in peer_handler for node {} with full_information={}",
+								log_pubkey!(node_id),
+								msg.full_information);
+						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {});
+						enqueue_msg!(peer, msg);
+						self.do_attempt_write_data(&mut descriptor, peer);
+					},
+					MessageSendEvent::SendGossipTimestampFilter { ref node_id, ref msg } => {
+						log_trace!(self.logger, "Handling SendGossipTimestampFilter event in peer_handler for node {} with first_timestamp={}",
+								log_pubkey!(node_id),
+								msg.first_timestamp);
+						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {});
+						enqueue_msg!(peer, msg);
+						self.do_attempt_write_data(&mut descriptor, peer);
+					},
 					MessageSendEvent::HandleError { ref node_id, ref action } => {
 						match *action {
 							msgs::ErrorAction::DisconnectPeer { ref msg } => {
 								if let Some(mut descriptor) = peers.node_id_to_descriptor.remove(node_id) {
 									peers.peers_needing_send.remove(&descriptor);
 									if let Some(mut peer) = peers.peers.remove(&descriptor) {
+										if !peer.has_channels { peers.peers_without_channels -= 1; }
 										if let Some(ref msg) = *msg {
 											log_trace!(self.logger, "Handling DisconnectPeer HandleError event in peer_handler for node {} with message {}",
 													log_pubkey!(node_id),
@@ -1111,7 +1575,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 								let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
 									//TODO: Do whatever we're gonna do for handling dropped messages
 								});
-								peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+								enqueue_msg!(peer, msg);
 								self.do_attempt_write_data(&mut descriptor, peer);
 							},
 						}
@@ -1125,6 +1589,26 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 					None => panic!("Inconsistent peers set state!"),
 				}
 			}
+
+			// Peers which don't read the data we send them can't make us buffer it forever, we drop
+			// the gossip we have yet to send them first, and disconnect them if that isn't enough.
+			let mut descriptors_over_limit = Vec::new();
+			for (descriptor, peer) in peers.peers.iter_mut() {
+				if peer.outbound_buffer_full || !self.make_outbound_buffer_room(&mut peers.stats, peer, 0) {
+					descriptors_over_limit.push(descriptor.clone());
+				}
+			}
+			for mut descriptor in descriptors_over_limit {
+				let peer = peers.peers.remove(&descriptor).unwrap();
+				peers.stats.peers_disconnected_outbound_buffer_full += 1;
+				if let Some(node_id) = peer.their_node_id {
+					log_debug!(self.logger, "Disconnecting peer {} as its outbound buffer is full", log_pubkey!(node_id));
+					peers.node_id_to_descriptor.remove(&node_id);
+					if !peer.has_channels { peers.peers_without_channels -= 1; }
+					self.message_handler.chan_handler.peer_disconnected(&node_id, false);
+				}
+				descriptor.disconnect_socket();
+			}
 		}
 	}
 
@@ -1150,6 +1634,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 				match peer.their_node_id {
 					Some(node_id) => {
 						peers.node_id_to_descriptor.remove(&node_id);
+						if !peer.has_channels { peers.peers_without_channels -= 1; }
 						self.message_handler.chan_handler.peer_disconnected(&node_id, no_connection_possible);
 					},
 					None => {}
@@ -1159,7 +1644,8 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 	}
 
 	/// This function should be called roughly once every 30 seconds.
-	/// It will send pings to each peer and disconnect those which did not respond to the last round of pings.
+	/// It will send pings to each peer and disconnect those which did not respond to the last round of pings,
+	/// as well as those which did not complete the handshake in time.
 
 	/// Will most likely call send_data on all of the registered descriptors, thus, be very careful with reentrancy issues!
 	pub fn timer_tick_occured(&self) {
@@ -1168,10 +1654,13 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 			let peers = &mut *peers_lock;
 			let peers_needing_send = &mut peers.peers_needing_send;
 			let node_id_to_descriptor = &mut peers.node_id_to_descriptor;
+			let peers_without_channels = &mut peers.peers_without_channels;
+			let stats = &mut peers.stats;
 			let peers = &mut peers.peers;
 			let mut descriptors_needing_disconnect = Vec::new();
 
 			peers.retain(|descriptor, peer| {
+				peer.gossip_relayed_since_tick = 0;
 				if peer.awaiting_pong {
 					peers_needing_send.remove(descriptor);
 					descriptors_needing_disconnect.push(descriptor.clone());
@@ -1179,6 +1668,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 						Some(node_id) => {
 							log_trace!(self.logger, "Disconnecting peer with id {} due to ping timeout", node_id);
 							node_id_to_descriptor.remove(&node_id);
+							if !peer.has_channels { *peers_without_channels -= 1; }
 							self.message_handler.chan_handler.peer_disconnected(&node_id, false);
 						}
 						None => {
@@ -1190,6 +1680,24 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 					return false;
 				}
 
+				if !peer.channel_encryptor.is_ready_for_encryption() || peer.their_features.is_none() {
+					peer.handshake_timer_ticks = peer.handshake_timer_ticks.saturating_add(1);
+					if peer.handshake_timer_ticks >= self.limits.max_handshake_timer_ticks {
+						peers_needing_send.remove(descriptor);
+						descriptors_needing_disconnect.push(descriptor.clone());
+						stats.peers_disconnected_handshake_timeout += 1;
+						if let Some(node_id) = peer.their_node_id {
+							log_trace!(self.logger, "Disconnecting peer with id {} due to handshake timeout", node_id);
+							node_id_to_descriptor.remove(&node_id);
+							if !peer.has_channels { *peers_without_channels -= 1; }
+							self.message_handler.chan_handler.peer_disconnected(&node_id, false);
+						} else {
+							log_trace!(self.logger, "Disconnecting peer due to noise handshake timeout");
+						}
+						return false;
+					}
+				}
+
 				if !peer.channel_encryptor.is_ready_for_encryption() {
 					// The peer needs to complete its handshake before we can exchange messages
 					return true;
@@ -1217,17 +1725,25 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref> PeerManager<D
 
 #[cfg(test)]
 mod tests {
-	use ln::peer_handler::{PeerManager, MessageHandler, SocketDescriptor};
+	use ln::peer_handler::{PeerManager, PeerManagerLimits, PeerManagerStats, MessageHandler, SocketDescriptor, OUTBOUND_BUFFER_LIMIT_DROP_GOSSIP, MAX_GOSSIP_RELAY_PER_TICK};
+	use ln::channelmanager::ChannelDetails;
+	use ln::features::InitFeatures;
+	use ln::peer_connection_manager::{PeerConnectionManager, PeerConnectionState, INITIAL_RECONNECT_DELAY_SECS};
+	use ln::wire;
 	use ln::msgs;
 	use util::events;
 	use util::test_utils;
+	use util::ser::VecWriter;
 
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::network::constants::Network;
 	use bitcoin::secp256k1::Secp256k1;
 	use bitcoin::secp256k1::key::{SecretKey, PublicKey};
 
 	use std;
 	use std::sync::{Arc, Mutex};
 	use std::sync::atomic::Ordering;
+	use std::time::{Duration, Instant};
 
 	#[derive(Clone)]
 	struct FileDescriptor {
@@ -1277,12 +1793,16 @@ mod tests {
 	}
 
 	fn create_network<'a>(peer_count: usize, cfgs: &'a Vec<PeerManagerCfg>) -> Vec<PeerManager<FileDescriptor, &'a test_utils::TestChannelMessageHandler, &'a test_utils::TestRoutingMessageHandler, &'a test_utils::TestLogger>> {
+		create_network_with_limits(peer_count, cfgs, PeerManagerLimits::default())
+	}
+
+	fn create_network_with_limits<'a>(peer_count: usize, cfgs: &'a Vec<PeerManagerCfg>, limits: PeerManagerLimits) -> Vec<PeerManager<FileDescriptor, &'a test_utils::TestChannelMessageHandler, &'a test_utils::TestRoutingMessageHandler, &'a test_utils::TestLogger>> {
 		let mut peers = Vec::new();
 		for i in 0..peer_count {
 			let node_secret = SecretKey::from_slice(&[42 + i as u8; 32]).unwrap();
 			let ephemeral_bytes = [i as u8; 32];
 			let msg_handler = MessageHandler { chan_handler: &cfgs[i].chan_handler, route_handler: &cfgs[i].routing_handler };
-			let peer = PeerManager::new(msg_handler, node_secret, &ephemeral_bytes, &cfgs[i].logger);
+			let peer = PeerManager::new_with_limits(msg_handler, node_secret, &ephemeral_bytes, &cfgs[i].logger, limits);
 			peers.push(peer);
 		}
 
@@ -1292,8 +1812,11 @@ mod tests {
 	fn establish_connection<'a>(peer_a: &PeerManager<FileDescriptor, &'a test_utils::TestChannelMessageHandler, &'a test_utils::TestRoutingMessageHandler, &'a test_utils::TestLogger>, peer_b: &PeerManager<FileDescriptor, &'a test_utils::TestChannelMessageHandler, &'a test_utils::TestRoutingMessageHandler, &'a test_utils::TestLogger>) -> (FileDescriptor, FileDescriptor) {
 		let secp_ctx = Secp256k1::new();
 		let a_id = PublicKey::from_secret_key(&secp_ctx, &peer_a.our_node_secret);
-		let mut fd_a = FileDescriptor { fd: 1, outbound_data: Arc::new(Mutex::new(Vec::new())) };
-		let mut fd_b = FileDescriptor { fd: 1, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+		// Use a new fd on each side so that a PeerManager can be connected to several peers.
+		let fd_a_num = peer_a.peers.lock().unwrap().peers.len() as u16 + 1;
+		let fd_b_num = peer_b.peers.lock().unwrap().peers.len() as u16 + 1;
+		let mut fd_a = FileDescriptor { fd: fd_a_num, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+		let mut fd_b = FileDescriptor { fd: fd_b_num, outbound_data: Arc::new(Mutex::new(Vec::new())) };
 		let initial_data = peer_b.new_outbound_connection(a_id, fd_b.clone()).unwrap();
 		peer_a.new_inbound_connection(fd_a.clone()).unwrap();
 		assert_eq!(peer_a.read_event(&mut fd_a, &initial_data).unwrap(), false);
@@ -1309,6 +1832,54 @@ mod tests {
 		(fd_a.clone(), fd_b.clone())
 	}
 
+	#[test]
+	fn test_peer_reconnection() {
+		// Drive a PeerConnectionManager with in-memory connections between two PeerManagers.
+		let cfgs = create_peermgr_cfgs(2);
+		let peers = create_network(2, &cfgs);
+		let secp_ctx = Secp256k1::new();
+		let their_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
+		let address = msgs::NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 };
+		let address_source = test_utils::TestPeerAddressSource::new();
+		address_source.addresses.lock().unwrap().insert(their_id, vec![address.clone()]);
+		let connection_manager = PeerConnectionManager::new(&address_source, &cfgs[0].logger);
+		connection_manager.set_channel_peers(&[ChannelDetails {
+			channel_id: [0; 32],
+			short_channel_id: None,
+			remote_network_id: their_id,
+			counterparty_features: InitFeatures::known(),
+			channel_value_satoshis: 0,
+			user_id: 0,
+			outbound_capacity_msat: 0,
+			inbound_capacity_msat: 0,
+			is_live: false,
+		}]);
+
+		let mut now = Instant::now();
+		assert_eq!(connection_manager.get_peers_to_connect(&peers[0].get_peer_node_ids(), now), vec![(their_id, vec![address.clone()])]);
+		let (fd_1, fd_0) = establish_connection_and_read_events(&peers[1], &peers[0]);
+		assert!(connection_manager.get_peers_to_connect(&peers[0].get_peer_node_ids(), now).is_empty());
+		assert_eq!(connection_manager.get_peer_states(), vec![(their_id, PeerConnectionState::Connected)]);
+
+		// Once the connection drops, we reconnect after a short delay.
+		peers[0].socket_disconnected(&fd_0);
+		peers[1].socket_disconnected(&fd_1);
+		connection_manager.connection_closed(&their_id, now);
+		assert!(connection_manager.get_peers_to_connect(&peers[0].get_peer_node_ids(), now).is_empty());
+		now += Duration::from_secs(INITIAL_RECONNECT_DELAY_SECS);
+		assert_eq!(connection_manager.get_peers_to_connect(&peers[0].get_peer_node_ids(), now).len(), 1);
+
+		// If the connection attempt fails, we back off.
+		connection_manager.connection_closed(&their_id, now);
+		assert_eq!(connection_manager.get_peer_states(), vec![(their_id, PeerConnectionState::Disconnected {
+			failed_attempts: 1, next_attempt: now + Duration::from_secs(INITIAL_RECONNECT_DELAY_SECS) })]);
+		now += Duration::from_secs(INITIAL_RECONNECT_DELAY_SECS);
+		assert_eq!(connection_manager.get_peers_to_connect(&peers[0].get_peer_node_ids(), now).len(), 1);
+		establish_connection_and_read_events(&peers[1], &peers[0]);
+		assert!(connection_manager.get_peers_to_connect(&peers[0].get_peer_node_ids(), now).is_empty());
+		assert_eq!(connection_manager.get_peer_states(), vec![(their_id, PeerConnectionState::Connected)]);
+	}
+
 	#[test]
 	fn test_disconnect_peer() {
 		// Simple test which builds a network of PeerManager, connects and brings them to NoiseState::Finished and
@@ -1414,4 +1985,425 @@ mod tests {
 			assert!(peer_1_features.unwrap().initial_routing_sync());
 		}
 	}
+	#[test]
+	fn test_gossip_queries_on_connect() {
+		// Both test routing handlers query the channel range of peers supporting gossip_queries
+		// once the Init messages have been exchanged.
+		let cfgs = create_peermgr_cfgs(2);
+		let peers = create_network(2, &cfgs);
+		let (mut fd_a, mut fd_b) = establish_connection_and_read_events(&peers[0], &peers[1]);
+
+		peers[0].process_events();
+		peers[1].process_events();
+		peers[1].read_event(&mut fd_b, &fd_a.outbound_data.lock().unwrap().split_off(0)).unwrap();
+		peers[0].read_event(&mut fd_a, &fd_b.outbound_data.lock().unwrap().split_off(0)).unwrap();
+
+		assert_eq!(cfgs[0].routing_handler.chan_range_queries_recvd.load(Ordering::Acquire), 1);
+		assert_eq!(cfgs[1].routing_handler.chan_range_queries_recvd.load(Ordering::Acquire), 1);
+	}
+
+	#[test]
+	fn test_gossip_timestamp_filter() {
+		// A gossip_timestamp_filter starts a dump of our routing table, only including the gossip
+		// within the requested time range. The test routing handler's gossip has a timestamp of 0.
+		for &(first_timestamp, expected_anns, expected_upds) in [(0, 50, 100), (1, 0, 0)].iter() {
+			let cfgs = create_peermgr_cfgs(2);
+			let peers = create_network(2, &cfgs);
+			let (mut fd_a, mut fd_b) = establish_connection_and_read_events(&peers[0], &peers[1]);
+			let secp_ctx = Secp256k1::new();
+			let a_id = PublicKey::from_secret_key(&secp_ctx, &peers[0].our_node_secret);
+
+			cfgs[1].routing_handler.pending_events.lock().unwrap().push(events::MessageSendEvent::SendGossipTimestampFilter {
+				node_id: a_id,
+				msg: msgs::GossipTimestampFilter {
+					chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+					first_timestamp,
+					timestamp_range: 0xffff_ffff,
+				},
+			});
+			peers[1].process_events();
+			peers[0].read_event(&mut fd_a, &fd_b.outbound_data.lock().unwrap().split_off(0)).unwrap();
+			peers[1].read_event(&mut fd_b, &fd_a.outbound_data.lock().unwrap().split_off(0)).unwrap();
+
+			assert_eq!(cfgs[1].routing_handler.chan_anns_recvd.load(Ordering::Acquire), expected_anns);
+			assert_eq!(cfgs[1].routing_handler.chan_upds_recvd.load(Ordering::Acquire), expected_upds);
+			assert_eq!(cfgs[0].routing_handler.chan_anns_recvd.load(Ordering::Acquire), 0);
+		}
+	}
+
+	#[test]
+	fn test_gossip_timestamp_filter_sync_once() {
+		// Only the first gossip_timestamp_filter of a connection makes us send our routing table,
+		// later ones only change the filter applied to the gossip we relay.
+		let cfgs = create_peermgr_cfgs(2);
+		let peers = create_network(2, &cfgs);
+		let (mut fd_a, mut fd_b) = establish_connection_and_read_events(&peers[0], &peers[1]);
+		let secp_ctx = Secp256k1::new();
+		let a_id = PublicKey::from_secret_key(&secp_ctx, &peers[0].our_node_secret);
+
+		for _ in 0..2 {
+			cfgs[1].routing_handler.pending_events.lock().unwrap().push(events::MessageSendEvent::SendGossipTimestampFilter {
+				node_id: a_id,
+				msg: msgs::GossipTimestampFilter {
+					chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+					first_timestamp: 0,
+					timestamp_range: 0xffff_ffff,
+				},
+			});
+			peers[1].process_events();
+			peers[0].read_event(&mut fd_a, &fd_b.outbound_data.lock().unwrap().split_off(0)).unwrap();
+			peers[1].read_event(&mut fd_b, &fd_a.outbound_data.lock().unwrap().split_off(0)).unwrap();
+			// Let the test routing handler hand out its channels again.
+			cfgs[0].routing_handler.chan_anns_sent.store(0, Ordering::Release);
+		}
+
+		assert_eq!(cfgs[1].routing_handler.chan_anns_recvd.load(Ordering::Acquire), 50);
+		assert_eq!(cfgs[1].routing_handler.chan_upds_recvd.load(Ordering::Acquire), 100);
+	}
+
+	#[test]
+	fn test_query_short_channel_ids_reply() {
+		// A query_short_channel_ids is answered with the gossip for the known channels, streamed as
+		// the outbound buffer drains, then a reply_short_channel_ids_end. Queries received before
+		// we finished replying to the previous one are ignored.
+		let cfgs = create_peermgr_cfgs(2);
+		let peers = create_network(2, &cfgs);
+		let (mut fd_a, mut fd_b) = establish_connection_and_read_events(&peers[0], &peers[1]);
+		let secp_ctx = Secp256k1::new();
+		let a_id = PublicKey::from_secret_key(&secp_ctx, &peers[0].our_node_secret);
+
+		let query_short_channel_ids = |count: usize| {
+			for _ in 0..count {
+				cfgs[1].routing_handler.pending_events.lock().unwrap().push(events::MessageSendEvent::SendShortIdsQuery {
+					node_id: a_id,
+					msg: msgs::QueryShortChannelIds {
+						chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+						// The test routing handler only knows about channels 0 to 99.
+						short_channel_ids: (50..150).collect(),
+					},
+				});
+			}
+		};
+
+		query_short_channel_ids(2);
+		peers[1].process_events();
+		peers[0].read_event(&mut fd_a, &fd_b.outbound_data.lock().unwrap().split_off(0)).unwrap();
+		peers[1].read_event(&mut fd_b, &fd_a.outbound_data.lock().unwrap().split_off(0)).unwrap();
+		assert_eq!(cfgs[1].routing_handler.chan_anns_recvd.load(Ordering::Acquire), 50);
+		assert_eq!(cfgs[1].routing_handler.chan_upds_recvd.load(Ordering::Acquire), 50);
+		assert_eq!(cfgs[1].routing_handler.short_ids_reply_ends_recvd.load(Ordering::Acquire), 1);
+
+		// Once we replied, the peer may query us again.
+		query_short_channel_ids(1);
+		peers[1].process_events();
+		peers[0].read_event(&mut fd_a, &fd_b.outbound_data.lock().unwrap().split_off(0)).unwrap();
+		peers[1].read_event(&mut fd_b, &fd_a.outbound_data.lock().unwrap().split_off(0)).unwrap();
+		assert_eq!(cfgs[1].routing_handler.chan_anns_recvd.load(Ordering::Acquire), 100);
+		assert_eq!(cfgs[1].routing_handler.short_ids_reply_ends_recvd.load(Ordering::Acquire), 2);
+	}
+
+	#[test]
+	fn test_gossip_relay() {
+		// Connect peers 1 and 2 to peer 0 and check that gossip accepted by peer 0 is relayed to
+		// the other peer, but not back to the peer which sent it, and that each peer only gets it
+		// once even if it is broadcast twice.
+		let cfgs = create_peermgr_cfgs(3);
+		cfgs[0].routing_handler.forward_gossip.store(true, Ordering::Release);
+		cfgs[1].routing_handler.forward_gossip.store(true, Ordering::Release);
+		let peers = create_network(3, &cfgs);
+		let (mut fd_0_to_1, mut fd_1_to_0) = establish_connection_and_read_events(&peers[0], &peers[1]);
+		let (fd_0_to_2, mut fd_2_to_0) = establish_connection_and_read_events(&peers[0], &peers[2]);
+
+		for _ in 0..2 {
+			cfgs[1].chan_handler.pending_events.lock().unwrap().push(events::MessageSendEvent::BroadcastChannelUpdate {
+				msg: test_utils::get_dummy_channel_update(42),
+			});
+			peers[1].process_events();
+			peers[0].read_event(&mut fd_0_to_1, &fd_1_to_0.outbound_data.lock().unwrap().split_off(0)).unwrap();
+			peers[0].process_events();
+			peers[1].read_event(&mut fd_1_to_0, &fd_0_to_1.outbound_data.lock().unwrap().split_off(0)).unwrap();
+			peers[2].read_event(&mut fd_2_to_0, &fd_0_to_2.outbound_data.lock().unwrap().split_off(0)).unwrap();
+		}
+
+		// Peer 1 knows peer 0 got the update the first time, so doesn't send it again, and only
+		// handled it itself when broadcasting it.
+		assert_eq!(cfgs[0].routing_handler.chan_upds_recvd.load(Ordering::Acquire), 1);
+		assert_eq!(cfgs[1].routing_handler.chan_upds_recvd.load(Ordering::Acquire), 2);
+		assert_eq!(cfgs[2].routing_handler.chan_upds_recvd.load(Ordering::Acquire), 1);
+	}
+
+	#[test]
+	fn test_relay_channel_announcement() {
+		// Gossip the routing handler applied later, eg after an asynchronous UTXO lookup, is relayed
+		// to our peers without handing it to the routing handler again.
+		let cfgs = create_peermgr_cfgs(2);
+		let peers = create_network(2, &cfgs);
+		let (fd_0_to_1, mut fd_1_to_0) = establish_connection_and_read_events(&peers[0], &peers[1]);
+
+		cfgs[0].routing_handler.pending_events.lock().unwrap().push(events::MessageSendEvent::RelayChannelAnnouncement {
+			msg: test_utils::get_dummy_channel_announcement(42),
+			update_msgs: vec![test_utils::get_dummy_channel_update(42)],
+		});
+		peers[0].process_events();
+		peers[1].read_event(&mut fd_1_to_0, &fd_0_to_1.outbound_data.lock().unwrap().split_off(0)).unwrap();
+
+		assert_eq!(cfgs[0].routing_handler.chan_anns_recvd.load(Ordering::Acquire), 0);
+		assert_eq!(cfgs[0].routing_handler.chan_upds_recvd.load(Ordering::Acquire), 0);
+		assert_eq!(cfgs[1].routing_handler.chan_anns_recvd.load(Ordering::Acquire), 1);
+		assert_eq!(cfgs[1].routing_handler.chan_upds_recvd.load(Ordering::Acquire), 1);
+	}
+
+	#[test]
+	fn test_gossip_relay_limits() {
+		// Gossip isn't relayed to peers with a full outbound buffer, nor to peers to which we
+		// relayed too much gossip since the last timer tick.
+		let cfgs = create_peermgr_cfgs(2);
+		let peers = create_network(2, &cfgs);
+		let (fd_0_to_1, _) = establish_connection_and_read_events(&peers[0], &peers[1]);
+		let relay_update = |short_channel_id: u64| {
+			let mut peers_lock = peers[0].peers.lock().unwrap();
+			peers[0].forward_broadcast_msg(&mut *peers_lock, &wire::Message::ChannelUpdate(test_utils::get_dummy_channel_update(short_channel_id)));
+			let peer = peers_lock.peers.get(&fd_0_to_1).unwrap();
+			peer.pending_outbound_buffer.len() + peer.pending_gossip_buffer.len()
+		};
+
+		{
+			let mut peers_lock = peers[0].peers.lock().unwrap();
+			let peer = peers_lock.peers.get_mut(&fd_0_to_1).unwrap();
+			peer.awaiting_write_event = true;
+			assert!(peer.pending_outbound_buffer.is_empty());
+		}
+		assert_eq!(relay_update(1), 1);
+		// The same message isn't relayed twice.
+		assert_eq!(relay_update(1), 1);
+
+		{
+			let mut peers_lock = peers[0].peers.lock().unwrap();
+			let peer = peers_lock.peers.get_mut(&fd_0_to_1).unwrap();
+			for _ in 1..OUTBOUND_BUFFER_LIMIT_DROP_GOSSIP {
+				peer.pending_outbound_buffer.push_back(Vec::new());
+			}
+		}
+		assert_eq!(relay_update(2), OUTBOUND_BUFFER_LIMIT_DROP_GOSSIP);
+
+		{
+			let mut peers_lock = peers[0].peers.lock().unwrap();
+			let peer = peers_lock.peers.get_mut(&fd_0_to_1).unwrap();
+			peer.pending_outbound_buffer.clear();
+			peer.pending_gossip_buffer.clear();
+			peer.gossip_relayed_since_tick = MAX_GOSSIP_RELAY_PER_TICK;
+		}
+		assert_eq!(relay_update(3), 0);
+		peers[0].timer_tick_occured();
+		{
+			let mut peers_lock = peers[0].peers.lock().unwrap();
+			let peer = peers_lock.peers.get_mut(&fd_0_to_1).unwrap();
+			peer.pending_outbound_buffer.clear();
+			peer.pending_gossip_buffer.clear();
+		}
+		assert_eq!(relay_update(3), 1);
+	}
+
+	#[test]
+	fn test_inbound_connections_per_ip_limit() {
+		// Inbound connections beyond max_inbound_connections_per_ip from the same IP address are
+		// refused, while connections from other or unknown addresses are still accepted.
+		let cfgs = create_peermgr_cfgs(1);
+		let limits = PeerManagerLimits { max_inbound_connections_per_ip: 2, ..Default::default() };
+		let peers = create_network_with_limits(1, &cfgs, limits);
+		let new_fd = |fd: u16| FileDescriptor { fd, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+		let addr = |last_byte: u8| Some(msgs::NetAddress::IPv4 { addr: [10, 0, 0, last_byte], port: 9735 });
+
+		assert!(peers[0].new_inbound_connection_with_address(new_fd(1), addr(1)).is_ok());
+		assert!(peers[0].new_inbound_connection_with_address(new_fd(2), addr(1)).is_ok());
+		assert!(peers[0].new_inbound_connection_with_address(new_fd(3), addr(1)).is_err());
+		assert!(peers[0].new_inbound_connection_with_address(new_fd(4), addr(2)).is_ok());
+		assert!(peers[0].new_inbound_connection_with_address(new_fd(5), None).is_ok());
+		assert!(peers[0].new_inbound_connection_with_address(new_fd(6), Some(msgs::NetAddress::IPv6 {
+			addr: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 10, 0, 0, 1], port: 9735 })).is_err());
+		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 4);
+
+		// Connections from loopback addresses, eg through a Tor hidden service, aren't limited.
+		for fd in 10..13 {
+			assert!(peers[0].new_inbound_connection_with_address(new_fd(fd), Some(msgs::NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 })).is_ok());
+		}
+		let ipv6_loopback = Some(msgs::NetAddress::IPv6 { addr: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1], port: 9735 });
+		for fd in 13..16 {
+			assert!(peers[0].new_inbound_connection_with_address(new_fd(fd), ipv6_loopback.clone()).is_ok());
+		}
+		for fd in 10..16 {
+			peers[0].socket_disconnected(&new_fd(fd));
+		}
+
+		// Once a connection from the IP address is closed, we accept a new one.
+		peers[0].socket_disconnected(&new_fd(1));
+		assert!(peers[0].new_inbound_connection_with_address(new_fd(7), addr(1)).is_ok());
+		assert_eq!(peers[0].get_stats(), PeerManagerStats { connections_refused_per_ip: 2, ..Default::default() });
+	}
+
+	#[test]
+	fn test_handshake_timeout() {
+		// Peers which don't complete the handshake within max_handshake_timer_ticks are
+		// disconnected, while peers which did stay connected.
+		let cfgs = create_peermgr_cfgs(2);
+		let peers = create_network(2, &cfgs);
+		let (mut fd_0_to_1, mut fd_1_to_0) = establish_connection_and_read_events(&peers[0], &peers[1]);
+		let silent_fd = FileDescriptor { fd: 10, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+		peers[0].new_inbound_connection(silent_fd).unwrap();
+		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 2);
+
+		peers[0].timer_tick_occured();
+		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 2);
+
+		// Answer the ping so that peer 1 isn't disconnected for not responding to it.
+		peers[1].read_event(&mut fd_1_to_0, &fd_0_to_1.outbound_data.lock().unwrap().split_off(0)).unwrap();
+		peers[0].read_event(&mut fd_0_to_1, &fd_1_to_0.outbound_data.lock().unwrap().split_off(0)).unwrap();
+		peers[0].timer_tick_occured();
+		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 1);
+		assert!(peers[0].peers.lock().unwrap().peers.contains_key(&fd_0_to_1));
+		assert_eq!(peers[0].get_stats(), PeerManagerStats { peers_disconnected_handshake_timeout: 1, ..Default::default() });
+	}
+
+	#[test]
+	fn test_peers_without_channels_limit() {
+		// Once we have max_peers_without_channels peers we have no channels with, new inbound
+		// peers are disconnected after the noise handshake, unless we have channels with them.
+		let cfgs = create_peermgr_cfgs(4);
+		let limits = PeerManagerLimits { max_peers_without_channels: 1, ..Default::default() };
+		let peers = create_network_with_limits(4, &cfgs, limits);
+		let secp_ctx = Secp256k1::new();
+		let a_id = PublicKey::from_secret_key(&secp_ctx, &peers[0].our_node_secret);
+		let id_2 = PublicKey::from_secret_key(&secp_ctx, &peers[2].our_node_secret);
+		cfgs[0].chan_handler.peers_with_channels.lock().unwrap().insert(id_2);
+
+		let (fd_0_to_1, _) = establish_connection(&peers[0], &peers[1]);
+		establish_connection(&peers[0], &peers[2]);
+		assert_eq!(peers[0].get_peer_node_ids().len(), 2);
+
+		let mut fd_a = FileDescriptor { fd: 3, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+		let mut fd_b = FileDescriptor { fd: 1, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+		let initial_data = peers[3].new_outbound_connection(a_id, fd_b.clone()).unwrap();
+		peers[0].new_inbound_connection(fd_a.clone()).unwrap();
+		assert_eq!(peers[0].read_event(&mut fd_a, &initial_data).unwrap(), false);
+		assert_eq!(peers[3].read_event(&mut fd_b, &fd_a.outbound_data.lock().unwrap().split_off(0)).unwrap(), false);
+		assert!(peers[0].read_event(&mut fd_a, &fd_b.outbound_data.lock().unwrap().split_off(0)).is_err());
+
+		assert_eq!(peers[0].get_peer_node_ids().len(), 2);
+		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 2);
+		assert_eq!(peers[0].get_stats(), PeerManagerStats { peers_refused_without_channels: 1, ..Default::default() });
+
+		// Once the peer without channels disconnects, there is room for another one
+		peers[0].socket_disconnected(&fd_0_to_1);
+		peers[3].socket_disconnected(&fd_b);
+		let mut fd_a = FileDescriptor { fd: 4, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+		let mut fd_b = FileDescriptor { fd: 2, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+		let initial_data = peers[3].new_outbound_connection(a_id, fd_b.clone()).unwrap();
+		peers[0].new_inbound_connection(fd_a.clone()).unwrap();
+		assert_eq!(peers[0].read_event(&mut fd_a, &initial_data).unwrap(), false);
+		assert_eq!(peers[3].read_event(&mut fd_b, &fd_a.outbound_data.lock().unwrap().split_off(0)).unwrap(), false);
+		assert_eq!(peers[0].read_event(&mut fd_a, &fd_b.outbound_data.lock().unwrap().split_off(0)).unwrap(), false);
+		assert_eq!(peers[0].get_peer_node_ids().len(), 2);
+	}
+
+	#[test]
+	fn test_outbound_buffer_limit() {
+		// Gossip isn't relayed to peers with more than half of max_outbound_buffer_bytes buffered,
+		// and peers with more than max_outbound_buffer_bytes buffered are disconnected.
+		let cfgs = create_peermgr_cfgs(2);
+		let limits = PeerManagerLimits { max_outbound_buffer_bytes: 10_000, ..Default::default() };
+		let peers = create_network_with_limits(2, &cfgs, limits);
+		let (fd_0_to_1, _) = establish_connection_and_read_events(&peers[0], &peers[1]);
+
+		{
+			let mut peers_lock = peers[0].peers.lock().unwrap();
+			let peer = peers_lock.peers.get_mut(&fd_0_to_1).unwrap();
+			peer.awaiting_write_event = true;
+			peer.pending_outbound_buffer.push_back(vec![0; 6_000]);
+		}
+		{
+			let mut peers_lock = peers[0].peers.lock().unwrap();
+			peers[0].forward_broadcast_msg(&mut *peers_lock, &wire::Message::ChannelUpdate(test_utils::get_dummy_channel_update(1)));
+			assert_eq!(peers_lock.peers.get(&fd_0_to_1).unwrap().pending_outbound_buffer.len(), 1);
+		}
+		peers[0].process_events();
+		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 1);
+		assert_eq!(peers[0].get_stats(), PeerManagerStats { gossip_messages_dropped: 1, ..Default::default() });
+
+		peers[0].peers.lock().unwrap().peers.get_mut(&fd_0_to_1).unwrap().pending_outbound_buffer.push_back(vec![0; 6_000]);
+		peers[0].process_events();
+		assert!(peers[0].peers.lock().unwrap().peers.is_empty());
+		assert!(peers[0].get_peer_node_ids().is_empty());
+		assert_eq!(peers[0].get_stats(), PeerManagerStats { gossip_messages_dropped: 1, peers_disconnected_outbound_buffer_full: 1, ..Default::default() });
+	}
+
+	#[test]
+	fn test_outbound_buffer_limit_drops_gossip_first() {
+		// Messages aren't queued past max_outbound_buffer_bytes: we first drop the gossip we have
+		// yet to send the peer, and only disconnect it if that doesn't make enough room.
+		let cfgs = create_peermgr_cfgs(2);
+		let limits = PeerManagerLimits { max_outbound_buffer_bytes: 10_000, ..Default::default() };
+		let peers = create_network_with_limits(2, &cfgs, limits);
+		let (fd_0_to_1, _) = establish_connection_and_read_events(&peers[0], &peers[1]);
+		let secp_ctx = Secp256k1::new();
+		let their_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
+		let funding_locked_event = || events::MessageSendEvent::SendFundingLocked {
+			node_id: their_id,
+			msg: msgs::FundingLocked { channel_id: [0; 32], next_per_commitment_point: their_id },
+		};
+		// Send the gossip queries generated on connection first.
+		peers[0].process_events();
+
+		{
+			let mut peers_lock = peers[0].peers.lock().unwrap();
+			{
+				let peer = peers_lock.peers.get_mut(&fd_0_to_1).unwrap();
+				peer.awaiting_write_event = true;
+				peer.pending_outbound_buffer.push_back(vec![0; 4_000]);
+			}
+			peers[0].forward_broadcast_msg(&mut *peers_lock, &wire::Message::ChannelUpdate(test_utils::get_dummy_channel_update(1)));
+			let peer = peers_lock.peers.get_mut(&fd_0_to_1).unwrap();
+			assert_eq!(peer.pending_gossip_buffer.len(), 1);
+			peer.pending_outbound_buffer.push_back(vec![0; 5_850]);
+		}
+
+		// There is only room for the funding_locked without the channel_update.
+		cfgs[0].chan_handler.pending_events.lock().unwrap().push(funding_locked_event());
+		peers[0].process_events();
+		{
+			let peers_lock = peers[0].peers.lock().unwrap();
+			let peer = peers_lock.peers.get(&fd_0_to_1).unwrap();
+			assert!(peer.pending_gossip_buffer.is_empty());
+			assert_eq!(peer.pending_outbound_buffer.len(), 3);
+		}
+		assert_eq!(peers[0].get_stats(), PeerManagerStats { gossip_messages_dropped: 1, ..Default::default() });
+
+		// Once there is no gossip left to drop, we disconnect the peer instead.
+		cfgs[0].chan_handler.pending_events.lock().unwrap().push(funding_locked_event());
+		peers[0].process_events();
+		assert!(peers[0].peers.lock().unwrap().peers.is_empty());
+		assert_eq!(peers[0].get_stats(), PeerManagerStats { gossip_messages_dropped: 1, peers_disconnected_outbound_buffer_full: 1, ..Default::default() });
+	}
+
+	#[test]
+	fn test_outbound_buffer_limit_on_read() {
+		// Responses to a peer's messages aren't queued past max_outbound_buffer_bytes either, the
+		// peer is disconnected as soon as we would.
+		let cfgs = create_peermgr_cfgs(2);
+		let limits = PeerManagerLimits { max_outbound_buffer_bytes: 10_000, ..Default::default() };
+		let peers = create_network_with_limits(2, &cfgs, limits);
+		let (mut fd_0_to_1, fd_1_to_0) = establish_connection_and_read_events(&peers[0], &peers[1]);
+		peers[0].peers.lock().unwrap().peers.get_mut(&fd_0_to_1).unwrap().awaiting_write_event = true;
+
+		let mut pings = Vec::new();
+		{
+			let mut peers_lock = peers[1].peers.lock().unwrap();
+			let peer = peers_lock.peers.get_mut(&fd_1_to_0).unwrap();
+			for _ in 0..2 {
+				pings.extend_from_slice(&peer.channel_encryptor.encrypt_message(&encode_msg!(&msgs::Ping { ponglen: 6_000, byteslen: 0 })));
+			}
+		}
+		assert!(peers[0].read_event(&mut fd_0_to_1, &pings).is_err());
+		assert!(peers[0].peers.lock().unwrap().peers.is_empty());
+		assert_eq!(peers[0].get_stats(), PeerManagerStats { peers_disconnected_outbound_buffer_full: 1, ..Default::default() });
+	}
 }
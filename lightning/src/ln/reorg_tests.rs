@@ -9,14 +9,19 @@
 
 //! Further functional tests which test blockchain reorganizations.
 
-use chain::channelmonitor::ANTI_REORG_DELAY;
+use chain::chaininterface::{ConfirmationTarget, FeeEstimator};
+use chain::channelmonitor::{ANTI_REORG_DELAY, Balance};
 use ln::features::InitFeatures;
 use ln::msgs::{ChannelMessageHandler, ErrorAction, HTLCFailChannelUpdate};
 use util::events::{Event, EventsProvider, MessageSendEvent, MessageSendEventsProvider};
 
+use util::test_utils;
+
 use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::transaction::Transaction;
 
 use std::default::Default;
+use std::sync::Mutex;
 
 use ln::functional_test_utils::*;
 
@@ -180,3 +185,152 @@ fn test_onchain_htlc_claim_reorg_remote_commitment() {
 fn test_onchain_htlc_timeout_delay_remote_commitment() {
 	do_test_onchain_htlc_reorg(false, false);
 }
+
+// A fee estimator whose estimate may be changed while a node uses it, to simulate fee spikes.
+struct SpikingFeeEstimator {
+	sat_per_kw: Mutex<u32>,
+}
+impl FeeEstimator for SpikingFeeEstimator {
+	fn get_est_sat_per_1000_weight(&self, _confirmation_target: ConfirmationTarget) -> u32 {
+		*self.sat_per_kw.lock().unwrap()
+	}
+}
+
+// Drains the transactions broadcast by the given node, returning the feerates of those claiming
+// outputs of the given commitment transaction. Note that as fees are computed against a predicted
+// weight, the feerates may be off by one from the ones we targeted.
+fn get_claim_feerates<'a, 'b, 'c>(node: &Node<'a, 'b, 'c>, commitment_tx: &Transaction) -> Vec<u64> {
+	let mut node_txn = node.tx_broadcaster.txn_broadcasted.lock().unwrap();
+	node_txn.drain(..).filter(|tx| tx.input[0].previous_output.txid == commitment_tx.txid()).map(|tx| {
+		check_spends!(tx, commitment_tx);
+		let fee = commitment_tx.output[tx.input[0].previous_output.vout as usize].value - tx.output[0].value;
+		fee * 1000 / tx.get_weight() as u64
+	}).collect()
+}
+
+// Sets up a channel between nodes[0] and nodes[1] with an HTLC from nodes[0] to nodes[1] which
+// nodes[1] learned the preimage of, returning nodes[0]'s commitment transaction (which nodes[1]
+// will have to claim the HTLC from) and the HTLC's CLTV expiry (after which nodes[0] may time it
+// out).
+macro_rules! setup_htlc_claim {
+	($nodes: expr) => { {
+		let chan = create_announced_chan_between_nodes_with_value(&$nodes, 0, 1, 1_000_000, 0, InitFeatures::known(), InitFeatures::known());
+		let (payment_preimage, _) = route_payment(&$nodes[0], &[&$nodes[1]], 80_000_000);
+		let cltv_expiry = match $nodes[0].chain_monitor.chain_monitor.get_claimable_balances().iter().filter_map(|balance| match balance {
+			&Balance::MaybeClaimableHTLCAwaitingTimeout { claimable_height, .. } => Some(claimable_height),
+			_ => None,
+		}).next() {
+			Some(cltv_expiry) => cltv_expiry,
+			None => panic!("Missing HTLC balance"),
+		};
+
+		assert!($nodes[1].node.claim_funds(payment_preimage, &None, 80_000_000));
+		check_added_monitors!($nodes[1], 1);
+		$nodes[1].node.get_and_clear_pending_msg_events();
+
+		let commitment_tx = get_local_commitment_txn!($nodes[0], chan.2)[0].clone();
+		check_spends!(commitment_tx, chan.3);
+		(commitment_tx, cltv_expiry)
+	} }
+}
+
+#[test]
+fn test_claim_bump_congestion() {
+	// Tests that a claim transaction which doesn't confirm while feerates spike is bumped to at
+	// least the new estimate, and then more and more frequently and aggressively as the HTLC's
+	// expiry approaches, even once the spike is over.
+	let fee_estimator = SpikingFeeEstimator { sat_per_kw: Mutex::new(253) };
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let mut node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	node_cfgs[1].chain_monitor = test_utils::TestChainMonitor::new(Some(&chanmon_cfgs[1].chain_source), &chanmon_cfgs[1].tx_broadcaster, &chanmon_cfgs[1].logger, &fee_estimator, &chanmon_cfgs[1].persister);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let (commitment_tx, cltv_expiry) = setup_htlc_claim!(nodes);
+
+	let conf_height = cltv_expiry - 20;
+	let header = BlockHeader { version: 0x2000_0000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[1], &Block { header, txdata: vec![commitment_tx.clone()] }, conf_height);
+	check_closed_broadcast!(nodes[1], false);
+	check_added_monitors!(nodes[1], 1);
+	let feerates = get_claim_feerates(&nodes[1], &commitment_tx);
+	assert_eq!(feerates.len(), 1);
+	assert!(feerates[0] < 300);
+
+	// Feerates spike, but we only bump once our 15-block timer expires...
+	*fee_estimator.sat_per_kw.lock().unwrap() = 10_000;
+	let block_hash = connect_blocks(&nodes[1], 14, conf_height, true, header.block_hash());
+	assert!(get_claim_feerates(&nodes[1], &commitment_tx).is_empty());
+	// ...at which point we use the new estimate.
+	let block_hash = connect_blocks(&nodes[1], 1, conf_height + 14, true, block_hash);
+	let feerates = get_claim_feerates(&nodes[1], &commitment_tx);
+	assert_eq!(feerates.len(), 1);
+	let spike_feerate = feerates[0];
+	assert!(spike_feerate + 1 >= 10_000);
+
+	// The spike is over, but as the expiry is now 5 blocks away we bump 3 blocks later, with
+	// only 2 blocks left, doubling the feerate...
+	*fee_estimator.sat_per_kw.lock().unwrap() = 253;
+	let block_hash = connect_blocks(&nodes[1], 2, cltv_expiry - 5, true, block_hash);
+	assert!(get_claim_feerates(&nodes[1], &commitment_tx).is_empty());
+	let block_hash = connect_blocks(&nodes[1], 1, cltv_expiry - 3, true, block_hash);
+	let feerates = get_claim_feerates(&nodes[1], &commitment_tx);
+	assert_eq!(feerates.len(), 1);
+	assert!(feerates[0] * 100 >= spike_feerate * 199);
+	let mut prev_feerate = feerates[0];
+
+	// ...and then keep doing so every block, up to the expiry.
+	let mut block_hash = block_hash;
+	for height in cltv_expiry - 1..cltv_expiry + 1 {
+		block_hash = connect_blocks(&nodes[1], 1, height - 1, true, block_hash);
+		let feerates = get_claim_feerates(&nodes[1], &commitment_tx);
+		assert_eq!(feerates.len(), 1);
+		assert!(feerates[0] * 100 >= prev_feerate * 199);
+		prev_feerate = feerates[0];
+	}
+}
+
+#[test]
+fn test_claim_bump_reorg() {
+	// Tests that if a reorg has the commitment transaction we're claiming from confirm closer to
+	// the HTLC's expiry, the new claim transaction's bumps follow the new, shorter, deadline.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let (commitment_tx, cltv_expiry) = setup_htlc_claim!(nodes);
+
+	let conf_height = cltv_expiry - 20;
+	let header = BlockHeader { version: 0x2000_0000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[1], &Block { header, txdata: vec![commitment_tx.clone()] }, conf_height);
+	check_closed_broadcast!(nodes[1], false);
+	check_added_monitors!(nodes[1], 1);
+	let header_1 = BlockHeader { prev_blockhash: header.block_hash(), ..header };
+	connect_block(&nodes[1], &Block { header: header_1, txdata: vec![] }, conf_height + 1);
+	assert_eq!(get_claim_feerates(&nodes[1], &commitment_tx).len(), 1);
+
+	// Reorg out both blocks, to have the commitment transaction confirm 16 blocks later on the new
+	// best chain, 4 blocks before the HTLC's expiry.
+	disconnect_block(&nodes[1], &header_1, conf_height + 1);
+	disconnect_block(&nodes[1], &header, conf_height);
+	let block_hash = connect_blocks(&nodes[1], 15, conf_height - 1, false, Default::default());
+	assert!(get_claim_feerates(&nodes[1], &commitment_tx).is_empty());
+	let header = BlockHeader { prev_blockhash: block_hash, ..header };
+	connect_block(&nodes[1], &Block { header, txdata: vec![commitment_tx.clone()] }, cltv_expiry - 4);
+	let feerates = get_claim_feerates(&nodes[1], &commitment_tx);
+	assert_eq!(feerates.len(), 1);
+	let mut prev_feerate = feerates[0];
+
+	// We bump 3 blocks later, rather than 15, and then every block, at least doubling the feerate
+	// in each of the last blocks.
+	let block_hash = connect_blocks(&nodes[1], 2, cltv_expiry - 4, true, header.block_hash());
+	assert!(get_claim_feerates(&nodes[1], &commitment_tx).is_empty());
+	let block_hash = connect_blocks(&nodes[1], 1, cltv_expiry - 2, true, block_hash);
+	let feerates = get_claim_feerates(&nodes[1], &commitment_tx);
+	assert_eq!(feerates.len(), 1);
+	assert!(feerates[0] > prev_feerate);
+	prev_feerate = feerates[0];
+	connect_blocks(&nodes[1], 1, cltv_expiry - 1, true, block_hash);
+	let feerates = get_claim_feerates(&nodes[1], &commitment_tx);
+	assert_eq!(feerates.len(), 1);
+	assert!(feerates[0] * 100 >= prev_feerate * 199);
+}
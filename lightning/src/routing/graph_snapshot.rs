@@ -0,0 +1,454 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Compact snapshots of the network graph, letting light clients bootstrap their graph with a
+//! single download instead of syncing all the gossip from their peers.
+//!
+//! A server with a synced [`NetworkGraph`] generates snapshots with [`write_snapshot`], either of
+//! the full graph or of the changes since a given time, which clients apply with
+//! [`apply_snapshot`] (or [`NetGraphMsgHandler::from_snapshot`] for the first one).
+//!
+//! Snapshots don't include signatures, so clients have to trust the server they got them from.
+//! For the same reason, the channels and updates loaded from a snapshot are used for routing but
+//! never relayed to our peers. Node announcements aren't included, they are received through
+//! gossip as usual.
+//!
+//! The snapshot format is as follows, where integers are big-endian and `varint` is a BigSize:
+//!  * version: u8 (currently 1)
+//!  * chain_hash: 32 bytes
+//!  * latest_seen_timestamp: u32, the timestamp of the latest channel_update included
+//!  * node_count: varint, followed by as many 33-byte node_ids, referred to by index below
+//!  * channel_count: varint, followed by as many channels, sorted by short_channel_id:
+//!    * short_channel_id delta from the previous channel (or 0): varint
+//!    * node_id_1 index: varint
+//!    * node_id_2 index: varint
+//!    * features: u16 length-prefixed feature bytes
+//!    * capacity_sats plus one, or 0 if unknown: varint
+//!  * the channel_update fields most of the updates have, used for the fields updates omit:
+//!    * cltv_expiry_delta: u16
+//!    * htlc_minimum_msat: varint
+//!    * fee_base_msat: u32
+//!    * fee_proportional_millionths: u32
+//!    * htlc_maximum_msat plus one, or 0 if absent: varint
+//!  * update_count: varint, followed by as many channel_updates, sorted by short_channel_id:
+//!    * short_channel_id delta from the previous update (or 0): varint
+//!    * flags: u8, the channel_update flags in bits 0 and 1, followed by one bit per field above,
+//!      set if the update has a different value, which then follows
+//!    * latest_seen_timestamp minus the update's timestamp: varint
+//!    * the fields which differ from the defaults, in order
+//!
+//! [`NetworkGraph`]: ../network_graph/struct.NetworkGraph.html
+//! [`write_snapshot`]: fn.write_snapshot.html
+//! [`apply_snapshot`]: fn.apply_snapshot.html
+//! [`NetGraphMsgHandler::from_snapshot`]: ../network_graph/struct.NetGraphMsgHandler.html#method.from_snapshot
+
+use bitcoin::secp256k1::key::PublicKey;
+use bitcoin::hash_types::BlockHash;
+
+use ln::features::ChannelFeatures;
+use ln::msgs::{DecodeError, OptionalField, UnsignedChannelUpdate};
+use routing::network_graph::{DirectionalChannelInfo, NetworkGraph};
+use util::ser::{BigSize, Readable, Writeable};
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Cursor;
+
+const SNAPSHOT_VERSION: u8 = 1;
+
+const CHANNEL_UPDATE_FLAGS_MASK: u8 = 0b11;
+const CLTV_EXPIRY_DELTA_PRESENT: u8 = 1 << 2;
+const HTLC_MINIMUM_MSAT_PRESENT: u8 = 1 << 3;
+const FEE_BASE_MSAT_PRESENT: u8 = 1 << 4;
+const FEE_PROPORTIONAL_MILLIONTHS_PRESENT: u8 = 1 << 5;
+const HTLC_MAXIMUM_MSAT_PRESENT: u8 = 1 << 6;
+
+/// The channel_update fields we deduplicate across updates.
+#[derive(Clone, PartialEq)]
+struct UpdateFields {
+	cltv_expiry_delta: u16,
+	htlc_minimum_msat: u64,
+	fee_base_msat: u32,
+	fee_proportional_millionths: u32,
+	htlc_maximum_msat: Option<u64>,
+}
+
+impl UpdateFields {
+	fn from_info(info: &DirectionalChannelInfo) -> Self {
+		UpdateFields {
+			cltv_expiry_delta: info.cltv_expiry_delta,
+			htlc_minimum_msat: info.htlc_minimum_msat,
+			fee_base_msat: info.fees.base_msat,
+			fee_proportional_millionths: info.fees.proportional_millionths,
+			htlc_maximum_msat: info.htlc_maximum_msat,
+		}
+	}
+}
+
+/// Returns the most common value in the given iterator, the smallest one among ties, or the
+/// default value if it's empty.
+fn most_common<T: Ord + Default, I: Iterator<Item=T>>(values: I) -> T {
+	let mut counts = BTreeMap::new();
+	for value in values {
+		*counts.entry(value).or_insert(0u64) += 1;
+	}
+	let mut most_common = None;
+	for (value, count) in counts {
+		let is_more_common = match most_common {
+			Some((_, max_count)) => count > max_count,
+			None => true,
+		};
+		if is_more_common {
+			most_common = Some((value, count));
+		}
+	}
+	most_common.map(|(value, _)| value).unwrap_or(T::default())
+}
+
+fn option_to_varint(value: Option<u64>) -> BigSize {
+	BigSize(value.map(|value| value + 1).unwrap_or(0))
+}
+
+fn varint_to_option(value: BigSize) -> Option<u64> {
+	if value.0 == 0 { None } else { Some(value.0 - 1) }
+}
+
+/// Serializes the channels of the given network graph into a snapshot for light clients.
+///
+/// Only the channels and channel updates newer than `since_timestamp` are included, so that
+/// clients which applied a previous snapshot can catch up with the latest_seen_timestamp it
+/// returned. Channels are considered newer if one of their updates is, or always if
+/// `since_timestamp` is 0.
+pub fn write_snapshot(network_graph: &NetworkGraph, chain_hash: &BlockHash, since_timestamp: u32) -> Vec<u8> {
+	let is_new = |info: &Option<DirectionalChannelInfo>| match info {
+		&Some(ref info) => info.last_update >= since_timestamp,
+		&None => false,
+	};
+	let channels: Vec<_> = network_graph.get_channels().iter().filter(|&(_, chan)| {
+		since_timestamp == 0 || is_new(&chan.one_to_two) || is_new(&chan.two_to_one)
+	}).collect();
+	let mut updates = Vec::new();
+	for (short_channel_id, chan) in network_graph.get_channels().iter() {
+		for &(direction, ref info) in [(0, &chan.one_to_two), (1, &chan.two_to_one)].iter() {
+			if is_new(info) {
+				updates.push((*short_channel_id, direction, info.as_ref().unwrap()));
+			}
+		}
+	}
+
+	let mut node_ids = Vec::new();
+	for &(_, chan) in channels.iter() {
+		node_ids.push(chan.node_one);
+		node_ids.push(chan.node_two);
+	}
+	node_ids.sort_unstable_by(|a, b| a.serialize()[..].cmp(&b.serialize()[..]));
+	node_ids.dedup();
+	let node_indexes: HashMap<PublicKey, u64> = node_ids.iter().enumerate().map(|(index, node_id)| (*node_id, index as u64)).collect();
+
+	let latest_seen_timestamp = updates.iter().map(|&(_, _, info)| info.last_update).max().unwrap_or(since_timestamp);
+	let defaults = UpdateFields {
+		cltv_expiry_delta: most_common(updates.iter().map(|&(_, _, info)| info.cltv_expiry_delta)),
+		htlc_minimum_msat: most_common(updates.iter().map(|&(_, _, info)| info.htlc_minimum_msat)),
+		fee_base_msat: most_common(updates.iter().map(|&(_, _, info)| info.fees.base_msat)),
+		fee_proportional_millionths: most_common(updates.iter().map(|&(_, _, info)| info.fees.proportional_millionths)),
+		htlc_maximum_msat: most_common(updates.iter().map(|&(_, _, info)| info.htlc_maximum_msat)),
+	};
+
+	// Writing to a Vec never fails.
+	let mut snapshot = Vec::new();
+	SNAPSHOT_VERSION.write(&mut snapshot).unwrap();
+	chain_hash.write(&mut snapshot).unwrap();
+	latest_seen_timestamp.write(&mut snapshot).unwrap();
+
+	BigSize(node_ids.len() as u64).write(&mut snapshot).unwrap();
+	for node_id in node_ids.iter() {
+		node_id.write(&mut snapshot).unwrap();
+	}
+
+	BigSize(channels.len() as u64).write(&mut snapshot).unwrap();
+	let mut previous_short_channel_id = 0;
+	for &(short_channel_id, chan) in channels.iter() {
+		BigSize(*short_channel_id - previous_short_channel_id).write(&mut snapshot).unwrap();
+		previous_short_channel_id = *short_channel_id;
+		BigSize(node_indexes[&chan.node_one]).write(&mut snapshot).unwrap();
+		BigSize(node_indexes[&chan.node_two]).write(&mut snapshot).unwrap();
+		chan.features.write(&mut snapshot).unwrap();
+		option_to_varint(chan.capacity_sats).write(&mut snapshot).unwrap();
+	}
+
+	defaults.cltv_expiry_delta.write(&mut snapshot).unwrap();
+	BigSize(defaults.htlc_minimum_msat).write(&mut snapshot).unwrap();
+	defaults.fee_base_msat.write(&mut snapshot).unwrap();
+	defaults.fee_proportional_millionths.write(&mut snapshot).unwrap();
+	option_to_varint(defaults.htlc_maximum_msat).write(&mut snapshot).unwrap();
+
+	BigSize(updates.len() as u64).write(&mut snapshot).unwrap();
+	let mut previous_short_channel_id = 0;
+	for &(short_channel_id, direction, info) in updates.iter() {
+		BigSize(short_channel_id - previous_short_channel_id).write(&mut snapshot).unwrap();
+		previous_short_channel_id = short_channel_id;
+
+		let fields = UpdateFields::from_info(info);
+		let mut flags = direction | if info.enabled { 0 } else { 1 << 1 };
+		if fields.cltv_expiry_delta != defaults.cltv_expiry_delta { flags |= CLTV_EXPIRY_DELTA_PRESENT; }
+		if fields.htlc_minimum_msat != defaults.htlc_minimum_msat { flags |= HTLC_MINIMUM_MSAT_PRESENT; }
+		if fields.fee_base_msat != defaults.fee_base_msat { flags |= FEE_BASE_MSAT_PRESENT; }
+		if fields.fee_proportional_millionths != defaults.fee_proportional_millionths { flags |= FEE_PROPORTIONAL_MILLIONTHS_PRESENT; }
+		if fields.htlc_maximum_msat != defaults.htlc_maximum_msat { flags |= HTLC_MAXIMUM_MSAT_PRESENT; }
+		flags.write(&mut snapshot).unwrap();
+		BigSize((latest_seen_timestamp - info.last_update) as u64).write(&mut snapshot).unwrap();
+
+		if flags & CLTV_EXPIRY_DELTA_PRESENT != 0 { fields.cltv_expiry_delta.write(&mut snapshot).unwrap(); }
+		if flags & HTLC_MINIMUM_MSAT_PRESENT != 0 { BigSize(fields.htlc_minimum_msat).write(&mut snapshot).unwrap(); }
+		if flags & FEE_BASE_MSAT_PRESENT != 0 { fields.fee_base_msat.write(&mut snapshot).unwrap(); }
+		if flags & FEE_PROPORTIONAL_MILLIONTHS_PRESENT != 0 { fields.fee_proportional_millionths.write(&mut snapshot).unwrap(); }
+		if flags & HTLC_MAXIMUM_MSAT_PRESENT != 0 { option_to_varint(fields.htlc_maximum_msat).write(&mut snapshot).unwrap(); }
+	}
+
+	snapshot
+}
+
+/// Applies a snapshot generated by [`write_snapshot`] to the given network graph, without
+/// checking any signature. Channels we already know about are left as is, and updates are only
+/// applied if they're newer than the ones we have.
+///
+/// Returns the latest_seen_timestamp of the snapshot, to use as `since_timestamp` when fetching
+/// the next one. The graph isn't modified if the snapshot is invalid or for another chain.
+///
+/// [`write_snapshot`]: fn.write_snapshot.html
+pub fn apply_snapshot(network_graph: &mut NetworkGraph, chain_hash: &BlockHash, snapshot: &[u8]) -> Result<u32, DecodeError> {
+	let mut reader = Cursor::new(snapshot);
+	let version: u8 = Readable::read(&mut reader)?;
+	if version != SNAPSHOT_VERSION {
+		return Err(DecodeError::UnknownVersion);
+	}
+	let snapshot_chain_hash: BlockHash = Readable::read(&mut reader)?;
+	if snapshot_chain_hash != *chain_hash {
+		return Err(DecodeError::InvalidValue);
+	}
+	let latest_seen_timestamp: u32 = Readable::read(&mut reader)?;
+
+	// Don't trust the counts to preallocate, a bogus snapshot could make us run out of memory.
+	let node_count: BigSize = Readable::read(&mut reader)?;
+	let mut node_ids = Vec::new();
+	for _ in 0..node_count.0 {
+		let node_id: PublicKey = Readable::read(&mut reader)?;
+		node_ids.push(node_id);
+	}
+	let get_node_id = |index: BigSize| -> Result<PublicKey, DecodeError> {
+		node_ids.get(index.0 as usize).cloned().ok_or(DecodeError::InvalidValue)
+	};
+
+	let channel_count: BigSize = Readable::read(&mut reader)?;
+	let mut channels = Vec::new();
+	let mut short_channel_id = 0u64;
+	for _ in 0..channel_count.0 {
+		let delta: BigSize = Readable::read(&mut reader)?;
+		short_channel_id = short_channel_id.checked_add(delta.0).ok_or(DecodeError::InvalidValue)?;
+		let node_one = get_node_id(Readable::read(&mut reader)?)?;
+		let node_two = get_node_id(Readable::read(&mut reader)?)?;
+		let features: ChannelFeatures = Readable::read(&mut reader)?;
+		let capacity_sats = varint_to_option(Readable::read(&mut reader)?);
+		channels.push((short_channel_id, features, node_one, node_two, capacity_sats));
+	}
+
+	let defaults = UpdateFields {
+		cltv_expiry_delta: Readable::read(&mut reader)?,
+		htlc_minimum_msat: { let value: BigSize = Readable::read(&mut reader)?; value.0 },
+		fee_base_msat: Readable::read(&mut reader)?,
+		fee_proportional_millionths: Readable::read(&mut reader)?,
+		htlc_maximum_msat: varint_to_option(Readable::read(&mut reader)?),
+	};
+
+	let update_count: BigSize = Readable::read(&mut reader)?;
+	let mut updates = Vec::new();
+	let mut short_channel_id = 0u64;
+	for _ in 0..update_count.0 {
+		let delta: BigSize = Readable::read(&mut reader)?;
+		short_channel_id = short_channel_id.checked_add(delta.0).ok_or(DecodeError::InvalidValue)?;
+		let flags: u8 = Readable::read(&mut reader)?;
+		let timestamp_delta: BigSize = Readable::read(&mut reader)?;
+		if timestamp_delta.0 > latest_seen_timestamp as u64 {
+			return Err(DecodeError::InvalidValue);
+		}
+
+		let mut fields = defaults.clone();
+		if flags & CLTV_EXPIRY_DELTA_PRESENT != 0 { fields.cltv_expiry_delta = Readable::read(&mut reader)?; }
+		if flags & HTLC_MINIMUM_MSAT_PRESENT != 0 { let value: BigSize = Readable::read(&mut reader)?; fields.htlc_minimum_msat = value.0; }
+		if flags & FEE_BASE_MSAT_PRESENT != 0 { fields.fee_base_msat = Readable::read(&mut reader)?; }
+		if flags & FEE_PROPORTIONAL_MILLIONTHS_PRESENT != 0 { fields.fee_proportional_millionths = Readable::read(&mut reader)?; }
+		if flags & HTLC_MAXIMUM_MSAT_PRESENT != 0 { fields.htlc_maximum_msat = varint_to_option(Readable::read(&mut reader)?); }
+
+		updates.push(UnsignedChannelUpdate {
+			chain_hash: *chain_hash,
+			short_channel_id,
+			timestamp: latest_seen_timestamp - timestamp_delta.0 as u32,
+			flags: flags & CHANNEL_UPDATE_FLAGS_MASK,
+			cltv_expiry_delta: fields.cltv_expiry_delta,
+			htlc_minimum_msat: fields.htlc_minimum_msat,
+			htlc_maximum_msat: match fields.htlc_maximum_msat {
+				Some(htlc_maximum_msat) => OptionalField::Present(htlc_maximum_msat),
+				None => OptionalField::Absent,
+			},
+			fee_base_msat: fields.fee_base_msat,
+			fee_proportional_millionths: fields.fee_proportional_millionths,
+			excess_data: Vec::new(),
+		});
+	}
+
+	// Errors only mean we already know about the channel or have a more recent update (or that the
+	// snapshot has an update for a channel we don't know about), which we can safely ignore.
+	for (short_channel_id, features, node_one, node_two, capacity_sats) in channels.drain(..) {
+		let _ = network_graph.add_channel_unannounced(short_channel_id, features, node_one, node_two, capacity_sats);
+	}
+	for update in updates.iter() {
+		let _ = network_graph.update_channel_unsigned(update);
+	}
+
+	Ok(latest_seen_timestamp)
+}
+
+#[cfg(test)]
+mod tests {
+	use ln::features::ChannelFeatures;
+	use ln::msgs::{OptionalField, UnsignedChannelUpdate, DecodeError};
+	use routing::graph_snapshot::{apply_snapshot, write_snapshot};
+	use ln::msgs::RoutingMessageHandler;
+	use routing::network_graph::{NetGraphMsgHandler, NetworkGraph};
+	use util::test_utils;
+
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::network::constants::Network;
+	use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+	use bitcoin::secp256k1::Secp256k1;
+
+	use std::sync::Arc;
+
+	fn get_node_id(index: u8) -> PublicKey {
+		PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[index; 32]).unwrap())
+	}
+
+	fn get_update(short_channel_id: u64, timestamp: u32, flags: u8, fee_base_msat: u32, htlc_maximum_msat: OptionalField<u64>) -> UnsignedChannelUpdate {
+		UnsignedChannelUpdate {
+			chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+			short_channel_id,
+			timestamp,
+			flags,
+			cltv_expiry_delta: 144,
+			htlc_minimum_msat: 1000,
+			htlc_maximum_msat,
+			fee_base_msat,
+			fee_proportional_millionths: 100,
+			excess_data: Vec::new(),
+		}
+	}
+
+	/// Builds a graph with channels 1 to 4 between nodes 1, 2 and 3, with updates at timestamps
+	/// 100 to 107, except for channel 4 which has none.
+	fn build_graph() -> NetworkGraph {
+		let mut network_graph = NetworkGraph::new();
+		network_graph.add_channel_unannounced(1 << 40, ChannelFeatures::empty(), get_node_id(1), get_node_id(2), Some(100_000)).unwrap();
+		network_graph.add_channel_unannounced(2 << 40, ChannelFeatures::known(), get_node_id(2), get_node_id(3), None).unwrap();
+		network_graph.add_channel_unannounced((2 << 40) | 1, ChannelFeatures::empty(), get_node_id(1), get_node_id(3), Some(200_000)).unwrap();
+		network_graph.add_channel_unannounced(3 << 40, ChannelFeatures::empty(), get_node_id(1), get_node_id(2), None).unwrap();
+		network_graph.update_channel_unsigned(&get_update(1 << 40, 100, 0, 1000, OptionalField::Absent)).unwrap();
+		network_graph.update_channel_unsigned(&get_update(1 << 40, 101, 1 | 2, 1000, OptionalField::Present(50_000_000))).unwrap();
+		network_graph.update_channel_unsigned(&get_update(2 << 40, 102, 0, 2000, OptionalField::Absent)).unwrap();
+		network_graph.update_channel_unsigned(&get_update(2 << 40, 103, 1, 1000, OptionalField::Absent)).unwrap();
+		network_graph.update_channel_unsigned(&get_update((2 << 40) | 1, 106, 1, 3000, OptionalField::Present(1000))).unwrap();
+		network_graph
+	}
+
+	#[test]
+	fn full_snapshot() {
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+		let network_graph = build_graph();
+		let snapshot = write_snapshot(&network_graph, &chain_hash, 0);
+
+		let mut client_graph = NetworkGraph::new();
+		assert_eq!(apply_snapshot(&mut client_graph, &chain_hash, &snapshot).unwrap(), 106);
+		assert!(client_graph == network_graph);
+
+		// Applying it again doesn't change anything.
+		assert_eq!(apply_snapshot(&mut client_graph, &chain_hash, &snapshot).unwrap(), 106);
+		assert!(client_graph == network_graph);
+
+		// Most update fields match the defaults and are omitted, leaving only the scid delta, the
+		// flags, the timestamp delta and the fee_base_msat or htlc_maximum_msat which differ.
+		let header_len = 1 + 32 + 4;
+		let nodes_len = 1 + 3 * 33;
+		let channels_len = 1 + (9 + 1 + 1 + 2 + 5) + (9 + 1 + 1 + 2 + 1) + (1 + 1 + 1 + 2 + 5) + (9 + 1 + 1 + 2 + 1);
+		let defaults_len = 2 + 3 + 4 + 4 + 1;
+		let updates_len = 1 + (9 + 1 + 1) + (1 + 1 + 1 + 5) + (9 + 1 + 1 + 4) + (1 + 1 + 1) + (1 + 1 + 1 + 4 + 3);
+		assert_eq!(snapshot.len(), header_len + nodes_len + channels_len + defaults_len + updates_len);
+	}
+
+	#[test]
+	fn delta_snapshot() {
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+		let network_graph = build_graph();
+
+		let mut client_graph = NetworkGraph::new();
+		client_graph.add_channel_unannounced(1 << 40, ChannelFeatures::empty(), get_node_id(1), get_node_id(2), Some(100_000)).unwrap();
+		client_graph.update_channel_unsigned(&get_update(1 << 40, 100, 0, 1000, OptionalField::Absent)).unwrap();
+		// A more recent update than the snapshot's isn't overwritten.
+		client_graph.update_channel_unsigned(&get_update(1 << 40, 110, 1, 5000, OptionalField::Absent)).unwrap();
+
+		let snapshot = write_snapshot(&network_graph, &chain_hash, 101);
+		assert_eq!(apply_snapshot(&mut client_graph, &chain_hash, &snapshot).unwrap(), 106);
+		let channels = client_graph.get_channels();
+		assert_eq!(channels.keys().cloned().collect::<Vec<u64>>(), vec![1 << 40, 2 << 40, (2 << 40) | 1]);
+		assert_eq!(channels[&(1 << 40)].two_to_one.as_ref().unwrap().fees.base_msat, 5000);
+		assert!(channels[&(2 << 40)] == network_graph.get_channels()[&(2 << 40)]);
+		assert!(channels[&((2 << 40) | 1)] == network_graph.get_channels()[&((2 << 40) | 1)]);
+
+		// With no newer updates, the snapshot is empty and the timestamp unchanged.
+		let snapshot = write_snapshot(&network_graph, &chain_hash, 107);
+		assert_eq!(apply_snapshot(&mut client_graph, &chain_hash, &snapshot).unwrap(), 107);
+	}
+
+	#[test]
+	fn msg_handler_from_snapshot() {
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+		let network_graph = build_graph();
+		let snapshot = write_snapshot(&network_graph, &chain_hash, 0);
+
+		let logger = Arc::new(test_utils::TestLogger::new());
+		let net_graph_msg_handler: NetGraphMsgHandler<Arc<test_utils::TestChainSource>, _> = NetGraphMsgHandler::from_snapshot(chain_hash, None, Arc::clone(&logger), &snapshot).unwrap();
+		assert!(*net_graph_msg_handler.network_graph.read().unwrap() == network_graph);
+
+		// Without signatures, we have nothing to relay to our peers.
+		assert!(net_graph_msg_handler.get_next_channel_announcements(0, 10).is_empty());
+
+		let other_chain_hash = genesis_block(Network::Bitcoin).header.block_hash();
+		assert!(NetGraphMsgHandler::<Arc<test_utils::TestChainSource>, _>::from_snapshot(other_chain_hash, None, Arc::clone(&logger), &snapshot).is_err());
+	}
+
+	#[test]
+	fn invalid_snapshots() {
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+		let snapshot = write_snapshot(&build_graph(), &chain_hash, 0);
+		let mut client_graph = NetworkGraph::new();
+
+		let other_chain_hash = genesis_block(Network::Bitcoin).header.block_hash();
+		match apply_snapshot(&mut client_graph, &other_chain_hash, &snapshot) { Err(DecodeError::InvalidValue) => {}, _ => panic!() }
+
+		let mut unknown_version = snapshot.clone();
+		unknown_version[0] = 2;
+		match apply_snapshot(&mut client_graph, &chain_hash, &unknown_version) { Err(DecodeError::UnknownVersion) => {}, _ => panic!() }
+
+		match apply_snapshot(&mut client_graph, &chain_hash, &snapshot[..snapshot.len() - 1]) { Err(DecodeError::ShortRead) => {}, _ => panic!() }
+
+		// The first channel refers to the first node, point it past the last one instead.
+		let mut bad_node_index = snapshot.clone();
+		bad_node_index[1 + 32 + 4 + 1 + 3 * 33 + 1 + 9] = 3;
+		match apply_snapshot(&mut client_graph, &chain_hash, &bad_node_index) { Err(DecodeError::InvalidValue) => {}, _ => panic!() }
+
+		assert!(client_graph == NetworkGraph::new());
+	}
+}
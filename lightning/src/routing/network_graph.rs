@@ -15,32 +15,58 @@ use bitcoin::secp256k1;
 
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use bitcoin::hashes::Hash;
-use bitcoin::blockdata::script::Builder;
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::script::{Builder, Script};
 use bitcoin::blockdata::transaction::TxOut;
 use bitcoin::blockdata::opcodes;
+use bitcoin::hash_types::BlockHash;
 
 use chain;
-use chain::Access;
+use chain::{Access, Filter};
+use chain::transaction::{OutPoint, TransactionData};
+use ln::chan_utils::make_funding_redeemscript;
 use ln::features::{ChannelFeatures, NodeFeatures};
 use ln::msgs::{DecodeError, ErrorAction, LightningError, RoutingMessageHandler, NetAddress, MAX_VALUE_MSAT};
 use ln::msgs::{ChannelAnnouncement, ChannelUpdate, NodeAnnouncement, OptionalField};
+use ln::msgs::{QueryChannelRange, ReplyChannelRange, QueryShortChannelIds, ReplyShortChannelIdsEnd, GossipTimestampFilter};
 use ln::msgs;
+use routing::graph_snapshot;
 use util::ser::{Writeable, Readable, Writer};
 use util::logger::Logger;
+use util::events::{MessageSendEvent, MessageSendEventsProvider};
 
-use std::{cmp, fmt};
-use std::sync::{RwLock, RwLockReadGuard};
+use std::{cmp, fmt, mem};
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::collections::btree_map::Entry as BtreeEntry;
 use std::ops::Deref;
 use bitcoin::hashes::hex::ToHex;
 
+/// Channels for which we didn't receive a channel_update in either direction for this long are
+/// considered closed, per BOLT #7, and removed by [`NetworkGraph::remove_stale_channels_with_time`].
+///
+/// [`NetworkGraph::remove_stale_channels_with_time`]: struct.NetworkGraph.html#method.remove_stale_channels_with_time
+pub const STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS: u64 = 60 * 60 * 24 * 14;
+
+/// Channels for which we never received a channel_update are removed by
+/// [`NetworkGraph::remove_stale_channels_with_time`] once their funding transaction has this many
+/// blocks on top of it, ie roughly two weeks' worth, matching
+/// [`STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS`].
+///
+/// [`NetworkGraph::remove_stale_channels_with_time`]: struct.NetworkGraph.html#method.remove_stale_channels_with_time
+/// [`STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS`]: constant.STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS.html
+pub const STALE_CHANNEL_ANNOUNCEMENT_AGE_LIMIT_BLOCKS: u32 = 6 * 24 * 14;
+
 /// Represents the network as nodes and channels between them
 #[derive(PartialEq)]
 pub struct NetworkGraph {
 	channels: BTreeMap<u64, ChannelInfo>,
 	nodes: BTreeMap<PublicKey, NodeInfo>,
+	/// The short_channel_ids of the channels we know the funding redeemscript of, indexed by it to
+	/// notice when their funding output is spent. Not persisted, but rebuilt from the stored
+	/// channel announcements when reading the graph.
+	channels_by_funding_script: HashMap<Script, u64>,
 }
 
 /// A simple newtype for RwLockReadGuard<'a, NetworkGraph>.
@@ -48,17 +74,63 @@ pub struct NetworkGraph {
 /// the C bindings, as it can be done directly in Rust code.
 pub struct LockedNetworkGraph<'a>(pub RwLockReadGuard<'a, NetworkGraph>);
 
+/// The maximum number of short_channel_ids we put in a single query_short_channel_ids or
+/// reply_channel_range message, keeping it well within the 65535-byte message size limit.
+const MAX_SHORT_CHANNEL_IDS_PER_MESSAGE: usize = 8000;
+
+/// The state of our gossip_queries-based sync with a peer.
+struct GossipQueryState {
+	/// The short_channel_ids the peer told us about in reply_channel_range messages which we don't
+	/// know about and have yet to query.
+	pending_short_channel_ids: Vec<u64>,
+	/// The short_channel_ids of our query_short_channel_ids in flight, if any. Per BOLT #7 we only
+	/// have a single query in flight with a given peer.
+	queried_short_channel_ids: Vec<u64>,
+}
+
+/// The maximum number of channel_announcements we hold on to while their funding UTXO is being
+/// looked up asynchronously, beyond which we ignore new ones which need a lookup.
+const MAX_PENDING_UTXO_LOOKUPS: usize = 1000;
+
+/// The number of calls to [`NetGraphMsgHandler::timer_tick_occurred`] after which we give up on a
+/// pending UTXO lookup.
+///
+/// [`NetGraphMsgHandler::timer_tick_occurred`]: struct.NetGraphMsgHandler.html#method.timer_tick_occurred
+const UTXO_LOOKUP_TIMEOUT_TICKS: u8 = 2;
+
+/// A channel_announcement whose funding UTXO is being looked up, along with the latest
+/// channel_update in each direction we received for the channel in the meantime, to be applied
+/// once the lookup completes.
+struct PendingUtxoLookup {
+	announcement: ChannelAnnouncement,
+	updates: [Option<ChannelUpdate>; 2],
+	timer_ticks: u8,
+}
+
 /// Receives and validates network updates from peers,
 /// stores authentic and relevant data as a network graph.
 /// This network graph is then used for routing payments.
 /// Provides interface to help with initial routing sync by
 /// serving historical announcements.
+///
+/// With peers which support `gossip_queries`, it also syncs the network graph by querying the
+/// short_channel_ids of all the channels the peer knows about and only requesting the gossip for
+/// the ones we don't know about yet, and answers such queries from our peers.
 pub struct NetGraphMsgHandler<C: Deref, L: Deref> where C::Target: chain::Access, L::Target: Logger {
 	secp_ctx: Secp256k1<secp256k1::VerifyOnly>,
 	/// Representation of the payment channel network
 	pub network_graph: RwLock<NetworkGraph>,
+	genesis_hash: BlockHash,
 	chain_access: Option<C>,
 	full_syncs_requested: AtomicUsize,
+	gossip_query_states: Mutex<HashMap<PublicKey, GossipQueryState>>,
+	pending_events: Mutex<Vec<MessageSendEvent>>,
+	pending_utxo_lookups: Mutex<HashMap<u64, PendingUtxoLookup>>,
+	/// The funding outputs of the channels we accepted since the last call to
+	/// register_funding_outputs, along with their script_pubkey.
+	funding_outputs_to_watch: Mutex<Vec<(OutPoint, Script)>>,
+	/// The time given to the last timer_tick_occurred call, in seconds since the UNIX epoch.
+	current_time: AtomicUsize,
 	logger: L,
 }
 
@@ -68,31 +140,43 @@ impl<C: Deref, L: Deref> NetGraphMsgHandler<C, L> where C::Target: chain::Access
 	/// Chain monitor is used to make sure announced channels exist on-chain,
 	/// channel data is correct, and that the announcement is signed with
 	/// channel owners' keys.
-	pub fn new(chain_access: Option<C>, logger: L) -> Self {
-		NetGraphMsgHandler {
-			secp_ctx: Secp256k1::verification_only(),
-			network_graph: RwLock::new(NetworkGraph {
-				channels: BTreeMap::new(),
-				nodes: BTreeMap::new(),
-			}),
-			full_syncs_requested: AtomicUsize::new(0),
-			chain_access,
-			logger,
-		}
+	/// The genesis hash is the one of the chain we sync the network graph of with gossip queries.
+	pub fn new(genesis_hash: BlockHash, chain_access: Option<C>, logger: L) -> Self {
+		Self::from_net_graph(genesis_hash, chain_access, logger, NetworkGraph::new())
 	}
 
 	/// Creates a new tracker of the actual state of the network of channels and nodes,
 	/// assuming an existing Network Graph.
-	pub fn from_net_graph(chain_access: Option<C>, logger: L, network_graph: NetworkGraph) -> Self {
+	pub fn from_net_graph(genesis_hash: BlockHash, chain_access: Option<C>, logger: L, network_graph: NetworkGraph) -> Self {
 		NetGraphMsgHandler {
 			secp_ctx: Secp256k1::verification_only(),
 			network_graph: RwLock::new(network_graph),
+			genesis_hash,
 			full_syncs_requested: AtomicUsize::new(0),
+			gossip_query_states: Mutex::new(HashMap::new()),
+			pending_events: Mutex::new(Vec::new()),
+			pending_utxo_lookups: Mutex::new(HashMap::new()),
+			funding_outputs_to_watch: Mutex::new(Vec::new()),
+			current_time: AtomicUsize::new(0),
 			chain_access,
 			logger,
 		}
 	}
 
+	/// Creates a new tracker of the actual state of the network of channels and nodes, starting
+	/// from a network graph snapshot generated by a trusted server with
+	/// [`graph_snapshot::write_snapshot`], for the chain with the given genesis hash.
+	/// Later snapshots can be applied to the network_graph with
+	/// [`graph_snapshot::apply_snapshot`].
+	///
+	/// [`graph_snapshot::write_snapshot`]: ../graph_snapshot/fn.write_snapshot.html
+	/// [`graph_snapshot::apply_snapshot`]: ../graph_snapshot/fn.apply_snapshot.html
+	pub fn from_snapshot(genesis_hash: BlockHash, chain_access: Option<C>, logger: L, snapshot: &[u8]) -> Result<Self, DecodeError> {
+		let mut network_graph = NetworkGraph::new();
+		graph_snapshot::apply_snapshot(&mut network_graph, &genesis_hash, snapshot)?;
+		Ok(Self::from_net_graph(genesis_hash, chain_access, logger, network_graph))
+	}
+
 	/// Take a read lock on the network_graph and return it in the C-bindings
 	/// newtype helper. This is likely only useful when called via the C
 	/// bindings as you can call `self.network_graph.read().unwrap()` in Rust
@@ -100,6 +184,175 @@ impl<C: Deref, L: Deref> NetGraphMsgHandler<C, L> where C::Target: chain::Access
 	pub fn read_locked_graph<'a>(&'a self) -> LockedNetworkGraph<'a> {
 		LockedNetworkGraph(self.network_graph.read().unwrap())
 	}
+
+	/// Registers the funding outputs of the channels we accepted since the last call with the given
+	/// [`chain::Filter`], so that the transactions spending them are given to [`block_connected`].
+	///
+	/// Only the funding outputs of channels whose announcement we checked with the
+	/// [`chain::Access`] given on construction are known. Should be called whenever gossip was
+	/// handled, eg along with [`PeerManager::process_events`], and at least before fetching each
+	/// block.
+	///
+	/// [`chain::Filter`]: ../../chain/trait.Filter.html
+	/// [`chain::Access`]: ../../chain/trait.Access.html
+	/// [`block_connected`]: #method.block_connected
+	/// [`PeerManager::process_events`]: ../../ln/peer_handler/struct.PeerManager.html#method.process_events
+	pub fn register_funding_outputs<F: Deref>(&self, chain_source: F) where F::Target: chain::Filter {
+		for (outpoint, script_pubkey) in self.funding_outputs_to_watch.lock().unwrap().drain(..) {
+			chain_source.register_output(&outpoint, &script_pubkey);
+		}
+	}
+
+	/// Removes the channels whose funding output is spent by a transaction of the connected block,
+	/// along with the nodes left without channels.
+	///
+	/// Spends are recognized by the funding redeemscript in the witness of the spending input, so
+	/// only channels whose channel_announcement we received can be removed this way, and `txdata`
+	/// must include the transactions spending their funding outputs. Light clients should register
+	/// them with [`register_funding_outputs`] to get them.
+	///
+	/// [`register_funding_outputs`]: #method.register_funding_outputs
+	pub fn block_connected(&self, _header: &BlockHeader, txdata: &TransactionData, height: u32) {
+		let mut network_graph = self.network_graph.write().unwrap();
+		for &(_, tx) in txdata.iter() {
+			for input in tx.input.iter() {
+				let witness_script = match input.witness.last() {
+					Some(witness_script) => Script::from(witness_script.clone()),
+					None => continue,
+				};
+				let short_channel_id = match network_graph.channels_by_funding_script.get(&witness_script) {
+					Some(short_channel_id) => *short_channel_id,
+					None => continue,
+				};
+				log_trace!(self.logger, "Removing channel {} as its funding output was spent at height {}", short_channel_id, height);
+				network_graph.remove_channel(short_channel_id);
+			}
+		}
+	}
+
+	/// Provides the result of a funding UTXO lookup for which [`chain::Access::get_utxo`] returned
+	/// [`chain::AccessError::Pending`], adding the announced channel to the network graph along
+	/// with the channel_updates we received for it in the meantime if the UTXO matches.
+	///
+	/// Channels added this way are then relayed to our peers along with their channel_updates,
+	/// through a [`MessageSendEvent::RelayChannelAnnouncement`], as they would have been had the
+	/// lookup completed synchronously. Results for lookups we gave up on in
+	/// [`timer_tick_occurred`] are ignored.
+	///
+	/// The result may be provided before [`chain::Access::get_utxo`] returns, eg from another
+	/// thread.
+	///
+	/// [`chain::Access::get_utxo`]: ../../chain/trait.Access.html#tymethod.get_utxo
+	/// [`chain::AccessError::Pending`]: ../../chain/enum.AccessError.html#variant.Pending
+	/// [`MessageSendEvent::RelayChannelAnnouncement`]: ../../util/events/enum.MessageSendEvent.html#variant.RelayChannelAnnouncement
+	/// [`timer_tick_occurred`]: #method.timer_tick_occurred
+	pub fn utxo_lookup_completed(&self, short_channel_id: u64, utxo: Result<(OutPoint, TxOut), chain::AccessError>) {
+		let lookup = match self.pending_utxo_lookups.lock().unwrap().remove(&short_channel_id) {
+			Some(lookup) => lookup,
+			None => return,
+		};
+		match self.add_looked_up_channel(&lookup, utxo) {
+			Ok((should_relay, update_msgs)) => {
+				if should_relay {
+					self.pending_events.lock().unwrap().push(MessageSendEvent::RelayChannelAnnouncement {
+						msg: lookup.announcement,
+						update_msgs,
+					});
+				}
+			},
+			Err(e) => {
+				log_trace!(self.logger, "Dropping channel_announcement for {} after UTXO lookup: {}", short_channel_id, e.err);
+			},
+		}
+	}
+
+	/// Adds the channel of a completed UTXO lookup to the network graph, along with the
+	/// channel_updates we received for it in the meantime, returning whether the
+	/// channel_announcement should be relayed and the channel_updates which should be relayed after
+	/// it.
+	fn add_looked_up_channel(&self, lookup: &PendingUtxoLookup, utxo: Result<(OutPoint, TxOut), chain::AccessError>) -> Result<(bool, Vec<ChannelUpdate>), LightningError> {
+		let (funding_outpoint, funding_utxo) = check_funding_utxo(&lookup.announcement.contents, utxo)?;
+
+		// Signatures were checked when we queued the messages.
+		let mut network_graph = self.network_graph.write().unwrap();
+		let should_relay = network_graph.update_channel_from_announcement(&lookup.announcement, Some(funding_utxo.value), None)?;
+		log_trace!(self.logger, "Added channel_announcement for {}{}", lookup.announcement.contents.short_channel_id,
+			if !lookup.announcement.contents.excess_data.is_empty() { " with excess uninterpreted data!" } else { "" });
+		self.funding_outputs_to_watch.lock().unwrap().push((funding_outpoint, funding_utxo.script_pubkey));
+		let mut update_msgs = Vec::new();
+		for update in lookup.updates.iter() {
+			if let &Some(ref update) = update {
+				if let Ok(true) = network_graph.update_channel(update, None) {
+					update_msgs.push(update.clone());
+				}
+			}
+		}
+		Ok((should_relay, update_msgs))
+	}
+
+	/// Gives up on the funding UTXO lookups which have been pending for too long, dropping their
+	/// channel_announcement and channel_updates, and records the current time, given in seconds
+	/// since the UNIX epoch.
+	///
+	/// The current time is used to only ask peers supporting `gossip_queries` to relay gossip newer
+	/// than it when connecting to them, relying on channel range queries for the older gossip.
+	/// Until this is first called, all the gossip they have is requested.
+	///
+	/// Should be called roughly once a minute (and once on startup), e.g. along with
+	/// [`PeerManager::timer_tick_occured`], in particular when [`chain::Access::get_utxo`] may
+	/// return [`chain::AccessError::Pending`].
+	///
+	/// [`chain::Access::get_utxo`]: ../../chain/trait.Access.html#tymethod.get_utxo
+	/// [`chain::AccessError::Pending`]: ../../chain/enum.AccessError.html#variant.Pending
+	/// [`PeerManager::timer_tick_occured`]: ../../ln/peer_handler/struct.PeerManager.html#method.timer_tick_occured
+	pub fn timer_tick_occurred(&self, current_time_unix: u64) {
+		self.current_time.store(cmp::min(current_time_unix, u32::max_value() as u64) as usize, Ordering::Release);
+		let logger = &self.logger;
+		self.pending_utxo_lookups.lock().unwrap().retain(|short_channel_id, lookup| {
+			lookup.timer_ticks += 1;
+			if lookup.timer_ticks > UTXO_LOOKUP_TIMEOUT_TICKS {
+				log_trace!(logger, "Timed out UTXO lookup for channel_announcement for {}", short_channel_id);
+				false
+			} else { true }
+		});
+	}
+
+	/// Queues a channel_announcement, whose signatures we checked, until its funding UTXO lookup
+	/// completes, unless it is already queued or we have too many lookups pending.
+	fn queue_pending_utxo_lookup(&self, msg: &msgs::ChannelAnnouncement) -> Result<(), LightningError> {
+		let mut pending_utxo_lookups = self.pending_utxo_lookups.lock().unwrap();
+		if pending_utxo_lookups.contains_key(&msg.contents.short_channel_id) {
+			return Err(LightningError{err: "Channel announcement is already pending UTXO lookup".to_owned(), action: ErrorAction::IgnoreError});
+		}
+		if pending_utxo_lookups.len() >= MAX_PENDING_UTXO_LOOKUPS {
+			return Err(LightningError{err: "Too many channel announcements pending UTXO lookup".to_owned(), action: ErrorAction::IgnoreError});
+		}
+		pending_utxo_lookups.insert(msg.contents.short_channel_id, PendingUtxoLookup {
+			announcement: msg.clone(),
+			updates: [None, None],
+			timer_ticks: 0,
+		});
+		Ok(())
+	}
+
+	/// Sends a query_short_channel_ids for the next batch of the given peer's pending
+	/// short_channel_ids, unless we already have a query in flight with it. Channels we have
+	/// learned about since they were added to the pending set are skipped.
+	fn query_next_short_channel_ids(&self, their_node_id: &PublicKey, query_state: &mut GossipQueryState, network_graph: &NetworkGraph) {
+		if !query_state.queried_short_channel_ids.is_empty() { return; }
+		query_state.pending_short_channel_ids.retain(|scid| !network_graph.channels.contains_key(scid));
+		let batch_len = cmp::min(query_state.pending_short_channel_ids.len(), MAX_SHORT_CHANNEL_IDS_PER_MESSAGE);
+		if batch_len == 0 { return; }
+		query_state.queried_short_channel_ids = query_state.pending_short_channel_ids.drain(..batch_len).collect();
+		log_trace!(self.logger, "Querying {} short_channel_ids from {}", batch_len, log_pubkey!(their_node_id));
+		self.pending_events.lock().unwrap().push(MessageSendEvent::SendShortIdsQuery {
+			node_id: their_node_id.clone(),
+			msg: QueryShortChannelIds {
+				chain_hash: self.genesis_hash,
+				short_channel_ids: query_state.queried_short_channel_ids.clone(),
+			}
+		});
+	}
 }
 
 impl<'a> LockedNetworkGraph<'a> {
@@ -109,7 +362,6 @@ impl<'a> LockedNetworkGraph<'a> {
 	}
 }
 
-
 macro_rules! secp_verify_sig {
 	( $secp_ctx: expr, $msg: expr, $sig: expr, $pubkey: expr ) => {
 		match $secp_ctx.verify($msg, $sig, $pubkey) {
@@ -119,6 +371,48 @@ macro_rules! secp_verify_sig {
 	};
 }
 
+/// Checks a channel_announcement's signatures.
+fn verify_channel_announcement(msg: &msgs::ChannelAnnouncement, secp_ctx: &Secp256k1<secp256k1::VerifyOnly>) -> Result<(), LightningError> {
+	let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
+	secp_verify_sig!(secp_ctx, &msg_hash, &msg.node_signature_1, &msg.contents.node_id_1);
+	secp_verify_sig!(secp_ctx, &msg_hash, &msg.node_signature_2, &msg.contents.node_id_2);
+	secp_verify_sig!(secp_ctx, &msg_hash, &msg.bitcoin_signature_1, &msg.contents.bitcoin_key_1);
+	secp_verify_sig!(secp_ctx, &msg_hash, &msg.bitcoin_signature_2, &msg.contents.bitcoin_key_2);
+	Ok(())
+}
+
+/// Checks the result of a funding UTXO lookup against the channel it was announced for, returning
+/// the channel's value.
+fn check_funding_utxo(announcement: &msgs::UnsignedChannelAnnouncement, utxo: Result<(OutPoint, TxOut), chain::AccessError>) -> Result<(OutPoint, TxOut), LightningError> {
+	match utxo {
+		Ok((outpoint, utxo)) => {
+			let expected_script = Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_2)
+			                                    .push_slice(&announcement.bitcoin_key_1.serialize())
+			                                    .push_slice(&announcement.bitcoin_key_2.serialize())
+			                                    .push_opcode(opcodes::all::OP_PUSHNUM_2)
+			                                    .push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script().to_v0_p2wsh();
+			if utxo.script_pubkey != expected_script {
+				return Err(LightningError{err: format!("Channel announcement key ({}) didn't match on-chain script ({})", utxo.script_pubkey.to_hex(), expected_script.to_hex()), action: ErrorAction::IgnoreError});
+			}
+			Ok((outpoint, utxo))
+		},
+		Err(chain::AccessError::UnknownChain) => {
+			Err(LightningError{err: format!("Channel announced on an unknown chain ({})", announcement.chain_hash.encode().to_hex()), action: ErrorAction::IgnoreError})
+		},
+		Err(chain::AccessError::UnknownTx) => {
+			Err(LightningError{err: "Channel announced without corresponding UTXO entry".to_owned(), action: ErrorAction::IgnoreError})
+		},
+		Err(chain::AccessError::Pending) => {
+			Err(LightningError{err: "Channel announcement UTXO lookup is still pending".to_owned(), action: ErrorAction::IgnoreError})
+		},
+	}
+}
+
+/// The redeemscript of the funding output of an announced channel.
+fn funding_redeemscript(announcement: &msgs::UnsignedChannelAnnouncement) -> Script {
+	make_funding_redeemscript(&announcement.bitcoin_key_1, &announcement.bitcoin_key_2)
+}
+
 impl<C: Deref + Sync + Send, L: Deref + Sync + Send> RoutingMessageHandler for NetGraphMsgHandler<C, L> where C::Target: chain::Access, L::Target: Logger {
 	fn handle_node_announcement(&self, msg: &msgs::NodeAnnouncement) -> Result<bool, LightningError> {
 		self.network_graph.write().unwrap().update_node_from_announcement(msg, Some(&self.secp_ctx))
@@ -128,37 +422,29 @@ impl<C: Deref + Sync + Send, L: Deref + Sync + Send> RoutingMessageHandler for N
 		if msg.contents.node_id_1 == msg.contents.node_id_2 || msg.contents.bitcoin_key_1 == msg.contents.bitcoin_key_2 {
 			return Err(LightningError{err: "Channel announcement node had a channel with itself".to_owned(), action: ErrorAction::IgnoreError});
 		}
+		// Check the signatures before looking up the UTXO, which may be expensive.
+		verify_channel_announcement(msg, &self.secp_ctx)?;
+
+		if let Some(ref chain_access) = self.chain_access {
+			// The lookup is queued before calling get_utxo, so that its result may be provided
+			// before get_utxo returns.
+			self.queue_pending_utxo_lookup(msg)?;
+			let utxo = chain_access.get_utxo(&msg.contents.chain_hash, msg.contents.short_channel_id);
+			if let Err(chain::AccessError::Pending) = utxo {
+				// We'll add and relay the channel once the lookup completes.
+				log_trace!(self.logger, "Queued channel_announcement for {} pending UTXO lookup", msg.contents.short_channel_id);
+				return Ok(false);
+			}
+			let lookup = match self.pending_utxo_lookups.lock().unwrap().remove(&msg.contents.short_channel_id) {
+				Some(lookup) => lookup,
+				None => return Err(LightningError{err: "Channel announcement UTXO lookup was already completed".to_owned(), action: ErrorAction::IgnoreError}),
+			};
+			let (should_relay, _) = self.add_looked_up_channel(&lookup, utxo)?;
+			return Ok(should_relay);
+		}
 
-		let utxo_value = match &self.chain_access {
-			&None => {
-				// Tentatively accept, potentially exposing us to DoS attacks
-				None
-			},
-			&Some(ref chain_access) => {
-				match chain_access.get_utxo(&msg.contents.chain_hash, msg.contents.short_channel_id) {
-					Ok(TxOut { value, script_pubkey }) => {
-						let expected_script = Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_2)
-						                                    .push_slice(&msg.contents.bitcoin_key_1.serialize())
-						                                    .push_slice(&msg.contents.bitcoin_key_2.serialize())
-						                                    .push_opcode(opcodes::all::OP_PUSHNUM_2)
-						                                    .push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script().to_v0_p2wsh();
-						if script_pubkey != expected_script {
-							return Err(LightningError{err: format!("Channel announcement key ({}) didn't match on-chain script ({})", script_pubkey.to_hex(), expected_script.to_hex()), action: ErrorAction::IgnoreError});
-						}
-						//TODO: Check if value is worth storing, use it to inform routing, and compare it
-						//to the new HTLC max field in channel_update
-						Some(value)
-					},
-					Err(chain::AccessError::UnknownChain) => {
-						return Err(LightningError{err: format!("Channel announced on an unknown chain ({})", msg.contents.chain_hash.encode().to_hex()), action: ErrorAction::IgnoreError});
-					},
-					Err(chain::AccessError::UnknownTx) => {
-						return Err(LightningError{err: "Channel announced without corresponding UTXO entry".to_owned(), action: ErrorAction::IgnoreError});
-					},
-				}
-			},
-		};
-		let result = self.network_graph.write().unwrap().update_channel_from_announcement(msg, utxo_value, Some(&self.secp_ctx));
+		// Tentatively accept, potentially exposing us to DoS attacks
+		let result = self.network_graph.write().unwrap().update_channel_from_announcement(msg, None, None);
 		log_trace!(self.logger, "Added channel_announcement for {}{}", msg.contents.short_channel_id, if !msg.contents.excess_data.is_empty() { " with excess uninterpreted data!" } else { "" });
 		result
 	}
@@ -178,6 +464,21 @@ impl<C: Deref + Sync + Send, L: Deref + Sync + Send> RoutingMessageHandler for N
 	}
 
 	fn handle_channel_update(&self, msg: &msgs::ChannelUpdate) -> Result<bool, LightningError> {
+		if let Some(lookup) = self.pending_utxo_lookups.lock().unwrap().get_mut(&msg.contents.short_channel_id) {
+			let direction = (msg.contents.flags & 1) as usize;
+			{
+				let node_id = if direction == 0 { &lookup.announcement.contents.node_id_1 } else { &lookup.announcement.contents.node_id_2 };
+				let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
+				secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.signature, node_id);
+			}
+			if let Some(ref queued_update) = lookup.updates[direction] {
+				if queued_update.contents.timestamp >= msg.contents.timestamp {
+					return Err(LightningError{err: "Update older than last processed update".to_owned(), action: ErrorAction::IgnoreError});
+				}
+			}
+			lookup.updates[direction] = Some(msg.clone());
+			return Ok(false);
+		}
 		self.network_graph.write().unwrap().update_channel(msg, Some(&self.secp_ctx))
 	}
 
@@ -243,6 +544,200 @@ impl<C: Deref + Sync + Send, L: Deref + Sync + Send> RoutingMessageHandler for N
 			false
 		}
 	}
+
+	fn sync_routing_table(&self, their_node_id: &PublicKey, init: &msgs::Init) {
+		if !init.features.supports_gossip_queries() {
+			return;
+		}
+		// Any sync from a previous connection with this peer was interrupted, start over.
+		self.gossip_query_states.lock().unwrap().insert(their_node_id.clone(), GossipQueryState {
+			pending_short_channel_ids: Vec::new(),
+			queried_short_channel_ids: Vec::new(),
+		});
+
+		// Ask for new gossip to be relayed to us as it comes in, and diff the peer's view of the
+		// channels against ours for the older gossip.
+		let first_timestamp = self.current_time.load(Ordering::Acquire) as u32;
+		let mut pending_events = self.pending_events.lock().unwrap();
+		pending_events.push(MessageSendEvent::SendGossipTimestampFilter {
+			node_id: their_node_id.clone(),
+			msg: GossipTimestampFilter {
+				chain_hash: self.genesis_hash,
+				first_timestamp,
+				timestamp_range: u32::max_value() - first_timestamp,
+			}
+		});
+		log_trace!(self.logger, "Querying all short_channel_ids from {}", log_pubkey!(their_node_id));
+		pending_events.push(MessageSendEvent::SendChannelRangeQuery {
+			node_id: their_node_id.clone(),
+			msg: QueryChannelRange {
+				chain_hash: self.genesis_hash,
+				first_blocknum: 0,
+				number_of_blocks: u32::max_value(),
+			}
+		});
+	}
+
+	fn handle_reply_channel_range(&self, their_node_id: &PublicKey, msg: &ReplyChannelRange) -> Result<(), LightningError> {
+		if msg.chain_hash != self.genesis_hash {
+			return Err(LightningError{err: "Received reply_channel_range for an unknown chain".to_owned(), action: ErrorAction::IgnoreError});
+		}
+		if !msg.full_information {
+			log_debug!(self.logger, "Peer {} doesn't maintain up-to-date information about the channels in the blocks {} to {}",
+				log_pubkey!(their_node_id), msg.first_blocknum, msg.first_blocknum as u64 + msg.number_of_blocks as u64);
+		}
+
+		let mut query_states = self.gossip_query_states.lock().unwrap();
+		let network_graph = self.network_graph.read().unwrap();
+		// Channels we're already querying from any peer don't need to be queried again.
+		let mut known_short_channel_ids = HashSet::new();
+		for query_state in query_states.values() {
+			known_short_channel_ids.extend(query_state.pending_short_channel_ids.iter());
+			known_short_channel_ids.extend(query_state.queried_short_channel_ids.iter());
+		}
+		let query_state = match query_states.get_mut(their_node_id) {
+			Some(query_state) => query_state,
+			None => return Err(LightningError{err: "Received unsolicited reply_channel_range".to_owned(), action: ErrorAction::IgnoreError}),
+		};
+		let mut new_short_channel_ids = 0;
+		for scid in msg.short_channel_ids.iter() {
+			if !network_graph.channels.contains_key(scid) && known_short_channel_ids.insert(*scid) {
+				query_state.pending_short_channel_ids.push(*scid);
+				new_short_channel_ids += 1;
+			}
+		}
+		log_trace!(self.logger, "Received {} short_channel_ids from {}, {} of which are new to us",
+			msg.short_channel_ids.len(), log_pubkey!(their_node_id), new_short_channel_ids);
+		self.query_next_short_channel_ids(their_node_id, query_state, &network_graph);
+		Ok(())
+	}
+
+	fn handle_reply_short_channel_ids_end(&self, their_node_id: &PublicKey, msg: &ReplyShortChannelIdsEnd) -> Result<(), LightningError> {
+		if msg.chain_hash != self.genesis_hash {
+			return Err(LightningError{err: "Received reply_short_channel_ids_end for an unknown chain".to_owned(), action: ErrorAction::IgnoreError});
+		}
+		let mut query_states = self.gossip_query_states.lock().unwrap();
+		let query_state = match query_states.get_mut(their_node_id) {
+			Some(query_state) => query_state,
+			None => return Err(LightningError{err: "Received unsolicited reply_short_channel_ids_end".to_owned(), action: ErrorAction::IgnoreError}),
+		};
+		if query_state.queried_short_channel_ids.is_empty() {
+			return Err(LightningError{err: "Received unsolicited reply_short_channel_ids_end".to_owned(), action: ErrorAction::IgnoreError});
+		}
+		if !msg.full_information {
+			log_debug!(self.logger, "Peer {} doesn't maintain up-to-date information about the channels we queried", log_pubkey!(their_node_id));
+		}
+		query_state.queried_short_channel_ids.clear();
+		let network_graph = self.network_graph.read().unwrap();
+		self.query_next_short_channel_ids(their_node_id, query_state, &network_graph);
+		Ok(())
+	}
+
+	fn handle_query_channel_range(&self, their_node_id: &PublicKey, msg: &QueryChannelRange) -> Result<(), LightningError> {
+		let mut pending_events = self.pending_events.lock().unwrap();
+		if msg.chain_hash != self.genesis_hash || msg.number_of_blocks == 0 {
+			pending_events.push(MessageSendEvent::SendReplyChannelRange {
+				node_id: their_node_id.clone(),
+				msg: ReplyChannelRange {
+					chain_hash: msg.chain_hash,
+					first_blocknum: msg.first_blocknum,
+					number_of_blocks: msg.number_of_blocks,
+					full_information: false,
+					short_channel_ids: Vec::new(),
+				}
+			});
+			return Err(LightningError{err: "Received query_channel_range for an unknown chain or an empty range".to_owned(), action: ErrorAction::IgnoreError});
+		}
+
+		// The block height is the upper 3 bytes of a short_channel_id.
+		let end_blocknum = msg.first_blocknum as u64 + msg.number_of_blocks as u64;
+		let network_graph = self.network_graph.read().unwrap();
+		let short_channel_ids: Vec<u64> = if msg.first_blocknum as u64 >= 1 << 24 { Vec::new() } else {
+			network_graph.channels.range((msg.first_blocknum as u64) << 40..)
+				.take_while(|&(scid, _)| (*scid >> 40) < end_blocknum)
+				.filter(|&(_, ref chan)| chan.announcement_message.is_some())
+				.map(|(scid, _)| *scid).collect()
+		};
+
+		// Split the reply in as many messages as needed, covering consecutive block ranges which
+		// together cover the queried range.
+		let mut first_blocknum = msg.first_blocknum as u64;
+		let mut chunks = short_channel_ids.chunks(MAX_SHORT_CHANNEL_IDS_PER_MESSAGE).peekable();
+		loop {
+			let chunk = chunks.next().unwrap_or(&[]);
+			let reply_end_blocknum = if chunks.peek().is_some() {
+				(chunk[chunk.len() - 1] >> 40) + 1
+			} else {
+				end_blocknum
+			};
+			pending_events.push(MessageSendEvent::SendReplyChannelRange {
+				node_id: their_node_id.clone(),
+				msg: ReplyChannelRange {
+					chain_hash: self.genesis_hash,
+					first_blocknum: first_blocknum as u32,
+					number_of_blocks: (reply_end_blocknum - first_blocknum) as u32,
+					full_information: true,
+					short_channel_ids: chunk.to_vec(),
+				}
+			});
+			if reply_end_blocknum == end_blocknum { break; }
+			first_blocknum = reply_end_blocknum;
+		}
+		Ok(())
+	}
+
+	fn handle_query_short_channel_ids(&self, their_node_id: &PublicKey, msg: &QueryShortChannelIds) -> Result<(), LightningError> {
+		if msg.chain_hash != self.genesis_hash {
+			self.pending_events.lock().unwrap().push(MessageSendEvent::SendShortIdsReply {
+				node_id: their_node_id.clone(),
+				msg: ReplyShortChannelIdsEnd {
+					chain_hash: msg.chain_hash,
+					full_information: false,
+				}
+			});
+			return Err(LightningError{err: "Received query_short_channel_ids for an unknown chain".to_owned(), action: ErrorAction::IgnoreError});
+		}
+		log_trace!(self.logger, "Replying to query for {} short_channel_ids from {}", msg.short_channel_ids.len(), log_pubkey!(their_node_id));
+		Ok(())
+	}
+
+	fn get_gossip_for_short_channel_ids(&self, short_channel_ids: &[u64]) -> (Vec<(ChannelAnnouncement, Option<ChannelUpdate>, Option<ChannelUpdate>)>, Vec<NodeAnnouncement>) {
+		let network_graph = self.network_graph.read().unwrap();
+		let mut channel_announcements = Vec::new();
+		let mut node_announcements = Vec::new();
+		let mut announced_nodes = HashSet::new();
+		for scid in short_channel_ids.iter() {
+			let chan = match network_graph.channels.get(scid) {
+				Some(chan) => chan,
+				None => continue,
+			};
+			let announcement = match chan.announcement_message {
+				Some(ref announcement) => announcement.clone(),
+				None => continue,
+			};
+			channel_announcements.push((announcement,
+				chan.one_to_two.as_ref().and_then(|one_to_two| one_to_two.last_update_message.clone()),
+				chan.two_to_one.as_ref().and_then(|two_to_one| two_to_one.last_update_message.clone())));
+			for node_id in [chan.node_one, chan.node_two].iter() {
+				if !announced_nodes.insert(*node_id) { continue; }
+				if let Some(node_info) = network_graph.nodes.get(node_id).and_then(|node| node.announcement_info.as_ref()) {
+					if let Some(ref node_announcement) = node_info.announcement_message {
+						node_announcements.push(node_announcement.clone());
+					}
+				}
+			}
+		}
+		(channel_announcements, node_announcements)
+	}
+}
+
+impl<C: Deref, L: Deref> MessageSendEventsProvider for NetGraphMsgHandler<C, L> where C::Target: chain::Access, L::Target: Logger {
+	fn get_and_clear_pending_msg_events(&self) -> Vec<MessageSendEvent> {
+		let mut ret = Vec::new();
+		let mut pending_events = self.pending_events.lock().unwrap();
+		mem::swap(&mut ret, &mut *pending_events);
+		ret
+	}
 }
 
 #[derive(PartialEq, Debug)]
@@ -301,6 +796,7 @@ pub struct ChannelInfo {
 	/// Details about the second direction of a channel
 	pub two_to_one: Option<DirectionalChannelInfo>,
 	/// The channel capacity as seen on-chain, if chain lookup is available.
+	/// Routes never send more than this over the channel.
 	pub capacity_sats: Option<u64>,
 	/// An initial announcement of the channel
 	/// Mostly redundant with the data we store in fields explicitly.
@@ -311,8 +807,8 @@ pub struct ChannelInfo {
 
 impl fmt::Display for ChannelInfo {
 	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-		write!(f, "features: {}, node_one: {}, one_to_two: {:?}, node_two: {}, two_to_one: {:?}",
-		   log_bytes!(self.features.encode()), log_pubkey!(self.node_one), self.one_to_two, log_pubkey!(self.node_two), self.two_to_one)?;
+		write!(f, "features: {}, node_one: {}, one_to_two: {:?}, node_two: {}, two_to_one: {:?}, capacity_sats: {:?}",
+		   log_bytes!(self.features.encode()), log_pubkey!(self.node_one), self.one_to_two, log_pubkey!(self.node_two), self.two_to_one, self.capacity_sats)?;
 		Ok(())
 	}
 }
@@ -499,7 +995,7 @@ impl Readable for NetworkGraph {
 		let mut channels = BTreeMap::new();
 		for _ in 0..channels_count {
 			let chan_id: u64 = Readable::read(reader)?;
-			let chan_info = Readable::read(reader)?;
+			let chan_info: ChannelInfo = Readable::read(reader)?;
 			channels.insert(chan_id, chan_info);
 		}
 		let nodes_count: u64 = Readable::read(reader)?;
@@ -509,9 +1005,16 @@ impl Readable for NetworkGraph {
 			let node_info = Readable::read(reader)?;
 			nodes.insert(node_id, node_info);
 		}
+		let mut channels_by_funding_script = HashMap::new();
+		for (short_channel_id, chan_info) in channels.iter() {
+			if let Some(ref announcement) = chan_info.announcement_message {
+				channels_by_funding_script.insert(funding_redeemscript(&announcement.contents), *short_channel_id);
+			}
+		}
 		Ok(NetworkGraph {
 			channels,
 			nodes,
+			channels_by_funding_script,
 		})
 	}
 }
@@ -559,6 +1062,7 @@ impl NetworkGraph {
 		Self {
 			channels: BTreeMap::new(),
 			nodes: BTreeMap::new(),
+			channels_by_funding_script: HashMap::new(),
 		}
 	}
 
@@ -602,11 +1106,7 @@ impl NetworkGraph {
 	/// Announcement signatures are checked here only if Secp256k1 object is provided.
 	fn update_channel_from_announcement(&mut self, msg: &msgs::ChannelAnnouncement, utxo_value: Option<u64>, secp_ctx: Option<&Secp256k1<secp256k1::VerifyOnly>>) -> Result<bool, LightningError> {
 		if let Some(sig_verifier) = secp_ctx {
-			let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
-			secp_verify_sig!(sig_verifier, &msg_hash, &msg.node_signature_1, &msg.contents.node_id_1);
-			secp_verify_sig!(sig_verifier, &msg_hash, &msg.node_signature_2, &msg.contents.node_id_2);
-			secp_verify_sig!(sig_verifier, &msg_hash, &msg.bitcoin_signature_1, &msg.contents.bitcoin_key_1);
-			secp_verify_sig!(sig_verifier, &msg_hash, &msg.bitcoin_signature_2, &msg.contents.bitcoin_key_2);
+			verify_channel_announcement(msg, sig_verifier)?;
 		}
 
 		let should_relay = msg.contents.excess_data.is_empty();
@@ -621,7 +1121,40 @@ impl NetworkGraph {
 				announcement_message: if should_relay { Some(msg.clone()) } else { None },
 			};
 
-		match self.channels.entry(msg.contents.short_channel_id) {
+		self.add_channel_intern(msg.contents.short_channel_id, chan_info, Some(funding_redeemscript(&msg.contents)))?;
+		Ok(should_relay)
+	}
+
+	/// Adds a channel of which we don't have the channel_announcement, such as one from a graph
+	/// snapshot, to the graph. Unlike announced channels, it is never replaced by a later
+	/// announcement nor relayed to our peers, and we can't notice when it is spent on-chain.
+	pub(crate) fn add_channel_unannounced(&mut self, short_channel_id: u64, features: ChannelFeatures, node_one: PublicKey, node_two: PublicKey, capacity_sats: Option<u64>) -> Result<(), LightningError> {
+		if node_one == node_two {
+			return Err(LightningError{err: "Channel announcement node had a channel with itself".to_owned(), action: ErrorAction::IgnoreError});
+		}
+		if self.channels.contains_key(&short_channel_id) {
+			return Err(LightningError{err: "Already have knowledge of channel".to_owned(), action: ErrorAction::IgnoreError});
+		}
+		let chan_info = ChannelInfo {
+			features,
+			node_one,
+			one_to_two: None,
+			node_two,
+			two_to_one: None,
+			capacity_sats,
+			announcement_message: None,
+		};
+		self.add_channel_intern(short_channel_id, chan_info, None)
+	}
+
+	/// Stores a new channel, or replaces an existing one if chan_info has a capacity checked
+	/// on-chain, and makes its nodes aware of it.
+	fn add_channel_intern(&mut self, short_channel_id: u64, chan_info: ChannelInfo, funding_script: Option<Script>) -> Result<(), LightningError> {
+		let utxo_value = chan_info.capacity_sats;
+		let node_one = chan_info.node_one;
+		let node_two = chan_info.node_two;
+
+		match self.channels.entry(short_channel_id) {
 			BtreeEntry::Occupied(mut entry) => {
 				//TODO: because asking the blockchain if short_channel_id is valid is only optional
 				//in the blockchain API, we need to handle it smartly here, though it's unclear
@@ -635,7 +1168,8 @@ impl NetworkGraph {
 					// b) we don't track UTXOs of channels we know about and remove them if they
 					//    get reorg'd out.
 					// c) it's unclear how to do so without exposing ourselves to massive DoS risk.
-					Self::remove_channel_in_nodes(&mut self.nodes, &entry.get(), msg.contents.short_channel_id);
+					Self::remove_channel_in_nodes(&mut self.nodes, &entry.get(), short_channel_id);
+					Self::remove_channel_funding_script(&mut self.channels_by_funding_script, &entry.get(), short_channel_id);
 					*entry.get_mut() = chan_info;
 				} else {
 					return Err(LightningError{err: "Already have knowledge of channel".to_owned(), action: ErrorAction::IgnoreError})
@@ -645,16 +1179,19 @@ impl NetworkGraph {
 				entry.insert(chan_info);
 			}
 		};
+		if let Some(funding_script) = funding_script {
+			self.channels_by_funding_script.insert(funding_script, short_channel_id);
+		}
 
 		macro_rules! add_channel_to_node {
 			( $node_id: expr ) => {
 				match self.nodes.entry($node_id) {
 					BtreeEntry::Occupied(node_entry) => {
-						node_entry.into_mut().channels.push(msg.contents.short_channel_id);
+						node_entry.into_mut().channels.push(short_channel_id);
 					},
 					BtreeEntry::Vacant(node_entry) => {
 						node_entry.insert(NodeInfo {
-							channels: vec!(msg.contents.short_channel_id),
+							channels: vec!(short_channel_id),
 							lowest_inbound_channel_fees: None,
 							announcement_info: None,
 						});
@@ -663,10 +1200,10 @@ impl NetworkGraph {
 			};
 		}
 
-		add_channel_to_node!(msg.contents.node_id_1);
-		add_channel_to_node!(msg.contents.node_id_2);
+		add_channel_to_node!(node_one);
+		add_channel_to_node!(node_two);
 
-		Ok(should_relay)
+		Ok(())
 	}
 
 	/// Close a channel if a corresponding HTLC fail was sent.
@@ -675,9 +1212,7 @@ impl NetworkGraph {
 	/// If not permanent, makes channels unavailable for routing.
 	pub fn close_channel_from_update(&mut self, short_channel_id: u64, is_permanent: bool) {
 		if is_permanent {
-			if let Some(chan) = self.channels.remove(&short_channel_id) {
-				Self::remove_channel_in_nodes(&mut self.nodes, &chan, short_channel_id);
-			}
+			self.remove_channel(short_channel_id);
 		} else {
 			if let Some(chan) = self.channels.get_mut(&short_channel_id) {
 				if let Some(one_to_two) = chan.one_to_two.as_mut() {
@@ -690,9 +1225,48 @@ impl NetworkGraph {
 		}
 	}
 
-	fn fail_node(&mut self, _node_id: &PublicKey, is_permanent: bool) {
+	/// Removes the channels for which neither direction got a channel_update in the last
+	/// [`STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS`] before `current_time_unix` (in seconds since the UNIX
+	/// epoch), along with the nodes left without channels.
+	///
+	/// Channels we never received a channel_update for are removed once the block their
+	/// short_channel_id points to is [`STALE_CHANNEL_ANNOUNCEMENT_AGE_LIMIT_BLOCKS`] deep given the
+	/// `best_block_height`, as their announcement is at least that old.
+	///
+	/// This should be called periodically, eg once an hour, to keep the graph from growing without
+	/// bound as channels are closed without us noticing.
+	///
+	/// [`STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS`]: constant.STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS.html
+	/// [`STALE_CHANNEL_ANNOUNCEMENT_AGE_LIMIT_BLOCKS`]: constant.STALE_CHANNEL_ANNOUNCEMENT_AGE_LIMIT_BLOCKS.html
+	pub fn remove_stale_channels_with_time(&mut self, current_time_unix: u64, best_block_height: u32) {
+		let min_time_unix = current_time_unix.saturating_sub(STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS);
+		let min_block_height = best_block_height.saturating_sub(STALE_CHANNEL_ANNOUNCEMENT_AGE_LIMIT_BLOCKS);
+		let is_stale = |info: &Option<DirectionalChannelInfo>| match info {
+			&Some(ref info) => (info.last_update as u64) < min_time_unix,
+			&None => true,
+		};
+		let stale_channels: Vec<u64> = self.channels.iter().filter(|&(short_channel_id, chan)| {
+			if chan.one_to_two.is_none() && chan.two_to_one.is_none() {
+				((*short_channel_id >> 40) as u32) < min_block_height
+			} else {
+				is_stale(&chan.one_to_two) && is_stale(&chan.two_to_one)
+			}
+		}).map(|(short_channel_id, _)| *short_channel_id).collect();
+		for short_channel_id in stale_channels {
+			self.remove_channel(short_channel_id);
+		}
+	}
+
+	fn fail_node(&mut self, node_id: &PublicKey, is_permanent: bool) {
 		if is_permanent {
-			// TODO: Wholly remove the node
+			// Removing the last channel of the node removes the node as well.
+			let node_channels = match self.nodes.get(node_id) {
+				Some(node) => node.channels.clone(),
+				None => return,
+			};
+			for short_channel_id in node_channels {
+				self.remove_channel(short_channel_id);
+			}
 		} else {
 			// TODO: downgrade the node
 		}
@@ -701,14 +1275,24 @@ impl NetworkGraph {
 	/// For an already known (from announcement) channel, update info about one of the directions of a channel.
 	/// Announcement signatures are checked here only if Secp256k1 object is provided.
 	fn update_channel(&mut self, msg: &msgs::ChannelUpdate, secp_ctx: Option<&Secp256k1<secp256k1::VerifyOnly>>) -> Result<bool, LightningError> {
+		self.update_channel_intern(&msg.contents, Some(msg), secp_ctx.map(|secp_ctx| (&msg.signature, secp_ctx)))
+	}
+
+	/// Updates a channel direction from a channel_update of which we don't have the signature, such
+	/// as one from a graph snapshot. Such updates are never relayed to our peers.
+	pub(crate) fn update_channel_unsigned(&mut self, msg: &msgs::UnsignedChannelUpdate) -> Result<(), LightningError> {
+		self.update_channel_intern(msg, None, None).map(|_| ())
+	}
+
+	fn update_channel_intern(&mut self, msg: &msgs::UnsignedChannelUpdate, full_msg: Option<&msgs::ChannelUpdate>, sig_info: Option<(&secp256k1::Signature, &Secp256k1<secp256k1::VerifyOnly>)>) -> Result<bool, LightningError> {
 		let dest_node_id;
-		let chan_enabled = msg.contents.flags & (1 << 1) != (1 << 1);
+		let chan_enabled = msg.flags & (1 << 1) != (1 << 1);
 		let chan_was_enabled;
 
-		match self.channels.get_mut(&msg.contents.short_channel_id) {
+		match self.channels.get_mut(&msg.short_channel_id) {
 			None => return Err(LightningError{err: "Couldn't find channel for update".to_owned(), action: ErrorAction::IgnoreError}),
 			Some(channel) => {
-				if let OptionalField::Present(htlc_maximum_msat) = msg.contents.htlc_maximum_msat {
+				if let OptionalField::Present(htlc_maximum_msat) = msg.htlc_maximum_msat {
 					if htlc_maximum_msat > MAX_VALUE_MSAT {
 						return Err(LightningError{err: "htlc_maximum_msat is larger than maximum possible msats".to_owned(), action: ErrorAction::IgnoreError});
 					}
@@ -724,7 +1308,7 @@ impl NetworkGraph {
 				macro_rules! maybe_update_channel_info {
 					( $target: expr, $src_node: expr) => {
 						if let Some(existing_chan_info) = $target.as_ref() {
-							if existing_chan_info.last_update >= msg.contents.timestamp {
+							if existing_chan_info.last_update >= msg.timestamp {
 								return Err(LightningError{err: "Update older than last processed update".to_owned(), action: ErrorAction::IgnoreError});
 							}
 							chan_was_enabled = existing_chan_info.enabled;
@@ -732,21 +1316,21 @@ impl NetworkGraph {
 							chan_was_enabled = false;
 						}
 
-						let last_update_message = if msg.contents.excess_data.is_empty() {
-							Some(msg.clone())
+						let last_update_message = if msg.excess_data.is_empty() {
+							full_msg.cloned()
 						} else {
 							None
 						};
 
 						let updated_channel_dir_info = DirectionalChannelInfo {
 							enabled: chan_enabled,
-							last_update: msg.contents.timestamp,
-							cltv_expiry_delta: msg.contents.cltv_expiry_delta,
-							htlc_minimum_msat: msg.contents.htlc_minimum_msat,
-							htlc_maximum_msat: if let OptionalField::Present(max_value) = msg.contents.htlc_maximum_msat { Some(max_value) } else { None },
+							last_update: msg.timestamp,
+							cltv_expiry_delta: msg.cltv_expiry_delta,
+							htlc_minimum_msat: msg.htlc_minimum_msat,
+							htlc_maximum_msat: if let OptionalField::Present(max_value) = msg.htlc_maximum_msat { Some(max_value) } else { None },
 							fees: RoutingFees {
-								base_msat: msg.contents.fee_base_msat,
-								proportional_millionths: msg.contents.fee_proportional_millionths,
+								base_msat: msg.fee_base_msat,
+								proportional_millionths: msg.fee_proportional_millionths,
 							},
 							last_update_message
 						};
@@ -754,17 +1338,17 @@ impl NetworkGraph {
 					}
 				}
 
-				let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
-				if msg.contents.flags & 1 == 1 {
+				let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.encode()[..])[..]);
+				if msg.flags & 1 == 1 {
 					dest_node_id = channel.node_one.clone();
-					if let Some(sig_verifier) = secp_ctx {
-						secp_verify_sig!(sig_verifier, &msg_hash, &msg.signature, &channel.node_two);
+					if let Some((sig, sig_verifier)) = sig_info {
+						secp_verify_sig!(sig_verifier, &msg_hash, sig, &channel.node_two);
 					}
 					maybe_update_channel_info!(channel.two_to_one, channel.node_two);
 				} else {
 					dest_node_id = channel.node_two.clone();
-					if let Some(sig_verifier) = secp_ctx {
-						secp_verify_sig!(sig_verifier, &msg_hash, &msg.signature, &channel.node_one);
+					if let Some((sig, sig_verifier)) = sig_info {
+						secp_verify_sig!(sig_verifier, &msg_hash, sig, &channel.node_one);
 					}
 					maybe_update_channel_info!(channel.one_to_two, channel.node_one);
 				}
@@ -773,8 +1357,8 @@ impl NetworkGraph {
 
 		if chan_enabled {
 			let node = self.nodes.get_mut(&dest_node_id).unwrap();
-			let mut base_msat = msg.contents.fee_base_msat;
-			let mut proportional_millionths = msg.contents.fee_proportional_millionths;
+			let mut base_msat = msg.fee_base_msat;
+			let mut proportional_millionths = msg.fee_proportional_millionths;
 			if let Some(fees) = node.lowest_inbound_channel_fees {
 				base_msat = cmp::min(base_msat, fees.base_msat);
 				proportional_millionths = cmp::min(proportional_millionths, fees.proportional_millionths);
@@ -808,7 +1392,29 @@ impl NetworkGraph {
 			node.lowest_inbound_channel_fees = lowest_inbound_channel_fees;
 		}
 
-		Ok(msg.contents.excess_data.is_empty())
+		Ok(msg.excess_data.is_empty() && full_msg.is_some())
+	}
+
+	/// Removes a channel, along with the nodes left without channels.
+	fn remove_channel(&mut self, short_channel_id: u64) {
+		if let Some(chan) = self.channels.remove(&short_channel_id) {
+			Self::remove_channel_in_nodes(&mut self.nodes, &chan, short_channel_id);
+			Self::remove_channel_funding_script(&mut self.channels_by_funding_script, &chan, short_channel_id);
+		}
+	}
+
+	fn remove_channel_funding_script(channels_by_funding_script: &mut HashMap<Script, u64>, chan: &ChannelInfo, short_channel_id: u64) {
+		match chan.announcement_message {
+			Some(ref announcement) => {
+				// Another channel may have been announced with the same funding keys since.
+				let funding_script = funding_redeemscript(&announcement.contents);
+				if channels_by_funding_script.get(&funding_script) == Some(&short_channel_id) {
+					channels_by_funding_script.remove(&funding_script);
+				}
+			},
+			// We indexed the channel when it was announced, but didn't store the announcement.
+			None => channels_by_funding_script.retain(|_, chan_id| *chan_id != short_channel_id),
+		}
 	}
 
 	fn remove_channel_in_nodes(nodes: &mut BTreeMap<PublicKey, NodeInfo>, chan: &ChannelInfo, short_channel_id: u64) {
@@ -835,21 +1441,26 @@ impl NetworkGraph {
 #[cfg(test)]
 mod tests {
 	use chain;
+	use chain::transaction::OutPoint;
 	use ln::features::{ChannelFeatures, NodeFeatures};
-	use routing::network_graph::{NetGraphMsgHandler, NetworkGraph};
+	use routing::network_graph::{NetGraphMsgHandler, NetworkGraph, STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS, STALE_CHANNEL_ANNOUNCEMENT_AGE_LIMIT_BLOCKS, UTXO_LOOKUP_TIMEOUT_TICKS, funding_redeemscript};
+	use ln::features::InitFeatures;
 	use ln::msgs::{OptionalField, RoutingMessageHandler, UnsignedNodeAnnouncement, NodeAnnouncement,
 		UnsignedChannelAnnouncement, ChannelAnnouncement, UnsignedChannelUpdate, ChannelUpdate, HTLCFailChannelUpdate,
-		MAX_VALUE_MSAT};
+		QueryChannelRange, QueryShortChannelIds, ReplyChannelRange, ReplyShortChannelIdsEnd, Init, MAX_VALUE_MSAT};
+	use util::events::{MessageSendEvent, MessageSendEventsProvider};
 	use util::test_utils;
 	use util::logger::Logger;
 	use util::ser::{Readable, Writeable};
 
 	use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 	use bitcoin::hashes::Hash;
+	use bitcoin::hash_types::BlockHash;
 	use bitcoin::network::constants::Network;
 	use bitcoin::blockdata::constants::genesis_block;
-	use bitcoin::blockdata::script::Builder;
-	use bitcoin::blockdata::transaction::TxOut;
+	use bitcoin::blockdata::block::BlockHeader;
+	use bitcoin::blockdata::script::{Builder, Script};
+	use bitcoin::blockdata::transaction::{OutPoint as BitcoinOutPoint, Transaction, TxIn, TxOut};
 	use bitcoin::blockdata::opcodes;
 
 	use hex;
@@ -857,12 +1468,12 @@ mod tests {
 	use bitcoin::secp256k1::key::{PublicKey, SecretKey};
 	use bitcoin::secp256k1::{All, Secp256k1};
 
-	use std::sync::Arc;
+	use std::sync::{Arc, Mutex, Weak};
 
 	fn create_net_graph_msg_handler() -> (Secp256k1<All>, NetGraphMsgHandler<Arc<test_utils::TestChainSource>, Arc<test_utils::TestLogger>>) {
 		let secp_ctx = Secp256k1::new();
 		let logger = Arc::new(test_utils::TestLogger::new());
-		let net_graph_msg_handler = NetGraphMsgHandler::new(None, Arc::clone(&logger));
+		let net_graph_msg_handler = NetGraphMsgHandler::new(genesis_block(Network::Testnet).header.block_hash(), None, Arc::clone(&logger));
 		(secp_ctx, net_graph_msg_handler)
 	}
 
@@ -1023,7 +1634,7 @@ mod tests {
 		};
 
 		// Test if the UTXO lookups were not supported
-		let mut net_graph_msg_handler = NetGraphMsgHandler::new(None, Arc::clone(&logger));
+		let mut net_graph_msg_handler = NetGraphMsgHandler::new(genesis_block(Network::Testnet).header.block_hash(), None, Arc::clone(&logger));
 		match net_graph_msg_handler.handle_channel_announcement(&valid_announcement) {
 			Ok(res) => assert!(res),
 			_ => panic!()
@@ -1047,7 +1658,7 @@ mod tests {
 		// Test if an associated transaction were not on-chain (or not confirmed).
 		let chain_source = Arc::new(test_utils::TestChainSource::new(Network::Testnet));
 		*chain_source.utxo_ret.lock().unwrap() = Err(chain::AccessError::UnknownTx);
-		net_graph_msg_handler = NetGraphMsgHandler::new(Some(chain_source.clone()), Arc::clone(&logger));
+		net_graph_msg_handler = NetGraphMsgHandler::new(genesis_block(Network::Testnet).header.block_hash(), Some(chain_source.clone()), Arc::clone(&logger));
 		unsigned_announcement.short_channel_id += 1;
 
 		msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_announcement.encode()[..])[..]);
@@ -1089,6 +1700,12 @@ mod tests {
 			}
 		}
 
+		// Its funding output is registered with the chain::Filter to notice when it's spent.
+		assert!(chain_source.watched_outputs.lock().unwrap().is_empty());
+		net_graph_msg_handler.register_funding_outputs(&*chain_source);
+		let funding_outpoint = test_utils::TestChainSource::funding_outpoint(unsigned_announcement.short_channel_id);
+		assert_eq!(*chain_source.watched_outputs.lock().unwrap(), vec![(funding_outpoint, good_script.clone())].into_iter().collect());
+
 		// If we receive announcement for the same channel (but TX is not confirmed),
 		// drop new one on the floor, since we can't see any changes.
 		*chain_source.utxo_ret.lock().unwrap() = Err(chain::AccessError::UnknownTx);
@@ -1171,7 +1788,7 @@ mod tests {
 		let secp_ctx = Secp256k1::new();
 		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
 		let chain_source = Arc::new(test_utils::TestChainSource::new(Network::Testnet));
-		let net_graph_msg_handler = NetGraphMsgHandler::new(Some(chain_source.clone()), Arc::clone(&logger));
+		let net_graph_msg_handler = NetGraphMsgHandler::new(genesis_block(Network::Testnet).header.block_hash(), Some(chain_source.clone()), Arc::clone(&logger));
 
 		let node_1_privkey = &SecretKey::from_slice(&[42; 32]).unwrap();
 		let node_2_privkey = &SecretKey::from_slice(&[41; 32]).unwrap();
@@ -1446,7 +2063,28 @@ mod tests {
 			// Nodes are also deleted because there are no associated channels anymore
 			assert_eq!(network.get_nodes().len(), 0);
 		}
-		// TODO: Test HTLCFailChannelUpdate::NodeFailure, which is not implemented yet.
+
+		// A permanent node failure removes the node along with all its channels.
+		let node_1_pubkey = PublicKey::from_secret_key(&secp_ctx, node_1_privkey);
+		for short_channel_id in 1..3 {
+			net_graph_msg_handler.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, short_channel_id)).unwrap();
+		}
+		net_graph_msg_handler.handle_htlc_fail_channel_update(&HTLCFailChannelUpdate::NodeFailure {
+			node_id: node_1_pubkey,
+			is_permanent: false,
+		});
+		assert_eq!(net_graph_msg_handler.network_graph.read().unwrap().get_channels().len(), 2);
+
+		net_graph_msg_handler.handle_htlc_fail_channel_update(&HTLCFailChannelUpdate::NodeFailure {
+			node_id: node_1_pubkey,
+			is_permanent: true,
+		});
+		{
+			let network = net_graph_msg_handler.network_graph.read().unwrap();
+			assert_eq!(network.get_channels().len(), 0);
+			assert_eq!(network.get_nodes().len(), 0);
+			assert!(network.channels_by_funding_script.is_empty());
+		}
 	}
 
 	#[test]
@@ -1766,4 +2404,568 @@ mod tests {
 		network.write(&mut w).unwrap();
 		assert!(<NetworkGraph>::read(&mut ::std::io::Cursor::new(&w.0)).unwrap() == *network);
 	}
+
+	fn get_signed_channel_announcement(secp_ctx: &Secp256k1<All>, short_channel_id: u64) -> ChannelAnnouncement {
+		let node_1_privkey = &SecretKey::from_slice(&[42; 32]).unwrap();
+		let node_2_privkey = &SecretKey::from_slice(&[41; 32]).unwrap();
+		let node_1_btckey = &SecretKey::from_slice(&[40; 32]).unwrap();
+		let node_2_btckey = &SecretKey::from_slice(&[39; 32]).unwrap();
+		let unsigned_announcement = UnsignedChannelAnnouncement {
+			features: ChannelFeatures::known(),
+			chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+			short_channel_id,
+			node_id_1: PublicKey::from_secret_key(&secp_ctx, node_1_privkey),
+			node_id_2: PublicKey::from_secret_key(&secp_ctx, node_2_privkey),
+			bitcoin_key_1: PublicKey::from_secret_key(&secp_ctx, node_1_btckey),
+			bitcoin_key_2: PublicKey::from_secret_key(&secp_ctx, node_2_btckey),
+			excess_data: Vec::new(),
+		};
+		let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_announcement.encode()[..])[..]);
+		ChannelAnnouncement {
+			node_signature_1: secp_ctx.sign(&msghash, node_1_privkey),
+			node_signature_2: secp_ctx.sign(&msghash, node_2_privkey),
+			bitcoin_signature_1: secp_ctx.sign(&msghash, node_1_btckey),
+			bitcoin_signature_2: secp_ctx.sign(&msghash, node_2_btckey),
+			contents: unsigned_announcement,
+		}
+	}
+
+	fn get_signed_channel_update(secp_ctx: &Secp256k1<All>, short_channel_id: u64, timestamp: u32, flags: u8) -> ChannelUpdate {
+		let node_privkey = &SecretKey::from_slice(&[if flags & 1 == 0 { 42 } else { 41 }; 32]).unwrap();
+		let unsigned_channel_update = UnsignedChannelUpdate {
+			chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+			short_channel_id,
+			timestamp,
+			flags,
+			cltv_expiry_delta: 144,
+			htlc_minimum_msat: 1000000,
+			htlc_maximum_msat: OptionalField::Absent,
+			fee_base_msat: 10000,
+			fee_proportional_millionths: 20,
+			excess_data: Vec::new()
+		};
+		let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_channel_update.encode()[..])[..]);
+		ChannelUpdate {
+			signature: secp_ctx.sign(&msghash, node_privkey),
+			contents: unsigned_channel_update,
+		}
+	}
+
+	#[test]
+	fn handling_async_utxo_lookups() {
+		let secp_ctx = Secp256k1::new();
+		let logger = Arc::new(test_utils::TestLogger::new());
+		let chain_source = Arc::new(test_utils::TestChainSource::new(Network::Testnet));
+		*chain_source.utxo_ret.lock().unwrap() = Err(chain::AccessError::Pending);
+		let net_graph_msg_handler = NetGraphMsgHandler::new(genesis_block(Network::Testnet).header.block_hash(), Some(chain_source.clone()), Arc::clone(&logger));
+
+		let good_utxo = TxOut { value: 1000, script_pubkey: Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_2)
+			.push_slice(&PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[40; 32]).unwrap()).serialize())
+			.push_slice(&PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[39; 32]).unwrap()).serialize())
+			.push_opcode(opcodes::all::OP_PUSHNUM_2)
+			.push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script().to_v0_p2wsh() };
+
+		// The announcement is queued, not relayed, and looked up only once.
+		let announcement = get_signed_channel_announcement(&secp_ctx, 1);
+		assert!(!net_graph_msg_handler.handle_channel_announcement(&announcement).unwrap());
+		assert!(net_graph_msg_handler.network_graph.read().unwrap().get_channels().is_empty());
+		match net_graph_msg_handler.handle_channel_announcement(&announcement) {
+			Ok(_) => panic!(),
+			Err(e) => assert_eq!(e.err, "Channel announcement is already pending UTXO lookup")
+		};
+		assert_eq!(*chain_source.utxo_lookups.lock().unwrap(), vec![1]);
+
+		// Forged announcements aren't looked up.
+		let mut forged_announcement = get_signed_channel_announcement(&secp_ctx, 2);
+		forged_announcement.contents.short_channel_id = 3;
+		assert!(net_graph_msg_handler.handle_channel_announcement(&forged_announcement).is_err());
+
+		// The latest valid update in each direction is queued along with the announcement.
+		assert!(!net_graph_msg_handler.handle_channel_update(&get_signed_channel_update(&secp_ctx, 1, 100, 0)).unwrap());
+		assert!(!net_graph_msg_handler.handle_channel_update(&get_signed_channel_update(&secp_ctx, 1, 101, 0)).unwrap());
+		match net_graph_msg_handler.handle_channel_update(&get_signed_channel_update(&secp_ctx, 1, 99, 0)) {
+			Ok(_) => panic!(),
+			Err(e) => assert_eq!(e.err, "Update older than last processed update")
+		};
+		let mut forged_update = get_signed_channel_update(&secp_ctx, 1, 100, 1);
+		forged_update.contents.timestamp = 200;
+		match net_graph_msg_handler.handle_channel_update(&forged_update) {
+			Ok(_) => panic!(),
+			Err(e) => assert_eq!(e.err, "Invalid signature from remote node")
+		};
+		assert!(!net_graph_msg_handler.handle_channel_update(&get_signed_channel_update(&secp_ctx, 1, 100, 1)).unwrap());
+
+		let funding_outpoint = test_utils::TestChainSource::funding_outpoint(1);
+		net_graph_msg_handler.utxo_lookup_completed(1, Ok((funding_outpoint, good_utxo.clone())));
+		{
+			let network = net_graph_msg_handler.network_graph.read().unwrap();
+			let channel = network.get_channels().get(&1).unwrap();
+			assert_eq!(channel.capacity_sats, Some(1000));
+			assert_eq!(channel.one_to_two.as_ref().unwrap().last_update, 101);
+			assert_eq!(channel.two_to_one.as_ref().unwrap().last_update, 100);
+		}
+		net_graph_msg_handler.register_funding_outputs(&*chain_source);
+		assert_eq!(*chain_source.watched_outputs.lock().unwrap(), vec![(funding_outpoint, good_utxo.script_pubkey.clone())].into_iter().collect());
+
+		// The channel is relayed along with its updates, as it would have been with a synchronous
+		// lookup.
+		let events = net_graph_msg_handler.get_and_clear_pending_msg_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			MessageSendEvent::RelayChannelAnnouncement { ref msg, ref update_msgs } => {
+				assert_eq!(*msg, announcement);
+				assert_eq!(*update_msgs, vec![get_signed_channel_update(&secp_ctx, 1, 101, 0), get_signed_channel_update(&secp_ctx, 1, 100, 1)]);
+			},
+			_ => panic!("Unexpected event"),
+		}
+
+		// Updates for the channel now go to the network graph directly.
+		assert!(net_graph_msg_handler.handle_channel_update(&get_signed_channel_update(&secp_ctx, 1, 102, 0)).unwrap());
+
+		// Failed lookups drop the announcement, which can then be looked up again.
+		let announcement = get_signed_channel_announcement(&secp_ctx, 2);
+		assert!(!net_graph_msg_handler.handle_channel_announcement(&announcement).unwrap());
+		net_graph_msg_handler.utxo_lookup_completed(2, Err(chain::AccessError::UnknownTx));
+		assert!(!net_graph_msg_handler.handle_channel_announcement(&announcement).unwrap());
+		net_graph_msg_handler.utxo_lookup_completed(2, Ok((test_utils::TestChainSource::funding_outpoint(2), TxOut { value: 1000, script_pubkey: Script::new() })));
+		assert!(!net_graph_msg_handler.network_graph.read().unwrap().get_channels().contains_key(&2));
+		assert_eq!(*chain_source.utxo_lookups.lock().unwrap(), vec![1, 2, 2]);
+
+		// Lookups time out after UTXO_LOOKUP_TIMEOUT_TICKS full ticks.
+		let announcement = get_signed_channel_announcement(&secp_ctx, 4);
+		assert!(!net_graph_msg_handler.handle_channel_announcement(&announcement).unwrap());
+		for _ in 0..UTXO_LOOKUP_TIMEOUT_TICKS {
+			net_graph_msg_handler.timer_tick_occurred(0);
+		}
+		assert!(net_graph_msg_handler.handle_channel_announcement(&announcement).is_err());
+		net_graph_msg_handler.timer_tick_occurred(0);
+		net_graph_msg_handler.utxo_lookup_completed(4, Ok((test_utils::TestChainSource::funding_outpoint(4), good_utxo)));
+		assert!(!net_graph_msg_handler.network_graph.read().unwrap().get_channels().contains_key(&4));
+		net_graph_msg_handler.register_funding_outputs(&*chain_source);
+		assert_eq!(chain_source.watched_outputs.lock().unwrap().len(), 1);
+		assert!(net_graph_msg_handler.get_and_clear_pending_msg_events().is_empty());
+	}
+
+	/// A chain::Access which provides the result of its lookups before returning Pending.
+	struct EagerChainSource {
+		utxo: TxOut,
+		net_graph_msg_handler: Mutex<Weak<NetGraphMsgHandler<Arc<EagerChainSource>, Arc<test_utils::TestLogger>>>>,
+	}
+	impl chain::Access for EagerChainSource {
+		fn get_utxo(&self, _genesis_hash: &BlockHash, short_channel_id: u64) -> Result<(OutPoint, TxOut), chain::AccessError> {
+			let net_graph_msg_handler = self.net_graph_msg_handler.lock().unwrap().upgrade().unwrap();
+			net_graph_msg_handler.utxo_lookup_completed(short_channel_id, Ok((test_utils::TestChainSource::funding_outpoint(short_channel_id), self.utxo.clone())));
+			Err(chain::AccessError::Pending)
+		}
+	}
+
+	#[test]
+	fn handling_utxo_lookups_completed_before_returning() {
+		let secp_ctx = Secp256k1::new();
+		let logger = Arc::new(test_utils::TestLogger::new());
+		let announcement = get_signed_channel_announcement(&secp_ctx, 1);
+		let chain_source = Arc::new(EagerChainSource {
+			utxo: TxOut { value: 1000, script_pubkey: Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_2)
+				.push_slice(&announcement.contents.bitcoin_key_1.serialize())
+				.push_slice(&announcement.contents.bitcoin_key_2.serialize())
+				.push_opcode(opcodes::all::OP_PUSHNUM_2)
+				.push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script().to_v0_p2wsh() },
+			net_graph_msg_handler: Mutex::new(Weak::new()),
+		});
+		let net_graph_msg_handler = Arc::new(NetGraphMsgHandler::new(genesis_block(Network::Testnet).header.block_hash(), Some(Arc::clone(&chain_source)), Arc::clone(&logger)));
+		*chain_source.net_graph_msg_handler.lock().unwrap() = Arc::downgrade(&net_graph_msg_handler);
+
+		// The lookup is pending by the time its result is provided, so the channel is added and
+		// relayed rather than the result being ignored.
+		assert!(!net_graph_msg_handler.handle_channel_announcement(&announcement).unwrap());
+		assert_eq!(net_graph_msg_handler.network_graph.read().unwrap().get_channels().get(&1).unwrap().capacity_sats, Some(1000));
+		assert!(net_graph_msg_handler.pending_utxo_lookups.lock().unwrap().is_empty());
+		match net_graph_msg_handler.get_and_clear_pending_msg_events()[..] {
+			[MessageSendEvent::RelayChannelAnnouncement { ref msg, ref update_msgs }] => {
+				assert_eq!(*msg, announcement);
+				assert!(update_msgs.is_empty());
+			},
+			_ => panic!("Unexpected events"),
+		}
+	}
+
+	#[test]
+	fn handling_query_channel_range() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+		let their_node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+
+		// Two channels in each of the blocks 1 to 4001, added without checking their signatures so
+		// that we can reuse a single announcement.
+		let announcement = get_signed_channel_announcement(&secp_ctx, 0);
+		{
+			let mut network_graph = net_graph_msg_handler.network_graph.write().unwrap();
+			for block in 1..4002u64 {
+				for tx_index in 0..2u64 {
+					let mut announcement = announcement.clone();
+					announcement.contents.short_channel_id = (block << 40) | (tx_index << 16);
+					network_graph.update_channel_from_announcement(&announcement, None, None).unwrap();
+				}
+			}
+		}
+
+		// A query for some blocks is answered with the channels in them.
+		net_graph_msg_handler.handle_query_channel_range(&their_node_id, &QueryChannelRange {
+			chain_hash, first_blocknum: 2, number_of_blocks: 2,
+		}).unwrap();
+		let events = net_graph_msg_handler.get_and_clear_pending_msg_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			MessageSendEvent::SendReplyChannelRange { ref node_id, ref msg } => {
+				assert_eq!(*node_id, their_node_id);
+				assert_eq!(*msg, ReplyChannelRange {
+					chain_hash, first_blocknum: 2, number_of_blocks: 2, full_information: true,
+					short_channel_ids: vec![2 << 40, (2 << 40) | (1 << 16), 3 << 40, (3 << 40) | (1 << 16)],
+				});
+			},
+			_ => panic!("Unexpected event"),
+		}
+
+		// A query for all blocks is split over several replies, covering the whole queried range.
+		net_graph_msg_handler.handle_query_channel_range(&their_node_id, &QueryChannelRange {
+			chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff,
+		}).unwrap();
+		let events = net_graph_msg_handler.get_and_clear_pending_msg_events();
+		assert_eq!(events.len(), 2);
+		match events[0] {
+			MessageSendEvent::SendReplyChannelRange { ref msg, .. } => {
+				assert_eq!(msg.first_blocknum, 0);
+				assert_eq!(msg.number_of_blocks, 4001);
+				assert_eq!(msg.short_channel_ids.len(), 8000);
+				assert_eq!(*msg.short_channel_ids.last().unwrap(), (4000 << 40) | (1 << 16));
+			},
+			_ => panic!("Unexpected event"),
+		}
+		match events[1] {
+			MessageSendEvent::SendReplyChannelRange { ref msg, .. } => {
+				assert_eq!(msg.first_blocknum, 4001);
+				assert_eq!(msg.number_of_blocks, 0xffff_ffff - 4001);
+				assert_eq!(msg.short_channel_ids, vec![4001 << 40, (4001 << 40) | (1 << 16)]);
+			},
+			_ => panic!("Unexpected event"),
+		}
+
+		// A query for another chain is answered with an empty reply.
+		let other_chain_hash = genesis_block(Network::Bitcoin).header.block_hash();
+		assert!(net_graph_msg_handler.handle_query_channel_range(&their_node_id, &QueryChannelRange {
+			chain_hash: other_chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff,
+		}).is_err());
+		let events = net_graph_msg_handler.get_and_clear_pending_msg_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			MessageSendEvent::SendReplyChannelRange { ref msg, .. } => {
+				assert_eq!(msg.chain_hash, other_chain_hash);
+				assert!(!msg.full_information);
+				assert!(msg.short_channel_ids.is_empty());
+			},
+			_ => panic!("Unexpected event"),
+		}
+	}
+
+	#[test]
+	fn handling_query_short_channel_ids() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+		let their_node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		let node_1_privkey = &SecretKey::from_slice(&[42; 32]).unwrap();
+
+		let announcement_1 = get_signed_channel_announcement(&secp_ctx, 1 << 40);
+		let announcement_2 = get_signed_channel_announcement(&secp_ctx, 2 << 40);
+		let update_1 = get_signed_channel_update(&secp_ctx, 1 << 40, 100, 0);
+		net_graph_msg_handler.handle_channel_announcement(&announcement_1).unwrap();
+		net_graph_msg_handler.handle_channel_announcement(&announcement_2).unwrap();
+		net_graph_msg_handler.handle_channel_update(&update_1).unwrap();
+
+		let unsigned_node_announcement = UnsignedNodeAnnouncement {
+			features: NodeFeatures::known(),
+			timestamp: 100,
+			node_id: PublicKey::from_secret_key(&secp_ctx, node_1_privkey),
+			rgb: [0; 3],
+			alias: [0; 32],
+			addresses: Vec::new(),
+			excess_address_data: Vec::new(),
+			excess_data: Vec::new(),
+		};
+		let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_node_announcement.encode()[..])[..]);
+		let node_announcement = NodeAnnouncement {
+			signature: secp_ctx.sign(&msghash, node_1_privkey),
+			contents: unsigned_node_announcement,
+		};
+		net_graph_msg_handler.handle_node_announcement(&node_announcement).unwrap();
+
+		// Queries for our chain are left for the PeerManager to reply to.
+		net_graph_msg_handler.handle_query_short_channel_ids(&their_node_id, &QueryShortChannelIds {
+			chain_hash, short_channel_ids: vec![1 << 40, 2 << 40, 3 << 40],
+		}).unwrap();
+		assert!(net_graph_msg_handler.get_and_clear_pending_msg_events().is_empty());
+
+		// Unknown channels are skipped and node_announcements are only sent once.
+		let (channel_announcements, node_announcements) = net_graph_msg_handler.get_gossip_for_short_channel_ids(&[1 << 40, 2 << 40, 3 << 40]);
+		assert_eq!(channel_announcements, vec![(announcement_1, Some(update_1), None), (announcement_2, None, None)]);
+		assert_eq!(node_announcements, vec![node_announcement]);
+
+		let other_chain_hash = genesis_block(Network::Bitcoin).header.block_hash();
+		assert!(net_graph_msg_handler.handle_query_short_channel_ids(&their_node_id, &QueryShortChannelIds {
+			chain_hash: other_chain_hash, short_channel_ids: vec![1 << 40],
+		}).is_err());
+		let events = net_graph_msg_handler.get_and_clear_pending_msg_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			MessageSendEvent::SendShortIdsReply { ref node_id, ref msg } => {
+				assert_eq!(*node_id, their_node_id);
+				assert_eq!(*msg, ReplyShortChannelIdsEnd { chain_hash: other_chain_hash, full_information: false });
+			},
+			_ => panic!("Unexpected event"),
+		}
+	}
+
+	#[test]
+	fn querying_missing_channels() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+		let their_node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		net_graph_msg_handler.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, 1 << 40)).unwrap();
+
+		// Replies from peers we didn't query are ignored.
+		let reply = ReplyChannelRange {
+			chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff, full_information: true,
+			short_channel_ids: vec![1 << 40, 2 << 40, 3 << 40],
+		};
+		assert!(net_graph_msg_handler.handle_reply_channel_range(&their_node_id, &reply).is_err());
+
+		// We don't query peers which don't support gossip queries.
+		net_graph_msg_handler.sync_routing_table(&their_node_id, &Init { features: InitFeatures::known().clear_gossip_queries() });
+		assert!(net_graph_msg_handler.get_and_clear_pending_msg_events().is_empty());
+
+		// Only gossip newer than the time of the last timer tick is requested.
+		net_graph_msg_handler.timer_tick_occurred(1_600_000_000);
+		net_graph_msg_handler.sync_routing_table(&their_node_id, &Init { features: InitFeatures::known() });
+		let events = net_graph_msg_handler.get_and_clear_pending_msg_events();
+		assert_eq!(events.len(), 2);
+		match events[0] {
+			MessageSendEvent::SendGossipTimestampFilter { ref node_id, ref msg } => {
+				assert_eq!(*node_id, their_node_id);
+				assert_eq!(msg.chain_hash, chain_hash);
+				assert_eq!(msg.first_timestamp, 1_600_000_000);
+				assert_eq!(msg.first_timestamp as u64 + msg.timestamp_range as u64, 0xffff_ffff);
+			},
+			_ => panic!("Unexpected event"),
+		}
+		match events[1] {
+			MessageSendEvent::SendChannelRangeQuery { ref node_id, ref msg } => {
+				assert_eq!(*node_id, their_node_id);
+				assert_eq!(*msg, QueryChannelRange { chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff });
+			},
+			_ => panic!("Unexpected event"),
+		}
+
+		// Only the channels we don't know about are queried.
+		net_graph_msg_handler.handle_reply_channel_range(&their_node_id, &reply).unwrap();
+		let events = net_graph_msg_handler.get_and_clear_pending_msg_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			MessageSendEvent::SendShortIdsQuery { ref node_id, ref msg } => {
+				assert_eq!(*node_id, their_node_id);
+				assert_eq!(*msg, QueryShortChannelIds { chain_hash, short_channel_ids: vec![2 << 40, 3 << 40] });
+			},
+			_ => panic!("Unexpected event"),
+		}
+
+		// We wait for the end of the reply to our query before sending another one.
+		net_graph_msg_handler.handle_reply_channel_range(&their_node_id, &ReplyChannelRange {
+			chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff, full_information: true,
+			short_channel_ids: vec![3 << 40, 4 << 40],
+		}).unwrap();
+		assert!(net_graph_msg_handler.get_and_clear_pending_msg_events().is_empty());
+
+		net_graph_msg_handler.handle_reply_short_channel_ids_end(&their_node_id, &ReplyShortChannelIdsEnd { chain_hash, full_information: true }).unwrap();
+		let events = net_graph_msg_handler.get_and_clear_pending_msg_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			MessageSendEvent::SendShortIdsQuery { ref msg, .. } => {
+				assert_eq!(msg.short_channel_ids, vec![4 << 40]);
+			},
+			_ => panic!("Unexpected event"),
+		}
+
+		net_graph_msg_handler.handle_reply_short_channel_ids_end(&their_node_id, &ReplyShortChannelIdsEnd { chain_hash, full_information: true }).unwrap();
+		assert!(net_graph_msg_handler.get_and_clear_pending_msg_events().is_empty());
+		assert!(net_graph_msg_handler.handle_reply_short_channel_ids_end(&their_node_id, &ReplyShortChannelIdsEnd { chain_hash, full_information: true }).is_err());
+	}
+
+	/// Delivers the gossip queries related messages generated by the handler of from_node_id to the
+	/// handlers of the given peers, returning the number of messages delivered.
+	fn deliver_gossip_query_messages(from_node_id: &PublicKey, from: &NetGraphMsgHandler<Arc<test_utils::TestChainSource>, Arc<test_utils::TestLogger>>,
+			peers: &[(PublicKey, &NetGraphMsgHandler<Arc<test_utils::TestChainSource>, Arc<test_utils::TestLogger>>)]) -> usize {
+		let get_peer = |node_id: &PublicKey| peers.iter().find(|&&(ref peer_id, _)| peer_id == node_id).unwrap().1;
+		let mut delivered = 0;
+		for event in from.get_and_clear_pending_msg_events() {
+			match event {
+				MessageSendEvent::SendChannelRangeQuery { ref node_id, ref msg } => {
+					get_peer(node_id).handle_query_channel_range(from_node_id, msg).unwrap();
+				},
+				MessageSendEvent::SendReplyChannelRange { ref node_id, ref msg } => {
+					get_peer(node_id).handle_reply_channel_range(from_node_id, msg).unwrap();
+				},
+				MessageSendEvent::SendShortIdsQuery { ref node_id, ref msg } => {
+					// Reply as the PeerManager would, all at once.
+					let peer = get_peer(node_id);
+					peer.handle_query_short_channel_ids(from_node_id, msg).unwrap();
+					let (channel_announcements, node_announcements) = peer.get_gossip_for_short_channel_ids(&msg.short_channel_ids);
+					for &(ref announcement, ref update_a, ref update_b) in channel_announcements.iter() {
+						from.handle_channel_announcement(announcement).unwrap();
+						if let &Some(ref update) = update_a { from.handle_channel_update(update).unwrap(); }
+						if let &Some(ref update) = update_b { from.handle_channel_update(update).unwrap(); }
+					}
+					for announcement in node_announcements.iter() {
+						from.handle_node_announcement(announcement).unwrap();
+					}
+					from.handle_reply_short_channel_ids_end(node_id, &ReplyShortChannelIdsEnd { chain_hash: msg.chain_hash, full_information: true }).unwrap();
+				},
+				MessageSendEvent::SendGossipTimestampFilter { .. } => {},
+				_ => panic!("Unexpected event"),
+			}
+			delivered += 1;
+		}
+		delivered
+	}
+
+	#[test]
+	fn syncing_with_multiple_peers() {
+		// Sync the network graph of a node knowing about channel 1 from two peers, one knowing about
+		// channels 1, 2 and 3 and the other about channels 2, 3 and 4. Each missing channel is
+		// only queried from a single peer.
+		let secp_ctx = Secp256k1::new();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		let peer_a_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[3; 32]).unwrap());
+		let peer_b_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[4; 32]).unwrap());
+		let (_, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let (_, peer_a) = create_net_graph_msg_handler();
+		let (_, peer_b) = create_net_graph_msg_handler();
+
+		for block in 1..5u64 {
+			let announcement = get_signed_channel_announcement(&secp_ctx, block << 40);
+			let update = get_signed_channel_update(&secp_ctx, block << 40, 100, 0);
+			if block == 1 {
+				net_graph_msg_handler.handle_channel_announcement(&announcement).unwrap();
+			}
+			if block <= 3 {
+				peer_a.handle_channel_announcement(&announcement).unwrap();
+				peer_a.handle_channel_update(&update).unwrap();
+			}
+			if block >= 2 {
+				peer_b.handle_channel_announcement(&announcement).unwrap();
+				peer_b.handle_channel_update(&update).unwrap();
+			}
+		}
+
+		net_graph_msg_handler.sync_routing_table(&peer_a_id, &Init { features: InitFeatures::known() });
+		net_graph_msg_handler.sync_routing_table(&peer_b_id, &Init { features: InitFeatures::known() });
+		let mut queries_sent = Vec::new();
+		loop {
+			for event in net_graph_msg_handler.get_and_clear_pending_msg_events() {
+				if let MessageSendEvent::SendShortIdsQuery { ref node_id, ref msg } = event {
+					queries_sent.push((*node_id, msg.short_channel_ids.clone()));
+				}
+				net_graph_msg_handler.pending_events.lock().unwrap().push(event);
+			}
+			let delivered = deliver_gossip_query_messages(&node_id, &net_graph_msg_handler, &[(peer_a_id, &peer_a), (peer_b_id, &peer_b)]) +
+				deliver_gossip_query_messages(&peer_a_id, &peer_a, &[(node_id, &net_graph_msg_handler)]) +
+				deliver_gossip_query_messages(&peer_b_id, &peer_b, &[(node_id, &net_graph_msg_handler)]);
+			if delivered == 0 { break; }
+		}
+
+		assert_eq!(queries_sent, vec![(peer_a_id, vec![2 << 40, 3 << 40]), (peer_b_id, vec![4 << 40])]);
+		let network_graph = net_graph_msg_handler.network_graph.read().unwrap();
+		assert_eq!(network_graph.get_channels().keys().cloned().collect::<Vec<u64>>(), vec![1 << 40, 2 << 40, 3 << 40, 4 << 40]);
+		assert!(network_graph.get_channels().get(&(4 << 40)).unwrap().one_to_two.is_some());
+	}
+
+	#[test]
+	fn removing_stale_channels() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let current_time = 100 + STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS as u32;
+		let best_block_height = 100 + STALE_CHANNEL_ANNOUNCEMENT_AGE_LIMIT_BLOCKS;
+
+		// Channel 1 only has a stale update, channel 2 has a stale and a recent one, channel 3,
+		// funded at height 100, has no update at all.
+		let chan_3 = 100 << 40;
+		for &short_channel_id in [1, 2, chan_3].iter() {
+			net_graph_msg_handler.handle_channel_announcement(&get_signed_channel_announcement(&secp_ctx, short_channel_id)).unwrap();
+		}
+		net_graph_msg_handler.handle_channel_update(&get_signed_channel_update(&secp_ctx, 1, 99, 0)).unwrap();
+		net_graph_msg_handler.handle_channel_update(&get_signed_channel_update(&secp_ctx, 2, 99, 0)).unwrap();
+		net_graph_msg_handler.handle_channel_update(&get_signed_channel_update(&secp_ctx, 2, 100, 1)).unwrap();
+
+		let mut network = net_graph_msg_handler.network_graph.write().unwrap();
+		network.remove_stale_channels_with_time(current_time as u64, best_block_height);
+		assert_eq!(network.get_channels().keys().cloned().collect::<Vec<u64>>(), vec![2, chan_3]);
+		assert_eq!(network.get_nodes().len(), 2);
+
+		network.remove_stale_channels_with_time(current_time as u64 + 1, best_block_height);
+		assert_eq!(network.get_channels().keys().cloned().collect::<Vec<u64>>(), vec![chan_3]);
+		assert_eq!(network.get_nodes().len(), 2);
+		assert_eq!(network.channels_by_funding_script.len(), 1);
+
+		// Once its announcement is old enough, channel 3 is removed as well.
+		network.remove_stale_channels_with_time(current_time as u64 + 1, best_block_height + 1);
+		assert!(network.get_channels().is_empty());
+		assert!(network.get_nodes().is_empty());
+		assert!(network.channels_by_funding_script.is_empty());
+	}
+
+	#[test]
+	fn removing_spent_channels() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+
+		// Channels 1 and 2 are between the same nodes, with different funding keys.
+		let mut announcements = Vec::new();
+		for short_channel_id in 1..3u8 {
+			let mut announcement = get_signed_channel_announcement(&secp_ctx, short_channel_id as u64);
+			announcement.contents.bitcoin_key_1 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[10 + short_channel_id; 32]).unwrap());
+			net_graph_msg_handler.network_graph.write().unwrap().update_channel_from_announcement(&announcement, None, None).unwrap();
+			announcements.push(announcement);
+		}
+
+		let spending_tx = |announcement: &ChannelAnnouncement| Transaction {
+			version: 2,
+			lock_time: 0,
+			input: vec![TxIn {
+				previous_output: BitcoinOutPoint::null(),
+				script_sig: Script::new(),
+				sequence: 0xffffffff,
+				witness: vec![Vec::new(), vec![1; 71], vec![2; 71], funding_redeemscript(&announcement.contents).into_bytes()],
+			}],
+			output: Vec::new(),
+		};
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+
+		// Spending an unrelated output doesn't affect the graph.
+		let mut unrelated_tx = spending_tx(&announcements[0]);
+		unrelated_tx.input[0].witness.pop();
+		net_graph_msg_handler.block_connected(&header, &[(0, &unrelated_tx)], 1);
+		assert_eq!(net_graph_msg_handler.network_graph.read().unwrap().get_channels().len(), 2);
+
+		let tx = spending_tx(&announcements[0]);
+		net_graph_msg_handler.block_connected(&header, &[(0, &tx)], 1);
+		{
+			let network = net_graph_msg_handler.network_graph.read().unwrap();
+			assert_eq!(network.get_channels().keys().cloned().collect::<Vec<u64>>(), vec![2]);
+			assert_eq!(network.get_nodes().len(), 2);
+		}
+
+		let tx = spending_tx(&announcements[1]);
+		net_graph_msg_handler.block_connected(&header, &[(0, &tx)], 2);
+		{
+			let network = net_graph_msg_handler.network_graph.read().unwrap();
+			assert!(network.get_channels().is_empty());
+			assert!(network.get_nodes().is_empty());
+			assert!(network.channels_by_funding_script.is_empty());
+		}
+	}
 }
@@ -17,7 +17,7 @@ use bitcoin::secp256k1::key::PublicKey;
 use ln::channelmanager::ChannelDetails;
 use ln::features::{ChannelFeatures, NodeFeatures};
 use ln::msgs::{DecodeError, ErrorAction, LightningError, MAX_VALUE_MSAT};
-use routing::network_graph::{NetworkGraph, RoutingFees};
+use routing::network_graph::{ChannelInfo, DirectionalChannelInfo, NetworkGraph, RoutingFees};
 use util::ser::{Writeable, Readable};
 use util::logger::Logger;
 
@@ -78,6 +78,53 @@ impl Readable for Vec<RouteHop> {
 	}
 }
 
+/// A trampoline node (or the final recipient) in a trampoline payment, see
+/// ChannelManager::send_trampoline_payment.
+#[derive(Clone, PartialEq)]
+pub struct TrampolineHop {
+	/// The node_id of the node at this hop.
+	pub pubkey: PublicKey,
+	/// The node_announcement features of the node at this hop. For the last hop, these may be
+	/// amended to match the features present in the invoice this node generated.
+	pub node_features: NodeFeatures,
+	/// The fee taken by this trampoline node, which must cover the fees of the route it finds to
+	/// the next hop. For the last hop, this should be the full value of the payment.
+	pub fee_msat: u64,
+	/// The CLTV delta added for this trampoline node, which must cover the CLTV deltas of the
+	/// route it finds to the next hop. For the last hop, this should be the full CLTV value
+	/// expected at the destination, in excess of the current block height.
+	pub cltv_expiry_delta: u32,
+}
+
+impl Writeable for Vec<TrampolineHop> {
+	fn write<W: ::util::ser::Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		(self.len() as u8).write(writer)?;
+		for hop in self.iter() {
+			hop.pubkey.write(writer)?;
+			hop.node_features.write(writer)?;
+			hop.fee_msat.write(writer)?;
+			hop.cltv_expiry_delta.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for Vec<TrampolineHop> {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<Vec<TrampolineHop>, DecodeError> {
+		let hops_count: u8 = Readable::read(reader)?;
+		let mut hops = Vec::with_capacity(hops_count as usize);
+		for _ in 0..hops_count {
+			hops.push(TrampolineHop {
+				pubkey: Readable::read(reader)?,
+				node_features: Readable::read(reader)?,
+				fee_msat: Readable::read(reader)?,
+				cltv_expiry_delta: Readable::read(reader)?,
+			});
+		}
+		Ok(hops)
+	}
+}
+
 /// A route directs a payment from the sender (us) to the recipient. If the recipient supports MPP,
 /// it can take multiple paths. Each path is composed of one or more hops through the network.
 #[derive(Clone, PartialEq)]
@@ -127,6 +174,86 @@ pub struct RouteHint {
 	pub htlc_minimum_msat: u64,
 }
 
+/// A path to a recipient which hides the identity of the recipient, and of the nodes between it
+/// and the path's introduction node, from the sender. A recipient may include these in its
+/// invoice in place of its node_id and RouteHints, see ChannelManager::create_blinded_path.
+///
+/// Senders find a route to the introduction node as usual, paying it the fees and CLTV delta
+/// for the whole blinded path, after which each hop decrypts the instructions the recipient left
+/// for it, see ChannelManager::send_payment_to_blinded_path.
+#[derive(Clone, PartialEq)]
+pub struct BlindedPath {
+	/// The node_id of the first node in the path, which is not blinded.
+	pub introduction_node_id: PublicKey,
+	/// The ephemeral key the introduction node uses to decrypt its hop's encrypted_payload.
+	pub blinding_point: PublicKey,
+	/// The hops in the path, starting with the introduction node and ending with the recipient.
+	pub blinded_hops: Vec<BlindedHop>,
+	/// The total base fee, in msat, charged by the nodes in the path.
+	pub fee_base_msat: u32,
+	/// The total proportional fee, in millionths of the amount forwarded, charged by the nodes in
+	/// the path. Together with fee_base_msat, this may slightly overestimate the fees actually
+	/// taken, as each node rounds in its own favor.
+	pub fee_proportional_millionths: u32,
+	/// The total CLTV delta added by the nodes in the path, not including the recipient's own
+	/// final CLTV delta.
+	pub cltv_expiry_delta: u16,
+}
+
+impl BlindedPath {
+	/// Gets the fee, in msat, which must be paid to the nodes in the path to deliver amount_msat
+	/// to the recipient, or None if it overflows.
+	pub fn fee_msat(&self, amount_msat: u64) -> Option<u64> {
+		amount_msat.checked_mul(self.fee_proportional_millionths as u64)
+			.and_then(|prop_fee| (prop_fee / 1_000_000 + if prop_fee % 1_000_000 != 0 { 1 } else { 0 }).checked_add(self.fee_base_msat as u64))
+			.and_then(|fee| if fee > MAX_VALUE_MSAT { None } else { Some(fee) })
+	}
+}
+
+/// A hop within a BlindedPath.
+#[derive(Clone, PartialEq)]
+pub struct BlindedHop {
+	/// The blinded node_id of the node at this hop, which the onion is encrypted to.
+	pub blinded_node_id: PublicKey,
+	/// Instructions for the node at this hop, which only it can decrypt.
+	pub encrypted_payload: Vec<u8>,
+}
+
+impl Writeable for BlindedPath {
+	fn write<W: ::util::ser::Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		self.introduction_node_id.write(writer)?;
+		self.blinding_point.write(writer)?;
+		self.fee_base_msat.write(writer)?;
+		self.fee_proportional_millionths.write(writer)?;
+		self.cltv_expiry_delta.write(writer)?;
+		(self.blinded_hops.len() as u8).write(writer)?;
+		for hop in self.blinded_hops.iter() {
+			hop.blinded_node_id.write(writer)?;
+			hop.encrypted_payload.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for BlindedPath {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<BlindedPath, DecodeError> {
+		let introduction_node_id = Readable::read(reader)?;
+		let blinding_point = Readable::read(reader)?;
+		let fee_base_msat = Readable::read(reader)?;
+		let fee_proportional_millionths = Readable::read(reader)?;
+		let cltv_expiry_delta = Readable::read(reader)?;
+		let hops_count: u8 = Readable::read(reader)?;
+		let mut blinded_hops = Vec::with_capacity(hops_count as usize);
+		for _ in 0..hops_count {
+			blinded_hops.push(BlindedHop {
+				blinded_node_id: Readable::read(reader)?,
+				encrypted_payload: Readable::read(reader)?,
+			});
+		}
+		Ok(BlindedPath { introduction_node_id, blinding_point, blinded_hops, fee_base_msat, fee_proportional_millionths, cltv_expiry_delta })
+	}
+}
+
 #[derive(Eq, PartialEq)]
 struct RouteGraphNode {
 	pubkey: PublicKey,
@@ -147,6 +274,13 @@ impl cmp::PartialOrd for RouteGraphNode {
 	}
 }
 
+/// The most we can send over a channel in the direction described by `directional_info`, bounded
+/// by the channel's capacity and the direction's htlc_maximum_msat, where known.
+fn max_htlc_msat(channel: &ChannelInfo, directional_info: &DirectionalChannelInfo) -> u64 {
+	let capacity_msat = channel.capacity_sats.and_then(|capacity_sats| capacity_sats.checked_mul(1000)).unwrap_or(u64::max_value());
+	cmp::min(capacity_msat, directional_info.htlc_maximum_msat.unwrap_or(u64::max_value()))
+}
+
 struct DummyDirectionalChannelInfo {
 	cltv_expiry_delta: u32,
 	htlc_minimum_msat: u64,
@@ -170,6 +304,9 @@ struct DummyDirectionalChannelInfo {
 /// The fees on channels from us to next-hops are ignored (as they are assumed to all be
 /// equal), however the enabled/disabled bit on such channels as well as the htlc_minimum_msat
 /// *is* checked as they may change based on the receiving node.
+///
+/// Channels from the network graph are only used if the amount to send over them fits within
+/// both their capacity, when known from their funding UTXO, and their htlc_maximum_msat.
 pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, target: &PublicKey, first_hops: Option<&[&ChannelDetails]>,
 	last_hops: &[&RouteHint], final_value_msat: u64, final_cltv: u32, logger: L) -> Result<Route, LightningError> where L::Target: Logger {
 	// TODO: Obviously *only* using total fee cost sucks. We should consider weighting by
@@ -226,10 +363,11 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 	macro_rules! add_entry {
 		// Adds entry which goes from $src_node_id to $dest_node_id
 		// over the channel with id $chan_id with fees described in
-		// $directional_info.
-		( $chan_id: expr, $src_node_id: expr, $dest_node_id: expr, $directional_info: expr, $chan_features: expr, $starting_fee_msat: expr ) => {
+		// $directional_info, if it can carry up to $max_htlc_msat.
+		( $chan_id: expr, $src_node_id: expr, $dest_node_id: expr, $directional_info: expr, $chan_features: expr, $starting_fee_msat: expr, $max_htlc_msat: expr ) => {
 			//TODO: Explore simply adding fee to hit htlc_minimum_msat
-			if $starting_fee_msat as u64 + final_value_msat >= $directional_info.htlc_minimum_msat {
+			if $starting_fee_msat as u64 + final_value_msat >= $directional_info.htlc_minimum_msat &&
+					$starting_fee_msat as u64 + final_value_msat <= $max_htlc_msat {
 				let proportional_fee_millions = ($starting_fee_msat + final_value_msat).checked_mul($directional_info.fees.proportional_millionths as u64);
 				if let Some(new_fee) = proportional_fee_millions.and_then(|part| {
 						($directional_info.fees.base_msat as u64).checked_add(part / 1000000) })
@@ -294,7 +432,7 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 		( $node: expr, $node_id: expr, $fee_to_target_msat: expr ) => {
 			if first_hops.is_some() {
 				if let Some(&(ref first_hop, ref features)) = first_hop_targets.get(&$node_id) {
-					add_entry!(first_hop, *our_node_id, $node_id, dummy_directional_info, features.to_context(), $fee_to_target_msat);
+					add_entry!(first_hop, *our_node_id, $node_id, dummy_directional_info, features.to_context(), $fee_to_target_msat, u64::max_value());
 				}
 			}
 
@@ -314,7 +452,7 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 							if first_hops.is_none() || chan.node_two != *our_node_id {
 								if let Some(two_to_one) = chan.two_to_one.as_ref() {
 									if two_to_one.enabled {
-										add_entry!(chan_id, chan.node_two, chan.node_one, two_to_one, chan.features, $fee_to_target_msat);
+										add_entry!(chan_id, chan.node_two, chan.node_one, two_to_one, chan.features, $fee_to_target_msat, max_htlc_msat(chan, two_to_one));
 									}
 								}
 							}
@@ -322,7 +460,7 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 							if first_hops.is_none() || chan.node_one != *our_node_id {
 								if let Some(one_to_two) = chan.one_to_two.as_ref() {
 									if one_to_two.enabled {
-										add_entry!(chan_id, chan.node_one, chan.node_two, one_to_two, chan.features, $fee_to_target_msat);
+										add_entry!(chan_id, chan.node_one, chan.node_two, one_to_two, chan.features, $fee_to_target_msat, max_htlc_msat(chan, one_to_two));
 									}
 								}
 
@@ -350,12 +488,12 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 						// bit lazy here. In the future, we should pull them out via our
 						// ChannelManager, but there's no reason to waste the space until we
 						// need them.
-						add_entry!(first_hop, *our_node_id , hop.src_node_id, dummy_directional_info, features.to_context(), 0);
+						add_entry!(first_hop, *our_node_id , hop.src_node_id, dummy_directional_info, features.to_context(), 0, u64::max_value());
 					}
 				}
 				// BOLT 11 doesn't allow inclusion of features for the last hop hints, which
 				// really sucks, cause we're gonna need that eventually.
-				add_entry!(hop.short_channel_id, hop.src_node_id, target, hop, ChannelFeatures::empty(), 0);
+				add_entry!(hop.short_channel_id, hop.src_node_id, target, hop, ChannelFeatures::empty(), 0, u64::max_value());
 			}
 		}
 	}
@@ -424,6 +562,9 @@ mod tests {
 	use bitcoin::hashes::Hash;
 	use bitcoin::network::constants::Network;
 	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::blockdata::opcodes;
+	use bitcoin::blockdata::script::Builder;
+	use bitcoin::blockdata::transaction::TxOut;
 
 	use hex;
 
@@ -534,7 +675,7 @@ mod tests {
 	fn build_graph() -> (Secp256k1<All>, NetGraphMsgHandler<std::sync::Arc<crate::util::test_utils::TestChainSource>, std::sync::Arc<crate::util::test_utils::TestLogger>>, std::sync::Arc<test_utils::TestLogger>) {
 		let secp_ctx = Secp256k1::new();
 		let logger = Arc::new(test_utils::TestLogger::new());
-		let net_graph_msg_handler = NetGraphMsgHandler::new(None, Arc::clone(&logger));
+		let net_graph_msg_handler = NetGraphMsgHandler::new(genesis_block(Network::Testnet).header.block_hash(), None, Arc::clone(&logger));
 		// Build network from our_id to node7:
 		//
 		//        -1(1)2-  node0  -1(3)2-
@@ -923,6 +1064,62 @@ mod tests {
 		assert_eq!(route.paths[0][1].channel_features.le_flags(), &id_to_feature_flags(13));
 	}
 
+	#[test]
+	fn available_liquidity_test() {
+		let (secp_ctx, net_graph_msg_handler, logger) = build_graph();
+		let (_, our_id, privkeys, nodes) = get_nodes(&secp_ctx);
+
+		// Limit channel 4 to less than we want to send, so that we route through node7 instead.
+		update_channel(&net_graph_msg_handler, &secp_ctx, &privkeys[1], UnsignedChannelUpdate {
+			chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+			short_channel_id: 4,
+			timestamp: 2,
+			flags: 0,
+			cltv_expiry_delta: (4 << 8) | 1,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: OptionalField::Present(99),
+			fee_base_msat: 0,
+			fee_proportional_millionths: 1000000,
+			excess_data: Vec::new()
+		});
+
+		let route = get_route(&our_id, &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2], None, &Vec::new(), 99, 42, Arc::clone(&logger)).unwrap();
+		assert_eq!(route.paths[0][1].short_channel_id, 4);
+		let route = get_route(&our_id, &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2], None, &Vec::new(), 100, 42, Arc::clone(&logger)).unwrap();
+		assert_eq!(route.paths[0][0].short_channel_id, 12);
+		assert_eq!(route.paths[0][1].short_channel_id, 13);
+
+		// The channel capacity from the funding UTXO bounds the amount too.
+		let chain_source = Arc::new(test_utils::TestChainSource::new(Network::Testnet));
+		let net_graph_msg_handler = NetGraphMsgHandler::new(genesis_block(Network::Testnet).header.block_hash(), Some(Arc::clone(&chain_source)), Arc::clone(&logger));
+		let (our_privkey, _, _, _) = get_nodes(&secp_ctx);
+		*chain_source.utxo_ret.lock().unwrap() = Ok(TxOut { value: 1, script_pubkey: Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_2)
+			.push_slice(&our_id.serialize())
+			.push_slice(&nodes[0].serialize())
+			.push_opcode(opcodes::all::OP_PUSHNUM_2)
+			.push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script().to_v0_p2wsh() });
+		add_channel(&net_graph_msg_handler, &secp_ctx, &our_privkey, &privkeys[0], ChannelFeatures::empty(), 1);
+		assert_eq!(net_graph_msg_handler.network_graph.read().unwrap().get_channels().get(&1).unwrap().capacity_sats, Some(1));
+		update_channel(&net_graph_msg_handler, &secp_ctx, &our_privkey, UnsignedChannelUpdate {
+			chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+			short_channel_id: 1,
+			timestamp: 1,
+			flags: 0,
+			cltv_expiry_delta: 0,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: OptionalField::Absent,
+			fee_base_msat: 0,
+			fee_proportional_millionths: 0,
+			excess_data: Vec::new()
+		});
+
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = get_route(&our_id, &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[0], None, &Vec::new(), 1001, 42, Arc::clone(&logger)) {
+			assert_eq!(err, "Failed to find a path to the given destination");
+		} else { panic!(); }
+		let route = get_route(&our_id, &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[0], None, &Vec::new(), 1000, 42, Arc::clone(&logger)).unwrap();
+		assert_eq!(route.paths[0][0].short_channel_id, 1);
+	}
+
 	#[test]
 	fn disable_node_test() {
 		let (secp_ctx, net_graph_msg_handler, logger) = build_graph();
@@ -217,6 +217,13 @@ pub struct UserConfig {
 	pub peer_channel_config_limits: ChannelHandshakeLimits,
 	/// Channel config which affects behavior during channel lifetime.
 	pub channel_options: ChannelConfig,
+	/// If set, we advertise `option_trampoline_routing` and accept HTLCs to forward as a
+	/// trampoline node. These are only routed onwards when
+	/// ChannelManager::process_pending_trampoline_forwards is called, so it must then be called
+	/// regularly.
+	///
+	/// Default value: false.
+	pub accept_trampoline_forwards: bool,
 }
 
 impl Default for UserConfig {
@@ -225,6 +232,7 @@ impl Default for UserConfig {
 			own_channel_config: ChannelHandshakeConfig::default(),
 			peer_channel_config_limits: ChannelHandshakeLimits::default(),
 			channel_options: ChannelConfig::default(),
+			accept_trampoline_forwards: false,
 		}
 	}
 }
@@ -120,10 +120,18 @@ impl ChannelKeys for EnforcingChannelKeys {
 		Ok(self.inner.sign_justice_transaction(justice_tx, input, amount, per_commitment_key, htlc, secp_ctx).unwrap())
 	}
 
+	fn sign_justice_transactions<T: secp256k1::Signing + secp256k1::Verification>(&self, justice_txn: &[Transaction], inputs: &[(u64, Option<HTLCOutputInCommitment>)], per_commitment_key: &SecretKey, secp_ctx: &Secp256k1<T>) -> Result<Vec<Vec<Signature>>, ()> {
+		Ok(self.inner.sign_justice_transactions(justice_txn, inputs, per_commitment_key, secp_ctx).unwrap())
+	}
+
 	fn sign_counterparty_htlc_transaction<T: secp256k1::Signing + secp256k1::Verification>(&self, htlc_tx: &Transaction, input: usize, amount: u64, per_commitment_point: &PublicKey, htlc: &HTLCOutputInCommitment, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
 		Ok(self.inner.sign_counterparty_htlc_transaction(htlc_tx, input, amount, per_commitment_point, htlc, secp_ctx).unwrap())
 	}
 
+	fn sign_delayed_payment_input<T: secp256k1::Signing + secp256k1::Verification>(&self, spend_tx: &Transaction, input: usize, amount: u64, per_commitment_point: &PublicKey, revocation_pubkey: &PublicKey, to_self_delay: u16, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		Ok(self.inner.sign_delayed_payment_input(spend_tx, input, amount, per_commitment_point, revocation_pubkey, to_self_delay, secp_ctx).unwrap())
+	}
+
 	fn sign_closing_transaction<T: secp256k1::Signing>(&self, closing_tx: &Transaction, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
 		Ok(self.inner.sign_closing_transaction(closing_tx, secp_ctx).unwrap())
 	}
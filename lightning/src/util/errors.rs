@@ -100,6 +100,10 @@ pub(crate) fn get_onion_error_description(error_code: u16) -> (&'static str, &'s
 		_c if _c == 19 => ("The final node indicated the amount in the HTLC does not match the value in the onion", "final_incorrect_htlc_amount"),
 		_c if _c == UPDATE|20 => ("Node indicated the outbound channel has been disabled", "channel_disabled"),
 		_c if _c == 21 => ("Node indicated the CLTV expiry in the HTLC is too far in the future", "expiry_too_far"),
+		_c if _c == BADONION|PERM|24 => ("Node within a blinded path indicated the HTLC could not be handled, without saying why", "invalid_onion_blinding"),
+		_c if _c == NODE|25 => ("Trampoline node indicated it was unable to route the payment to the next node temporarily", "temporary_trampoline_failure"),
+		_c if _c == NODE|26 => ("Trampoline node indicated the fee amount does not cover the route to the next node", "trampoline_fee_insufficient"),
+		_c if _c == NODE|27 => ("Trampoline node indicated the CLTV expiry does not leave enough room to route to the next node", "trampoline_expiry_too_soon"),
 		_ => ("Unknown", ""),
 	}
 }
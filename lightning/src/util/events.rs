@@ -15,7 +15,7 @@
 //! few other things.
 
 use ln::msgs;
-use ln::channelmanager::{PaymentPreimage, PaymentHash, PaymentSecret};
+use ln::channelmanager::{PaymentPreimage, PaymentHash, PaymentSecret, PaymentId};
 use chain::transaction::OutPoint;
 use chain::keysinterface::SpendableOutputDescriptor;
 use util::ser::{Writeable, Writer, MaybeReadable, Readable};
@@ -86,6 +86,11 @@ pub enum Event {
 		/// compare this to the expected value before accepting the payment (as otherwise you are
 		/// providing proof-of-payment for less than the value you expected!).
 		amt: u64,
+		/// Any custom TLV records (of type msgs::MIN_CUSTOM_TLV_TYPE or above) the sender included
+		/// in the onion, as (type, value) pairs in increasing type order. As we reject payments
+		/// including even types we don't understand, these are always of odd type, and thus may
+		/// be safely ignored.
+		custom_tlvs: Vec<(u64, Vec<u8>)>,
 	},
 	/// Indicates an outbound payment we made succeeded (ie it made it all the way to its target
 	/// and we got back the payment preimage for it).
@@ -113,6 +118,17 @@ pub enum Event {
 #[cfg(test)]
 		error_data: Option<Vec<u8>>,
 	},
+	/// Indicates an outbound payment which was abandoned via ChannelManager::abandon_payment has
+	/// finally failed: every HTLC sent for it has been resolved and none were claimed by the
+	/// recipient. Once you receive this event, no part of the payment will ever be paid, and it
+	/// is safe to pay the same invoice again under a new payment_id.
+	/// Unlike PaymentSent and PaymentFailed, this event is only generated once per payment.
+	PaymentAbandoned {
+		/// The payment_id which was given to ChannelManager::send_payment.
+		payment_id: PaymentId,
+		/// The hash which was given to ChannelManager::send_payment.
+		payment_hash: PaymentHash,
+	},
 	/// Used to indicate that ChannelManager::process_pending_htlc_forwards should be called at a
 	/// time in the future.
 	PendingHTLCsForwardable {
@@ -122,6 +138,10 @@ pub enum Event {
 		/// now + 5*time_forwardable).
 		time_forwardable: Duration,
 	},
+	/// Used to indicate that ChannelManager::process_pending_trampoline_forwards should be called
+	/// with an up-to-date network graph, as we've received HTLCs as a trampoline node which need
+	/// to be routed onwards.
+	PendingTrampolineForwards {},
 	/// Used to indicate that an output was generated on-chain which you should know how to spend.
 	/// Such an output will *not* ever be spent by rust-lightning, and are not at risk of your
 	/// counterparty spending them due to some kind of timeout. Thus, you need to store them
@@ -145,12 +165,19 @@ impl Writeable for Event {
 				funding_txo.write(writer)?;
 				user_channel_id.write(writer)?;
 			},
-			&Event::PaymentReceived { ref payment_hash, ref payment_secret, ref amt } => {
+			&Event::PaymentReceived { ref payment_hash, ref payment_secret, ref amt, ref custom_tlvs } if custom_tlvs.is_empty() => {
 				2u8.write(writer)?;
 				payment_hash.write(writer)?;
 				payment_secret.write(writer)?;
 				amt.write(writer)?;
 			},
+			&Event::PaymentReceived { ref payment_hash, ref payment_secret, ref amt, ref custom_tlvs } => {
+				9u8.write(writer)?;
+				payment_hash.write(writer)?;
+				payment_secret.write(writer)?;
+				amt.write(writer)?;
+				custom_tlvs.write(writer)?;
+			},
 			&Event::PaymentSent { ref payment_preimage } => {
 				3u8.write(writer)?;
 				payment_preimage.write(writer)?;
@@ -181,6 +208,14 @@ impl Writeable for Event {
 					output.write(writer)?;
 				}
 			},
+			&Event::PaymentAbandoned { ref payment_id, ref payment_hash } => {
+				7u8.write(writer)?;
+				payment_id.write(writer)?;
+				payment_hash.write(writer)?;
+			},
+			&Event::PendingTrampolineForwards {} => {
+				8u8.write(writer)?;
+			},
 		}
 		Ok(())
 	}
@@ -197,6 +232,7 @@ impl MaybeReadable for Event {
 					payment_hash: Readable::read(reader)?,
 					payment_secret: Readable::read(reader)?,
 					amt: Readable::read(reader)?,
+					custom_tlvs: Vec::new(),
 				})),
 			3u8 => Ok(Some(Event::PaymentSent {
 					payment_preimage: Readable::read(reader)?,
@@ -220,6 +256,17 @@ impl MaybeReadable for Event {
 				}
 				Ok(Some(Event::SpendableOutputs { outputs }))
 			},
+			7u8 => Ok(Some(Event::PaymentAbandoned {
+					payment_id: Readable::read(reader)?,
+					payment_hash: Readable::read(reader)?,
+				})),
+			8u8 => Ok(Some(Event::PendingTrampolineForwards {})),
+			9u8 => Ok(Some(Event::PaymentReceived {
+					payment_hash: Readable::read(reader)?,
+					payment_secret: Readable::read(reader)?,
+					amt: Readable::read(reader)?,
+					custom_tlvs: Readable::read(reader)?,
+				})),
 			_ => Err(msgs::DecodeError::InvalidValue)
 		}
 	}
@@ -334,6 +381,16 @@ pub enum MessageSendEvent {
 		/// The channel_update which should be sent.
 		msg: msgs::ChannelUpdate,
 	},
+	/// Used to indicate that a channel_announcement which the RoutingMessageHandler received from
+	/// a peer and only applied later, eg once its funding UTXO lookup completed, should be relayed
+	/// to all peers, along with the channel_updates for the channel. Unlike the Broadcast* events,
+	/// the messages aren't handed to the RoutingMessageHandler again.
+	RelayChannelAnnouncement {
+		/// The channel_announcement which should be relayed.
+		msg: msgs::ChannelAnnouncement,
+		/// The channel_updates which should be relayed after it.
+		update_msgs: Vec<msgs::ChannelUpdate>,
+	},
 	/// Broadcast an error downstream to be handled
 	HandleError {
 		/// The node_id of the node which should receive this message
@@ -346,7 +403,48 @@ pub enum MessageSendEvent {
 	PaymentFailureNetworkUpdate {
 		/// The channel/node update which should be sent to NetGraphMsgHandler
 		update: msgs::HTLCFailChannelUpdate,
-	}
+	},
+	/// Used to indicate that a query_channel_range message should be sent to the peer with the
+	/// given node_id.
+	SendChannelRangeQuery {
+		/// The node_id of this message recipient
+		node_id: PublicKey,
+		/// The query_channel_range which should be sent.
+		msg: msgs::QueryChannelRange,
+	},
+	/// Used to indicate that a reply_channel_range message should be sent to the peer with the
+	/// given node_id.
+	SendReplyChannelRange {
+		/// The node_id of this message recipient
+		node_id: PublicKey,
+		/// The reply_channel_range which should be sent.
+		msg: msgs::ReplyChannelRange,
+	},
+	/// Used to indicate that a query_short_channel_ids message should be sent to the peer with the
+	/// given node_id.
+	SendShortIdsQuery {
+		/// The node_id of this message recipient
+		node_id: PublicKey,
+		/// The query_short_channel_ids which should be sent.
+		msg: msgs::QueryShortChannelIds,
+	},
+	/// Used to indicate that a reply_short_channel_ids_end message should be sent to the peer with
+	/// the given node_id, ending our reply to a query_short_channel_ids we refused to answer (eg
+	/// for an unknown chain). Replies to other queries are sent by the PeerManager itself.
+	SendShortIdsReply {
+		/// The node_id of this message recipient
+		node_id: PublicKey,
+		/// The reply_short_channel_ids_end which should be sent.
+		msg: msgs::ReplyShortChannelIdsEnd,
+	},
+	/// Used to indicate that a gossip_timestamp_filter message should be sent to the peer with the
+	/// given node_id.
+	SendGossipTimestampFilter {
+		/// The node_id of this message recipient
+		node_id: PublicKey,
+		/// The gossip_timestamp_filter which should be sent.
+		msg: msgs::GossipTimestampFilter,
+	},
 }
 
 /// A trait indicating an object may generate message send events
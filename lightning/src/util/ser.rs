@@ -26,7 +26,7 @@ use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use bitcoin::hash_types::{Txid, BlockHash};
 use std::marker::Sized;
 use ln::msgs::DecodeError;
-use ln::channelmanager::{PaymentPreimage, PaymentHash, PaymentSecret};
+use ln::channelmanager::{PaymentPreimage, PaymentHash, PaymentSecret, PaymentId};
 use util::byte_utils;
 
 use util::byte_utils::{be64_to_array, be48_to_array, be32_to_array, be16_to_array, slice_to_be16, slice_to_be32, slice_to_be48, slice_to_be64};
@@ -476,6 +476,24 @@ impl Readable for Vec<u8> {
 		Ok(ret)
 	}
 }
+/// A byte vector which is written without a length prefix, reading until the end of the stream.
+/// This is only useful as the value of a TLV record, where the record's length bounds the read.
+pub(crate) struct VecReadToEnd(pub Vec<u8>);
+impl Writeable for VecReadToEnd {
+	#[inline]
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		w.write_all(&self.0)
+	}
+}
+impl Readable for VecReadToEnd {
+	#[inline]
+	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+		let mut ret = Vec::new();
+		r.read_to_end(&mut ret)?;
+		Ok(VecReadToEnd(ret))
+	}
+}
+
 impl Writeable for Vec<Signature> {
 	#[inline]
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
@@ -503,6 +521,28 @@ impl Readable for Vec<Signature> {
 	}
 }
 
+// (type, value) pairs, eg custom TLV records
+impl Writeable for Vec<(u64, Vec<u8>)> {
+	#[inline]
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		(self.len() as u16).write(w)?;
+		for e in self.iter() {
+			e.write(w)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for Vec<(u64, Vec<u8>)> {
+	#[inline]
+	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+		let len: u16 = Readable::read(r)?;
+		let mut ret = Vec::with_capacity(cmp::min(len as usize, 16));
+		for _ in 0..len { ret.push(Readable::read(r)?); }
+		Ok(ret)
+	}
+}
+
 impl Writeable for Script {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
 		(self.len() as u16).write(w)?;
@@ -610,6 +650,19 @@ impl Readable for PaymentHash {
 	}
 }
 
+impl Writeable for PaymentId {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		self.0.write(w)
+	}
+}
+
+impl Readable for PaymentId {
+	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+		let buf: [u8; 32] = Readable::read(r)?;
+		Ok(PaymentId(buf))
+	}
+}
+
 impl Writeable for PaymentSecret {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
 		self.0.write(w)
@@ -42,8 +42,14 @@ macro_rules! encode_varint_length_prefixed_tlv {
 }
 
 macro_rules! decode_tlv {
-	($stream: expr, {$(($reqtype: expr, $reqfield: ident)),*}, {$(($type: expr, $field: ident)),*}) => { {
+	($stream: expr, {$(($reqtype: expr, $reqfield: ident)),*}, {$(($type: expr, $field: ident)),*}) => {
+		decode_tlv!($stream, {$(($reqtype, $reqfield)),*}, {$(($type, $field)),*}, None)
+	};
+	// If custom_records is Some, unknown odd types of at least ln::msgs::MIN_CUSTOM_TLV_TYPE are
+	// pushed to it as (type, value) pairs rather than ignored.
+	($stream: expr, {$(($reqtype: expr, $reqfield: ident)),*}, {$(($type: expr, $field: ident)),*}, $custom_records: expr) => { {
 		use ln::msgs::DecodeError;
+		let mut custom_records: Option<&mut Vec<(u64, Vec<u8>)>> = $custom_records;
 		let mut last_seen_type: Option<u64> = None;
 		'tlv_read: loop {
 			use util::ser;
@@ -102,6 +108,10 @@ macro_rules! decode_tlv {
 				x if x % 2 == 0 => {
 					Err(DecodeError::UnknownRequiredFeature)?
 				},
+				x if x >= ::ln::msgs::MIN_CUSTOM_TLV_TYPE && custom_records.is_some() => {
+					let value: ser::VecReadToEnd = ser::Readable::read(&mut s)?;
+					custom_records.as_mut().unwrap().push((x, value.0));
+				},
 				_ => {},
 			}
 			s.eat_remaining()?;
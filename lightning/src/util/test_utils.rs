@@ -18,6 +18,8 @@ use chain::keysinterface;
 use ln::features::{ChannelFeatures, InitFeatures};
 use ln::msgs;
 use ln::msgs::OptionalField;
+use ln::peer_connection_manager;
+use util::byte_utils;
 use util::enforcing_trait_impls::EnforcingChannelKeys;
 use util::events;
 use util::logger::{Logger, Level, Record};
@@ -29,6 +31,7 @@ use bitcoin::blockdata::script::{Builder, Script};
 use bitcoin::blockdata::opcodes;
 use bitcoin::network::constants::Network;
 use bitcoin::hash_types::{BlockHash, Txid};
+use bitcoin::hashes::Hash;
 
 use bitcoin::secp256k1::{SecretKey, PublicKey, Secp256k1, Signature};
 
@@ -63,14 +66,14 @@ impl chaininterface::FeeEstimator for TestFeeEstimator {
 pub struct TestChainMonitor<'a> {
 	pub added_monitors: Mutex<Vec<(OutPoint, channelmonitor::ChannelMonitor<EnforcingChannelKeys>)>>,
 	pub latest_monitor_update_id: Mutex<HashMap<[u8; 32], (OutPoint, u64)>>,
-	pub chain_monitor: chainmonitor::ChainMonitor<EnforcingChannelKeys, &'a TestChainSource, &'a chaininterface::BroadcasterInterface, &'a TestFeeEstimator, &'a TestLogger, &'a channelmonitor::Persist<EnforcingChannelKeys>>,
+	pub chain_monitor: chainmonitor::ChainMonitor<EnforcingChannelKeys, &'a TestChainSource, &'a chaininterface::BroadcasterInterface, &'a chaininterface::FeeEstimator, &'a TestLogger, &'a channelmonitor::Persist<EnforcingChannelKeys>>,
 	pub update_ret: Mutex<Option<Result<(), channelmonitor::ChannelMonitorUpdateErr>>>,
 	// If this is set to Some(), after the next return, we'll always return this until update_ret
 	// is changed:
 	pub next_update_ret: Mutex<Option<Result<(), channelmonitor::ChannelMonitorUpdateErr>>>,
 }
 impl<'a> TestChainMonitor<'a> {
-	pub fn new(chain_source: Option<&'a TestChainSource>, broadcaster: &'a chaininterface::BroadcasterInterface, logger: &'a TestLogger, fee_estimator: &'a TestFeeEstimator, persister: &'a channelmonitor::Persist<EnforcingChannelKeys>) -> Self {
+	pub fn new(chain_source: Option<&'a TestChainSource>, broadcaster: &'a chaininterface::BroadcasterInterface, logger: &'a TestLogger, fee_estimator: &'a chaininterface::FeeEstimator, persister: &'a channelmonitor::Persist<EnforcingChannelKeys>) -> Self {
 		Self {
 			added_monitors: Mutex::new(Vec::new()),
 			latest_monitor_update_id: Mutex::new(HashMap::new()),
@@ -177,12 +180,14 @@ impl chaininterface::BroadcasterInterface for TestBroadcaster {
 
 pub struct TestChannelMessageHandler {
 	pub pending_events: Mutex<Vec<events::MessageSendEvent>>,
+	pub peers_with_channels: Mutex<HashSet<PublicKey>>,
 }
 
 impl TestChannelMessageHandler {
 	pub fn new() -> Self {
 		TestChannelMessageHandler {
 			pending_events: Mutex::new(Vec::new()),
+			peers_with_channels: Mutex::new(HashSet::new()),
 		}
 	}
 }
@@ -206,6 +211,9 @@ impl msgs::ChannelMessageHandler for TestChannelMessageHandler {
 	fn handle_channel_reestablish(&self, _their_node_id: &PublicKey, _msg: &msgs::ChannelReestablish) {}
 	fn peer_disconnected(&self, _their_node_id: &PublicKey, _no_connection_possible: bool) {}
 	fn peer_connected(&self, _their_node_id: &PublicKey, _msg: &msgs::Init) {}
+	fn has_channels_with_peer(&self, their_node_id: &PublicKey) -> bool {
+		self.peers_with_channels.lock().unwrap().contains(their_node_id)
+	}
 	fn handle_error(&self, _their_node_id: &PublicKey, _msg: &msgs::ErrorMessage) {}
 }
 
@@ -218,7 +226,7 @@ impl events::MessageSendEventsProvider for TestChannelMessageHandler {
 	}
 }
 
-fn get_dummy_channel_announcement(short_chan_id: u64) -> msgs::ChannelAnnouncement {
+pub fn get_dummy_channel_announcement(short_chan_id: u64) -> msgs::ChannelAnnouncement {
 	use bitcoin::secp256k1::ffi::Signature as FFISignature;
 	let secp_ctx = Secp256k1::new();
 	let network = Network::Testnet;
@@ -246,7 +254,7 @@ fn get_dummy_channel_announcement(short_chan_id: u64) -> msgs::ChannelAnnounceme
 	}
 }
 
-fn get_dummy_channel_update(short_chan_id: u64) -> msgs::ChannelUpdate {
+pub fn get_dummy_channel_update(short_chan_id: u64) -> msgs::ChannelUpdate {
 	use bitcoin::secp256k1::ffi::Signature as FFISignature;
 	let network = Network::Testnet;
 	msgs::ChannelUpdate {
@@ -270,7 +278,12 @@ pub struct TestRoutingMessageHandler {
 	pub chan_upds_recvd: AtomicUsize,
 	pub chan_anns_recvd: AtomicUsize,
 	pub chan_anns_sent: AtomicUsize,
+	pub chan_range_queries_recvd: AtomicUsize,
+	pub short_ids_reply_ends_recvd: AtomicUsize,
 	pub request_full_sync: AtomicBool,
+	/// Whether the channel announcements and updates we receive should be forwarded to our peers.
+	pub forward_gossip: AtomicBool,
+	pub pending_events: Mutex<Vec<events::MessageSendEvent>>,
 }
 
 impl TestRoutingMessageHandler {
@@ -279,7 +292,11 @@ impl TestRoutingMessageHandler {
 			chan_upds_recvd: AtomicUsize::new(0),
 			chan_anns_recvd: AtomicUsize::new(0),
 			chan_anns_sent: AtomicUsize::new(0),
+			chan_range_queries_recvd: AtomicUsize::new(0),
+			short_ids_reply_ends_recvd: AtomicUsize::new(0),
 			request_full_sync: AtomicBool::new(false),
+			forward_gossip: AtomicBool::new(false),
+			pending_events: Mutex::new(Vec::new()),
 		}
 	}
 }
@@ -289,10 +306,16 @@ impl msgs::RoutingMessageHandler for TestRoutingMessageHandler {
 	}
 	fn handle_channel_announcement(&self, _msg: &msgs::ChannelAnnouncement) -> Result<bool, msgs::LightningError> {
 		self.chan_anns_recvd.fetch_add(1, Ordering::AcqRel);
+		if self.forward_gossip.load(Ordering::Acquire) {
+			return Ok(true);
+		}
 		Err(msgs::LightningError { err: "".to_owned(), action: msgs::ErrorAction::IgnoreError })
 	}
 	fn handle_channel_update(&self, _msg: &msgs::ChannelUpdate) -> Result<bool, msgs::LightningError> {
 		self.chan_upds_recvd.fetch_add(1, Ordering::AcqRel);
+		if self.forward_gossip.load(Ordering::Acquire) {
+			return Ok(true);
+		}
 		Err(msgs::LightningError { err: "".to_owned(), action: msgs::ErrorAction::IgnoreError })
 	}
 	fn handle_htlc_fail_channel_update(&self, _update: &msgs::HTLCFailChannelUpdate) {}
@@ -319,6 +342,52 @@ impl msgs::RoutingMessageHandler for TestRoutingMessageHandler {
 	fn should_request_full_sync(&self, _node_id: &PublicKey) -> bool {
 		self.request_full_sync.load(Ordering::Acquire)
 	}
+
+	fn sync_routing_table(&self, their_node_id: &PublicKey, init: &msgs::Init) {
+		if init.features.supports_gossip_queries() {
+			self.pending_events.lock().unwrap().push(events::MessageSendEvent::SendChannelRangeQuery {
+				node_id: their_node_id.clone(),
+				msg: msgs::QueryChannelRange {
+					chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+					first_blocknum: 0,
+					number_of_blocks: 0xffff_ffff,
+				},
+			});
+		}
+	}
+
+	fn handle_reply_channel_range(&self, _their_node_id: &PublicKey, _msg: &msgs::ReplyChannelRange) -> Result<(), msgs::LightningError> {
+		Ok(())
+	}
+
+	fn handle_reply_short_channel_ids_end(&self, _their_node_id: &PublicKey, _msg: &msgs::ReplyShortChannelIdsEnd) -> Result<(), msgs::LightningError> {
+		self.short_ids_reply_ends_recvd.fetch_add(1, Ordering::AcqRel);
+		Ok(())
+	}
+
+	fn handle_query_channel_range(&self, _their_node_id: &PublicKey, _msg: &msgs::QueryChannelRange) -> Result<(), msgs::LightningError> {
+		self.chan_range_queries_recvd.fetch_add(1, Ordering::AcqRel);
+		Ok(())
+	}
+
+	fn handle_query_short_channel_ids(&self, _their_node_id: &PublicKey, _msg: &msgs::QueryShortChannelIds) -> Result<(), msgs::LightningError> {
+		Ok(())
+	}
+
+	fn get_gossip_for_short_channel_ids(&self, short_channel_ids: &[u64]) -> (Vec<(msgs::ChannelAnnouncement, Option<msgs::ChannelUpdate>, Option<msgs::ChannelUpdate>)>, Vec<msgs::NodeAnnouncement>) {
+		let chan_anns = short_channel_ids.iter().filter(|&&scid| scid < 100)
+			.map(|&scid| (get_dummy_channel_announcement(scid), Some(get_dummy_channel_update(scid)), None)).collect();
+		(chan_anns, Vec::new())
+	}
+}
+
+impl events::MessageSendEventsProvider for TestRoutingMessageHandler {
+	fn get_and_clear_pending_msg_events(&self) -> Vec<events::MessageSendEvent> {
+		let mut pending_events = self.pending_events.lock().unwrap();
+		let mut ret = Vec::new();
+		mem::swap(&mut ret, &mut *pending_events);
+		ret
+	}
 }
 
 pub struct TestLogger {
@@ -381,7 +450,7 @@ impl Logger for TestLogger {
 }
 
 pub struct TestKeysInterface {
-	backing: keysinterface::KeysManager,
+	pub backing: keysinterface::KeysManager,
 	pub override_session_priv: Mutex<Option<[u8; 32]>>,
 	pub override_channel_id_priv: Mutex<Option<[u8; 32]>>,
 }
@@ -429,6 +498,7 @@ impl TestKeysInterface {
 pub struct TestChainSource {
 	pub genesis_hash: BlockHash,
 	pub utxo_ret: Mutex<Result<TxOut, chain::AccessError>>,
+	pub utxo_lookups: Mutex<Vec<u64>>,
 	pub watched_txn: Mutex<HashSet<(Txid, Script)>>,
 	pub watched_outputs: Mutex<HashSet<(OutPoint, Script)>>,
 }
@@ -439,19 +509,29 @@ impl TestChainSource {
 		Self {
 			genesis_hash: genesis_block(network).block_hash(),
 			utxo_ret: Mutex::new(Ok(TxOut { value: u64::max_value(), script_pubkey })),
+			utxo_lookups: Mutex::new(Vec::new()),
 			watched_txn: Mutex::new(HashSet::new()),
 			watched_outputs: Mutex::new(HashSet::new()),
 		}
 	}
 }
 
+impl TestChainSource {
+	/// The outpoint get_utxo returns along with utxo_ret for the given short_channel_id.
+	pub fn funding_outpoint(short_channel_id: u64) -> OutPoint {
+		OutPoint { txid: Txid::hash(&byte_utils::be64_to_array(short_channel_id)), index: short_channel_id as u16 }
+	}
+}
+
 impl chain::Access for TestChainSource {
-	fn get_utxo(&self, genesis_hash: &BlockHash, _short_channel_id: u64) -> Result<TxOut, chain::AccessError> {
+	fn get_utxo(&self, genesis_hash: &BlockHash, short_channel_id: u64) -> Result<(OutPoint, TxOut), chain::AccessError> {
 		if self.genesis_hash != *genesis_hash {
 			return Err(chain::AccessError::UnknownChain);
 		}
 
-		self.utxo_ret.lock().unwrap().clone()
+		self.utxo_lookups.lock().unwrap().push(short_channel_id);
+
+		self.utxo_ret.lock().unwrap().clone().map(|utxo| (Self::funding_outpoint(short_channel_id), utxo))
 	}
 }
 
@@ -464,3 +544,19 @@ impl chain::Filter for TestChainSource {
 		self.watched_outputs.lock().unwrap().insert((*outpoint, script_pubkey.clone()));
 	}
 }
+
+pub struct TestPeerAddressSource {
+	pub addresses: Mutex<HashMap<PublicKey, Vec<msgs::NetAddress>>>,
+}
+
+impl TestPeerAddressSource {
+	pub fn new() -> Self {
+		Self { addresses: Mutex::new(HashMap::new()) }
+	}
+}
+
+impl peer_connection_manager::PeerAddressSource for TestPeerAddressSource {
+	fn get_peer_addresses(&self, node_id: &PublicKey) -> Vec<msgs::NetAddress> {
+		self.addresses.lock().unwrap().get(node_id).cloned().unwrap_or(Vec::new())
+	}
+}